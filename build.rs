@@ -0,0 +1,15 @@
+//! Generates the gRPC service from `proto/fluxdb.proto` when the `grpc` feature is enabled,
+//! with a vendored `protoc` so building doesn't need one installed.
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/fluxdb.proto");
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("no vendored protoc");
+        std::env::set_var("PROTOC", protoc);
+        tonic_build::configure()
+            .compile_protos(&["proto/fluxdb.proto"], &["proto"])
+            .expect("compiling proto/fluxdb.proto failed");
+    }
+}
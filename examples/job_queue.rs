@@ -0,0 +1,238 @@
+//! A durable job queue shared by several workers: jobs are claimed by exactly one worker,
+//! and put back in the queue if it fails them.
+//!
+//! Pending jobs are stored under `pending/<priority>/<id>`, so a scan of `pending/` meets
+//! the most urgent first, oldest first within a priority. Claiming a job moves it to
+//! `running/<id>` in a transaction: a worker racing another for the same job gets a
+//! conflict and moves on to the next one.
+//!
+//! Run with `cargo run --example job_queue`.
+
+use flux_db::{Disk, DiskOptions, FluxError, WriteBatch};
+use std::sync::Mutex;
+use std::thread;
+
+pub struct Job {
+    pub id: u64,
+    pub priority: u8,
+    pub payload: Vec<u8>,
+}
+
+pub struct JobQueue {
+    db: Disk,
+    /// Id of the next job, locked while a job is queued so ids are saved in order.
+    next_id: Mutex<u64>,
+}
+
+impl JobQueue {
+    /// Opens the queue kept in `db`, numbering new jobs after those it already holds.
+    pub fn new(db: Disk) -> Result<JobQueue, FluxError> {
+        let next_id = match db.get(b"next-id")? {
+            Some(entry) => decode_u64(entry.value())?,
+            None => 0,
+        };
+        Ok(JobQueue {
+            db,
+            next_id: Mutex::new(next_id),
+        })
+    }
+
+    /// Queues a job, 0 being the most urgent priority, and returns its id.
+    pub fn push(&self, priority: u8, payload: &[u8]) -> Result<u64, FluxError> {
+        let mut next_id = self.next_id.lock().unwrap();
+        let id = *next_id;
+        let mut batch = WriteBatch::new();
+        batch.put(&pending_key(priority, id), payload);
+        batch.put(b"next-id", &(id + 1).to_be_bytes());
+        self.db
+            .write(batch)
+            .map_err(|_| rejected("queueing a job"))?;
+        *next_id += 1;
+        Ok(id)
+    }
+
+    /// Claims the most urgent pending job, `None` if there is none.
+    pub fn claim(&self) -> Result<Option<Job>, FluxError> {
+        loop {
+            let pending = self.db.scan(&b"pending/"[..]..&b"pending0"[..])?;
+            if pending.is_empty() {
+                return Ok(None);
+            }
+            for entry in pending {
+                let mut transaction = self.db.transaction();
+                // Another worker got to it first.
+                let Some(payload) = transaction.get(entry.key())? else {
+                    continue;
+                };
+                let (priority, id) = parse_pending_key(entry.key())?;
+                transaction.delete(entry.key());
+                transaction.set(&running_key(id), &[&[priority][..], &payload].concat());
+                match transaction.commit() {
+                    Ok(_) => {
+                        return Ok(Some(Job {
+                            id,
+                            priority,
+                            payload,
+                        }))
+                    }
+                    Err(FluxError::Conflict) => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+
+    /// Marks a claimed job done.
+    pub fn complete(&self, id: u64) -> Result<(), FluxError> {
+        self.db
+            .delete(&running_key(id))
+            .map_err(|_| rejected("completing a job"))?;
+        Ok(())
+    }
+
+    /// Puts a claimed job back in the queue, at its priority.
+    pub fn fail(&self, id: u64) -> Result<(), FluxError> {
+        let Some(entry) = self.db.get(&running_key(id))? else {
+            return Err(FluxError::InvalidArgument(format!(
+                "job {} isn't running",
+                id
+            )));
+        };
+        let (priority, payload) = entry.value().split_first().ok_or_else(|| corrupt("job"))?;
+        let mut batch = WriteBatch::new();
+        batch.delete(&running_key(id));
+        batch.put(&pending_key(*priority, id), payload);
+        self.db
+            .write(batch)
+            .map_err(|_| rejected("failing a job"))?;
+        Ok(())
+    }
+
+    /// Returns the number of pending and running jobs.
+    pub fn len(&self) -> Result<(usize, usize), FluxError> {
+        let pending = self.db.scan(&b"pending/"[..]..&b"pending0"[..])?.len();
+        let running = self.db.scan(&b"running/"[..]..&b"running0"[..])?.len();
+        Ok((pending, running))
+    }
+}
+
+fn pending_key(priority: u8, id: u64) -> Vec<u8> {
+    format!("pending/{:03}/{:020}", priority, id).into_bytes()
+}
+
+fn running_key(id: u64) -> Vec<u8> {
+    format!("running/{:020}", id).into_bytes()
+}
+
+fn parse_pending_key(key: &[u8]) -> Result<(u8, u64), FluxError> {
+    let key = std::str::from_utf8(key).map_err(|_| corrupt("job key"))?;
+    let mut parts = key.split('/').skip(1).map(str::parse::<u64>);
+    match (parts.next(), parts.next()) {
+        (Some(Ok(priority)), Some(Ok(id))) if priority <= u8::MAX as u64 => {
+            Ok((priority as u8, id))
+        }
+        _ => Err(corrupt("job key")),
+    }
+}
+
+fn decode_u64(bytes: &[u8]) -> Result<u64, FluxError> {
+    let bytes = bytes.try_into().map_err(|_| corrupt("job counter"))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn rejected(action: &str) -> FluxError {
+    FluxError::Rejected(format!("{} failed", action))
+}
+
+fn corrupt(what: &str) -> FluxError {
+    FluxError::InvalidArgument(format!("corrupt {}", what))
+}
+
+fn main() -> Result<(), FluxError> {
+    let dir = std::env::temp_dir().join(format!("fluxdb-jobs-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let queue = JobQueue::new(Disk::open(&dir.to_string_lossy(), DiskOptions::default())?)?;
+
+    for i in 0..20 {
+        queue.push(
+            if i % 5 == 0 { 0 } else { 1 },
+            format!("resize image {}", i).as_bytes(),
+        )?;
+    }
+    thread::scope(|scope| {
+        for worker in 0..4 {
+            let queue = &queue;
+            scope.spawn(move || {
+                while let Some(job) = queue.claim().unwrap() {
+                    println!(
+                        "worker {} runs job {}: {}",
+                        worker,
+                        job.id,
+                        String::from_utf8_lossy(&job.payload)
+                    );
+                    queue.complete(job.id).unwrap();
+                }
+            });
+        }
+    });
+    println!("left: {:?}", queue.len()?);
+
+    drop(queue);
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_priorities_and_retries() {
+        let queue = JobQueue::new(Disk::open_in_memory().unwrap()).unwrap();
+        queue.push(2, b"backup").unwrap();
+        queue.push(0, b"page on-call").unwrap();
+        queue.push(2, b"reindex").unwrap();
+
+        let job = queue.claim().unwrap().unwrap();
+        assert_eq!((job.id, &job.payload[..]), (1, &b"page on-call"[..]));
+        let job = queue.claim().unwrap().unwrap();
+        assert_eq!(job.payload, b"backup");
+        assert_eq!(queue.len().unwrap(), (1, 2));
+
+        // A failed job goes back ahead of newer jobs of its priority.
+        queue.fail(job.id).unwrap();
+        assert!(queue.fail(job.id).is_err());
+        queue.complete(1).unwrap();
+        assert_eq!(queue.claim().unwrap().unwrap().payload, b"backup");
+        assert_eq!(queue.claim().unwrap().unwrap().payload, b"reindex");
+        assert!(queue.claim().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_each_job_claimed_once() {
+        let db = Disk::open_in_memory().unwrap();
+        let queue = JobQueue::new(db.clone()).unwrap();
+        for i in 0..200u32 {
+            queue.push((i % 3) as u8, &i.to_be_bytes()).unwrap();
+        }
+        let claimed = Mutex::new(Vec::new());
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    while let Some(job) = queue.claim().unwrap() {
+                        claimed.lock().unwrap().push(job.id);
+                        queue.complete(job.id).unwrap();
+                    }
+                });
+            }
+        });
+        let mut claimed = claimed.into_inner().unwrap();
+        claimed.sort_unstable();
+        assert_eq!(claimed, (0..200).collect::<Vec<u64>>());
+        assert_eq!(queue.len().unwrap(), (0, 0));
+
+        // Ids carry on after a restart.
+        drop(queue);
+        assert_eq!(JobQueue::new(db).unwrap().push(0, b"").unwrap(), 200);
+    }
+}
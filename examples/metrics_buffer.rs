@@ -0,0 +1,149 @@
+//! A buffer of metric samples: services record samples as they come, dashboards read a
+//! window of a series, and old samples are trimmed away.
+//!
+//! Each sample is stored under `metric/<name>/<timestamp>`, the timestamp in microseconds as
+//! 16 hex digits so the samples of a series sort by time, and a window is a range scan.
+//! Trimming a series removes everything before a point with a single range delete, however
+//! many samples that is.
+//!
+//! Run with `cargo run --example metrics_buffer`.
+
+use flux_db::{Disk, DiskOptions, FluxError};
+
+pub struct Summary {
+    pub count: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+}
+
+pub struct MetricsBuffer {
+    db: Disk,
+}
+
+impl MetricsBuffer {
+    pub fn new(db: Disk) -> MetricsBuffer {
+        MetricsBuffer { db }
+    }
+
+    /// Records samples of a series, written together.
+    pub fn record(&self, name: &str, samples: &[(u64, f64)]) -> Result<(), FluxError> {
+        let pairs = samples
+            .iter()
+            .map(|&(timestamp, value)| (sample_key(name, timestamp), value.to_be_bytes()));
+        self.db
+            .set_many(pairs)
+            .map(drop)
+            .map_err(|_| FluxError::Rejected("recording samples failed".to_owned()))
+    }
+
+    /// Returns the samples of a series from `from` (inclusive) to `to` (exclusive), oldest
+    /// first.
+    pub fn window(&self, name: &str, from: u64, to: u64) -> Result<Vec<(u64, f64)>, FluxError> {
+        let (start, end) = (sample_key(name, from), sample_key(name, to));
+        let entries = self.db.scan(start.as_slice()..end.as_slice())?;
+        entries
+            .iter()
+            .map(|entry| {
+                let timestamp = &entry.key()[start.len() - 16..];
+                let timestamp = std::str::from_utf8(timestamp).ok();
+                let timestamp = timestamp.and_then(|hex| u64::from_str_radix(hex, 16).ok());
+                let value = entry.value().try_into().ok().map(f64::from_be_bytes);
+                let corrupt = || FluxError::InvalidArgument("corrupt sample".to_owned());
+                timestamp.zip(value).ok_or_else(corrupt)
+            })
+            .collect()
+    }
+
+    /// Summarizes the samples of a series within a window, `None` if there are none.
+    pub fn summarize(&self, name: &str, from: u64, to: u64) -> Result<Option<Summary>, FluxError> {
+        let samples = self.window(name, from, to)?;
+        if samples.is_empty() {
+            return Ok(None);
+        }
+        let values = samples.iter().map(|&(_, value)| value);
+        Ok(Some(Summary {
+            count: samples.len(),
+            min: values.clone().fold(f64::INFINITY, f64::min),
+            max: values.clone().fold(f64::NEG_INFINITY, f64::max),
+            mean: values.sum::<f64>() / samples.len() as f64,
+        }))
+    }
+
+    /// Removes the samples of a series older than `before`.
+    pub fn trim(&self, name: &str, before: u64) -> Result<(), FluxError> {
+        let (start, end) = (sample_key(name, 0), sample_key(name, before));
+        self.db
+            .delete_range(&start, &end)
+            .map(drop)
+            .map_err(|_| FluxError::Rejected("trimming samples failed".to_owned()))
+    }
+}
+
+fn sample_key(name: &str, timestamp: u64) -> Vec<u8> {
+    format!("metric/{}/{:016x}", name, timestamp).into_bytes()
+}
+
+fn main() -> Result<(), FluxError> {
+    let dir = std::env::temp_dir().join(format!("fluxdb-metrics-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let buffer = MetricsBuffer::new(Disk::open(&dir.to_string_lossy(), DiskOptions::default())?);
+
+    // A minute of CPU samples, one per second.
+    let samples: Vec<(u64, f64)> = (0..60)
+        .map(|second| (second * 1_000_000, 40.0 + (second % 7) as f64))
+        .collect();
+    buffer.record("cpu", &samples)?;
+
+    if let Some(summary) = buffer.summarize("cpu", 30_000_000, 60_000_000)? {
+        println!(
+            "last 30s: {} samples, min {}, max {}, mean {:.2}",
+            summary.count, summary.min, summary.max, summary.mean
+        );
+    }
+    buffer.trim("cpu", 50_000_000)?;
+    println!(
+        "{} samples left after trimming",
+        buffer.window("cpu", 0, u64::MAX)?.len()
+    );
+
+    drop(buffer);
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics() {
+        let buffer = MetricsBuffer::new(Disk::open_in_memory().unwrap());
+        buffer
+            .record("cpu", &[(30, 0.5), (10, 0.25), (20, 1.0)])
+            .unwrap();
+        buffer.record("disk", &[(15, 90.0)]).unwrap();
+
+        // Samples come back in time order, and only from their own series.
+        assert_eq!(
+            buffer.window("cpu", 0, u64::MAX).unwrap(),
+            [(10, 0.25), (20, 1.0), (30, 0.5)]
+        );
+        assert_eq!(
+            buffer.window("cpu", 10, 30).unwrap(),
+            [(10, 0.25), (20, 1.0)]
+        );
+        let summary = buffer.summarize("cpu", 0, 100).unwrap().unwrap();
+        assert_eq!((summary.count, summary.min, summary.max), (3, 0.25, 1.0));
+        assert!((summary.mean - 1.75 / 3.0).abs() < 1e-9);
+        assert!(buffer.summarize("memory", 0, 100).unwrap().is_none());
+
+        buffer.trim("cpu", 25).unwrap();
+        assert_eq!(buffer.window("cpu", 0, u64::MAX).unwrap(), [(30, 0.5)]);
+        assert_eq!(buffer.window("disk", 0, u64::MAX).unwrap(), [(15, 90.0)]);
+
+        // Samples recorded after a trim aren't affected by it.
+        buffer.record("cpu", &[(5, 0.75)]).unwrap();
+        assert_eq!(buffer.window("cpu", 0, 10).unwrap(), [(5, 0.75)]);
+    }
+}
@@ -0,0 +1,77 @@
+//! Measures how long opening a database takes when its WAL holds a lot of writes.
+//!
+//! Writes the given number of MiB (1024 by default) of 1 KiB values without flushing any of
+//! them, then times `Disk::open`, which reads the WAL files in place, against replaying the
+//! same files into a fresh WAL with `WAL::replay_files`, which writes every record again,
+//! as opening a database used to.
+//!
+//! Run with `cargo run --release --example recovery_bench -- 1024`.
+
+use flux_db::wal::WAL;
+use flux_db::{Disk, DiskOptions};
+use std::error::Error;
+use std::time::Instant;
+
+const VALUE_SIZE: usize = 1024;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mib: u64 = match std::env::args().nth(1) {
+        Some(arg) => arg.parse()?,
+        None => 1024,
+    };
+    let dir = std::env::temp_dir().join(format!("fluxdb-recovery-{}", std::process::id()));
+    let scratch = dir.join("replayed");
+    std::fs::create_dir_all(&scratch)?;
+    let options = DiskOptions {
+        memtable_size: usize::MAX,
+        ..DiskOptions::default()
+    };
+
+    let disk = Disk::open(&dir.to_string_lossy(), options.clone())?;
+    let value = vec![b'x'; VALUE_SIZE];
+    let mut written = 0;
+    let mut i = 0u64;
+    while written < mib << 20 {
+        disk.set(format!("key{:012}", i).as_bytes(), &value)
+            .map_err(|_| "a write failed")?;
+        written += VALUE_SIZE as u64;
+        i += 1;
+    }
+    let wal_files = disk.wal_files();
+    drop(disk);
+    let wal_bytes: u64 = wal_files
+        .iter()
+        .map(|path| std::fs::metadata(path).map(|metadata| metadata.len()))
+        .sum::<std::io::Result<_>>()?;
+    println!("{} writes, {} MiB of WAL", i, wal_bytes >> 20);
+
+    let start = Instant::now();
+    let disk = Disk::open(&dir.to_string_lossy(), options.clone())?;
+    let elapsed = start.elapsed();
+    let recovery = disk.statistics().recovery;
+    let fresh = disk.wal_files().last().map(std::fs::metadata).transpose()?;
+    println!(
+        "open, reading in place: {:.2?}, {} records, {} bytes written to the fresh WAL",
+        elapsed,
+        recovery.records_replayed,
+        fresh.map_or(0, |metadata| metadata.len())
+    );
+    drop(disk);
+
+    let start = Instant::now();
+    let (mut fresh, mem_table, recovery) =
+        WAL::replay_files_with_stats(&scratch, &wal_files, &options, 0, &[])?;
+    // Opening a database made the copied records durable before retiring the files read.
+    fresh.sync()?;
+    let elapsed = start.elapsed();
+    println!(
+        "replaying into a fresh WAL: {:.2?}, {} records, {} bytes written to the fresh WAL",
+        elapsed,
+        recovery.records_replayed,
+        fresh.size()
+    );
+
+    drop((fresh, mem_table));
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
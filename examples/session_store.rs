@@ -0,0 +1,232 @@
+//! A web session store: sessions expire after a time to live, and signing a user out ends
+//! every session they hold.
+//!
+//! Each session is stored under `session/<id>` as its expiry, in microseconds since the Unix
+//! epoch, followed by the user and the session data. An index entry `user/<user>/<id>` lists
+//! the sessions of a user; both are written in one batch, so they never disagree.
+//!
+//! Run with `cargo run --example session_store`.
+
+use flux_db::{Disk, DiskOptions, FluxError, WriteBatch};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub struct Session {
+    pub user: String,
+    pub data: Vec<u8>,
+    pub expires_at: u64,
+}
+
+pub struct SessionStore {
+    db: Disk,
+}
+
+impl SessionStore {
+    pub fn new(db: Disk) -> SessionStore {
+        SessionStore { db }
+    }
+
+    /// Starts a session for `user` lasting `ttl` from `now`.
+    pub fn create(
+        &self,
+        id: &str,
+        user: &str,
+        data: &[u8],
+        now: u64,
+        ttl: Duration,
+    ) -> Result<(), FluxError> {
+        let session = Session {
+            user: user.to_owned(),
+            data: data.to_vec(),
+            expires_at: now + ttl.as_micros() as u64,
+        };
+        let mut batch = WriteBatch::new();
+        batch.put(&session_key(id), &encode(&session));
+        batch.put(&user_key(user, id), b"");
+        self.db
+            .write(batch)
+            .map(drop)
+            .map_err(|_| failed("creating a session"))
+    }
+
+    /// Returns the session if it hasn't expired as of `now`. Expired sessions are removed
+    /// as they are found.
+    pub fn get(&self, id: &str, now: u64) -> Result<Option<Session>, FluxError> {
+        let Some(entry) = self.db.get(&session_key(id))? else {
+            return Ok(None);
+        };
+        let session = decode(entry.value())?;
+        if session.expires_at <= now {
+            self.end(id, &session.user)?;
+            return Ok(None);
+        }
+        Ok(Some(session))
+    }
+
+    /// Extends a live session to last `ttl` from `now`, returning whether it was live.
+    pub fn touch(&self, id: &str, now: u64, ttl: Duration) -> Result<bool, FluxError> {
+        let Some(mut session) = self.get(id, now)? else {
+            return Ok(false);
+        };
+        session.expires_at = now + ttl.as_micros() as u64;
+        self.db
+            .set(&session_key(id), &encode(&session))
+            .map_err(|_| failed("extending a session"))?;
+        Ok(true)
+    }
+
+    /// Ends every session of a user, returning how many there were.
+    pub fn sign_out(&self, user: &str) -> Result<usize, FluxError> {
+        let prefix = format!("user/{}/", user);
+        let end = prefix_end(prefix.as_bytes());
+        let sessions = self.db.scan(prefix.as_bytes()..end.as_slice())?;
+        let mut batch = WriteBatch::new();
+        for entry in &sessions {
+            let id = &entry.key()[prefix.len()..];
+            batch.delete(&[b"session/", id].concat());
+            batch.delete(entry.key());
+        }
+        self.db.write(batch).map_err(|_| failed("signing out"))?;
+        Ok(sessions.len())
+    }
+
+    /// Removes every session expired as of `now`, returning how many there were.
+    pub fn purge_expired(&self, now: u64) -> Result<usize, FluxError> {
+        let mut purged = 0;
+        for entry in self.db.scan(&b"session/"[..]..&b"session0"[..])? {
+            let session = decode(entry.value())?;
+            if session.expires_at <= now {
+                let id = String::from_utf8_lossy(&entry.key()[b"session/".len()..]);
+                self.end(&id, &session.user)?;
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+
+    fn end(&self, id: &str, user: &str) -> Result<(), FluxError> {
+        let mut batch = WriteBatch::new();
+        batch.delete(&session_key(id));
+        batch.delete(&user_key(user, id));
+        self.db
+            .write(batch)
+            .map(drop)
+            .map_err(|_| failed("ending a session"))
+    }
+}
+
+fn session_key(id: &str) -> Vec<u8> {
+    format!("session/{}", id).into_bytes()
+}
+
+fn user_key(user: &str, id: &str) -> Vec<u8> {
+    format!("user/{}/{}", user, id).into_bytes()
+}
+
+/// Returns the smallest key above every key starting with `prefix`.
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            break;
+        }
+    }
+    end
+}
+
+fn encode(session: &Session) -> Vec<u8> {
+    let mut value = session.expires_at.to_be_bytes().to_vec();
+    value.extend_from_slice(&(session.user.len() as u32).to_be_bytes());
+    value.extend_from_slice(session.user.as_bytes());
+    value.extend_from_slice(&session.data);
+    value
+}
+
+fn decode(value: &[u8]) -> Result<Session, FluxError> {
+    let corrupt = || FluxError::InvalidArgument("corrupt session".to_owned());
+    let expires_at = u64::from_be_bytes(value.get(..8).ok_or_else(corrupt)?.try_into().unwrap());
+    let len = u32::from_be_bytes(value.get(8..12).ok_or_else(corrupt)?.try_into().unwrap());
+    let user = value.get(12..12 + len as usize).ok_or_else(corrupt)?;
+    Ok(Session {
+        user: String::from_utf8(user.to_vec()).map_err(|_| corrupt())?,
+        data: value[12 + len as usize..].to_vec(),
+        expires_at,
+    })
+}
+
+fn failed(action: &str) -> FluxError {
+    FluxError::Rejected(format!("{} failed", action))
+}
+
+fn main() -> Result<(), FluxError> {
+    let dir = std::env::temp_dir().join(format!("fluxdb-sessions-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let store = SessionStore::new(Disk::open(&dir.to_string_lossy(), DiskOptions::default())?);
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_micros() as u64;
+    let hour = Duration::from_secs(60 * 60);
+    store.create("a1", "alice", b"cart=3", now, hour)?;
+    store.create("a2", "alice", b"cart=0", now, hour)?;
+    store.create("b1", "bob", b"theme=dark", now, hour)?;
+
+    let session = store.get("b1", now)?.expect("bob is signed in");
+    println!(
+        "b1 belongs to {}: {}",
+        session.user,
+        String::from_utf8_lossy(&session.data)
+    );
+    println!("signed alice out of {} sessions", store.sign_out("alice")?);
+    println!(
+        "{} sessions expired two hours later",
+        store.purge_expired(now + 2 * hour.as_micros() as u64)?
+    );
+
+    drop(store);
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+    const MINUTE_MICROS: u64 = 60_000_000;
+
+    #[test]
+    fn test_sessions() {
+        let store = SessionStore::new(Disk::open_in_memory().unwrap());
+        store.create("a1", "alice", b"cart=3", 0, MINUTE).unwrap();
+        store.create("a2", "alice", b"", 0, 2 * MINUTE).unwrap();
+        store.create("b1", "bob", b"theme=dark", 0, MINUTE).unwrap();
+
+        let session = store.get("a1", MINUTE_MICROS / 2).unwrap().unwrap();
+        assert_eq!(
+            (session.user.as_str(), &session.data[..]),
+            ("alice", &b"cart=3"[..])
+        );
+        assert!(store.touch("b1", MINUTE_MICROS / 2, MINUTE).unwrap());
+
+        // a1 has expired, b1 was extended.
+        assert!(store.get("a1", MINUTE_MICROS).unwrap().is_none());
+        assert!(store.get("b1", MINUTE_MICROS).unwrap().is_some());
+        assert!(!store.touch("a1", MINUTE_MICROS, MINUTE).unwrap());
+
+        // Only the session left is signed out, and its index entry goes with it.
+        assert_eq!(store.sign_out("alice").unwrap(), 1);
+        assert!(store.get("a2", 0).unwrap().is_none());
+        assert_eq!(store.sign_out("alice").unwrap(), 0);
+
+        assert_eq!(store.purge_expired(2 * MINUTE_MICROS).unwrap(), 1);
+        assert!(store.db.scan(..).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_prefix_end() {
+        assert_eq!(prefix_end(b"user/"), b"user0");
+        assert_eq!(prefix_end(b"a\xff"), b"b");
+    }
+}
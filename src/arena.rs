@@ -0,0 +1,232 @@
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::ptr;
+use std::sync::Arc;
+
+/// Size of the chunks an arena copies keys and values into.
+pub const ARENA_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Bump allocator for the keys and values of a memtable. Bytes are copied one after the
+/// other into large chunks, so writing a record takes no allocation of its own and the
+/// records written together sit together in memory.
+///
+/// Each chunk is freed once nothing points into it anymore: usually all at once, when the
+/// memtable is dropped after its flush, and earlier for a chunk whose records have all been
+/// replaced.
+pub(crate) struct Arena {
+    chunk: Option<Arc<Chunk>>,
+    /// Bytes of the current chunk in use.
+    used: usize,
+    /// Bytes of all the chunks allocated so far.
+    allocated: usize,
+}
+
+impl Arena {
+    pub fn new() -> Arena {
+        Arena {
+            chunk: None,
+            used: 0,
+            allocated: 0,
+        }
+    }
+
+    /// Copies `bytes` into the arena.
+    pub fn alloc(&mut self, bytes: &[u8]) -> ArenaBytes {
+        // Bytes too large to share a chunk get one of their own, rather than wasting the
+        // end of the current one.
+        if bytes.len() > ARENA_CHUNK_SIZE / 4 {
+            self.allocated += bytes.len();
+            return ArenaBytes::from(bytes);
+        }
+        let chunk = match &self.chunk {
+            Some(chunk) if chunk.capacity - self.used >= bytes.len() => chunk.clone(),
+            _ => {
+                let chunk = Arc::new(Chunk::new(ARENA_CHUNK_SIZE));
+                self.chunk = Some(chunk.clone());
+                self.used = 0;
+                self.allocated += ARENA_CHUNK_SIZE;
+                chunk
+            }
+        };
+        let offset = self.used;
+        // SAFETY: the bytes past `used` are within the chunk and no `ArenaBytes` covers
+        // them yet, so nothing reads them while they are written.
+        unsafe { chunk.write(offset, bytes) };
+        self.used += bytes.len();
+        ArenaBytes {
+            chunk,
+            offset,
+            len: bytes.len(),
+        }
+    }
+
+    /// Returns the bytes of all the chunks allocated so far.
+    pub fn allocated(&self) -> usize {
+        self.allocated
+    }
+}
+
+/// A fixed-size buffer written to in increasing offsets, each byte once, before being read.
+struct Chunk {
+    data: *mut u8,
+    capacity: usize,
+}
+
+// SAFETY: the bytes of a chunk are only written by the arena that owns it, before any
+// `ArenaBytes` covering them exists, and only read afterwards.
+unsafe impl Send for Chunk {}
+unsafe impl Sync for Chunk {}
+
+impl Chunk {
+    fn new(capacity: usize) -> Chunk {
+        let mut buffer = Vec::<u8>::with_capacity(capacity);
+        let data = buffer.as_mut_ptr();
+        std::mem::forget(buffer);
+        Chunk { data, capacity }
+    }
+
+    /// Copies `bytes` to `offset`.
+    ///
+    /// # Safety
+    ///
+    /// The bytes written must be within the chunk, and not yet covered by any `ArenaBytes`.
+    unsafe fn write(&self, offset: usize, bytes: &[u8]) {
+        debug_assert!(offset + bytes.len() <= self.capacity);
+        ptr::copy_nonoverlapping(bytes.as_ptr(), self.data.add(offset), bytes.len());
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        // SAFETY: `data` and `capacity` come from a vector leaked in `Chunk::new`.
+        drop(unsafe { Vec::from_raw_parts(self.data, 0, self.capacity) });
+    }
+}
+
+/// Bytes of a key or value held in an arena, keeping their chunk alive. Dereferences to the
+/// bytes; cloning shares the chunk instead of copying them.
+#[derive(Clone)]
+pub struct ArenaBytes {
+    chunk: Arc<Chunk>,
+    offset: usize,
+    len: usize,
+}
+
+impl From<&[u8]> for ArenaBytes {
+    /// Copies the bytes into a chunk of their own.
+    fn from(bytes: &[u8]) -> ArenaBytes {
+        let chunk = Arc::new(Chunk::new(bytes.len()));
+        // SAFETY: the chunk is exactly as long as the bytes, and nothing covers it yet.
+        unsafe { chunk.write(0, bytes) };
+        ArenaBytes {
+            chunk,
+            offset: 0,
+            len: bytes.len(),
+        }
+    }
+}
+
+impl Deref for ArenaBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the bytes were written before this handle was created, and the chunk is
+        // kept alive by it.
+        unsafe { std::slice::from_raw_parts(self.chunk.data.add(self.offset), self.len) }
+    }
+}
+
+impl AsRef<[u8]> for ArenaBytes {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Borrow<[u8]> for ArenaBytes {
+    fn borrow(&self) -> &[u8] {
+        self
+    }
+}
+
+impl PartialEq for ArenaBytes {
+    fn eq(&self, other: &ArenaBytes) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl Eq for ArenaBytes {}
+
+impl PartialEq<[u8]> for ArenaBytes {
+    fn eq(&self, other: &[u8]) -> bool {
+        &self[..] == other
+    }
+}
+
+impl PartialEq<&[u8]> for ArenaBytes {
+    fn eq(&self, other: &&[u8]) -> bool {
+        &self[..] == *other
+    }
+}
+
+impl<const N: usize> PartialEq<&[u8; N]> for ArenaBytes {
+    fn eq(&self, other: &&[u8; N]) -> bool {
+        &self[..] == *other
+    }
+}
+
+impl<const N: usize> PartialEq<[u8; N]> for ArenaBytes {
+    fn eq(&self, other: &[u8; N]) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl PartialEq<Vec<u8>> for ArenaBytes {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl Hash for ArenaBytes {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self[..].hash(state)
+    }
+}
+
+impl fmt::Debug for ArenaBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "b\"{}\"", self.escape_ascii())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records_share_chunks() {
+        let mut arena = Arena::new();
+        let key = arena.alloc(b"Server");
+        let value = arena.alloc(b"nginx");
+        assert_eq!(&key[..], b"Server");
+        assert_eq!(value, b"nginx");
+        assert!(Arc::ptr_eq(&key.chunk, &value.chunk));
+        assert_eq!(value.offset, 6);
+        assert_eq!(arena.allocated(), ARENA_CHUNK_SIZE);
+
+        // A full chunk is followed by a new one, and large values get their own.
+        let filler = vec![7; ARENA_CHUNK_SIZE / 4];
+        let copies: Vec<ArenaBytes> = (0..4).map(|_| arena.alloc(&filler)).collect();
+        assert!(!Arc::ptr_eq(&key.chunk, &copies[3].chunk));
+        assert_eq!(copies[3], filler);
+        let large = arena.alloc(&vec![1; ARENA_CHUNK_SIZE]);
+        assert_eq!(large.len(), ARENA_CHUNK_SIZE);
+        assert_eq!(arena.allocated(), 3 * ARENA_CHUNK_SIZE);
+
+        // Handles outlive the arena.
+        drop(arena);
+        assert_eq!(key.clone(), ArenaBytes::from(&b"Server"[..]));
+        assert_eq!(ArenaBytes::from(&b""[..]).len(), 0);
+    }
+}
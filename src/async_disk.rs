@@ -0,0 +1,236 @@
+use crate::disk::{Disk, DiskEntry};
+use crate::error::FluxError;
+use crate::options::DiskOptions;
+use crate::subscription::ChangeEvent;
+use crate::wal_tail::AsyncWalTail;
+use crate::write_batch::{WriteBatch, WriteOptions};
+use std::io;
+use std::ops::Bound;
+#[cfg(not(tokio_unstable))]
+use tokio::task::spawn_blocking;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+
+/// Async front-end to a `Disk`, for embedding the engine in async services.
+///
+/// Every call runs the blocking engine operation on tokio's blocking thread pool, so file
+/// I/O and lock waits never stall the runtime's worker threads. Requires the `async` feature
+/// and must be used from within a tokio runtime.
+#[derive(Clone)]
+pub struct AsyncDisk {
+    disk: Disk,
+}
+
+impl AsyncDisk {
+    /// Opens the database in `dir`, recovering its WAL files on the blocking pool.
+    pub async fn open(dir: &str, options: DiskOptions) -> io::Result<AsyncDisk> {
+        let dir = dir.to_owned();
+        let disk = run("fluxdb::open", move || Disk::open(&dir, options)).await?;
+        Ok(AsyncDisk { disk })
+    }
+
+    /// Wraps an already open database.
+    pub fn from_disk(disk: Disk) -> AsyncDisk {
+        AsyncDisk { disk }
+    }
+
+    /// Returns the underlying blocking handle.
+    pub fn disk(&self) -> &Disk {
+        &self.disk
+    }
+
+    /// Async version of `Disk::get`.
+    pub async fn get(&self, key: &[u8]) -> io::Result<Option<DiskEntry>> {
+        let disk = self.disk.clone();
+        let key = key.to_vec();
+        run("fluxdb::get", move || disk.get(&key)).await
+    }
+
+    /// Async version of `Disk::scan`, taking owned bounds.
+    pub async fn scan(
+        &self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+    ) -> Result<Vec<DiskEntry>, FluxError> {
+        let disk = self.disk.clone();
+        run("fluxdb::scan", move || {
+            disk.scan((as_slice(&start), as_slice(&end)))
+        }).await
+    }
+
+    /// Async version of `Disk::set`.
+    pub async fn set(&self, key: &[u8], value: &[u8]) -> Result<usize, usize> {
+        let disk = self.disk.clone();
+        let (key, value) = (key.to_vec(), value.to_vec());
+        run("fluxdb::set", move || disk.set(&key, &value)).await
+    }
+
+    /// Async version of `Disk::set_with`.
+    pub async fn set_with(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        write_options: WriteOptions,
+    ) -> Result<usize, usize> {
+        let disk = self.disk.clone();
+        let (key, value) = (key.to_vec(), value.to_vec());
+        run("fluxdb::set", move || disk.set_with(&key, &value, &write_options)).await
+    }
+
+    /// Async version of `Disk::delete`.
+    pub async fn delete(&self, key: &[u8]) -> Result<usize, usize> {
+        let disk = self.disk.clone();
+        let key = key.to_vec();
+        run("fluxdb::delete", move || disk.delete(&key)).await
+    }
+
+    /// Async version of `Disk::delete_with`.
+    pub async fn delete_with(
+        &self,
+        key: &[u8],
+        write_options: WriteOptions,
+    ) -> Result<usize, usize> {
+        let disk = self.disk.clone();
+        let key = key.to_vec();
+        run("fluxdb::delete", move || disk.delete_with(&key, &write_options)).await
+    }
+
+    /// Async version of `Disk::delete_range`.
+    pub async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<usize, usize> {
+        let disk = self.disk.clone();
+        let (start, end) = (start.to_vec(), end.to_vec());
+        run("fluxdb::delete_range", move || disk.delete_range(&start, &end)).await
+    }
+
+    /// Async version of `Disk::write`.
+    pub async fn write(&self, batch: WriteBatch) -> Result<usize, usize> {
+        let disk = self.disk.clone();
+        run("fluxdb::write", move || disk.write(batch)).await
+    }
+
+    /// Async version of `Disk::write_with`.
+    pub async fn write_with(
+        &self,
+        batch: WriteBatch,
+        write_options: WriteOptions,
+    ) -> Result<usize, usize> {
+        let disk = self.disk.clone();
+        run("fluxdb::write", move || disk.write_with(batch, &write_options)).await
+    }
+
+    /// Version of `Disk::subscribe` delivering the committed writes over a tokio channel, to
+    /// be awaited from async tasks.
+    pub fn subscribe(&self) -> UnboundedReceiver<ChangeEvent> {
+        self.disk.subscribe_async()
+    }
+
+    /// Version of `Disk::tail` delivering the writes to be awaited from async tasks. The
+    /// backlog is read from the WAL on the blocking pool.
+    pub async fn tail(&self, from_sequence: u64) -> Result<AsyncWalTail, FluxError> {
+        let disk = self.disk.clone();
+        run("fluxdb::tail", move || disk.tail_async(from_sequence)).await
+    }
+}
+
+/// Runs a blocking engine call on the blocking pool inside a span named after the task,
+/// re-raising any panic in the caller.
+pub(crate) async fn run<T, F>(task: &'static str, f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let span = tracing::debug_span!("fluxdb", task);
+    let blocking_span = span.clone();
+    let handle = spawn_named(task, move || blocking_span.in_scope(f));
+    match handle.instrument(span).await {
+        Ok(result) => result,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// Spawns a blocking task, naming it so it can be told apart in tokio-console. Task names
+/// require building with `--cfg tokio_unstable`; otherwise the task is spawned unnamed.
+#[cfg(tokio_unstable)]
+fn spawn_named<T, F>(name: &'static str, f: F) -> JoinHandle<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    tokio::task::Builder::new()
+        .name(name)
+        .spawn_blocking(f)
+        .expect("failed to spawn blocking task")
+}
+
+#[cfg(not(tokio_unstable))]
+fn spawn_named<T, F>(_name: &'static str, f: F) -> JoinHandle<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    spawn_blocking(f)
+}
+
+pub(crate) fn as_slice(bound: &Bound<Vec<u8>>) -> Bound<&[u8]> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.as_slice()),
+        Bound::Excluded(key) => Bound::Excluded(key.as_slice()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::subscription::ChangeOp;
+    use rand::Rng;
+    use std::fs::{create_dir_all, remove_dir_all};
+
+    #[test]
+    fn test_async_operations() {
+        let mut rng = rand::thread_rng();
+        let test_dir = format!("./{}/", rng.gen::<u32>());
+        create_dir_all(&test_dir).unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let disk = AsyncDisk::open(&test_dir, DiskOptions::default())
+                .await
+                .unwrap();
+            let mut events = disk.subscribe();
+            disk.set(b"API", b"GraphQL").await.unwrap();
+            disk.set(b"Database", b"PostgreSQL").await.unwrap();
+            disk.set(b"Server", b"nginx").await.unwrap();
+            disk.delete(b"Database").await.unwrap();
+
+            assert_eq!(disk.get(b"API").await.unwrap().unwrap().value(), b"GraphQL");
+            assert!(disk.get(b"Database").await.unwrap().is_none());
+
+            let entries = disk
+                .scan(Bound::Included(b"A".to_vec()), Bound::Unbounded)
+                .await
+                .unwrap();
+            let keys: Vec<&[u8]> = entries.iter().map(|entry| entry.key()).collect();
+            assert_eq!(keys, vec![&b"API"[..], b"Server"]);
+
+            let event = events.recv().await.unwrap();
+            assert_eq!(event.key, b"API");
+            assert_eq!(event.op, ChangeOp::Put(b"GraphQL".to_vec()));
+            assert_eq!(event.sequence, 1);
+            let mut sequences = vec![event.sequence];
+            while let Ok(event) = events.try_recv() {
+                sequences.push(event.sequence);
+            }
+            assert_eq!(sequences, vec![1, 2, 3, 4]);
+
+            let mut tail = disk.tail(2).await.unwrap();
+            assert_eq!(tail.recv().await.unwrap().key, b"Server");
+            assert_eq!(tail.position(), 3);
+        });
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+}
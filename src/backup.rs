@@ -0,0 +1,135 @@
+use crate::manifest::{Manifest, MANIFEST_FILE};
+use crate::platform::{self, sync_dir};
+use crate::reflink;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+/// Summary of a backup written by `Disk::create_backup`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BackupInfo {
+    /// Segment and value log files added to the backup.
+    pub segments_added: usize,
+    /// Segment and value log files an earlier backup to the same directory already held,
+    /// and were kept.
+    pub segments_reused: usize,
+    /// Bytes of WAL copied.
+    pub wal_bytes: u64,
+    /// Sequence number of the last write the backup holds.
+    pub last_sequence: u64,
+}
+
+/// Adds a segment or value log file to a backup, unless the backup already holds it. Neither
+/// is modified once written and their names are never reused, so an existing file of the
+/// same name and size is the same file. New ones are hard-linked where possible, copied
+/// otherwise. Returns whether the file was added.
+pub(crate) fn add_segment(src: &Path, dest: &Path) -> io::Result<bool> {
+    if let Ok(existing) = fs::metadata(dest) {
+        if existing.len() == fs::metadata(src)?.len() {
+            return Ok(false);
+        }
+        fs::remove_file(dest)?;
+    }
+    link_or_copy(src, dest)?;
+    Ok(true)
+}
+
+/// Copies the first `len` bytes of an open WAL file to `dest`. The handle keeps the file
+/// readable even if it is retired meanwhile, and bytes appended after the backup point
+/// are left out.
+pub(crate) fn copy_wal(file: &File, len: u64, dest: &Path) -> io::Result<u64> {
+    let temp_path = dest.with_extension("tmp");
+    let out = reflink::clone_or_copy(file, len, &temp_path).inspect_err(|_| {
+        let _ = fs::remove_file(&temp_path);
+    })?;
+    out.sync_all()?;
+    platform::rename(&temp_path, dest)?;
+    Ok(len)
+}
+
+/// Completes a backup once its files are in place: stores the manifest, then removes the
+/// files of earlier backups it no longer lists.
+pub(crate) fn finish_backup(dest: &Path, manifest: &Manifest) -> io::Result<()> {
+    sync_dir(dest)?;
+    manifest.store(dest)?;
+    manifest.remove_unlisted_files(dest)?;
+    Ok(())
+}
+
+/// Restores the backup in `src` into `dst`, which must not hold a database. The restored
+/// database holds every write of the backup when opened.
+pub fn restore(src: &Path, dst: &Path) -> io::Result<()> {
+    let manifest = Manifest::load(src)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no backup in {}", src.display()),
+        )
+    })?;
+    manifest.verify_files_exist(src)?;
+    if dst.join(MANIFEST_FILE).exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already holds a database", dst.display()),
+        ));
+    }
+
+    fs::create_dir_all(dst)?;
+    for name in manifest.segment_files.iter().chain(&manifest.value_log_files) {
+        link_or_copy(&src.join(name), &dst.join(name))?;
+    }
+    // WAL files are copied, so the backup never shares a file that may be written to.
+    for name in manifest.wal_files.iter() {
+        let file = File::open(src.join(name))?;
+        let len = file.metadata()?.len();
+        copy_wal(&file, len, &dst.join(name))?;
+    }
+    sync_dir(dst)?;
+    manifest.store(dst)
+}
+
+/// Hard-links an immutable file into `dest`, or clones or copies it where links aren't
+/// possible, such as across file systems.
+fn link_or_copy(src: &Path, dest: &Path) -> io::Result<()> {
+    if fs::hard_link(src, dest).is_err() {
+        let file = File::open(src)?;
+        let len = file.metadata()?.len();
+        reflink::clone_or_copy(&file, len, dest)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_restore_checks_directories() {
+        let mut rng = rand::thread_rng();
+        let test_dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir_all(&test_dir).unwrap();
+        let (src, dst) = (test_dir.join("backup"), test_dir.join("restored"));
+        create_dir_all(&src).unwrap();
+
+        let err = restore(&src, &dst).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        let manifest = Manifest {
+            segment_files: vec!["000001.sst".to_owned()],
+            ..Manifest::default()
+        };
+        manifest.store(&src).unwrap();
+        assert_eq!(restore(&src, &dst).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        fs::write(src.join("000001.sst"), b"segment").unwrap();
+        restore(&src, &dst).unwrap();
+        assert_eq!(fs::read(dst.join("000001.sst")).unwrap(), b"segment");
+        assert_eq!(Manifest::load(&dst).unwrap(), Some(manifest));
+        let err = restore(&src, &dst).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+}
@@ -0,0 +1,734 @@
+//! Network server exposing a FluxDB database over the Redis protocol (RESP), so clients in
+//! any language, or `redis-cli`, can use it.
+//!
+//! Connections are multiplexed onto a fixed pool of worker threads, all sharing the one
+//! `Disk`, whose reads run concurrently and whose writes are serialized by the engine. A
+//! worker answers the commands a client has sent, then hands the connection to a poller
+//! thread that watches every idle connection and queues it for the workers again once more
+//! bytes arrive, so an open connection costs no thread while its client is quiet. A client
+//! has `COMMAND_TIMEOUT` to finish sending a command it started, and a connection idle for
+//! `IDLE_TIMEOUT` is closed. Commands are arrays of bulk strings, as Redis clients send
+//! them, or inline lines of space-separated words, for `telnet` and `nc`.
+//!
+//! `SCAN` pages through the keys of a snapshot taken by the `SCAN 0` that started the pass,
+//! reading one page per call. Each reply names a new cursor, and the cursor it was given
+//! keeps naming the same page, so a client repeating a `SCAN` whose reply it lost reads that
+//! page again, as with Redis. Cursors unused for `DEFAULT_CURSOR_IDLE_TIMEOUT` are
+//! forgotten, releasing their snapshot.
+
+use flux_db::cursor::DEFAULT_CURSOR_IDLE_TIMEOUT;
+use flux_db::snapshot::Snapshot;
+use flux_db::{Disk, DiskOptions, FluxError};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Bound;
+use std::process::ExitCode;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "usage: fluxdb-server <dir> [address]
+
+Serves the database in <dir>, creating it if needed, on <address> (127.0.0.1:6380 by
+default) over the Redis protocol.
+
+commands:
+  GET key                  the value of a key, or nil
+  SET key value            insert or update a key
+  DEL key [key ...]        remove keys, returning how many existed
+  SCAN cursor [COUNT n]    the next n keys (10 by default) of a cursor, 0 opening a new
+                           one, and the cursor to pass next, 0 once every key was returned
+  PING [message]           PONG, or the message
+  QUIT                     close the connection";
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:6380";
+/// Largest bulk string accepted, as in Redis.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+/// Most arguments a command may have.
+const MAX_ARGS: usize = 1024 * 1024;
+/// Most arguments allocated for ahead of reading them, whatever count a command announces.
+const PREALLOCATED_ARGS: usize = 64;
+/// Longest line accepted: an inline command, or the header of an array or bulk string.
+const MAX_LINE_LEN: usize = 64 * 1024;
+/// Keys `SCAN` returns when no `COUNT` is given, as in Redis.
+const DEFAULT_SCAN_COUNT: usize = 10;
+/// Most keys a single `SCAN` returns.
+const MAX_SCAN_COUNT: usize = 10_000;
+/// Most `SCAN` cursors remembered at once; past it the least recently used is forgotten.
+const MAX_SCAN_CURSORS: usize = 10_000;
+/// How long a client may take to send the rest of a command, or to read a reply.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a connection may stay idle between commands before it is closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+/// Most commands a worker answers on one connection before serving the others, so a client
+/// pipelining without pause can't hold a worker.
+const COMMANDS_PER_TURN: usize = 64;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (dir, address) = match &args[..] {
+        [dir] => (dir.as_str(), DEFAULT_ADDRESS),
+        [dir, address] => (dir.as_str(), address.as_str()),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    match serve(dir, address) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("fluxdb-server: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn serve(dir: &str, address: &str) -> io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let disk = Disk::open(dir, DiskOptions::default())?;
+    let listener = TcpListener::bind(address)?;
+    let workers = thread::available_parallelism().map_or(4, |count| count.get());
+    let ready = start(disk, workers, IDLE_TIMEOUT)?;
+    eprintln!(
+        "fluxdb-server: serving {} on {}",
+        dir,
+        listener.local_addr()?
+    );
+    for stream in listener.incoming() {
+        let connection = stream.and_then(Connection::new);
+        match connection {
+            // A new client is expected to send a command right away.
+            Ok(connection) => ready.send(connection).expect("the workers stopped"),
+            Err(e) => eprintln!("fluxdb-server: accepting a connection failed: {}", e),
+        }
+    }
+    Ok(())
+}
+
+/// Starts the workers and the poller, and returns where to send the connections ready to
+/// be served.
+fn start(disk: Disk, workers: usize, idle_timeout: Duration) -> io::Result<Sender<Connection>> {
+    let (ready, queue) = mpsc::channel();
+    let queue = Arc::new(Mutex::new(queue));
+    let (idle, poller) = Poller::new(ready.clone(), idle_timeout)?;
+    thread::Builder::new()
+        .name("fluxdb-poller".to_owned())
+        .spawn(move || poller.run())?;
+    let scans = Arc::new(ScanCursors::new(disk.clone()));
+    for index in 0..workers {
+        let (disk, scans, queue) = (disk.clone(), scans.clone(), queue.clone());
+        let (ready, idle) = (ready.clone(), idle.clone());
+        thread::Builder::new()
+            .name(format!("fluxdb-worker-{}", index))
+            .spawn(move || loop {
+                // The lock is held only while waiting, so idle workers queue up behind it.
+                let Ok(mut connection) = queue.lock().unwrap().recv() else {
+                    return;
+                };
+                match answer(&disk, &scans, &mut connection) {
+                    Ok(Turn::Idle) => idle.park(connection),
+                    Ok(Turn::Busy) => ready.send(connection).expect("the workers stopped"),
+                    Ok(Turn::Closed) => {}
+                    Err(e) => eprintln!("fluxdb-server: connection dropped: {}", e),
+                }
+            })?;
+    }
+    Ok(ready)
+}
+
+/// A client connection, with the bytes read from it but not yet parsed and the replies not
+/// yet sent.
+struct Connection {
+    reader: BufReader<TcpStream>,
+    out: BufWriter<TcpStream>,
+    /// When the connection was parked idle.
+    idle_since: Instant,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> io::Result<Connection> {
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(COMMAND_TIMEOUT))?;
+        stream.set_write_timeout(Some(COMMAND_TIMEOUT))?;
+        Ok(Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            out: BufWriter::new(stream),
+            idle_since: Instant::now(),
+        })
+    }
+
+    fn stream(&self) -> &TcpStream {
+        self.reader.get_ref()
+    }
+}
+
+/// What became of a connection after a worker's turn on it.
+#[derive(Debug, PartialEq, Eq)]
+enum Turn {
+    /// Every command sent was answered; the connection waits for more.
+    Idle,
+    /// Commands are left to answer once the other connections had a turn.
+    Busy,
+    /// The client disconnected or quit.
+    Closed,
+}
+
+/// Answers the commands the client has sent, at most `COMMANDS_PER_TURN` of them.
+/// Pipelined commands are answered in one write.
+fn answer(disk: &Disk, scans: &ScanCursors, connection: &mut Connection) -> io::Result<Turn> {
+    for _ in 0..COMMANDS_PER_TURN {
+        if connection.reader.buffer().is_empty() {
+            connection.out.flush()?;
+            // Whether the client sent more, without waiting for it.
+            connection.stream().set_nonblocking(true)?;
+            let filled = connection.reader.fill_buf().map(|buffer| buffer.len());
+            connection.stream().set_nonblocking(false)?;
+            match filled {
+                Ok(0) => return Ok(Turn::Closed),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Turn::Idle),
+                Err(e) => return Err(e),
+            }
+        }
+        let args = match read_command(&mut connection.reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(Turn::Closed),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                // The stream can't be followed past a malformed command.
+                let error = Reply::Error(format!("ERR Protocol error: {}", e));
+                error.write_to(&mut connection.out)?;
+                connection.out.flush()?;
+                return Ok(Turn::Closed);
+            }
+            Err(e) => return Err(e),
+        };
+        if args.is_empty() {
+            continue;
+        }
+        execute(disk, scans, &args).write_to(&mut connection.out)?;
+        if args[0].eq_ignore_ascii_case(b"QUIT") {
+            connection.out.flush()?;
+            return Ok(Turn::Closed);
+        }
+    }
+    connection.out.flush()?;
+    Ok(Turn::Busy)
+}
+
+/// Where workers park idle connections for the poller.
+#[derive(Clone)]
+struct Idle {
+    parked: Sender<Connection>,
+    /// Written to after parking, to wake the poller from its wait.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    wake: Arc<std::os::unix::net::UnixStream>,
+}
+
+impl Idle {
+    fn park(&self, mut connection: Connection) {
+        connection.idle_since = Instant::now();
+        if self.parked.send(connection).is_err() {
+            return;
+        }
+        // A full socket means a wake-up is already pending.
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        let _ = (&*self.wake).write(&[1]);
+    }
+}
+
+/// Watches the idle connections, handing each back to the workers once its client sends
+/// more, or closing it after the idle timeout.
+struct Poller {
+    parked: Receiver<Connection>,
+    ready: Sender<Connection>,
+    idle_timeout: Duration,
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    wake: std::os::unix::net::UnixStream,
+}
+
+/// How long the poller waits at most before looking at newly parked connections, where it
+/// can't be woken by them.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+impl Poller {
+    fn new(ready: Sender<Connection>, idle_timeout: Duration) -> io::Result<(Idle, Poller)> {
+        let (parked, receiver) = mpsc::channel();
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        let (wake, woken) = std::os::unix::net::UnixStream::pair()?;
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        {
+            wake.set_nonblocking(true)?;
+            woken.set_nonblocking(true)?;
+        }
+        let idle = Idle {
+            parked,
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            wake: Arc::new(wake),
+        };
+        let poller = Poller {
+            parked: receiver,
+            ready,
+            idle_timeout,
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            wake: woken,
+        };
+        Ok((idle, poller))
+    }
+
+    fn run(self) {
+        let mut idle: Vec<Connection> = Vec::new();
+        loop {
+            idle.extend(self.parked.try_iter());
+            let now = Instant::now();
+            let timeout = idle
+                .iter()
+                .map(|connection| {
+                    (connection.idle_since + self.idle_timeout).saturating_duration_since(now)
+                })
+                .min()
+                .unwrap_or(self.idle_timeout);
+            let readable = match self.wait(&idle, timeout) {
+                Ok(readable) => readable,
+                Err(e) => {
+                    eprintln!("fluxdb-server: watching idle connections failed: {}", e);
+                    return;
+                }
+            };
+            let mut readable = readable.into_iter();
+            for connection in std::mem::take(&mut idle) {
+                if readable.next() == Some(true) {
+                    if self.ready.send(connection).is_err() {
+                        return;
+                    }
+                } else if connection.idle_since.elapsed() < self.idle_timeout {
+                    idle.push(connection);
+                }
+                // Otherwise dropping the connection closes it.
+            }
+        }
+    }
+
+    /// Waits until one of the connections is readable, a connection is parked, or the
+    /// timeout passes, and returns which connections are readable.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn wait(&self, idle: &[Connection], timeout: Duration) -> io::Result<Vec<bool>> {
+        use std::os::fd::AsRawFd;
+        let fds = std::iter::once(self.wake.as_raw_fd()).chain(
+            idle.iter()
+                .map(|connection| connection.stream().as_raw_fd()),
+        );
+        let mut fds: Vec<libc::pollfd> = fds
+            .map(|fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let timeout = timeout.as_millis().saturating_add(1).min(i32::MAX as u128) as i32;
+        // SAFETY: `fds` is a valid array of `fds.len()` entries.
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+        // Clears the wake-ups, which were for connections already taken from `parked`
+        // or about to be on the next turn.
+        while let Ok(1..) = (&self.wake).read(&mut [0; 64]) {}
+        Ok(fds[1..].iter().map(|fd| fd.revents != 0).collect())
+    }
+
+    /// Looks at each connection without blocking, and sleeps a little if none is readable.
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    fn wait(&self, idle: &[Connection], timeout: Duration) -> io::Result<Vec<bool>> {
+        let mut readable = Vec::with_capacity(idle.len());
+        for connection in idle {
+            connection.stream().set_nonblocking(true)?;
+            let peeked = connection.stream().peek(&mut [0]);
+            connection.stream().set_nonblocking(false)?;
+            readable.push(!matches!(peeked, Err(e) if e.kind() == io::ErrorKind::WouldBlock));
+        }
+        if !readable.contains(&true) {
+            thread::sleep(timeout.min(POLL_INTERVAL));
+        }
+        Ok(readable)
+    }
+}
+
+/// A reply in the Redis protocol.
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        match self {
+            Reply::Simple(text) => write!(out, "+{}\r\n", text),
+            Reply::Error(message) => write!(out, "-{}\r\n", message.replace(['\r', '\n'], " ")),
+            Reply::Integer(value) => write!(out, ":{}\r\n", value),
+            Reply::Bulk(None) => out.write_all(b"$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                write!(out, "${}\r\n", bytes.len())?;
+                out.write_all(bytes)?;
+                out.write_all(b"\r\n")
+            }
+            Reply::Array(items) => {
+                write!(out, "*{}\r\n", items.len())?;
+                items.iter().try_for_each(|item| item.write_to(out))
+            }
+        }
+    }
+}
+
+/// Runs a command against the database.
+fn execute(disk: &Disk, scans: &ScanCursors, args: &[Vec<u8>]) -> Reply {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+    match (name.as_str(), &args[1..]) {
+        ("GET", [key]) => match disk.get(key) {
+            Ok(entry) => Reply::Bulk(entry.map(|entry| entry.value().to_vec())),
+            Err(e) => Reply::Error(format!("ERR {}", e)),
+        },
+        ("SET", [key, value]) => match disk.set(key, value) {
+            Ok(_) => Reply::Simple("OK"),
+            Err(_) => Reply::Error("ERR write failed".to_owned()),
+        },
+        ("DEL", keys) if !keys.is_empty() => match delete(disk, keys) {
+            Ok(removed) => Reply::Integer(removed),
+            Err(FluxError::Io(e)) => Reply::Error(format!("ERR {}", e)),
+            Err(_) => Reply::Error("ERR write failed".to_owned()),
+        },
+        ("SCAN", [cursor, options @ ..]) => scan(scans, cursor, options),
+        ("PING", []) => Reply::Simple("PONG"),
+        ("PING", [message]) => Reply::Bulk(Some(message.clone())),
+        ("QUIT", []) => Reply::Simple("OK"),
+        // Sent by redis-cli when it connects.
+        ("COMMAND", _) => Reply::Array(Vec::new()),
+        ("GET" | "SET" | "DEL" | "SCAN" | "PING" | "QUIT", _) => Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name.to_ascii_lowercase()
+        )),
+        _ => Reply::Error(format!(
+            "ERR unknown command '{}'",
+            name.to_ascii_lowercase()
+        )),
+    }
+}
+
+/// Removes the keys in one transaction, retried until no write to them came in between,
+/// and returns how many existed.
+fn delete(disk: &Disk, keys: &[Vec<u8>]) -> Result<i64, FluxError> {
+    loop {
+        let mut transaction = disk.transaction();
+        let mut removed = 0;
+        for key in keys {
+            if transaction.get(key)?.is_some() {
+                removed += 1;
+                transaction.delete(key);
+            }
+        }
+        match transaction.commit() {
+            Ok(_) => return Ok(removed),
+            Err(FluxError::Conflict) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Returns the page of keys the `SCAN` cursor names, starting a pass over every key for
+/// cursor 0, along with the cursor naming the next page, 0 once the keys are exhausted.
+fn scan(scans: &ScanCursors, cursor: &[u8], options: &[Vec<u8>]) -> Reply {
+    let Some(cursor) = parse_number(cursor) else {
+        return Reply::Error("ERR invalid cursor".to_owned());
+    };
+    let count = match options {
+        [] => DEFAULT_SCAN_COUNT,
+        [option, count] if option.eq_ignore_ascii_case(b"COUNT") => {
+            match parse_number(count).and_then(|count| usize::try_from(count).ok()) {
+                Some(count) if count > 0 => count.min(MAX_SCAN_COUNT),
+                _ => return Reply::Error("ERR value is out of range".to_owned()),
+            }
+        }
+        _ => return Reply::Error("ERR syntax error".to_owned()),
+    };
+    match scans.page(cursor, count) {
+        Ok(Some((next, keys))) => {
+            let keys = keys.into_iter().map(|key| Reply::Bulk(Some(key)));
+            Reply::Array(vec![
+                Reply::Bulk(Some(next.to_string().into_bytes())),
+                Reply::Array(keys.collect()),
+            ])
+        }
+        // Unknown or forgotten.
+        Ok(None) => Reply::Error("ERR invalid cursor".to_owned()),
+        Err(e) => Reply::Error(format!("ERR {}", e)),
+    }
+}
+
+/// The keys of a `SCAN` page, after the cursor naming the page after it.
+type Page = (u64, Vec<Vec<u8>>);
+
+/// The `SCAN` cursors handed out, each naming a page of a pass, shared by every connection.
+struct ScanCursors {
+    disk: Disk,
+    state: Mutex<ScanState>,
+}
+
+#[derive(Default)]
+struct ScanState {
+    positions: HashMap<u64, Position>,
+    next_cursor: u64,
+}
+
+/// Where the page a cursor names starts.
+struct Position {
+    /// The snapshot of the pass, taken by its `SCAN 0`.
+    snapshot: Arc<Snapshot>,
+    /// The last key of the page before, if any.
+    after: Option<Vec<u8>>,
+    last_used: Instant,
+}
+
+impl ScanCursors {
+    fn new(disk: Disk) -> ScanCursors {
+        ScanCursors {
+            disk,
+            state: Mutex::new(ScanState::default()),
+        }
+    }
+
+    /// Reads the page of at most `count` keys that `cursor` names, and returns the cursor
+    /// naming the page after it, 0 if none is left, along with the keys. Returns `None` if
+    /// the cursor is unknown or was forgotten.
+    fn page(&self, cursor: u64, count: usize) -> Result<Option<Page>, FluxError> {
+        let (snapshot, after) = match cursor {
+            0 => (Arc::new(self.disk.snapshot()), None),
+            cursor => {
+                let mut state = self.lock_forgetting_idle();
+                let Some(position) = state.positions.get_mut(&cursor) else {
+                    return Ok(None);
+                };
+                position.last_used = Instant::now();
+                (position.snapshot.clone(), position.after.clone())
+            }
+        };
+        let start = after.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+        let page = snapshot.scan_page((start, Bound::Unbounded), count)?;
+        let keys: Vec<Vec<u8>> = page.iter().map(|entry| entry.key().to_vec()).collect();
+        if keys.len() < count {
+            return Ok(Some((0, keys)));
+        }
+        let position = Position {
+            snapshot,
+            after: keys.last().cloned(),
+            last_used: Instant::now(),
+        };
+        let mut state = self.lock_forgetting_idle();
+        if state.positions.len() >= MAX_SCAN_CURSORS {
+            let oldest = state
+                .positions
+                .iter()
+                .min_by_key(|(_, position)| position.last_used);
+            let oldest = *oldest.expect("the limit is positive").0;
+            state.positions.remove(&oldest);
+        }
+        state.next_cursor += 1;
+        let next = state.next_cursor;
+        state.positions.insert(next, position);
+        Ok(Some((next, keys)))
+    }
+
+    fn lock_forgetting_idle(&self) -> std::sync::MutexGuard<'_, ScanState> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .positions
+            .retain(|_, position| position.last_used.elapsed() < DEFAULT_CURSOR_IDLE_TIMEOUT);
+        state
+    }
+}
+
+fn parse_number(digits: &[u8]) -> Option<u64> {
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+/// Reads the next command, as an array of bulk strings or an inline line. Returns `None`
+/// once the client disconnects between commands.
+fn read_command(reader: &mut impl BufRead) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(reader)? else {
+        return Ok(None);
+    };
+    let Some(count) = line.strip_prefix(b"*") else {
+        let words = line.split(|byte| byte.is_ascii_whitespace());
+        return Ok(Some(
+            words
+                .filter(|word| !word.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect(),
+        ));
+    };
+    let count = parse_len(count, MAX_ARGS)?;
+    let mut args = Vec::with_capacity(count.min(PREALLOCATED_ARGS));
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or_else(truncated)?;
+        let len = match line.strip_prefix(b"$") {
+            Some(len) => parse_len(len, MAX_BULK_LEN)?,
+            None => return Err(invalid("expected a bulk string")),
+        };
+        let mut arg = Vec::new();
+        Read::take(&mut *reader, len as u64 + 2).read_to_end(&mut arg)?;
+        if arg.len() < len + 2 {
+            return Err(truncated());
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(invalid("bulk string not terminated by CRLF"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Reads a line without its line ending. Returns `None` at the end of the stream.
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    Read::take(&mut *reader, MAX_LINE_LEN as u64 + 1).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(match line.len() < MAX_LINE_LEN {
+            true => truncated(),
+            false => invalid("line too long"),
+        });
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8], max: usize) -> io::Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .filter(|&len| len <= max)
+        .ok_or_else(|| invalid("invalid length"))
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "connection closed within a command",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(input: &[u8]) -> io::Result<Option<Vec<Vec<u8>>>> {
+        read_command(&mut BufReader::new(input))
+    }
+
+    fn reply(reply: Reply) -> String {
+        let mut out = Vec::new();
+        reply.write_to(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_protocol() {
+        let set = command(b"*3\r\n$3\r\nSET\r\n$6\r\nServer\r\n$7\r\nng\r\ninx\r\n").unwrap();
+        assert_eq!(set.unwrap(), [&b"SET"[..], b"Server", b"ng\r\ninx"]);
+        let inline = command(b"get  Server\r\n").unwrap();
+        assert_eq!(inline.unwrap(), [&b"get"[..], b"Server"]);
+        assert!(command(b"").unwrap().is_none());
+        assert!(command(b"*2\r\n$3\r\nGET\r\n").is_err());
+        assert!(command(b"*1\r\n$3\r\nGETX\r\n").is_err());
+        assert!(command(b"*1\r\n:3\r\n").is_err());
+
+        let disk = Disk::open_in_memory().unwrap();
+        let scans = ScanCursors::new(disk.clone());
+        let run = |args: &[&str]| {
+            let args: Vec<Vec<u8>> = args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+            reply(execute(&disk, &scans, &args))
+        };
+        assert_eq!(run(&["set", "Server", "nginx"]), "+OK\r\n");
+        assert_eq!(run(&["SET", "Database", "PostgreSQL"]), "+OK\r\n");
+        assert_eq!(run(&["GET", "Server"]), "$5\r\nnginx\r\n");
+        assert_eq!(run(&["GET", "Cache"]), "$-1\r\n");
+        assert_eq!(run(&["DEL", "Database", "Cache", "Database"]), ":1\r\n");
+        assert_eq!(run(&["SET", "Cache", "Redis"]), "+OK\r\n");
+        // A cursor pages through the keys as they were when it was opened.
+        assert_eq!(
+            run(&["SCAN", "0", "COUNT", "1"]),
+            "*2\r\n$1\r\n1\r\n*1\r\n$5\r\nCache\r\n"
+        );
+        assert_eq!(run(&["SET", "Proxy", "haproxy"]), "+OK\r\n");
+        assert_eq!(
+            run(&["SCAN", "1", "count", "5"]),
+            "*2\r\n$1\r\n0\r\n*1\r\n$6\r\nServer\r\n"
+        );
+        // Repeating a cursor reads its page again.
+        assert_eq!(
+            run(&["SCAN", "1", "COUNT", "1"]),
+            "*2\r\n$1\r\n2\r\n*1\r\n$6\r\nServer\r\n"
+        );
+        assert!(run(&["SCAN", "3"]).starts_with("-ERR invalid cursor"));
+        assert!(run(&["SCAN", "0", "COUNT", "0"]).starts_with("-ERR value is out of range"));
+        assert_eq!(run(&["PING"]), "+PONG\r\n");
+        assert!(run(&["GET"]).starts_with("-ERR wrong number of arguments"));
+        assert!(run(&["FLUSHALL"]).starts_with("-ERR unknown command"));
+    }
+
+    #[test]
+    fn test_connection() {
+        let disk = Disk::open_in_memory().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let ready = start(disk, 1, Duration::from_millis(300)).unwrap();
+        let connect = || {
+            let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            ready.send(Connection::new(stream).unwrap()).unwrap();
+            client
+        };
+
+        // Pipelined commands, in both forms.
+        let mut client = connect();
+        client
+            .write_all(b"*3\r\n$3\r\nSET\r\n$6\r\nServer\r\n$5\r\nnginx\r\n")
+            .unwrap();
+        client.write_all(b"GET Server\r\nQUIT\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "+OK\r\n$5\r\nnginx\r\n+OK\r\n");
+
+        // An idle connection holds no worker, so the one worker serves both clients, and the
+        // first is closed once idle for longer than the timeout.
+        let mut idle = connect();
+        let mut other = connect();
+        let mut response = [0; 7];
+        for client in [&mut other, &mut idle] {
+            client.write_all(b"PING\r\n").unwrap();
+            client.read_exact(&mut response).unwrap();
+            assert_eq!(&response, b"+PONG\r\n");
+        }
+        thread::sleep(Duration::from_millis(100));
+        idle.write_all(b"GET Server\r\n").unwrap();
+        let mut response = [0; 11];
+        idle.read_exact(&mut response).unwrap();
+        assert_eq!(&response, b"$5\r\nnginx\r\n");
+        let mut rest = Vec::new();
+        assert_eq!(idle.read_to_end(&mut rest).unwrap(), 0);
+    }
+}
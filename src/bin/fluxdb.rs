@@ -0,0 +1,293 @@
+//! Command-line tool for inspecting and changing a FluxDB database directory.
+
+use flux_db::manifest::{self, Manifest};
+use flux_db::stats::Statistics;
+use flux_db::wal::{self, find_wal_files};
+use flux_db::{Disk, DiskOptions, Follower};
+use std::io::{self, Write};
+use std::ops::Bound;
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::Duration;
+
+const USAGE: &str = "usage: fluxdb <dir> <command> [args]
+
+commands:
+  get <key>            print the value of a key
+  set <key> <value>    insert or update a key
+  del <key>            remove a key
+  scan [start] [end]   print the keys from start (inclusive) to end (exclusive)
+  dump-wal             print the records of the WAL files with their offsets, and where a
+                       file is corrupted, without opening the database
+  stats                print statistics and file counts
+  history              print the edits made to the live files: WAL files started and
+                       retired, segments flushed and compacted, without opening the database
+  flush                write the memtable to a segment
+  compact [start end]  flush the memtable and merge every segment into one, or only the
+                       segments holding keys from start (inclusive) to end (exclusive)
+
+Keys and values are taken as given; bytes outside printable ASCII are printed escaped.
+get, scan and stats only read the directory, so they also work while a process has the
+database open.";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let stdout = io::stdout();
+    match run(&args, &mut stdout.lock()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Error::Usage) => {
+            eprintln!("{}", USAGE);
+            ExitCode::from(2)
+        }
+        Err(Error::Failed(message)) => {
+            eprintln!("fluxdb: {}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Error {
+    Usage,
+    Failed(String),
+}
+
+impl<E: std::fmt::Display> From<E> for Error {
+    fn from(e: E) -> Error {
+        Error::Failed(e.to_string())
+    }
+}
+
+fn run(args: &[String], out: &mut impl Write) -> Result<(), Error> {
+    let (dir, command, args) = match args {
+        [dir, command, args @ ..] => (dir.as_str(), command.as_str(), args),
+        _ => return Err(Error::Usage),
+    };
+    // Only writing a key may create a database.
+    if command == "set" {
+        std::fs::create_dir_all(dir)?;
+    }
+    if !Path::new(dir).is_dir() {
+        return Err(Error::Failed(format!("{} is not a directory", dir)));
+    }
+
+    match (command, args) {
+        ("get", [key]) => match open_read_only(dir)?.get(key.as_bytes())? {
+            Some(entry) => writeln!(out, "{}", escape(entry.value()))?,
+            None => return Err(Error::Failed(format!("{} not found", key))),
+        },
+        ("set", [key, value]) => {
+            open(dir)?
+                .set(key.as_bytes(), value.as_bytes())
+                .map_err(|_| Error::Failed(format!("writing {} failed", key)))?;
+        }
+        ("del", [key]) => {
+            open(dir)?
+                .delete(key.as_bytes())
+                .map_err(|_| Error::Failed(format!("removing {} failed", key)))?;
+        }
+        ("scan", bounds) if bounds.len() <= 2 => {
+            let start = bounds.first().map(|start| start.as_bytes());
+            let end = bounds.get(1).map(|end| end.as_bytes());
+            let range = (
+                start.map_or(Bound::Unbounded, Bound::Included),
+                end.map_or(Bound::Unbounded, Bound::Excluded),
+            );
+            for entry in open_read_only(dir)?.scan(range)? {
+                writeln!(out, "{}\t{}", escape(entry.key()), escape(entry.value()))?;
+            }
+        }
+        ("dump-wal", []) => dump_wal(Path::new(dir), out)?,
+        ("history", []) => {
+            for edit in manifest::load_history(Path::new(dir))? {
+                let files = edit.added.iter().map(|name| format!("+{}", name));
+                let files: Vec<String> =
+                    files.chain(edit.removed.iter().map(|name| format!("-{}", name))).collect();
+                writeln!(
+                    out,
+                    "{}\t{}\t{}\t{}",
+                    edit.timestamp,
+                    edit.reason.name(),
+                    edit.last_sequence,
+                    files.join(" ")
+                )?;
+            }
+        }
+        ("stats", []) => {
+            let disk = open_read_only(dir)?;
+            let stats = Statistics::load(Path::new(dir))?.snapshot();
+            writeln!(out, "bytes_written\t{}", stats.bytes_written)?;
+            writeln!(out, "flushes\t{}", stats.flushes)?;
+            writeln!(out, "compactions\t{}", stats.compactions)?;
+            writeln!(out, "stall_time_ms\t{}", stats.stall_time.as_millis())?;
+            writeln!(out, "puts\t{}", stats.puts)?;
+            writeln!(out, "deletes\t{}", stats.deletes)?;
+            writeln!(out, "gets\t{}", stats.gets)?;
+            writeln!(out, "wal_bytes_written\t{}", stats.wal_bytes_written)?;
+            writeln!(out, "compaction_bytes_read\t{}", stats.compaction_bytes_read)?;
+            writeln!(out, "compaction_bytes_written\t{}", stats.compaction_bytes_written)?;
+            writeln!(out, "bloom_hit_rate\t{:.3}", stats.bloom_hit_rate())?;
+            writeln!(out, "last_sequence\t{}", disk.last_sequence())?;
+            writeln!(out, "segment_files\t{}", disk.segment_files().len())?;
+            writeln!(out, "wal_files\t{}", disk.wal_files().len())?;
+        }
+        ("flush", []) => open(dir)?.flush()?,
+        ("compact", []) => open(dir)?.compact()?,
+        ("compact", [start, end]) => open(dir)?.compact_range(start.as_bytes(), end.as_bytes())?,
+        _ => return Err(Error::Usage),
+    }
+    Ok(())
+}
+
+fn open(dir: &str) -> io::Result<Disk> {
+    Disk::open(dir, DiskOptions::default())
+}
+
+/// Opens the database without writing to its directory, so reading it neither waits for
+/// nor disturbs a process that has it open, and leaves no WAL file or manifest edit behind.
+fn open_read_only(dir: &str) -> io::Result<Follower> {
+    Follower::open(dir, DiskOptions::default(), Duration::MAX)
+}
+
+/// Prints every record of the live WAL files, or of every WAL file found if the directory
+/// has no manifest, with its offset, sequence, timestamp, kind and schema version. A file
+/// that ends in a torn or corrupted record says where and why.
+fn dump_wal(dir: &Path, out: &mut impl Write) -> io::Result<()> {
+    let paths = match Manifest::load(dir)? {
+        Some(manifest) => manifest.wal_paths(dir),
+        None => find_wal_files(dir)?,
+    };
+    for path in paths {
+        writeln!(out, "# {}", path.display())?;
+        for item in wal::inspect(&path)? {
+            let record = match item {
+                Ok(record) => record,
+                Err(corruption) => {
+                    writeln!(out, "# {}", corruption)?;
+                    break;
+                }
+            };
+            let kind = match (record.is_range_removal, record.is_removed, &record.data) {
+                (true, _, _) => "delete-range",
+                (false, true, _) if record.is_backdated => "backdated-delete",
+                (false, false, _) if record.is_backdated => "backdated-put",
+                (false, true, Some(_)) => "soft-delete",
+                (false, true, None) => "delete",
+                (false, false, _) => "put",
+            };
+            write!(
+                out,
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                record.offset,
+                record.sequence,
+                record.event_time,
+                kind,
+                record.schema,
+                escape(&record.identifier)
+            )?;
+            match &record.data {
+                Some(data) => writeln!(out, "\t{}", escape(data))?,
+                None => writeln!(out)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Renders bytes as text, escaping anything outside printable ASCII.
+fn escape(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .flat_map(|&byte| std::ascii::escape_default(byte))
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use std::fs::remove_dir_all;
+
+    fn fluxdb(args: &[&str]) -> Result<String, Error> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let mut out = Vec::new();
+        run(&args, &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut rng = rand::thread_rng();
+        let test_dir = format!("./{}/", rng.gen::<u32>());
+        let dir = test_dir.as_str();
+
+        assert!(matches!(fluxdb(&[dir, "get", "Server"]), Err(Error::Failed(_))));
+        fluxdb(&[dir, "set", "Server", "nginx"]).unwrap();
+        fluxdb(&[dir, "set", "Database", "PostgreSQL"]).unwrap();
+        fluxdb(&[dir, "set", "Cache", "Redis\n"]).unwrap();
+        fluxdb(&[dir, "del", "Database"]).unwrap();
+
+        assert_eq!(fluxdb(&[dir, "get", "Server"]).unwrap(), "nginx\n");
+        assert!(matches!(fluxdb(&[dir, "get", "Database"]), Err(Error::Failed(_))));
+        assert_eq!(fluxdb(&[dir, "scan"]).unwrap(), "Cache\tRedis\\n\nServer\tnginx\n");
+        assert_eq!(fluxdb(&[dir, "scan", "D", "T"]).unwrap(), "Server\tnginx\n");
+
+        let wal = fluxdb(&[dir, "dump-wal"]).unwrap();
+        let records: Vec<&str> = wal.lines().filter(|line| !line.starts_with('#')).collect();
+        assert_eq!(records.len(), 4);
+        let fields: Vec<&str> = records[3].split('\t').collect();
+        assert_eq!(fields[1], "4");
+        assert_eq!(fields[3..], ["delete", "0", "Database"]);
+        // Each run of the tool left its write in a WAL file of its own, as none was flushed.
+        let mut offsets: Vec<Vec<u64>> = Vec::new();
+        for line in wal.lines() {
+            match line.starts_with('#') {
+                true => offsets.push(Vec::new()),
+                false => offsets
+                    .last_mut()
+                    .unwrap()
+                    .push(line.split('\t').next().unwrap().parse().unwrap()),
+            }
+        }
+        assert!(offsets.iter().filter(|file| !file.is_empty()).count() > 1);
+        assert!(offsets.iter().all(|file| file.windows(2).all(|pair| pair[0] < pair[1])));
+
+        fluxdb(&[dir, "compact"]).unwrap();
+        let stats = fluxdb(&[dir, "stats"]).unwrap();
+        assert!(stats.contains("compactions\t1\n") && stats.contains("segment_files\t1\n"));
+        let history = fluxdb(&[dir, "history"]).unwrap();
+        let edits: Vec<Vec<&str>> =
+            history.lines().map(|line| line.split('\t').skip(1).collect()).collect();
+        let background: Vec<&[&str]> =
+            edits.iter().filter(|edit| edit[0] != "open").map(|edit| &edit[..2]).collect();
+        assert_eq!(
+            background,
+            [["wal-rotation", "4"], ["flush", "4"], ["compaction", "4"]]
+        );
+        assert_eq!(fluxdb(&[dir, "get", "Server"]).unwrap(), "nginx\n");
+
+        fluxdb(&[dir, "set", "Cache", "Memcached"]).unwrap();
+        fluxdb(&[dir, "flush"]).unwrap();
+        assert!(fluxdb(&[dir, "stats"]).unwrap().contains("segment_files\t2\n"));
+        fluxdb(&[dir, "compact", "A", "D"]).unwrap();
+        assert!(fluxdb(&[dir, "stats"]).unwrap().contains("segment_files\t1\n"));
+        assert_eq!(fluxdb(&[dir, "get", "Cache"]).unwrap(), "Memcached\n");
+
+        // Reads leave the directory alone, and work while the database is open.
+        let disk = open(dir).unwrap();
+        let wal_files = disk.wal_files();
+        disk.set(b"Proxy", b"HAProxy").unwrap();
+        assert_eq!(fluxdb(&[dir, "get", "Proxy"]).unwrap(), "HAProxy\n");
+        assert!(fluxdb(&[dir, "scan", "P"]).unwrap().starts_with("Proxy\tHAProxy\n"));
+        assert!(fluxdb(&[dir, "stats"]).unwrap().contains("segment_files\t1\n"));
+        assert!(fluxdb(&[dir, "set", "Proxy", "Envoy"]).is_err());
+        assert_eq!(disk.wal_files(), wal_files);
+        drop(disk);
+
+        assert!(matches!(fluxdb(&[dir, "set", "Server"]), Err(Error::Usage)));
+        assert!(matches!(fluxdb(&[dir]), Err(Error::Usage)));
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+}
@@ -0,0 +1,262 @@
+use crate::bytes::Bytes;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Marks the end of the recency list.
+const NIL: usize = usize::MAX;
+
+/// A cache of decompressed segment blocks, evicting the least recently used ones once their
+/// total size passes a capacity in bytes.
+///
+/// The handle is cheap to clone; clones share the same cache, so handing one to several
+/// databases through `DiskOptions::block_cache` bounds the memory of all of them together.
+/// Repeated reads of a cached block skip the read from storage, its checksum and its
+/// decompression.
+#[derive(Clone)]
+pub struct BlockCache {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    capacity: usize,
+    lru: Mutex<Lru>,
+    next_file: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+/// Cached blocks, keyed by file and block number, in a list from the most to the least
+/// recently used.
+struct Lru {
+    map: HashMap<(u64, usize), usize>,
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    head: usize,
+    tail: usize,
+    usage: usize,
+}
+
+struct Node {
+    key: (u64, usize),
+    block: Bytes,
+    prev: usize,
+    next: usize,
+}
+
+/// Counters of a block cache, summed over every database sharing it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BlockCacheStats {
+    /// Capacity in bytes.
+    pub capacity: usize,
+    /// Bytes of blocks cached.
+    pub usage: usize,
+    /// Number of blocks cached.
+    pub blocks: usize,
+    /// Block reads served from the cache.
+    pub hits: u64,
+    /// Block reads that went to storage.
+    pub misses: u64,
+    /// Blocks evicted to make room for others.
+    pub evictions: u64,
+}
+
+impl BlockCacheStats {
+    /// Returns the share of block reads served from the cache, or 0 before any read.
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            reads => self.hits as f64 / reads as f64,
+        }
+    }
+}
+
+impl BlockCache {
+    /// Creates an empty cache holding up to `capacity` bytes of blocks.
+    pub fn new(capacity: usize) -> BlockCache {
+        BlockCache {
+            shared: Arc::new(Shared {
+                capacity,
+                lru: Mutex::new(Lru {
+                    map: HashMap::new(),
+                    nodes: Vec::new(),
+                    free: Vec::new(),
+                    head: NIL,
+                    tail: NIL,
+                    usage: 0,
+                }),
+                next_file: AtomicU64::new(1),
+                hits: AtomicU64::new(0),
+                misses: AtomicU64::new(0),
+                evictions: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Returns a number identifying a file among every file sharing the cache.
+    pub(crate) fn file_id(&self) -> u64 {
+        self.shared.next_file.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Returns a cached block, marking it as the most recently used.
+    pub(crate) fn get(&self, file: u64, block: usize) -> Option<Bytes> {
+        let mut lru = self.shared.lru.lock().unwrap();
+        let Some(&node) = lru.map.get(&(file, block)) else {
+            self.shared.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        self.shared.hits.fetch_add(1, Ordering::Relaxed);
+        lru.unlink(node);
+        lru.push_front(node);
+        Some(lru.nodes[node].block.clone())
+    }
+
+    /// Caches a block, evicting the least recently used ones past the capacity. Blocks
+    /// larger than the whole cache aren't kept.
+    pub(crate) fn insert(&self, file: u64, block: usize, data: Bytes) {
+        if data.len() > self.shared.capacity {
+            return;
+        }
+        let mut lru = self.shared.lru.lock().unwrap();
+        if let Some(node) = lru.map.get(&(file, block)).copied() {
+            lru.remove(node);
+        }
+        lru.usage += data.len();
+        let node = Node {
+            key: (file, block),
+            block: data,
+            prev: NIL,
+            next: NIL,
+        };
+        let node = match lru.free.pop() {
+            Some(free) => {
+                lru.nodes[free] = node;
+                free
+            }
+            None => {
+                lru.nodes.push(node);
+                lru.nodes.len() - 1
+            }
+        };
+        lru.map.insert((file, block), node);
+        lru.push_front(node);
+        while lru.usage > self.shared.capacity {
+            let tail = lru.tail;
+            lru.remove(tail);
+            self.shared.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Drops the blocks of a file that is gone.
+    pub(crate) fn remove_file(&self, file: u64) {
+        let mut lru = self.shared.lru.lock().unwrap();
+        let nodes: Vec<usize> = lru
+            .map
+            .iter()
+            .filter(|((cached_file, _), _)| *cached_file == file)
+            .map(|(_, &node)| node)
+            .collect();
+        for node in nodes {
+            lru.remove(node);
+        }
+    }
+
+    /// Returns the counters of the cache.
+    pub fn stats(&self) -> BlockCacheStats {
+        let lru = self.shared.lru.lock().unwrap();
+        BlockCacheStats {
+            capacity: self.shared.capacity,
+            usage: lru.usage,
+            blocks: lru.map.len(),
+            hits: self.shared.hits.load(Ordering::Relaxed),
+            misses: self.shared.misses.load(Ordering::Relaxed),
+            evictions: self.shared.evictions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockCache")
+            .field("capacity", &self.shared.capacity)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Lru {
+    fn push_front(&mut self, node: usize) {
+        self.nodes[node].prev = NIL;
+        self.nodes[node].next = self.head;
+        match self.head {
+            NIL => self.tail = node,
+            head => self.nodes[head].prev = node,
+        }
+        self.head = node;
+    }
+
+    fn unlink(&mut self, node: usize) {
+        let (prev, next) = (self.nodes[node].prev, self.nodes[node].next);
+        match prev {
+            NIL => self.head = next,
+            prev => self.nodes[prev].next = next,
+        }
+        match next {
+            NIL => self.tail = prev,
+            next => self.nodes[next].prev = prev,
+        }
+    }
+
+    fn remove(&mut self, node: usize) {
+        self.unlink(node);
+        let removed = &mut self.nodes[node];
+        self.map.remove(&removed.key);
+        self.usage -= removed.block.len();
+        removed.block = Bytes::new();
+        self.free.push(node);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(len: usize) -> Bytes {
+        Bytes::from(vec![7; len])
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = BlockCache::new(300);
+        let (file, other) = (cache.file_id(), cache.file_id());
+        cache.insert(file, 0, block(100));
+        cache.insert(file, 1, block(100));
+        cache.insert(other, 0, block(100));
+        assert!(cache.get(file, 0).is_some());
+
+        // Block 1 of the first file is now the least recently used.
+        cache.insert(other, 1, block(100));
+        assert!(cache.get(file, 1).is_none());
+        assert_eq!(cache.get(file, 0).unwrap().len(), 100);
+        cache.insert(other, 2, block(301));
+        assert!(cache.get(other, 2).is_none());
+
+        cache.remove_file(other);
+        assert_eq!(
+            cache.stats(),
+            BlockCacheStats {
+                capacity: 300,
+                usage: 100,
+                blocks: 1,
+                hits: 2,
+                misses: 2,
+                evictions: 1,
+            }
+        );
+        assert_eq!(cache.stats().hit_rate(), 0.5);
+
+        cache.insert(file, 0, block(50));
+        assert_eq!(cache.stats().usage, 50);
+    }
+}
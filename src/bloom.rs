@@ -0,0 +1,106 @@
+use std::io;
+
+/// Bits per key used when no other setting is given, for a false positive rate near 1%.
+pub const DEFAULT_BITS_PER_KEY: usize = 10;
+
+/// A bloom filter over the keys of a segment, letting lookups skip segments that can't hold
+/// a key without reading any of their blocks.
+///
+/// Probes are derived from a single 64-bit hash by double hashing. Encoded as the bit array
+/// followed by one byte holding the number of probes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    probes: u8,
+}
+
+impl BloomFilter {
+    /// Builds a filter holding the given key hashes, as computed by `hash_key`.
+    pub fn build(hashes: &[u64], bits_per_key: usize) -> BloomFilter {
+        let bit_count = (hashes.len() * bits_per_key).max(64);
+        // ln(2) * bits per key minimizes the false positive rate.
+        let probes = ((bits_per_key as f64 * 0.69) as u8).clamp(1, 30);
+        let mut filter = BloomFilter {
+            bits: vec![0; bit_count.div_ceil(8)],
+            probes,
+        };
+        for &hash in hashes {
+            for bit in filter.probe_bits(hash) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    /// Returns whether the key may have been added; `false` means it certainly wasn't.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.may_contain_hash(hash_key(key))
+    }
+
+    /// Same as `may_contain`, for a hash computed by `hash_key`.
+    pub fn may_contain_hash(&self, hash: u64) -> bool {
+        self.probe_bits(hash)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// Returns the encoded filter.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = self.bits.clone();
+        bytes.push(self.probes);
+        bytes
+    }
+
+    /// Decodes a filter produced by `encode`.
+    pub fn decode(bytes: &[u8]) -> io::Result<BloomFilter> {
+        match bytes.split_last() {
+            Some((&probes, bits)) if !bits.is_empty() && (1..=30).contains(&probes) => {
+                Ok(BloomFilter {
+                    bits: bits.to_vec(),
+                    probes,
+                })
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "bad bloom filter")),
+        }
+    }
+
+    fn probe_bits(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let bit_count = (self.bits.len() * 8) as u64;
+        let delta = hash.rotate_right(33);
+        (0..self.probes as u64)
+            .map(move |probe| (hash.wrapping_add(probe.wrapping_mul(delta)) % bit_count) as usize)
+    }
+}
+
+/// Hashes a key for a bloom filter: FNV-1a followed by a final avalanche so every bit of
+/// the result depends on every byte of the key. Stable across builds, as filters are stored.
+pub fn hash_key(key: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in key {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_false_negatives_and_few_false_positives() {
+        let keys: Vec<String> = (0..1000).map(|i| format!("key{}", i)).collect();
+        let hashes: Vec<u64> = keys.iter().map(|key| hash_key(key.as_bytes())).collect();
+        let filter = BloomFilter::decode(&BloomFilter::build(&hashes, 10).encode()).unwrap();
+
+        assert!(keys.iter().all(|key| filter.may_contain(key.as_bytes())));
+        let false_positives = (0..10000)
+            .filter(|i| filter.may_contain(format!("other{}", i).as_bytes()))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+        assert!(BloomFilter::decode(&[]).is_err());
+    }
+}
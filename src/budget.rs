@@ -0,0 +1,54 @@
+use crate::disk::DiskEntry;
+use crate::error::FluxError;
+use std::mem;
+
+/// Tracks the memory a single read materializes on behalf of its caller, so one query over
+/// millions of entries fails with `FluxError::MemoryLimit` instead of exhausting the process.
+pub(crate) struct ReadBudget {
+    limit: Option<usize>,
+    used: usize,
+}
+
+impl ReadBudget {
+    /// Creates a budget allowing `limit` bytes, or any amount if `None`.
+    pub(crate) fn new(limit: Option<usize>) -> ReadBudget {
+        ReadBudget { limit, used: 0 }
+    }
+
+    /// Accounts for an entry held by the read: its key and value plus the fixed size of an
+    /// entry. Fails once the total passes the limit.
+    pub(crate) fn charge(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<(), FluxError> {
+        self.used += mem::size_of::<DiskEntry>()
+            + key.len()
+            + value.map_or(0, <[u8]>::len);
+        match self.limit {
+            Some(limit) if self.used > limit => Err(FluxError::MemoryLimit { limit }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fails_past_limit() {
+        let mut budget = ReadBudget::new(Some(1000));
+        let value = [0u8; 100];
+        let mut charged = 0;
+        let err = loop {
+            match budget.charge(b"Server", Some(&value)) {
+                Ok(()) => charged += 1,
+                Err(e) => break e,
+            }
+        };
+        assert!(charged > 0 && charged < 10, "{} entries charged", charged);
+        assert!(matches!(err, FluxError::MemoryLimit { limit: 1000 }));
+
+        let mut unlimited = ReadBudget::new(None);
+        for _ in 0..10_000 {
+            unlimited.charge(b"Server", Some(&value)).unwrap();
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::{Bound, Deref, Range, RangeBounds};
+use std::sync::Arc;
+
+/// An immutable, reference-counted byte buffer, or a slice of one.
+///
+/// Cloning or slicing a `Bytes` shares its buffer instead of copying it, so keys and values
+/// returned by reads can be kept, sent to other threads or cut into pieces without an
+/// allocation per copy.
+#[derive(Clone)]
+pub struct Bytes {
+    buffer: Arc<Vec<u8>>,
+    range: Range<usize>,
+}
+
+impl Bytes {
+    /// Returns an empty buffer.
+    pub fn new() -> Bytes {
+        Bytes::from(Vec::new())
+    }
+
+    /// Returns the bytes within `range` of this buffer, sharing it.
+    ///
+    /// Panics if the range is out of bounds, as slicing a `[u8]` does.
+    pub fn slice(&self, range: impl RangeBounds<usize>) -> Bytes {
+        let len = self.range.len();
+        let start = match range.start_bound() {
+            Bound::Included(&start) => start,
+            Bound::Excluded(&start) => start + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => end + 1,
+            Bound::Excluded(&end) => end,
+            Bound::Unbounded => len,
+        };
+        assert!(start <= end && end <= len, "range {}..{} out of bounds of {}", start, end, len);
+        Bytes {
+            buffer: self.buffer.clone(),
+            range: self.range.start + start..self.range.start + end,
+        }
+    }
+
+    /// Returns a copy of the bytes.
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.as_ref().to_vec()
+    }
+}
+
+impl Default for Bytes {
+    fn default() -> Bytes {
+        Bytes::new()
+    }
+}
+
+impl From<Vec<u8>> for Bytes {
+    /// Takes ownership of the vector without copying it.
+    fn from(buffer: Vec<u8>) -> Bytes {
+        let range = 0..buffer.len();
+        Bytes {
+            buffer: Arc::new(buffer),
+            range,
+        }
+    }
+}
+
+impl From<&[u8]> for Bytes {
+    fn from(bytes: &[u8]) -> Bytes {
+        Bytes::from(bytes.to_vec())
+    }
+}
+
+impl Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buffer[self.range.clone()]
+    }
+}
+
+impl AsRef<[u8]> for Bytes {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Borrow<[u8]> for Bytes {
+    fn borrow(&self) -> &[u8] {
+        self
+    }
+}
+
+impl PartialEq for Bytes {
+    fn eq(&self, other: &Bytes) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl Eq for Bytes {}
+
+impl PartialEq<[u8]> for Bytes {
+    fn eq(&self, other: &[u8]) -> bool {
+        &self[..] == other
+    }
+}
+
+impl Hash for Bytes {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self[..].hash(state)
+    }
+}
+
+impl fmt::Debug for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "b\"{}\"", self.escape_ascii())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slices_share_the_buffer() {
+        let bytes = Bytes::from(b"Database".to_vec());
+        let tail = bytes.slice(4..);
+        assert_eq!(&tail[..], b"base");
+        assert_eq!(&tail.slice(..=1)[..], b"ba");
+        assert!(Arc::ptr_eq(&bytes.buffer, &tail.buffer));
+        assert_eq!(bytes.clone(), Bytes::from(&b"Database"[..]));
+        assert_eq!(format!("{:?}", tail.slice(1..1)), "b\"\"");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_slice_out_of_bounds() {
+        Bytes::from(b"Server".to_vec()).slice(2..7);
+    }
+}
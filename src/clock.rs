@@ -0,0 +1,85 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of the timestamps writes are stored with and WAL files are named after, in
+/// microseconds since the Unix epoch. Cheap to clone; clones share their time.
+///
+/// Defaults to the system clock. `Clock::logical` makes them deterministic, so tests can
+/// compare exact file names, timestamps and bytes across runs.
+#[derive(Clone, Default)]
+pub struct Clock {
+    logical: Option<Arc<AtomicU64>>,
+}
+
+impl Clock {
+    /// The system clock.
+    pub fn system() -> Clock {
+        Clock::default()
+    }
+
+    /// A clock reading `start` first, then one microsecond more at every reading, so no
+    /// two readings are equal.
+    ///
+    /// Its time lives only in memory and isn't stored with the database. Reopening a
+    /// database with a new logical clock starts over from its `start`, so writes would be
+    /// timestamped before the ones already stored, and rejected as regressions by default:
+    /// reopen with the same clock, or a clone of it, or a `start` past every timestamp
+    /// written.
+    pub fn logical(start: u64) -> Clock {
+        Clock {
+            logical: Some(Arc::new(AtomicU64::new(start))),
+        }
+    }
+
+    /// Returns the current time in microseconds since the Unix epoch.
+    pub fn now_micros(&self) -> u128 {
+        match &self.logical {
+            Some(next) => next.fetch_add(1, Ordering::Relaxed) as u128,
+            None => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_micros(),
+        }
+    }
+
+    /// Returns the time the next reading would at least return, without advancing a
+    /// logical clock.
+    pub fn peek_micros(&self) -> u128 {
+        match &self.logical {
+            Some(next) => next.load(Ordering::Relaxed) as u128,
+            None => self.now_micros(),
+        }
+    }
+}
+
+impl fmt::Debug for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.logical {
+            Some(next) => f
+                .debug_tuple("Logical")
+                .field(&next.load(Ordering::Relaxed))
+                .finish(),
+            None => f.write_str("System"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_logical() {
+        let clock = Clock::logical(1000);
+        let shared = clock.clone();
+        assert_eq!(clock.now_micros(), 1000);
+        assert_eq!(shared.now_micros(), 1001);
+        assert_eq!(clock.now_micros(), 1002);
+        assert_eq!(format!("{:?}", clock), "Logical(1003)");
+        assert_eq!(clock.peek_micros(), 1003);
+        assert_eq!(shared.now_micros(), 1003);
+        assert!(Clock::system().now_micros() > 1_600_000_000_000_000);
+    }
+}
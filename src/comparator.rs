@@ -0,0 +1,187 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+
+/// Name recorded for the default byte order.
+pub const BYTEWISE: &str = "bytewise";
+
+/// A total order over keys, used in place of byte order by the memtables, segments and
+/// merges of a database through `DiskOptions::comparator`.
+///
+/// `compare` must only return `Equal` for identical keys: a case-insensitive order, for
+/// instance, should break ties between differently cased keys by their bytes. The order must
+/// never change for a database once written, since its files are sorted by it; its `name` is
+/// recorded in the manifest, and opening the database with a comparator of another name
+/// fails.
+pub trait Comparator: Send + Sync {
+    /// Returns the name identifying the order.
+    fn name(&self) -> &str;
+
+    /// Compares two keys.
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+}
+
+/// Orders keys by their bytes, the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BytewiseComparator;
+
+impl Comparator for BytewiseComparator {
+    fn name(&self) -> &str {
+        BYTEWISE
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        compare_keys(a, b)
+    }
+}
+
+/// Handle to the order keys are sorted in: byte order by default, or a `Comparator`.
+/// Cheap to clone.
+#[derive(Clone, Default)]
+pub struct KeyOrder {
+    // Byte order is compared directly rather than through a trait object.
+    comparator: Option<Arc<dyn Comparator>>,
+}
+
+impl KeyOrder {
+    /// Creates an order using `comparator`.
+    pub fn new<C: Comparator + 'static>(comparator: C) -> KeyOrder {
+        KeyOrder {
+            comparator: Some(Arc::new(comparator)),
+        }
+    }
+
+    /// Returns the name of the order.
+    pub fn name(&self) -> &str {
+        self.comparator.as_ref().map_or(BYTEWISE, |comparator| comparator.name())
+    }
+
+    /// Returns whether this is byte order, under whichever comparator.
+    pub fn is_bytewise(&self) -> bool {
+        self.name() == BYTEWISE
+    }
+
+    /// Compares two keys.
+    pub fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        match &self.comparator {
+            None => compare_keys(a, b),
+            Some(comparator) => comparator.compare(a, b),
+        }
+    }
+
+    /// Returns whether `key` falls within the bounds.
+    pub fn contains<'a, R: RangeBounds<&'a [u8]>>(&self, range: &R, key: &[u8]) -> bool {
+        let above_start = match range.start_bound() {
+            Bound::Included(start) => self.compare(start, key).is_le(),
+            Bound::Excluded(start) => self.compare(start, key).is_lt(),
+            Bound::Unbounded => true,
+        };
+        let below_end = match range.end_bound() {
+            Bound::Included(end) => self.compare(key, end).is_le(),
+            Bound::Excluded(end) => self.compare(key, end).is_lt(),
+            Bound::Unbounded => true,
+        };
+        above_start && below_end
+    }
+}
+
+impl fmt::Debug for KeyOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("KeyOrder").field(&self.name()).finish()
+    }
+}
+
+/// Compares two keys in byte order.
+///
+/// Long keys are compared 32 bytes at a time with AVX2 when the CPU supports it (detected at
+/// runtime), which keeps binary searches over long keys cheap.
+pub fn compare_keys(a: &[u8], b: &[u8]) -> Ordering {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if a.len().min(b.len()) >= 32 && std::arch::is_x86_feature_detected!("avx2") {
+            // SAFETY: the CPU supports AVX2, checked just above.
+            return unsafe { compare_keys_avx2(a, b) };
+        }
+    }
+    a.cmp(b)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn compare_keys_avx2(a: &[u8], b: &[u8]) -> Ordering {
+    use std::arch::x86_64::{__m256i, _mm256_cmpeq_epi8, _mm256_loadu_si256, _mm256_movemask_epi8};
+
+    let len = a.len().min(b.len());
+    let mut offset = 0;
+    while offset + 32 <= len {
+        // SAFETY: both slices hold at least `offset + 32` bytes; loads are unaligned.
+        let (left, right) = unsafe {
+            (
+                _mm256_loadu_si256(a.as_ptr().add(offset) as *const __m256i),
+                _mm256_loadu_si256(b.as_ptr().add(offset) as *const __m256i),
+            )
+        };
+        let equal = _mm256_movemask_epi8(_mm256_cmpeq_epi8(left, right)) as u32;
+        if equal != u32::MAX {
+            let index = offset + (!equal).trailing_zeros() as usize;
+            return a[index].cmp(&b[index]);
+        }
+        offset += 32;
+    }
+    a[offset..].cmp(&b[offset..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_byte_order() {
+        let base: Vec<u8> = (0..100u8).collect();
+        let mut keys = vec![base.clone(), base[..64].to_vec(), base[..31].to_vec(), Vec::new()];
+        for index in [0, 31, 32, 63, 64, 99] {
+            let mut higher = base.clone();
+            higher[index] = 200;
+            keys.push(higher);
+            let mut lower = base.clone();
+            lower[index] = 0;
+            lower[index..].iter_mut().for_each(|byte| *byte = 0);
+            keys.push(lower);
+        }
+
+        for a in keys.iter() {
+            for b in keys.iter() {
+                assert_eq!(compare_keys(a, b), a.cmp(b), "{:?} vs {:?}", a, b);
+            }
+        }
+    }
+
+    struct Reverse;
+
+    impl Comparator for Reverse {
+        fn name(&self) -> &str {
+            "reverse"
+        }
+
+        fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+            b.cmp(a)
+        }
+    }
+
+    #[test]
+    fn test_key_order() {
+        let bytewise = KeyOrder::default();
+        assert!(bytewise.is_bytewise() && KeyOrder::new(BytewiseComparator).is_bytewise());
+        assert_eq!(bytewise.compare(b"a", b"b"), Ordering::Less);
+        assert!(bytewise.contains(&(&b"a"[..]..&b"c"[..]), b"b"));
+
+        let reverse = KeyOrder::new(Reverse);
+        assert_eq!(reverse.name(), "reverse");
+        assert!(!reverse.is_bytewise());
+        assert_eq!(reverse.compare(b"a", b"b"), Ordering::Greater);
+        assert!(reverse.contains(&(&b"c"[..]..&b"a"[..]), b"b"));
+        assert!(!reverse.contains(&(&b"c"[..]..&b"a"[..]), b"a"));
+        assert!(!reverse.contains(&(&b"a"[..]..), b"b"));
+    }
+}
@@ -0,0 +1,188 @@
+use std::io;
+
+/// Compression codecs that can be applied to payloads written by the engine.
+///
+/// Every variant is always known to the reader so that file headers can be parsed,
+/// but a codec can only be used when its cargo feature (`lz4`, `snappy`, `zstd`) is enabled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Snappy,
+    Zstd,
+}
+
+impl Compression {
+    /// Returns the identifier stored in file headers for this codec.
+    pub fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Snappy => 2,
+            Compression::Zstd => 3,
+        }
+    }
+
+    /// Resolves a codec from the identifier stored in a file header.
+    pub fn from_id(id: u8) -> io::Result<Compression> {
+        match id {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            2 => Ok(Compression::Snappy),
+            3 => Ok(Compression::Zstd),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown compression codec id {}", id),
+            )),
+        }
+    }
+
+    /// Returns the human readable name of the codec.
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Lz4 => "lz4",
+            Compression::Snappy => "snappy",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// Returns whether support for this codec was compiled into the binary.
+    pub fn is_available(self) -> bool {
+        match self {
+            Compression::None => true,
+            Compression::Lz4 => cfg!(feature = "lz4"),
+            Compression::Snappy => cfg!(feature = "snappy"),
+            Compression::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+
+    /// Fails with `Unsupported` if the codec was not compiled into the binary.
+    pub fn ensure_available(self) -> io::Result<()> {
+        if self.is_available() {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "compression codec {} requires the `{}` feature",
+                    self.name(),
+                    self.name()
+                ),
+            ))
+        }
+    }
+
+    /// Compresses a payload with this codec.
+    pub fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.ensure_available()?;
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+            #[cfg(feature = "snappy")]
+            Compression::Snappy => snap::raw::Encoder::new()
+                .compress_vec(data)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::bulk::compress(data, 0),
+            #[allow(unreachable_patterns)]
+            _ => unreachable!("availability checked above"),
+        }
+    }
+
+    /// Decompresses a payload previously produced by `compress` with the same codec.
+    pub fn decompress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.ensure_available()?;
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(data)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            #[cfg(feature = "snappy")]
+            Compression::Snappy => snap::raw::Decoder::new()
+                .decompress_vec(data)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::stream::decode_all(data),
+            #[allow(unreachable_patterns)]
+            _ => unreachable!("availability checked above"),
+        }
+    }
+
+    /// Decompresses a payload like `decompress`, failing with `InvalidData` instead of
+    /// allocating if it decompresses to more than `limit` bytes.
+    pub fn decompress_within(self, data: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        self.ensure_available()?;
+        let too_large = || io::Error::new(io::ErrorKind::InvalidData, "payload too large");
+        match self {
+            Compression::None if data.len() > limit => Err(too_large()),
+            Compression::None => Ok(data.to_vec()),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                let (size, data) = lz4_flex::block::uncompressed_size(data)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                if size > limit {
+                    return Err(too_large());
+                }
+                lz4_flex::decompress(data, size)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+            #[cfg(feature = "snappy")]
+            Compression::Snappy => {
+                let size = snap::raw::decompress_len(data)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                if size > limit {
+                    return Err(too_large());
+                }
+                snap::raw::Decoder::new()
+                    .decompress_vec(data)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::bulk::decompress(data, limit),
+            #[allow(unreachable_patterns)]
+            _ => unreachable!("availability checked above"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codec_ids_roundtrip() {
+        for codec in [
+            Compression::None,
+            Compression::Lz4,
+            Compression::Snappy,
+            Compression::Zstd,
+        ] {
+            assert_eq!(Compression::from_id(codec.id()).unwrap(), codec);
+        }
+        assert!(Compression::from_id(42).is_err());
+    }
+
+    #[test]
+    fn test_available_codecs_roundtrip() {
+        let payload = b"GraphQL GraphQL GraphQL GraphQL GraphQL GraphQL".repeat(8);
+        for codec in [
+            Compression::None,
+            Compression::Lz4,
+            Compression::Snappy,
+            Compression::Zstd,
+        ] {
+            if !codec.is_available() {
+                assert!(codec.compress(&payload).is_err());
+                continue;
+            }
+            let compressed = codec.compress(&payload).unwrap();
+            assert_eq!(codec.decompress(&compressed).unwrap(), payload);
+            let within = codec.decompress_within(&compressed, payload.len());
+            assert_eq!(within.unwrap(), payload);
+            assert!(codec.decompress_within(&compressed, payload.len() - 1).is_err());
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use crate::disk::{Disk, DiskEntry};
+use crate::error::FluxError;
+use crate::snapshot::Snapshot;
+use std::collections::{HashMap, VecDeque};
+use std::ops::Bound;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Number of entries a cursor reads ahead in the direction it moves.
+const CURSOR_PAGE_SIZE: usize = 64;
+/// Time after which the servers close a cursor their client stopped reading.
+pub const DEFAULT_CURSOR_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// A position in the database as of when the cursor was opened, which can be moved to a key
+/// and from there in either direction. Created by `Disk::cursor`.
+///
+/// The cursor reads through a snapshot, so it sees the same state of the database however
+/// long it is kept. Entries are read a page at a time in the direction the cursor moves,
+/// each page from the memtables and segments live when it is read, which stay pinned for
+/// that read alone: flushes and compactions go on while the cursor is open, and the files
+/// they retire are deleted once no read holds them.
+pub struct Cursor {
+    snapshot: Snapshot,
+    current: Option<DiskEntry>,
+    /// Entries read ahead of `current`, nearest first.
+    ahead: VecDeque<DiskEntry>,
+    /// Whether `ahead` holds the entries before `current` rather than after it.
+    reverse: bool,
+}
+
+type KeyRange<'a> = (Bound<&'a [u8]>, Bound<&'a [u8]>);
+
+impl Cursor {
+    pub(crate) fn new(snapshot: Snapshot) -> Cursor {
+        Cursor {
+            snapshot,
+            current: None,
+            ahead: VecDeque::new(),
+            reverse: false,
+        }
+    }
+
+    /// Returns the sequence number of the last write the cursor sees.
+    pub fn sequence(&self) -> u64 {
+        self.snapshot.sequence()
+    }
+
+    /// Returns the entry the cursor is at, or `None` if it isn't positioned or has moved
+    /// past either end.
+    pub fn entry(&self) -> Option<&DiskEntry> {
+        self.current.as_ref()
+    }
+
+    /// Moves to the first entry whose key is at or after `key`.
+    pub fn seek(&mut self, key: &[u8]) -> Result<Option<&DiskEntry>, FluxError> {
+        self.position((Bound::Included(key), Bound::Unbounded), false)
+    }
+
+    /// Moves to the last entry whose key is at or before `key`.
+    pub fn seek_for_prev(&mut self, key: &[u8]) -> Result<Option<&DiskEntry>, FluxError> {
+        self.position((Bound::Unbounded, Bound::Included(key)), true)
+    }
+
+    /// Moves to the first entry of the database.
+    pub fn seek_to_first(&mut self) -> Result<Option<&DiskEntry>, FluxError> {
+        self.position((Bound::Unbounded, Bound::Unbounded), false)
+    }
+
+    /// Moves to the last entry of the database.
+    pub fn seek_to_last(&mut self) -> Result<Option<&DiskEntry>, FluxError> {
+        self.position((Bound::Unbounded, Bound::Unbounded), true)
+    }
+
+    /// Moves to the entry after the current one. Past the last entry the cursor is no longer
+    /// positioned, and stays so until the next seek.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<Option<&DiskEntry>, FluxError> {
+        self.step(false)
+    }
+
+    /// Moves to the entry before the current one. Past the first entry the cursor is no
+    /// longer positioned, and stays so until the next seek.
+    pub fn prev(&mut self) -> Result<Option<&DiskEntry>, FluxError> {
+        self.step(true)
+    }
+
+    fn position(
+        &mut self,
+        range: KeyRange<'_>,
+        reverse: bool,
+    ) -> Result<Option<&DiskEntry>, FluxError> {
+        self.current = None;
+        self.read_ahead(range, reverse)?;
+        self.current = self.ahead.pop_front();
+        Ok(self.current.as_ref())
+    }
+
+    fn step(&mut self, reverse: bool) -> Result<Option<&DiskEntry>, FluxError> {
+        let Some(current) = self.current.take() else {
+            return Ok(None);
+        };
+        if reverse != self.reverse || self.ahead.is_empty() {
+            let key = Bound::Excluded(current.key());
+            let range = match reverse {
+                false => (key, Bound::Unbounded),
+                true => (Bound::Unbounded, key),
+            };
+            if let Err(e) = self.read_ahead(range, reverse) {
+                self.current = Some(current);
+                return Err(e);
+            }
+        }
+        self.current = self.ahead.pop_front();
+        Ok(self.current.as_ref())
+    }
+
+    fn read_ahead(&mut self, range: KeyRange<'_>, reverse: bool) -> Result<(), FluxError> {
+        let (disk, sequence) = (self.snapshot.disk(), self.snapshot.sequence());
+        let page = match reverse {
+            false => disk.scan_at(range, sequence, CURSOR_PAGE_SIZE)?,
+            true => disk.scan_rev_at(range, sequence, CURSOR_PAGE_SIZE)?,
+        };
+        self.ahead = page.into();
+        self.reverse = reverse;
+        Ok(())
+    }
+}
+
+/// Server-side cursors, letting remote clients page through a scan without re-reading it
+/// from the start.
+///
+/// Each cursor pins a snapshot taken when it was opened, so every page reads the same
+/// state of the database. Cursors are closed once exhausted or by `close`, and cursors left
+/// idle for longer than the idle timeout are closed on the next call, so a client going
+/// away can't pin old versions forever.
+pub struct CursorTable {
+    disk: Disk,
+    idle_timeout: Duration,
+    state: Mutex<CursorState>,
+}
+
+#[derive(Default)]
+struct CursorState {
+    cursors: HashMap<u64, OpenCursor>,
+    next_id: u64,
+}
+
+struct OpenCursor {
+    snapshot: Snapshot,
+    /// Where the next page starts: after the last key returned.
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    last_used: Instant,
+}
+
+impl CursorTable {
+    pub fn new(disk: Disk, idle_timeout: Duration) -> CursorTable {
+        CursorTable {
+            disk,
+            idle_timeout,
+            state: Mutex::new(CursorState::default()),
+        }
+    }
+
+    /// Opens a cursor over the range, reading the database as of now, and returns its id.
+    pub fn open(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> u64 {
+        let cursor = OpenCursor {
+            snapshot: self.disk.snapshot(),
+            start,
+            end,
+            last_used: Instant::now(),
+        };
+        let mut state = self.lock_expiring_idle();
+        state.next_id += 1;
+        let id = state.next_id;
+        state.cursors.insert(id, cursor);
+        id
+    }
+
+    /// Returns the next page of at most `limit` entries, which must be at least 1. The
+    /// cursor is closed once a page comes back shorter than `limit`, as nothing is left to
+    /// read.
+    pub fn next(&self, id: u64, limit: usize) -> Result<Vec<DiskEntry>, FluxError> {
+        if limit == 0 {
+            return Err(FluxError::InvalidArgument(
+                "a cursor page holds at least one entry".to_owned(),
+            ));
+        }
+        let mut state = self.lock_expiring_idle();
+        let cursor = state.cursors.get_mut(&id).ok_or_else(|| {
+            FluxError::InvalidArgument(format!("cursor {} is unknown or expired", id))
+        })?;
+        let start = cursor.start.as_ref().map(Vec::as_slice);
+        let page = cursor
+            .snapshot
+            .scan_page((start, cursor.end.as_ref().map(Vec::as_slice)), limit)?;
+        cursor.last_used = Instant::now();
+        match page.last() {
+            Some(last) if page.len() >= limit => {
+                cursor.start = Bound::Excluded(last.key().to_vec());
+            }
+            _ => {
+                state.cursors.remove(&id);
+            }
+        }
+        Ok(page)
+    }
+
+    /// Closes a cursor, releasing its snapshot. Returns whether it was open.
+    pub fn close(&self, id: u64) -> bool {
+        self.lock_expiring_idle().cursors.remove(&id).is_some()
+    }
+
+    /// Returns the number of open cursors.
+    pub fn len(&self) -> usize {
+        self.lock_expiring_idle().cursors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock_expiring_idle(&self) -> std::sync::MutexGuard<'_, CursorState> {
+        let mut state = self.state.lock().unwrap();
+        let idle_timeout = self.idle_timeout;
+        state
+            .cursors
+            .retain(|_, cursor| cursor.last_used.elapsed() < idle_timeout);
+        state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use std::fs::{create_dir_all, remove_dir_all};
+
+    fn key(entry: Option<&DiskEntry>) -> Option<String> {
+        entry.map(|entry| String::from_utf8(entry.key().to_vec()).unwrap())
+    }
+
+    #[test]
+    fn test_seek_and_move_both_ways() {
+        let mut rng = rand::thread_rng();
+        let test_dir = format!("./{}/", rng.gen::<u32>());
+        create_dir_all(&test_dir).unwrap();
+
+        let disk = Disk::new(&test_dir);
+        for i in (0..200).step_by(2) {
+            disk.set(format!("key{:03}", i).as_bytes(), b"nginx").unwrap();
+        }
+        disk.compact().unwrap();
+        // Newer versions and deletions in the memtable shadow the segment.
+        disk.set(b"key100", b"apache").unwrap();
+        disk.delete(b"key098").unwrap();
+        disk.delete_range(b"key010", b"key020").unwrap();
+
+        let mut cursor = disk.cursor();
+        disk.set(b"key101", b"caddy").unwrap();
+        assert_eq!(key(cursor.seek(b"key099").unwrap()).unwrap(), "key100");
+        assert_eq!(cursor.entry().unwrap().value(), b"apache");
+        assert_eq!(key(cursor.next().unwrap()).unwrap(), "key102");
+        assert_eq!(key(cursor.prev().unwrap()).unwrap(), "key100");
+        assert_eq!(key(cursor.prev().unwrap()).unwrap(), "key096");
+        assert_eq!(key(cursor.seek_for_prev(b"key021").unwrap()).unwrap(), "key020");
+        assert_eq!(key(cursor.prev().unwrap()).unwrap(), "key008");
+
+        // Every key before key150, read backwards across pages.
+        cursor.seek_for_prev(b"key149").unwrap();
+        let mut keys = Vec::new();
+        while let Some(key) = key(cursor.entry()) {
+            keys.push(key);
+            cursor.prev().unwrap();
+        }
+        let mut expected: Vec<String> = (0..150)
+            .step_by(2)
+            .filter(|i| *i != 98 && !(10..20).contains(i))
+            .map(|i| format!("key{:03}", i))
+            .collect();
+        expected.reverse();
+        assert_eq!(keys, expected);
+
+        assert_eq!(key(cursor.seek_to_last().unwrap()).unwrap(), "key198");
+        assert!(cursor.next().unwrap().is_none());
+        assert!(cursor.prev().unwrap().is_none());
+        assert_eq!(key(cursor.seek_to_first().unwrap()).unwrap(), "key000");
+        assert!(cursor.prev().unwrap().is_none());
+        assert!(cursor.seek(b"key999").unwrap().is_none());
+
+        drop(cursor);
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_pages_through_a_pinned_snapshot() {
+        let mut rng = rand::thread_rng();
+        let test_dir = format!("./{}/", rng.gen::<u32>());
+        create_dir_all(&test_dir).unwrap();
+
+        let disk = Disk::new(&test_dir);
+        for i in 0..25 {
+            disk.set(format!("key{:02}", i).as_bytes(), b"nginx").unwrap();
+        }
+        let cursors = CursorTable::new(disk.clone(), Duration::from_secs(60));
+        let id = cursors.open(Bound::Included(b"key05".to_vec()), Bound::Unbounded);
+
+        let mut keys = Vec::new();
+        loop {
+            let page = cursors.next(id, 8).unwrap();
+            // Writes made while paging are not seen by the cursor.
+            disk.set(b"key99", b"apache").unwrap();
+            disk.delete(b"key20").unwrap();
+            keys.extend(page.iter().map(|entry| entry.key().to_vec()));
+            if page.len() < 8 {
+                break;
+            }
+        }
+        let expected: Vec<Vec<u8>> =
+            (5..25).map(|i| format!("key{:02}", i).into_bytes()).collect();
+        assert_eq!(keys, expected);
+        assert!(cursors.is_empty());
+        assert!(matches!(cursors.next(id, 8), Err(FluxError::InvalidArgument(_))));
+
+        // An empty page would close the cursor without reading anything.
+        let id = cursors.open(Bound::Unbounded, Bound::Unbounded);
+        assert!(matches!(cursors.next(id, 0), Err(FluxError::InvalidArgument(_))));
+        assert_eq!(cursors.next(id, 1).unwrap().len(), 1);
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_idle_cursors_expire() {
+        let mut rng = rand::thread_rng();
+        let test_dir = format!("./{}/", rng.gen::<u32>());
+        create_dir_all(&test_dir).unwrap();
+
+        let disk = Disk::new(&test_dir);
+        disk.set(b"Server", b"nginx").unwrap();
+        let cursors = CursorTable::new(disk.clone(), Duration::from_millis(20));
+        let idle = cursors.open(Bound::Unbounded, Bound::Unbounded);
+        let closed = cursors.open(Bound::Unbounded, Bound::Unbounded);
+        assert!(cursors.close(closed));
+        assert!(!cursors.close(closed));
+
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(cursors.next(idle, 10).is_err());
+        assert_eq!(cursors.len(), 0);
+        // Expired cursors no longer pin their snapshot.
+        assert!(disk.live_snapshots().is_empty());
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+}
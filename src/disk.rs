@@ -1,13 +1,107 @@
-use crate::mem_table::InMemoryTable;
-use crate::wal::WAL;
-use std::path::PathBuf;
-use std::time::{SystemTime, UNIX_EPOCH};
+use crate::arena::{ArenaBytes, ARENA_CHUNK_SIZE};
+use crate::backup::{self, BackupInfo};
+use crate::budget::ReadBudget;
+use crate::block_cache::BlockCache;
+use crate::bytes::Bytes;
+use crate::comparator::KeyOrder;
+use crate::cursor::Cursor;
+use crate::dump::{DumpReader, DumpRecord, DumpWriter};
+use crate::error::FluxError;
+use crate::health::{Health, HealthMonitor};
+use crate::invalidation::{Granularity, InvalidationFeed, Invalidations};
+use crate::lock_metrics::{LockMetrics, LockMetricsSnapshot};
+use crate::logging::{Level, Subsystem};
+use crate::manifest::{
+  file_name, is_database_file_name, load_history_with, EditReason, Manifest, ManifestEdit,
+  QUARANTINE_EXTENSION, WAL_EXTENSION,
+};
+use crate::mem_table::{InMemoryRecord, InMemoryTable};
+use crate::metrics;
+use crate::merge::{EntrySource, MergeIterator, RetainVersions};
+use crate::options::DiskOptions;
+use crate::prefix::prefix_end;
+use crate::scan_iterator::{decode_continuation, encode_continuation, Checkpoint, ScanIterator};
+use crate::read_options::ReadOptions;
+use crate::scan_options::{ScanFilter, ScanOptions};
+use crate::scrub::{CorruptBlock, ScrubOptions, ScrubReport};
+use crate::single_file::SingleFileBackend;
+use crate::snapshot::{stripe, Snapshot, SnapshotList};
+use crate::sstable::{BlockReads, Entry, RangeTombstone, SSTable, SSTableWriter};
+use crate::stats::{
+  DiskStats, Histogram, KeyDistribution, LatencyHistograms, LevelStats, LiveFileInfo, Operation,
+  Statistics, StatisticsSnapshot, TombstoneDensity, TreeStats,
+};
+use crate::storage::{MemoryBackend, Storage, StorageLock, LOCK_FILE};
+use crate::subscription::{Change, ChangeEvent, ChangeKind, Subscribers};
+use crate::transaction::Transaction;
+use crate::wal::{find_wal_files_with, FollowedWal, WAL};
+use crate::wal_iterator::LogFileIterator;
+#[cfg(feature = "async")]
+use crate::wal_tail::AsyncWalTail;
+use crate::wal_tail::{record_event, WalTail, TAIL_CAPACITY};
+use crate::write_batch::{Op, TimestampRegression, WriteBatch, WriteOptions, MAX_BATCH_BYTES};
+use crate::rate_limiter::IoPriority;
+use crate::value_log::{pointed_value_size, ValueLog, ValueLogStats, ValueLogWriter};
+use crate::write_stall::{Stall, StallPolicy};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::seq::{index, SliceRandom};
+use rand::Rng;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs::{self, create_dir_all, File};
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::ops::{Bound, Deref, Range, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{
+  Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak,
+};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Pause before retrying a flush or compaction that failed.
+const BACKGROUND_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// How often the background thread checks whether retired files are still being read.
+const OBSOLETE_FILE_RECHECK: Duration = Duration::from_secs(1);
+/// Attempts a follower makes at catching up while its primary retires the files it reads.
+const CATCH_UP_ATTEMPTS: usize = 3;
+/// How often a write stopped by `DiskOptions::write_stall` checks whether it may go on.
+const STALL_RECHECK: Duration = Duration::from_millis(100);
+/// Least number of keys a scan copies out of a memtable at a time, however few it returns.
+const MEM_TABLE_SCAN_KEYS: usize = 256;
+/// Number of entries `export_to` reads at a time.
+const EXPORT_PAGE_SIZE: usize = 1024;
+/// Fixed-point scale of the rolling read amplification average.
+const READ_AMPLIFICATION_SCALE: u64 = 1000;
+/// Number of most common key prefixes `Disk::key_distribution` returns.
+const TOP_PREFIXES: usize = 10;
+/// Weight of the latest lookup in the rolling read amplification average, as a fraction.
+const READ_AMPLIFICATION_WINDOW: u64 = 64;
+/// Directory the files of an in-memory database are named under.
+const IN_MEMORY_DIR: &str = "memory";
+/// Leading bytes of a key that slow-operation warnings show, in hex. Keys may hold user data,
+/// so the logs get enough to tell hot keys apart and no more.
+const LOGGED_KEY_BYTES: usize = 4;
+
+/// What `read_range_versions` returns of each key.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RangeRead {
+  /// The live keys with their values.
+  Live,
+  /// The live keys, with empty values.
+  Keys,
+  /// The live keys with their values, and the deleted ones as tombstones.
+  Tombstones,
+}
 
 #[derive(Debug)]
 pub struct DiskEntry {
-  key: Vec<u8>,
-  value: Vec<u8>,
+  key: Bytes,
+  value: Bytes,
   timestamp: u128,
+  sequence: u64,
 }
 
 impl DiskEntry {
@@ -19,75 +113,7972 @@ impl DiskEntry {
     &self.value
   }
 
+  /// Returns the key as a `Bytes` sharing the entry's buffer, which outlives the entry
+  /// without copying it.
+  pub fn key_bytes(&self) -> Bytes {
+    self.key.clone()
+  }
+
+  /// Returns the value as a `Bytes` sharing the entry's buffer, as `key_bytes` does.
+  pub fn value_bytes(&self) -> Bytes {
+    self.value.clone()
+  }
+
+  pub fn timestamp(&self) -> u128 {
+    self.timestamp
+  }
+
+  /// Returns the sequence number of the write that stored this value.
+  pub fn sequence(&self) -> u64 {
+    self.sequence
+  }
+
+  /// Converts a stored entry, or returns `None` for a tombstone.
+  fn from_entry(entry: Entry) -> Option<DiskEntry> {
+    Some(DiskEntry {
+      key: entry.key.into(),
+      value: entry.value?.into(),
+      timestamp: entry.timestamp,
+      sequence: entry.sequence,
+    })
+  }
+}
+
+/// A version of a key, as returned by `Disk::history`.
+#[derive(Debug)]
+pub struct Version {
+  value: Option<Bytes>,
+  timestamp: u128,
+  sequence: u64,
+}
+
+impl Version {
+  /// Returns the value written, or `None` if the version deletes the key.
+  pub fn value(&self) -> Option<&[u8]> {
+    self.value.as_deref()
+  }
+
+  pub fn is_deleted(&self) -> bool {
+    self.value.is_none()
+  }
+
   pub fn timestamp(&self) -> u128 {
     self.timestamp
   }
+
+  /// Returns the sequence number of the write.
+  pub fn sequence(&self) -> u64 {
+    self.sequence
+  }
+
+  fn from_entry(entry: Entry) -> Version {
+    Version {
+      value: entry.value.map(Bytes::from),
+      timestamp: entry.timestamp,
+      sequence: entry.sequence,
+    }
+  }
+}
+
+/// The value of a key, read in place where possible. See `Disk::get_pinned`.
+pub struct PinnedValue<'a> {
+  pinned: Pinned,
+  /// Keeps the value from outliving the handle it was read through.
+  disk: PhantomData<&'a Disk>,
+}
+
+enum Pinned {
+  /// A value in a memtable, kept alive by its arena chunk.
+  MemTable(ArenaBytes),
+  /// A value read from a segment.
+  Owned(Bytes),
+}
+
+impl PinnedValue<'_> {
+  /// Returns the value as a `Bytes`. Values pinned in a memtable are copied; values read
+  /// from segments are shared.
+  pub fn into_bytes(self) -> Bytes {
+    match self.pinned {
+      Pinned::Owned(value) => value,
+      _ => Bytes::from(self.to_vec()),
+    }
+  }
+}
+
+impl Deref for PinnedValue<'_> {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    match &self.pinned {
+      Pinned::MemTable(value) => value,
+      Pinned::Owned(value) => value,
+    }
+  }
+}
+
+impl AsRef<[u8]> for PinnedValue<'_> {
+  fn as_ref(&self) -> &[u8] {
+    self
+  }
+}
+
+impl fmt::Debug for PinnedValue<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_tuple("PinnedValue").field(&&self[..]).finish()
+  }
 }
 
+/// Handle to an open database.
+///
+/// The handle is cheap to clone and can be shared across threads: readers take a shared lock
+/// on the memtables and never block each other, while writers are serialized on the WAL.
+/// Full memtables are flushed to segment files by a background thread, which stops once the
+/// last handle is dropped.
+#[derive(Clone)]
 pub struct Disk {
-  mem_table: InMemoryTable,
+  inner: Arc<DiskInner>,
+  // Stops the background thread once the last handle is dropped.
+  _worker: Arc<BackgroundWorker>,
+}
+
+/// A cloneable, thread-safe handle to an open database.
+pub type Db = Disk;
+
+struct DiskInner {
+  dir: PathBuf,
+  options: DiskOptions,
+  mem_tables: RwLock<MemTables>,
+  /// Live segments, newest first. Replaced as a whole so readers can keep using a snapshot.
+  segments: RwLock<Arc<Vec<Arc<SSTable>>>>,
+  log: Mutex<WriteLog>,
+  /// Sequence number of the last write applied to the memtable, and so visible to readers.
+  visible_sequence: AtomicU64,
+  snapshots: SnapshotList,
+  lock_metrics: LockMetrics,
+  stats: Statistics,
+  /// Report of the last pass of the background scrubber, once one has finished.
+  last_scrub: Mutex<Option<ScrubReport>>,
+  /// Rolling average of the segments read per lookup, scaled by `READ_AMPLIFICATION_SCALE`.
+  read_amplification: AtomicU64,
+  /// Set once a compaction has been asked for because of `read_amplification`.
+  read_compaction_requested: AtomicBool,
+  /// Sequence number of the last write at the start of each recent compaction, oldest
+  /// first, for `TombstoneRetention::Compactions`.
+  compaction_rounds: Mutex<VecDeque<u64>>,
+  /// Size of the frozen memtables waiting to be flushed, for `DiskOptions::write_stall`.
+  pending_bytes: AtomicUsize,
+  /// Files holding the values moved out of the segments.
+  value_log: ValueLog,
+  /// Files retired from the manifest, waiting to be deleted.
+  obsolete: Mutex<Vec<ObsoleteFile>>,
+  invalidations: Invalidations,
+  subscribers: Subscribers,
+  /// Whether the directory accepts writes; every write to it is checked against this.
+  health: Arc<HealthMonitor>,
+  /// Group commit of writes under `sync_writes`. Never held while taking another lock.
+  sync: Mutex<SyncState>,
+  sync_changed: Condvar,
+  work: Mutex<WorkState>,
+  work_changed: Condvar,
+  /// How often a follower catches up with its primary; `None` for a database of its own.
+  follow_interval: Option<Duration>,
+  /// Keeps other handles, in this process or another, from opening the directory while
+  /// this one has it open. Followers take none.
+  _lock: StorageLock,
+}
+
+/// How far the WAL is known to be durable.
+struct SyncState {
+  /// Sequence number of the last write synced.
+  synced: u64,
+  /// Whether a writer is syncing the WAL on behalf of the others.
+  syncing: bool,
+  /// Kind and message of the sync that failed, after which none is trusted again.
+  failed: Option<(io::ErrorKind, String)>,
+}
+
+/// The memtable receiving writes, and the full ones waiting to be flushed.
+struct MemTables {
+  active: InMemoryTable,
+  /// Frozen memtables, oldest first.
+  immutable: Vec<ImmutableMemTable>,
+}
+
+impl MemTables {
+  /// Returns the range tombstones of every memtable written at or before `sequence`.
+  fn range_tombstones(&self, sequence: u64) -> Vec<RangeTombstone> {
+    let frozen = self.immutable.iter().map(|frozen| frozen.table.as_ref());
+    std::iter::once(&self.active)
+      .chain(frozen)
+      .flat_map(|table| visible_range_tombstones(table.range_tombstones(), sequence))
+      .collect()
+  }
+}
+
+/// The frozen memtables and segments a read goes through, pinned together while the
+/// memtables lock is held. A flush publishes its segment before dropping the memtable it
+/// wrote, and a compaction swaps its inputs for its output at once, so a view finds every
+/// write at least once, even with a flush or compaction finishing meanwhile. The view keeps
+/// what it pinned alive for as long as the read needs it: a retired segment file is only
+/// deleted once no view holds the segment.
+struct ReadView {
+  /// Frozen memtables, newest first.
+  immutable: Vec<Arc<InMemoryTable>>,
+  /// Live segments, newest first.
+  segments: Arc<Vec<Arc<SSTable>>>,
+}
+
+/// A frozen memtable along with the WAL files holding its records, which are retired once
+/// it has been written to a segment.
+#[derive(Clone)]
+struct ImmutableMemTable {
+  table: Arc<InMemoryTable>,
+  wal_files: Vec<String>,
+  /// Total size of `wal_files`.
+  wal_bytes: u64,
+}
+
+/// A file retired from the manifest by a flush or a compaction, deleted once its grace
+/// period is over and nothing reads it any more.
+struct ObsoleteFile {
+  name: String,
+  subsystem: Subsystem,
+  retired_at: Instant,
+  /// The segment the file holds, which scans and iterators may still have open.
+  segment: Option<Weak<SSTable>>,
+}
+
+impl ObsoleteFile {
+  /// Returns whether no segment handle is left reading the file.
+  fn is_unread(&self) -> bool {
+    self.segment.as_ref().is_none_or(|segment| segment.strong_count() == 0)
+  }
+}
+
+/// State owned by the write path. Writers hold its lock while appending to the WAL and
+/// applying the write to the memtable, so both see writes in the same order. Every change
+/// to the manifest is made under this lock too.
+struct WriteLog {
   wal: WAL,
+  manifest: Manifest,
+  /// WAL files holding records of the active memtable, oldest first.
+  active_wal_files: Vec<String>,
+  /// Sequence number of the last logged write.
+  last_sequence: u64,
+  /// Size of the sealed live WAL files, those of frozen memtables included.
+  sealed_wal_bytes: u64,
+  /// Size of the sealed WAL files holding records of the active memtable.
+  active_sealed_wal_bytes: u64,
+  /// Size of the active WAL file already counted in the statistics.
+  counted_wal_size: u64,
+  /// When the active memtable received its first write, for `memtable_max_age`.
+  active_since: Option<Instant>,
+  /// The primary's WAL files as read so far, for a follower.
+  followed: Option<FollowedWal>,
 }
 
-impl Disk {
-  pub fn new(dir: &str) -> Disk {
-    let dir = PathBuf::from(dir);
+impl WriteLog {
+  /// Returns the size of every live WAL file.
+  fn total_wal_bytes(&self) -> u64 {
+    self.sealed_wal_bytes + self.wal.size()
+  }
+}
+
+/// Size and kind of the operations applied by a write, for the statistics.
+#[derive(Default)]
+struct Written {
+  bytes: usize,
+  puts: u64,
+  deletes: u64,
+}
+
+impl Written {
+  fn put(bytes: usize) -> Written {
+    Written {
+      bytes,
+      puts: 1,
+      deletes: 0,
+    }
+  }
+
+  fn delete(bytes: usize) -> Written {
+    Written {
+      bytes,
+      puts: 0,
+      deletes: 1,
+    }
+  }
+
+  fn add(self, other: Written) -> Written {
+    Written {
+      bytes: self.bytes + other.bytes,
+      puts: self.puts + other.puts,
+      deletes: self.deletes + other.deletes,
+    }
+  }
+}
+
+/// Coordination between the write path and the background thread.
+#[derive(Default)]
+struct WorkState {
+  requested: bool,
+  running: bool,
+  shutdown: bool,
+  /// Number of flushes and compactions callers of `Disk::flush`, `Disk::compact` and
+  /// `Disk::compact_range` wait for, and how many of them are done.
+  compactions_requested: u64,
+  compactions_done: u64,
+  /// Whether `Disk::compact` asked for every segment to be merged.
+  full_compaction: bool,
+  /// Key ranges `Disk::compact_range` asked for the segments of to be merged.
+  compaction_ranges: Vec<(Vec<u8>, Vec<u8>)>,
+  /// Failure of a requested compaction, for the caller waiting on it.
+  compaction_error: Option<io::Error>,
+  /// When the active memtable reaches `memtable_max_age`, if it holds any write.
+  memtable_deadline: Option<Instant>,
+}
+
+/// Owns the background threads; dropped along with the last `Disk` handle.
+struct BackgroundWorker {
+  inner: Arc<DiskInner>,
+  handle: Option<JoinHandle<()>>,
+  /// Thread of the scrubber, if `DiskOptions::scrub` is set.
+  scrubber: Option<JoinHandle<()>>,
+}
+
+impl Drop for BackgroundWorker {
+  fn drop(&mut self) {
+    self.inner.work.lock().unwrap().shutdown = true;
+    self.inner.work_changed.notify_all();
+    for handle in [self.handle.take(), self.scrubber.take()].into_iter().flatten() {
+      let _ = handle.join();
+    }
+    self.inner.save_stats();
+  }
+}
+
+impl DiskInner {
+  fn lock_log(&self) -> MutexGuard<'_, WriteLog> {
+    self.lock_metrics.lock_write_log(&self.log)
+  }
+
+  /// Waits until the write numbered `sequence` is durable. The first writer to find no
+  /// sync running syncs every write logged so far, while the others wait for it and return
+  /// together if it covered them.
+  ///
+  /// A failed sync poisons the WAL: the operating system may have dropped the pages it
+  /// couldn't write, so a later sync succeeding wouldn't make them durable. Every write
+  /// waiting or to come then fails, until the database is reopened and replays what
+  /// reached the file.
+  fn wait_durable(&self, sequence: u64) -> io::Result<()> {
+    let mut state = self.sync.lock().unwrap();
+    while state.synced < sequence && state.syncing {
+      state = self.sync_changed.wait(state).unwrap();
+    }
+    if let Some((kind, message)) = &state.failed {
+      let message = format!("an earlier WAL sync failed, reopen the database: {}", message);
+      return Err(io::Error::new(*kind, message));
+    }
+    if state.synced >= sequence {
+      return Ok(());
+    }
+    state.syncing = true;
+    drop(state);
+
+    let result = (|| {
+      let (synced, file) = {
+        let mut log = self.lock_log();
+        (log.last_sequence, log.wal.sync_handle()?)
+      };
+      #[cfg(feature = "tracing")]
+      let _span = tracing::debug_span!("fluxdb::wal_sync", sequence = synced).entered();
+      // Sealed WAL files are synced when sealed, so the active one holds every write
+      // that isn't durable yet.
+      let start = Instant::now();
+      file.sync().inspect_err(|e| self.poison_wal(e))?;
+      self.stats.record_wal_sync();
+      if start.elapsed() >= self.options.slow_operation_threshold {
+        let message = format_args!("syncing the WAL took {:?}", start.elapsed());
+        self.options.logger.log(Level::Warn, Subsystem::Wal, message);
+      }
+      Ok(synced)
+    })();
+
+    let mut state = self.sync.lock().unwrap();
+    state.syncing = false;
+    if let Ok(synced) = result {
+      state.synced = state.synced.max(synced);
+    }
+    self.sync_changed.notify_all();
+    result.map(drop)
+  }
+
+  /// Fails every durable write from now on, after a WAL sync failed with `e`.
+  fn poison_wal(&self, e: &io::Error) {
+    let mut state = self.sync.lock().unwrap();
+    state.failed.get_or_insert_with(|| (e.kind(), e.to_string()));
+    let message = format_args!("syncing the WAL failed, durable writes fail from now on: {}", e);
+    self.options.logger.log(Level::Error, Subsystem::Wal, message);
+  }
+
+  fn read_mem_tables(&self) -> RwLockReadGuard<'_, MemTables> {
+    self.lock_metrics.read_mem_table(&self.mem_tables)
+  }
+
+  fn write_mem_tables(&self) -> RwLockWriteGuard<'_, MemTables> {
+    self.lock_metrics.write_mem_table(&self.mem_tables)
+  }
+
+  /// Pins the frozen memtables and the segments for a read. Taking the memtables lock
+  /// first, as the signature demands, is what keeps a concurrent flush from hiding writes.
+  fn read_view(&self, mem_tables: &MemTables) -> ReadView {
+    ReadView {
+      immutable: mem_tables.immutable.iter().rev().map(|frozen| frozen.table.clone()).collect(),
+      segments: self.segments(),
+    }
+  }
+
+  fn segments(&self) -> Arc<Vec<Arc<SSTable>>> {
+    self.segments.read().unwrap().clone()
+  }
+
+  /// Tracks how long an operation of a caller took, on `key` if it was on one, and logs it
+  /// as a warning once it passes its threshold.
+  fn record_latency(&self, operation: Operation, key: Option<&[u8]>, elapsed: Duration) {
+    self.stats.record_latency(operation, elapsed);
+    let threshold = match operation {
+      Operation::Get => self.options.slow_get_threshold,
+      Operation::Set | Operation::Delete | Operation::Write => self.options.slow_write_threshold,
+    };
+    if threshold.is_some_and(|threshold| elapsed >= threshold) {
+      let key = key.map(|key| format!(" of {}", redact_key(key))).unwrap_or_default();
+      let message = format_args!("{}{} took {:?}", operation.name(), key, elapsed);
+      self.options.logger.log(Level::Warn, Subsystem::Operations, message);
+    }
+  }
+
+  /// Folds the number of segments a lookup read into the rolling average, and asks for a
+  /// compaction once it passes `read_amplification_trigger`.
+  fn record_read_amplification(&self, reads: u64) {
+    let sample = reads * READ_AMPLIFICATION_SCALE;
+    let update = |average: u64| {
+      Some(average - average / READ_AMPLIFICATION_WINDOW + sample / READ_AMPLIFICATION_WINDOW)
+    };
+    let _ = self.read_amplification.fetch_update(Ordering::Relaxed, Ordering::Relaxed, update);
+    if self.reads_need_compaction()
+      && !self.read_compaction_requested.swap(true, Ordering::Relaxed)
+    {
+      self.request_background_work();
+    }
+  }
+
+  fn reads_need_compaction(&self) -> bool {
+    self.options.read_amplification_trigger.is_some_and(|trigger| {
+      let average = self.read_amplification.load(Ordering::Relaxed);
+      average as f64 / READ_AMPLIFICATION_SCALE as f64 > trigger
+    })
+  }
+
+  fn request_background_work(&self) {
+    self.work.lock().unwrap().requested = true;
+    self.work_changed.notify_all();
+  }
+
+  /// Body of the background thread: flushes frozen memtables and compacts segments whenever
+  /// the write path asks for it, freezes the active memtable once it reaches
+  /// `memtable_max_age`, deletes retired files once nothing reads them, and periodically
+  /// saves the statistics, until the database is dropped.
+  fn run_background_work(&self) {
+    let mut next_stats_save = Instant::now() + self.options.stats_save_interval;
+    loop {
+      self.delete_obsolete_files();
+      let obsolete_deadline = self.obsolete_files_deadline();
+      let (requested, forced, force, aged, full, ranges) = {
+        let mut state = self.work.lock().unwrap();
+        loop {
+          if state.shutdown {
+            return;
+          }
+          let now = Instant::now();
+          let wake = [state.memtable_deadline, obsolete_deadline]
+            .into_iter()
+            .flatten()
+            .fold(next_stats_save, Instant::min);
+          if state.requested || now >= wake {
+            break;
+          }
+          state = self.work_changed.wait_timeout(state, wake - now).unwrap().0;
+        }
+        let aged = state.memtable_deadline.is_some_and(|at| Instant::now() >= at);
+        if aged {
+          state.memtable_deadline = None;
+        }
+        state.running = std::mem::take(&mut state.requested);
+        let forced = state.compactions_requested;
+        let full = std::mem::take(&mut state.full_compaction);
+        let ranges = std::mem::take(&mut state.compaction_ranges);
+        (state.running, forced, forced > state.compactions_done, aged, full, ranges)
+      };
+
+      if Instant::now() >= next_stats_save {
+        // A failed save only loses the counts since the previous one.
+        self.save_stats();
+        next_stats_save = Instant::now() + self.options.stats_save_interval;
+      }
+      if aged {
+        // Freezing asks for the flush, which the next round runs.
+        if let Err(e) = self.freeze_aged_mem_table() {
+          let message = format_args!("switching to a new memtable failed: {}", e);
+          self.options.logger.log(Level::Warn, Subsystem::Wal, message);
+          let retry = Instant::now() + BACKGROUND_RETRY_DELAY;
+          self.work.lock().unwrap().memtable_deadline = Some(retry);
+        }
+      }
+      if !requested {
+        continue;
+      }
+
+      let result = self.resume_writes().and_then(|()| self.flush_and_compact(full, &ranges));
 
-    let (wal, mem_table) = WAL::recover_from_directory(&dir).unwrap();
+      let mut state = self.work.lock().unwrap();
+      state.running = false;
+      let failed = match result {
+        Ok(()) => {
+          state.compactions_done = state.compactions_done.max(forced);
+          state.compaction_error = None;
+          false
+        }
+        Err(e) => {
+          if force {
+            state.compaction_error = Some(e);
+          }
+          // Requested compactions are retried along with the flushes.
+          state.full_compaction |= full;
+          state.compaction_ranges.extend(ranges);
+          true
+        }
+      };
+      self.work_changed.notify_all();
+      if failed && !state.shutdown {
+        // The frozen memtables stay queued and their WAL files stay live until a retry succeeds.
+        state.requested = true;
+        let _ = self.work_changed.wait_timeout(state, BACKGROUND_RETRY_DELAY);
+      }
+    }
+  }
+
+  /// Resumes writes once the directory accepts them again after the database turned
+  /// read-only, and fails while it doesn't. Writes carry on in a new WAL file: the active
+  /// one may hold the start of writes that failed, which later records mustn't follow.
+  fn resume_writes(&self) -> io::Result<()> {
+    if !self.health.is_read_only() {
+      return Ok(());
+    }
+    self.health.probe(&self.dir)?;
+    let mut log = self.lock_log();
+    self.health.resume_with(|| self.switch_wal(&mut log).map(WAL::discard_unflushed))
+  }
 
-    Disk {
-      mem_table,
-      wal,
+  /// Records a new WAL file in the manifest and switches to it, returning the sealed one.
+  fn switch_wal(&self, log: &mut WriteLog) -> io::Result<WAL> {
+    let next = WAL::create_with_options(&self.dir, &self.options)?;
+    let name = file_name(next.path());
+    let mut manifest = log.manifest.clone();
+    manifest.last_sequence = log.last_sequence;
+    manifest.wal_files.push(name.clone());
+    if let Err(e) = manifest.store_with(&self.options.storage, &self.dir) {
+      let _ = self.options.storage.delete(next.path());
+      if let Some(mirror) = &self.options.wal_mirror {
+        mirror.remove(&name);
+      }
+      return Err(e);
     }
+    record_edit(&self.options, &self.dir, &log.manifest, &manifest, EditReason::WalRotation);
+
+    let sealed = std::mem::replace(&mut log.wal, next);
+    log.sealed_wal_bytes += sealed.size();
+    log.active_sealed_wal_bytes += sealed.size();
+    log.counted_wal_size = log.wal.size();
+    log.manifest = manifest;
+    log.active_wal_files.push(name);
+    Ok(sealed)
   }
 
-  pub fn get(&self, key: &[u8]) -> Option<DiskEntry> {
-    if let Some(mem_entry) = self.mem_table.fetch(key) {
-      return Some(DiskEntry {
-        key: mem_entry.key.clone(),
-        value: mem_entry.value.as_ref().unwrap().clone(),
-        timestamp: mem_entry.timestamp,
+  /// Moves the active memtable to the flush queue and continues in a fresh memtable and
+  /// WAL file, so writes never wait for the full memtable to be written out.
+  fn freeze_mem_table(&self, log: &mut WriteLog) -> io::Result<()> {
+    self.start_new_wal(log)?;
+    let current = log.active_wal_files.pop().unwrap();
+    let wal_files = std::mem::replace(&mut log.active_wal_files, vec![current]);
+    let wal_bytes = std::mem::take(&mut log.active_sealed_wal_bytes);
+    log.active_since = None;
+
+    {
+      let mut mem_tables = self.write_mem_tables();
+      let order = self.options.comparator.clone();
+      let fresh = InMemoryTable::with_kind(order, self.options.memtable_kind)
+        .with_retained_versions(self.options.retained_versions);
+      let table = std::mem::replace(&mut mem_tables.active, fresh);
+      self.pending_bytes.fetch_add(table.current_size(), Ordering::Relaxed);
+      mem_tables.immutable.push(ImmutableMemTable {
+        table: Arc::new(table),
+        wal_files,
+        wal_bytes,
       });
     }
+    self.request_background_work();
+    Ok(())
+  }
 
-    None
+  /// Freezes the active memtable once its first write is older than `memtable_max_age`, so
+  /// it is flushed even if writes are too few to fill it. Otherwise sets the deadline for
+  /// the background thread to check again.
+  fn freeze_aged_mem_table(&self) -> io::Result<()> {
+    let Some(max_age) = self.options.memtable_max_age else {
+      return Ok(());
+    };
+    let mut log = self.lock_log();
+    match log.active_since {
+      Some(since) if since.elapsed() >= max_age => self.freeze_mem_table(&mut log),
+      Some(since) => {
+        self.work.lock().unwrap().memtable_deadline = since.checked_add(max_age);
+        Ok(())
+      }
+      None => Ok(()),
+    }
   }
 
-  pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<usize, usize> {
-    let timestamp = SystemTime::now()
-      .duration_since(UNIX_EPOCH)
-      .unwrap()
-      .as_micros();
+  /// Seals the current WAL file and records a new one in the manifest before switching to it.
+  fn start_new_wal(&self, log: &mut WriteLog) -> io::Result<()> {
+    if self.options.sync_writes {
+      log.wal.sync().inspect_err(|e| self.poison_wal(e))?;
+    } else {
+      log.wal.flush()?;
+    }
+    self.switch_wal(log).map(drop)
+  }
 
-    let wal_res = self.wal.record_insertion(key, value, timestamp);
-    if wal_res.is_err() {
-      return Err(0);
+  /// Flushes the frozen memtables, then compacts the segments once there are enough of them
+  /// or lookups read too many of them, or if `full` is set, as long as there are any.
+  /// Otherwise merges the segments holding keys of the requested `ranges`, if any.
+  fn flush_and_compact(&self, full: bool, ranges: &[(Vec<u8>, Vec<u8>)]) -> io::Result<()> {
+    // Only the memtables frozen by now: those frozen meanwhile ask for another pass, so
+    // writes can't hold off compactions by freezing memtables as fast as they are flushed.
+    let frozen = self.read_mem_tables().immutable.len();
+    for mem_table in (0..frozen).map_while(|_| self.oldest_immutable()) {
+      self.flush(mem_table).inspect_err(|e| {
+        let message = format_args!("flushing a memtable failed, retrying: {}", e);
+        self.options.logger.log(Level::Error, Subsystem::Flush, message);
+      })?;
     }
-    if self.wal.flush().is_err() {
-      return Err(0);
+    let segments = self.segments().len();
+    let for_reads = self.reads_need_compaction() && segments >= 2;
+    if segments < 2 {
+      // Too few segments to merge: lookups ask again once there are more.
+      self.read_compaction_requested.store(false, Ordering::Relaxed);
+    }
+    // Writes wait for the segments to be merged once there are enough to stop them.
+    let for_writes = self.options.write_stall.stops_at(segments) && segments >= 2;
+    let trigger = self.options.compaction_trigger.max(2);
+    if segments >= trigger || for_reads || for_writes || (full && segments > 0) {
+      self.compact().inspect_err(|e| {
+        let message = format_args!("compaction failed, retrying: {}", e);
+        self.options.logger.log(Level::Error, Subsystem::Compaction, message);
+      })?;
+      // Lookups now read a single segment; the average starts over from there.
+      self.read_amplification.store(0, Ordering::Relaxed);
+      self.read_compaction_requested.store(false, Ordering::Relaxed);
+    } else if !ranges.is_empty() {
+      let result = self.segments_within(ranges).and_then(|inputs| match inputs.is_empty() {
+        true => Ok(()),
+        false => self.compact_segments(inputs),
+      });
+      result.inspect_err(|e| {
+        let message = format_args!("compacting key ranges failed, retrying: {}", e);
+        self.options.logger.log(Level::Error, Subsystem::Compaction, message);
+      })?;
+    }
+    for name in self.value_log.purge() {
+      self.retire(&name, Subsystem::Compaction, None);
     }
+    self.delete_obsolete_files();
+    Ok(())
+  }
 
-    self.mem_table.insert(key, value, timestamp);
+  /// Body of the scrubber thread: checks every segment block at the pace `options` sets,
+  /// then waits `options.interval` before the next pass, until the database is dropped.
+  fn run_scrubber(&self, options: ScrubOptions) {
+    loop {
+      match self.scrub(Some(options.bytes_per_second)) {
+        Ok(report) => *self.last_scrub.lock().unwrap() = Some(report),
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => return,
+        Err(e) => {
+          let message = format_args!("scrubbing the segments failed: {}", e);
+          self.options.logger.log(Level::Warn, Subsystem::Scrub, message);
+        }
+      }
+      if !self.sleep_until(Instant::now().checked_add(options.interval)) {
+        return;
+      }
+    }
+  }
 
-    Ok(1)
+  /// Waits until `deadline`, forever if `None`. Returns false, early, once the database is
+  /// being dropped.
+  fn sleep_until(&self, deadline: Option<Instant>) -> bool {
+    let mut state = self.work.lock().unwrap();
+    loop {
+      if state.shutdown {
+        return false;
+      }
+      let now = Instant::now();
+      state = match deadline {
+        Some(deadline) if now >= deadline => return true,
+        Some(deadline) => self.work_changed.wait_timeout(state, deadline - now).unwrap().0,
+        None => self.work_changed.wait(state).unwrap(),
+      };
+    }
   }
 
-  pub fn delete(&mut self, key: &[u8]) -> Result<usize, usize> {
-    let timestamp = SystemTime::now()
-      .duration_since(UNIX_EPOCH)
-      .unwrap()
-      .as_micros();
+  /// Checks every data block of the live segments, reading at most `bytes_per_second` if
+  /// set. Damaged blocks are logged and copied next to their segment. Segments compacted
+  /// away before their turn are skipped, and those written meanwhile left to the next pass.
+  /// Fails with `Interrupted` if the database is dropped meanwhile.
+  fn scrub(&self, bytes_per_second: Option<u64>) -> io::Result<ScrubReport> {
+    let start = Instant::now();
+    let paths: Vec<PathBuf> =
+      self.segments().iter().map(|segment| segment.path().to_owned()).collect();
+    let mut report = ScrubReport::default();
+    for path in paths {
+      // Looked up again for each segment, so a pass holds on to one segment at a time rather
+      // than keeping the files of every segment compacted away meanwhile, and skips those.
+      let segment = self.segments().iter().find(|segment| segment.path() == path).cloned();
+      let Some(segment) = segment else {
+        continue;
+      };
+      report.segments += 1;
+      let file = file_name(segment.path());
+      for block in 0..segment.block_count() {
+        match segment.verify_block(block) {
+          Ok(size) => report.bytes += size,
+          Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            let stored = segment.raw_block(block);
+            report.bytes += stored.as_ref().map_or(0, |stored| stored.len() as u64);
+            report.corrupt_blocks.push(self.quarantine_block(&file, block, &e, stored));
+          }
+          Err(e) => return Err(e),
+        }
+        report.blocks += 1;
+        let due = bytes_per_second
+          .map(|rate| start + Duration::from_secs_f64(report.bytes as f64 / rate.max(1) as f64));
+        if !self.sleep_until(Some(due.unwrap_or(start))) {
+          return Err(io::Error::new(io::ErrorKind::Interrupted, "database closed"));
+        }
+      }
+    }
+    report.elapsed = start.elapsed();
+    Ok(report)
+  }
 
-    let wal_res = self.wal.record_removal(key, timestamp);
-    if wal_res.is_err() {
-      return Err(0);
+  /// Reports a damaged block of the segment `file`, and copies it as `stored` to
+  /// `<file>.<block>.corrupt` for inspection, as it may be gone by the time someone looks.
+  fn quarantine_block(
+    &self,
+    file: &str,
+    block: usize,
+    error: &io::Error,
+    stored: io::Result<Vec<u8>>,
+  ) -> CorruptBlock {
+    let name = format!("{}.{}.{}", file, block, QUARANTINE_EXTENSION);
+    let quarantined = stored
+      .and_then(|stored| self.options.storage.replace(&self.dir, &name, &stored))
+      .map(|()| self.dir.join(&name));
+    let copy = match &quarantined {
+      Ok(path) => format!("copied to {}", path.display()),
+      Err(e) => format!("copying it failed: {}", e),
+    };
+    let message =
+      format_args!("block {} of segment {} is damaged ({}); {}", block, file, error, copy);
+    self.options.logger.log(Level::Error, Subsystem::Scrub, message);
+    CorruptBlock {
+      file: file.to_owned(),
+      block,
+      error: error.to_string(),
+      quarantined: quarantined.ok(),
     }
-    if self.wal.flush().is_err() {
-      return Err(0);
+  }
+
+  /// Catches up with the primary every `interval` until the database is closed. A failed
+  /// attempt is logged and tried again at the next one.
+  fn run_follower(&self, interval: Duration) {
+    loop {
+      // Intervals too long to count only catch up when asked to.
+      let next_catch_up = Instant::now().checked_add(interval);
+      {
+        let mut state = self.work.lock().unwrap();
+        loop {
+          if state.shutdown {
+            return;
+          }
+          let now = Instant::now();
+          state = match next_catch_up {
+            Some(next) if now >= next => break,
+            Some(next) => self.work_changed.wait_timeout(state, next - now).unwrap().0,
+            None => self.work_changed.wait(state).unwrap(),
+          };
+        }
+      }
+      if let Err(e) = self.catch_up() {
+        let message = format_args!("catching up with the primary failed: {}", e);
+        self.options.logger.log(Level::Warn, Subsystem::Replication, message);
+      }
+    }
+  }
+
+  /// Brings a follower up to date with the manifest and live WAL files its primary wrote.
+  /// Segments already open are kept, and the WAL files read again into a fresh memtable.
+  /// The primary may retire files between the manifest and the read; the attempt is then
+  /// made again with the newer manifest.
+  fn catch_up(&self) -> io::Result<()> {
+    let mut attempts = 0;
+    loop {
+      attempts += 1;
+      match self.try_catch_up() {
+        Err(e) if e.kind() == io::ErrorKind::NotFound && attempts < CATCH_UP_ATTEMPTS => {}
+        result => return result,
+      }
+    }
+  }
+
+  fn try_catch_up(&self) -> io::Result<()> {
+    let storage = &self.options.storage;
+    // The log lock keeps concurrent attempts from installing an older state last.
+    let mut log = self.lock_log();
+    let manifest = Manifest::load_with(storage, &self.dir)?.ok_or_else(|| {
+      io::Error::new(io::ErrorKind::NotFound, "the primary's manifest is gone")
+    })?;
+    manifest.check_features()?;
+
+    let new_value_logs: Vec<String> = manifest
+      .value_log_files
+      .iter()
+      .filter(|name| !log.manifest.value_log_files.contains(name))
+      .cloned()
+      .collect();
+    self.value_log.load(&new_value_logs)?;
+    let open: HashMap<PathBuf, Arc<SSTable>> = self
+      .segments()
+      .iter()
+      .map(|segment| (segment.path().to_owned(), segment.clone()))
+      .collect();
+    let segments = manifest
+      .segment_paths(&self.dir)
+      .iter()
+      .rev()
+      .map(|path| match open.get(path) {
+        Some(segment) => Ok(segment.clone()),
+        None => open_listed_segment(&self.options, &self.value_log, &manifest, path).map(Arc::new),
+      })
+      .collect::<io::Result<Vec<_>>>()?;
+    let (wal_files, pinned) = (manifest.wal_paths(&self.dir), manifest.pinned_sequences());
+    // Only the records appended since the last pass are read, until the primary retires a WAL
+    // file: its records moved to a segment, and the memtable is read anew. A failed pass
+    // leaves the files to be read anew too, as it may have read records it never applied.
+    let appended = match log.followed.take() {
+      Some(mut followed) => followed
+        .read_appended(&wal_files, &self.options)?
+        .map(|records| (followed, records)),
+      None => None,
+    };
+    let (followed, fresh, appended) = match appended {
+      Some((followed, records)) => (followed, None, records),
+      None => {
+        let (followed, mem_table, _) =
+          FollowedWal::open(&wal_files, &self.options, manifest.last_sequence, &pinned)?;
+        (followed, Some(mem_table), Vec::new())
+      }
+    };
+    let last_sequence = manifest.last_sequence.max(followed.last_sequence());
+
+    // Readers take the memtables before the segments, so installing the segments first
+    // never shows them the fresh memtable without the writes flushed out of it.
+    *self.segments.write().unwrap() = Arc::new(segments);
+    {
+      let mut mem_tables = self.write_mem_tables();
+      match fresh {
+        Some(mem_table) => {
+          mem_tables.active = mem_table;
+          mem_tables.immutable.clear();
+        }
+        None => FollowedWal::apply(&mut mem_tables.active, appended, &pinned)?,
+      }
+    }
+    log.followed = Some(followed);
+    log.active_wal_files.clone_from(&manifest.wal_files);
+    log.manifest = manifest;
+    log.last_sequence = last_sequence;
+    self.visible_sequence.store(last_sequence, Ordering::Release);
+    Ok(())
+  }
+
+  fn save_stats(&self) {
+    // The statistics in the directory are the primary's.
+    if self.follow_interval.is_some() {
+      return;
+    }
+    if let Err(e) = self.stats.save_with(&self.options.storage, &self.dir) {
+      let message = format_args!("saving statistics failed: {}", e);
+      self.options.logger.log(Level::Warn, Subsystem::Stats, message);
+    }
+  }
+
+  /// Queues a file no longer listed in the manifest for deletion, along with the segment it
+  /// holds, if any.
+  fn retire(&self, name: &str, subsystem: Subsystem, segment: Option<&Arc<SSTable>>) {
+    self.obsolete.lock().unwrap().push(ObsoleteFile {
+      name: name.to_owned(),
+      subsystem,
+      retired_at: Instant::now(),
+      segment: segment.map(Arc::downgrade),
+    });
+  }
+
+  /// Deletes the retired files past `obsolete_file_grace` that no segment handle reads any
+  /// more, and returns how many were deleted. A file the manifest lists again is kept, and a
+  /// failure is only logged, as unlisted files are removed on the next open.
+  fn delete_obsolete_files(&self) -> usize {
+    let listed: HashSet<String> = {
+      let log = self.lock_log();
+      let manifest = &log.manifest;
+      let files = manifest.wal_files.iter().chain(manifest.segment_files.iter());
+      files.chain(manifest.value_log_files.iter()).cloned().collect()
+    };
+    let grace = self.options.obsolete_file_grace;
+    let ready = {
+      let mut obsolete = self.obsolete.lock().unwrap();
+      let (ready, waiting) = std::mem::take(&mut *obsolete)
+        .into_iter()
+        .partition::<Vec<_>, _>(|file| file.retired_at.elapsed() >= grace && file.is_unread());
+      *obsolete = waiting;
+      ready
+    };
+    let mut deleted = 0;
+    for file in ready {
+      if listed.contains(&file.name) {
+        let message = format_args!("retired file {} is still live, keeping it", file.name);
+        self.options.logger.log(Level::Warn, file.subsystem, message);
+        continue;
+      }
+      let path = self.dir.join(&file.name);
+      let result = match is_database_file_name(&file.name, WAL_EXTENSION) {
+        true => discard_wal_file(&self.options, &self.dir, &path),
+        false => self.options.storage.delete(&path),
+      };
+      match result {
+        Ok(()) => deleted += 1,
+        Err(e) => {
+          let message = format_args!("removing retired file {} failed: {}", file.name, e);
+          self.options.logger.log(Level::Warn, file.subsystem, message);
+        }
+      }
+    }
+    if deleted > 0 {
+      prune_wal_archive(&self.options, &self.dir);
+    }
+    deleted
+  }
+
+  /// Returns when the background thread should next look at the retired files: when the
+  /// first grace period ends, or shortly if some are only waiting for readers.
+  fn obsolete_files_deadline(&self) -> Option<Instant> {
+    let grace = self.options.obsolete_file_grace;
+    let obsolete = self.obsolete.lock().unwrap();
+    let deadlines = obsolete.iter().map(|file| match file.retired_at.checked_add(grace) {
+      Some(at) if at > Instant::now() => at,
+      _ => Instant::now() + OBSOLETE_FILE_RECHECK,
+    });
+    deadlines.min()
+  }
+
+  /// Returns how far flushes and compactions are behind, as far as writes are concerned.
+  fn stall(&self) -> Stall {
+    let pending_bytes = self.pending_bytes.load(Ordering::Relaxed);
+    self.options.write_stall.check(pending_bytes, self.segments.read().unwrap().len())
+  }
+
+  fn oldest_immutable(&self) -> Option<ImmutableMemTable> {
+    self.read_mem_tables().immutable.first().cloned()
+  }
+
+  /// Writes a frozen memtable to a new segment, then retires the memtable and its WAL files.
+  fn flush(&self, mem_table: ImmutableMemTable) -> io::Result<()> {
+    let start = Instant::now();
+    let name = self.lock_log().manifest.new_segment_name();
+    let path = self.dir.join(&name);
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!(
+      "fluxdb::flush",
+      segment = %name,
+      records = mem_table.table.record_count(),
+      bytes = mem_table.table.current_size(),
+    )
+    .entered();
+    mem_table.table.debug_assert_size_consistent();
+    let snapshots = self.snapshots.sequences();
+    let (runs, coalesced) = match self.options.coalesce_tombstones {
+      Some(min_run) if self.options.retained_versions == 0 => {
+        self.coalescable_runs(mem_table.table.range_by_key(..), &snapshots, min_run.max(1))?
+      }
+      _ => (Vec::new(), 0),
+    };
+    // Runs are in key order, so one pass drops the records they stand in for.
+    let order = &self.options.comparator;
+    let mut next_run = 0;
+    let records = mem_table.table.all_records().filter(|record| {
+      while runs.get(next_run).is_some_and(|run| order.compare(&run.end, &record.key).is_le()) {
+        next_run += 1;
+      }
+      !runs.get(next_run).is_some_and(|run| run.contains(&record.key, order))
+    });
+    let records = records.map(|record| Ok(record_entry(record)));
+    let entries = RetainVersions::new(records, snapshots, false)
+      .with_retained_versions(self.options.retained_versions);
+    let mut tombstones = mem_table.table.range_tombstones().to_vec();
+    tombstones.extend(runs.iter().cloned());
+    let priority = IoPriority::High;
+    let mut values = self.value_log_writer(priority, HashSet::new())?;
+    let segment =
+      self.write_segment(&path, entries, &tombstones, Subsystem::Flush, priority, values.as_mut())?;
+    let segment_entries = segment.entry_count();
+
+    let mut log = self.lock_log();
+    let mut manifest = log.manifest.clone();
+    manifest.last_sequence = log.last_sequence;
+    manifest.segment_files.push(name.clone());
+    manifest.value_log_files.extend(values.as_ref().and_then(ValueLogWriter::file_name));
+    manifest
+      .wal_files
+      .retain(|name| !mem_table.wal_files.contains(name));
+    if let Err(e) = manifest.store_with(&self.options.storage, &self.dir) {
+      let _ = self.options.storage.delete(&path);
+      if let Some(values) = values.as_mut() {
+        values.abandon();
+      }
+      return Err(e);
+    }
+    record_edit(&self.options, &self.dir, &log.manifest, &manifest, EditReason::Flush);
+    log.manifest = manifest;
+    log.sealed_wal_bytes -= mem_table.wal_bytes;
+    if let Some(values) = values {
+      self.value_log.commit(values, None);
+    }
+
+    // Publish the segment before dropping the memtable, so readers always find the records
+    // in one or the other.
+    let mut segments = Vec::clone(&self.segments());
+    segments.insert(0, Arc::new(segment));
+    *self.segments.write().unwrap() = Arc::new(segments);
+    self.write_mem_tables().immutable.remove(0);
+    self.pending_bytes.fetch_sub(mem_table.table.current_size(), Ordering::Relaxed);
+    drop(log);
+
+    for name in mem_table.wal_files.iter() {
+      self.retire(name, Subsystem::Flush, None);
+      if let Some(mirror) = &self.options.wal_mirror {
+        mirror.remove(name);
+      }
+    }
+    self.stats.record_flush();
+    self.stats.record_tombstones_coalesced(coalesced);
+    let elapsed = start.elapsed();
+    let message = format_args!(
+      "flushed a memtable to {} ({} entries) in {:?}",
+      name, segment_entries, elapsed
+    );
+    self.options.logger.log(self.options.operation_level(elapsed), Subsystem::Flush, message);
+    Ok(())
+  }
+
+  /// Finds the runs of at least `min_run` consecutive keys of a flushing memtable whose last
+  /// write is a plain delete, and returns a range tombstone to write in place of each run's
+  /// point tombstones, along with how many point tombstones they replace.
+  ///
+  /// The range tombstone is as new as the newest delete of its run, so it would also hide
+  /// older versions of keys between the run's keys; a run is only kept if the segments hold
+  /// no such key still live. Every version of a run's keys must fall in the same snapshot
+  /// stripe, so that no snapshot can tell the range tombstone from the deletes.
+  fn coalescable_runs<'a>(
+    &self,
+    keys: impl Iterator<Item = &'a [InMemoryRecord]>,
+    snapshots: &[u64],
+    min_run: usize,
+  ) -> io::Result<(Vec<RangeTombstone>, u64)> {
+    let mut runs = Vec::new();
+    let mut coalesced = 0;
+    let mut run: Vec<&[InMemoryRecord]> = Vec::new();
+    let mut run_stripe = 0;
+    for versions in keys {
+      // Versions are newest first.
+      let newest = &versions[0];
+      let versions_stripe = stripe(newest.sequence, snapshots);
+      let deleted = newest.is_deleted
+        && newest.value.is_none()
+        && versions.iter().all(|version| stripe(version.sequence, snapshots) == versions_stripe);
+      if deleted && !run.is_empty() && versions_stripe == run_stripe {
+        run.push(versions);
+        continue;
+      }
+      if let Some(tombstone) = self.coalesce_run(&run, Some(&newest.key), min_run)? {
+        coalesced += run.len() as u64;
+        runs.push(tombstone);
+      }
+      run.clear();
+      if deleted {
+        run.push(versions);
+        run_stripe = versions_stripe;
+      }
+    }
+    if let Some(tombstone) = self.coalesce_run(&run, None, min_run)? {
+      coalesced += run.len() as u64;
+      runs.push(tombstone);
+    }
+    Ok((runs, coalesced))
+  }
+
+  /// Returns the range tombstone replacing a run of deleted keys, followed in the memtable
+  /// by the key `next`, or `None` if the run is too short or spans a key the segments still
+  /// hold live.
+  fn coalesce_run(
+    &self,
+    run: &[&[InMemoryRecord]],
+    next: Option<&[u8]>,
+    min_run: usize,
+  ) -> io::Result<Option<RangeTombstone>> {
+    if run.len() < min_run {
+      return Ok(None);
+    }
+    let order = &self.options.comparator;
+    let start = run[0][0].key.to_vec();
+    // The range ends at the smallest key after the run's last one, only known in byte
+    // order; other orders end it at the next key of the memtable.
+    let end = match next {
+      _ if order.is_bytewise() => [&run[run.len() - 1][0].key[..], &[0]].concat(),
+      Some(next) => next.to_vec(),
+      None => return Ok(None),
+    };
+
+    let segments = self.segments();
+    let sources = segments
+      .iter()
+      .map(|segment| {
+        Box::new(segment.iter_from(Bound::Included(&start)).unresolved()) as EntrySource
+      })
+      .collect();
+    let segment_tombstones: Vec<&RangeTombstone> = segments
+      .iter()
+      .flat_map(|segment| segment.range_tombstones())
+      .collect();
+    let mut run_keys = run.iter().map(|versions| &versions[0].key[..]).peekable();
+    let mut last_key: Option<Vec<u8>> = None;
+    for entry in MergeIterator::with_key_order(sources, order, false) {
+      let entry = entry?;
+      if order.compare(&entry.key, &end).is_ge() {
+        break;
+      }
+      // Only the newest version of a key tells whether it is live.
+      if entry.backdated || last_key.as_ref() == Some(&entry.key) {
+        continue;
+      }
+      while run_keys.next_if(|key| order.compare(key, &entry.key).is_lt()).is_some() {}
+      let in_run = run_keys.peek() == Some(&&entry.key[..]);
+      let live = entry.value.is_some() || entry.retained.is_some();
+      let covered = segment_tombstones
+        .iter()
+        .any(|tombstone| tombstone.covers(&entry.key, entry.sequence, order));
+      if !in_run && live && !covered {
+        return Ok(None);
+      }
+      last_key = Some(entry.key);
+    }
+
+    let newest = run
+      .iter()
+      .max_by_key(|versions| versions[0].sequence)
+      .map(|versions| &versions[0])
+      .unwrap();
+    Ok(Some(RangeTombstone {
+      start,
+      end,
+      timestamp: newest.timestamp,
+      sequence: newest.sequence,
+    }))
+  }
+
+  /// Merges every segment into one, as `compact_segments` does.
+  fn compact(&self) -> io::Result<()> {
+    self.compact_segments(self.segments().to_vec())
+  }
+
+  /// Returns the segments to merge for compacting the key ranges, each from its start
+  /// (inclusive) to its end (exclusive): those whose keys or range tombstones span part of
+  /// one of them, and every segment between them in age, so the output can take their place.
+  /// Reads the first block of each segment for its smallest key.
+  fn segments_within(&self, ranges: &[(Vec<u8>, Vec<u8>)]) -> io::Result<Vec<Arc<SSTable>>> {
+    use std::cmp::Ordering::Less;
+    let order = &self.options.comparator;
+    let overlaps = |first: &[u8], last: &[u8], inclusive: bool| {
+      ranges.iter().any(|(start, end)| {
+        let after_start = match inclusive {
+          true => order.compare(last, start) != Less,
+          false => order.compare(start, last) == Less,
+        };
+        order.compare(first, end) == Less && after_start
+      })
+    };
+    let segments = self.segments();
+    let mut overlapping = Vec::new();
+    for (i, segment) in segments.iter().enumerate() {
+      let keys = match (segment.smallest_key()?, segment.largest_key()) {
+        (Some(first), Some(last)) => overlaps(&first, last, true),
+        _ => false,
+      };
+      let tombstones = segment.range_tombstones().iter().any(|tombstone| {
+        overlaps(&tombstone.start, &tombstone.end, false)
+      });
+      if keys || tombstones {
+        overlapping.push(i);
+      }
+    }
+    Ok(match (overlapping.first(), overlapping.last()) {
+      (Some(&newest), Some(&oldest)) => segments[newest..=oldest].to_vec(),
+      _ => Vec::new(),
+    })
+  }
+
+  /// Merges a run of segments, newest first and consecutive in age, into one that takes
+  /// their place, keeping only the versions live snapshots can still read, and dropping the
+  /// versions range tombstones hide from every reader. When the run reaches the oldest
+  /// segment, no older data remains: tombstones left at the bottom of a key's history are
+  /// dropped too, and a range tombstone is kept only while a snapshot older than it may
+  /// still read versions it covers, unless `tombstone_retention` keeps them longer. Value
+  /// log files are only relocated and retired when every segment is merged, as other
+  /// segments may point into them.
+  fn compact_segments(&self, inputs: Vec<Arc<SSTable>>) -> io::Result<()> {
+    let start = Instant::now();
+    let segments = self.segments();
+    let bottommost = match (inputs.last(), segments.last()) {
+      (Some(input), Some(oldest)) => Arc::ptr_eq(input, oldest),
+      _ => true,
+    };
+    let every_segment = bottommost && inputs.len() == segments.len();
+    let name = self.lock_log().manifest.new_segment_name();
+    let path = self.dir.join(&name);
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!("fluxdb::compaction", segment = %name, inputs = inputs.len())
+      .entered();
+
+    // Values in the value log are carried over as pointers, except those in files that are
+    // mostly garbage, which are copied to a new file so the old ones can be removed.
+    let sources: Vec<EntrySource> = inputs
+      .iter()
+      .map(|segment| Box::new(segment.iter().unresolved()) as EntrySource)
+      .collect();
+    let relocate = match &self.options.value_log {
+      Some(options) if every_segment => self.value_log.garbage_files(options.gc_garbage_ratio),
+      _ => HashSet::new(),
+    };
+    let mut values = self.value_log_writer(IoPriority::Low, relocate)?;
+    let snapshots = self.snapshots.sequences();
+    let range_tombstones: Vec<RangeTombstone> = inputs
+      .iter()
+      .flat_map(|segment| segment.range_tombstones().iter().cloned())
+      .collect();
+    let order = &self.options.comparator;
+    // A covered version is only needed by snapshots taken between it and the tombstone.
+    let hidden = |entry: &Entry| {
+      range_tombstones.iter().any(|tombstone| {
+        tombstone.covers(&entry.key, entry.sequence, order)
+          && stripe(tombstone.sequence, &snapshots) == stripe(entry.sequence, &snapshots)
+      })
+    };
+    let now = self.options.clock.now_micros();
+    let grace_cutoff = now.saturating_sub(self.options.soft_delete_grace.as_micros());
+    let horizon = self.options.tombstone_retention.horizon(
+      now,
+      self.visible_sequence.load(Ordering::Acquire),
+      &mut self.compaction_rounds.lock().unwrap(),
+    );
+    let merged = MergeIterator::with_key_order(sources, order, false)
+      .filter(|entry| !entry.as_ref().is_ok_and(hidden))
+      .map(|entry| {
+        entry.map(|mut entry| {
+          // Past the grace period a soft tombstone becomes a plain one.
+          if entry.timestamp < grace_cutoff {
+            entry.retained = None;
+          }
+          entry
+        })
+      });
+    let mut retained = RetainVersions::new(merged, snapshots.clone(), bottommost)
+      .with_retained_versions(self.options.retained_versions)
+      .with_tombstone_horizon(horizon);
+    let live = retained.by_ref().map(|entry| entry.map(|entry| self.upgrade_in_place(entry)));
+    let kept_tombstones: Vec<RangeTombstone> = range_tombstones
+      .iter()
+      .filter(|tombstone| {
+        !bottommost
+          || snapshots.iter().any(|&snapshot| snapshot < tombstone.sequence)
+          || !horizon.allows(tombstone.sequence, tombstone.timestamp)
+      })
+      .cloned()
+      .collect();
+    let (subsystem, priority) = (Subsystem::Compaction, IoPriority::Low);
+    let output =
+      self.write_segment(&path, live, &kept_tombstones, subsystem, priority, values.as_mut())?;
+    let dropped_ranges = range_tombstones.len() - kept_tombstones.len();
+    let purged = retained.tombstones_dropped() + dropped_ranges as u64;
+    let output_entries = output.entry_count();
+    let bytes_read = inputs.iter().map(|segment| segment.file_size()).sum();
+    let bytes_written = output.file_size();
+    let output = if output.entry_count() > 0 || !kept_tombstones.is_empty() {
+      Some(output)
+    } else {
+      drop(output);
+      self.options.storage.delete(&path)?;
+      None
+    };
+
+    let input_names: Vec<String> = inputs.iter().map(|segment| file_name(segment.path())).collect();
+    let mut log = self.lock_log();
+    let mut manifest = log.manifest.clone();
+    manifest.last_sequence = log.last_sequence;
+    // The output takes the place of the oldest input, the segments being listed oldest first.
+    let at = manifest.segment_files.iter().position(|name| input_names.contains(name));
+    manifest
+      .segment_files
+      .retain(|name| !input_names.contains(name));
+    if output.is_some() {
+      let at = at.unwrap_or(0).min(manifest.segment_files.len());
+      manifest.segment_files.insert(at, name);
+    }
+    // Value log files the output doesn't point to are no longer needed.
+    let unreferenced = match &values {
+      Some(values) if every_segment => self.value_log.unreferenced(values),
+      _ => Vec::new(),
+    };
+    manifest.value_log_files.retain(|name| !unreferenced.contains(name));
+    manifest.value_log_files.extend(values.as_ref().and_then(ValueLogWriter::file_name));
+    if let Err(e) = manifest.store_with(&self.options.storage, &self.dir) {
+      let _ = self.options.storage.delete(&path);
+      if let Some(values) = values.as_mut() {
+        values.abandon();
+      }
+      return Err(e);
+    }
+    record_edit(&self.options, &self.dir, &log.manifest, &manifest, EditReason::Compaction);
+    log.manifest = manifest;
+    if let Some(values) = values {
+      let compaction = every_segment.then_some((unreferenced.as_slice(), inputs.as_slice()));
+      self.value_log.commit(values, compaction);
+    }
+    {
+      let mut segments = self.segments.write().unwrap();
+      let mut merged: Vec<Arc<SSTable>> = segments.as_ref().clone();
+      let is_input =
+        |segment: &Arc<SSTable>| inputs.iter().any(|input| Arc::ptr_eq(segment, input));
+      let at = merged.iter().position(is_input);
+      merged.retain(|segment| !is_input(segment));
+      if let Some(output) = output {
+        merged.insert(at.unwrap_or(0).min(merged.len()), Arc::new(output));
+      }
+      *segments = Arc::new(merged);
+    }
+    drop(log);
+
+    for (name, input) in input_names.iter().zip(inputs.iter()) {
+      self.retire(name, Subsystem::Compaction, Some(input));
+    }
+    self.stats.record_compaction(bytes_read, bytes_written);
+    self.stats.record_tombstones_purged(purged);
+    let elapsed = start.elapsed();
+    let message = format_args!(
+      "compacted {} segments into {} entries in {:?}",
+      input_names.len(),
+      output_entries,
+      elapsed
+    );
+    let level = self.options.operation_level(elapsed);
+    self.options.logger.log(level, Subsystem::Compaction, message);
+    Ok(())
+  }
+
+  /// Rewrites a segment file built by `SstWriter` into the directory, its large values going
+  /// to the value log file `values` writes, as a flush sends them. Returns the name of the
+  /// new segment, the segment and `values`, to commit once the manifest lists them.
+  fn separate_values(
+    &self,
+    path: &Path,
+    mut values: ValueLogWriter,
+  ) -> io::Result<(String, SSTable, ValueLogWriter)> {
+    let source = SSTable::open_with(&self.options.storage, path)?
+      .with_key_order(&self.options.comparator);
+    let priority = IoPriority::High;
+    let name = self.lock_log().manifest.new_segment_name();
+    let dest = self.dir.join(&name);
+    let segment =
+      self.write_segment(&dest, source.iter(), &[], Subsystem::Flush, priority, Some(&mut values))?;
+    Ok((name, segment, values))
+  }
+
+  /// Describes the data of the database whose keys fall from `smallest` to `largest`, both
+  /// inclusive, if any: keys in a memtable or a segment, or a range tombstone reaching into
+  /// the range.
+  fn ingestion_overlap(&self, smallest: &[u8], largest: &[u8]) -> io::Result<Option<String>> {
+    let order = &self.options.comparator;
+    let reaches = |tombstone: &RangeTombstone| {
+      order.compare(&tombstone.start, largest).is_le()
+        && order.compare(smallest, &tombstone.end).is_lt()
+    };
+    {
+      let mem_tables = self.read_mem_tables();
+      let frozen = mem_tables.immutable.iter().map(|frozen| frozen.table.as_ref());
+      let range = (Bound::Included(smallest), Bound::Included(largest));
+      if std::iter::once(&mem_tables.active)
+        .chain(frozen)
+        .any(|table| table.range(range).next().is_some())
+      {
+        return Ok(Some("keys in a memtable".to_owned()));
+      }
+      if mem_tables.range_tombstones(u64::MAX).iter().any(reaches) {
+        return Ok(Some("a range deletion in a memtable".to_owned()));
+      }
+    }
+    for segment in self.segments().iter() {
+      let first = segment.iter_from(Bound::Included(smallest)).unresolved().next().transpose()?;
+      let within = first.is_some_and(|entry| order.compare(&entry.key, largest).is_le());
+      if within || segment.range_tombstones().iter().any(reaches) {
+        return Ok(Some(format!("segment {}", file_name(segment.path()))));
+      }
+    }
+    Ok(None)
+  }
+
+  /// Brings a value read from the database to the current schema version.
+  fn upgrade(&self, entry: Entry) -> io::Result<Entry> {
+    match &self.options.value_schema {
+      Some(schema) => schema.upgrade(entry),
+      None => Ok(entry),
+    }
+  }
+
+  /// Upgrades a value being rewritten by compaction, so it no longer needs upgrading on
+  /// every read. A value that fails to upgrade is kept as it is and fails on read instead.
+  fn upgrade_in_place(&self, entry: Entry) -> Entry {
+    match &self.options.value_schema {
+      // Values kept in the value log are only upgraded on read.
+      Some(schema) if entry.schema < schema.version() && !entry.value_pointer => {
+        schema.upgrade(entry.clone()).unwrap_or(entry)
+      }
+      _ => entry,
+    }
+  }
+
+  /// Starts the value log file a flush or a compaction moves large values to, if the value
+  /// log is enabled, relocating the values of the files in `relocate`.
+  fn value_log_writer(
+    &self,
+    priority: IoPriority,
+    relocate: HashSet<u64>,
+  ) -> io::Result<Option<ValueLogWriter>> {
+    let Some(options) = &self.options.value_log else {
+      return Ok(None);
+    };
+    let name = self.lock_log().manifest.new_value_log_name();
+    let storage = match &self.options.rate_limiter {
+      Some(limiter) => limiter.limit(&self.options.storage, priority),
+      None => self.options.storage.clone(),
+    };
+    let writer = self.value_log.writer(storage, name, options.min_value_size, relocate)?;
+    Ok(Some(writer))
+  }
+
+  /// Writes sorted entries and range tombstones to a new segment file, removing the file if
+  /// anything fails. A slow sync is logged for the subsystem writing the segment. The writes
+  /// are paced by `rate_limiter` at `priority`; reads, those of compaction inputs included,
+  /// aren't. With `values`, large values go to a value log file, synced before the segment.
+  fn write_segment(
+    &self,
+    path: &Path,
+    entries: impl Iterator<Item = io::Result<Entry>>,
+    range_tombstones: &[RangeTombstone],
+    subsystem: Subsystem,
+    priority: IoPriority,
+    mut values: Option<&mut ValueLogWriter>,
+  ) -> io::Result<SSTable> {
+    let storage = &self.options.storage;
+    let limiter = self.options.rate_limiter.as_ref();
+    let limited = limiter.map(|limiter| limiter.limit(storage, priority));
+    let result = SSTableWriter::create_with(
+      limited.as_ref().unwrap_or(storage),
+      path,
+      self.options.compression,
+      self.options.block_size,
+    )
+    .and_then(|mut writer| {
+      writer.set_bloom_bits_per_key(self.options.bloom_bits_per_key);
+      writer.set_prefix_extractor(self.options.prefix_extractor.as_ref());
+      writer.set_key_order(&self.options.comparator);
+      for entry in entries {
+        match values.as_deref_mut() {
+          Some(values) => writer.add(&values.separate(entry?)?)?,
+          None => writer.add(&entry?)?,
+        }
+      }
+      for tombstone in range_tombstones {
+        writer.add_range_tombstone(tombstone);
+      }
+      if let Some(values) = values.as_deref_mut() {
+        values.finish()?;
+      }
+      let start = Instant::now();
+      writer.finish()?;
+      if start.elapsed() >= self.options.slow_operation_threshold {
+        let message = format_args!(
+          "syncing segment {} took {:?}",
+          path.display(),
+          start.elapsed()
+        );
+        self.options.logger.log(Level::Warn, subsystem, message);
+      }
+      Ok(())
+    })
+    .and_then(|_| open_segment(&self.options, &self.value_log, path));
+    if result.is_err() {
+      let _ = storage.delete(path);
+      if let Some(values) = values {
+        values.abandon();
+      }
+    }
+    result
+  }
+}
+
+impl Disk {
+  pub fn new(dir: &str) -> Disk {
+    Disk::open(dir, DiskOptions::default()).unwrap()
+  }
+
+  /// Opens an empty database whose files are all kept in memory, for test suites that
+  /// shouldn't touch the file system and for ordered in-process caches. It behaves exactly
+  /// like a database on disk, except that its contents are gone once the last handle is
+  /// dropped and it can't be backed up. Other options can be combined with a
+  /// `MemoryBackend` through `DiskOptions::storage`. Fails only if the background threads
+  /// can't be started.
+  pub fn open_in_memory() -> io::Result<Disk> {
+    let options = DiskOptions {
+      storage: Storage::new(MemoryBackend::new()),
+      ..DiskOptions::default()
+    };
+    Disk::open(IN_MEMORY_DIR, options)
+  }
+
+  /// Opens the database in `dir`, loading the segments and recovering the live WAL files
+  /// listed in its manifest.
+  pub fn open(dir: &str, options: DiskOptions) -> io::Result<Disk> {
+    Disk::open_with(dir, options, None)
+  }
+
+  /// Opens the database another process keeps in `dir` as a follower, catching up with its
+  /// writes every `interval`, without writing anything to the directory. See `Follower`.
+  pub(crate) fn open_follower(
+    dir: &str,
+    options: DiskOptions,
+    interval: Duration,
+  ) -> io::Result<Disk> {
+    Disk::open_with(dir, options, Some(interval))
+  }
+
+  fn open_with(
+    dir: &str,
+    mut options: DiskOptions,
+    follow_interval: Option<Duration>,
+  ) -> io::Result<Disk> {
+    let start = Instant::now();
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!("fluxdb::recovery", dir).entered();
+    // Lookups read at most one segment once compacted, so a lower average would never be met.
+    let too_low = |trigger: &f64| trigger.is_nan() || *trigger < 1.0;
+    if let Some(trigger) = options.read_amplification_trigger.filter(too_low) {
+      let message = format!("read_amplification_trigger of {} is below 1", trigger);
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
     }
+    // Writes waiting on a threshold nothing brings them back under would wait forever.
+    if let Some(message) = options.write_stall.unreachable_stop() {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+    }
+    let dir = PathBuf::from(dir);
+    if options.single_file || (options.storage.is_local() && dir.is_file()) {
+      // Opening the container repairs and reclaims it, which would corrupt the one a primary
+      // is writing.
+      if follow_interval.is_some() {
+        let message =
+          format!("{} is a single-file database, which can't be followed", dir.display());
+        return Err(io::Error::new(io::ErrorKind::Unsupported, message));
+      }
+      options.storage = Storage::new(SingleFileBackend::open(&dir)?);
+    }
+    let health = HealthMonitor::new(options.storage.clone(), options.logger.clone());
+    options.storage = health.guard();
+    let storage = &options.storage;
+    // Taken before anything is read, as opening removes the files the manifest doesn't list.
+    let lock = match follow_interval {
+      Some(_) => StorageLock::unlocked(),
+      None => storage.lock(&dir.join(LOCK_FILE))?,
+    };
+
+    if let Some(archive) = options.wal_archive.as_ref().filter(|_| follow_interval.is_none()) {
+      if storage.is_local() {
+        create_dir_all(archive.path(&dir))?;
+      }
+    }
+
+    let mut manifest = match Manifest::load_with(storage, &dir)? {
+      // Files of the primary are only ever read, as it may be writing them.
+      Some(manifest) if follow_interval.is_some() => {
+        manifest.check_features()?;
+        manifest.check_comparator(&options.comparator)?;
+        manifest
+      }
+      None if follow_interval.is_some() => {
+        let message = format!("no database to follow in {}", dir.display());
+        return Err(io::Error::new(io::ErrorKind::NotFound, message));
+      }
+      Some(manifest) => {
+        manifest.check_features()?;
+        manifest.check_comparator(&options.comparator)?;
+        if let Some(mirror) = &options.wal_mirror {
+          for name in mirror.restore(storage, &dir, &manifest.wal_files)? {
+            let message = format_args!("restored {} from the WAL mirror", name);
+            options.logger.log(Level::Warn, Subsystem::Recovery, message);
+          }
+        }
+        manifest.verify_files_exist_with(storage, &dir)?;
+        if options.wal_archive.is_some() {
+          // WAL files retired just before a crash are archived rather than cleaned up.
+          for path in storage.list_with_extension(&dir, WAL_EXTENSION)? {
+            let name = file_name(&path);
+            if is_database_file_name(&name, WAL_EXTENSION) && !manifest.wal_files.contains(&name) {
+              discard_wal_file(&options, &dir, &path)?;
+            }
+          }
+        }
+        let cleanup = manifest.clean_up_unlisted_files_with(storage, &dir)?;
+        for path in cleanup.removed {
+          let message = format_args!("removed {} left by an interrupted write", path.display());
+          options.logger.log(Level::Info, Subsystem::Recovery, message);
+        }
+        for (path, quarantined) in cleanup.quarantined {
+          let message = format_args!(
+            "moved {} to {}: the database never writes a file by that name",
+            path.display(),
+            quarantined.display()
+          );
+          options.logger.log(Level::Warn, Subsystem::Recovery, message);
+        }
+        manifest
+      }
+      // Directories written before the manifest existed: every WAL file is live.
+      // Stray files are quarantined on the next open, once there is a manifest.
+      None => Manifest {
+        wal_files: find_wal_files_with(storage, &dir)?.iter().map(|path| file_name(path)).collect(),
+        comparator: match &options.comparator {
+          order if order.is_bytewise() => None,
+          order => Some(order.name().to_owned()),
+        },
+        ..Manifest::default()
+      },
+    };
+
+    let previous = manifest.clone();
+
+    let value_log = ValueLog::new(storage, &dir);
+    value_log.load(&manifest.value_log_files)?;
+    let segments = manifest
+      .segment_paths(&dir)
+      .iter()
+      .rev()
+      .map(|path| open_listed_segment(&options, &value_log, &manifest, path).map(Arc::new))
+      .collect::<io::Result<Vec<_>>>()?;
 
-    self.mem_table.remove(key, timestamp);
+    let replayed = manifest.wal_paths(&dir);
+    let pinned = manifest.pinned_sequences();
+    let mut followed = None;
+    let (mut wal, mem_table, mut recovery, kept) = match follow_interval {
+      Some(_) => {
+        let (files, mem_table, recovery) =
+          FollowedWal::open(&replayed, &options, manifest.last_sequence, &pinned)?;
+        followed = Some(files);
+        // A follower never writes: its log only stands in for the one of a primary.
+        let scratch = DiskOptions {
+          storage: Storage::new(MemoryBackend::new()),
+          wal_mirror: None,
+          ..options.clone()
+        };
+        (WAL::create_with_options(&dir, &scratch)?, mem_table, recovery, Vec::new())
+      }
+      None => WAL::recover_files(&dir, &replayed, &options, manifest.last_sequence, &pinned)?,
+    };
+    recovery.segments_opened = segments.len() as u64;
+    recovery.segment_bytes = segments.iter().map(|segment| segment.file_size()).sum();
+    let last_sequence = manifest.last_sequence.max(mem_table.last_sequence());
 
-    Ok(1)
+    let mut kept_wal_bytes = 0;
+    if follow_interval.is_none() {
+      // The replayed files holding records stay live until the memtable is flushed. Record
+      // the fresh WAL before retiring the others, so a crash in between only leaves unlisted
+      // files behind.
+      for path in &kept {
+        kept_wal_bytes += storage.open(path)?.size()?;
+      }
+      manifest.wal_files = kept.iter().map(|path| file_name(path)).collect();
+      manifest.wal_files.push(file_name(wal.path()));
+      manifest.last_sequence = last_sequence;
+      for feature in options.on_disk_features() {
+        manifest.add_feature(feature);
+      }
+      // The replayed records must be durable in the fresh WAL before the old files go.
+      if !replayed.is_empty() {
+        wal.sync()?;
+      }
+      manifest.store_with(storage, &dir)?;
+      record_edit(&options, &dir, &previous, &manifest, EditReason::Open);
+      for path in replayed.iter().filter(|path| !kept.contains(path)) {
+        discard_wal_file(&options, &dir, path)?;
+      }
+      prune_wal_archive(&options, &dir);
+      if let Some(mirror) = &options.wal_mirror {
+        mirror.remove_unlisted(&manifest.wal_files);
+      }
+    }
+
+    // Replayed records were counted when first written.
+    let wal_size = wal.size();
+    // Replayed writes count as made on open for `memtable_max_age`.
+    let replayed_writes = follow_interval.is_none() && mem_table.record_count() > 0;
+    let active_since = replayed_writes.then(Instant::now);
+    let memtable_deadline = active_since
+      .zip(options.memtable_max_age)
+      .and_then(|(since, max_age)| since.checked_add(max_age));
+    let inner = Arc::new(DiskInner {
+      mem_tables: RwLock::new(MemTables {
+        active: mem_table,
+        immutable: Vec::new(),
+      }),
+      segments: RwLock::new(Arc::new(segments)),
+      log: Mutex::new(WriteLog {
+        active_wal_files: manifest.wal_files.clone(),
+        wal,
+        manifest,
+        last_sequence,
+        sealed_wal_bytes: kept_wal_bytes,
+        active_sealed_wal_bytes: kept_wal_bytes,
+        counted_wal_size: wal_size,
+        active_since,
+        followed,
+      }),
+      visible_sequence: AtomicU64::new(last_sequence),
+      snapshots: SnapshotList::default(),
+      lock_metrics: LockMetrics::new(options.lock_metrics),
+      // A follower counts its own reads, and leaves the statistics of the primary alone.
+      stats: match follow_interval {
+        Some(_) => Statistics::default(),
+        None => Statistics::load_with(storage, &dir)?,
+      },
+      last_scrub: Mutex::new(None),
+      read_amplification: AtomicU64::new(0),
+      read_compaction_requested: AtomicBool::new(false),
+      compaction_rounds: Mutex::new(VecDeque::new()),
+      pending_bytes: AtomicUsize::new(0),
+      value_log,
+      obsolete: Mutex::new(Vec::new()),
+      invalidations: Invalidations::default(),
+      subscribers: Subscribers::default(),
+      health,
+      sync: Mutex::new(SyncState {
+        synced: last_sequence,
+        syncing: false,
+        failed: None,
+      }),
+      sync_changed: Condvar::new(),
+      work: Mutex::new(WorkState {
+        memtable_deadline,
+        ..WorkState::default()
+      }),
+      work_changed: Condvar::new(),
+      follow_interval,
+      _lock: lock,
+      dir,
+      options,
+    });
+
+    for sequence in pinned {
+      inner.snapshots.acquire(sequence);
+    }
+    recovery.duration = start.elapsed();
+    let message = format_args!(
+      "opened with {} records replayed from {} WAL files in {:?}",
+      recovery.records_replayed, recovery.wal_files_replayed, recovery.duration
+    );
+    let level = inner.options.operation_level(recovery.duration);
+    inner.options.logger.log(level, Subsystem::Recovery, message);
+    inner.stats.record_recovery(recovery);
+    let weak = Arc::downgrade(&inner);
+    inner.health.on_read_only(move || {
+      if let Some(inner) = weak.upgrade() {
+        inner.request_background_work();
+      }
+    });
+
+    let worker_inner = inner.clone();
+    let handle = match follow_interval {
+      Some(interval) => thread::Builder::new()
+        .name("fluxdb-follower".to_owned())
+        .spawn(move || worker_inner.run_follower(interval))?,
+      None => thread::Builder::new()
+        .name("fluxdb-flush".to_owned())
+        .spawn(move || worker_inner.run_background_work())?,
+    };
+    // Followers leave checking the files to their primary.
+    let scrubber = match (&inner.options.scrub, follow_interval) {
+      (Some(options), None) => {
+        let (scrubber_inner, options) = (inner.clone(), options.clone());
+        let scrubber = thread::Builder::new()
+          .name("fluxdb-scrub".to_owned())
+          .spawn(move || scrubber_inner.run_scrubber(options))?;
+        Some(scrubber)
+      }
+      _ => None,
+    };
+
+    Ok(Disk {
+      _worker: Arc::new(BackgroundWorker {
+        inner: inner.clone(),
+        handle: Some(handle),
+        scrubber,
+      }),
+      inner,
+    })
+  }
+
+  /// Catches up with the primary of a follower right away. See `Follower::catch_up`.
+  pub(crate) fn catch_up(&self) -> io::Result<()> {
+    self.inner.catch_up()
+  }
+
+  /// Looks up a key in the active memtable, then the frozen ones, then the segments, newest
+  /// first. Returns `None` if the key is missing or deleted.
+  pub fn get(&self, key: &[u8]) -> io::Result<Option<DiskEntry>> {
+    self.get_at_sequence(key, u64::MAX)
+  }
+
+  /// Calls `f` with the latest value of a key, if the key is live, and returns its result.
+  /// The value is read as `get_pinned` does, and released once `f` returns.
+  pub fn get_ref<R>(&self, key: &[u8], f: impl FnOnce(&[u8]) -> R) -> io::Result<Option<R>> {
+    Ok(self.get_pinned(key)?.map(|value| f(&value)))
+  }
+
+  /// Returns the latest value of a key, if the key is live, without copying it when it is
+  /// in a memtable.
+  ///
+  /// A value in a memtable is borrowed in place, pinned by keeping the arena chunk holding
+  /// it alive, so the guard holds no lock and writes go on meanwhile. Keys only found in
+  /// segments, and every key when a `value_schema` may have to upgrade the value, are read
+  /// as `get` does and handed out as a `Bytes`.
+  pub fn get_pinned(&self, key: &[u8]) -> io::Result<Option<PinnedValue<'_>>> {
+    let start = Instant::now();
+    if self.inner.options.value_schema.is_none() {
+      let mem_tables = self.inner.read_mem_tables();
+      let frozen = mem_tables.immutable.iter().rev().map(|frozen| &frozen.table);
+      let tables = || std::iter::once(&mem_tables.active).chain(frozen.clone().map(Arc::as_ref));
+      let found = tables().find_map(|records| {
+        records.position_at(key, u64::MAX).map(|index| records.record(key, index))
+      });
+      if let Some(record) = found {
+        // Memtables only hold writes newer than the segments, so only their range
+        // tombstones can cover the record.
+        let covered = tables().any(|table| {
+          let mut tombstones = table.range_tombstones().iter();
+          tombstones.any(|tombstone| tombstone.covers(key, record.sequence, table.order()))
+        });
+        self.inner.stats.record_gets(1);
+        self.inner.record_latency(Operation::Get, Some(key), start.elapsed());
+        if record.is_deleted || covered {
+          return Ok(None);
+        }
+        return Ok(record.value.clone().map(|value| PinnedValue {
+          pinned: Pinned::MemTable(value),
+          disk: PhantomData,
+        }));
+      }
+    }
+    let value = self.get(key)?.map(|entry| entry.value_bytes());
+    Ok(value.map(|value| PinnedValue {
+      pinned: Pinned::Owned(value),
+      disk: PhantomData,
+    }))
+  }
+
+  /// Looks up a key as `read_options` says: as of its snapshot, if any, reading segment
+  /// blocks through the block cache as it asks. Fails with `ErrorKind::InvalidInput` if the
+  /// snapshot was taken from another database.
+  pub fn get_with(
+    &self,
+    key: &[u8],
+    read_options: &ReadOptions,
+  ) -> io::Result<Option<DiskEntry>> {
+    let sequence = self.read_sequence(read_options)?;
+    self.get_at_sequence_with(key, sequence, read_options.block_reads())
+  }
+
+  /// Returns the sequence number `read_options` reads as of.
+  fn read_sequence(&self, read_options: &ReadOptions) -> io::Result<u64> {
+    match &read_options.snapshot {
+      Some(snapshot) if !Arc::ptr_eq(&snapshot.disk().inner, &self.inner) => Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "the snapshot was taken from another database",
+      )),
+      Some(snapshot) => Ok(snapshot.sequence()),
+      None => Ok(u64::MAX),
+    }
+  }
+
+  /// Looks up the latest version of a key written at or before `sequence`.
+  pub(crate) fn get_at_sequence(
+    &self,
+    key: &[u8],
+    sequence: u64,
+  ) -> io::Result<Option<DiskEntry>> {
+    self.get_at_sequence_with(key, sequence, BlockReads::default())
+  }
+
+  /// Like `get_at_sequence`, reading segment blocks as `reads` says.
+  fn get_at_sequence_with(
+    &self,
+    key: &[u8],
+    sequence: u64,
+    reads: BlockReads,
+  ) -> io::Result<Option<DiskEntry>> {
+    let start = Instant::now();
+    let entry = match self.lookup_with(key, sequence, reads)? {
+      Some(entry) => DiskEntry::from_entry(self.inner.upgrade(entry)?),
+      None => None,
+    };
+    self.inner.stats.record_gets(1);
+    self.inner.record_latency(Operation::Get, Some(key), start.elapsed());
+    Ok(entry)
+  }
+
+  /// Looks up the version of a key with the latest timestamp at or before `timestamp`, the
+  /// latest write winning ties. Returns `None` if the key was missing or deleted as of then.
+  /// Versions older than `DiskOptions::retained_versions` allow may have been dropped.
+  pub fn get_at(&self, key: &[u8], timestamp: u128) -> io::Result<Option<DiskEntry>> {
+    let versions = self.versions(key)?;
+    let version = versions
+      .into_iter()
+      .filter(|entry| entry.timestamp <= timestamp)
+      .max_by_key(|entry| (entry.timestamp, entry.sequence));
+    self.inner.stats.record_gets(1);
+    match version {
+      Some(entry) => Ok(DiskEntry::from_entry(self.inner.upgrade(entry)?)),
+      None => Ok(None),
+    }
+  }
+
+  /// Returns the versions of a key the database still holds, deletions included, newest
+  /// first by timestamp and then by write, so a version kept by
+  /// `TimestampRegression::KeepAsOlderVersion` shows below the newer ones. A range deletion
+  /// covering the key shows as a deletion of its own. How many versions are kept is set by
+  /// `DiskOptions::retained_versions`, on top of the versions live snapshots read.
+  pub fn history(&self, key: &[u8]) -> io::Result<Vec<Version>> {
+    self
+      .versions(key)?
+      .into_iter()
+      .map(|entry| Ok(Version::from_entry(self.inner.upgrade(entry)?)))
+      .collect()
+  }
+
+  /// Gathers every version of a key from the memtables and segments, newest first by
+  /// timestamp, with a tombstone for each range deletion covering it.
+  fn versions(&self, key: &[u8]) -> io::Result<Vec<Entry>> {
+    let (mut versions, mut range_tombstones) = {
+      let mem_tables = self.inner.read_mem_tables();
+      let frozen = mem_tables.immutable.iter().map(|frozen| frozen.table.as_ref());
+      let versions: Vec<Entry> = std::iter::once(&mem_tables.active)
+        .chain(frozen)
+        .flat_map(|table| table.range(key..=key).map(record_entry))
+        .collect();
+      (versions, mem_tables.range_tombstones(u64::MAX))
+    };
+    // Segments are taken after the memtables, so a concurrent flush can't hide versions;
+    // those it copies over are read twice and deduplicated.
+    for segment in self.inner.segments().iter() {
+      range_tombstones.extend_from_slice(segment.range_tombstones());
+      if segment.has_bloom_filter() && !segment.may_contain(key) {
+        continue;
+      }
+      for entry in segment.iter_from(Bound::Included(key)) {
+        let entry = entry?;
+        if entry.key != key {
+          break;
+        }
+        versions.push(entry);
+      }
+    }
+
+    let order = &self.inner.options.comparator;
+    let covering = range_tombstones.iter().filter(|tombstone| tombstone.contains(key, order));
+    versions.extend(covering.map(|tombstone| Entry {
+      key: key.to_vec(),
+      value: None,
+      timestamp: tombstone.timestamp,
+      sequence: tombstone.sequence,
+      schema: 0,
+      retained: None,
+      value_pointer: false,
+      backdated: false,
+    }));
+    // Copies of a version share its timestamp, so they still end up next to each other.
+    versions.sort_by_key(|entry| std::cmp::Reverse((entry.timestamp, entry.sequence)));
+    versions.dedup_by_key(|entry| entry.sequence);
+    Ok(versions)
+  }
+
+  /// Looks up several keys at once, returning their entries in the order of `keys`. The keys
+  /// are looked up in sorted order, so each segment is searched once for all of them, its
+  /// bloom filter is consulted per key, and keys falling into the same block share its read.
+  pub fn multi_get(&self, keys: &[&[u8]]) -> Result<Vec<Option<DiskEntry>>, FluxError> {
+    self.multi_get_at(keys, u64::MAX)
+  }
+
+  /// Looks up several keys as of `sequence`.
+  pub(crate) fn multi_get_at(
+    &self,
+    keys: &[&[u8]],
+    sequence: u64,
+  ) -> Result<Vec<Option<DiskEntry>>, FluxError> {
+    self.inner.stats.record_gets(keys.len() as u64);
+    let mut budget = ReadBudget::new(self.inner.options.read_memory_limit);
+    let mut order: Vec<usize> = (0..keys.len()).collect();
+    let key_order = &self.inner.options.comparator;
+    order.sort_by(|&a, &b| key_order.compare(keys[a], keys[b]));
+    // `None` until the latest version of the key, possibly a tombstone, has been found.
+    let mut found: Vec<Option<Entry>> = vec![None; keys.len()];
+
+    let (view, mut range_tombstones) = {
+      let mem_tables = self.inner.read_mem_tables();
+      for &i in order.iter() {
+        found[i] = mem_tables.active.fetch_at(keys[i], sequence).map(record_entry);
+      }
+      (self.inner.read_view(&mem_tables), mem_tables.range_tombstones(sequence))
+    };
+    for table in view.immutable.iter() {
+      for &i in order.iter() {
+        if found[i].is_none() {
+          found[i] = table.fetch_at(keys[i], sequence).map(record_entry);
+        }
+      }
+    }
+    for entry in found.iter().flatten() {
+      budget.charge(&entry.key, entry.value.as_deref())?;
+    }
+
+    let segments = &view.segments;
+    for segment in segments.iter() {
+      range_tombstones.extend(visible_range_tombstones(segment.range_tombstones(), sequence));
+    }
+    for segment in segments.iter() {
+      let pending: Vec<usize> = order.iter().copied().filter(|&i| found[i].is_none()).collect();
+      if pending.is_empty() {
+        break;
+      }
+      let pending_keys: Vec<&[u8]> = pending.iter().map(|&i| keys[i]).collect();
+      for (i, entry) in pending.into_iter().zip(segment.get_many_at(&pending_keys, sequence)?) {
+        if let Some(entry) = &entry {
+          budget.charge(&entry.key, entry.value.as_deref())?;
+        }
+        found[i] = entry;
+      }
+    }
+
+    found
+      .into_iter()
+      .zip(keys)
+      .map(|(entry, key)| match resolve_range_deletes(key, entry, &range_tombstones, key_order) {
+        Some(entry) => Ok(DiskEntry::from_entry(self.inner.upgrade(entry)?)),
+        None => Ok(None),
+      })
+      .collect()
+  }
+
+  /// Finds the latest version of a key written at or before `sequence`, tombstones included.
+  /// A key removed by a range deletion reads as a tombstone written by it.
+  fn lookup(&self, key: &[u8], sequence: u64) -> io::Result<Option<Entry>> {
+    self.lookup_with(key, sequence, BlockReads::default())
+  }
+
+  /// Like `lookup`, reading segment blocks as `block_reads` says.
+  fn lookup_with(
+    &self,
+    key: &[u8],
+    sequence: u64,
+    block_reads: BlockReads,
+  ) -> io::Result<Option<Entry>> {
+    let (mut entry, view, mut range_tombstones) = {
+      let mem_tables = self.inner.read_mem_tables();
+      let entry = mem_tables.active.fetch_at(key, sequence).map(record_entry);
+      (entry, self.inner.read_view(&mem_tables), mem_tables.range_tombstones(sequence))
+    };
+    for table in view.immutable.iter() {
+      if entry.is_some() {
+        break;
+      }
+      entry = table.fetch_at(key, sequence).map(record_entry);
+    }
+
+    let segments = &view.segments;
+    for segment in segments.iter() {
+      range_tombstones.extend(visible_range_tombstones(segment.range_tombstones(), sequence));
+    }
+    let (mut checks, mut negatives, mut false_positives, mut reads) = (0, 0, 0, 0);
+    for segment in segments.iter() {
+      if entry.is_some() {
+        break;
+      }
+      if segment.has_bloom_filter() {
+        checks += 1;
+        if !segment.may_contain(key) {
+          negatives += 1;
+          continue;
+        }
+      }
+      reads += 1;
+      entry = segment.get_at_with(key, sequence, block_reads)?;
+      if entry.is_none() && segment.has_bloom_filter() {
+        false_positives += 1;
+      }
+    }
+    self.inner.stats.record_lookup(checks, negatives, false_positives, reads);
+    self.inner.record_read_amplification(reads);
+
+    let order = &self.inner.options.comparator;
+    Ok(resolve_range_deletes(key, entry, &range_tombstones, order))
+  }
+
+  /// Returns the live entries whose keys fall within the range, in key order. Fails with
+  /// `FluxError::MemoryLimit` once the entries pass `DiskOptions::read_memory_limit`.
+  pub fn scan<'a, R: RangeBounds<&'a [u8]>>(
+    &self,
+    range: R,
+  ) -> Result<Vec<DiskEntry>, FluxError> {
+    self.scan_at(range, u64::MAX, usize::MAX)
+  }
+
+  /// Returns up to `limit` live entries within the range as of `sequence`, in key order.
+  pub(crate) fn scan_at<'a, R: RangeBounds<&'a [u8]>>(
+    &self,
+    range: R,
+    sequence: u64,
+    limit: usize,
+  ) -> Result<Vec<DiskEntry>, FluxError> {
+    let options = ScanOptions { limit, ..ScanOptions::default() };
+    self.scan_with_at(range, sequence, &options)
+  }
+
+  /// Returns up to `limit` live entries within the range as of `sequence`, in decreasing
+  /// key order.
+  pub(crate) fn scan_rev_at<'a, R: RangeBounds<&'a [u8]>>(
+    &self,
+    range: R,
+    sequence: u64,
+    limit: usize,
+  ) -> Result<Vec<DiskEntry>, FluxError> {
+    let options = ScanOptions { limit, reverse: true, ..ScanOptions::default() };
+    self.scan_with_at(range, sequence, &options)
+  }
+
+  /// Returns the live entries within the range that pass the filters of `options`, in the
+  /// order and up to the limit it sets. The filters run inside the engine, so the records
+  /// they reject cost little more than reading their blocks; see `ScanOptions`.
+  pub fn scan_with<'a, R: RangeBounds<&'a [u8]>>(
+    &self,
+    range: R,
+    options: &ScanOptions,
+  ) -> Result<Vec<DiskEntry>, FluxError> {
+    self.scan_with_at(range, u64::MAX, options)
+  }
+
+  /// Returns a page of up to `limit` live entries within the range, and a continuation
+  /// token if more follow. Passing the token back with the same range returns the next
+  /// page, so an API can paginate without holding an iterator or a snapshot between
+  /// requests; each page reads the latest writes. The token is opaque, encoding the key the
+  /// next page starts after; a page never starts before the range, whatever the token says.
+  /// Fails with `FluxError::InvalidArgument` if `limit` is 0 or the token is malformed.
+  pub fn scan_page<'a, R: RangeBounds<&'a [u8]>>(
+    &self,
+    range: R,
+    limit: usize,
+    continuation: Option<Vec<u8>>,
+  ) -> Result<(Vec<DiskEntry>, Option<Vec<u8>>), FluxError> {
+    if limit == 0 {
+      return Err(FluxError::InvalidArgument("page limit must be positive".to_owned()));
+    }
+    let start = range.start_bound().map(|key| key.to_vec());
+    let start = match continuation.map(|token| decode_continuation(&token)).transpose()? {
+      // A token from another range, or forged, can't reach before this one.
+      Some(Bound::Excluded(after)) => match &start {
+        Bound::Included(first) | Bound::Excluded(first)
+          if self.inner.options.comparator.compare(&after, first).is_lt() =>
+        {
+          start
+        }
+        _ => Bound::Excluded(after),
+      },
+      _ => start,
+    };
+    let bounds = (start.as_ref().map(Vec::as_slice), range.end_bound().cloned());
+    // One entry past the page tells whether another one follows.
+    let options = ScanOptions { limit: limit.saturating_add(1), ..ScanOptions::default() };
+    let reads = BlockReads::default();
+    let mut entries = self.read_range(bounds, u64::MAX, &options, None, false, reads)?;
+    if entries.len() <= limit {
+      return Ok((entries, None));
+    }
+    entries.truncate(limit);
+    let token = entries.last().map(|entry| encode_continuation(entry.key()));
+    Ok((entries, token))
+  }
+
+  /// Like `scan_with`, as of `sequence`.
+  pub(crate) fn scan_with_at<'a, R: RangeBounds<&'a [u8]>>(
+    &self,
+    range: R,
+    sequence: u64,
+    options: &ScanOptions,
+  ) -> Result<Vec<DiskEntry>, FluxError> {
+    let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+    self.read_range(bounds, sequence, options, None, false, BlockReads::default())
+  }
+
+  /// Like `scan_with`, read as `read_options` says: as of its snapshot, if any, reading
+  /// segment blocks through the block cache as it asks. Fails with `ErrorKind::InvalidInput`
+  /// if the snapshot was taken from another database.
+  pub fn scan_read<'a, R: RangeBounds<&'a [u8]>>(
+    &self,
+    range: R,
+    options: &ScanOptions,
+    read_options: &ReadOptions,
+  ) -> Result<Vec<DiskEntry>, FluxError> {
+    let sequence = self.read_sequence(read_options)?;
+    let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+    let reads = read_options.block_reads();
+    self.read_range(bounds, sequence, options, None, false, reads)
+  }
+
+  /// Returns the keys of the live entries within the range, in key order, without reading
+  /// their values: segment blocks are still read and decompressed, but values are neither
+  /// copied out of them nor read from the value log, so auditing which keys exist costs
+  /// little more than the blocks themselves. Fails with `FluxError::MemoryLimit` once the
+  /// keys pass `DiskOptions::read_memory_limit`.
+  pub fn scan_keys<'a, R: RangeBounds<&'a [u8]>>(
+    &self,
+    range: R,
+  ) -> Result<Vec<Vec<u8>>, FluxError> {
+    self.scan_keys_at(range, u64::MAX)
+  }
+
+  /// Returns the keys of the live entries within the range as of `sequence`.
+  pub(crate) fn scan_keys_at<'a, R: RangeBounds<&'a [u8]>>(
+    &self,
+    range: R,
+    sequence: u64,
+  ) -> Result<Vec<Vec<u8>>, FluxError> {
+    let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+    let options = ScanOptions::default();
+    let entries = self.read_range(bounds, sequence, &options, None, true, BlockReads::default())?;
+    Ok(entries.iter().map(|entry| entry.key().to_vec()).collect())
+  }
+
+  /// Returns the live entries whose keys start with `prefix`, in key order. When `prefix`
+  /// is one of the prefixes of `DiskOptions::prefix_extractor`, the segments whose prefix
+  /// filter rules it out are skipped without reading any of their blocks.
+  pub fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<DiskEntry>, FluxError> {
+    self.scan_prefix_at(prefix, u64::MAX)
+  }
+
+  /// Returns the live entries whose keys start with `prefix` as of `sequence`.
+  pub(crate) fn scan_prefix_at(
+    &self,
+    prefix: &[u8],
+    sequence: u64,
+  ) -> Result<Vec<DiskEntry>, FluxError> {
+    if !self.inner.options.comparator.is_bytewise() {
+      // Keys sharing a prefix needn't be adjacent in other orders.
+      let owned = prefix.to_vec();
+      let key_filter: ScanFilter = Arc::new(move |key: &[u8]| key.starts_with(&owned));
+      let options = ScanOptions { key_filter: Some(key_filter), ..ScanOptions::default() };
+      let bounds = (Bound::Unbounded, Bound::Unbounded);
+      let reads = BlockReads::default();
+      return self.read_range(bounds, sequence, &options, Some(prefix), false, reads);
+    }
+    let end = prefix_end(prefix);
+    let end = end.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+    let bounds = (Bound::Included(prefix), end);
+    let options = ScanOptions::default();
+    self.read_range(bounds, sequence, &options, Some(prefix), false, BlockReads::default())
+  }
+
+  /// Reads the entries within the bounds as `options` asks, and segment blocks as `reads`
+  /// says. With a `prefix` every key within the bounds starts with, segments whose prefix
+  /// filter rules it out are skipped.
+  fn read_range(
+    &self,
+    bounds: (Bound<&[u8]>, Bound<&[u8]>),
+    sequence: u64,
+    options: &ScanOptions,
+    prefix: Option<&[u8]>,
+    keys_only: bool,
+    reads: BlockReads,
+  ) -> Result<Vec<DiskEntry>, FluxError> {
+    let kind = match keys_only {
+      true => RangeRead::Keys,
+      false => RangeRead::Live,
+    };
+    let entries = self.read_range_versions(bounds, sequence, options, prefix, kind, reads)?;
+    Ok(entries.into_iter().filter_map(DiskEntry::from_entry).collect())
+  }
+
+  /// Reads the latest version of each key within the bounds as `read_range` does, keeping
+  /// the deleted keys as tombstones if `kind` asks for them. A key hidden by a range
+  /// tombstone reads as a tombstone with the timestamp of the deletion.
+  ///
+  /// The memtables are copied a bounded number of keys at a time, so a small page of a
+  /// large memtable doesn't copy all of it; the read goes on past the keys copied only if
+  /// the page isn't full yet.
+  fn read_range_versions(
+    &self,
+    bounds: (Bound<&[u8]>, Bound<&[u8]>),
+    sequence: u64,
+    options: &ScanOptions,
+    prefix: Option<&[u8]>,
+    kind: RangeRead,
+    reads: BlockReads,
+  ) -> Result<Vec<Entry>, FluxError> {
+    let keys_only = kind == RangeRead::Keys;
+    let mut budget = ReadBudget::new(self.inner.options.read_memory_limit);
+    let (descending, key_filter) = (options.reverse, options.key_filter.as_ref());
+    let order = &self.inner.options.comparator;
+    let max_keys = options.limit.max(MEM_TABLE_SCAN_KEYS);
+
+    let mut entries = Vec::new();
+    let mut last_key: Option<Vec<u8>> = None;
+    // Key the memtables were copied up to, when they hold more keys past it.
+    let mut resume: Option<Vec<u8>> = None;
+    loop {
+      let bounds = match (&resume, descending) {
+        (None, _) => bounds,
+        (Some(key), false) => (Bound::Excluded(key.as_slice()), bounds.1),
+        (Some(key), true) => (bounds.0, Bound::Excluded(key.as_slice())),
+      };
+      let mut cutoffs = Vec::new();
+      let mut source = |table: &InMemoryTable| {
+        let (source, cutoff) =
+          mem_table_source(table, bounds, descending, key_filter, keys_only, max_keys);
+        cutoffs.extend(cutoff);
+        source
+      };
+
+      let (active, view, mut range_tombstones) = {
+        let mem_tables = self.inner.read_mem_tables();
+        let active = source(&mem_tables.active);
+        (active, self.inner.read_view(&mem_tables), mem_tables.range_tombstones(sequence))
+      };
+      let mut sources = vec![active];
+      for table in view.immutable.iter() {
+        sources.push(source(table));
+      }
+      // The scan stops at the first key a memtable wasn't copied past, and reads on from it.
+      let cutoff = match descending {
+        false => cutoffs.into_iter().min_by(|a, b| order.compare(a, b)),
+        true => cutoffs.into_iter().max_by(|a, b| order.compare(a, b)),
+      };
+      let extractor = self.inner.options.prefix_extractor.as_ref();
+      for segment in view.segments.iter() {
+        // Range tombstones of skipped segments still hide keys of the others.
+        range_tombstones.extend(visible_range_tombstones(segment.range_tombstones(), sequence));
+        let may_match = |(prefix, extractor)| segment.may_contain_prefix(prefix, extractor);
+        if !prefix.zip(extractor).is_none_or(may_match) {
+          continue;
+        }
+        let key_filter = key_filter.cloned();
+        let ascending = segment.iter_from(bounds.0).filter_keys(key_filter.clone());
+        let ascending = ascending.with_reads(reads);
+        match (descending, keys_only) {
+          (false, false) => sources.push(Box::new(ascending)),
+          (false, true) => sources.push(Box::new(ascending.keys_only())),
+          (true, _) => {
+            let descending = segment.iter_rev_to(bounds.1).filter_keys(key_filter);
+            sources.push(Box::new(descending.with_reads(reads)))
+          }
+        }
+      }
+      let merge = MergeIterator::with_key_order(sources, order, descending);
+
+      let past = match descending {
+        false => std::cmp::Ordering::Greater,
+        true => std::cmp::Ordering::Less,
+      };
+      for entry in merge {
+        let entry = entry?;
+        if !order.contains(&bounds, &entry.key) || entries.len() >= options.limit {
+          break;
+        }
+        if cutoff.as_ref().is_some_and(|cutoff| order.compare(&entry.key, cutoff) == past) {
+          break;
+        }
+        // Versions come newest first; the first one old enough is the one to read.
+        if entry.sequence > sequence || entry.backdated || last_key.as_ref() == Some(&entry.key) {
+          continue;
+        }
+        last_key = Some(entry.key.clone());
+        let mut tombstones = range_tombstones.iter();
+        if let Some(tombstone) =
+          tombstones.find(|tombstone| tombstone.covers(&entry.key, entry.sequence, order))
+        {
+          if kind == RangeRead::Tombstones {
+            budget.charge(&entry.key, None)?;
+            entries.push(Entry {
+              value: None,
+              retained: None,
+              value_pointer: false,
+              timestamp: tombstone.timestamp,
+              sequence: tombstone.sequence,
+              ..entry
+            });
+          }
+          continue;
+        }
+        // Empty values of a keys-only read have nothing to upgrade.
+        let entry = match keys_only {
+          true => entry,
+          false => self.inner.upgrade(entry)?,
+        };
+        match entry.value.as_deref() {
+          Some(value) if !options.keeps_value(value) => continue,
+          None if kind != RangeRead::Tombstones => continue,
+          _ => {}
+        }
+        budget.charge(&entry.key, entry.value.as_deref())?;
+        entries.push(entry);
+      }
+      match cutoff {
+        Some(cutoff) if entries.len() < options.limit => resume = Some(cutoff),
+        _ => return Ok(entries),
+      }
+    }
+  }
+
+  /// Returns a feed of the keys written or deleted from now on, for keeping external caches
+  /// in sync. Writes are coalesced over `window` and reported with the granularity asked
+  /// for; see `InvalidationFeed`.
+  pub fn subscribe_invalidations(
+    &self,
+    granularity: Granularity,
+    window: Duration,
+  ) -> InvalidationFeed {
+    self.inner.invalidations.subscribe(granularity, window)
+  }
+
+  /// Returns a channel receiving every write committed from now on, key, operation, value
+  /// and timestamp, in commit order. Events are sent once a write is in the WAL and visible
+  /// to readers, from the writing thread, so downstream indexes can follow the database
+  /// without polling it. The channel is unbounded, so a receiver should keep up or be
+  /// dropped; it disconnects once the database is closed.
+  pub fn subscribe(&self) -> Receiver<ChangeEvent> {
+    self.inner.subscribers.subscribe()
+  }
+
+  /// Like `subscribe`, with a tokio channel. See `AsyncDisk::subscribe`.
+  #[cfg(feature = "async")]
+  pub(crate) fn subscribe_async(&self) -> tokio::sync::mpsc::UnboundedReceiver<ChangeEvent> {
+    self.inner.subscribers.subscribe_async()
+  }
+
+  /// Returns whether the database accepts writes. It turns read-only once a write to its
+  /// directory fails with `EROFS` or `EACCES`, as when the volume is remounted read-only:
+  /// reads keep being served, and writes fail right away with
+  /// `ErrorKind::ReadOnlyFilesystem`. The background thread probes the directory every
+  /// second meanwhile, and resumes writes once it accepts them again.
+  pub fn health(&self) -> Health {
+    self.inner.health.health()
+  }
+
+  /// Returns a channel receiving the health of the database every time it changes. It
+  /// disconnects once the database is closed.
+  pub fn watch_health(&self) -> Receiver<Health> {
+    self.inner.health.watch()
+  }
+
+  /// Returns the writes committed after `from_sequence`, read back from the live WAL files,
+  /// followed by every write committed from now on, for replicating the database to
+  /// another system. See the `wal_tail` module. Fails with `FluxError::InvalidArgument`
+  /// when some of the writes after `from_sequence` have already been flushed out of the WAL.
+  pub fn tail(&self, from_sequence: u64) -> Result<WalTail, FluxError> {
+    let subscribe = |subscribers: &Subscribers| subscribers.subscribe_bounded(TAIL_CAPACITY);
+    let (backlog, live) = self.wal_backlog(from_sequence, subscribe)?;
+    Ok(WalTail::new(backlog, live, from_sequence))
+  }
+
+  /// Like `tail`, with a tokio channel. See `AsyncDisk::tail`.
+  #[cfg(feature = "async")]
+  pub(crate) fn tail_async(&self, from_sequence: u64) -> Result<AsyncWalTail, FluxError> {
+    let subscribe =
+      |subscribers: &Subscribers| subscribers.subscribe_async_bounded(TAIL_CAPACITY);
+    let (backlog, live) = self.wal_backlog(from_sequence, subscribe)?;
+    Ok(AsyncWalTail::new(backlog, live, from_sequence))
+  }
+
+  /// Subscribes to the writes to come and reads the writes after `from_sequence` up to the
+  /// subscription back from the WAL.
+  fn wal_backlog<R>(
+    &self,
+    from_sequence: u64,
+    subscribe: impl FnOnce(&Subscribers) -> R,
+  ) -> Result<(VecDeque<ChangeEvent>, R), FluxError> {
+    // Holding the log lock keeps writes from committing between the end of the backlog and
+    // the subscription, and the files from being retired before they are opened.
+    let (files, last_sequence, live) = {
+      let mut log = self.inner.lock_log();
+      log.wal.flush()?;
+      let storage = &self.inner.options.storage;
+      let files = log
+        .manifest
+        .wal_files
+        .iter()
+        .map(|name| LogFileIterator::open_with(storage, &self.inner.dir.join(name)))
+        .collect::<io::Result<Vec<_>>>()?;
+      (files, log.last_sequence, subscribe(&self.inner.subscribers))
+    };
+
+    let mut backlog: Vec<ChangeEvent> = files
+      .into_iter()
+      .flatten()
+      .filter(|record| record.sequence > from_sequence && record.sequence <= last_sequence)
+      .map(record_event)
+      .collect();
+    backlog.sort_by_key(|event| event.sequence);
+    let first = backlog.first().map_or(last_sequence + 1, |event| event.sequence);
+    let last = backlog.last().map_or(from_sequence, |event| event.sequence);
+    if from_sequence < last_sequence && (first != from_sequence + 1 || last != last_sequence) {
+      return Err(FluxError::InvalidArgument(format!(
+        "writes after sequence {} are no longer in the WAL",
+        from_sequence
+      )));
+    }
+    Ok((backlog.into(), live))
+  }
+
+  /// Takes a snapshot of the database: reads through it keep seeing the current state,
+  /// whatever is written afterwards.
+  pub fn snapshot(&self) -> Snapshot {
+    // Holding the log lock keeps writers from pruning versions the new snapshot still reads.
+    let _log = self.inner.lock_log();
+    let sequence = self.inner.visible_sequence.load(Ordering::Acquire);
+    self.inner.snapshots.acquire(sequence);
+    Snapshot::new(self.clone(), sequence)
+  }
+
+  /// Opens a cursor over the database as of now, to seek to a key and move from there in
+  /// either direction.
+  pub fn cursor(&self) -> Cursor {
+    Cursor::new(self.snapshot())
+  }
+
+  /// Starts a scan of the range as of now that can be resumed from a checkpoint, even after
+  /// a restart. The scan's sequence is pinned in the manifest until the scan reaches its end
+  /// or its checkpoint is released, keeping the versions it reads from being compacted away.
+  pub fn scan_resumable<'a, R: RangeBounds<&'a [u8]>>(
+    &self,
+    range: R,
+  ) -> Result<ScanIterator, FluxError> {
+    let start = range.start_bound().map(|key| key.to_vec());
+    let end = range.end_bound().map(|key| key.to_vec());
+    let mut log = self.inner.lock_log();
+    let sequence = self.inner.visible_sequence.load(Ordering::Acquire);
+    let mut manifest = log.manifest.clone();
+    let pin = manifest.pinned_scans.iter().map(|&(id, _)| id).max().unwrap_or(0) + 1;
+    manifest.pinned_scans.push((pin, sequence));
+    manifest.last_sequence = log.last_sequence;
+    manifest.store_with(&self.inner.options.storage, &self.inner.dir)?;
+    log.manifest = manifest;
+    // Once for the pin, once for the snapshot the iterator reads through.
+    self.inner.snapshots.acquire(sequence);
+    self.inner.snapshots.acquire(sequence);
+    let snapshot = Snapshot::new(self.clone(), sequence);
+    Ok(ScanIterator::new(snapshot, pin, start, end))
+  }
+
+  /// Continues a scan from a checkpoint returned by `ScanIterator::checkpoint`, reading at
+  /// the same sequence. Fails if the checkpoint was released or its scan has completed.
+  pub fn resume_scan(&self, checkpoint: &[u8]) -> Result<ScanIterator, FluxError> {
+    let checkpoint = Checkpoint::decode(checkpoint)?;
+    let log = self.inner.lock_log();
+    if !log.manifest.pinned_scans.contains(&(checkpoint.pin, checkpoint.sequence)) {
+      return Err(FluxError::InvalidArgument(
+        "the scan checkpoint was released or its scan has completed".to_owned(),
+      ));
+    }
+    self.inner.snapshots.acquire(checkpoint.sequence);
+    drop(log);
+    let snapshot = Snapshot::new(self.clone(), checkpoint.sequence);
+    Ok(ScanIterator::new(snapshot, checkpoint.pin, checkpoint.start, checkpoint.end))
+  }
+
+  /// Releases the pin held for a resumable scan that won't be continued, so the versions it
+  /// kept can be compacted away. Releasing a checkpoint twice is harmless.
+  pub fn release_scan_checkpoint(&self, checkpoint: &[u8]) -> Result<(), FluxError> {
+    Ok(self.unpin_scan(Checkpoint::decode(checkpoint)?.pin)?)
+  }
+
+  pub(crate) fn unpin_scan(&self, pin: u64) -> io::Result<()> {
+    let mut log = self.inner.lock_log();
+    let mut manifest = log.manifest.clone();
+    let Some(index) = manifest.pinned_scans.iter().position(|&(id, _)| id == pin) else {
+      return Ok(());
+    };
+    let (_, sequence) = manifest.pinned_scans.remove(index);
+    manifest.last_sequence = log.last_sequence;
+    manifest.store_with(&self.inner.options.storage, &self.inner.dir)?;
+    log.manifest = manifest;
+    self.inner.snapshots.release(sequence);
+    Ok(())
+  }
+
+  /// Starts an optimistic transaction reading from a snapshot of the current state.
+  pub fn transaction(&self) -> Transaction {
+    Transaction::new(self.snapshot())
+  }
+
+  pub(crate) fn release_snapshot(&self, sequence: u64) {
+    self.inner.snapshots.release(sequence);
+  }
+
+  /// Returns the sequences of the snapshots still held.
+  #[cfg(test)]
+  pub(crate) fn live_snapshots(&self) -> Vec<u64> {
+    self.inner.snapshots.sequences()
+  }
+
+  #[cfg(feature = "replication")]
+  pub(crate) fn options(&self) -> &DiskOptions {
+    &self.inner.options
+  }
+
+  #[cfg(feature = "replication")]
+  pub(crate) fn dir(&self) -> &Path {
+    &self.inner.dir
+  }
+
+  /// Returns the sequence number of the last committed write.
+  pub fn last_sequence(&self) -> u64 {
+    self.inner.visible_sequence.load(Ordering::Acquire)
+  }
+
+  pub fn set(&self, key: &[u8], value: &[u8]) -> Result<usize, usize> {
+    self.set_with(key, value, &WriteOptions::default())
+  }
+
+  /// Like `set`, logging the write as `write_options` says instead of `DiskOptions`.
+  pub fn set_with(
+    &self,
+    key: &[u8],
+    value: &[u8],
+    write_options: &WriteOptions,
+  ) -> Result<usize, usize> {
+    let start = Instant::now();
+    if write_options.check().is_err() || self.inner.options.validate(key, Some(value)).is_err()
+    {
+      return Err(0);
+    }
+    if self.throttle().is_err() {
+      return Err(0);
+    }
+    let mut log = self.inner.lock_log();
+    let timestamp = self.inner.options.clock.now_micros();
+
+    let sequence = log.last_sequence + 1;
+    let schema = self.inner.options.schema_version();
+    if !write_options.disable_wal {
+      if self.rotate_wal_if_full(&mut log).is_err() {
+        return Err(0);
+      }
+      let wal_res = log.wal.record_insertion_with_schema(key, value, timestamp, sequence, schema);
+      if wal_res.is_err() {
+        return Err(0);
+      }
+      if log.wal.flush().is_err() {
+        return Err(0);
+      }
+    }
+
+    let written = Written::put(key.len() + value.len());
+    let changes = std::iter::once(Change {
+      key,
+      kind: ChangeKind::Put(value),
+      timestamp,
+      sequence,
+    });
+    self.apply(&mut log, written, sequence, changes, |mem_table, snapshots| {
+      mem_table.apply(key, Some(value), timestamp, sequence, schema, snapshots)
+    });
+
+    let result = self.finish_write_with(log, write_options).map(|_| 1).map_err(|_| 0);
+    self.inner.record_latency(Operation::Set, Some(key), start.elapsed());
+    result
+  }
+
+  pub fn delete(&self, key: &[u8]) -> Result<usize, usize> {
+    self.delete_with(key, &WriteOptions::default())
+  }
+
+  /// Like `delete`, logging the write as `write_options` says instead of `DiskOptions`.
+  pub fn delete_with(&self, key: &[u8], write_options: &WriteOptions) -> Result<usize, usize> {
+    let start = Instant::now();
+    if write_options.check().is_err() || self.inner.options.validate(key, None).is_err() {
+      return Err(0);
+    }
+    if self.throttle().is_err() {
+      return Err(0);
+    }
+    let mut log = self.inner.lock_log();
+    let timestamp = self.inner.options.clock.now_micros();
+
+    let sequence = log.last_sequence + 1;
+    if !write_options.disable_wal {
+      if self.rotate_wal_if_full(&mut log).is_err() {
+        return Err(0);
+      }
+      if log.wal.record_removal(key, timestamp, sequence).is_err() {
+        return Err(0);
+      }
+      if log.wal.flush().is_err() {
+        return Err(0);
+      }
+    }
+
+    let written = Written::delete(key.len());
+    let changes = std::iter::once(Change {
+      key,
+      kind: ChangeKind::Delete,
+      timestamp,
+      sequence,
+    });
+    self.apply(&mut log, written, sequence, changes, |mem_table, snapshots| {
+      mem_table.apply(key, None, timestamp, sequence, 0, snapshots)
+    });
+
+    let result = self.finish_write_with(log, write_options).map(|_| 1).map_err(|_| 0);
+    self.inner.record_latency(Operation::Delete, Some(key), start.elapsed());
+    result
+  }
+
+  /// Deletes every key from `start` (inclusive) to `end` (exclusive) with a single range
+  /// tombstone, instead of a tombstone per key. Reads skip the keys it covers, and compaction
+  /// drops them. Returns 0 if the range is empty. Both bounds go through the checks and
+  /// validators a delete of that key would, except an empty `start`, standing for the first
+  /// key.
+  pub fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<usize, usize> {
+    self.delete_range_with(start, end, None)
+  }
+
+  /// Deletes a range as `delete_range` does, stamping the range tombstone with `timestamp`,
+  /// in microseconds since the Unix epoch, instead of the time of the delete. Meant for
+  /// replaying deletes made elsewhere, such as on the primary of a replica.
+  pub fn delete_range_at(
+    &self,
+    start: &[u8],
+    end: &[u8],
+    timestamp: u128,
+  ) -> Result<usize, usize> {
+    self.delete_range_with(start, end, Some(timestamp))
+  }
+
+  fn delete_range_with(
+    &self,
+    start: &[u8],
+    end: &[u8],
+    timestamp: Option<u128>,
+  ) -> Result<usize, usize> {
+    if self.inner.options.comparator.compare(start, end) != std::cmp::Ordering::Less {
+      return Ok(0);
+    }
+    let start_bound = (!start.is_empty()).then_some(start);
+    let mut bounds = start_bound.into_iter().chain([end]);
+    if bounds.any(|bound| self.inner.options.validate(bound, None).is_err()) {
+      return Err(0);
+    }
+    if self.throttle().is_err() {
+      return Err(0);
+    }
+    let mut log = self.inner.lock_log();
+    let timestamp = timestamp.unwrap_or_else(|| self.inner.options.clock.now_micros());
+
+    if self.rotate_wal_if_full(&mut log).is_err() {
+      return Err(0);
+    }
+    let sequence = log.last_sequence + 1;
+    let wal_res = log.wal.record_range_removal(start, end, timestamp, sequence);
+    if wal_res.is_err() {
+      return Err(0);
+    }
+    if log.wal.flush().is_err() {
+      return Err(0);
+    }
+
+    let written = Written::delete(start.len() + end.len());
+    let changes = std::iter::once(Change {
+      key: start,
+      kind: ChangeKind::Range(end),
+      timestamp,
+      sequence,
+    });
+    self.apply(&mut log, written, sequence, changes, |mem_table, _| {
+      mem_table.apply_range_delete(start, end, timestamp, sequence)
+    });
+
+    self.finish_write(log).map(|_| 1).map_err(|_| 0)
+  }
+
+  /// Deletes a key but keeps its value, so `undelete` can restore it until a compaction runs
+  /// after `soft_delete_grace` has passed. Returns 0 if the key holds no value.
+  pub fn soft_delete(&self, key: &[u8]) -> Result<usize, usize> {
+    if self.inner.options.validate(key, None).is_err() {
+      return Err(0);
+    }
+    if self.throttle().is_err() {
+      return Err(0);
+    }
+    let mut log = self.inner.lock_log();
+    let current = match self.lookup(key, u64::MAX) {
+      Ok(current) => current,
+      Err(_) => return Err(0),
+    };
+    let Some((value, schema)) = current.and_then(|entry| Some((entry.value?, entry.schema))) else {
+      return Ok(0);
+    };
+    let timestamp = self.inner.options.clock.now_micros();
+
+    if self.rotate_wal_if_full(&mut log).is_err() {
+      return Err(0);
+    }
+    let sequence = log.last_sequence + 1;
+    let wal_res = log.wal.record_soft_removal(key, &value, timestamp, sequence, schema);
+    if wal_res.is_err() {
+      return Err(0);
+    }
+    if log.wal.flush().is_err() {
+      return Err(0);
+    }
+
+    let written = Written::delete(key.len());
+    let changes = std::iter::once(Change {
+      key,
+      kind: ChangeKind::Delete,
+      timestamp,
+      sequence,
+    });
+    self.apply(&mut log, written, sequence, changes, |mem_table, snapshots| {
+      mem_table.apply_soft_delete(key, &value, timestamp, sequence, schema, snapshots)
+    });
+
+    self.finish_write(log).map(|_| 1).map_err(|_| 0)
+  }
+
+  /// Moves the value of `old_key` to `new_key`, replacing any value there, and deletes
+  /// `old_key`. Both writes are logged as one WAL frame and become visible together, so
+  /// readers never see the value under both keys or neither, and a crash can't keep only
+  /// one of them. Returns 0 if `old_key` holds no value, or is `new_key` itself.
+  pub fn rename(&self, old_key: &[u8], new_key: &[u8]) -> Result<usize, usize> {
+    if old_key == new_key {
+      return Ok(0);
+    }
+    if self.throttle().is_err() {
+      return Err(0);
+    }
+    let mut log = self.inner.lock_log();
+    let current = match self.lookup(old_key, u64::MAX) {
+      Ok(Some(entry)) if entry.value.is_some() => entry,
+      Ok(_) => return Ok(0),
+      Err(_) => return Err(0),
+    };
+    // The value is written again with the current schema version.
+    let Ok(Entry { value: Some(value), .. }) = self.inner.upgrade(current) else {
+      return Err(0);
+    };
+
+    let mut batch = WriteBatch::new();
+    batch.delete(old_key);
+    batch.put(new_key, &value);
+    if self.inner.options.validate_batch(&batch).is_err() {
+      return Err(0);
+    }
+    if self.write_logged(&mut log, batch, &WriteOptions::default()).is_err() {
+      return Err(0);
+    }
+    self.finish_write(log).map(|_| 1).map_err(|_| 0)
+  }
+
+  /// Adds `delta` to the counter stored at `key`, an 8-byte little-endian `i64`, and returns
+  /// the new count. A missing key counts from 0. The read and the write happen under the log
+  /// lock, so concurrent increments are never lost, and the new count is logged to the WAL
+  /// like any write. Fails with `FluxError::InvalidArgument` if the value isn't 8 bytes long
+  /// or the count would overflow.
+  pub fn increment(&self, key: &[u8], delta: i64) -> Result<i64, FluxError> {
+    self.throttle()?;
+    let mut log = self.inner.lock_log();
+    let count = match self.lookup(key, u64::MAX)? {
+      Some(entry) if entry.value.is_some() => {
+        let value = self.inner.upgrade(entry)?.value.unwrap_or_default();
+        let bytes = <[u8; 8]>::try_from(value.as_slice()).map_err(|_| {
+          FluxError::InvalidArgument(format!(
+            "value of key {:?} isn't an 8-byte counter",
+            String::from_utf8_lossy(key)
+          ))
+        })?;
+        i64::from_le_bytes(bytes)
+      }
+      _ => 0,
+    };
+    let count = count.checked_add(delta).ok_or_else(|| {
+      FluxError::InvalidArgument(format!(
+        "counter of key {:?} would overflow",
+        String::from_utf8_lossy(key)
+      ))
+    })?;
+
+    let mut batch = WriteBatch::new();
+    batch.put(key, &count.to_le_bytes());
+    self.inner.options.validate_batch(&batch)?;
+    self.write_logged(&mut log, batch, &WriteOptions::default())?;
+    self.finish_write(log)?;
+    Ok(count)
+  }
+
+  /// Restores the value of a soft-deleted key. Returns 0 if the key wasn't soft-deleted, or
+  /// its value has already been discarded.
+  pub fn undelete(&self, key: &[u8]) -> Result<usize, usize> {
+    if self.throttle().is_err() {
+      return Err(0);
+    }
+    let mut log = self.inner.lock_log();
+    let current = match self.lookup(key, u64::MAX) {
+      Ok(current) => current,
+      Err(_) => return Err(0),
+    };
+    let Some((value, schema)) = current.and_then(|entry| Some((entry.retained?, entry.schema)))
+    else {
+      return Ok(0);
+    };
+    if self.inner.options.validate(key, Some(&value)).is_err() {
+      return Err(0);
+    }
+    let timestamp = self.inner.options.clock.now_micros();
+
+    if self.rotate_wal_if_full(&mut log).is_err() {
+      return Err(0);
+    }
+    let sequence = log.last_sequence + 1;
+    let wal_res = log.wal.record_insertion_with_schema(key, &value, timestamp, sequence, schema);
+    if wal_res.is_err() {
+      return Err(0);
+    }
+    if log.wal.flush().is_err() {
+      return Err(0);
+    }
+
+    let written = Written::put(key.len() + value.len());
+    let changes = std::iter::once(Change {
+      key,
+      kind: ChangeKind::Put(&value),
+      timestamp,
+      sequence,
+    });
+    self.apply(&mut log, written, sequence, changes, |mem_table, snapshots| {
+      mem_table.apply(key, Some(&value), timestamp, sequence, schema, snapshots)
+    });
+
+    self.finish_write(log).map(|_| 1).map_err(|_| 0)
+  }
+
+  /// Commits every operation of the batch atomically and returns the number of operations.
+  /// Batches of several operations must encode within `MAX_BATCH_BYTES`; `set_many` takes
+  /// care of that for bulk writes. A batch one of the validators rejects fails as a whole.
+  pub fn write(&self, batch: WriteBatch) -> Result<usize, usize> {
+    self.write_with(batch, &WriteOptions::default())
+  }
+
+  /// Like `write`, logging the batch as `write_options` says instead of `DiskOptions`.
+  pub fn write_with(
+    &self,
+    batch: WriteBatch,
+    write_options: &WriteOptions,
+  ) -> Result<usize, usize> {
+    let start = Instant::now();
+    if write_options.check().is_err() {
+      return Err(0);
+    }
+    if batch.is_empty() {
+      return Ok(0);
+    }
+    if batch.len() > 1 && batch.approximate_size() > MAX_BATCH_BYTES {
+      return Err(0);
+    }
+    if self.validate(&batch).is_err() {
+      return Err(0);
+    }
+    if self.throttle().is_err() {
+      return Err(0);
+    }
+
+    let mut log = self.inner.lock_log();
+    let Ok(count) = self.write_logged(&mut log, batch, write_options) else {
+      return Err(0);
+    };
+    let result = self.finish_write_with(log, write_options).map(|_| count).map_err(|_| 0);
+    self.inner.record_latency(Operation::Write, None, start.elapsed());
+    result
+  }
+
+  /// Commits the writes of a transaction, unless one of the keys it read was written after
+  /// the transaction's snapshot at `sequence`. The check and the write happen under the log
+  /// lock, so no other write can slip in between.
+  pub(crate) fn commit<'a>(
+    &self,
+    batch: WriteBatch,
+    reads: impl IntoIterator<Item = &'a [u8]>,
+    sequence: u64,
+  ) -> Result<usize, FluxError> {
+    if batch.len() > 1 && batch.approximate_size() > MAX_BATCH_BYTES {
+      return Err(FluxError::Io(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "transaction writes exceed the batch size limit",
+      )));
+    }
+    self.validate(&batch)?;
+    self.throttle()?;
+
+    let mut log = self.inner.lock_log();
+    for key in reads {
+      if self.lookup(key, u64::MAX)?.is_some_and(|entry| entry.sequence > sequence) {
+        return Err(FluxError::Conflict);
+      }
+    }
+    if batch.is_empty() {
+      return Ok(0);
+    }
+    let count = self.write_logged(&mut log, batch, &WriteOptions::default())?;
+    self.finish_write(log)?;
+    Ok(count)
+  }
+
+  /// Checks a batch against the key and value size limits and the validators set in
+  /// `DiskOptions` without committing it. Writes that fail validation only return 0; this
+  /// tells why.
+  pub fn validate(&self, batch: &WriteBatch) -> Result<(), FluxError> {
+    self.inner.options.validate_batch(batch)
+  }
+
+  /// Returns the batch to commit in place of one with explicit timestamps, its operations
+  /// older than the latest version of their key dealt with as
+  /// `DiskOptions::on_timestamp_regression` says. Expects the log lock to be held.
+  fn resolve_regressions(&self, batch: &WriteBatch, now: u128) -> io::Result<WriteBatch> {
+    // Timestamp of the latest version of each key of the batch so far.
+    let mut latest: HashMap<&[u8], u128> = HashMap::new();
+    let mut resolved = WriteBatch::new();
+    for (key, op, timestamp) in batch.iter_timestamped(now) {
+      let previous = match latest.get(key) {
+        Some(&previous) => Some(previous),
+        None => self.lookup(key, u64::MAX)?.map(|entry| entry.timestamp),
+      };
+      let Some(previous) = previous.filter(|&previous| timestamp < previous) else {
+        resolved.push_at(key, op.clone(), timestamp);
+        latest.insert(key, timestamp);
+        continue;
+      };
+      match self.inner.options.on_timestamp_regression {
+        TimestampRegression::Reject => {
+          return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "timestamp is older than the latest version of a key",
+          ));
+        }
+        TimestampRegression::Ignore => {}
+        TimestampRegression::KeepAsOlderVersion => {
+          resolved.push_backdated(key, op.clone(), timestamp);
+        }
+      }
+      latest.insert(key, previous);
+    }
+    Ok(resolved)
+  }
+
+  /// Logs a batch as one WAL frame and applies it to the memtable. In a batch carrying its
+  /// own timestamps, operations older than the latest version of their key are handled as
+  /// `DiskOptions::on_timestamp_regression` says.
+  fn write_logged(
+    &self,
+    log: &mut WriteLog,
+    batch: WriteBatch,
+    write_options: &WriteOptions,
+  ) -> io::Result<usize> {
+    let timestamp = self.inner.options.clock.now_micros();
+    let batch = match batch.has_explicit_timestamps() {
+      true => self.resolve_regressions(&batch, timestamp)?,
+      false => batch,
+    };
+    if batch.is_empty() {
+      return Ok(0);
+    }
+
+    let first_sequence = log.last_sequence + 1;
+    let schema = self.inner.options.schema_version();
+    if !write_options.disable_wal {
+      self.rotate_wal_if_full(log)?;
+      log.wal.record_batch(&batch, timestamp, first_sequence, schema)?;
+      log.wal.flush()?;
+    }
+
+    let written = batch
+      .iter()
+      .map(|(key, op)| match op {
+        Op::Put(value) => Written::put(key.len() + value.len()),
+        Op::Delete => Written::delete(key.len()),
+      })
+      .fold(Written::default(), Written::add);
+    let last_sequence = log.last_sequence + batch.len() as u64;
+    let ops = batch.iter_committed(timestamp).zip(first_sequence..);
+    let changes = ops.map(|((key, op, timestamp, _), sequence)| Change {
+      key,
+      kind: match op {
+        Op::Put(value) => ChangeKind::Put(value),
+        Op::Delete => ChangeKind::Delete,
+      },
+      timestamp,
+      sequence,
+    });
+    self.apply(log, written, last_sequence, changes, |mem_table, snapshots| {
+      let ops = batch.iter_committed(timestamp);
+      for ((key, op, timestamp, backdated), sequence) in ops.zip(first_sequence..) {
+        let (value, schema) = match op {
+          Op::Put(value) => (Some(value.as_slice()), schema),
+          Op::Delete => (None, 0),
+        };
+        match backdated {
+          false => mem_table.apply(key, value, timestamp, sequence, schema, snapshots),
+          true => mem_table.apply_backdated(key, value, timestamp, sequence, schema, snapshots),
+        }
+      }
+    });
+
+    Ok(batch.len())
+  }
+
+  /// Inserts or updates many key-value pairs, committing them in WAL frames that stay within
+  /// the batch size limit. Each frame is atomic but the call as a whole is not: on failure
+  /// the error holds the number of pairs committed before it.
+  pub fn set_many<K, V, I>(&self, pairs: I) -> Result<usize, usize>
+  where
+    K: AsRef<[u8]>,
+    V: AsRef<[u8]>,
+    I: IntoIterator<Item = (K, V)>,
+  {
+    let mut batch = WriteBatch::new();
+    batch.extend_from_iter(
+      pairs
+        .into_iter()
+        .map(|(key, value)| (key, Op::Put(value.as_ref().to_vec()))),
+    );
+
+    let mut written = 0;
+    for frame in batch.split(MAX_BATCH_BYTES) {
+      match self.write(frame) {
+        Ok(count) => written += count,
+        Err(_) => return Err(written),
+      }
+    }
+    Ok(written)
+  }
+
+  /// Returns the lock wait counters collected while lock metrics were enabled.
+  pub fn lock_metrics(&self) -> LockMetricsSnapshot {
+    self.inner.lock_metrics.snapshot()
+  }
+
+  /// Turns lock metrics collection on or off at runtime.
+  pub fn set_lock_metrics_enabled(&self, enabled: bool) {
+    self.inner.lock_metrics.set_enabled(enabled);
+  }
+
+  /// Clears the collected lock metrics.
+  pub fn reset_lock_metrics(&self) {
+    self.inner.lock_metrics.reset();
+  }
+
+  /// Returns the cumulative statistics, including those carried over from earlier runs.
+  pub fn statistics(&self) -> StatisticsSnapshot {
+    self.inner.stats.snapshot()
+  }
+
+  /// Returns the counters and histograms of `statistics` along with the shape of the tree
+  /// of `tree_stats`, read together for a dashboard.
+  pub fn stats(&self) -> DiskStats {
+    DiskStats {
+      counters: self.statistics(),
+      tree: self.tree_stats(),
+    }
+  }
+
+  /// Returns the shape of the segment tree as it stands: the files, bytes and entries of
+  /// each level, the compaction debt, and the bytes of values and tombstones waiting in the
+  /// memtables. Unlike `statistics`, which counts the work done, it tells how much work is
+  /// left, for alerting when segments pile up faster than compactions merge them.
+  pub fn tree_stats(&self) -> TreeStats {
+    let segments = self.inner.segments();
+    let level = LevelStats {
+      level: 0,
+      files: segments.len(),
+      bytes: segments.iter().map(|segment| segment.file_size()).sum(),
+      entries: segments.iter().map(|segment| segment.entry_count()).sum(),
+    };
+    let mem_tables = self.inner.read_mem_tables();
+    let frozen = mem_tables.immutable.iter().map(|frozen| frozen.table.as_ref());
+    let tables: Vec<&InMemoryTable> = std::iter::once(&mem_tables.active).chain(frozen).collect();
+    let trigger = self.inner.options.compaction_trigger.max(2);
+    TreeStats {
+      compaction_debt: if level.files >= trigger { level.bytes } else { 0 },
+      levels: vec![level],
+      memtable_live_bytes: tables.iter().map(|table| table.live_bytes() as u64).sum(),
+      memtable_tombstone_bytes: tables.iter().map(|table| table.tombstone_bytes() as u64).sum(),
+    }
+  }
+
+  /// Returns how long gets, sets, deletes and batch writes took since the database was
+  /// opened, for reporting percentiles such as `latency_histograms().get.percentile(99.0)`.
+  pub fn latency_histograms(&self) -> LatencyHistograms {
+    self.inner.stats.latency_histograms()
+  }
+
+  /// Returns the statistics in the Prometheus text exposition format, for serving to a
+  /// scraper.
+  pub fn prometheus_metrics(&self) -> String {
+    metrics::render(self)
+  }
+
+  /// Returns the block cache the database reads segments through, if it has one.
+  pub fn block_cache(&self) -> Option<&BlockCache> {
+    self.inner.options.block_cache.as_ref()
+  }
+
+  /// Estimates the number of keys from the entry counts of the segments and memtables,
+  /// without reading any data. Every stored version and tombstone counts, so overwrites and
+  /// deletes not compacted yet push the estimate above the number of live keys.
+  pub fn estimate_num_keys(&self) -> u64 {
+    let mem_records = {
+      let mem_tables = self.inner.read_mem_tables();
+      let immutable = mem_tables.immutable.iter().map(|frozen| frozen.table.record_count());
+      mem_tables.active.record_count() + immutable.sum::<usize>()
+    };
+    let segments = self.inner.segments();
+    mem_records as u64 + segments.iter().map(|segment| segment.entry_count()).sum::<u64>()
+  }
+
+  /// Estimates the bytes the range takes: the stored size of the segment blocks that may
+  /// hold its keys, found from their indexes, plus the keys and values of the memtables
+  /// within it. Values kept in the value log count as the size of their pointers. Blocks
+  /// straddling the bounds count whole, so small ranges are overestimated.
+  pub fn approximate_size<'a, R: RangeBounds<&'a [u8]>>(&self, range: R) -> u64 {
+    let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+    let size = |record: &InMemoryRecord| {
+      record.key.len() + record.value.as_ref().map_or(0, |value| value.len())
+    };
+    let record_bytes = |table: &InMemoryTable| table.range(bounds).map(size).sum::<usize>();
+    let mem_bytes = {
+      let mem_tables = self.inner.read_mem_tables();
+      let immutable = mem_tables.immutable.iter().map(|frozen| record_bytes(&frozen.table));
+      record_bytes(&mem_tables.active) + immutable.sum::<usize>()
+    };
+    let segments = self.inner.segments();
+    mem_bytes as u64 + segments.iter().map(|segment| segment.approximate_size(bounds)).sum::<u64>()
+  }
+
+  /// Counts the versions a scan of the range reads through and how many of them are deleted,
+  /// to find ranges where scans slow down stepping over tombstones. Reads every version in
+  /// the range, so it costs about as much as a keys-only scan of it.
+  pub fn tombstone_density<'a, R: RangeBounds<&'a [u8]>>(
+    &self,
+    range: R,
+  ) -> Result<TombstoneDensity, FluxError> {
+    let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+    let (active, view, mut range_tombstones) = {
+      let mem_tables = self.inner.read_mem_tables();
+      let active = mem_table_source(&mem_tables.active, bounds, false, None, true, usize::MAX).0;
+      (active, self.inner.read_view(&mem_tables), mem_tables.range_tombstones(u64::MAX))
+    };
+    let segments = &view.segments;
+    let mut sources = vec![active];
+    for table in view.immutable.iter() {
+      sources.push(mem_table_source(table, bounds, false, None, true, usize::MAX).0);
+    }
+    for segment in segments.iter() {
+      sources.push(Box::new(segment.iter_from(bounds.0).keys_only()));
+      range_tombstones.extend(segment.range_tombstones().iter().cloned());
+    }
+
+    let mut density = TombstoneDensity::default();
+    let mut last_key: Option<Vec<u8>> = None;
+    let order = &self.inner.options.comparator;
+    for entry in MergeIterator::with_key_order(sources, order, false) {
+      let entry = entry?;
+      if !order.contains(&bounds, &entry.key) {
+        break;
+      }
+      density.versions += 1;
+      let covered = range_tombstones
+        .iter()
+        .any(|tombstone| tombstone.covers(&entry.key, entry.sequence, order));
+      if entry.is_deleted() {
+        density.tombstones += 1;
+      } else if covered {
+        density.covered += 1;
+      }
+      // Versions come newest first.
+      if !entry.backdated && last_key.as_ref() != Some(&entry.key) {
+        if !entry.is_deleted() && !covered {
+          density.live_keys += 1;
+        }
+        last_key = Some(entry.key);
+      }
+    }
+    Ok(density)
+  }
+
+  /// Returns up to `n` live keys of the range picked at random, in key order, so audits can
+  /// look at a representative subset without scanning it. Memtable keys are sampled with a
+  /// reservoir; segment keys come from blocks drawn at random among those the segment
+  /// indexes place in the range, weighted by the entries each segment holds per block, so
+  /// only the blocks drawn are read. Keys of sparse blocks are somewhat favored, and fewer
+  /// than `n` keys come back when the range holds fewer or many of those drawn are deleted.
+  pub fn sample_keys<'a, R: RangeBounds<&'a [u8]>>(
+    &self,
+    n: usize,
+    range: R,
+  ) -> Result<Vec<Vec<u8>>, FluxError> {
+    let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+    let order = &self.inner.options.comparator;
+    let mut rng = rand::thread_rng();
+    // Twice as many keys as asked for are drawn, to make up for deleted and repeated ones.
+    let draws = n.saturating_mul(2);
+
+    let mut reservoir: Vec<Vec<u8>> = Vec::new();
+    let mut mem_keys = 0;
+    let view = {
+      let mem_tables = self.inner.read_mem_tables();
+      let frozen = mem_tables.immutable.iter().map(|frozen| frozen.table.as_ref());
+      for table in std::iter::once(&mem_tables.active).chain(frozen) {
+        for versions in table.range_by_key(bounds) {
+          mem_keys += 1;
+          if reservoir.len() < draws {
+            reservoir.push(versions[0].key.to_vec());
+          } else if let Some(slot) = reservoir.get_mut(rng.gen_range(0..mem_keys)) {
+            *slot = versions[0].key.to_vec();
+          }
+        }
+      }
+      self.inner.read_view(&mem_tables)
+    };
+    let segments = &view.segments;
+
+    let blocks: Vec<Range<usize>> =
+      segments.iter().map(|segment| segment.blocks_within(bounds)).collect();
+    let mut weights = vec![mem_keys as f64];
+    for (segment, blocks) in segments.iter().zip(&blocks) {
+      let per_block = segment.entry_count() as f64 / segment.block_count().max(1) as f64;
+      weights.push(blocks.len() as f64 * per_block);
+    }
+    let Ok(sources) = WeightedIndex::new(&weights) else {
+      return Ok(Vec::new());
+    };
+    let mut mem_draws = 0;
+    let mut block_draws: HashMap<(usize, usize), usize> = HashMap::new();
+    if weights.iter().sum::<f64>() <= draws as f64 {
+      // A range holding about as few keys as are drawn is read whole.
+      mem_draws = reservoir.len();
+      for (segment, blocks) in blocks.iter().enumerate() {
+        block_draws.extend(blocks.clone().map(|block| ((segment, block), usize::MAX)));
+      }
+    } else {
+      for _ in 0..draws {
+        match sources.sample(&mut rng) {
+          0 => mem_draws += 1,
+          source => {
+            let block = rng.gen_range(blocks[source - 1].clone());
+            *block_draws.entry((source - 1, block)).or_default() += 1;
+          }
+        }
+      }
+    }
+
+    let mut candidates: Vec<Vec<u8>> =
+      reservoir.choose_multiple(&mut rng, mem_draws).cloned().collect();
+    for ((segment, block), count) in block_draws {
+      let entries = segments[segment].block_entries(block)?;
+      let mut keys: Vec<&[u8]> = entries
+        .iter()
+        .map(|entry| entry.key.as_slice())
+        .filter(|key| order.contains(&bounds, key))
+        .collect();
+      keys.dedup();
+      candidates.extend(keys.choose_multiple(&mut rng, count).map(|key| key.to_vec()));
+    }
+    candidates.sort_by(|a, b| order.compare(a, b));
+    candidates.dedup();
+
+    let keys: Vec<&[u8]> = candidates.iter().map(Vec::as_slice).collect();
+    let live = self.multi_get(&keys)?;
+    let mut sample: Vec<Vec<u8>> = candidates
+      .into_iter()
+      .zip(live)
+      .filter_map(|(key, entry)| entry.map(|_| key))
+      .collect();
+    if sample.len() > n {
+      let mut kept = index::sample(&mut rng, sample.len(), n).into_vec();
+      kept.sort_unstable();
+      sample = kept.into_iter().map(|i| std::mem::take(&mut sample[i])).collect();
+    }
+    Ok(sample)
+  }
+
+  /// Draws a sample of the live keys and returns the distribution of their sizes, of the
+  /// sizes of their values, and their 10 most common prefixes, to find hot
+  /// prefixes and pick a `prefix_extractor`. Every memtable key and every segment block is
+  /// sampled with probability `sample_rate`, so only the blocks drawn are read, along with
+  /// the keys of newer segments that may hold a later version of a sampled key; values in
+  /// the value log are sized from their pointers without being read. Prefixes are those of
+  /// `DiskOptions::prefix_extractor`, or without one, keys up to and including their first
+  /// byte that isn't an ASCII letter or digit, as `user:` or `tenant/`. Fails with
+  /// `FluxError::InvalidArgument` unless `sample_rate` is above 0 and at most 1.
+  pub fn key_distribution(&self, sample_rate: f64) -> Result<KeyDistribution, FluxError> {
+    if !(sample_rate > 0.0 && sample_rate <= 1.0) {
+      let message = format!("sample rate {} is not within (0, 1]", sample_rate);
+      return Err(FluxError::InvalidArgument(message));
+    }
+    let mut rng = rand::thread_rng();
+    let mut sampled: Vec<(Vec<u8>, u64)> = Vec::new();
+    let mut mem_keys: HashSet<Vec<u8>> = HashSet::new();
+    let mut mem_sampled = Vec::new();
+    let (view, mut range_tombstones) = {
+      let mem_tables = self.inner.read_mem_tables();
+      let frozen = mem_tables.immutable.iter().map(|frozen| frozen.table.as_ref());
+      for table in std::iter::once(&mem_tables.active).chain(frozen) {
+        for versions in table.range_by_key(..) {
+          let Some(latest) = versions.iter().find(|version| !version.backdated) else {
+            continue;
+          };
+          if !mem_keys.insert(latest.key.to_vec()) {
+            // A newer memtable holds a later version of the key.
+            continue;
+          }
+          if !latest.is_deleted && rng.gen_bool(sample_rate) {
+            let size = latest.value.as_ref().map_or(0, |value| value.len());
+            mem_sampled.push((latest.key.to_vec(), size as u64, latest.sequence));
+          }
+        }
+      }
+      (self.inner.read_view(&mem_tables), mem_tables.range_tombstones(u64::MAX))
+    };
+    for segment in view.segments.iter() {
+      range_tombstones.extend_from_slice(segment.range_tombstones());
+    }
+    let order = &self.inner.options.comparator;
+    for (key, size, sequence) in mem_sampled {
+      let covered = range_tombstones
+        .iter()
+        .any(|tombstone| tombstone.covers(&key, sequence, order));
+      if !covered {
+        sampled.push((key, size));
+      }
+    }
+    let segments = &view.segments;
+    // Whether a memtable or a segment newer than the `i`th one holds a version of `key` other
+    // than a backdated one.
+    let shadowed = |i: usize, key: &[u8]| -> io::Result<bool> {
+      if mem_keys.contains(key) {
+        return Ok(true);
+      }
+      for newer in segments[..i].iter().filter(|newer| newer.may_contain(key)) {
+        let mut entries = newer.iter_from(Bound::Included(key)).keys_only();
+        let latest = entries.find(|entry| !entry.as_ref().is_ok_and(|entry| entry.backdated));
+        if let Some(entry) = latest {
+          if entry?.key == key {
+            return Ok(true);
+          }
+        }
+      }
+      Ok(false)
+    };
+    for (i, segment) in segments.iter().enumerate() {
+      for block in 0..segment.block_count() {
+        if !rng.gen_bool(sample_rate) {
+          continue;
+        }
+        let mut entries = segment.block_entries(block)?;
+        // Versions come newest first.
+        entries.retain(|entry| !entry.backdated);
+        entries.dedup_by(|older, newer| older.key == newer.key);
+        for entry in entries {
+          let covered = range_tombstones
+            .iter()
+            .any(|tombstone| tombstone.covers(&entry.key, entry.sequence, order));
+          let size = match &entry.value {
+            None => continue,
+            _ if covered || shadowed(i, &entry.key)? => continue,
+            Some(pointer) if entry.value_pointer => pointed_value_size(pointer, entry.key.len())?,
+            Some(value) => value.len() as u64,
+          };
+          sampled.push((entry.key, size));
+        }
+      }
+    }
+
+    let (key_sizes, value_sizes) = (Histogram::default(), Histogram::default());
+    let mut prefixes: HashMap<&[u8], u64> = HashMap::new();
+    let extractor = self.inner.options.prefix_extractor.as_ref();
+    for (key, value_size) in &sampled {
+      key_sizes.record(key.len() as u64);
+      value_sizes.record(*value_size);
+      let prefix = match extractor {
+        Some(extractor) => extractor.prefix(key),
+        None => key
+          .iter()
+          .position(|byte| !byte.is_ascii_alphanumeric())
+          .map(|end| &key[..=end]),
+      };
+      if let Some(prefix) = prefix {
+        *prefixes.entry(prefix).or_default() += 1;
+      }
+    }
+    let mut top_prefixes: Vec<(Vec<u8>, u64)> =
+      prefixes.into_iter().map(|(prefix, count)| (prefix.to_vec(), count)).collect();
+    top_prefixes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    top_prefixes.truncate(TOP_PREFIXES);
+    Ok(KeyDistribution {
+      sampled_keys: sampled.len() as u64,
+      estimated_keys: (sampled.len() as f64 / sample_rate).round() as u64,
+      key_sizes: key_sizes.snapshot(),
+      value_sizes: value_sizes.snapshot(),
+      top_prefixes,
+    })
+  }
+
+  /// Returns the rolling average of the number of segments lookups read blocks from, over
+  /// roughly the last 64 of them, as compared to `read_amplification_trigger`.
+  pub fn read_amplification(&self) -> f64 {
+    let average = self.inner.read_amplification.load(Ordering::Relaxed);
+    average as f64 / READ_AMPLIFICATION_SCALE as f64
+  }
+
+  /// Checks every data block of the segments right away, at full speed, as a pass of the
+  /// background scrubber would, and returns what it found. Damaged blocks are logged and
+  /// copied next to their segment; see `DiskOptions::scrub`.
+  pub fn scrub(&self) -> io::Result<ScrubReport> {
+    let report = self.inner.scrub(None)?;
+    *self.inner.last_scrub.lock().unwrap() = Some(report.clone());
+    Ok(report)
+  }
+
+  /// Returns the report of the last finished scrub, by the background scrubber or `scrub`,
+  /// or `None` if none has finished since the database was opened.
+  pub fn last_scrub(&self) -> Option<ScrubReport> {
+    self.inner.last_scrub.lock().unwrap().clone()
+  }
+
+  /// Writes a consistent copy of the database as of now to `dest`, which can be opened with
+  /// `Disk::open` or restored with `restore_from_backup`. Segments are hard-linked where the
+  /// file system allows it; the live WAL files are cloned or copied up to the current write.
+  ///
+  /// Backing up to a directory holding an earlier backup is incremental: the segments it
+  /// already holds are kept and only newer ones are added, while those the database no
+  /// longer uses are removed.
+  pub fn create_backup(&self, dest: &str) -> io::Result<BackupInfo> {
+    self.ensure_local_files()?;
+    let dest = Path::new(dest);
+    create_dir_all(dest)?;
+    self.copy_into(dest)
+  }
+
+  /// Creates an openable copy of the database as of now in `dest`, which must not exist yet,
+  /// and returns the sequence number of the last write it holds. Writes are only held up
+  /// while segments are hard-linked, so checkpoints are cheap enough to spawn read-only
+  /// copies for analytics from a live database.
+  pub fn checkpoint(&self, dest: &str) -> io::Result<u64> {
+    self.ensure_local_files()?;
+    let dest = Path::new(dest);
+    if let Some(parent) = dest.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+      create_dir_all(parent)?;
+    }
+    // Creating the directory itself claims it, so two checkpoints racing for the same
+    // destination can't both write into it.
+    fs::create_dir(dest).map_err(|e| match e.kind() {
+      io::ErrorKind::AlreadyExists => {
+        io::Error::new(e.kind(), format!("{} already exists", dest.display()))
+      }
+      _ => e,
+    })?;
+    Ok(self.copy_into(dest)?.last_sequence)
+  }
+
+  fn ensure_local_files(&self) -> io::Result<()> {
+    if self.inner.options.storage.is_local() {
+      return Ok(());
+    }
+    Err(io::Error::new(
+      io::ErrorKind::Unsupported,
+      "backups need a storage backend keeping files on the local file system",
+    ))
+  }
+
+  /// Brings `dest` up to date with the database as of now, as `create_backup` describes.
+  fn copy_into(&self, dest: &Path) -> io::Result<BackupInfo> {
+    let mut info = BackupInfo::default();
+
+    // Holding the log lock keeps the manifest from changing and its files from being
+    // retired while they are linked, and stops writes at a single point in time.
+    let (manifest, wal_files) = {
+      let mut log = self.inner.lock_log();
+      log.wal.flush()?;
+      let mut manifest = log.manifest.clone();
+      manifest.last_sequence = log.last_sequence;
+      for name in manifest.segment_files.iter().chain(&manifest.value_log_files) {
+        match backup::add_segment(&self.inner.dir.join(name), &dest.join(name))? {
+          true => info.segments_added += 1,
+          false => info.segments_reused += 1,
+        }
+      }
+      let mut wal_files = Vec::new();
+      for name in manifest.wal_files.iter() {
+        let file = File::open(self.inner.dir.join(name))?;
+        let len = match name == &file_name(log.wal.path()) {
+          true => log.wal.size(),
+          false => file.metadata()?.len(),
+        };
+        wal_files.push((name.clone(), file, len));
+      }
+      (manifest, wal_files)
+    };
+
+    // Copied without the lock: WAL files are only appended to, and the open handles keep
+    // files retired meanwhile readable.
+    for (name, file, len) in wal_files {
+      info.wal_bytes += backup::copy_wal(&file, len, &dest.join(name))?;
+    }
+    backup::finish_backup(dest, &manifest)?;
+    info.last_sequence = manifest.last_sequence;
+    Ok(info)
+  }
+
+  /// Restores a backup written by `create_backup` in `src` to `dst`, which must not hold a
+  /// database yet. Open `dst` afterwards to use the restored database.
+  pub fn restore_from_backup(src: &str, dst: &str) -> io::Result<()> {
+    backup::restore(Path::new(src), Path::new(dst))
+  }
+
+  /// Writes every key of the database as of now to `writer` in the portable dump format
+  /// described in the `dump` module, and returns the number of records written. Deleted keys
+  /// whose tombstones haven't been compacted away yet are written as tombstones, so
+  /// importing the dump into a database holding older versions of them deletes those too.
+  /// The keys are read a page at a time from a snapshot, so writes may carry on meanwhile.
+  pub fn export_to<W: Write>(&self, writer: W) -> Result<u64, FluxError> {
+    let snapshot = self.snapshot();
+    let mut dump = DumpWriter::new(writer)?;
+    let mut start: Bound<Vec<u8>> = Bound::Unbounded;
+    let options = ScanOptions { limit: EXPORT_PAGE_SIZE, ..ScanOptions::default() };
+    loop {
+      let bounds = (start.as_ref().map(Vec::as_slice), Bound::Unbounded);
+      let (kind, reads) = (RangeRead::Tombstones, BlockReads::default());
+      let page =
+        self.read_range_versions(bounds, snapshot.sequence(), &options, None, kind, reads)?;
+      for entry in page.iter() {
+        dump.write_record(&DumpRecord {
+          key: entry.key.clone(),
+          value: entry.value.clone(),
+          timestamp: entry.timestamp,
+        })?;
+      }
+      match page.last() {
+        Some(last) if page.len() >= EXPORT_PAGE_SIZE => start = Bound::Excluded(last.key.clone()),
+        _ => break,
+      }
+    }
+    let count = dump.count();
+    dump.finish()?;
+    Ok(count)
+  }
+
+  /// Applies the records of a dump read from `reader`, keeping their timestamps, and
+  /// returns the number applied. Records are committed in batches of at most
+  /// `MAX_BATCH_BYTES` as they are read, so a failure part way through leaves the records
+  /// before it applied. A record older than the version a key already holds fails the
+  /// import, unless `DiskOptions::on_timestamp_regression` says otherwise.
+  pub fn import_from<R: Read>(&self, reader: R) -> Result<u64, FluxError> {
+    let mut batch = WriteBatch::new();
+    let mut count = 0;
+    for record in DumpReader::new(reader)? {
+      let record = record?;
+      match &record.value {
+        Some(value) => batch.put_at(&record.key, value, record.timestamp),
+        None => batch.delete_at(&record.key, record.timestamp),
+      }
+      if batch.approximate_size() >= MAX_BATCH_BYTES {
+        // The last record may have taken the batch past the limit: it starts the next one.
+        let mut parts = std::mem::take(&mut batch).split(MAX_BATCH_BYTES);
+        batch = parts.pop().unwrap_or_default();
+        for part in parts {
+          count += self.import_batch(part)?;
+        }
+      }
+    }
+    for part in batch.split(MAX_BATCH_BYTES) {
+      count += self.import_batch(part)?;
+    }
+    Ok(count as u64)
+  }
+
+  /// Commits a batch of imported records.
+  fn import_batch(&self, batch: WriteBatch) -> Result<usize, FluxError> {
+    self.validate(&batch)?;
+    self.throttle()?;
+    let mut log = self.inner.lock_log();
+    let count = self.write_logged(&mut log, batch, &WriteOptions::default())?;
+    self.finish_write(log)?;
+    Ok(count)
+  }
+
+  /// Writes the active memtable, and any frozen one, to segments, so the WAL files holding
+  /// their writes are retired. Blocks until they are written.
+  pub fn flush(&self) -> io::Result<()> {
+    self.wait_for_requested_work(|_| {})
+  }
+
+  /// Flushes the active memtable and merges every segment into one, dropping the versions
+  /// and tombstones no reader needs any more. Blocks until the compaction is done; it runs on
+  /// the background thread, so it never overlaps with another one.
+  pub fn compact(&self) -> io::Result<()> {
+    self.wait_for_requested_work(|state| state.full_compaction = true)
+  }
+
+  /// Flushes the active memtable and merges the segments holding keys from `start`
+  /// (inclusive) to `end` (exclusive), along with those between them in age, leaving the
+  /// others alone. After a bulk delete, this drops the deleted keys and their tombstones
+  /// from a hot range right away, without rewriting the whole database: tombstones are
+  /// dropped when the merged segments include the oldest one, and otherwise kept to hide
+  /// the older versions below. Blocks until the compaction is done.
+  pub fn compact_range(&self, start: &[u8], end: &[u8]) -> io::Result<()> {
+    if self.inner.options.comparator.compare(start, end) != std::cmp::Ordering::Less {
+      return Ok(());
+    }
+    let range = (start.to_vec(), end.to_vec());
+    self.wait_for_requested_work(|state| state.compaction_ranges.push(range))
+  }
+
+  /// Flushes the active memtable and merges every segment into one, dropping the point and
+  /// range tombstones `DiskOptions::tombstone_retention` no longer keeps, so deleted keys
+  /// stop taking space without waiting for enough segments to trigger a compaction.
+  /// Tombstones a live snapshot still needs are kept. Returns the number of tombstones
+  /// dropped, counting those of any compaction running meanwhile.
+  pub fn purge_tombstones(&self) -> io::Result<u64> {
+    let before = self.inner.stats.snapshot().tombstones_purged;
+    self.compact()?;
+    Ok(self.inner.stats.snapshot().tombstones_purged - before)
+  }
+
+  /// Deletes the segment and WAL files retired by flushes and compactions whose
+  /// `obsolete_file_grace` is over and that no scan or iterator reads any more, as the
+  /// background thread does on its own, and returns how many were deleted.
+  pub fn delete_obsolete_files(&self) -> usize {
+    self.inner.delete_obsolete_files()
+  }
+
+  /// Returns the files retired by flushes and compactions that are waiting to be deleted.
+  pub fn obsolete_files(&self) -> Vec<PathBuf> {
+    let obsolete = self.inner.obsolete.lock().unwrap();
+    obsolete.iter().map(|file| self.inner.dir.join(&file.name)).collect()
+  }
+
+  /// Freezes the active memtable unless it is empty, then has the background thread flush
+  /// the frozen memtables and do the compaction `request` asks for, and waits for it.
+  fn wait_for_requested_work(&self, request: impl FnOnce(&mut WorkState)) -> io::Result<()> {
+    {
+      let mut log = self.inner.lock_log();
+      if self.inner.read_mem_tables().active.current_size() > 0 {
+        self.inner.freeze_mem_table(&mut log)?;
+      }
+    }
+
+    let mut state = self.inner.work.lock().unwrap();
+    request(&mut state);
+    state.compactions_requested += 1;
+    let target = state.compactions_requested;
+    state.requested = true;
+    self.inner.work_changed.notify_all();
+    loop {
+      if state.compactions_done >= target {
+        return Ok(());
+      }
+      if let Some(e) = state.compaction_error.take() {
+        return Err(e);
+      }
+      state = self.inner.work_changed.wait(state).unwrap();
+    }
+  }
+
+  /// Adds segment files written by `SstWriter` with the options of this database, all at
+  /// once, and returns the number of keys they hold. Each file is moved into the database
+  /// directory, or copied and then removed if it is on another file system. With a value
+  /// log, the files are rewritten instead, their large values going to the value log as a
+  /// flush sends them.
+  ///
+  /// Ingested keys are stored below every other write, so the key ranges of the files must
+  /// not overlap each other or the data the database already holds, range deletions
+  /// included; otherwise the call fails with `InvalidInput` and adds none of the files.
+  /// The ingestion takes a sequence number of its own, so snapshots taken before it don't
+  /// see the ingested keys. No WAL record carries it: replicas, which follow the WAL, don't
+  /// receive the keys, and a tail can't resume from before it.
+  pub fn ingest_segments<P: AsRef<Path>>(&self, paths: &[P]) -> io::Result<u64> {
+    let inner = &self.inner;
+    let (storage, order) = (&inner.options.storage, &inner.options.comparator);
+    let mut files = Vec::new();
+    for path in paths.iter().map(AsRef::as_ref) {
+      let segment = SSTable::open_with(storage, path)?.with_key_order(order);
+      if !segment.range_tombstones().is_empty() {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidInput,
+          format!("{} holds range deletions and can't be ingested", path.display()),
+        ));
+      }
+      let (Some(smallest), Some(largest)) = (segment.smallest_key()?, segment.largest_key())
+      else {
+        continue;
+      };
+      let largest = largest.to_vec();
+      files.push((path.to_owned(), smallest, largest, segment.entry_count()));
+    }
+    files.sort_by(|a, b| order.compare(&a.1, &b.1));
+    for pair in files.windows(2) {
+      if order.compare(&pair[0].2, &pair[1].1).is_ge() {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidInput,
+          format!(
+            "the key ranges of {} and {} overlap",
+            pair[0].0.display(),
+            pair[1].0.display()
+          ),
+        ));
+      }
+    }
+    if files.is_empty() {
+      return Ok(0);
+    }
+
+    // The rewrites happen before the log lock is taken, as writing a value log file takes it.
+    let mut rewritten = Vec::new();
+    for (path, ..) in files.iter() {
+      let separated = match inner.value_log_writer(IoPriority::High, HashSet::new()) {
+        Ok(Some(values)) => inner.separate_values(path, values),
+        Ok(None) => break,
+        Err(e) => Err(e),
+      };
+      match separated {
+        Ok(file) => rewritten.push(file),
+        Err(e) => {
+          abandon_rewritten(storage, rewritten);
+          return Err(e);
+        }
+      }
+    }
+
+    // Holding the log lock keeps writes from landing in the ranges until the files are in.
+    let mut log = inner.lock_log();
+    for (path, smallest, largest, _) in files.iter() {
+      let overlap = match inner.ingestion_overlap(smallest, largest) {
+        Ok(overlap) => overlap,
+        Err(e) => {
+          abandon_rewritten(storage, rewritten);
+          return Err(e);
+        }
+      };
+      if let Some(overlap) = overlap {
+        abandon_rewritten(storage, rewritten);
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidInput,
+          format!("the key range of {} overlaps {}", path.display(), overlap),
+        ));
+      }
+    }
+    let sequence = log.last_sequence + 1;
+    let rewrite = !rewritten.is_empty();
+    let mut moved: Vec<(PathBuf, PathBuf)> = Vec::new();
+    let mut names = Vec::new();
+    let mut segments = Vec::new();
+    let mut values = Vec::new();
+    let result = match rewrite {
+      false => files.iter().try_for_each(|(path, ..)| {
+        let name = log.manifest.new_segment_name();
+        let dest = inner.dir.join(&name);
+        move_file(storage, path, &dest)?;
+        moved.push((path.clone(), dest.clone()));
+        names.push(name);
+        let segment = open_segment(&inner.options, &inner.value_log, &dest)?;
+        segments.push(Arc::new(segment.with_global_sequence(sequence)));
+        Ok(())
+      }),
+      true => {
+        for (name, segment, writer) in rewritten {
+          names.push(name);
+          segments.push(Arc::new(segment.with_global_sequence(sequence)));
+          values.push(writer);
+        }
+        Ok(())
+      }
+    };
+    let mut manifest = log.manifest.clone();
+    manifest.last_sequence = sequence;
+    // Ingested segments are the oldest, the segments being listed oldest first.
+    manifest.segment_files.splice(0..0, names.iter().cloned());
+    manifest.ingested_segments.extend(names.iter().map(|name| (name.clone(), sequence)));
+    manifest.value_log_files.extend(values.iter().filter_map(ValueLogWriter::file_name));
+    if let Err(e) = result.and_then(|_| manifest.store_with(storage, &inner.dir)) {
+      for segment in segments.iter().filter(|_| rewrite) {
+        let _ = storage.delete(segment.path());
+      }
+      drop(segments);
+      for (path, dest) in moved.iter() {
+        let _ = move_file(storage, dest, path);
+      }
+      for writer in values.iter_mut() {
+        writer.abandon();
+      }
+      return Err(e);
+    }
+    record_edit(&inner.options, &inner.dir, &log.manifest, &manifest, EditReason::Ingestion);
+    log.manifest = manifest;
+    log.last_sequence = sequence;
+    for writer in values {
+      inner.value_log.commit(writer, None);
+    }
+    let mut live = Vec::clone(&inner.segments());
+    live.extend(segments);
+    *inner.segments.write().unwrap() = Arc::new(live);
+    inner.visible_sequence.store(sequence, Ordering::Release);
+    drop(log);
+
+    // The files rewritten are moved in, as far as the caller can tell.
+    if rewrite {
+      for (path, ..) in files.iter() {
+        if let Err(e) = storage.delete(path) {
+          let message =
+            format_args!("removing {} after ingesting it failed: {}", path.display(), e);
+          inner.options.logger.log(Level::Warn, Subsystem::Flush, message);
+        }
+      }
+    }
+    let keys = files.iter().map(|file| file.3).sum();
+    let message = format_args!("ingested {} segments holding {} keys", files.len(), keys);
+    inner.options.logger.log(Level::Info, Subsystem::Flush, message);
+    Ok(keys)
+  }
+
+  /// Returns the WAL files holding records not yet written to a segment, oldest first.
+  pub fn wal_files(&self) -> Vec<PathBuf> {
+    self.inner.lock_log().manifest.wal_paths(&self.inner.dir)
+  }
+
+  /// Returns the WAL files moved to `DiskOptions::wal_archive`, oldest first, or none if the
+  /// database has no archive.
+  pub fn archived_wal_files(&self) -> io::Result<Vec<PathBuf>> {
+    let options = &self.inner.options;
+    match &options.wal_archive {
+      Some(archive) => find_wal_files_with(&options.storage, &archive.path(&self.inner.dir)),
+      None => Ok(Vec::new()),
+    }
+  }
+
+  /// Returns the live segment files, oldest first.
+  pub fn segment_files(&self) -> Vec<PathBuf> {
+    self.inner.lock_log().manifest.segment_paths(&self.inner.dir)
+  }
+
+  /// Describes the live segment files, oldest first: their key ranges, sizes, entry counts
+  /// and creation times. Reads the first block of each segment for its smallest key.
+  pub fn live_files(&self) -> io::Result<Vec<LiveFileInfo>> {
+    let local = self.inner.options.storage.is_local();
+    let segments = self.inner.segments();
+    segments
+      .iter()
+      .rev()
+      .map(|segment| {
+        let created = match local {
+          true => fs::metadata(segment.path()).and_then(|meta| meta.modified()).ok(),
+          false => None,
+        };
+        Ok(LiveFileInfo {
+          path: segment.path().to_path_buf(),
+          level: 0,
+          smallest_key: segment.smallest_key()?,
+          largest_key: segment.largest_key().map(<[u8]>::to_vec),
+          size: segment.file_size(),
+          entry_count: segment.entry_count(),
+          created,
+        })
+      })
+      .collect()
+  }
+
+  /// Returns the live value log files, holding the values moved out of the segments.
+  pub fn value_log_files(&self) -> Vec<PathBuf> {
+    self.inner.lock_log().manifest.value_log_paths(&self.inner.dir)
+  }
+
+  /// Returns the size of the value log and how much of it segments still point to.
+  pub fn value_log_stats(&self) -> ValueLogStats {
+    self.inner.value_log.stats()
+  }
+
+  /// Returns every edit made to the set of live files since the database was created, oldest
+  /// first: the WAL files started and retired, the segments written by flushes, and the
+  /// inputs and output of compactions, with when they happened and the last sequence number
+  /// then, to reconstruct the background work that led to an incident.
+  pub fn manifest_history(&self) -> io::Result<Vec<ManifestEdit>> {
+    load_history_with(&self.inner.options.storage, &self.inner.dir)
+  }
+
+  /// Applies a logged write, counted as `written` in the statistics and numbered up to
+  /// `last_sequence`, to the active memtable and makes it visible to readers, freezing the
+  /// memtable once it is full or the live WAL files exceed `max_total_wal_bytes`. The write
+  /// is given the live snapshots, whose versions it must keep.
+  fn apply<'a>(
+    &self,
+    log: &mut WriteLog,
+    written: Written,
+    last_sequence: u64,
+    changes: impl Iterator<Item = Change<'a>> + Clone,
+    write: impl FnOnce(&mut InMemoryTable, &[u64]),
+  ) {
+    let snapshots = self.inner.snapshots.sequences();
+    let (full, flush_pending) = {
+      let mut mem_tables = self.inner.write_mem_tables();
+      write(&mut mem_tables.active, &snapshots);
+      let options = &self.inner.options;
+      let active = &mem_tables.active;
+      // Chunks pinned by a few live records, and the one being filled, take memory the
+      // size of the records doesn't show.
+      let full = active.current_size() >= options.memtable_size
+        || active.allocated_bytes() >= options.memtable_size + ARENA_CHUNK_SIZE
+        || options.memtable_max_records.is_some_and(|max| active.record_count() >= max)
+        || options
+          .memtable_max_tombstone_bytes
+          .is_some_and(|max| active.tombstone_bytes() >= max);
+      (full, !mem_tables.immutable.is_empty())
+    };
+    log.last_sequence = last_sequence;
+    if log.active_since.is_none() {
+      let now = Instant::now();
+      log.active_since = Some(now);
+      if let Some(max_age) = self.inner.options.memtable_max_age {
+        self.inner.work.lock().unwrap().memtable_deadline = now.checked_add(max_age);
+        self.inner.work_changed.notify_all();
+      }
+    }
+    self.inner.visible_sequence.store(last_sequence, Ordering::Release);
+    self.inner.invalidations.publish(last_sequence, changes.clone());
+    self.inner.subscribers.publish(changes);
+    let stats = &self.inner.stats;
+    stats.record_write(written.bytes, written.puts, written.deletes);
+    stats.record_wal_bytes(log.wal.size().saturating_sub(log.counted_wal_size));
+    log.counted_wal_size = log.wal.size();
+
+    // While frozen memtables are waiting, their flush is about to retire WAL files, so the
+    // active one is only frozen early once it holds a good share of the log itself.
+    let wal_full = self.inner.options.max_total_wal_bytes.is_some_and(|limit| {
+      let active_bytes = log.active_sealed_wal_bytes + log.wal.size();
+      log.total_wal_bytes() >= limit && (!flush_pending || active_bytes >= limit / 2)
+    });
+    if full || wal_full {
+      let start = Instant::now();
+      // The write itself is already durable; a failed switch is retried by the next write.
+      if let Err(e) = self.inner.freeze_mem_table(log) {
+        let message = format_args!("switching to a new memtable failed: {}", e);
+        self.inner.options.logger.log(Level::Warn, Subsystem::Wal, message);
+      }
+      self.inner.stats.record_stall(start.elapsed());
+    }
+  }
+
+  /// Starts a new WAL file once the active one reaches `max_wal_file_size`. Sealed files are
+  /// kept, and replayed in order on recovery, until their records are written to a segment.
+  fn rotate_wal_if_full(&self, log: &mut WriteLog) -> io::Result<()> {
+    match self.inner.options.max_wal_file_size {
+      Some(limit) if log.wal.size() >= limit => self.inner.start_new_wal(log),
+      _ => Ok(()),
+    }
+  }
+
+  /// Slows down or stops a write while flushes and compactions are behind, as
+  /// `DiskOptions::write_stall` says. Called before taking the log lock, so the background
+  /// thread can catch up meanwhile. A stopped write goes on once the database is read-only,
+  /// as it can't catch up then, and the write fails right away.
+  fn throttle(&self) -> Result<(), FluxError> {
+    let options = &self.inner.options.write_stall;
+    if options.is_disabled() {
+      return Ok(());
+    }
+    let start = Instant::now();
+    match self.inner.stall() {
+      Stall::None => return Ok(()),
+      Stall::SlowDown => thread::sleep(options.slowdown_delay),
+      Stall::Stop if options.on_stop == StallPolicy::Reject => return Err(FluxError::Busy),
+      Stall::Stop => {
+        let mut state = self.inner.work.lock().unwrap();
+        while self.inner.stall() == Stall::Stop && !self.inner.health.is_read_only() {
+          state = self.inner.work_changed.wait_timeout(state, STALL_RECHECK).unwrap().0;
+        }
+      }
+    }
+    let stalled = start.elapsed();
+    self.inner.stats.record_stall(stalled);
+    let message = format_args!(
+      "held a write back for {:?} while flushes and compactions catch up",
+      stalled
+    );
+    let level = self.inner.options.operation_level(stalled);
+    self.inner.options.logger.log(level, Subsystem::Flush, message);
+    Ok(())
+  }
+
+  /// Releases the log lock after a write and, under `sync_writes`, waits for the write to
+  /// be durable. The write is already in the memtable, so readers see it before it is
+  /// durable, and still see it if the sync fails.
+  fn finish_write(&self, log: MutexGuard<'_, WriteLog>) -> io::Result<()> {
+    self.finish_write_with(log, &WriteOptions::default())
+  }
+
+  /// Like `finish_write`, also waiting for the write to be durable if `write_options` asks
+  /// for it. A write that skipped the WAL has nothing to wait for.
+  fn finish_write_with(
+    &self,
+    log: MutexGuard<'_, WriteLog>,
+    write_options: &WriteOptions,
+  ) -> io::Result<()> {
+    let sequence = log.last_sequence;
+    drop(log);
+    let sync = write_options.sync || self.inner.options.sync_writes;
+    match sync && !write_options.disable_wal {
+      true => self.inner.wait_durable(sequence),
+      false => Ok(()),
+    }
+  }
+
+  /// Blocks until every frozen memtable is flushed and background work is idle.
+  #[cfg(test)]
+  pub(crate) fn wait_for_background_work(&self) {
+    let mut state = self.inner.work.lock().unwrap();
+    while state.requested || state.running || !self.inner.read_mem_tables().immutable.is_empty() {
+      state = self.inner.work_changed.wait(state).unwrap();
+    }
+  }
+}
+
+/// Appends the edit from `previous` to `manifest`, just stored, to the manifest history.
+/// The history is only read for audits, so failing to append to it is logged rather than
+/// failing the edit.
+fn record_edit(
+  options: &DiskOptions,
+  dir: &Path,
+  previous: &Manifest,
+  manifest: &Manifest,
+  reason: EditReason,
+) {
+  // Peeking leaves the ticks of a logical clock to the writes.
+  let edit = ManifestEdit::between(previous, manifest, reason, options.clock.peek_micros());
+  if let Err(e) = edit.append_with(&options.storage, dir) {
+    let subsystem = match reason {
+      EditReason::Open => Subsystem::Recovery,
+      EditReason::WalRotation => Subsystem::Wal,
+      EditReason::Flush => Subsystem::Flush,
+      EditReason::Compaction => Subsystem::Compaction,
+      EditReason::Ingestion => Subsystem::Flush,
+    };
+    let message = format_args!("appending to the manifest history failed: {}", e);
+    options.logger.log(Level::Warn, subsystem, message);
+  }
+}
+
+/// Moves a WAL file the database no longer needs to the archive, or deletes it if there is
+/// none.
+fn discard_wal_file(options: &DiskOptions, dir: &Path, path: &Path) -> io::Result<()> {
+  match &options.wal_archive {
+    Some(archive) => move_file(&options.storage, path, &archive.path(dir).join(file_name(path))),
+    None => options.storage.delete(path),
+  }
+}
+
+/// Deletes the archived WAL files past the retention of the archive, if there is one. A
+/// failure is only logged, as the next pass tries again.
+fn prune_wal_archive(options: &DiskOptions, dir: &Path) {
+  let Some(archive) = &options.wal_archive else {
+    return;
+  };
+  let now = options.clock.now_micros();
+  match archive.prune(&options.storage, &archive.path(dir), now) {
+    Ok(removed) => {
+      for path in removed {
+        let message = format_args!("removed {} from the WAL archive", path.display());
+        options.logger.log(Level::Debug, Subsystem::Wal, message);
+      }
+    }
+    Err(e) => {
+      let message = format_args!("pruning the WAL archive failed: {}", e);
+      options.logger.log(Level::Warn, Subsystem::Wal, message);
+    }
+  }
+}
+
+/// Moves a file within `storage`, copying it then removing the original if the rename
+/// fails because the two paths are on different file systems.
+fn move_file(storage: &Storage, from: &Path, to: &Path) -> io::Result<()> {
+  match storage.rename(from, to) {
+    Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {}
+    result => return result,
+  }
+  let source = storage.open(from)?;
+  let mut dest = storage.create(to)?;
+  let copied = (|| {
+    let mut buf = vec![0; 1 << 20];
+    let mut offset = 0;
+    loop {
+      let read = source.read_at(&mut buf, offset)?;
+      if read == 0 {
+        break;
+      }
+      dest.append(&buf[..read])?;
+      offset += read as u64;
+    }
+    dest.sync()
+  })();
+  if let Err(e) = copied {
+    let _ = storage.delete(to);
+    return Err(e);
+  }
+  storage.delete(from)
+}
+
+/// Describes a key for the logs by its length and its first `LOGGED_KEY_BYTES` bytes in hex.
+fn redact_key(key: &[u8]) -> String {
+  let shown = &key[..key.len().min(LOGGED_KEY_BYTES)];
+  let hex: String = shown.iter().map(|byte| format!("{:02x}", byte)).collect();
+  let more = if shown.len() < key.len() { ".." } else { "" };
+  format!("key {}{} ({} bytes)", hex, more, key.len())
+}
+
+/// Removes the segments `Disk::ingest_segments` rewrote, and their value log files, once the
+/// ingestion failed.
+fn abandon_rewritten(storage: &Storage, rewritten: Vec<(String, SSTable, ValueLogWriter)>) {
+  for (_, segment, mut values) in rewritten {
+    let path = segment.path().to_owned();
+    drop(segment);
+    let _ = storage.delete(&path);
+    values.abandon();
+  }
+}
+
+/// Opens a segment of the database, on its block cache if it has one.
+fn open_segment(options: &DiskOptions, value_log: &ValueLog, path: &Path) -> io::Result<SSTable> {
+  let segment = SSTable::open_with(&options.storage, path)?
+    .with_key_order(&options.comparator)
+    .with_value_log(value_log);
+  Ok(match &options.block_cache {
+    Some(cache) => segment.with_block_cache(cache),
+    None => segment,
+  })
+}
+
+/// Opens a segment `manifest` lists, reading the entries of an ingested one at the sequence
+/// number it was ingested at.
+fn open_listed_segment(
+  options: &DiskOptions,
+  value_log: &ValueLog,
+  manifest: &Manifest,
+  path: &Path,
+) -> io::Result<SSTable> {
+  let sequence = manifest.ingested_sequence(&file_name(path));
+  Ok(open_segment(options, value_log, path)?.with_global_sequence(sequence))
+}
+
+fn record_entry(record: &InMemoryRecord) -> Entry {
+  Entry {
+    key: record.key.to_vec(),
+    value: record.value.as_deref().filter(|_| !record.is_deleted).map(<[u8]>::to_vec),
+    retained: record.value.as_deref().filter(|_| record.is_deleted).map(<[u8]>::to_vec),
+    timestamp: record.timestamp,
+    sequence: record.sequence,
+    schema: record.schema,
+    value_pointer: false,
+    backdated: record.backdated,
+  }
+}
+
+/// Returns copies of the range tombstones written at or before `sequence`.
+fn visible_range_tombstones(
+  range_tombstones: &[RangeTombstone],
+  sequence: u64,
+) -> impl Iterator<Item = RangeTombstone> + '_ {
+  range_tombstones
+    .iter()
+    .filter(move |tombstone| tombstone.sequence <= sequence)
+    .cloned()
+}
+
+/// Applies range tombstones to the version of a key found by a read, or to its absence: the
+/// newest tombstone covering the key hides the version and is returned in its place.
+fn resolve_range_deletes(
+  key: &[u8],
+  entry: Option<Entry>,
+  range_tombstones: &[RangeTombstone],
+  order: &KeyOrder,
+) -> Option<Entry> {
+  let written = entry.as_ref().map_or(0, |entry| entry.sequence);
+  let covering = range_tombstones
+    .iter()
+    .filter(|tombstone| tombstone.covers(key, written, order))
+    .max_by_key(|tombstone| tombstone.sequence);
+  match covering {
+    Some(tombstone) => Some(Entry {
+      key: key.to_vec(),
+      value: None,
+      timestamp: tombstone.timestamp,
+      sequence: tombstone.sequence,
+      schema: 0,
+      retained: None,
+      value_pointer: false,
+      backdated: false,
+    }),
+    None => entry,
+  }
+}
+
+/// Copies the records of a memtable within the range, so the merge doesn't hold its lock.
+fn mem_table_source<'a>(
+  table: &InMemoryTable,
+  bounds: (Bound<&[u8]>, Bound<&[u8]>),
+  descending: bool,
+  key_filter: Option<&ScanFilter>,
+  keys_only: bool,
+  max_keys: usize,
+) -> (EntrySource<'a>, Option<Vec<u8>>) {
+  let keys = table.range_by_key(bounds);
+  // Records whose keys the filter rejects are never copied, nor any value of a keys-only read.
+  let kept = |versions: &&[InMemoryRecord]| {
+    key_filter.is_none_or(|filter| versions.first().is_none_or(|record| filter(&record.key)))
+  };
+  let entry = |record: &InMemoryRecord| match keys_only {
+    true => Entry {
+      key: record.key.to_vec(),
+      value: (!record.is_deleted).then(Vec::new),
+      timestamp: record.timestamp,
+      sequence: record.sequence,
+      schema: record.schema,
+      retained: None,
+      value_pointer: false,
+      backdated: record.backdated,
+    },
+    false => record_entry(record),
+  };
+  let mut keys: Box<dyn Iterator<Item = &[InMemoryRecord]>> = match descending {
+    false => Box::new(keys.filter(kept)),
+    // Keys in decreasing order, but the versions of each still newest first.
+    true => Box::new(keys.collect::<Vec<_>>().into_iter().rev().filter(kept)),
+  };
+  let copied: Vec<&[InMemoryRecord]> = keys.by_ref().take(max_keys).collect();
+  let cutoff = match keys.next() {
+    Some(_) => copied.last().and_then(|versions| versions.first()).map(|last| last.key.to_vec()),
+    None => None,
+  };
+  let entries: Vec<Entry> = copied.into_iter().flatten().map(entry).collect();
+  (Box::new(entries.into_iter().map(Ok)), cutoff)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::clock::Clock;
+  use crate::comparator::Comparator;
+  use crate::compression::Compression;
+  use crate::invalidation::Invalidation;
+  use crate::logging::{LogSink, Logger};
+  use crate::rate_limiter::RateLimiter;
+  use crate::manifest::MANIFEST_FILE;
+  use crate::mem_table::{MemTableKind, RECORD_OVERHEAD};
+  use crate::prefix::PrefixExtractor;
+  use crate::snapshot::Snapshot;
+  use crate::sst_writer::SstWriter;
+  use crate::storage::{StorageBackend, StorageFile};
+  use crate::subscription::ChangeOp;
+  use crate::tombstone_retention::TombstoneRetention;
+  use crate::validation::{ForbiddenPrefix, MaxValueSize, WriteValidators};
+  use crate::value_log::ValueLogOptions;
+  use crate::wal_archive::WalArchive;
+  use crate::wal_mirror::WalMirror;
+  use crate::write_stall::WriteStall;
+  use crate::utils::find_files_with_extension;
+  use rand::Rng;
+  use std::fs::{create_dir_all, remove_dir_all};
+
+  #[test]
+  fn test_wal_rotation_by_size() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      max_wal_file_size: Some(64),
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    for i in 0..10 {
+      disk.set(format!("Server{}", i).as_bytes(), b"nginx").unwrap();
+    }
+    disk.delete(b"Server3").unwrap();
+
+    let wal_files = disk.wal_files();
+    assert!(wal_files.len() > 1, "WAL should have rotated");
+    assert_eq!(
+      find_files_with_extension(test_dir.as_ref(), "wal").len(),
+      wal_files.len()
+    );
+    drop(disk);
+
+    let disk = Disk::open(&test_dir, options).unwrap();
+    for i in 0..10 {
+      let key = format!("Server{}", i);
+      assert_eq!(disk.get(key.as_bytes()).unwrap().is_some(), i != 3);
+    }
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_open_removes_unlisted_wal_files() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let disk = Disk::new(&test_dir);
+    disk.set(b"Server", b"nginx").unwrap();
+    drop(disk);
+
+    // A WAL created by an interrupted rotation or recovery is not in the manifest.
+    let mut stale = WAL::create_new(test_dir.as_ref()).unwrap();
+    stale.record_insertion(b"Server", b"apache", u128::MAX, 1).unwrap();
+    stale.flush().unwrap();
+    let stale_path = stale.path().to_owned();
+    drop(stale);
+
+    let disk = Disk::new(&test_dir);
+    assert!(!stale_path.exists());
+    assert_eq!(disk.get(b"Server").unwrap().unwrap().value(), b"nginx");
+    assert_eq!(
+      Manifest::load(test_dir.as_ref()).unwrap().unwrap().wal_paths(test_dir.as_ref()),
+      disk.wal_files()
+    );
+    let mut wal_files = find_files_with_extension(test_dir.as_ref(), "wal");
+    wal_files.sort();
+    assert_eq!(wal_files, disk.wal_files());
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_open_keeps_wal_files_until_flush() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let disk = Disk::open(&test_dir, DiskOptions::default()).unwrap();
+    disk.set(b"Server", b"nginx").unwrap();
+    let first = disk.wal_files();
+    drop(disk);
+
+    // Recovery reads the WAL file in place instead of copying it into the fresh one.
+    let bytes = std::fs::read(&first[0]).unwrap();
+    let disk = Disk::open(&test_dir, DiskOptions::default()).unwrap();
+    let wal_files = disk.wal_files();
+    assert_eq!(wal_files.len(), 2);
+    assert_eq!(wal_files[0], first[0]);
+    assert_eq!(std::fs::read(&first[0]).unwrap(), bytes);
+    assert_eq!(disk.statistics().recovery.records_replayed, 1);
+    disk.set(b"Database", b"PostgreSQL").unwrap();
+    drop(disk);
+
+    // Files left empty are retired rather than piling up.
+    let disk = Disk::open(&test_dir, DiskOptions::default()).unwrap();
+    drop(disk);
+    let disk = Disk::open(&test_dir, DiskOptions::default()).unwrap();
+    assert_eq!(disk.wal_files().len(), 3);
+    assert_eq!(disk.get(b"Server").unwrap().unwrap().value(), b"nginx");
+    assert_eq!(disk.get(b"Database").unwrap().unwrap().value(), b"PostgreSQL");
+    assert_eq!(disk.last_sequence(), 2);
+
+    // Flushing the memtable retires them.
+    disk.compact().unwrap();
+    assert_eq!(disk.wal_files().len(), 1);
+    assert_eq!(find_files_with_extension(test_dir.as_ref(), "wal"), disk.wal_files());
+    drop(disk);
+    let disk = Disk::open(&test_dir, DiskOptions::default()).unwrap();
+    assert_eq!(disk.statistics().recovery.records_replayed, 0);
+    assert_eq!(disk.get(b"Database").unwrap().unwrap().value(), b"PostgreSQL");
+
+    drop(disk);
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_concurrent_handles() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Db>();
+
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let db = Db::new(&test_dir);
+    let writers: Vec<_> = (0..4)
+      .map(|writer| {
+        let db = db.clone();
+        std::thread::spawn(move || {
+          for i in 0..50 {
+            let key = format!("writer{}/key{}", writer, i);
+            db.set(key.as_bytes(), b"value").unwrap();
+            assert!(db.get(key.as_bytes()).unwrap().is_some());
+          }
+        })
+      })
+      .collect();
+    for writer in writers {
+      writer.join().unwrap();
+    }
+    drop(db);
+
+    let db = Db::new(&test_dir);
+    for writer in 0..4 {
+      for i in 0..50 {
+        let key = format!("writer{}/key{}", writer, i);
+        assert_eq!(db.get(key.as_bytes()).unwrap().unwrap().value(), b"value");
+      }
+    }
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_write_batch_and_set_many() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let disk = Disk::new(&test_dir);
+    disk.set(b"Server", b"nginx").unwrap();
+
+    let mut batch = WriteBatch::new();
+    batch.put(b"Database", b"PostgreSQL");
+    batch.delete(b"Server");
+    assert_eq!(disk.write(batch), Ok(2));
+    assert!(disk.get(b"Server").unwrap().is_none());
+
+    // Larger than a single frame, so it is committed in several.
+    let value = vec![7; 64 * 1024];
+    let pairs: Vec<_> = (0..100).map(|i| (format!("blob{}", i), value.clone())).collect();
+    assert_eq!(disk.set_many(pairs), Ok(100));
+    drop(disk);
+
+    let disk = Disk::new(&test_dir);
+    assert!(disk.get(b"Server").unwrap().is_none());
+    assert_eq!(disk.get(b"Database").unwrap().unwrap().value(), b"PostgreSQL");
+    for i in 0..100 {
+      assert_eq!(disk.get(format!("blob{}", i).as_bytes()).unwrap().unwrap().value(), &value[..]);
+    }
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_resumable_scan() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      memtable_size: 1024,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    for i in 0..2000 {
+      disk.set(format!("key{:04}", i).as_bytes(), b"nginx").unwrap();
+    }
+    let mut scan = disk.scan_resumable(&b"key0100"[..]..).unwrap();
+    let first: Vec<DiskEntry> = scan.by_ref().take(1500).map(Result::unwrap).collect();
+    assert_eq!(first.last().unwrap().key(), b"key1599");
+    let checkpoint = scan.checkpoint();
+    drop(scan);
+
+    // Overwrites after the checkpoint, flushed and compacted across a restart, stay unseen.
+    for i in 0..2000 {
+      disk.set(format!("key{:04}", i).as_bytes(), b"apache").unwrap();
+    }
+    disk.delete_range(b"key1700", b"key1800").unwrap();
+    drop(disk);
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    disk.compact().unwrap();
+
+    let rest: Vec<DiskEntry> = disk.resume_scan(&checkpoint).unwrap().map(Result::unwrap).collect();
+    assert_eq!(rest.len(), 400);
+    assert_eq!(rest[0].key(), b"key1600");
+    assert!(rest.iter().all(|entry| entry.value() == b"nginx"));
+
+    // The completed scan released its pin.
+    assert!(matches!(disk.resume_scan(&checkpoint), Err(FluxError::InvalidArgument(_))));
+    assert!(disk.inner.lock_log().manifest.pinned_scans.is_empty());
+
+    let scan = disk.scan_resumable(..).unwrap();
+    let checkpoint = scan.checkpoint();
+    drop(scan);
+    disk.release_scan_checkpoint(&checkpoint).unwrap();
+    disk.release_scan_checkpoint(&checkpoint).unwrap();
+    assert!(disk.resume_scan(&checkpoint).is_err());
+    assert!(disk.live_snapshots().is_empty());
+    assert!(disk.resume_scan(b"garbage").is_err());
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_commit_timestamp_override() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let disk = Disk::new(&test_dir);
+    let mut batch = WriteBatch::new();
+    batch.put(b"Server", b"nginx");
+    batch.put(b"Database", b"PostgreSQL");
+    batch.set_commit_timestamp(1_000);
+    assert_eq!(disk.write(batch), Ok(2));
+
+    let mut backfill = WriteBatch::new();
+    backfill.put(b"Server", b"apache");
+    backfill.set_commit_timestamp(2_000);
+    assert_eq!(disk.write(backfill), Ok(1));
+
+    // Going back in time for a key is rejected, and nothing of the batch is written.
+    let mut stale = WriteBatch::new();
+    stale.put(b"Cache", b"Redis");
+    stale.delete(b"Server");
+    stale.set_commit_timestamp(1_500);
+    assert!(disk.write(stale).is_err());
+    assert!(disk.get(b"Cache").unwrap().is_none());
+
+    // So is a timestamp from the future.
+    let mut future = WriteBatch::new();
+    future.put(b"Cache", b"Redis");
+    future.set_commit_timestamp(u128::MAX);
+    assert!(matches!(disk.validate(&future), Err(FluxError::InvalidArgument(_))));
+    assert!(disk.write(future).is_err());
+    assert!(disk.get(b"Cache").unwrap().is_none());
+    drop(disk);
+
+    let disk = Disk::new(&test_dir);
+    assert_eq!(disk.get(b"Server").unwrap().unwrap().timestamp(), 2_000);
+    assert_eq!(disk.get(b"Database").unwrap().unwrap().timestamp(), 1_000);
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_timestamp_regressions() {
+    let options = |on_timestamp_regression| DiskOptions {
+      on_timestamp_regression,
+      retained_versions: 2,
+      storage: Storage::new(MemoryBackend::new()),
+      ..DiskOptions::default()
+    };
+    let open = |options: DiskOptions| {
+      let disk = Disk::open("memory", options).unwrap();
+      let mut batch = WriteBatch::new();
+      batch.put_at(b"Server", b"nginx", 2_000);
+      batch.delete_at(b"Cache", 2_000);
+      disk.write(batch).unwrap();
+      disk
+    };
+    let stale = || {
+      let mut batch = WriteBatch::new();
+      batch.put_at(b"Server", b"apache", 1_000);
+      batch.put_at(b"Cache", b"redis", 1_500);
+      batch.put_at(b"Database", b"PostgreSQL", 1_000);
+      batch
+    };
+    let timestamps = |disk: &Disk, key: &[u8]| -> Vec<u128> {
+      disk.history(key).unwrap().iter().map(Version::timestamp).collect()
+    };
+
+    let disk = open(options(TimestampRegression::Reject));
+    assert!(disk.write(stale()).is_err());
+    assert!(disk.get(b"Database").unwrap().is_none());
+
+    // Only the keys that don't go back in time are written.
+    let disk = open(options(TimestampRegression::Ignore));
+    assert_eq!(disk.write(stale()), Ok(1));
+    assert_eq!(disk.get(b"Server").unwrap().unwrap().value(), b"nginx");
+    assert_eq!(timestamps(&disk, b"Server"), [2_000]);
+    assert!(disk.get(b"Database").unwrap().is_some());
+
+    // Stale writes go below the latest versions, which reads keep returning.
+    let options = options(TimestampRegression::KeepAsOlderVersion);
+    let disk = open(options.clone());
+    assert_eq!(disk.write(stale()), Ok(3));
+    let check = |disk: &Disk| {
+      let server = disk.get(b"Server").unwrap().unwrap();
+      assert_eq!((server.value(), server.timestamp()), (&b"nginx"[..], 2_000));
+      assert_eq!(disk.get_at(b"Server", 1_500).unwrap().unwrap().value(), b"apache");
+      assert_eq!(timestamps(disk, b"Server"), [2_000, 1_000]);
+      assert!(disk.get(b"Cache").unwrap().is_none());
+      assert!(disk.get_at(b"Cache", 2_500).unwrap().is_none());
+      assert_eq!(disk.get_at(b"Cache", 1_800).unwrap().unwrap().value(), b"redis");
+      let keys: Vec<Vec<u8>> = disk.scan(..).unwrap().iter().map(|e| e.key().to_vec()).collect();
+      assert_eq!(keys, [&b"Database"[..], b"Server"]);
+    };
+    check(&disk);
+
+    // They are replayed from the WAL, and kept by flushes and compactions.
+    drop(disk);
+    let disk = Disk::open("memory", options).unwrap();
+    check(&disk);
+    disk.flush().unwrap();
+    check(&disk);
+    disk.compact().unwrap();
+    check(&disk);
+  }
+
+  #[test]
+  fn test_lock_metrics_toggle() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let disk = Disk::new(&test_dir);
+    disk.set(b"Server", b"nginx").unwrap();
+    assert_eq!(disk.lock_metrics(), LockMetricsSnapshot::default());
+
+    disk.set_lock_metrics_enabled(true);
+    disk.set(b"Server", b"apache").unwrap();
+    disk.get(b"Server").unwrap().unwrap();
+    let metrics = disk.lock_metrics();
+    assert_eq!(metrics.write_log.acquisitions, 1);
+    assert_eq!(metrics.mem_table_write.acquisitions, 1);
+    assert_eq!(metrics.mem_table_read.acquisitions, 1);
+
+    disk.reset_lock_metrics();
+    assert_eq!(disk.lock_metrics(), LockMetricsSnapshot::default());
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_flush_to_segments() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      memtable_size: 1024,
+      block_size: 256,
+      compaction_trigger: 100,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    for i in 0..200 {
+      disk.set(format!("key{:03}", i).as_bytes(), b"nginx").unwrap();
+    }
+    for i in (0..200).step_by(3) {
+      disk.delete(format!("key{:03}", i).as_bytes()).unwrap();
+    }
+    disk.wait_for_background_work();
+
+    let segment_files = disk.segment_files();
+    assert!(segment_files.len() > 1, "memtables should have been flushed");
+    assert_eq!(find_files_with_extension(test_dir.as_ref(), "sst").len(), segment_files.len());
+    assert_eq!(find_files_with_extension(test_dir.as_ref(), "wal").len(), disk.wal_files().len());
+
+    let check = |disk: &Disk| {
+      for i in 0..200 {
+        let entry = disk.get(format!("key{:03}", i).as_bytes()).unwrap();
+        assert_eq!(entry.is_some(), i % 3 != 0, "key{:03}", i);
+      }
+      let keys: Vec<Vec<u8>> = disk
+        .scan(&b"key010"[..]..&b"key020"[..])
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.key().to_vec())
+        .collect();
+      let expected: Vec<Vec<u8>> = (10..20)
+        .filter(|i| i % 3 != 0)
+        .map(|i| format!("key{:03}", i).into_bytes())
+        .collect();
+      assert_eq!(keys, expected);
+    };
+    check(&disk);
+    drop(disk);
+
+    let disk = Disk::open(&test_dir, options).unwrap();
+    check(&disk);
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_flush_and_compact_range() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      compaction_trigger: 100,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    let set_all = |prefix: &str, value: &[u8]| {
+      for i in 0..100 {
+        disk.set(format!("{}{:03}", prefix, i).as_bytes(), value).unwrap();
+      }
+    };
+    // Segments, oldest first: the b keys, the a keys, a bulk delete of a keys along with a
+    // newer b050, then the c keys.
+    set_all("b", b"old");
+    disk.flush().unwrap();
+    set_all("a", b"nginx");
+    disk.flush().unwrap();
+    for i in 20..80 {
+      disk.delete(format!("a{:03}", i).as_bytes()).unwrap();
+    }
+    disk.set(b"b050", b"new").unwrap();
+    disk.flush().unwrap();
+    set_all("c", b"nginx");
+    disk.flush().unwrap();
+    assert_eq!(disk.inner.read_mem_tables().active.record_count(), 0);
+    let segments = disk.segment_files();
+    assert_eq!(segments.len(), 4);
+
+    // Only the two segments holding a keys are merged, in the place of the older one.
+    disk.compact_range(b"a", b"b").unwrap();
+    let merged = disk.segment_files();
+    assert_eq!(merged.len(), 3);
+    assert_eq!((&merged[0], &merged[2]), (&segments[0], &segments[3]));
+    assert_eq!(disk.scan(&b"a"[..]..&b"b"[..]).unwrap().len(), 40);
+    assert_eq!(disk.get(b"b050").unwrap().unwrap().value(), b"new");
+    // The oldest segment may still hold versions the tombstones hide.
+    assert_eq!(disk.tombstone_density(&b"a"[..]..&b"b"[..]).unwrap().tombstones, 60);
+    assert_eq!(disk.statistics().compactions, 1);
+    drop(disk);
+
+    let disk = Disk::open(&test_dir, options).unwrap();
+    assert_eq!(disk.get(b"b050").unwrap().unwrap().value(), b"new");
+    assert_eq!(disk.get(b"b049").unwrap().unwrap().value(), b"old");
+    // Reaching the oldest segment, the merge drops the tombstones.
+    disk.compact_range(b"b050", b"b051").unwrap();
+    assert_eq!(disk.segment_files().len(), 2);
+    assert_eq!(disk.tombstone_density(&b"a"[..]..&b"b"[..]).unwrap().tombstones, 0);
+    assert_eq!(disk.scan(..).unwrap().len(), 240);
+    disk.compact_range(b"x", b"z").unwrap();
+    disk.compact_range(b"z", b"a").unwrap();
+    assert_eq!(disk.segment_files().len(), 2);
+
+    drop(disk);
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_ingest_segments() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    let load_dir = Path::new(&test_dir).join("load");
+    create_dir_all(&load_dir).unwrap();
+
+    let options = DiskOptions::default();
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    disk.set(b"a0000", b"nginx").unwrap();
+    disk.set(b"z0000", b"nginx").unwrap();
+    disk.delete_range(b"x", b"y").unwrap();
+    let write = |name: &str, prefix: &str, keys: std::ops::Range<usize>| {
+      let path = load_dir.join(name);
+      let mut writer = SstWriter::create(&path, &options).unwrap();
+      for i in keys {
+        writer.put(format!("{}{:04}", prefix, i).as_bytes(), b"loaded").unwrap();
+      }
+      writer.finish().unwrap();
+      path
+    };
+    let first = write("first.sst", "m", 0..1000);
+    let second = write("second.sst", "n", 0..500);
+
+    // Files overlapping each other or the data already there are refused, and left alone.
+    let overlapping = write("overlapping.sst", "m", 999..1001);
+    let e = disk.ingest_segments(&[&first, &overlapping]).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    let e = disk.ingest_segments(&[write("memtable.sst", "a", 0..1)]).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    disk.flush().unwrap();
+    let e = disk.ingest_segments(&[write("deleted.sst", "x", 0..1)]).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    let e = disk.ingest_segments(&[write("segment.sst", "a", 0..1)]).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    assert!(first.exists() && overlapping.exists());
+    let flushed = disk.segment_files();
+    assert_eq!(flushed.len(), 1);
+
+    // The ingestion takes a sequence number, which snapshots taken before it don't see.
+    let before = disk.snapshot();
+    assert_eq!(disk.ingest_segments(&[&second, &first]).unwrap(), 1500);
+    assert!(!first.exists() && !second.exists());
+    assert_eq!(disk.last_sequence(), before.sequence() + 1);
+    assert!(before.get(b"m0999").unwrap().is_none());
+    assert_eq!(before.scan(&b"m"[..]..&b"o"[..]).unwrap().len(), 0);
+    assert_eq!(disk.get(b"n0000").unwrap().unwrap().sequence(), disk.last_sequence());
+    drop(before);
+    // The ingested segments are listed first, as the oldest.
+    let segments = disk.segment_files();
+    assert_eq!(segments.len(), 3);
+    assert_eq!(segments[2], flushed[0]);
+    assert_eq!(disk.get(b"m0999").unwrap().unwrap().value(), b"loaded");
+    assert_eq!(disk.scan(&b"m"[..]..&b"o"[..]).unwrap().len(), 1500);
+    let history = disk.manifest_history().unwrap();
+    assert_eq!(history.last().unwrap().reason, EditReason::Ingestion);
+
+    // Writes made afterwards hide the ingested keys, through flushes and compactions too.
+    disk.set(b"m0000", b"apache").unwrap();
+    disk.delete(b"n0000").unwrap();
+    disk.compact().unwrap();
+    drop(disk);
+    let disk = Disk::open(&test_dir, options).unwrap();
+    assert_eq!(disk.get(b"m0000").unwrap().unwrap().value(), b"apache");
+    assert!(disk.get(b"n0000").unwrap().is_none());
+    assert_eq!(disk.scan(..).unwrap().len(), 1501);
+    drop(disk);
+
+    // With a value log, the files are rewritten with their large values moved to it, and
+    // the ingested sequence outlives a reopen.
+    let options = DiskOptions {
+      value_log: Some(ValueLogOptions {
+        min_value_size: 100,
+        gc_garbage_ratio: 0.5,
+      }),
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    let path = load_dir.join("large.sst");
+    let mut writer = SstWriter::create(&path, &options).unwrap();
+    writer.put(b"p0000", &[b'a'; 200]).unwrap();
+    writer.put(b"p0001", b"small").unwrap();
+    writer.finish().unwrap();
+    let value_logs = disk.value_log_files().len();
+    assert_eq!(disk.ingest_segments(&[&path]).unwrap(), 2);
+    assert!(!path.exists());
+    assert_eq!(disk.value_log_files().len(), value_logs + 1);
+    let sequence = disk.last_sequence();
+    drop(disk);
+    let disk = Disk::open(&test_dir, options).unwrap();
+    let entry = disk.get(b"p0000").unwrap().unwrap();
+    assert_eq!((entry.value(), entry.sequence()), (&[b'a'; 200][..], sequence));
+    assert_eq!(disk.get(b"p0001").unwrap().unwrap().value(), b"small");
+
+    drop(disk);
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_obsolete_files() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      compaction_trigger: 100,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    disk.set(b"Server", b"nginx").unwrap();
+    disk.flush().unwrap();
+    disk.set(b"Server", b"apache").unwrap();
+    disk.flush().unwrap();
+    assert!(disk.obsolete_files().is_empty());
+
+    // Segments a read still has open outlive the compaction retiring them.
+    let reading = disk.inner.segments();
+    disk.compact().unwrap();
+    let obsolete = disk.obsolete_files();
+    assert_eq!(obsolete.len(), 2);
+    assert_eq!(disk.delete_obsolete_files(), 0);
+    assert!(obsolete.iter().all(|path| path.exists()));
+    drop(reading);
+    assert_eq!(disk.delete_obsolete_files(), 2);
+    assert!(obsolete.iter().all(|path| !path.exists()));
+    assert_eq!(disk.get(b"Server").unwrap().unwrap().value(), b"apache");
+    drop(disk);
+
+    // Retired files wait out the grace period, then the background thread deletes them.
+    let options = DiskOptions {
+      obsolete_file_grace: Duration::from_millis(200),
+      ..options
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    disk.set(b"Server", b"caddy").unwrap();
+    disk.flush().unwrap();
+    let obsolete = disk.obsolete_files();
+    assert!(!obsolete.is_empty());
+    assert_eq!(disk.delete_obsolete_files(), 0);
+    assert!(obsolete.iter().all(|path| path.exists()));
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !disk.obsolete_files().is_empty() {
+      assert!(Instant::now() < deadline, "retired files weren't deleted");
+      thread::sleep(Duration::from_millis(10));
+    }
+    assert!(obsolete.iter().all(|path| !path.exists()));
+
+    drop(disk);
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_reads_during_flush_and_compaction() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      memtable_size: 512,
+      compaction_trigger: 2,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    let writer = {
+      let disk = disk.clone();
+      thread::spawn(move || {
+        for i in 0..2000 {
+          disk.set(format!("key{:05}", i).as_bytes(), b"nginx").unwrap();
+        }
+      })
+    };
+    // Every write is one sequence number, so a snapshot sees as many keys as its sequence,
+    // however the memtables and segments holding them change under its reads.
+    while !writer.is_finished() {
+      let snapshot = disk.snapshot();
+      let expected = snapshot.sequence() as usize;
+      assert_eq!(snapshot.scan(..).unwrap().len(), expected);
+      let mut cursor = disk.cursor();
+      let mut seen = 0;
+      let mut entry = cursor.seek_to_first().unwrap().is_some();
+      while entry {
+        seen += 1;
+        entry = cursor.next().unwrap().is_some();
+      }
+      assert_eq!(seen, cursor.sequence() as usize);
+      let last = format!("key{:05}", expected.saturating_sub(1));
+      assert_eq!(snapshot.get(last.as_bytes()).unwrap().is_some(), expected > 0);
+    }
+    writer.join().unwrap();
+    assert!(disk.statistics().compactions > 0);
+
+    drop(disk);
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_compaction_merges_segments() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      memtable_size: 512,
+      compaction_trigger: 2,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    for round in 0..5 {
+      for i in 0..50 {
+        let value = format!("round{}", round);
+        disk.set(format!("key{:02}", i).as_bytes(), value.as_bytes()).unwrap();
+      }
+    }
+    for i in 0..25 {
+      disk.delete(format!("key{:02}", i).as_bytes()).unwrap();
+    }
+    disk.set(b"filler", &[0; 512]).unwrap();
+    disk.wait_for_background_work();
+
+    assert_eq!(disk.segment_files().len(), 1);
+    assert_eq!(find_files_with_extension(test_dir.as_ref(), "sst"), disk.segment_files());
+    drop(disk);
+
+    let disk = Disk::open(&test_dir, options).unwrap();
+    for i in 0..50 {
+      let entry = disk.get(format!("key{:02}", i).as_bytes()).unwrap();
+      match i < 25 {
+        true => assert!(entry.is_none()),
+        false => assert_eq!(entry.unwrap().value(), b"round4"),
+      }
+    }
+    let segment = SSTable::open(&disk.segment_files()[0]).unwrap();
+    assert!(segment.iter().all(|entry| !entry.unwrap().is_deleted()));
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_manual_compaction() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      memtable_size: 1024,
+      compaction_trigger: 100,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    for i in 0..100 {
+      disk.set(format!("key{:02}", i).as_bytes(), b"nginx").unwrap();
+    }
+    disk.delete(b"key00").unwrap();
+    disk.compact().unwrap();
+    assert_eq!(disk.segment_files().len(), 1);
+    assert_eq!(disk.statistics().compactions, 1);
+    assert!(disk.get(b"key00").unwrap().is_none());
+    assert_eq!(disk.get(b"key99").unwrap().unwrap().value(), b"nginx");
+
+    // The unflushed write is compacted too.
+    disk.set(b"key00", b"apache").unwrap();
+    disk.compact().unwrap();
+    assert_eq!(disk.segment_files().len(), 1);
+    assert_eq!(disk.statistics().compactions, 2);
+    assert_eq!(disk.get(b"key00").unwrap().unwrap().value(), b"apache");
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_get_ref() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let disk = Disk::open(&test_dir, DiskOptions::default()).unwrap();
+    disk.set(b"Server", b"nginx").unwrap();
+    disk.compact().unwrap();
+    disk.set(b"Database", b"PostgreSQL").unwrap();
+    disk.set(b"Cache", b"Redis").unwrap();
+
+    // From a segment, then from the memtable.
+    assert_eq!(disk.get_ref(b"Server", |value| value.len()).unwrap(), Some(5));
+    assert_eq!(disk.get_ref(b"Database", |value| value == b"PostgreSQL").unwrap(), Some(true));
+    assert_eq!(disk.get_ref(b"Queue", |value| value.len()).unwrap(), None);
+
+    disk.delete(b"Cache").unwrap();
+    disk.delete_range(b"A", b"E").unwrap();
+    disk.set(b"Server", b"apache").unwrap();
+    assert_eq!(disk.get_ref(b"Cache", |value| value.len()).unwrap(), None);
+    assert_eq!(disk.get_ref(b"Database", |value| value.len()).unwrap(), None);
+    assert_eq!(disk.get_ref(b"Server", |value| value.to_vec()).unwrap(), Some(b"apache".to_vec()));
+
+    let entry = disk.get(b"Server").unwrap().unwrap();
+    let (key, value) = (entry.key_bytes(), entry.value_bytes());
+    drop(entry);
+    assert_eq!((&key[..], &value[..]), (&b"Server"[..], &b"apache"[..]));
+
+    // Pinned in the memtable without holding it locked, so writers go on meanwhile.
+    let gets = disk.statistics().get_latency_micros.count;
+    let pinned = disk.get_pinned(b"Server").unwrap().unwrap();
+    assert_eq!(&pinned[..], b"apache");
+    assert_eq!(disk.statistics().get_latency_micros.count, gets + 1);
+    disk.set(b"Server", b"caddy").unwrap();
+    assert_eq!(&pinned[..], b"apache");
+    assert_eq!(&pinned.into_bytes()[..], b"apache");
+    assert!(disk.get_pinned(b"Cache").unwrap().is_none());
+    disk.compact().unwrap();
+    assert_eq!(&disk.get_pinned(b"Server").unwrap().unwrap()[..], b"caddy");
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_arena_memory_freezes_memtable() {
+    let options = DiskOptions {
+      memtable_size: 4096,
+      storage: Storage::new(MemoryBackend::new()),
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open("arena", options).unwrap();
+    // Overwrites keep the memtable small, but each leaves its value in the arena.
+    for _ in 0..100 {
+      disk.set(b"Server", &[b'x'; 1000]).unwrap();
+    }
+    disk.wait_for_background_work();
+    assert!(disk.statistics().flushes > 0);
+  }
+
+  #[test]
+  fn test_statistics_persist_across_restarts() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      memtable_size: 256,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    for i in 0..20 {
+      disk.set(format!("key{:02}", i).as_bytes(), &[0; 45]).unwrap();
+    }
+    disk.wait_for_background_work();
+    let before = disk.statistics();
+    assert_eq!(before.bytes_written, 20 * 50);
+    assert!(before.flushes > 0);
+    drop(disk);
+
+    let disk = Disk::open(&test_dir, options).unwrap();
+    let after = disk.statistics();
+    assert_eq!(StatisticsSnapshot { recovery: before.recovery, ..after }, before);
+    disk.delete(b"key00").unwrap();
+    assert_eq!(disk.statistics().bytes_written, before.bytes_written + 5);
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_rename() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let disk = Disk::new(&test_dir);
+    disk.set(b"Server", b"nginx").unwrap();
+    disk.set(b"Proxy", b"haproxy").unwrap();
+    disk.compact().unwrap();
+    let feed = disk.subscribe_invalidations(Granularity::Key, Duration::ZERO);
+
+    assert_eq!(disk.rename(b"Server", b"WebServer"), Ok(1));
+    assert_eq!(disk.rename(b"Server", b"Other"), Ok(0));
+    assert_eq!(disk.rename(b"WebServer", b"WebServer"), Ok(0));
+    // Replaces the value at the new key.
+    assert_eq!(disk.rename(b"WebServer", b"Proxy"), Ok(1));
+    assert_eq!(
+      feed.recv().unwrap().invalidations,
+      vec![
+        Invalidation::Key(b"Proxy".to_vec()),
+        Invalidation::Key(b"Server".to_vec()),
+        Invalidation::Key(b"WebServer".to_vec()),
+      ]
+    );
+    drop(disk);
+
+    let disk = Disk::new(&test_dir);
+    assert!(disk.get(b"Server").unwrap().is_none());
+    assert!(disk.get(b"WebServer").unwrap().is_none());
+    assert_eq!(disk.get(b"Proxy").unwrap().unwrap().value(), b"nginx");
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_sync_writes_share_syncs() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      sync_writes: true,
+      max_wal_file_size: Some(4096),
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    disk.set(b"Server", b"nginx").unwrap();
+    disk.delete(b"Server").unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put(b"Cache", b"Redis");
+    disk.write(batch).unwrap();
+    assert_eq!(disk.statistics().wal_syncs, 3);
+
+    let writers: Vec<_> = (0..8)
+      .map(|writer| {
+        let disk = disk.clone();
+        thread::spawn(move || {
+          for i in 0..50 {
+            disk.set(format!("key{}-{:02}", writer, i).as_bytes(), &[0; 64]).unwrap();
+          }
+        })
+      })
+      .collect();
+    for writer in writers {
+      writer.join().unwrap();
+    }
+    let syncs = disk.statistics().wal_syncs - 3;
+    assert!(syncs > 0 && syncs <= 400);
+    drop(disk);
+
+    let disk = Disk::new(&test_dir);
+    assert_eq!(disk.scan(..).unwrap().len(), 401);
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_write_options() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let disk = Disk::new(&test_dir);
+    let unlogged = WriteOptions {
+      disable_wal: true,
+      ..WriteOptions::default()
+    };
+    let synced = WriteOptions {
+      sync: true,
+      ..WriteOptions::default()
+    };
+    let invalid = WriteOptions {
+      disable_wal: true,
+      sync: true,
+    };
+    assert!(disk.set_with(b"Server", b"nginx", &invalid).is_err());
+
+    disk.set(b"Server", b"nginx").unwrap();
+    disk.set_with(b"Cache", b"Redis", &unlogged).unwrap();
+    disk.delete_with(b"Server", &unlogged).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put(b"Queue", b"Kafka");
+    disk.write_with(batch, &unlogged).unwrap();
+    assert_eq!(disk.get(b"Cache").unwrap().unwrap().value(), b"Redis");
+    assert_eq!(disk.statistics().wal_syncs, 0);
+    disk.set_with(b"Proxy", b"HAProxy", &synced).unwrap();
+    assert_eq!(disk.statistics().wal_syncs, 1);
+    drop(disk);
+
+    // Only the logged writes are replayed.
+    let disk = Disk::new(&test_dir);
+    assert_eq!(disk.get(b"Server").unwrap().unwrap().value(), b"nginx");
+    assert!(disk.get(b"Cache").unwrap().is_none());
+    assert!(disk.get(b"Queue").unwrap().is_none());
+    assert_eq!(disk.get(b"Proxy").unwrap().unwrap().value(), b"HAProxy");
+
+    // Unlogged writes survive once flushed.
+    disk.set_with(b"Cache", b"Redis", &unlogged).unwrap();
+    disk.flush().unwrap();
+    drop(disk);
+    let disk = Disk::new(&test_dir);
+    assert_eq!(disk.get(b"Cache").unwrap().unwrap().value(), b"Redis");
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_recovery_statistics() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let disk = Disk::new(&test_dir);
+    disk.set(b"Server", b"nginx").unwrap();
+    disk.compact().unwrap();
+    disk.set(b"Database", b"PostgreSQL").unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put(b"Cache", b"Redis");
+    batch.delete(b"Server");
+    disk.write(batch).unwrap();
+    let wal_path = disk.wal_files()[0].clone();
+    drop(disk);
+
+    // A torn record at the end of the WAL.
+    let wal_size = std::fs::metadata(&wal_path).unwrap().len();
+    let mut wal = std::fs::OpenOptions::new().append(true).open(&wal_path).unwrap();
+    wal.write_all(&[1, 0, 0]).unwrap();
+    drop(wal);
+
+    let recovery = Disk::new(&test_dir).statistics().recovery;
+    assert_eq!(recovery.segments_opened, 1);
+    assert!(recovery.segment_bytes > 0);
+    assert_eq!(recovery.wal_files_replayed, 1);
+    assert_eq!(recovery.records_replayed, 3);
+    assert_eq!(recovery.wal_bytes_replayed, wal_size);
+    assert_eq!(recovery.torn_tails, 1);
+    assert!(recovery.duration > Duration::ZERO);
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_wal_mirror_survives_lost_wal() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    let mirror_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+    create_dir_all(&mirror_dir).unwrap();
+    let options = DiskOptions {
+      wal_mirror: Some(WalMirror::new(&mirror_dir)),
+      memtable_max_records: Some(2),
+      ..DiskOptions::default()
+    };
+    let mirrored = || {
+      let paths = find_files_with_extension(Path::new(&mirror_dir), "wal");
+      let mut names: Vec<String> = paths.iter().map(|path| file_name(path)).collect();
+      names.sort();
+      names
+    };
+
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    disk.set(b"Server", b"nginx").unwrap();
+    disk.set(b"Database", b"PostgreSQL").unwrap();
+    disk.wait_for_background_work();
+    disk.set(b"Cache", b"Redis").unwrap();
+    let live: Vec<String> = disk.wal_files().iter().map(|path| file_name(path)).collect();
+    assert_eq!(mirrored(), live);
+    drop(disk);
+
+    // The device holding the WAL loses it.
+    for path in live.iter() {
+      std::fs::remove_file(Path::new(&test_dir).join(path)).unwrap();
+    }
+    let disk = Disk::open(&test_dir, options).unwrap();
+    assert_eq!(disk.get(b"Cache").unwrap().unwrap().value(), b"Redis");
+    assert_eq!(disk.get(b"Server").unwrap().unwrap().value(), b"nginx");
+    let live: Vec<String> = disk.wal_files().iter().map(|path| file_name(path)).collect();
+    assert_eq!(mirrored(), live);
+    drop(disk);
+
+    remove_dir_all(&test_dir).unwrap();
+    remove_dir_all(&mirror_dir).unwrap();
+  }
+
+  #[test]
+  fn test_statistics_counters() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let disk = Disk::open(&test_dir, DiskOptions::default()).unwrap();
+    let wal_size = || std::fs::metadata(&disk.wal_files()[0]).unwrap().len();
+    let empty_wal_size = wal_size();
+    disk.set(b"Server", b"nginx").unwrap();
+    disk.set(b"Database", b"PostgreSQL").unwrap();
+    disk.delete(b"Server").unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put(b"Cache", b"Redis");
+    batch.delete(b"Database");
+    disk.write(batch).unwrap();
+    let stats = disk.statistics();
+    assert_eq!((stats.puts, stats.deletes), (3, 2));
+    assert_eq!(stats.wal_bytes_written, wal_size() - empty_wal_size);
+
+    disk.compact().unwrap();
+    disk.get(b"Cache").unwrap();
+    disk.get(b"Queue").unwrap();
+    disk.multi_get(&[b"Cache", b"Server"]).unwrap();
+    let stats = disk.statistics();
+    assert_eq!(stats.gets, 4);
+    assert_eq!(stats.get_latency_micros.count, 2);
+    assert_eq!(stats.read_amplification.count, 2);
+    assert_eq!(stats.bloom_checks, 2);
+    assert_eq!(stats.bloom_negatives + stats.bloom_false_positives, 1);
+    assert!(stats.compaction_bytes_written > 0);
+    assert!(stats.compaction_bytes_read >= stats.compaction_bytes_written);
+    let stats = disk.stats();
+    assert_eq!(stats.counters, disk.statistics());
+    assert_eq!(stats.tree.levels[0].files, 1);
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_snapshots_survive_flush_and_compaction() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      memtable_size: 1024,
+      block_size: 256,
+      compaction_trigger: 2,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    disk.set(b"Server", b"nginx").unwrap();
+    disk.set(b"Database", b"PostgreSQL").unwrap();
+    let snapshot = disk.snapshot();
+    assert_eq!(snapshot.sequence(), disk.last_sequence());
+
+    disk.set(b"Server", b"apache").unwrap();
+    disk.delete(b"Database").unwrap();
+    disk.set(b"Cache", b"redis").unwrap();
+
+    let check = |snapshot: &Snapshot| {
+      assert_eq!(snapshot.get(b"Server").unwrap().unwrap().value(), b"nginx");
+      assert_eq!(snapshot.get(b"Database").unwrap().unwrap().value(), b"PostgreSQL");
+      assert!(snapshot.get(b"Cache").unwrap().is_none());
+      let keys: Vec<Vec<u8>> = snapshot
+        .scan(..)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.key().to_vec())
+        .collect();
+      assert_eq!(keys, vec![b"Database".to_vec(), b"Server".to_vec()]);
+    };
+    check(&snapshot);
+
+    // Push everything through several flushes and compactions.
+    for i in 0..200 {
+      disk.set(format!("key{:03}", i).as_bytes(), b"filler").unwrap();
+    }
+    disk.wait_for_background_work();
+    assert_eq!(disk.segment_files().len(), 1, "segments should have been compacted");
+    check(&snapshot);
+    assert_eq!(disk.get(b"Server").unwrap().unwrap().value(), b"apache");
+    assert!(disk.get(b"Database").unwrap().is_none());
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_versioned_reads() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      retained_versions: 2,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    let write = |op: &dyn Fn(&mut WriteBatch)| {
+      let mut batch = WriteBatch::new();
+      op(&mut batch);
+      disk.write(batch).unwrap();
+    };
+    write(&|batch| batch.put_at(b"Server", b"nginx", 10));
+    write(&|batch| batch.put_at(b"Server", b"apache", 20));
+    write(&|batch| batch.delete_at(b"Server", 30));
+    write(&|batch| batch.put_at(b"Server", b"caddy", 40));
+    write(&|batch| batch.put_at(b"Cache", b"redis", 15));
+
+    let check = |disk: &Disk| {
+      let history = disk.history(b"Server").unwrap();
+      let versions: Vec<(Option<&[u8]>, u128)> =
+        history.iter().map(|version| (version.value(), version.timestamp())).collect();
+      assert_eq!(
+        versions,
+        vec![(Some(&b"caddy"[..]), 40), (None, 30), (Some(&b"apache"[..]), 20)]
+      );
+      assert!(history[1].is_deleted());
+      assert_eq!(disk.get_at(b"Server", 45).unwrap().unwrap().value(), b"caddy");
+      assert!(disk.get_at(b"Server", 35).unwrap().is_none());
+      assert_eq!(disk.get_at(b"Server", 20).unwrap().unwrap().value(), b"apache");
+      // The oldest version was beyond the retained ones.
+      assert!(disk.get_at(b"Server", 15).unwrap().is_none());
+      assert_eq!(disk.get_at(b"Cache", 15).unwrap().unwrap().value(), b"redis");
+      assert!(disk.history(b"Database").unwrap().is_empty());
+    };
+    check(&disk);
+    disk.compact().unwrap();
+    check(&disk);
+
+    disk.delete_range(b"A", b"Z").unwrap();
+    let history = disk.history(b"Server").unwrap();
+    assert_eq!(history.len(), 4);
+    assert!(history[0].is_deleted());
+    assert_eq!(history[1].value(), Some(&b"caddy"[..]));
+    drop(disk);
+
+    // Without retention only the latest version is kept.
+    let disk = Disk::open(&test_dir, DiskOptions::default()).unwrap();
+    disk.set(b"database", b"PostgreSQL").unwrap();
+    disk.set(b"database", b"MySQL").unwrap();
+    let history = disk.history(b"database").unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].value(), Some(&b"MySQL"[..]));
+    assert!(disk.get_at(b"database", 0).unwrap().is_none());
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_sequence_numbers_persist_across_restarts() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      memtable_size: 1024,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    for i in 0..100 {
+      disk.set(format!("key{:03}", i).as_bytes(), b"nginx").unwrap();
+    }
+    let mut batch = WriteBatch::new();
+    batch.put(b"Server", b"apache");
+    batch.delete(b"key000");
+    assert_eq!(disk.write(batch), Ok(2));
+    assert_eq!(disk.last_sequence(), 102);
+    assert_eq!(disk.get(b"Server").unwrap().unwrap().sequence(), 101);
+    disk.wait_for_background_work();
+    drop(disk);
+
+    let disk = Disk::open(&test_dir, options).unwrap();
+    assert_eq!(disk.last_sequence(), 102);
+    assert_eq!(disk.get(b"key050").unwrap().unwrap().sequence(), 51);
+    disk.set(b"Server", b"nginx").unwrap();
+    assert_eq!(disk.get(b"Server").unwrap().unwrap().sequence(), 103);
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_value_schema_upgrades() {
+    use crate::schema::ValueSchema;
+    use std::sync::atomic::AtomicUsize;
+
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      memtable_size: 1024,
+      compaction_trigger: 2,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    disk.set(b"Server", b"nginx").unwrap();
+    drop(disk);
+
+    let upgrades = Arc::new(AtomicUsize::new(0));
+    let counter = upgrades.clone();
+    let options = DiskOptions {
+      value_schema: Some(ValueSchema::new(2, move |from, value| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Ok([format!("v{}:", from).as_bytes(), value].concat())
+      })),
+      ..options
+    };
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    disk.set(b"Database", b"PostgreSQL").unwrap();
+    assert_eq!(disk.get(b"Server").unwrap().unwrap().value(), b"v0:nginx");
+    assert_eq!(disk.get(b"Database").unwrap().unwrap().value(), b"PostgreSQL");
+    assert_eq!(upgrades.load(Ordering::SeqCst), 1);
+
+    // Compaction stores the upgraded value, so later reads no longer upgrade it.
+    for i in 0..100 {
+      disk.set(format!("key{:03}", i).as_bytes(), b"filler").unwrap();
+    }
+    disk.wait_for_background_work();
+    assert_eq!(disk.segment_files().len(), 1, "segments should have been compacted");
+    let before = upgrades.load(Ordering::SeqCst);
+    let values: Vec<Vec<u8>> = disk
+      .scan(&b"D"[..]..&b"T"[..])
+      .unwrap()
+      .into_iter()
+      .map(|entry| entry.value().to_vec())
+      .collect();
+    assert_eq!(values, vec![b"PostgreSQL".to_vec(), b"v0:nginx".to_vec()]);
+    assert_eq!(upgrades.load(Ordering::SeqCst), before);
+    drop(disk);
+
+    // Values tagged with a newer schema than the one configured can't be read.
+    let downgraded = DiskOptions {
+      value_schema: Some(ValueSchema::new(1, |_, value| Ok(value.to_vec()))),
+      ..options
+    };
+    let disk = Disk::open(&test_dir, downgraded).unwrap();
+    assert!(disk.get(b"Database").is_err());
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_tombstone_retention() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      tombstone_retention: TombstoneRetention::Age(Duration::from_secs(3600)),
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    disk.set(b"Server", b"nginx").unwrap();
+    disk.set(b"Database", b"mysql").unwrap();
+    disk.set(b"Cache", b"redis").unwrap();
+    disk.flush().unwrap();
+    disk.delete(b"Server").unwrap();
+    disk.delete_range(b"C", b"D").unwrap();
+
+    // Too recent to go, the tombstones still hide the versions below them.
+    assert_eq!(disk.purge_tombstones().unwrap(), 0);
+    assert!(disk.get(b"Server").unwrap().is_none());
+    assert!(disk.get(b"Cache").unwrap().is_none());
+    drop(disk);
+
+    // Counted in compactions, they are kept through one and dropped by the next.
+    let options = DiskOptions {
+      tombstone_retention: TombstoneRetention::Compactions(1),
+      ..options
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    assert_eq!(disk.purge_tombstones().unwrap(), 0);
+    assert_eq!(disk.purge_tombstones().unwrap(), 2);
+    assert!(disk.get(b"Server").unwrap().is_none());
+    assert!(disk.get(b"Cache").unwrap().is_none());
+    assert_eq!(disk.get(b"Database").unwrap().unwrap().value(), b"mysql");
+    assert_eq!(disk.statistics().tombstones_purged, 2);
+
+    drop(disk);
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_soft_delete_and_undelete() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      memtable_size: 1024,
+      compaction_trigger: 2,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    disk.set(b"Server", b"nginx").unwrap();
+    disk.set(b"Database", b"PostgreSQL").unwrap();
+    assert_eq!(disk.soft_delete(b"Server"), Ok(1));
+    assert_eq!(disk.soft_delete(b"Missing"), Ok(0));
+    disk.delete(b"Database").unwrap();
+    assert!(disk.get(b"Server").unwrap().is_none());
+    drop(disk);
+
+    // The retained value survives recovery, flushes and compaction within the grace period.
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    for i in 0..100 {
+      disk.set(format!("key{:03}", i).as_bytes(), b"filler").unwrap();
+    }
+    disk.wait_for_background_work();
+    assert!(disk.get(b"Server").unwrap().is_none());
+    assert_eq!(disk.undelete(b"Server"), Ok(1));
+    assert_eq!(disk.get(b"Server").unwrap().unwrap().value(), b"nginx");
+    assert_eq!(disk.undelete(b"Server"), Ok(0));
+    assert_eq!(disk.undelete(b"Database"), Ok(0));
+    drop(disk);
+
+    // Once the grace period has passed, compaction discards the value.
+    let options = DiskOptions {
+      soft_delete_grace: Duration::ZERO,
+      ..options
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    assert_eq!(disk.soft_delete(b"Server"), Ok(1));
+    for i in 0..100 {
+      disk.set(format!("key{:03}", i).as_bytes(), b"filler").unwrap();
+    }
+    disk.wait_for_background_work();
+    assert_eq!(disk.segment_files().len(), 1, "segments should have been compacted");
+    assert_eq!(disk.undelete(b"Server"), Ok(0));
+    assert!(disk.get(b"Server").unwrap().is_none());
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_memtable_max_records() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      memtable_max_records: Some(10),
+      compaction_trigger: 100,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    for i in 0..25 {
+      disk.set(format!("key{:02}", i).as_bytes(), b"nginx").unwrap();
+    }
+    disk.wait_for_background_work();
+    assert_eq!(disk.segment_files().len(), 2);
+    assert_eq!(disk.inner.read_mem_tables().active.record_count(), 5);
+    assert_eq!(disk.get(b"key03").unwrap().unwrap().value(), b"nginx");
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_memtable_max_tombstone_bytes() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      memtable_max_tombstone_bytes: Some(10 * (5 + RECORD_OVERHEAD)),
+      compaction_trigger: 100,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    // Values never reach the limit, however many.
+    for i in 0..25 {
+      disk.set(format!("key{:02}", i).as_bytes(), b"nginx").unwrap();
+    }
+    disk.wait_for_background_work();
+    assert!(disk.segment_files().is_empty());
+    for i in 0..12 {
+      disk.delete(format!("key{:02}", i).as_bytes()).unwrap();
+    }
+    disk.wait_for_background_work();
+    assert_eq!(disk.segment_files().len(), 1);
+    assert!(disk.get(b"key03").unwrap().is_none());
+    let mem_tables = disk.inner.read_mem_tables();
+    assert_eq!(mem_tables.active.tombstone_bytes(), 2 * (5 + RECORD_OVERHEAD));
+    assert_eq!(mem_tables.active.live_bytes(), 0);
+    drop(mem_tables);
+    let stats = disk.tree_stats();
+    assert_eq!(stats.memtable_tombstone_bytes, 2 * (5 + RECORD_OVERHEAD) as u64);
+    assert_eq!(stats.memtable_live_bytes, 0);
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_memtable_max_age() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      memtable_max_age: Some(Duration::from_millis(50)),
+      compaction_trigger: 100,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert!(disk.segment_files().is_empty(), "an empty memtable is never flushed");
+
+    // A single write is flushed once it is old enough, without any further write.
+    disk.set(b"Server", b"nginx").unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while disk.segment_files().is_empty() {
+      assert!(Instant::now() < deadline, "the memtable wasn't flushed");
+      thread::sleep(Duration::from_millis(10));
+    }
+    disk.wait_for_background_work();
+    assert_eq!(disk.inner.read_mem_tables().active.record_count(), 0);
+    assert_eq!(disk.wal_files().len(), 1);
+    disk.set(b"Database", b"PostgreSQL").unwrap();
+    drop(disk);
+
+    // Writes replayed on open count from the open.
+    let disk = Disk::open(&test_dir, options).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while disk.segment_files().len() < 2 {
+      assert!(Instant::now() < deadline, "the replayed memtable wasn't flushed");
+      thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(disk.get(b"Database").unwrap().unwrap().value(), b"PostgreSQL");
+
+    drop(disk);
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_coalesce_tombstones() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      memtable_max_records: Some(20),
+      coalesce_tombstones: Some(8),
+      compaction_trigger: 100,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    for i in 0..19 {
+      disk.set(format!("key{:02}", i).as_bytes(), b"nginx").unwrap();
+    }
+    disk.set(b"key13a", b"nginx").unwrap();
+    disk.wait_for_background_work();
+
+    // Deleting key10 to key18 would also hide key13a, so only the first run is replaced.
+    for i in 0..10 {
+      disk.delete(format!("key{:02}", i).as_bytes()).unwrap();
+    }
+    disk.set(b"key095", b"apache").unwrap();
+    for i in 10..19 {
+      disk.delete(format!("key{:02}", i).as_bytes()).unwrap();
+    }
+    disk.wait_for_background_work();
+    assert_eq!(disk.segment_files().len(), 2);
+    assert_eq!(disk.inner.segments()[0].entry_count(), 10);
+    assert_eq!(disk.statistics().tombstones_coalesced, 10);
+
+    let check = |disk: &Disk| {
+      for i in 0..19 {
+        assert!(disk.get(format!("key{:02}", i).as_bytes()).unwrap().is_none());
+      }
+      let keys: Vec<Vec<u8>> =
+        disk.scan(..).unwrap().into_iter().map(|entry| entry.key().to_vec()).collect();
+      assert_eq!(keys, vec![b"key095".to_vec(), b"key13a".to_vec()]);
+    };
+    check(&disk);
+    let density = disk.tombstone_density(..).unwrap();
+    assert_eq!(
+      density,
+      TombstoneDensity {
+        versions: 30,
+        tombstones: 9,
+        covered: 10,
+        live_keys: 2,
+      }
+    );
+    assert_eq!(density.density(), 19.0 / 30.0);
+    assert_eq!(disk.tombstone_density(&b"key00"[..]..&b"key05"[..]).unwrap().live_keys, 0);
+
+    drop(disk);
+    let disk = Disk::open(&test_dir, options).unwrap();
+    check(&disk);
+    disk.compact().unwrap();
+    check(&disk);
+    assert_eq!(disk.tombstone_density(..).unwrap().density(), 0.0);
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_validators_reject_whole_batches() {
+    let validators = WriteValidators::new()
+      .with(ForbiddenPrefix::new(b"internal/"))
+      .with(MaxValueSize::new(b"", 8));
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+    let options = DiskOptions {
+      validators,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+
+    assert_eq!(disk.set(b"Server", b"nginx"), Ok(1));
+    assert_eq!(disk.set(b"internal/lock", b"1"), Err(0));
+    assert_eq!(disk.set(b"Server", b"apache tomcat"), Err(0));
+    assert_eq!(disk.delete(b"internal/lock"), Err(0));
+
+    let mut batch = WriteBatch::new();
+    batch.put(b"Cache", b"redis");
+    batch.put(b"internal/lock", b"1");
+    let err = disk.validate(&batch).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "write rejected: key \"internal/lock\" has the forbidden prefix \"internal/\""
+    );
+    assert_eq!(disk.write(batch.clone()), Err(0));
+    assert!(disk.get(b"Cache").unwrap().is_none());
+    assert_eq!(disk.rename(b"Server", b"internal/Server"), Err(0));
+    assert_eq!(disk.get(b"Server").unwrap().unwrap().value(), b"nginx");
+
+    let mut transaction = disk.transaction();
+    transaction.set(b"Cache", b"redis");
+    transaction.set(b"Proxy", b"a very long value");
+    assert!(matches!(transaction.commit(), Err(FluxError::Rejected(_))));
+    assert!(disk.get(b"Cache").unwrap().is_none());
+    assert_eq!(disk.last_sequence(), 1);
+
+    // Either bound of a range delete can be rejected.
+    assert_eq!(disk.delete_range(b"internal/a", b"z"), Err(0));
+    assert_eq!(disk.delete_range(b"A", b"internal/z"), Err(0));
+    assert_eq!(disk.delete_range(b"", b"A"), Ok(1));
+    assert_eq!(disk.get(b"Server").unwrap().unwrap().value(), b"nginx");
+
+    drop(disk);
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_key_and_value_size_limits() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+    let options = DiskOptions {
+      max_key_size: 8,
+      max_value_size: 16,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+
+    assert_eq!(disk.set(b"Server", b"nginx"), Ok(1));
+    assert_eq!(disk.set(b"", b"nginx"), Err(0));
+    assert_eq!(disk.set(b"WebServer", b"nginx"), Err(0));
+    assert_eq!(disk.set(b"Server", &[b'a'; 17]), Err(0));
+    assert_eq!(disk.delete(b""), Err(0));
+    assert_eq!(disk.set(b"Proxy", &[b'a'; 16]), Ok(1));
+
+    let mut batch = WriteBatch::new();
+    batch.put(b"Cache", b"redis");
+    batch.delete(b"");
+    let err = disk.validate(&batch).unwrap_err();
+    assert_eq!(err.to_string(), "invalid argument: keys can't be empty");
+    assert_eq!(disk.write(batch), Err(0));
+    assert!(disk.get(b"Cache").unwrap().is_none());
+
+    let mut transaction = disk.transaction();
+    transaction.set(b"Cache", &[b'a'; 32]);
+    let err = transaction.commit().unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "invalid argument: value of 32 bytes for key \"Cache\" exceeds the limit of 16 bytes"
+    );
+    assert_eq!(disk.last_sequence(), 2);
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_increment() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+    let disk = Disk::open(&test_dir, DiskOptions::default()).unwrap();
+
+    assert_eq!(disk.increment(b"hits", 5).unwrap(), 5);
+    assert_eq!(disk.increment(b"hits", -2).unwrap(), 3);
+    let threads: Vec<_> = (0..4)
+      .map(|_| {
+        let disk = disk.clone();
+        thread::spawn(move || {
+          for _ in 0..100 {
+            disk.increment(b"hits", 1).unwrap();
+          }
+        })
+      })
+      .collect();
+    for thread in threads {
+      thread.join().unwrap();
+    }
+    assert_eq!(disk.get(b"hits").unwrap().unwrap().value(), 403i64.to_le_bytes());
+
+    disk.set(b"Server", b"nginx").unwrap();
+    assert!(matches!(disk.increment(b"Server", 1), Err(FluxError::InvalidArgument(_))));
+    disk.set(b"max", &i64::MAX.to_le_bytes()).unwrap();
+    assert!(matches!(disk.increment(b"max", 1), Err(FluxError::InvalidArgument(_))));
+    drop(disk);
+
+    // Counters are logged like any write.
+    let disk = Disk::open(&test_dir, DiskOptions::default()).unwrap();
+    assert_eq!(disk.increment(b"hits", 0).unwrap(), 403);
+
+    drop(disk);
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_scan_keys() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+    let options = DiskOptions {
+      value_log: Some(ValueLogOptions {
+        min_value_size: 8,
+        ..ValueLogOptions::default()
+      }),
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    disk.set(b"Cache", b"redis cluster").unwrap();
+    disk.set(b"Database", b"postgres primary").unwrap();
+    disk.set(b"Queue", b"rabbitmq broker").unwrap();
+    disk.set(b"Server", b"nginx frontend").unwrap();
+    disk.flush().unwrap();
+    disk.delete(b"Database").unwrap();
+    disk.delete_range(b"Q", b"R").unwrap();
+    disk.set(b"Auth", b"keycloak").unwrap();
+
+    // Emptying the value log breaks reads of the values, but not of the keys.
+    for path in find_files_with_extension(test_dir.as_ref(), "vlog") {
+      File::options().write(true).open(path).unwrap().set_len(0).unwrap();
+    }
+    assert!(disk.scan(..).is_err());
+    let keys = disk.scan_keys(..).unwrap();
+    assert_eq!(keys, [&b"Auth"[..], b"Cache", b"Server"]);
+    assert_eq!(disk.scan_keys(&b"B"[..]..&b"R"[..]).unwrap(), [b"Cache"]);
+
+    let snapshot = disk.snapshot();
+    disk.delete(b"Cache").unwrap();
+    assert_eq!(disk.scan_keys(..).unwrap(), [&b"Auth"[..], b"Server"]);
+    assert_eq!(snapshot.scan_keys(..).unwrap(), keys);
+
+    drop((snapshot, disk));
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_scan_with_filters() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+    let options = DiskOptions {
+      value_log: Some(ValueLogOptions {
+        min_value_size: 8,
+        ..ValueLogOptions::default()
+      }),
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    for i in 0..10 {
+      let role = if i % 3 == 0 { "admin" } else { "viewer" };
+      let value = format!("{} of team {}", role, i);
+      disk.set(format!("user/{}", i).as_bytes(), value.as_bytes()).unwrap();
+    }
+    disk.compact().unwrap();
+    // Newer versions and deletes in the memtable hide the segment ones.
+    disk.set(b"user/4", b"admin of team 4").unwrap();
+    disk.set(b"user/6", b"viewer of team 6").unwrap();
+    disk.delete(b"user/0").unwrap();
+    let snapshot = disk.snapshot();
+    disk.set(b"user/8", b"admin of team 8").unwrap();
+
+    let even: ScanFilter = Arc::new(|key: &[u8]| key.last().is_some_and(|digit| digit % 2 == 0));
+    let admin: ScanFilter = Arc::new(|value: &[u8]| value.starts_with(b"admin"));
+    let keys = |entries: Vec<DiskEntry>| -> Vec<String> {
+      entries.iter().map(|entry| String::from_utf8_lossy(entry.key()).into_owned()).collect()
+    };
+    let mut options = ScanOptions {
+      key_filter: Some(even.clone()),
+      ..ScanOptions::default()
+    };
+    assert_eq!(
+      keys(disk.scan_with(.., &options).unwrap()),
+      ["user/2", "user/4", "user/6", "user/8"]
+    );
+    options.value_filter = Some(admin);
+    assert_eq!(keys(disk.scan_with(.., &options).unwrap()), ["user/4", "user/8"]);
+    assert_eq!(keys(snapshot.scan_with(.., &options).unwrap()), ["user/4"]);
+    options.key_filter = None;
+    options.reverse = true;
+    options.limit = 3;
+    assert_eq!(keys(disk.scan_with(.., &options).unwrap()), ["user/9", "user/8", "user/4"]);
+    assert_eq!(
+      keys(disk.scan_with(&b"user/1"[..]..&b"user/7"[..], &options).unwrap()),
+      ["user/4", "user/3"]
+    );
+
+    drop(snapshot);
+    drop(disk);
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_scan_copies_mem_tables_in_chunks() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+    let disk = Disk::new(&test_dir);
+    let count = 3 * MEM_TABLE_SCAN_KEYS + 10;
+    for i in 0..count {
+      let value = if i % 100 == 0 { "admin" } else { "viewer" };
+      disk.set(format!("user/{:04}", i).as_bytes(), value.as_bytes()).unwrap();
+    }
+    disk.delete(b"user/0300").unwrap();
+
+    let keys = |entries: Vec<DiskEntry>| -> Vec<String> {
+      entries.iter().map(|entry| String::from_utf8_lossy(entry.key()).into_owned()).collect()
+    };
+    assert_eq!(disk.scan(..).unwrap().len(), count - 1);
+    // Pages that the first keys copied don't fill read on past them.
+    let admin: ScanFilter = Arc::new(|value: &[u8]| value.starts_with(b"admin"));
+    let mut options = ScanOptions {
+      value_filter: Some(admin),
+      ..ScanOptions::default()
+    };
+    assert_eq!(
+      keys(disk.scan_with(.., &options).unwrap()),
+      ["user/0000", "user/0100", "user/0200", "user/0400", "user/0500", "user/0600", "user/0700"]
+    );
+    options.reverse = true;
+    options.limit = 4;
+    assert_eq!(
+      keys(disk.scan_with(.., &options).unwrap()),
+      ["user/0700", "user/0600", "user/0500", "user/0400"]
+    );
+
+    drop(disk);
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_scan_page_with_continuation() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+    let disk = Disk::open(&test_dir, DiskOptions::default()).unwrap();
+    for i in 0..25 {
+      disk.set(format!("key{:02}", i).as_bytes(), b"nginx").unwrap();
+    }
+    disk.set(b"other", b"nginx").unwrap();
+
+    let range = &b"key"[..]..&b"key99"[..];
+    let (page, token) = disk.scan_page(range.clone(), 10, None).unwrap();
+    assert_eq!((page.len(), page[9].key()), (10, &b"key09"[..]));
+    // Pages read the latest writes.
+    disk.delete(b"key10").unwrap();
+    let (page, token) = disk.scan_page(range.clone(), 10, token).unwrap();
+    assert_eq!((page[0].key(), page[9].key()), (&b"key11"[..], &b"key20"[..]));
+    let (page, token) = disk.scan_page(range.clone(), 10, token).unwrap();
+    assert_eq!((page.len(), token), (4, None));
+    // A page ending with the range has no token.
+    let (page, token) = disk.scan_page(range.clone(), 24, None).unwrap();
+    assert_eq!((page.len(), token), (24, None));
+
+    // A token from before the range starts the page at the range.
+    let token = Some(encode_continuation(b"a"));
+    let (page, _) = disk.scan_page(range.clone(), 1, token).unwrap();
+    assert_eq!(page[0].key(), b"key00");
+
+    assert!(matches!(
+      disk.scan_page(range.clone(), 10, Some(b"garbage".to_vec())),
+      Err(FluxError::InvalidArgument(_))
+    ));
+    assert!(matches!(disk.scan_page(range, 0, None), Err(FluxError::InvalidArgument(_))));
+
+    drop(disk);
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_size_and_key_estimates() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+    let disk = Disk::open(&test_dir, DiskOptions::default()).unwrap();
+    for i in 0..1000 {
+      disk.set(format!("key{:04}", i).as_bytes(), &[b'a'; 100]).unwrap();
+    }
+    disk.compact().unwrap();
+    for i in 0..10 {
+      disk.set(format!("new{}", i).as_bytes(), &[b'a'; 100]).unwrap();
+    }
+
+    assert_eq!(disk.estimate_num_keys(), 1010);
+    let file_size = disk.segment_files()[0].metadata().unwrap().len();
+    let total = disk.approximate_size(..);
+    assert!(total > 1010 * 100 && total <= file_size + 10 * 104);
+    // Half the keys take about half the space.
+    let half = disk.approximate_size(&b"key0000"[..]..&b"key0500"[..]);
+    assert!(half > total * 2 / 5 && half < total * 3 / 5);
+    assert_eq!(disk.approximate_size(&b"new"[..]..&b"new9~"[..]), 10 * 104);
+
+    drop(disk);
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_live_files() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+    let options = DiskOptions {
+      memtable_size: 1024,
+      compaction_trigger: 100,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    for i in 0..100 {
+      disk.set(format!("key{:03}", i).as_bytes(), b"nginx").unwrap();
+    }
+    disk.wait_for_background_work();
+
+    // Keys were written in order, so each flushed segment starts after the one before.
+    let files = disk.live_files().unwrap();
+    assert!(files.len() > 1);
+    let paths: Vec<PathBuf> = files.iter().map(|file| file.path.clone()).collect();
+    assert_eq!(paths, disk.segment_files());
+    for pair in files.windows(2) {
+      assert!(pair[0].largest_key < pair[1].smallest_key);
+    }
+    assert_eq!(files[0].smallest_key.as_deref(), Some(&b"key000"[..]));
+    for file in files.iter() {
+      assert_eq!(file.level, 0);
+      assert_eq!(file.size, file.path.metadata().unwrap().len());
+      assert!(file.created.is_some());
+    }
+
+    disk.compact().unwrap();
+    let files = disk.live_files().unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].largest_key.as_deref(), Some(&b"key099"[..]));
+    assert_eq!(files[0].entry_count, 100);
+
+    drop(disk);
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_scan_prefix_skips_segments() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+    let extractor = PrefixExtractor::delimited(b'/');
+    let options = DiskOptions {
+      memtable_size: 1024,
+      compaction_trigger: 100,
+      prefix_extractor: Some(extractor.clone()),
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    for tenant in ["acme", "beta"] {
+      for i in 0..50 {
+        disk.set(format!("{}/user/{:02}", tenant, i).as_bytes(), b"nginx").unwrap();
+      }
+      disk.wait_for_background_work();
+    }
+    // The range tombstone lands in a segment holding only keys of the other tenant.
+    disk.delete_range(b"acme/user/10", b"acme/user/20").unwrap();
+    for i in 50..100 {
+      disk.set(format!("beta/user/{:02}", i).as_bytes(), b"nginx").unwrap();
+    }
+    disk.wait_for_background_work();
+
+    let segments = disk.inner.segments();
+    let skipped = segments
+      .iter()
+      .filter(|segment| !segment.may_contain_prefix(b"acme/", &extractor))
+      .count();
+    assert!(skipped > segments.len() / 2);
+    let entries = disk.scan_prefix(b"acme/").unwrap();
+    assert_eq!(entries.len(), 40);
+    assert_eq!(entries[10].key(), b"acme/user/20");
+    assert!(disk.scan_prefix(b"gamma/").unwrap().is_empty());
+    assert_eq!(disk.scan_prefix(b"beta/user/9").unwrap().len(), 10);
+    assert_eq!(disk.snapshot().scan_prefix(b"beta/").unwrap().len(), 100);
+
+    drop(disk);
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_directory_lock() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+    let disk = Disk::open(&test_dir, DiskOptions::default()).unwrap();
+    disk.set(b"Server", b"nginx").unwrap();
+
+    // A second handle would remove the WAL file the first one writes to.
+    let err = Disk::open(&test_dir, DiskOptions::default()).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    assert!(err.to_string().contains("the database is already open"));
+    drop(disk);
+    let disk = Disk::open(&test_dir, DiskOptions::default()).unwrap();
+    assert_eq!(disk.get(b"Server").unwrap().unwrap().value(), b"nginx");
+
+    drop(disk);
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_unexpected_files_quarantined() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    let dir = Path::new(&test_dir);
+    create_dir_all(&test_dir).unwrap();
+
+    // Before there is a manifest, WAL files the engine didn't name are ignored.
+    std::fs::write(dir.join("notes.wal"), b"not a log").unwrap();
+    std::fs::write(dir.join("scratch"), b"no extension").unwrap();
+    let disk = Disk::open(&test_dir, DiskOptions::default()).unwrap();
+    disk.set(b"Server", b"nginx").unwrap();
+    drop(disk);
+
+    std::fs::write(dir.join("000099.sst"), b"half written").unwrap();
+    let disk = Disk::open(&test_dir, DiskOptions::default()).unwrap();
+    assert_eq!(disk.get(b"Server").unwrap().unwrap().value(), b"nginx");
+    assert!(!dir.join("000099.sst").exists());
+    assert!(!dir.join("notes.wal").exists());
+    assert_eq!(std::fs::read(dir.join("notes.wal.corrupt")).unwrap(), b"not a log");
+    assert!(dir.join("scratch").exists());
+
+    drop(disk);
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_max_total_wal_bytes_forces_flush() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      max_total_wal_bytes: Some(4096),
+      compaction_trigger: 100,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    for i in 0..500 {
+      disk.set(format!("key{:03}", i).as_bytes(), b"nginx").unwrap();
+    }
+    disk.wait_for_background_work();
+
+    // The memtable never fills up, but the log was retired through flushes.
+    assert!(!disk.segment_files().is_empty(), "memtables should have been flushed");
+    let wal_bytes: u64 = disk
+      .wal_files()
+      .iter()
+      .map(|path| std::fs::metadata(path).unwrap().len())
+      .sum();
+    assert!(wal_bytes < 4096, "live WAL files hold {} bytes", wal_bytes);
+    drop(disk);
+
+    let disk = Disk::open(&test_dir, options).unwrap();
+    for i in 0..500 {
+      assert!(disk.get(format!("key{:03}", i).as_bytes()).unwrap().is_some());
+    }
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_multi_get() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      memtable_size: 1024,
+      block_size: 256,
+      compaction_trigger: 100,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    for i in 0..200 {
+      disk.set(format!("key{:03}", i).as_bytes(), format!("v{}", i).as_bytes()).unwrap();
+    }
+    for i in (0..200).step_by(3) {
+      disk.delete(format!("key{:03}", i).as_bytes()).unwrap();
+    }
+    let snapshot = disk.snapshot();
+    for i in (0..200).step_by(5) {
+      disk.set(format!("key{:03}", i).as_bytes(), b"updated").unwrap();
+    }
+    disk.wait_for_background_work();
+    assert!(disk.segment_files().len() > 1, "memtables should have been flushed");
+
+    // Unsorted, with duplicates and keys that were never written.
+    let keys: Vec<String> = (0..250)
+      .rev()
+      .chain([7, 7, 120])
+      .map(|i| format!("key{:03}", i))
+      .collect();
+    let keys: Vec<&[u8]> = keys.iter().map(|key| key.as_bytes()).collect();
+    let found = disk.multi_get(&keys).unwrap();
+    assert_eq!(found.len(), keys.len());
+    let value = |entry: Option<DiskEntry>| entry.map(|entry| entry.value().to_vec());
+    for (key, entry) in keys.iter().zip(found) {
+      assert_eq!(value(entry), value(disk.get(key).unwrap()), "{:?}", key);
+    }
+    for (key, entry) in keys.iter().zip(snapshot.multi_get(&keys).unwrap()) {
+      assert_eq!(value(entry), value(snapshot.get(key).unwrap()), "{:?}", key);
+    }
+    assert!(disk.multi_get(&[]).unwrap().is_empty());
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_read_memory_limit() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      memtable_size: 4096,
+      read_memory_limit: Some(16 * 1024),
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    let value = [7u8; 256];
+    for i in 0..200 {
+      disk.set(format!("key{:03}", i).as_bytes(), &value).unwrap();
+    }
+    disk.wait_for_background_work();
+
+    assert_eq!(disk.scan(&b"key000"[..]..&b"key010"[..]).unwrap().len(), 10);
+    let err = disk.scan(..).unwrap_err();
+    assert!(matches!(err, FluxError::MemoryLimit { limit: 16384 }), "{}", err);
+
+    let keys: Vec<String> = (0..200).map(|i| format!("key{:03}", i)).collect();
+    let keys: Vec<&[u8]> = keys.iter().map(|key| key.as_bytes()).collect();
+    assert_eq!(disk.multi_get(&keys[..10]).unwrap().len(), 10);
+    assert!(matches!(disk.multi_get(&keys), Err(FluxError::MemoryLimit { .. })));
+    // Keys that aren't found take no memory.
+    let missing: Vec<&[u8]> = vec![b"missing"; 1000];
+    assert!(disk.multi_get(&missing).unwrap().iter().all(Option::is_none));
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_delete_range() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      memtable_size: 1024,
+      compaction_trigger: 100,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    for i in 0..100 {
+      disk.set(format!("key{:02}", i).as_bytes(), b"nginx").unwrap();
+    }
+    let before = disk.snapshot();
+    assert_eq!(disk.delete_range(b"key20", b"key40").unwrap(), 1);
+    assert_eq!(disk.delete_range(b"key50", b"key50").unwrap(), 0);
+    disk.set(b"key25", b"apache").unwrap();
+
+    let check = |disk: &Disk| {
+      for i in 0..100 {
+        let entry = disk.get(format!("key{:02}", i).as_bytes()).unwrap();
+        match i {
+          25 => assert_eq!(entry.unwrap().value(), b"apache"),
+          20..=39 => assert!(entry.is_none(), "key{:02}", i),
+          _ => assert_eq!(entry.unwrap().value(), b"nginx"),
+        }
+      }
+      let keys: Vec<Vec<u8>> = disk
+        .scan(&b"key18"[..]..&b"key42"[..])
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.key().to_vec())
+        .collect();
+      assert_eq!(keys, vec![&b"key18"[..], b"key19", b"key25", b"key40", b"key41"]);
+      let found = disk.multi_get(&[b"key30", b"key25", b"key19"]).unwrap();
+      let found: Vec<bool> = found.iter().map(Option::is_some).collect();
+      assert_eq!(found, vec![false, true, true]);
+    };
+    check(&disk);
+    assert_eq!(before.get(b"key30").unwrap().unwrap().value(), b"nginx");
+    assert_eq!(before.scan(..).unwrap().len(), 100);
+    drop(before);
+
+    // Once flushed the tombstone still applies, and survives recovery.
+    disk.set(b"filler", &[0; 1024]).unwrap();
+    disk.wait_for_background_work();
+    assert!(!disk.segment_files().is_empty(), "memtable should have been flushed");
+    check(&disk);
+    drop(disk);
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    check(&disk);
+    drop(disk);
+
+    // Compaction drops the covered versions, and the tombstone with them.
+    let options = DiskOptions {
+      compaction_trigger: 1,
+      ..options
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    disk.set(b"filler", &[1; 1024]).unwrap();
+    disk.wait_for_background_work();
+    check(&disk);
+    let segments = disk.segment_files();
+    assert_eq!(segments.len(), 1);
+    let segment = SSTable::open(&segments[0]).unwrap();
+    assert!(segment.range_tombstones().is_empty());
+    assert_eq!(segment.entry_count(), 82);
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  /// Keeps the messages logged, as `level subsystem message` lines.
+  struct Collect(Arc<Mutex<Vec<String>>>);
+
+  impl LogSink for Collect {
+    fn log(&self, level: Level, subsystem: Subsystem, message: &std::fmt::Arguments<'_>) {
+      let line = format!("{} {} {}", level.name(), subsystem.name(), message);
+      self.0.lock().unwrap().push(line);
+    }
+  }
+
+  #[test]
+  fn test_recovery_anomalies_are_logged() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let disk = Disk::new(&test_dir);
+    disk.set(b"Server", b"nginx").unwrap();
+    disk.set(b"Database", b"PostgreSQL").unwrap();
+    let wal_path = disk.wal_files().pop().unwrap();
+    drop(disk);
+    // Lose the tail of the last record, as if the process crashed while writing it.
+    let file = std::fs::OpenOptions::new().write(true).open(&wal_path).unwrap();
+    file.set_len(file.metadata().unwrap().len() - 5).unwrap();
+
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let options = DiskOptions {
+      logger: Logger::new(Collect(lines.clone())).with_level(Subsystem::Flush, Some(Level::Debug)),
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    assert!(disk.get(b"Server").unwrap().is_some());
+    assert!(disk.get(b"Database").unwrap().is_none());
+    drop(disk);
+
+    let lines = lines.lock().unwrap();
+    assert_eq!(lines.len(), 1, "{:?}", lines);
+    assert!(lines[0].starts_with("warn recovery ignored "), "{}", lines[0]);
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_slow_operations_are_logged() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let options = DiskOptions {
+      logger: Logger::new(Collect(lines.clone())),
+      slow_operation_threshold: Duration::ZERO,
+      sync_writes: true,
+      write_stall: WriteStall {
+        slowdown_segments: Some(0),
+        slowdown_delay: Duration::ZERO,
+        ..WriteStall::disabled()
+      },
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    disk.set(b"Server", b"nginx").unwrap();
+    disk.flush().unwrap();
+    disk.set(b"Database", b"PostgreSQL").unwrap();
+    disk.flush().unwrap();
+    disk.compact_range(b"A", b"Z").unwrap();
+    drop(disk);
+
+    let lines = lines.lock().unwrap();
+    for prefix in [
+      "warn recovery opened with 0 records replayed",
+      "warn flush held a write back for ",
+      "warn wal syncing the WAL took ",
+      "warn flush flushed a memtable to ",
+      "warn compaction compacted 2 segments into 2 entries in ",
+    ] {
+      assert!(lines.iter().any(|line| line.starts_with(prefix)), "{}: {:?}", prefix, lines);
+    }
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_latency_histograms() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let options = DiskOptions {
+      logger: Logger::new(Collect(lines.clone())),
+      slow_get_threshold: Some(Duration::ZERO),
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    disk.set(b"Server", b"nginx").unwrap();
+    disk.set(b"Database", b"PostgreSQL").unwrap();
+    disk.delete(b"Database").unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put(b"Cache", b"redis");
+    disk.write(batch).unwrap();
+    disk.get(b"Server").unwrap();
+
+    let latencies = disk.latency_histograms();
+    assert_eq!(latencies.get.count, 1);
+    assert_eq!(latencies.set.count, 2);
+    assert_eq!(latencies.delete.count, 1);
+    assert_eq!(latencies.write.count, 1);
+    assert!(latencies.set.percentile(99.0) >= latencies.set.percentile(50.0));
+    // Only gets have a threshold.
+    let lines = lines.lock().unwrap();
+    assert_eq!(lines.len(), 1, "{:?}", lines);
+    let warning = "warn operations get of key 53657276.. (6 bytes) took ";
+    assert!(lines[0].starts_with(warning), "{}", lines[0]);
+    assert_eq!(redact_key(b"Tor"), "key 546f72 (3 bytes)");
+    drop(disk);
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_wal_archive() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      wal_archive: Some(WalArchive::default()),
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    disk.set(b"Server", b"nginx").unwrap();
+    disk.delete(b"Server").unwrap();
+    disk.flush().unwrap();
+
+    let archived = disk.archived_wal_files().unwrap();
+    assert!(!archived.is_empty());
+    assert!(archived.iter().all(|path| path.starts_with(Path::new(&test_dir).join("archive"))));
+    assert!(disk.wal_files().iter().all(|path| !archived.contains(path)));
+    let records: Vec<_> = archived
+      .iter()
+      .flat_map(|path| LogFileIterator::from_path(path.clone()).unwrap())
+      .collect();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].identifier, b"Server");
+    assert!(records[1].is_removed);
+    drop(disk);
+
+    // Reopening archives the files it replayed, then prunes the archive to fit.
+    let options = DiskOptions {
+      wal_archive: Some(WalArchive {
+        max_bytes: Some(0),
+        ..WalArchive::default()
+      }),
+      ..options
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    assert!(disk.archived_wal_files().unwrap().is_empty());
+    drop(disk);
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_export_and_import() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    let (src_dir, dst_dir) = (format!("{}src", test_dir), format!("{}dst", test_dir));
+    create_dir_all(&src_dir).unwrap();
+    create_dir_all(&dst_dir).unwrap();
+
+    let options = DiskOptions {
+      memtable_size: 1024,
+      ..DiskOptions::default()
+    };
+    let dst = Disk::open(&dst_dir, options.clone()).unwrap();
+    dst.set(b"key0042", b"apache").unwrap();
+    dst.set(b"key9999", b"apache").unwrap();
+    let src = Disk::open(&src_dir, options).unwrap();
+    for i in 0..3000 {
+      src.set(format!("key{:04}", i).as_bytes(), b"nginx").unwrap();
+    }
+    src.delete(b"key0042").unwrap();
+    src.delete_range(b"key2990", b"key2995").unwrap();
+    let mut dump = Vec::new();
+    assert_eq!(src.export_to(&mut dump).unwrap(), 3000);
+
+    // Deleted keys travel as tombstones, while keys the dump doesn't hold are left alone.
+    assert_eq!(dst.import_from(&dump[..]).unwrap(), 3000);
+    assert!(dst.get(b"key0042").unwrap().is_none());
+    assert!(dst.get(b"key2992").unwrap().is_none());
+    assert_eq!(dst.get(b"key9999").unwrap().unwrap().value(), b"apache");
+    for i in [0, 41, 1500, 2999] {
+      let key = format!("key{:04}", i);
+      let expected = src.get(key.as_bytes()).unwrap().unwrap();
+      let imported = dst.get(key.as_bytes()).unwrap().unwrap();
+      assert_eq!(imported.value(), expected.value());
+      assert_eq!(imported.timestamp(), expected.timestamp());
+    }
+
+    // Records are committed in batches within the size limit, and records older than what
+    // a key holds fail the import.
+    let mut writer = DumpWriter::new(Vec::new()).unwrap();
+    for key in [&b"key9999"[..], b"large1", b"large2", b"large3"] {
+      writer
+        .write_record(&DumpRecord {
+          key: key.to_vec(),
+          value: (key != b"key9999").then(|| vec![7; MAX_BATCH_BYTES / 2]),
+          timestamp: Clock::system().now_micros(),
+        })
+        .unwrap();
+    }
+    assert_eq!(dst.import_from(&writer.finish().unwrap()[..]).unwrap(), 4);
+    assert!(dst.get(b"key9999").unwrap().is_none());
+    assert_eq!(dst.get(b"large3").unwrap().unwrap().value().len(), MAX_BATCH_BYTES / 2);
+    dst.set(b"key0001", b"caddy").unwrap();
+    assert!(dst.import_from(&dump[..]).is_err());
+    assert!(dst.import_from(&b"not a dump"[..]).is_err());
+    drop(src);
+    drop(dst);
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_checkpoint() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    let (db_dir, checkpoint_dir) = (format!("{}db", test_dir), format!("{}checkpoint", test_dir));
+    create_dir_all(&db_dir).unwrap();
+
+    let options = DiskOptions {
+      memtable_size: 1024,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&db_dir, options.clone()).unwrap();
+    for i in 0..100 {
+      disk.set(format!("key{:02}", i).as_bytes(), b"nginx").unwrap();
+    }
+    disk.wait_for_background_work();
+    disk.set(b"key00", b"apache").unwrap();
+
+    let sequence = disk.checkpoint(&checkpoint_dir).unwrap();
+    assert_eq!(sequence, disk.last_sequence());
+    let err = disk.checkpoint(&checkpoint_dir).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+    // Writes after the checkpoint don't reach it, and its writes don't reach the database.
+    disk.set(b"key01", b"caddy").unwrap();
+    let checkpoint = Disk::open(&checkpoint_dir, options).unwrap();
+    assert_eq!(checkpoint.last_sequence(), sequence);
+    assert_eq!(checkpoint.get(b"key00").unwrap().unwrap().value(), b"apache");
+    assert_eq!(checkpoint.get(b"key01").unwrap().unwrap().value(), b"nginx");
+    assert_eq!(checkpoint.get(b"key99").unwrap().unwrap().value(), b"nginx");
+    checkpoint.delete(b"key02").unwrap();
+    assert_eq!(disk.get(b"key02").unwrap().unwrap().value(), b"nginx");
+    drop(checkpoint);
+    drop(disk);
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_backup_and_restore() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    let (db_dir, backup_dir) = (format!("{}db", test_dir), format!("{}backup", test_dir));
+    create_dir_all(&db_dir).unwrap();
+
+    let options = DiskOptions {
+      memtable_size: 1024,
+      compaction_trigger: 100,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&db_dir, options.clone()).unwrap();
+    for i in 0..100 {
+      disk.set(format!("key{:02}", i).as_bytes(), b"nginx").unwrap();
+    }
+    disk.wait_for_background_work();
+    let segments = disk.segment_files().len();
+    assert!(segments > 1, "memtables should have been flushed");
+
+    let first = disk.create_backup(&backup_dir).unwrap();
+    assert_eq!((first.segments_added, first.segments_reused), (segments, 0));
+    assert!(first.wal_bytes > 0);
+    assert_eq!(first.last_sequence, disk.last_sequence());
+
+    // A second backup only adds the segments written since.
+    for i in 0..50 {
+      disk.set(format!("key{:02}", i).as_bytes(), b"apache").unwrap();
+    }
+    disk.delete(b"key99").unwrap();
+    disk.wait_for_background_work();
+    let second = disk.create_backup(&backup_dir).unwrap();
+    assert_eq!(second.segments_reused, segments);
+    assert_eq!(second.segments_added, disk.segment_files().len() - segments);
+    disk.set(b"key00", b"caddy").unwrap();
+
+    let restored_dir = format!("{}restored", test_dir);
+    Disk::restore_from_backup(&backup_dir, &restored_dir).unwrap();
+    assert!(Disk::restore_from_backup(&backup_dir, &restored_dir).is_err());
+    let restored = Disk::open(&restored_dir, options).unwrap();
+    assert_eq!(restored.last_sequence(), second.last_sequence);
+    for i in 0..100 {
+      let entry = restored.get(format!("key{:02}", i).as_bytes()).unwrap();
+      match i {
+        0..=49 => assert_eq!(entry.unwrap().value(), b"apache"),
+        99 => assert!(entry.is_none()),
+        _ => assert_eq!(entry.unwrap().value(), b"nginx"),
+      }
+    }
+    drop(restored);
+    drop(disk);
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_scrub() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+    let scrub = ScrubOptions {
+      bytes_per_second: 1024 * 1024,
+      interval: Duration::from_millis(10),
+    };
+    let options = DiskOptions {
+      block_size: 64,
+      compaction_trigger: 100,
+      scrub: Some(scrub),
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    for i in 0..50 {
+      disk.set(format!("key{:02}", i).as_bytes(), b"nginx").unwrap();
+    }
+    disk.compact().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while disk.last_scrub().is_none_or(|report| report.segments == 0) {
+      assert!(Instant::now() < deadline, "the scrubber didn't get to the segment");
+      thread::sleep(Duration::from_millis(10));
+    }
+    let report = disk.last_scrub().unwrap();
+    assert!(report.blocks > 1);
+    assert!(report.corrupt_blocks.is_empty());
+    let path = disk.segment_files().pop().unwrap();
+    drop(disk);
+
+    // Flip a byte of the first data block, right after the header.
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[10] ^= 0xff;
+    std::fs::write(&path, &bytes).unwrap();
+    let options = DiskOptions {
+      scrub: None,
+      ..options
+    };
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    assert!(disk.last_scrub().is_none());
+    let report = disk.scrub().unwrap();
+    assert_eq!(report.blocks, disk.inner.segments()[0].block_count() as u64);
+    assert_eq!(report.corrupt_blocks.len(), 1);
+    let corrupt = &report.corrupt_blocks[0];
+    assert_eq!((corrupt.file.as_str(), corrupt.block), (file_name(&path).as_str(), 0));
+    let quarantined = corrupt.quarantined.clone().unwrap();
+    assert_eq!(std::fs::read(&quarantined).unwrap()[4], bytes[10]);
+    assert_eq!(disk.last_scrub(), Some(report));
+    // The other blocks are still served.
+    assert!(disk.get(b"key00").is_err());
+    assert_eq!(disk.get(b"key49").unwrap().unwrap().value(), b"nginx");
+    drop(disk);
+
+    // The copy is kept across opens.
+    drop(Disk::open(&test_dir, options).unwrap());
+    assert!(quarantined.exists());
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_memory_storage() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+
+    let storage = Storage::new(MemoryBackend::new());
+    let options = DiskOptions {
+      memtable_size: 256,
+      storage: storage.clone(),
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    for i in 0..50 {
+      disk.set(format!("key{:02}", i).as_bytes(), b"nginx").unwrap();
+    }
+    disk.delete(b"key07").unwrap();
+    disk.compact().unwrap();
+    disk.set(b"key49", b"apache").unwrap();
+    assert!(!disk.segment_files().is_empty());
+    drop(disk);
+
+    // Nothing reached the file system, yet reopening on the same storage finds every write.
+    assert!(!Path::new(&test_dir).exists());
+    assert!(storage.exists(&Path::new(&test_dir).join(MANIFEST_FILE)));
+    let disk = Disk::open(&test_dir, options).unwrap();
+    for i in 0..50 {
+      let entry = disk.get(format!("key{:02}", i).as_bytes()).unwrap();
+      match i {
+        7 => assert!(entry.is_none()),
+        49 => assert_eq!(entry.unwrap().value(), b"apache"),
+        _ => assert_eq!(entry.unwrap().value(), b"nginx"),
+      }
+    }
+  }
+
+  #[test]
+  fn test_invalidation_feed() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let disk = Disk::new(&test_dir);
+    disk.set(b"key0", b"nginx").unwrap();
+    let feed = disk.subscribe_invalidations(Granularity::Key, Duration::ZERO);
+    disk.set(b"key1", b"nginx").unwrap();
+    disk.set(b"key1", b"apache").unwrap();
+    disk.delete(b"key2").unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put(b"key3", b"caddy");
+    batch.delete(b"key1");
+    disk.write(batch).unwrap();
+    disk.delete_range(b"a", b"b").unwrap();
+
+    let batch = feed.recv().unwrap();
+    assert_eq!(batch.sequence, disk.last_sequence());
+    assert_eq!(
+      batch.invalidations,
+      vec![
+        Invalidation::Key(b"key1".to_vec()),
+        Invalidation::Key(b"key2".to_vec()),
+        Invalidation::Key(b"key3".to_vec()),
+        Invalidation::Range {
+          start: b"a".to_vec(),
+          end: b"b".to_vec(),
+        },
+      ]
+    );
+    assert_eq!(feed.try_recv(), None);
+
+    drop(disk);
+    assert_eq!(feed.recv(), None);
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_open_in_memory() {
+    let disk = Disk::open_in_memory().unwrap();
+    let other = Disk::open_in_memory().unwrap();
+    for i in 0..100 {
+      disk.set(format!("key{:02}", i).as_bytes(), b"nginx").unwrap();
+    }
+    disk.delete(b"key42").unwrap();
+    disk.compact().unwrap();
+
+    assert!(!Path::new(IN_MEMORY_DIR).exists());
+    assert_eq!(disk.get(b"key07").unwrap().unwrap().value(), b"nginx");
+    assert!(disk.get(b"key42").unwrap().is_none());
+    assert_eq!(disk.scan(..).unwrap().len(), 99);
+    assert!(other.get(b"key07").unwrap().is_none());
+    assert_eq!(
+      disk.checkpoint("./unused").unwrap_err().kind(),
+      io::ErrorKind::Unsupported
+    );
+  }
+
+  #[test]
+  fn test_shared_block_cache() {
+    let cache = BlockCache::new(1024 * 1024);
+    let options = DiskOptions {
+      block_cache: Some(cache.clone()),
+      storage: Storage::new(MemoryBackend::new()),
+      ..DiskOptions::default()
+    };
+    let disks = [
+      Disk::open("first", options.clone()).unwrap(),
+      Disk::open("second", options).unwrap(),
+    ];
+    for disk in disks.iter() {
+      disk.set(b"Server", b"nginx").unwrap();
+      disk.compact().unwrap();
+    }
+
+    let before = cache.stats();
+    for _ in 0..3 {
+      for disk in disks.iter() {
+        assert_eq!(disk.get(b"Server").unwrap().unwrap().value(), b"nginx");
+      }
+    }
+    let stats = cache.stats();
+    assert_eq!((stats.misses - before.misses, stats.hits - before.hits), (2, 4));
+    assert_eq!(stats.blocks, 2);
+
+    // Blocks of compacted segments are dropped with them.
+    disks[0].set(b"Database", b"PostgreSQL").unwrap();
+    disks[0].compact().unwrap();
+    assert_eq!(cache.stats().blocks, 1);
+  }
+
+  #[test]
+  fn test_memtable_kinds() {
+    for kind in [MemTableKind::SkipList, MemTableKind::HashIndex] {
+      let mut rng = rand::thread_rng();
+      let test_dir = format!("./{}/", rng.gen::<u32>());
+      create_dir_all(&test_dir).unwrap();
+      let options = DiskOptions {
+        memtable_kind: kind,
+        ..DiskOptions::default()
+      };
+      let disk = Disk::open(&test_dir, options.clone()).unwrap();
+      disk.set(b"Server", b"nginx").unwrap();
+      disk.set(b"Database", b"MySQL").unwrap();
+      disk.set(b"Cache", b"Redis").unwrap();
+      disk.set(b"Database", b"PostgreSQL").unwrap();
+      disk.delete(b"Cache").unwrap();
+      assert_eq!(&*disk.get_pinned(b"Database").unwrap().unwrap(), b"PostgreSQL");
+      let keys = |entries: Vec<DiskEntry>| -> Vec<Vec<u8>> {
+        entries.into_iter().map(|entry| entry.key().to_vec()).collect()
+      };
+      assert_eq!(keys(disk.scan(..).unwrap()), [b"Database".to_vec(), b"Server".to_vec()]);
+      let reverse = ScanOptions {
+        reverse: true,
+        ..ScanOptions::default()
+      };
+      let scanned = keys(disk.scan_with(.., &reverse).unwrap());
+      assert_eq!(scanned, [b"Server".to_vec(), b"Database".to_vec()]);
+      drop(disk);
+
+      // The WAL is replayed into a memtable of the same kind, then flushed from it.
+      let disk = Disk::open(&test_dir, options).unwrap();
+      assert_eq!(disk.get(b"Database").unwrap().unwrap().value(), b"PostgreSQL");
+      disk.flush().unwrap();
+      assert!(disk.get(b"Cache").unwrap().is_none());
+      assert_eq!(keys(disk.scan(..).unwrap()), [b"Database".to_vec(), b"Server".to_vec()]);
+
+      remove_dir_all(&test_dir).unwrap();
+    }
+  }
+
+  #[test]
+  fn test_tree_stats() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+    let options = DiskOptions {
+      compaction_trigger: 100,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    assert_eq!(disk.tree_stats().levels, [LevelStats::default()]);
+
+    disk.set(b"Server", b"nginx").unwrap();
+    disk.flush().unwrap();
+    assert_eq!(disk.tree_stats().compaction_debt, 0);
+    disk.set(b"Database", b"PostgreSQL").unwrap();
+    disk.delete(b"Server").unwrap();
+    disk.flush().unwrap();
+    let stats = disk.tree_stats();
+    let files = disk.live_files().unwrap();
+    assert_eq!(stats.levels.len(), 1);
+    assert_eq!(stats.levels[0].files, 2);
+    assert_eq!(stats.levels[0].entries, 3);
+    assert_eq!(stats.levels[0].bytes, files.iter().map(|file| file.size).sum::<u64>());
+    // Segments below the compaction trigger aren't owed a compaction yet.
+    assert_eq!(stats.compaction_debt, 0);
+    let options = DiskOptions {
+      compaction_trigger: 2,
+      ..DiskOptions::default()
+    };
+    let follower = Disk::open_follower(&test_dir, options, Duration::from_secs(60)).unwrap();
+    assert_eq!(follower.tree_stats().compaction_debt, stats.levels[0].bytes);
+    drop(follower);
+
+    disk.compact().unwrap();
+    let stats = disk.tree_stats();
+    assert_eq!(stats.levels[0].files, 1);
+    assert_eq!(stats.compaction_debt, 0);
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_read_options() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+    let cache = BlockCache::new(1024 * 1024);
+    let options = DiskOptions {
+      block_cache: Some(cache.clone()),
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    disk.set(b"Server", b"nginx").unwrap();
+    disk.set(b"Database", b"PostgreSQL").unwrap();
+    disk.compact().unwrap();
+
+    // Reads that don't fill the cache leave it as it was.
+    let uncached = ReadOptions {
+      fill_cache: false,
+      ..ReadOptions::default()
+    };
+    let scan = ScanOptions::default();
+    assert_eq!(disk.scan_read(.., &scan, &uncached).unwrap().len(), 2);
+    assert!(disk.get_with(b"Server", &uncached).unwrap().is_some());
+    assert_eq!(cache.stats().blocks, 0);
+    assert!(disk.get(b"Server").unwrap().is_some());
+    assert_eq!(cache.stats().blocks, 1);
+
+    let snapshot = disk.snapshot();
+    disk.set(b"Server", b"Caddy").unwrap();
+    let as_of_snapshot = ReadOptions {
+      snapshot: Some(snapshot),
+      ..ReadOptions::default()
+    };
+    assert_eq!(disk.get_with(b"Server", &as_of_snapshot).unwrap().unwrap().value(), b"nginx");
+    assert_eq!(disk.scan_read(.., &scan, &as_of_snapshot).unwrap()[1].value(), b"nginx");
+    let last = ScanOptions { limit: 1, reverse: true, ..ScanOptions::default() };
+    let entries = disk.scan_read(.., &last, &as_of_snapshot).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].value(), b"nginx");
+    let other_options = DiskOptions {
+      storage: Storage::new(MemoryBackend::new()),
+      ..DiskOptions::default()
+    };
+    let other = Disk::open("other", other_options).unwrap();
+    let foreign = ReadOptions {
+      snapshot: Some(other.snapshot()),
+      ..ReadOptions::default()
+    };
+    assert!(disk.get_with(b"Server", &foreign).is_err());
+    assert!(disk.scan_read(.., &scan, &foreign).is_err());
+
+    // Damage the data block on disk: reads keep being served from the cache, unless they
+    // verify checksums.
+    for path in find_files_with_extension(test_dir.as_ref(), "sst") {
+      let mut bytes = std::fs::read(&path).unwrap();
+      bytes[10] ^= 0xff;
+      std::fs::write(&path, bytes).unwrap();
+    }
+    assert_eq!(disk.get(b"Database").unwrap().unwrap().value(), b"PostgreSQL");
+    let verified = ReadOptions {
+      verify_checksums: true,
+      ..ReadOptions::default()
+    };
+    assert!(disk.get_with(b"Database", &verified).is_err());
+    assert!(disk.scan_read(.., &scan, &verified).is_err());
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_key_distribution() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+    let options = DiskOptions {
+      value_log: Some(ValueLogOptions {
+        min_value_size: 64,
+        ..ValueLogOptions::default()
+      }),
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    for i in 0..30 {
+      disk.set(format!("user:{:02}", i).as_bytes(), b"0123456789").unwrap();
+    }
+    for i in 0..10 {
+      disk.set(format!("order/{}", i).as_bytes(), &[b'x'; 100]).unwrap();
+    }
+    disk.flush().unwrap();
+    disk.set(b"config", b"").unwrap();
+    disk.delete(b"user:00").unwrap();
+    // Keys the range tombstone covers are dropped from the memtables as well.
+    disk.set(b"user:15", b"0123456789").unwrap();
+    disk.delete_range(b"user:1", b"user:2").unwrap();
+
+    // Sampling everything counts every live key once.
+    let distribution = disk.key_distribution(1.0).unwrap();
+    assert_eq!(distribution.sampled_keys, 30);
+    assert_eq!(distribution.estimated_keys, 30);
+    assert_eq!(distribution.key_sizes.count, 30);
+    assert_eq!(distribution.key_sizes.sum, 19 * 7 + 10 * 7 + 6);
+    // Values in the value log are sized from their pointers.
+    assert_eq!(distribution.value_sizes.sum, 19 * 10 + 10 * 100);
+    let expected = [(b"user:".to_vec(), 19), (b"order/".to_vec(), 10)];
+    assert_eq!(distribution.top_prefixes, expected);
+
+    let sampled = disk.key_distribution(0.5).unwrap();
+    assert!(sampled.sampled_keys <= 30);
+    assert!(disk.key_distribution(0.0).is_err());
+    assert!(disk.key_distribution(1.5).is_err());
+
+    drop(disk);
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_sample_keys() {
+    let options = DiskOptions {
+      block_size: 256,
+      storage: Storage::new(MemoryBackend::new()),
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open("sample", options).unwrap();
+    for i in 0..1000 {
+      disk.set(format!("key{:04}", i).as_bytes(), b"nginx").unwrap();
+    }
+    disk.compact().unwrap();
+    for i in (0..1000).step_by(10) {
+      disk.delete(format!("key{:04}", i).as_bytes()).unwrap();
+    }
+    disk.set(b"key0500a", b"caddy").unwrap();
+
+    let sample = disk.sample_keys(50, &b"key0200"[..]..&b"key0800"[..]).unwrap();
+    assert_eq!(sample.len(), 50);
+    assert!(sample.windows(2).all(|pair| pair[0] < pair[1]));
+    for key in sample.iter() {
+      assert!(key[..] >= b"key0200"[..] && key[..] < b"key0800"[..]);
+      assert!(disk.get(key).unwrap().is_some());
+    }
+
+    let sample = disk.sample_keys(10, &b"key0500"[..]..=&b"key0502"[..]).unwrap();
+    assert_eq!(sample, vec![b"key0500a".to_vec(), b"key0501".to_vec(), b"key0502".to_vec()]);
+    assert!(disk.sample_keys(0, ..).unwrap().is_empty());
+    assert!(disk.sample_keys(10, &b"zzz"[..]..).unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_tail() {
+    let disk = Disk::open_in_memory().unwrap();
+    disk.set(b"API", b"GraphQL").unwrap();
+    disk.soft_delete(b"API").unwrap();
+    disk.delete_range(b"a", b"c").unwrap();
+    let mut tail = disk.tail(1).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put(b"Database", b"PostgreSQL");
+    batch.delete(b"Server");
+    disk.write(batch).unwrap();
+
+    let events: Vec<(u64, ChangeOp)> = (0..4)
+      .map(|_| tail.recv().unwrap())
+      .map(|event| (event.sequence, event.op))
+      .collect();
+    assert_eq!(
+      events,
+      vec![
+        (2, ChangeOp::Delete),
+        (3, ChangeOp::DeleteRange { end: b"c".to_vec() }),
+        (4, ChangeOp::Put(b"PostgreSQL".to_vec())),
+        (5, ChangeOp::Delete),
+      ]
+    );
+    assert_eq!(tail.try_recv(), None);
+    assert_eq!(tail.position(), 5);
+
+    let writer = {
+      let disk = disk.clone();
+      thread::spawn(move || disk.set(b"Server", b"nginx").unwrap())
+    };
+    let event = tail.recv().unwrap();
+    assert_eq!((event.key, event.sequence), (b"Server".to_vec(), 6));
+    writer.join().unwrap();
+    assert_eq!(disk.tail(6).unwrap().try_recv(), None);
+
+    // Writes flushed to segments can no longer be tailed.
+    disk.compact().unwrap();
+    assert!(matches!(disk.tail(0), Err(FluxError::InvalidArgument(_))));
+    disk.set(b"Server", b"caddy").unwrap();
+    assert_eq!(disk.tail(6).unwrap().recv().unwrap().sequence, 7);
+  }
+
+  #[test]
+  fn test_logical_clock() {
+    // Two databases given the same writes on the same clock end up with the same files.
+    let files = || {
+      let options = DiskOptions {
+        clock: Clock::logical(1000),
+        retained_versions: 1,
+        storage: Storage::new(MemoryBackend::new()),
+        ..DiskOptions::default()
+      };
+      let disk = Disk::open("clock", options.clone()).unwrap();
+      disk.set(b"API", b"GraphQL").unwrap();
+      disk.set(b"API", b"REST").unwrap();
+      disk.delete(b"Server").unwrap();
+      let history = disk.history(b"API").unwrap();
+      let timestamps: Vec<u128> = history.iter().map(Version::timestamp).collect();
+      assert_eq!(timestamps, vec![1002, 1001]);
+      drop(disk);
+
+      let disk = Disk::open("clock", options.clone()).unwrap();
+      disk.set(b"Database", b"PostgreSQL").unwrap();
+      assert_eq!(disk.history(b"Database").unwrap()[0].timestamp(), 1005);
+      drop(disk);
+      let storage = &options.storage;
+      let mut paths = storage.list(Path::new("clock")).unwrap();
+      paths.sort();
+      // The first WAL file stays live until its records are flushed.
+      let mut wals = storage.list_with_extension(Path::new("clock"), "wal").unwrap();
+      wals.sort();
+      let expected = ["clock/1000.wal", "clock/1004.wal"].map(PathBuf::from);
+      assert_eq!(wals, expected);
+      paths
+        .into_iter()
+        .map(|path| (path.clone(), storage.read(&path).unwrap()))
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(files(), files());
+  }
+
+  #[test]
+  fn test_single_file() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+    let path = format!("{}app.fluxdb", test_dir);
+
+    let options = DiskOptions {
+      single_file: true,
+      memtable_size: 1,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&path, options).unwrap();
+    for i in 0..100 {
+      disk.set(format!("key{:02}", i).as_bytes(), b"nginx").unwrap();
+    }
+    disk.delete(b"key42").unwrap();
+    disk.wait_for_background_work();
+    disk.compact().unwrap();
+    drop(disk);
+    assert_eq!(std::fs::read_dir(&test_dir).unwrap().count(), 1);
+
+    // Recognized without the option once created.
+    let disk = Disk::new(&path);
+    assert_eq!(disk.get(b"key07").unwrap().unwrap().value(), b"nginx");
+    assert!(disk.get(b"key42").unwrap().is_none());
+    assert_eq!(disk.scan(..).unwrap().len(), 99);
+    assert_eq!(disk.create_backup("./unused").unwrap_err().kind(), io::ErrorKind::Unsupported);
+    drop(disk);
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_open_checks_manifest_features() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+    let dir = Path::new(&test_dir);
+
+    let options = DiskOptions {
+      compression: Compression::Zstd,
+      ..DiskOptions::default()
+    };
+    if options.compression.is_available() {
+      let disk = Disk::open(&test_dir, options).unwrap();
+      disk.set(b"Server", b"nginx").unwrap();
+      drop(disk);
+      assert_eq!(Manifest::load(dir).unwrap().unwrap().features, vec!["zstd".to_owned()]);
+    } else {
+      drop(Disk::new(&test_dir));
+    }
+
+    // A database written by a newer version with a feature this one lacks isn't opened.
+    let mut manifest = Manifest::load(dir).unwrap().unwrap();
+    manifest.add_feature("encryption");
+    manifest.store(dir).unwrap();
+    let err = Disk::open(&test_dir, DiskOptions::default()).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    assert!(err.to_string().starts_with("missing feature encryption"));
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  /// Orders keys that are decimal numbers by value, before every other key.
+  struct Numeric;
+
+  impl Comparator for Numeric {
+    fn name(&self) -> &str {
+      "numeric"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+      let number = |key: &[u8]| std::str::from_utf8(key).ok()?.parse::<u64>().ok();
+      match (number(a), number(b)) {
+        (Some(x), Some(y)) => x.cmp(&y).then_with(|| a.cmp(b)),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => a.cmp(b),
+      }
+    }
+  }
+
+  #[test]
+  fn test_custom_comparator() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      comparator: KeyOrder::new(Numeric),
+      memtable_max_records: Some(8),
+      compaction_trigger: 100,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    for i in (1..=20).rev() {
+      disk.set(i.to_string().as_bytes(), b"nginx").unwrap();
+    }
+    disk.set(b"key", b"apache").unwrap();
+    assert_eq!(disk.delete_range(b"5", b"12").unwrap(), 1);
+    assert_eq!(disk.delete_range(b"12", b"5").unwrap(), 0);
+    disk.wait_for_background_work();
+    assert!(disk.segment_files().len() >= 2);
+
+    let check = |disk: &Disk| {
+      let keys = |entries: Vec<DiskEntry>| -> Vec<String> {
+        entries.iter().map(|entry| String::from_utf8(entry.key().to_vec()).unwrap()).collect()
+      };
+      let expected = ["1", "2", "3", "4", "12", "13", "14", "15", "16", "17", "18", "19", "20"];
+      let mut all = expected.map(str::to_owned).to_vec();
+      all.push("key".to_owned());
+      assert_eq!(keys(disk.scan(..).unwrap()), all);
+      assert_eq!(keys(disk.scan(&b"3"[..]..&b"14"[..]).unwrap()), ["3", "4", "12", "13"]);
+      assert_eq!(keys(disk.scan_rev_at(&b"18"[..].., u64::MAX, 3).unwrap()), ["key", "20", "19"]);
+      assert!(disk.get(b"7").unwrap().is_none());
+      assert_eq!(disk.get(b"key").unwrap().unwrap().value(), b"apache");
+      let found = disk.multi_get(&[b"20", b"9", b"2"]).unwrap();
+      assert_eq!(found.iter().map(Option::is_some).collect::<Vec<_>>(), [true, false, true]);
+    };
+    check(&disk);
+    drop(disk);
+
+    let disk = Disk::open(&test_dir, options.clone()).unwrap();
+    check(&disk);
+    disk.compact().unwrap();
+    check(&disk);
+    drop(disk);
+    let dir = Path::new(&test_dir);
+    assert_eq!(Manifest::load(dir).unwrap().unwrap().comparator.as_deref(), Some("numeric"));
+
+    // Files sorted in one order would be misread in another.
+    let err = Disk::open(&test_dir, DiskOptions::default()).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    remove_dir_all(&test_dir).unwrap();
+    create_dir_all(&test_dir).unwrap();
+    drop(Disk::new(&test_dir));
+    assert!(Disk::open(&test_dir, options).is_err());
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_read_amplification_triggers_compaction() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      memtable_size: 1,
+      compaction_trigger: 100,
+      bloom_bits_per_key: 0,
+      read_amplification_trigger: Some(2.5),
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    for i in 0..4 {
+      disk.set(format!("key{}", i).as_bytes(), b"nginx").unwrap();
+      disk.wait_for_background_work();
+    }
+    assert_eq!(disk.segment_files().len(), 4);
+
+    // Without bloom filters, looking up the oldest key reads every segment.
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while disk.read_amplification() <= 2.5 {
+      let average = disk.read_amplification();
+      assert!(Instant::now() < deadline, "read amplification stuck at {}", average);
+      assert_eq!(disk.get(b"key0").unwrap().unwrap().value(), b"nginx");
+    }
+    disk.wait_for_background_work();
+    assert_eq!(disk.segment_files().len(), 1);
+    assert_eq!(disk.read_amplification(), 0.0);
+    assert_eq!(disk.statistics().compactions, 1);
+    drop(disk);
+
+    // A single segment is all lookups ever read once compacted.
+    let options = DiskOptions {
+      read_amplification_trigger: Some(0.5),
+      ..DiskOptions::default()
+    };
+    let err = Disk::open(&test_dir, options).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_write_stall_rejects_writes() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      memtable_size: 1,
+      compaction_trigger: 100,
+      write_stall: WriteStall {
+        stop_segments: Some(1),
+        on_stop: StallPolicy::Reject,
+        ..WriteStall::disabled()
+      },
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    assert_eq!(disk.set(b"Server", b"nginx"), Ok(1));
+    disk.wait_for_background_work();
+    assert_eq!(disk.segment_files().len(), 1);
+
+    // A single segment can't be compacted away, so writes stay stopped; reads go on.
+    assert_eq!(disk.set(b"Content-Type", b"text/html"), Err(0));
+    assert_eq!(disk.delete(b"Server"), Err(0));
+    let mut batch = WriteBatch::new();
+    batch.put(b"Content-Type", b"text/html");
+    assert!(matches!(disk.commit(batch, [], 0), Err(FluxError::Busy)));
+    assert_eq!(disk.get(b"Server").unwrap().unwrap().value(), b"nginx");
+    drop(disk);
+
+    let disk = Disk::open(&test_dir, DiskOptions::default()).unwrap();
+    assert_eq!(disk.set(b"Content-Type", b"text/html"), Ok(1));
+    drop(disk);
+
+    // Waiting instead would never end.
+    let options = DiskOptions {
+      write_stall: WriteStall { stop_segments: Some(1), ..WriteStall::default() },
+      ..DiskOptions::default()
+    };
+    let err = Disk::open(&test_dir, options).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_write_stall_waits_for_compaction() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = DiskOptions {
+      memtable_size: 1,
+      compaction_trigger: 100,
+      write_stall: WriteStall {
+        slowdown_segments: Some(2),
+        stop_segments: Some(3),
+        ..WriteStall::disabled()
+      },
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    for i in 0..20 {
+      assert_eq!(disk.set(format!("key{:02}", i).as_bytes(), b"nginx"), Ok(1));
+    }
+    disk.wait_for_background_work();
+
+    // Reaching the stop threshold compacts the segments, well below `compaction_trigger`.
+    assert!(disk.segment_files().len() < 3);
+    assert!(disk.statistics().compactions > 0);
+    for i in 0..20 {
+      assert!(disk.get(format!("key{:02}", i).as_bytes()).unwrap().is_some());
+    }
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_rate_limiter_paces_background_writes() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let limiter = RateLimiter::new(100_000, 1_000);
+    let options = DiskOptions {
+      memtable_size: 1024,
+      compaction_trigger: 100,
+      rate_limiter: Some(limiter.clone()),
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options).unwrap();
+    for i in 0..100 {
+      disk.set(format!("key{:03}", i).as_bytes(), b"nginx").unwrap();
+    }
+    disk.wait_for_background_work();
+    let flushed = limiter.stats().high_priority_bytes;
+    let sizes = disk.segment_files().into_iter().map(|path| path.metadata().unwrap().len());
+    assert_eq!(flushed, sizes.sum::<u64>());
+    disk.compact().unwrap();
+
+    // Flushes and compactions pay for every byte of the segments they write.
+    let stats = limiter.stats();
+    assert!(stats.high_priority_bytes >= flushed && stats.low_priority_bytes > 0);
+    assert!(stats.wait_time > Duration::ZERO);
+    assert_eq!(disk.get(b"key042").unwrap().unwrap().value(), b"nginx");
+
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  #[test]
+  fn test_value_log() {
+    let mut rng = rand::thread_rng();
+    let test_dir = format!("./{}/", rng.gen::<u32>());
+    create_dir_all(&test_dir).unwrap();
+
+    let options = || DiskOptions {
+      value_log: Some(ValueLogOptions {
+        min_value_size: 100,
+        gc_garbage_ratio: 0.5,
+      }),
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(&test_dir, options()).unwrap();
+    for i in 0..10 {
+      disk.set(format!("key{}", i).as_bytes(), &[b'a'; 200]).unwrap();
+    }
+    disk.set(b"small", b"nginx").unwrap();
+    disk.compact().unwrap();
+    assert_eq!(disk.value_log_files().len(), 1);
+    let segment_size = disk.segment_files()[0].metadata().unwrap().len();
+    assert!(segment_size < 2000);
+    drop(disk);
+
+    // Large values are read back through their pointers, after reopening too.
+    let disk = Disk::open(&test_dir, options()).unwrap();
+    assert_eq!(disk.get(b"key3").unwrap().unwrap().value(), &[b'a'; 200]);
+    assert_eq!(disk.get(b"small").unwrap().unwrap().value(), b"nginx");
+    let entries = disk.scan(..).unwrap();
+    let values: Vec<_> = entries.iter().map(|entry| entry.value().len()).collect();
+    assert_eq!(values, [200; 10].into_iter().chain([5]).collect::<Vec<_>>());
+
+    // Overwriting every value leaves nothing pointing to the first file, which the
+    // compaction removes.
+    let first = disk.value_log_files();
+    for i in 0..10 {
+      disk.set(format!("key{}", i).as_bytes(), &[b'b'; 200]).unwrap();
+    }
+    disk.compact().unwrap();
+    assert!(!first[0].exists());
+    assert_eq!(disk.value_log_stats().files, 1);
+
+    // Once half of a file is garbage, the next compaction moves its live values to a new
+    // file.
+    let second = disk.value_log_files();
+    for i in 0..5 {
+      disk.set(format!("key{}", i).as_bytes(), b"small").unwrap();
+    }
+    disk.compact().unwrap();
+    assert_eq!(disk.value_log_files(), second);
+    disk.compact().unwrap();
+    assert!(!second[0].exists());
+    let stats = disk.value_log_stats();
+    assert_eq!((stats.files, stats.live_bytes), (1, stats.bytes - 5));
+    assert!(stats.relocated_bytes >= 5 * 200);
+    assert_eq!(disk.get(b"key7").unwrap().unwrap().value(), &[b'b'; 200]);
+    assert_eq!(disk.get(b"key2").unwrap().unwrap().value(), b"small");
+
+    drop(disk);
+    remove_dir_all(&test_dir).unwrap();
+  }
+
+  /// Passes calls on to `storage` until `budget` file creations, appends, syncs, renames and
+  /// deletions have gone through, then fails every one that follows, leaving the files as a
+  /// process crashing at that point would.
+  struct CrashingBackend {
+    storage: Storage,
+    budget: Arc<AtomicUsize>,
+  }
+
+  struct CrashingFile {
+    file: Box<dyn StorageFile>,
+    budget: Arc<AtomicUsize>,
+  }
+
+  fn spend(budget: &AtomicUsize) -> io::Result<()> {
+    match budget.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| left.checked_sub(1)) {
+      Ok(_) => Ok(()),
+      Err(_) => Err(io::Error::other("crashed")),
+    }
+  }
+
+  impl CrashingBackend {
+    fn wrap(&self, file: Box<dyn StorageFile>) -> Box<dyn StorageFile> {
+      let budget = self.budget.clone();
+      Box::new(CrashingFile { file, budget })
+    }
+  }
+
+  impl StorageFile for CrashingFile {
+    fn append(&mut self, data: &[u8]) -> io::Result<()> {
+      spend(&self.budget)?;
+      self.file.append(data)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+      self.file.read_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+      self.file.size()
+    }
+
+    fn sync(&self) -> io::Result<()> {
+      spend(&self.budget)?;
+      self.file.sync()
+    }
+  }
+
+  impl StorageBackend for CrashingBackend {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+      spend(&self.budget)?;
+      Ok(self.wrap(self.storage.create(path)?))
+    }
+
+    fn append(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+      Ok(self.wrap(self.storage.append(path)?))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+      self.storage.open(path)
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+      spend(&self.budget)?;
+      self.storage.delete(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+      spend(&self.budget)?;
+      self.storage.rename(from, to)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+      self.storage.list(dir)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+      self.storage.exists(path)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+      spend(&self.budget)?;
+      self.storage.sync_dir(dir)
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<StorageLock> {
+      self.storage.lock(path)
+    }
+  }
+
+  #[test]
+  fn test_failed_wal_sync_poisons_durable_writes() {
+    let dir = Path::new("db");
+    let storage = Storage::new(MemoryBackend::new());
+    let budget = Arc::new(AtomicUsize::new(usize::MAX));
+    let options = DiskOptions {
+      storage: Storage::new(CrashingBackend {
+        storage: storage.clone(),
+        budget: budget.clone(),
+      }),
+      logger: Logger::default().with_levels(None),
+      sync_writes: true,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(dir.to_str().unwrap(), options).unwrap();
+    disk.set(b"Server", b"nginx").unwrap();
+
+    // The record reaches the file, but not its sync.
+    budget.store(1, Ordering::Relaxed);
+    assert!(disk.set(b"Cache", b"redis").is_err());
+    // Logged writes are visible before they are durable, even when the sync fails.
+    assert_eq!(disk.get(b"Cache").unwrap().unwrap().value(), b"redis");
+    budget.store(usize::MAX, Ordering::Relaxed);
+    assert!(disk.set(b"Database", b"mysql").is_err());
+    let mut batch = WriteBatch::new();
+    batch.put(b"Queue", b"kafka");
+    assert!(disk.write(batch).is_err());
+    drop(disk);
+
+    let options = DiskOptions {
+      storage,
+      sync_writes: true,
+      ..DiskOptions::default()
+    };
+    let disk = Disk::open(dir.to_str().unwrap(), options).unwrap();
+    assert_eq!(disk.get(b"Server").unwrap().unwrap().value(), b"nginx");
+    disk.set(b"Database", b"mysql").unwrap();
+  }
+
+  #[test]
+  fn test_interrupted_recovery() {
+    let dir = Path::new("db");
+    // A directory from before the manifest existed, holding a file from before headers and
+    // sequence numbers, which recovery copies into a fresh WAL, and one read in place.
+    let setup = || {
+      let storage = Storage::new(MemoryBackend::new());
+      let mut legacy = storage.create(&dir.join("1.wal")).unwrap();
+      let records = [(&b"Server"[..], &b"nginx"[..], 7u128), (b"Cache", b"redis", 8)];
+      for (key, value, timestamp) in records {
+        legacy.append(&(key.len() as u64).to_le_bytes()).unwrap();
+        legacy.append(&[0]).unwrap();
+        legacy.append(&(value.len() as u64).to_le_bytes()).unwrap();
+        legacy.append(key).unwrap();
+        legacy.append(value).unwrap();
+        legacy.append(&timestamp.to_le_bytes()).unwrap();
+      }
+      storage
+    };
+
+    // Crashing after every number of steps in turn, until one lets recovery finish, must
+    // leave files that the next open recovers to the same state as an uninterrupted one.
+    for budget in 0.. {
+      let storage = setup();
+      let crashing = DiskOptions {
+        storage: Storage::new(CrashingBackend {
+          storage: storage.clone(),
+          budget: Arc::new(AtomicUsize::new(budget)),
+        }),
+        logger: Logger::default().with_levels(None),
+        ..DiskOptions::default()
+      };
+      let finished = Disk::open(dir.to_str().unwrap(), crashing).is_ok();
+
+      let options = DiskOptions {
+        storage,
+        ..DiskOptions::default()
+      };
+      let disk = Disk::open(dir.to_str().unwrap(), options.clone()).unwrap();
+      disk.set(b"Database", b"mysql").unwrap();
+      drop(disk);
+      let disk = Disk::open(dir.to_str().unwrap(), options).unwrap();
+      assert_eq!(disk.get(b"Server").unwrap().unwrap().value(), b"nginx");
+      assert_eq!(disk.get(b"Cache").unwrap().unwrap().value(), b"redis");
+      assert_eq!(disk.get(b"Database").unwrap().unwrap().value(), b"mysql");
+      assert_eq!(disk.last_sequence(), 3, "crashed after {} steps", budget);
+      drop(disk);
+      if finished {
+        break;
+      }
+    }
   }
 }
@@ -0,0 +1,232 @@
+//! Portable dump format written by `Disk::export_to` and read by `Disk::import_from`.
+//!
+//! A dump is a stream of little-endian fields, independent of the on-disk formats of the
+//! engine, so it can move data between versions and machines or be piped through a
+//! compressor:
+//!
+//! ```text
+//! header:  magic "FLUXDUMP" (8 bytes) | format version (u32)
+//! record:  kind (u8: 1 value, 2 tombstone) | key length (u64) | key
+//!          | value length (u64) | value      -- value records only
+//!          | timestamp (u128, microseconds since the Unix epoch)
+//! trailer: kind (u8: 0) | record count (u64)
+//! ```
+//!
+//! The trailer lets a reader tell a complete dump from a truncated one.
+
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+const MAGIC: &[u8; 8] = b"FLUXDUMP";
+const DUMP_VERSION: u32 = 1;
+
+const END: u8 = 0;
+const VALUE: u8 = 1;
+const TOMBSTONE: u8 = 2;
+
+/// A key's value, or its removal, as held in a dump.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DumpRecord {
+    pub key: Vec<u8>,
+    /// `None` for a tombstone.
+    pub value: Option<Vec<u8>>,
+    pub timestamp: u128,
+}
+
+/// Writes records to a dump stream.
+pub struct DumpWriter<W: Write> {
+    writer: BufWriter<W>,
+    count: u64,
+}
+
+impl<W: Write> DumpWriter<W> {
+    /// Starts a dump by writing its header.
+    pub fn new(writer: W) -> io::Result<DumpWriter<W>> {
+        let mut writer = BufWriter::new(writer);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&DUMP_VERSION.to_le_bytes())?;
+        Ok(DumpWriter { writer, count: 0 })
+    }
+
+    pub fn write_record(&mut self, record: &DumpRecord) -> io::Result<()> {
+        let kind = match record.value {
+            Some(_) => VALUE,
+            None => TOMBSTONE,
+        };
+        self.writer.write_all(&[kind])?;
+        self.write_bytes(&record.key)?;
+        if let Some(value) = &record.value {
+            self.write_bytes(value)?;
+        }
+        self.writer.write_all(&record.timestamp.to_le_bytes())?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Returns the number of records written so far.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Ends the dump with its trailer and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.write_all(&[END])?;
+        self.writer.write_all(&self.count.to_le_bytes())?;
+        self.writer.into_inner().map_err(io::IntoInnerError::into_error)
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.writer.write_all(&(bytes.len() as u64).to_le_bytes())?;
+        self.writer.write_all(bytes)
+    }
+}
+
+/// Reads the records of a dump stream in order. A stream that ends before the trailer, or
+/// whose trailer doesn't match the records read, yields an error.
+pub struct DumpReader<R: Read> {
+    reader: BufReader<R>,
+    count: u64,
+    done: bool,
+}
+
+impl<R: Read> DumpReader<R> {
+    /// Opens a dump, checking its header.
+    pub fn new(reader: R) -> io::Result<DumpReader<R>> {
+        let mut reader = BufReader::new(reader);
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a FluxDB dump".to_owned()));
+        }
+        let version = u32::from_le_bytes(read_array(&mut reader)?);
+        if version != DUMP_VERSION {
+            return Err(invalid(format!("unsupported dump version {}", version)));
+        }
+        Ok(DumpReader {
+            reader,
+            count: 0,
+            done: false,
+        })
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<DumpRecord>> {
+        let [kind] = read_array(&mut self.reader)?;
+        match kind {
+            END => {
+                let count = u64::from_le_bytes(read_array(&mut self.reader)?);
+                if count != self.count {
+                    return Err(invalid(format!(
+                        "dump holds {} records but its trailer says {}",
+                        self.count, count
+                    )));
+                }
+                Ok(None)
+            }
+            VALUE | TOMBSTONE => {
+                let key = read_bytes(&mut self.reader)?;
+                let value = match kind {
+                    VALUE => Some(read_bytes(&mut self.reader)?),
+                    _ => None,
+                };
+                let timestamp = u128::from_le_bytes(read_array(&mut self.reader)?);
+                self.count += 1;
+                Ok(Some(DumpRecord {
+                    key,
+                    value,
+                    timestamp,
+                }))
+            }
+            _ => Err(invalid(format!("unknown dump record kind {}", kind))),
+        }
+    }
+}
+
+impl<R: Read> Iterator for DumpReader<R> {
+    type Item = io::Result<DumpRecord>;
+
+    fn next(&mut self) -> Option<io::Result<DumpRecord>> {
+        if self.done {
+            return None;
+        }
+        let record = self.read_record();
+        self.done = !matches!(record, Ok(Some(_)));
+        record.transpose()
+    }
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buffer = [0; N];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+fn read_bytes(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let len = u64::from_le_bytes(read_array(reader)?);
+    let mut bytes = Vec::new();
+    // Read through `take` so a corrupt length can't make us allocate it all up front.
+    reader.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "dump ends inside a record"));
+    }
+    Ok(bytes)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Vec<DumpRecord> {
+        vec![
+            DumpRecord {
+                key: b"Database".to_vec(),
+                value: Some(b"PostgreSQL".to_vec()),
+                timestamp: 1,
+            },
+            DumpRecord {
+                key: b"Server".to_vec(),
+                value: None,
+                timestamp: 2,
+            },
+        ]
+    }
+
+    fn dump() -> Vec<u8> {
+        let mut writer = DumpWriter::new(Vec::new()).unwrap();
+        for record in records() {
+            writer.write_record(&record).unwrap();
+        }
+        assert_eq!(writer.count(), 2);
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let read: Vec<DumpRecord> = DumpReader::new(&dump()[..])
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(read, records());
+    }
+
+    #[test]
+    fn test_detects_truncation_and_corruption() {
+        let mut bytes = dump();
+
+        // Without its trailer, the dump reads as truncated.
+        let read: Vec<_> = DumpReader::new(&bytes[..bytes.len() - 9]).unwrap().collect();
+        assert_eq!(read.len(), 3);
+        assert_eq!(read[1].as_ref().unwrap(), &records()[1]);
+        assert_eq!(read[2].as_ref().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        let trailer = bytes.len() - 8;
+        bytes[trailer..].copy_from_slice(&5u64.to_le_bytes());
+        let err = DumpReader::new(&bytes[..]).unwrap().last().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        bytes[0] = b'X';
+        assert!(DumpReader::new(&bytes[..]).is_err());
+    }
+}
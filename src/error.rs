@@ -0,0 +1,59 @@
+use std::fmt;
+use std::io;
+
+/// Errors returned by database operations.
+#[derive(Debug)]
+pub enum FluxError {
+    /// Reading or writing the database files failed.
+    Io(io::Error),
+    /// A transaction could not commit because a key it read was written by someone else
+    /// after its snapshot was taken. Retrying the transaction from the start may succeed.
+    Conflict,
+    /// A scan or multi-get materialized more than `DiskOptions::read_memory_limit` bytes
+    /// and was aborted. Narrowing the range or splitting the keys may succeed.
+    MemoryLimit { limit: usize },
+    /// An argument was rejected, such as an unknown or expired cursor.
+    InvalidArgument(String),
+    /// A validator set in `DiskOptions::validators` rejected a write, for the given reason.
+    /// Nothing of its batch was applied.
+    Rejected(String),
+    /// Flushes and compactions have fallen so far behind that writes are stopped, and
+    /// `WriteStall::on_stop` rejects them. Retrying later may succeed.
+    Busy,
+}
+
+impl fmt::Display for FluxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FluxError::Io(e) => write!(f, "I/O error: {}", e),
+            FluxError::Conflict => write!(f, "transaction conflict"),
+            FluxError::MemoryLimit { limit } => {
+                write!(f, "read exceeded its memory limit of {} bytes", limit)
+            }
+            FluxError::InvalidArgument(reason) => write!(f, "invalid argument: {}", reason),
+            FluxError::Rejected(reason) => write!(f, "write rejected: {}", reason),
+            FluxError::Busy => {
+                write!(f, "writes are stopped until flushes and compactions catch up")
+            }
+        }
+    }
+}
+
+impl std::error::Error for FluxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FluxError::Io(e) => Some(e),
+            FluxError::Conflict
+            | FluxError::MemoryLimit { .. }
+            | FluxError::InvalidArgument(_)
+            | FluxError::Rejected(_)
+            | FluxError::Busy => None,
+        }
+    }
+}
+
+impl From<io::Error> for FluxError {
+    fn from(e: io::Error) -> FluxError {
+        FluxError::Io(e)
+    }
+}
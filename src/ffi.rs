@@ -0,0 +1,244 @@
+//! C bindings for opening a database and paging through it with cursors, for callers in
+//! other languages. Requires the `ffi` feature; build the shared library with
+//! `cargo rustc --release --lib --features ffi --crate-type cdylib`.
+//!
+//! A database is opened with `fluxdb_open` and closed with `fluxdb_close`. A cursor reads
+//! the keys as they were when `fluxdb_cursor_open` was called; each `fluxdb_cursor_next`
+//! returns a page the caller owns and frees with `fluxdb_page_free`. Functions that fail
+//! return null, or 0 for a cursor id.
+
+use crate::cursor::{CursorTable, DEFAULT_CURSOR_IDLE_TIMEOUT};
+use crate::disk::Disk;
+use crate::options::DiskOptions;
+use std::ffi::{c_char, CStr};
+use std::ops::Bound;
+use std::{ptr, slice};
+
+/// An open database, read through its cursors.
+pub struct FluxDb {
+    cursors: CursorTable,
+}
+
+/// An entry of a page. The bytes belong to the page.
+#[repr(C)]
+pub struct FluxEntry {
+    pub key: *const u8,
+    pub key_len: usize,
+    pub value: *const u8,
+    pub value_len: usize,
+}
+
+/// A page of entries returned by `fluxdb_cursor_next`.
+#[repr(C)]
+pub struct FluxPage {
+    pub entries: *const FluxEntry,
+    pub len: usize,
+    /// Whether the range is exhausted, and the cursor closed.
+    pub done: bool,
+}
+
+/// Opens the database in the directory `dir`, with the default options. Returns null if
+/// it can't be opened.
+///
+/// # Safety
+///
+/// `dir` must be a valid, nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn fluxdb_open(dir: *const c_char) -> *mut FluxDb {
+    if dir.is_null() {
+        return ptr::null_mut();
+    }
+    let Ok(dir) = CStr::from_ptr(dir).to_str() else {
+        return ptr::null_mut();
+    };
+    match Disk::open(dir, DiskOptions::default()) {
+        Ok(disk) => {
+            let cursors = CursorTable::new(disk, DEFAULT_CURSOR_IDLE_TIMEOUT);
+            Box::into_raw(Box::new(FluxDb { cursors }))
+        }
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Closes a database, along with its open cursors.
+///
+/// # Safety
+///
+/// `db` must be null or returned by `fluxdb_open`, and not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn fluxdb_close(db: *mut FluxDb) {
+    if !db.is_null() {
+        drop(Box::from_raw(db));
+    }
+}
+
+/// Opens a cursor over the keys from `start`, inclusive, to `end`, exclusive, and returns
+/// its id. A null bound leaves that side of the range open. Returns 0 if `db` is null.
+///
+/// # Safety
+///
+/// `db` must be null or an open database, and each non-null bound must point to as many
+/// bytes as its length.
+#[no_mangle]
+pub unsafe extern "C" fn fluxdb_cursor_open(
+    db: *const FluxDb,
+    start: *const u8,
+    start_len: usize,
+    end: *const u8,
+    end_len: usize,
+) -> u64 {
+    let Some(db) = db.as_ref() else {
+        return 0;
+    };
+    let start = bytes(start, start_len).map_or(Bound::Unbounded, Bound::Included);
+    let end = bytes(end, end_len).map_or(Bound::Unbounded, Bound::Excluded);
+    db.cursors.open(start, end)
+}
+
+/// Returns the next page of at most `limit` entries of a cursor. Returns null if the
+/// cursor is unknown, closed or expired, if `limit` is 0, or if the read fails.
+///
+/// # Safety
+///
+/// `db` must be null or an open database.
+#[no_mangle]
+pub unsafe extern "C" fn fluxdb_cursor_next(
+    db: *const FluxDb,
+    cursor: u64,
+    limit: usize,
+) -> *mut FluxPage {
+    let Some(db) = db.as_ref() else {
+        return ptr::null_mut();
+    };
+    let Ok(page) = db.cursors.next(cursor, limit) else {
+        return ptr::null_mut();
+    };
+    let entries: Box<[FluxEntry]> = page
+        .iter()
+        .map(|entry| {
+            let (key, key_len) = leak(entry.key());
+            let (value, value_len) = leak(entry.value());
+            FluxEntry {
+                key,
+                key_len,
+                value,
+                value_len,
+            }
+        })
+        .collect();
+    let page = FluxPage {
+        len: entries.len(),
+        done: entries.len() < limit,
+        entries: Box::into_raw(entries) as *const FluxEntry,
+    };
+    Box::into_raw(Box::new(page))
+}
+
+/// Closes a cursor before it is exhausted. Returns whether it was open.
+///
+/// # Safety
+///
+/// `db` must be null or an open database.
+#[no_mangle]
+pub unsafe extern "C" fn fluxdb_cursor_close(db: *const FluxDb, cursor: u64) -> bool {
+    db.as_ref().is_some_and(|db| db.cursors.close(cursor))
+}
+
+/// Frees a page and the bytes of its entries.
+///
+/// # Safety
+///
+/// `page` must be null or returned by `fluxdb_cursor_next`, and not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn fluxdb_page_free(page: *mut FluxPage) {
+    if page.is_null() {
+        return;
+    }
+    let page = Box::from_raw(page);
+    let entries = slice::from_raw_parts_mut(page.entries as *mut FluxEntry, page.len);
+    let entries = Box::from_raw(entries as *mut [FluxEntry]);
+    for entry in entries.iter() {
+        unleak(entry.key, entry.key_len);
+        unleak(entry.value, entry.value_len);
+    }
+}
+
+/// Returns the bytes behind a pointer, or `None` for null.
+unsafe fn bytes(data: *const u8, len: usize) -> Option<Vec<u8>> {
+    (!data.is_null()).then(|| slice::from_raw_parts(data, len).to_vec())
+}
+
+/// Copies bytes to the heap for the caller, until `unleak` frees them.
+fn leak(bytes: &[u8]) -> (*const u8, usize) {
+    let bytes: Box<[u8]> = bytes.into();
+    let len = bytes.len();
+    (Box::into_raw(bytes) as *const u8, len)
+}
+
+/// Frees bytes copied by `leak`.
+unsafe fn unleak(data: *const u8, len: usize) {
+    drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+        data as *mut u8,
+        len,
+    )));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use std::ffi::CString;
+    use std::fs::{create_dir_all, remove_dir_all};
+
+    #[test]
+    fn test_pages_through_a_cursor() {
+        let mut rng = rand::thread_rng();
+        let test_dir = format!("./{}/", rng.gen::<u32>());
+        create_dir_all(&test_dir).unwrap();
+
+        let disk = Disk::new(&test_dir);
+        for i in 0..5 {
+            disk.set(format!("key{}", i).as_bytes(), b"nginx").unwrap();
+        }
+        drop(disk);
+
+        let dir = CString::new(test_dir.clone()).unwrap();
+        unsafe {
+            let db = fluxdb_open(dir.as_ptr());
+            assert!(!db.is_null());
+            let start = b"key1";
+            let cursor = fluxdb_cursor_open(db, start.as_ptr(), start.len(), ptr::null(), 0);
+
+            let mut keys = Vec::new();
+            loop {
+                let page = fluxdb_cursor_next(db, cursor, 3);
+                assert!(!page.is_null());
+                let entries = slice::from_raw_parts((*page).entries, (*page).len);
+                for entry in entries {
+                    keys.push(slice::from_raw_parts(entry.key, entry.key_len).to_vec());
+                    assert_eq!(
+                        slice::from_raw_parts(entry.value, entry.value_len),
+                        b"nginx"
+                    );
+                }
+                let done = (*page).done;
+                fluxdb_page_free(page);
+                if done {
+                    break;
+                }
+            }
+            assert_eq!(keys, [&b"key1"[..], b"key2", b"key3", b"key4"]);
+
+            // The exhausted cursor is closed.
+            assert!(fluxdb_cursor_next(db, cursor, 3).is_null());
+            let cursor = fluxdb_cursor_open(db, ptr::null(), 0, ptr::null(), 0);
+            assert!(fluxdb_cursor_next(db, cursor, 0).is_null());
+            assert!(fluxdb_cursor_close(db, cursor));
+            assert!(!fluxdb_cursor_close(db, cursor));
+            fluxdb_close(db);
+            assert!(fluxdb_open(ptr::null()).is_null());
+        }
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+}
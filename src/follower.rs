@@ -0,0 +1,229 @@
+//! Read-only followers of a database another process writes to.
+//!
+//! A `Follower` opens the directory of a primary database on the same file system and
+//! serves reads from it without ever writing there: it loads the segments listed in the
+//! manifest and reads the live WAL files into a memtable of its own. Every catch-up reads
+//! the manifest again, opens the segments the primary has written since, keeping those
+//! already open, and reads the records appended to the live WAL files since the last one,
+//! so reads trail the primary by at most the catch-up interval. Once the primary flushes a
+//! memtable and retires its WAL files, the remaining ones are read again from the start.
+//! Segments the primary compacts away stay readable through the files the follower holds
+//! open until its next catch-up.
+//!
+//! Single-file databases can't be followed: their container is only ever opened by the
+//! process writing it.
+
+use crate::disk::{Disk, DiskEntry};
+use crate::error::FluxError;
+use crate::options::DiskOptions;
+use crate::scan_options::ScanOptions;
+use std::io;
+use std::ops::RangeBounds;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// A read-only view of a database written by another process, catching up with it in the
+/// background.
+///
+/// Reads see a consistent prefix of the primary's writes, as of the last catch-up. Writes
+/// not yet in the WAL, such as the buffered ones of a primary without `sync_writes`, are
+/// seen once the primary hands them to the operating system. Catch-ups failing, as when the
+/// primary is down or its directory unreadable, are logged under the `replication`
+/// subsystem and leave reads on the last state caught up with.
+pub struct Follower {
+    disk: Disk,
+}
+
+impl Follower {
+    /// Opens the database in `dir` as a follower, catching up with the primary every
+    /// `interval`. The options must name the comparator and `value_schema` the primary uses;
+    /// options governing writes are ignored. Fails with `NotFound` if `dir` holds no
+    /// database, and with `Unsupported` if it is a single-file database.
+    pub fn open(dir: &str, options: DiskOptions, interval: Duration) -> io::Result<Follower> {
+        Ok(Follower {
+            disk: Disk::open_follower(dir, options, interval)?,
+        })
+    }
+
+    /// Catches up with the primary right away, for reading writes it just made.
+    pub fn catch_up(&self) -> io::Result<()> {
+        self.disk.catch_up()
+    }
+
+    /// Returns the sequence number of the last write of the primary the follower has read.
+    pub fn last_sequence(&self) -> u64 {
+        self.disk.last_sequence()
+    }
+
+    /// Looks up a key, as `Disk::get` does.
+    pub fn get(&self, key: &[u8]) -> io::Result<Option<DiskEntry>> {
+        self.disk.get(key)
+    }
+
+    /// Looks up several keys at once, as `Disk::multi_get` does.
+    pub fn multi_get(&self, keys: &[&[u8]]) -> Result<Vec<Option<DiskEntry>>, FluxError> {
+        self.disk.multi_get(keys)
+    }
+
+    /// Returns the live entries within the range, as `Disk::scan` does.
+    pub fn scan<'a, R: RangeBounds<&'a [u8]>>(
+        &self,
+        range: R,
+    ) -> Result<Vec<DiskEntry>, FluxError> {
+        self.disk.scan(range)
+    }
+
+    /// Returns the live entries within the range that pass the filters of `options`, as
+    /// `Disk::scan_with` does.
+    pub fn scan_with<'a, R: RangeBounds<&'a [u8]>>(
+        &self,
+        range: R,
+        options: &ScanOptions,
+    ) -> Result<Vec<DiskEntry>, FluxError> {
+        self.disk.scan_with(range, options)
+    }
+
+    /// Returns the live entries whose keys start with `prefix`, as `Disk::scan_prefix` does.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<DiskEntry>, FluxError> {
+        self.disk.scan_prefix(prefix)
+    }
+
+    /// Returns the paths of the segment files of the primary, as of the last catch-up.
+    pub fn segment_files(&self) -> Vec<PathBuf> {
+        self.disk.segment_files()
+    }
+
+    /// Returns the paths of the live WAL files of the primary, as of the last catch-up.
+    pub fn wal_files(&self) -> Vec<PathBuf> {
+        self.disk.wal_files()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use std::collections::BTreeSet;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::Instant;
+
+    fn files(dir: &str) -> BTreeSet<PathBuf> {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect()
+    }
+
+    #[test]
+    fn test_follower_catches_up() {
+        let mut rng = rand::thread_rng();
+        let test_dir = format!("./{}/", rng.gen::<u32>());
+        create_dir_all(&test_dir).unwrap();
+        let options = DiskOptions {
+            memtable_size: 1024,
+            compaction_trigger: 100,
+            ..DiskOptions::default()
+        };
+        let primary = Disk::open(&test_dir, options).unwrap();
+        primary.set(b"Server", b"nginx").unwrap();
+
+        // Followers leave the directory alone.
+        let before = files(&test_dir);
+        let follower =
+            Follower::open(&test_dir, DiskOptions::default(), Duration::from_secs(60)).unwrap();
+        follower.catch_up().unwrap();
+        drop(follower);
+        assert_eq!(files(&test_dir), before);
+
+        let follower =
+            Follower::open(&test_dir, DiskOptions::default(), Duration::from_secs(60)).unwrap();
+        assert_eq!(follower.get(b"Server").unwrap().unwrap().value(), b"nginx");
+        assert_eq!(follower.last_sequence(), 1);
+
+        // Writes flushed to segments, still in the WAL and compacted are all caught up with.
+        for i in 0..100 {
+            primary
+                .set(format!("key{:03}", i).as_bytes(), b"nginx")
+                .unwrap();
+        }
+        primary.delete(b"Server").unwrap();
+        primary.wait_for_background_work();
+        assert_eq!(follower.scan(..).unwrap().len(), 1);
+        follower.catch_up().unwrap();
+        assert_eq!(follower.scan(..).unwrap().len(), 100);
+        assert!(follower.get(b"Server").unwrap().is_none());
+        primary.compact().unwrap();
+        primary.set(b"key100", b"apache").unwrap();
+        follower.catch_up().unwrap();
+        assert_eq!(follower.scan_prefix(b"key1").unwrap().len(), 1);
+        assert_eq!(follower.last_sequence(), primary.last_sequence());
+
+        // The background catch-up picks up new writes without being asked.
+        let background =
+            Follower::open(&test_dir, DiskOptions::default(), Duration::from_millis(10)).unwrap();
+        primary.set(b"Server", b"caddy").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while background.get(b"Server").unwrap().is_none() {
+            assert!(Instant::now() < deadline, "the follower didn't catch up");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        drop((follower, background, primary));
+        let missing = Follower::open("./no-such-database/", DiskOptions::default(), Duration::MAX);
+        assert_eq!(missing.err().unwrap().kind(), io::ErrorKind::NotFound);
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_follower_reads_only_appended_records() {
+        let mut rng = rand::thread_rng();
+        let test_dir = format!("./{}/", rng.gen::<u32>());
+        create_dir_all(&test_dir).unwrap();
+        let options = DiskOptions {
+            wal_prefix_keys: true,
+            ..DiskOptions::default()
+        };
+        let primary = Disk::open(&test_dir, options).unwrap();
+        primary.set(b"key0", b"nginx").unwrap();
+        let follower = Follower::open(&test_dir, DiskOptions::default(), Duration::MAX).unwrap();
+
+        // Records appended to the WAL file already read are applied on top of the others,
+        // their prefix-encoded keys decoded against the last key read.
+        for i in 1..5 {
+            primary.set(format!("key{}", i).as_bytes(), b"nginx").unwrap();
+            follower.catch_up().unwrap();
+            assert_eq!(follower.scan(..).unwrap().len(), i + 1);
+        }
+        primary.delete(b"key0").unwrap();
+        follower.catch_up().unwrap();
+        follower.catch_up().unwrap();
+        assert!(follower.get(b"key0").unwrap().is_none());
+        assert_eq!(follower.scan(..).unwrap().len(), 4);
+        assert_eq!(follower.last_sequence(), primary.last_sequence());
+
+        // A flush retires the WAL file, and the remaining ones are read anew.
+        primary.flush().unwrap();
+        primary.set(b"key5", b"nginx").unwrap();
+        follower.catch_up().unwrap();
+        assert_eq!(follower.scan(..).unwrap().len(), 5);
+        assert_eq!(follower.last_sequence(), primary.last_sequence());
+        drop((follower, primary));
+
+        // Opening a single-file database would repair the container its primary writes.
+        let path = format!("{}single", test_dir);
+        let options = DiskOptions {
+            single_file: true,
+            ..DiskOptions::default()
+        };
+        Disk::open(&path, options.clone()).unwrap().set(b"Server", b"nginx").unwrap();
+        let single = Follower::open(&path, options, Duration::MAX);
+        assert_eq!(single.err().unwrap().kind(), io::ErrorKind::Unsupported);
+        let single = Follower::open(&path, DiskOptions::default(), Duration::MAX);
+        assert_eq!(single.err().unwrap().kind(), io::ErrorKind::Unsupported);
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+}
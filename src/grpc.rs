@@ -0,0 +1,499 @@
+//! gRPC service wrapping the engine, generated with tonic from `proto/fluxdb.proto`.
+//! Requires the `grpc` feature.
+//!
+//! Engine calls run on tokio's blocking thread pool, as `AsyncDisk` runs them. A call whose
+//! client set a deadline, carried in the `grpc-timeout` header, fails with
+//! `DEADLINE_EXCEEDED` once it passes; the engine call itself isn't interrupted, but its
+//! result is dropped, and a scan stops reading further pages.
+//!
+//! Besides `Scan`, which streams a range in one call, clients can page through a range
+//! across calls with the cursors of a `CursorTable`, opened by `OpenCursor`.
+
+use crate::async_disk::{as_slice, run, AsyncDisk};
+use crate::cursor::{CursorTable, DEFAULT_CURSOR_IDLE_TIMEOUT};
+use crate::disk::DiskEntry;
+use crate::error::FluxError;
+use crate::write_batch::WriteBatch;
+use proto::flux_db_server::{FluxDb, FluxDbServer};
+use proto::mutation::Op;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+
+/// Messages and service traits generated from `proto/fluxdb.proto`, including the
+/// `flux_db_client::FluxDbClient` client.
+pub mod proto {
+    tonic::include_proto!("fluxdb.v1");
+}
+
+/// Entries a scan reads from the engine at a time.
+const SCAN_PAGE: usize = 256;
+/// Entries a scan buffers ahead of a slow client.
+const SCAN_BUFFER: usize = 1024;
+
+/// The `FluxDb` gRPC service, serving one database. Requires the `grpc` feature.
+#[derive(Clone)]
+pub struct GrpcService {
+    disk: AsyncDisk,
+    cursors: Arc<CursorTable>,
+}
+
+impl GrpcService {
+    /// Creates the service for a database, closing cursors left idle for
+    /// `DEFAULT_CURSOR_IDLE_TIMEOUT`.
+    pub fn new(disk: AsyncDisk) -> GrpcService {
+        GrpcService::with_cursor_idle_timeout(disk, DEFAULT_CURSOR_IDLE_TIMEOUT)
+    }
+
+    /// Creates the service for a database, closing cursors left idle for `idle_timeout`.
+    pub fn with_cursor_idle_timeout(disk: AsyncDisk, idle_timeout: Duration) -> GrpcService {
+        let cursors = Arc::new(CursorTable::new(disk.disk().clone(), idle_timeout));
+        GrpcService { disk, cursors }
+    }
+
+    /// Wraps the service for adding to a `tonic::transport::Server`.
+    pub fn into_server(self) -> FluxDbServer<GrpcService> {
+        FluxDbServer::new(self)
+    }
+}
+
+/// Serves a database over gRPC on `addr` until the returned future is dropped.
+pub async fn serve(disk: AsyncDisk, addr: SocketAddr) -> Result<(), tonic::transport::Error> {
+    tonic::transport::Server::builder()
+        .add_service(GrpcService::new(disk).into_server())
+        .serve(addr)
+        .await
+}
+
+#[tonic::async_trait]
+impl FluxDb for GrpcService {
+    async fn get(
+        &self,
+        request: Request<proto::GetRequest>,
+    ) -> Result<Response<proto::GetResponse>, Status> {
+        let deadline = deadline(&request)?;
+        let key = request.into_inner().key;
+        let entry = within(deadline, self.disk.get(&key)).await?;
+        match entry.map_err(FluxError::from).map_err(status)? {
+            Some(entry) => Ok(Response::new(proto::GetResponse {
+                entry: Some(message(&entry)),
+            })),
+            None => Err(Status::not_found("key not found")),
+        }
+    }
+
+    async fn put(
+        &self,
+        request: Request<proto::PutRequest>,
+    ) -> Result<Response<proto::PutResponse>, Status> {
+        let deadline = deadline(&request)?;
+        let request = request.into_inner();
+        let mut batch = WriteBatch::new();
+        match request.timestamp {
+            Some(timestamp) => batch.put_at(&request.key, &request.value, timestamp as u128),
+            None => batch.put(&request.key, &request.value),
+        }
+        self.commit(batch, deadline).await?;
+        Ok(Response::new(proto::PutResponse {}))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<proto::DeleteRequest>,
+    ) -> Result<Response<proto::DeleteResponse>, Status> {
+        let deadline = deadline(&request)?;
+        let mut batch = WriteBatch::new();
+        batch.delete(&request.into_inner().key);
+        self.commit(batch, deadline).await?;
+        Ok(Response::new(proto::DeleteResponse {}))
+    }
+
+    type ScanStream = ReceiverStream<Result<proto::Entry, Status>>;
+
+    async fn scan(
+        &self,
+        request: Request<proto::ScanRequest>,
+    ) -> Result<Response<Self::ScanStream>, Status> {
+        let deadline = deadline(&request)?;
+        let request = request.into_inner();
+        let mut start = request.start.map_or(Bound::Unbounded, Bound::Included);
+        let end = request.end.map_or(Bound::Unbounded, Bound::Excluded);
+        let mut remaining = match request.limit {
+            0 => usize::MAX,
+            limit => usize::try_from(limit).unwrap_or(usize::MAX),
+        };
+        let (sender, receiver) = mpsc::channel(SCAN_BUFFER);
+        let snapshot = self.disk.disk().snapshot();
+        tokio::task::spawn_blocking(move || {
+            while remaining > 0 {
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    let _ = sender.blocking_send(Err(deadline_exceeded()));
+                    return;
+                }
+                let page_size = remaining.min(SCAN_PAGE);
+                let page = match snapshot.scan_page((as_slice(&start), as_slice(&end)), page_size) {
+                    Ok(page) => page,
+                    Err(e) => {
+                        let _ = sender.blocking_send(Err(status(e)));
+                        return;
+                    }
+                };
+                for entry in &page {
+                    // The client went away.
+                    if sender.blocking_send(Ok(message(entry))).is_err() {
+                        return;
+                    }
+                }
+                match page.last() {
+                    Some(last) if page.len() == page_size => {
+                        start = Bound::Excluded(last.key().to_vec());
+                        remaining -= page.len();
+                    }
+                    _ => return,
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn batch_write(
+        &self,
+        request: Request<proto::BatchWriteRequest>,
+    ) -> Result<Response<proto::BatchWriteResponse>, Status> {
+        let deadline = deadline(&request)?;
+        let mut batch = WriteBatch::new();
+        for mutation in request.into_inner().mutations {
+            let key = &mutation.key;
+            match (mutation.op, mutation.timestamp) {
+                (Some(Op::Put(value)), Some(timestamp)) => {
+                    batch.put_at(key, &value, timestamp as u128)
+                }
+                (Some(Op::Put(value)), None) => batch.put(key, &value),
+                (Some(Op::Delete(_)), Some(timestamp)) => batch.delete_at(key, timestamp as u128),
+                (Some(Op::Delete(_)), None) => batch.delete(key),
+                (None, _) => return Err(Status::invalid_argument("mutation without an op")),
+            }
+        }
+        let written = self.commit(batch, deadline).await?;
+        Ok(Response::new(proto::BatchWriteResponse {
+            written: written as u64,
+        }))
+    }
+
+    async fn open_cursor(
+        &self,
+        request: Request<proto::OpenCursorRequest>,
+    ) -> Result<Response<proto::OpenCursorResponse>, Status> {
+        let request = request.into_inner();
+        let start = request.start.map_or(Bound::Unbounded, Bound::Included);
+        let end = request.end.map_or(Bound::Unbounded, Bound::Excluded);
+        Ok(Response::new(proto::OpenCursorResponse {
+            cursor: self.cursors.open(start, end),
+        }))
+    }
+
+    async fn next_page(
+        &self,
+        request: Request<proto::NextPageRequest>,
+    ) -> Result<Response<proto::NextPageResponse>, Status> {
+        let deadline = deadline(&request)?;
+        let request = request.into_inner();
+        let limit = usize::try_from(request.limit).unwrap_or(usize::MAX);
+        let cursors = self.cursors.clone();
+        let page = run("fluxdb::cursor", move || {
+            cursors.next(request.cursor, limit)
+        });
+        let page = within(deadline, page).await?.map_err(status)?;
+        Ok(Response::new(proto::NextPageResponse {
+            done: page.len() < limit,
+            entries: page.iter().map(message).collect(),
+        }))
+    }
+
+    async fn close_cursor(
+        &self,
+        request: Request<proto::CloseCursorRequest>,
+    ) -> Result<Response<proto::CloseCursorResponse>, Status> {
+        Ok(Response::new(proto::CloseCursorResponse {
+            closed: self.cursors.close(request.into_inner().cursor),
+        }))
+    }
+}
+
+impl GrpcService {
+    /// Commits a batch, with the cause of a failure, unlike `Disk::write`.
+    async fn commit(&self, batch: WriteBatch, deadline: Option<Instant>) -> Result<usize, Status> {
+        let disk = self.disk.disk().clone();
+        let commit = run("fluxdb::write", move || disk.commit(batch, [], 0));
+        within(deadline, commit).await?.map_err(status)
+    }
+}
+
+/// Returns when the deadline set by the client in the `grpc-timeout` header passes, if it
+/// set one.
+#[allow(clippy::result_large_err)]
+fn deadline<T>(request: &Request<T>) -> Result<Option<Instant>, Status> {
+    let Some(timeout) = request.metadata().get("grpc-timeout") else {
+        return Ok(None);
+    };
+    let invalid = || Status::invalid_argument("malformed grpc-timeout header");
+    let timeout = timeout.to_str().map_err(|_| invalid())?;
+    if timeout.len() < 2 || timeout.len() > 9 {
+        return Err(invalid());
+    }
+    let (amount, unit) = timeout.split_at(timeout.len() - 1);
+    let amount: u64 = amount.parse().map_err(|_| invalid())?;
+    let timeout = match unit {
+        "H" => Duration::from_secs(amount * 60 * 60),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return Err(invalid()),
+    };
+    Ok(Some(Instant::now() + timeout))
+}
+
+/// Awaits `future`, failing with `DEADLINE_EXCEEDED` if the deadline passes first.
+async fn within<T>(
+    deadline: Option<Instant>,
+    future: impl Future<Output = T>,
+) -> Result<T, Status> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), future)
+            .await
+            .map_err(|_| deadline_exceeded()),
+        None => Ok(future.await),
+    }
+}
+
+fn deadline_exceeded() -> Status {
+    Status::deadline_exceeded("deadline exceeded")
+}
+
+/// Maps an engine error to the status a client can act on.
+fn status(e: FluxError) -> Status {
+    let message = e.to_string();
+    match e {
+        FluxError::Io(e) => match e.kind() {
+            io::ErrorKind::InvalidInput => Status::invalid_argument(message),
+            io::ErrorKind::InvalidData => Status::data_loss(message),
+            io::ErrorKind::ReadOnlyFilesystem => Status::unavailable(message),
+            _ => Status::internal(message),
+        },
+        FluxError::Conflict => Status::aborted(message),
+        FluxError::MemoryLimit { .. } | FluxError::Busy => Status::resource_exhausted(message),
+        FluxError::InvalidArgument(_) => Status::invalid_argument(message),
+        FluxError::Rejected(_) => Status::failed_precondition(message),
+    }
+}
+
+fn message(entry: &DiskEntry) -> proto::Entry {
+    proto::Entry {
+        key: entry.key().to_vec(),
+        value: entry.value().to_vec(),
+        timestamp: entry.timestamp() as u64,
+        sequence: entry.sequence(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::Disk;
+    use crate::options::DiskOptions;
+    use crate::storage::{MemoryBackend, Storage};
+    use crate::validation::{ForbiddenPrefix, WriteValidators};
+    use proto::flux_db_client::FluxDbClient;
+    use tonic::Code;
+
+    fn mutation(key: &[u8], op: Op, timestamp: Option<u64>) -> proto::Mutation {
+        proto::Mutation {
+            key: key.to_vec(),
+            op: Some(op),
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn test_service() {
+        let options = DiskOptions {
+            validators: WriteValidators::new().with(ForbiddenPrefix::new(b"internal/")),
+            storage: Storage::new(MemoryBackend::new()),
+            ..DiskOptions::default()
+        };
+        let disk = AsyncDisk::from_disk(Disk::open("memory", options).unwrap());
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let incoming = tokio_stream::wrappers::TcpListenerStream::new(listener);
+            let server = tonic::transport::Server::builder()
+                .add_service(GrpcService::new(disk).into_server())
+                .serve_with_incoming(incoming);
+            tokio::spawn(server);
+            let mut client = FluxDbClient::connect(format!("http://{}", addr))
+                .await
+                .unwrap();
+
+            let put = |key: &[u8], value: &[u8], timestamp| proto::PutRequest {
+                key: key.to_vec(),
+                value: value.to_vec(),
+                timestamp,
+            };
+            client
+                .put(put(b"Server", b"nginx", Some(2_000)))
+                .await
+                .unwrap();
+            let get = proto::GetRequest {
+                key: b"Server".to_vec(),
+            };
+            let entry = client.get(get).await.unwrap().into_inner().entry.unwrap();
+            assert_eq!((&entry.value[..], entry.timestamp), (&b"nginx"[..], 2_000));
+
+            let batch = proto::BatchWriteRequest {
+                mutations: vec![
+                    mutation(b"Cache", Op::Put(b"Redis".to_vec()), None),
+                    mutation(b"Database", Op::Put(b"PostgreSQL".to_vec()), None),
+                    mutation(b"Server", Op::Delete(proto::mutation::Delete {}), None),
+                ],
+            };
+            assert_eq!(
+                client
+                    .batch_write(batch)
+                    .await
+                    .unwrap()
+                    .into_inner()
+                    .written,
+                3
+            );
+            let delete = proto::DeleteRequest {
+                key: b"Cache".to_vec(),
+            };
+            client.delete(delete).await.unwrap();
+
+            for i in 0..600 {
+                let key = format!("Node{:03}", i);
+                client.put(put(key.as_bytes(), b"up", None)).await.unwrap();
+            }
+            let scan = |start: Option<&[u8]>, end: Option<&[u8]>, limit| proto::ScanRequest {
+                start: start.map(<[u8]>::to_vec),
+                end: end.map(<[u8]>::to_vec),
+                limit,
+            };
+            let mut stream = client.scan(scan(None, None, 0)).await.unwrap().into_inner();
+            let mut keys = Vec::new();
+            while let Some(entry) = stream.message().await.unwrap() {
+                keys.push(entry.key);
+            }
+            assert_eq!(keys.len(), 601);
+            assert_eq!(
+                (&keys[0][..], &keys[600][..]),
+                (&b"Database"[..], &b"Node599"[..])
+            );
+            let mut stream = client
+                .scan(scan(Some(b"Node"), Some(b"Node3"), 300))
+                .await
+                .unwrap()
+                .into_inner();
+            let mut count = 0;
+            while stream.message().await.unwrap().is_some() {
+                count += 1;
+            }
+            assert_eq!(count, 300);
+
+            // A cursor pages through the range as it was when opened, across calls.
+            let open = proto::OpenCursorRequest {
+                start: Some(b"Node5".to_vec()),
+                end: None,
+            };
+            let cursor = client.open_cursor(open).await.unwrap().into_inner().cursor;
+            client.put(put(b"Node999", b"up", None)).await.unwrap();
+            let next = |limit| proto::NextPageRequest { cursor, limit };
+            let page = client.next_page(next(60)).await.unwrap().into_inner();
+            assert_eq!((page.entries.len(), page.done), (60, false));
+            assert_eq!(&page.entries[0].key[..], b"Node500");
+            let page = client.next_page(next(60)).await.unwrap().into_inner();
+            assert_eq!((page.entries.len(), page.done), (40, true));
+            assert_eq!(&page.entries[39].key[..], b"Node599");
+            let closed = client.next_page(next(60)).await.unwrap_err();
+            assert_eq!(closed.code(), Code::InvalidArgument);
+            let open = proto::OpenCursorRequest::default();
+            let cursor = client.open_cursor(open).await.unwrap().into_inner().cursor;
+            let close = proto::CloseCursorRequest { cursor };
+            assert!(
+                client
+                    .close_cursor(close)
+                    .await
+                    .unwrap()
+                    .into_inner()
+                    .closed
+            );
+
+            // Failures come with their status codes.
+            let missing = client
+                .get(proto::GetRequest {
+                    key: b"Server".to_vec(),
+                })
+                .await;
+            assert_eq!(missing.unwrap_err().code(), Code::NotFound);
+            client
+                .put(put(b"Clock", b"now", Some(2_000)))
+                .await
+                .unwrap();
+            let stale = client.put(put(b"Clock", b"then", Some(1_000))).await;
+            assert_eq!(stale.unwrap_err().code(), Code::InvalidArgument);
+            let rejected = client.put(put(b"internal/key", b"", None)).await;
+            assert_eq!(rejected.unwrap_err().code(), Code::FailedPrecondition);
+            let empty = proto::BatchWriteRequest {
+                mutations: vec![proto::Mutation::default()],
+            };
+            assert_eq!(
+                client.batch_write(empty).await.unwrap_err().code(),
+                Code::InvalidArgument
+            );
+        });
+    }
+
+    #[test]
+    fn test_deadlines() {
+        let mut request = Request::new(());
+        assert!(deadline(&request).unwrap().is_none());
+        request
+            .metadata_mut()
+            .insert("grpc-timeout", "250m".parse().unwrap());
+        let remaining = deadline(&request).unwrap().unwrap() - Instant::now();
+        assert!(remaining > Duration::from_millis(200) && remaining <= Duration::from_millis(250));
+        request
+            .metadata_mut()
+            .insert("grpc-timeout", "1x".parse().unwrap());
+        assert_eq!(
+            deadline(&request).unwrap_err().code(),
+            tonic::Code::InvalidArgument
+        );
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let passed = Some(Instant::now());
+            let slow = tokio::time::sleep(Duration::from_secs(1));
+            assert_eq!(
+                within(passed, slow).await.unwrap_err().code(),
+                tonic::Code::DeadlineExceeded
+            );
+            assert_eq!(within(None, async { 7 }).await.unwrap(), 7);
+        });
+    }
+}
@@ -0,0 +1,114 @@
+use crate::arena::ArenaBytes;
+use crate::comparator::KeyOrder;
+use crate::mem_table::{insert_version, InMemoryRecord, MemTable};
+use std::collections::HashMap;
+use std::ops::Bound;
+
+/// Records of a memtable in a vector in the order their keys were first written, with a
+/// hash index from each key to its place. Writes and lookups take constant time, while
+/// scans sort the keys they read.
+pub struct HashIndex {
+    index: HashMap<ArenaBytes, usize>,
+    /// Versions of each key, newest first.
+    keys: Vec<Vec<InMemoryRecord>>,
+    records: usize,
+    order: KeyOrder,
+}
+
+impl HashIndex {
+    pub fn new(order: KeyOrder) -> HashIndex {
+        HashIndex {
+            index: HashMap::new(),
+            keys: Vec::new(),
+            records: 0,
+            order,
+        }
+    }
+}
+
+impl MemTable for HashIndex {
+    fn insert(&mut self, record: InMemoryRecord) -> Option<InMemoryRecord> {
+        let replaced = match self.index.get(&record.key) {
+            Some(&position) => insert_version(&mut self.keys[position], record),
+            None => {
+                self.index.insert(record.key.clone(), self.keys.len());
+                self.keys.push(vec![record]);
+                None
+            }
+        };
+        if replaced.is_none() {
+            self.records += 1;
+        }
+        replaced
+    }
+
+    fn remove(&mut self, key: &[u8], index: usize) -> InMemoryRecord {
+        // A key left without versions keeps its place; lookups and scans skip it.
+        let position = self.index[key];
+        self.records -= 1;
+        self.keys[position].remove(index)
+    }
+
+    fn fetch(&self, key: &[u8]) -> &[InMemoryRecord] {
+        match self.index.get(key) {
+            Some(&position) => &self.keys[position],
+            None => &[],
+        }
+    }
+
+    fn iter<'a>(
+        &'a self,
+        bounds: (Bound<&[u8]>, Bound<&[u8]>),
+    ) -> Box<dyn Iterator<Item = &'a [InMemoryRecord]> + 'a> {
+        let mut keys: Vec<&[InMemoryRecord]> = self
+            .keys
+            .iter()
+            .filter(|versions| {
+                versions
+                    .first()
+                    .is_some_and(|newest| self.order.contains(&bounds, &newest.key))
+            })
+            .map(Vec::as_slice)
+            .collect();
+        keys.sort_unstable_by(|a, b| self.order.compare(&a[0].key, &b[0].key));
+        Box::new(keys.into_iter())
+    }
+
+    fn size(&self) -> usize {
+        self.records
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_sorts_keys() {
+        let mut table = HashIndex::new(KeyOrder::default());
+        for (sequence, key) in [&b"SDK"[..], b"API", b"CLI", b"API"]
+            .into_iter()
+            .enumerate()
+        {
+            table.insert(InMemoryRecord {
+                key: ArenaBytes::from(key),
+                value: None,
+                timestamp: 0,
+                is_deleted: true,
+                sequence: sequence as u64,
+                schema: 0,
+                backdated: false,
+            });
+        }
+        assert_eq!(table.size(), 4);
+        assert_eq!(table.fetch(b"API").len(), 2);
+        assert_eq!(table.fetch(b"API")[0].sequence, 3);
+
+        let bounds = (Bound::Included(&b"B"[..]), Bound::Unbounded);
+        let keys: Vec<&[u8]> = table
+            .iter(bounds)
+            .map(|versions| &versions[0].key[..])
+            .collect();
+        assert_eq!(keys, [&b"CLI"[..], b"SDK"]);
+    }
+}
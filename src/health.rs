@@ -0,0 +1,391 @@
+//! Read-only serving for a data directory that stops accepting writes, as when a volume is
+//! remounted read-only underneath the process.
+//!
+//! Every write an open database makes to its directory goes through a `GuardedBackend`. The
+//! first one failing with `EROFS` or `EACCES` turns the database read-only: reads carry on,
+//! writes fail right away with `ErrorKind::ReadOnlyFilesystem` without reaching the
+//! directory, and the watchers are told. The background thread then probes the directory
+//! until it accepts writes again, and resumes them.
+
+use crate::logging::{Level, Logger, Subsystem};
+use crate::storage::{Storage, StorageBackend, StorageFile, StorageLock};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// File written and removed to find out whether the directory accepts writes again.
+const PROBE_FILE: &str = "WRITE-PROBE";
+
+/// Whether a database accepts writes, as returned by `Disk::health` and sent to the
+/// receivers of `Disk::watch_health`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Health {
+    Writable,
+    /// The directory stopped accepting writes; holds the error that showed it.
+    ReadOnly(String),
+}
+
+/// Tracks whether the directory of a database accepts writes.
+pub(crate) struct HealthMonitor {
+    /// The storage of the database, unguarded.
+    storage: Storage,
+    logger: Logger,
+    /// Set while writes are refused. Checked on every write, without taking `health`.
+    read_only: AtomicBool,
+    health: Mutex<Health>,
+    watchers: Mutex<Vec<Sender<Health>>>,
+    /// Called after turning read-only, to have the background thread start probing.
+    on_read_only: OnceLock<Box<dyn Fn() + Send + Sync>>,
+}
+
+impl HealthMonitor {
+    pub(crate) fn new(storage: Storage, logger: Logger) -> Arc<HealthMonitor> {
+        Arc::new(HealthMonitor {
+            storage,
+            logger,
+            read_only: AtomicBool::new(false),
+            health: Mutex::new(Health::Writable),
+            watchers: Mutex::new(Vec::new()),
+            on_read_only: OnceLock::new(),
+        })
+    }
+
+    /// Returns the storage to hand the database: the unguarded one, with every write checked
+    /// and its failure watched.
+    pub(crate) fn guard(self: &Arc<HealthMonitor>) -> Storage {
+        Storage::new(GuardedBackend {
+            monitor: self.clone(),
+        })
+    }
+
+    pub(crate) fn on_read_only(&self, f: impl Fn() + Send + Sync + 'static) {
+        let _ = self.on_read_only.set(Box::new(f));
+    }
+
+    pub(crate) fn health(&self) -> Health {
+        self.health.lock().unwrap().clone()
+    }
+
+    pub(crate) fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn watch(&self) -> Receiver<Health> {
+        let (sender, receiver) = mpsc::channel();
+        self.watchers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Writes and removes a file in `dir`, bypassing the guard, to find out whether the
+    /// directory accepts writes again.
+    pub(crate) fn probe(&self, dir: &Path) -> io::Result<()> {
+        let path = dir.join(PROBE_FILE);
+        // Left over from a probe interrupted by a crash.
+        if self.storage.exists(&path) {
+            self.storage.delete(&path)?;
+        }
+        let mut file = self.storage.create(&path)?;
+        file.append(b"probe")?;
+        file.sync()?;
+        drop(file);
+        self.storage.delete(&path)
+    }
+
+    /// Lets writes through again to run `reopen`, which prepares the database for them, and
+    /// reports the database writable if it succeeds. Otherwise writes stay refused.
+    pub(crate) fn resume_with(&self, reopen: impl FnOnce() -> io::Result<()>) -> io::Result<()> {
+        self.read_only.store(false, Ordering::Release);
+        if let Err(e) = reopen() {
+            self.read_only.store(true, Ordering::Release);
+            return Err(e);
+        }
+        let message = format_args!("the data directory accepts writes again, resuming them");
+        self.logger.log(Level::Info, Subsystem::Storage, message);
+        *self.health.lock().unwrap() = Health::Writable;
+        self.notify(Health::Writable);
+        Ok(())
+    }
+
+    /// Fails a write while the database is read-only.
+    fn check(&self) -> io::Result<()> {
+        if !self.is_read_only() {
+            return Ok(());
+        }
+        let Health::ReadOnly(reason) = self.health() else {
+            return Ok(());
+        };
+        Err(io::Error::new(
+            io::ErrorKind::ReadOnlyFilesystem,
+            format!("database is read-only: {}", reason),
+        ))
+    }
+
+    /// Turns the database read-only if a write failed because the directory no longer
+    /// accepts writes.
+    fn observe<T>(&self, result: io::Result<T>) -> io::Result<T> {
+        if let Err(e) = &result {
+            let kind = e.kind();
+            if kind == io::ErrorKind::ReadOnlyFilesystem || kind == io::ErrorKind::PermissionDenied
+            {
+                self.enter_read_only(e);
+            }
+        }
+        result
+    }
+
+    fn enter_read_only(&self, e: &io::Error) {
+        let health = {
+            let mut health = self.health.lock().unwrap();
+            self.read_only.store(true, Ordering::Release);
+            // Already read-only, or resuming and about to be again.
+            if let Health::ReadOnly(_) = *health {
+                return;
+            }
+            *health = Health::ReadOnly(e.to_string());
+            health.clone()
+        };
+        let message = format_args!(
+            "the data directory stopped accepting writes, serving reads only: {}",
+            e
+        );
+        self.logger.log(Level::Error, Subsystem::Storage, message);
+        self.notify(health);
+        if let Some(f) = self.on_read_only.get() {
+            f();
+        }
+    }
+
+    /// Sends a change of health to the watchers, dropping those whose receiver is gone.
+    fn notify(&self, health: Health) {
+        let mut watchers = self.watchers.lock().unwrap();
+        watchers.retain(|watcher| watcher.send(health.clone()).is_ok());
+    }
+}
+
+/// Storage of an open database, checking every write against its `HealthMonitor`.
+struct GuardedBackend {
+    monitor: Arc<HealthMonitor>,
+}
+
+impl GuardedBackend {
+    fn write<T>(&self, write: impl FnOnce(&Storage) -> io::Result<T>) -> io::Result<T> {
+        self.monitor.check()?;
+        self.monitor.observe(write(&self.monitor.storage))
+    }
+
+    fn wrap(&self, file: Box<dyn StorageFile>) -> Box<dyn StorageFile> {
+        Box::new(GuardedFile {
+            file,
+            monitor: self.monitor.clone(),
+        })
+    }
+}
+
+impl StorageBackend for GuardedBackend {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(self.wrap(self.write(|storage| storage.create(path))?))
+    }
+
+    fn append(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(self.wrap(self.write(|storage| storage.append(path))?))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        self.monitor.storage.open(path)
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        self.write(|storage| storage.delete(path))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.write(|storage| storage.rename(from, to))
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.monitor.storage.list(dir)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.monitor.storage.exists(path)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        self.write(|storage| storage.sync_dir(dir))
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<StorageLock> {
+        self.write(|storage| storage.lock(path))
+    }
+
+    fn is_local(&self) -> bool {
+        self.monitor.storage.is_local()
+    }
+}
+
+struct GuardedFile {
+    file: Box<dyn StorageFile>,
+    monitor: Arc<HealthMonitor>,
+}
+
+impl StorageFile for GuardedFile {
+    fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        self.monitor.check()?;
+        self.monitor.observe(self.file.append(data))
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.file.read_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.file.size()
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.monitor.check()?;
+        self.monitor.observe(self.file.sync())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::Disk;
+    use crate::error::FluxError;
+    use crate::options::DiskOptions;
+    use crate::storage::MemoryBackend;
+    use crate::write_batch::WriteBatch;
+    use std::time::Duration;
+
+    /// Memory storage whose writes fail with `EROFS` while `read_only` is set, as on a
+    /// volume remounted read-only.
+    struct RemountableBackend {
+        files: MemoryBackend,
+        read_only: Arc<AtomicBool>,
+    }
+
+    struct RemountableFile {
+        file: Box<dyn StorageFile>,
+        read_only: Arc<AtomicBool>,
+    }
+
+    fn check(read_only: &AtomicBool) -> io::Result<()> {
+        match read_only.load(Ordering::SeqCst) {
+            true => Err(io::Error::from(io::ErrorKind::ReadOnlyFilesystem)),
+            false => Ok(()),
+        }
+    }
+
+    impl StorageFile for RemountableFile {
+        fn append(&mut self, data: &[u8]) -> io::Result<()> {
+            check(&self.read_only)?;
+            self.file.append(data)
+        }
+
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            self.file.read_at(buf, offset)
+        }
+
+        fn size(&self) -> io::Result<u64> {
+            self.file.size()
+        }
+
+        fn sync(&self) -> io::Result<()> {
+            check(&self.read_only)
+        }
+    }
+
+    impl RemountableBackend {
+        fn wrap(&self, file: Box<dyn StorageFile>) -> Box<dyn StorageFile> {
+            let read_only = self.read_only.clone();
+            Box::new(RemountableFile { file, read_only })
+        }
+    }
+
+    impl StorageBackend for RemountableBackend {
+        fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+            check(&self.read_only)?;
+            Ok(self.wrap(self.files.create(path)?))
+        }
+
+        fn append(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+            check(&self.read_only)?;
+            Ok(self.wrap(self.files.append(path)?))
+        }
+
+        fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+            self.files.open(path)
+        }
+
+        fn delete(&self, path: &Path) -> io::Result<()> {
+            check(&self.read_only)?;
+            self.files.delete(path)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            check(&self.read_only)?;
+            self.files.rename(from, to)
+        }
+
+        fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+            self.files.list(dir)
+        }
+
+        fn exists(&self, path: &Path) -> bool {
+            self.files.exists(path)
+        }
+
+        fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+            check(&self.read_only)?;
+            self.files.sync_dir(dir)
+        }
+    }
+
+    #[test]
+    fn test_read_only_directory() {
+        let read_only = Arc::new(AtomicBool::new(false));
+        let backend = RemountableBackend {
+            files: MemoryBackend::new(),
+            read_only: read_only.clone(),
+        };
+        let options = DiskOptions {
+            storage: Storage::new(backend),
+            wal_prefix_keys: true,
+            ..DiskOptions::default()
+        };
+        let disk = Disk::open("remountable", options.clone()).unwrap();
+        let health = disk.watch_health();
+        disk.set(b"Server", b"nginx").unwrap();
+        assert_eq!(disk.health(), Health::Writable);
+
+        read_only.store(true, Ordering::SeqCst);
+        assert!(disk.set(b"Server2", b"apache").is_err());
+        assert!(matches!(health.recv().unwrap(), Health::ReadOnly(_)));
+        assert!(matches!(disk.health(), Health::ReadOnly(_)));
+
+        // Reads carry on, and writes fail without reaching the directory.
+        assert_eq!(disk.get(b"Server").unwrap().unwrap().value(), b"nginx");
+        let mut batch = WriteBatch::new();
+        batch.put(b"Cache", b"Redis");
+        let Err(FluxError::Io(e)) = disk.commit(batch, [], 0) else {
+            panic!("write to a read-only database succeeded");
+        };
+        assert_eq!(e.kind(), io::ErrorKind::ReadOnlyFilesystem);
+        assert!(e.to_string().starts_with("database is read-only"));
+
+        read_only.store(false, Ordering::SeqCst);
+        let resumed = health.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(resumed, Health::Writable);
+        disk.set(b"Server3", b"caddy").unwrap();
+        drop(disk);
+
+        // The failed write was left out of the WAL, and the writes after it replay.
+        let disk = Disk::open("remountable", options).unwrap();
+        assert!(disk.get(b"Server2").unwrap().is_none());
+        assert!(disk.get(b"Cache").unwrap().is_none());
+        assert_eq!(disk.get(b"Server3").unwrap().unwrap().value(), b"caddy");
+    }
+}
@@ -0,0 +1,417 @@
+//! HTTP endpoints for a database, for debugging with curl and for lightweight integrations
+//! that don't want a client library. Requires the `http` feature.
+//!
+//! | Request                          | Response                                             |
+//! |----------------------------------|------------------------------------------------------|
+//! | `GET /kv/{key}`                  | The value, `404` if the key is missing               |
+//! | `PUT /kv/{key}`                  | Sets the key to the request body, `204`              |
+//! | `DELETE /kv/{key}`               | Deletes the key, `204`                               |
+//! | `GET /scan?prefix=..&limit=..`   | `key\tvalue` lines of the keys starting with prefix  |
+//! | `POST /cursors?prefix=..`        | Id of a cursor over the keys starting with prefix    |
+//! | `GET /cursors/{id}?limit=..`     | The next page of the cursor, as `key\tvalue` lines   |
+//! | `DELETE /cursors/{id}`           | Closes the cursor, `204`, `404` if it isn't open     |
+//! | `GET /stats`                     | The statistics, in the Prometheus text format        |
+//! | `POST /compact`                  | Compacts every segment into one, `204`               |
+//!
+//! Keys are taken from the rest of the path, so they may contain `/`; other bytes, whether
+//! UTF-8 or not, can be percent-encoded, as can the bytes of a `prefix`. `GET /kv` also
+//! returns the timestamp and sequence number of the value in the `x-fluxdb-timestamp` and
+//! `x-fluxdb-sequence` headers. Scans escape keys and values as the `fluxdb` command line
+//! does, and return 1000 entries unless `limit` says otherwise. Failed writes answer with a
+//! status code telling why, as the gRPC service does, and the error in the body.
+//!
+//! A cursor reads the keys as they were when it was opened, so a client can page through
+//! them across requests. Pages hold 100 entries unless `limit` says otherwise; the cursor
+//! is closed once a page comes back shorter than its limit, and after five minutes unused.
+
+use crate::async_disk::{run, AsyncDisk};
+use crate::cursor::{CursorTable, DEFAULT_CURSOR_IDLE_TIMEOUT};
+use crate::disk::DiskEntry;
+use crate::error::FluxError;
+use crate::prefix::prefix_end;
+use crate::write_batch::{WriteBatch, MAX_BATCH_BYTES};
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Extension, Path, RawQuery, State};
+use axum::http::{header, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::Router;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Bound;
+use std::sync::Arc;
+
+/// Entries in a cursor page when the request doesn't give a limit.
+const DEFAULT_CURSOR_PAGE: usize = 100;
+/// Entries a scan returns when the request doesn't give a limit.
+const DEFAULT_SCAN_LIMIT: usize = 1000;
+
+/// Returns the routes serving `disk`, for nesting in a server of your own.
+pub fn router(disk: AsyncDisk) -> Router {
+    Router::new()
+        .route("/kv/*key", get(get_key).put(put_key).delete(delete_key))
+        .route("/scan", get(scan))
+        .route("/cursors", post(open_cursor))
+        .route("/cursors/:id", get(next_page).delete(close_cursor))
+        .route("/stats", get(stats))
+        .route("/compact", post(compact))
+        .layer(DefaultBodyLimit::max(MAX_BATCH_BYTES))
+        .layer(Extension(Arc::new(CursorTable::new(
+            disk.disk().clone(),
+            DEFAULT_CURSOR_IDLE_TIMEOUT,
+        ))))
+        .with_state(disk)
+}
+
+/// Serves a database over HTTP on `addr` until the returned future is dropped.
+pub async fn serve(disk: AsyncDisk, addr: SocketAddr) -> io::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    axum::serve(listener, router(disk)).await
+}
+
+async fn get_key(State(disk): State<AsyncDisk>, uri: Uri) -> Response {
+    match disk.get(&path_key(&uri)).await {
+        Ok(Some(entry)) => {
+            let headers = [
+                (header::CONTENT_TYPE, "application/octet-stream".to_owned()),
+                (
+                    header::HeaderName::from_static("x-fluxdb-timestamp"),
+                    entry.timestamp().to_string(),
+                ),
+                (
+                    header::HeaderName::from_static("x-fluxdb-sequence"),
+                    entry.sequence().to_string(),
+                ),
+            ];
+            (headers, entry.value().to_vec()).into_response()
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, "key not found\n").into_response(),
+        Err(e) => error(e.into()),
+    }
+}
+
+async fn put_key(State(disk): State<AsyncDisk>, uri: Uri, value: Bytes) -> Response {
+    let mut batch = WriteBatch::new();
+    batch.put(&path_key(&uri), &value);
+    commit(&disk, batch).await
+}
+
+async fn delete_key(State(disk): State<AsyncDisk>, uri: Uri) -> Response {
+    let mut batch = WriteBatch::new();
+    batch.delete(&path_key(&uri));
+    commit(&disk, batch).await
+}
+
+/// Commits a batch, with the cause of a failure, unlike `Disk::write`.
+async fn commit(disk: &AsyncDisk, batch: WriteBatch) -> Response {
+    let disk = disk.disk().clone();
+    match run("fluxdb::write", move || disk.commit(batch, [], 0)).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error(e),
+    }
+}
+
+async fn scan(State(disk): State<AsyncDisk>, RawQuery(query): RawQuery) -> Response {
+    let params = params(query.as_deref());
+    let prefix = params.get("prefix").cloned().unwrap_or_default();
+    let Some(limit) = limit(&params, DEFAULT_SCAN_LIMIT) else {
+        return (StatusCode::BAD_REQUEST, "limit isn't a number\n").into_response();
+    };
+    let end = prefix_bound(&prefix);
+    let disk = disk.disk().clone();
+    let entries = run("fluxdb::scan", move || {
+        let range = (
+            Bound::Included(prefix.as_slice()),
+            end.as_ref().map(Vec::as_slice),
+        );
+        disk.snapshot().scan_page(range, limit)
+    });
+    match entries.await {
+        Ok(entries) => lines(&entries),
+        Err(e) => error(e),
+    }
+}
+
+async fn open_cursor(
+    Extension(cursors): Extension<Arc<CursorTable>>,
+    RawQuery(query): RawQuery,
+) -> Response {
+    let prefix = params(query.as_deref())
+        .remove("prefix")
+        .unwrap_or_default();
+    let end = prefix_bound(&prefix);
+    let id = cursors.open(Bound::Included(prefix), end);
+    format!("{}\n", id).into_response()
+}
+
+async fn next_page(
+    Extension(cursors): Extension<Arc<CursorTable>>,
+    Path(id): Path<u64>,
+    RawQuery(query): RawQuery,
+) -> Response {
+    let Some(limit) = limit(&params(query.as_deref()), DEFAULT_CURSOR_PAGE) else {
+        return (StatusCode::BAD_REQUEST, "limit isn't a number\n").into_response();
+    };
+    match run("fluxdb::cursor", move || cursors.next(id, limit)).await {
+        Ok(entries) => lines(&entries),
+        Err(e) => error(e),
+    }
+}
+
+async fn close_cursor(
+    Extension(cursors): Extension<Arc<CursorTable>>,
+    Path(id): Path<u64>,
+) -> Response {
+    if cursors.close(id) {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        (StatusCode::NOT_FOUND, "cursor not found\n").into_response()
+    }
+}
+
+/// Returns the key of a `/kv/{key}` request, percent-decoded into the bytes it stands for.
+fn path_key(uri: &Uri) -> Vec<u8> {
+    let path = uri.path();
+    percent_decode(path.strip_prefix("/kv/").unwrap_or(path), false)
+}
+
+/// Returns the parameters of a query string, each value percent-decoded into the bytes it
+/// stands for.
+fn params(query: Option<&str>) -> HashMap<String, Vec<u8>> {
+    let pairs = query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty());
+    pairs
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let name = String::from_utf8_lossy(&percent_decode(name, true)).into_owned();
+            (name, percent_decode(value, true))
+        })
+        .collect()
+}
+
+/// Decodes the `%XX` escapes of a URL component, leaving malformed ones as they are, and
+/// `+` as a space where `plus_as_space`, as in query strings.
+fn percent_decode(text: &str, plus_as_space: bool) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = |at: usize| {
+            bytes
+                .get(at)
+                .and_then(|&digit| (digit as char).to_digit(16))
+        };
+        match bytes[i] {
+            b'%' if hex(i + 1).is_some() && hex(i + 2).is_some() => {
+                decoded.push((hex(i + 1).unwrap() * 16 + hex(i + 2).unwrap()) as u8);
+                i += 3;
+                continue;
+            }
+            b'+' if plus_as_space => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    decoded
+}
+
+/// Returns the `limit` parameter, `default` without one, or `None` if it isn't a number.
+fn limit(params: &HashMap<String, Vec<u8>>, default: usize) -> Option<usize> {
+    match params.get("limit") {
+        None => Some(default),
+        Some(limit) => std::str::from_utf8(limit).ok()?.parse().ok(),
+    }
+}
+
+/// Returns the end of the keys starting with `prefix`.
+fn prefix_bound(prefix: &[u8]) -> Bound<Vec<u8>> {
+    match prefix_end(prefix) {
+        Some(end) => Bound::Excluded(end),
+        None => Bound::Unbounded,
+    }
+}
+
+/// Answers with a `key\tvalue` line per entry.
+fn lines(entries: &[DiskEntry]) -> Response {
+    let mut body = String::new();
+    for entry in entries {
+        let _ = writeln!(body, "{}\t{}", escape(entry.key()), escape(entry.value()));
+    }
+    body.into_response()
+}
+
+async fn stats(State(disk): State<AsyncDisk>) -> Response {
+    let metrics = disk.disk().prometheus_metrics();
+    (
+        [(
+            header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )],
+        metrics,
+    )
+        .into_response()
+}
+
+async fn compact(State(disk): State<AsyncDisk>) -> Response {
+    let disk = disk.disk().clone();
+    match run("fluxdb::compact", move || disk.compact()).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error(e.into()),
+    }
+}
+
+/// Answers with the status code telling why a call failed, and the error in the body.
+fn error(e: FluxError) -> Response {
+    let status = match &e {
+        FluxError::Io(e) => match e.kind() {
+            io::ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+            io::ErrorKind::ReadOnlyFilesystem => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        },
+        FluxError::Conflict => StatusCode::CONFLICT,
+        // The read asked for more than it may hold; a narrower one may succeed.
+        FluxError::MemoryLimit { .. } => StatusCode::BAD_REQUEST,
+        FluxError::InvalidArgument(_) => StatusCode::BAD_REQUEST,
+        FluxError::Rejected(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        FluxError::Busy => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, format!("{}\n", e)).into_response()
+}
+
+fn escape(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .flat_map(|&byte| std::ascii::escape_default(byte))
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::Disk;
+    use crate::options::DiskOptions;
+    use crate::storage::{MemoryBackend, Storage};
+    use crate::validation::{ForbiddenPrefix, WriteValidators};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    fn request(addr: SocketAddr, method: &str, target: &str, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            target,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, response)
+    }
+
+    fn body(response: &str) -> &str {
+        response.split_once("\r\n\r\n").map_or("", |(_, body)| body)
+    }
+
+    #[test]
+    fn test_endpoints() {
+        let options = DiskOptions {
+            validators: WriteValidators::new().with(ForbiddenPrefix::new(b"internal/")),
+            storage: Storage::new(MemoryBackend::new()),
+            ..DiskOptions::default()
+        };
+        let disk = AsyncDisk::from_disk(Disk::open("memory", options).unwrap());
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let listener = runtime
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = router(disk.clone());
+        std::thread::spawn(move || runtime.block_on(async { axum::serve(listener, app).await }));
+
+        assert_eq!(request(addr, "PUT", "/kv/Server", "nginx").0, 204);
+        assert_eq!(
+            request(addr, "PUT", "/kv/config/cache%20size", "64MB").0,
+            204
+        );
+        assert_eq!(request(addr, "PUT", "/kv/config/proxy", "on\n").0, 204);
+        let (status, response) = request(addr, "GET", "/kv/Server", "");
+        assert_eq!((status, body(&response)), (200, "nginx"));
+        assert!(response.contains("x-fluxdb-sequence: 1\r\n"));
+        let (status, response) = request(addr, "GET", "/kv/config/cache%20size", "");
+        assert_eq!((status, body(&response)), (200, "64MB"));
+        assert_eq!(request(addr, "DELETE", "/kv/Server", "").0, 204);
+        assert_eq!(request(addr, "GET", "/kv/Server", "").0, 404);
+
+        let (status, response) = request(addr, "GET", "/scan?prefix=config/", "");
+        assert_eq!(status, 200);
+        assert_eq!(
+            body(&response),
+            "config/cache size\t64MB\nconfig/proxy\ton\\n\n"
+        );
+        let (_, response) = request(addr, "GET", "/scan?prefix=config/&limit=1", "");
+        assert_eq!(body(&response), "config/cache size\t64MB\n");
+        assert_eq!(request(addr, "GET", "/scan?limit=many", "").0, 400);
+
+        // Keys and prefixes needn't be UTF-8, and scans are limited by default.
+        assert_eq!(request(addr, "PUT", "/kv/raw/%FF%00", "binary").0, 204);
+        let (status, response) = request(addr, "GET", "/kv/raw/%ff%00", "");
+        assert_eq!((status, body(&response)), (200, "binary"));
+        let (_, response) = request(addr, "GET", "/scan?prefix=raw%2F%FF", "");
+        assert_eq!(body(&response), "raw/\\xff\\x00\tbinary\n");
+        for i in 0..DEFAULT_SCAN_LIMIT {
+            disk.disk()
+                .set(format!("scan/{:04}", i).as_bytes(), b"")
+                .unwrap();
+        }
+        let (_, response) = request(addr, "GET", "/scan", "");
+        assert_eq!(body(&response).lines().count(), DEFAULT_SCAN_LIMIT);
+        let (_, response) = request(addr, "GET", "/scan?limit=2000", "");
+        assert_eq!(body(&response).lines().count(), DEFAULT_SCAN_LIMIT + 3);
+
+        // A cursor pages through the keys as they were when it was opened.
+        let (status, response) = request(addr, "POST", "/cursors?prefix=config/", "");
+        assert_eq!(status, 200);
+        let page = format!("/cursors/{}?limit=1", body(&response).trim());
+        assert_eq!(request(addr, "PUT", "/kv/config/ttl", "60").0, 204);
+        let (_, response) = request(addr, "GET", &page, "");
+        assert_eq!(body(&response), "config/cache size\t64MB\n");
+        let (_, response) = request(addr, "GET", &page, "");
+        assert_eq!(body(&response), "config/proxy\ton\\n\n");
+        let (_, response) = request(addr, "GET", &page, "");
+        assert_eq!(body(&response), "");
+        assert_eq!(request(addr, "GET", &page, "").0, 400);
+        let (_, response) = request(addr, "POST", "/cursors", "");
+        let cursor = format!("/cursors/{}", body(&response).trim());
+        assert_eq!(
+            request(addr, "GET", &format!("{}?limit=0", cursor), "").0,
+            400
+        );
+        assert_eq!(request(addr, "DELETE", &cursor, "").0, 204);
+        assert_eq!(request(addr, "DELETE", &cursor, "").0, 404);
+
+        // Failures come with their status codes.
+        let (status, response) = request(addr, "PUT", "/kv/internal/key", "");
+        assert_eq!(status, 422);
+        assert!(body(&response).contains("internal/"));
+        assert_eq!(request(addr, "POST", "/kv/Server", "").0, 405);
+        let status = error(FluxError::MemoryLimit { limit: 1024 }).status();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        assert_eq!(request(addr, "POST", "/compact", "").0, 204);
+        assert_eq!(disk.disk().segment_files().len(), 1);
+        let (status, response) = request(addr, "GET", "/stats", "");
+        assert_eq!(status, 200);
+        assert!(body(&response).contains("fluxdb_compactions_total 1\n"));
+    }
+}
@@ -0,0 +1,287 @@
+use crate::subscription::{Change, ChangeKind};
+use std::collections::BTreeSet;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
+
+/// Number of distinct invalidations a feed holds before collapsing them into
+/// `Invalidation::All`, so a consumer that stops reading can't grow its feed without bound.
+pub const MAX_PENDING_INVALIDATIONS: usize = 65536;
+
+/// Something an external cache should drop.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Invalidation {
+    /// A key that was written or deleted.
+    Key(Vec<u8>),
+    /// Every key starting with these bytes, reported by prefix granular feeds.
+    Prefix(Vec<u8>),
+    /// Every key from `start` up to, but not including, `end`, removed by a range delete.
+    Range { start: Vec<u8>, end: Vec<u8> },
+    /// Everything, reported instead of the individual invalidations once a feed has more than
+    /// `MAX_PENDING_INVALIDATIONS` of them pending.
+    All,
+}
+
+/// How finely a feed reports the keys written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Granularity {
+    /// Each key written is reported.
+    Key,
+    /// Keys are reported by their first `n` bytes, so writes to many keys sharing a prefix
+    /// coalesce into one invalidation.
+    Prefix(usize),
+}
+
+/// The invalidations coalesced over one window.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidationBatch {
+    /// Sequence number of the last write the batch covers. Every write up to it is either in
+    /// this batch or in an earlier one.
+    pub sequence: u64,
+    /// Invalidations, deduplicated and in order.
+    pub invalidations: Vec<Invalidation>,
+}
+
+/// Receives the invalidations of a database, batched and deduplicated.
+///
+/// Writers only add their keys to the pending set of each feed; batches are cut when the
+/// consumer asks for them, once `window` has passed since the first pending write, so a key
+/// written many times within a window is reported once. This is much lighter than a full
+/// change feed: values aren't kept and nothing is read back from the database.
+pub struct InvalidationFeed {
+    state: Arc<FeedState>,
+}
+
+struct FeedState {
+    granularity: Granularity,
+    window: Duration,
+    pending: Mutex<Pending>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct Pending {
+    invalidations: BTreeSet<Invalidation>,
+    sequence: u64,
+    /// When the first of the pending invalidations was added.
+    since: Option<Instant>,
+    closed: bool,
+}
+
+impl InvalidationFeed {
+    /// Waits for the next batch. Returns `None` once the database is closed and every
+    /// invalidation has been received.
+    pub fn recv(&self) -> Option<InvalidationBatch> {
+        self.recv_until(None)
+    }
+
+    /// Waits at most `timeout` for the next batch.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<InvalidationBatch> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    /// Returns the pending invalidations right away, without waiting for the window to end.
+    pub fn try_recv(&self) -> Option<InvalidationBatch> {
+        self.state.take(&mut self.state.lock())
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Option<InvalidationBatch> {
+        let mut pending = self.state.lock();
+        loop {
+            let now = Instant::now();
+            let ready = match pending.since {
+                Some(since) if pending.closed || now >= since + self.state.window => {
+                    return self.state.take(&mut pending);
+                }
+                Some(since) => Some(since + self.state.window),
+                None if pending.closed => return None,
+                None => None,
+            };
+            let wake = match (ready, deadline) {
+                (_, Some(deadline)) if deadline <= now => return None,
+                (Some(ready), Some(deadline)) => Some(ready.min(deadline)),
+                (ready, deadline) => ready.or(deadline),
+            };
+            pending = match wake {
+                Some(wake) => self.state.changed.wait_timeout(pending, wake - now).unwrap().0,
+                None => self.state.changed.wait(pending).unwrap(),
+            };
+        }
+    }
+}
+
+impl FeedState {
+    fn lock(&self) -> MutexGuard<'_, Pending> {
+        self.pending.lock().unwrap()
+    }
+
+    fn take(&self, pending: &mut Pending) -> Option<InvalidationBatch> {
+        pending.since?;
+        pending.since = None;
+        Some(InvalidationBatch {
+            sequence: pending.sequence,
+            invalidations: std::mem::take(&mut pending.invalidations).into_iter().collect(),
+        })
+    }
+
+    fn add<'a>(&self, sequence: u64, changes: impl Iterator<Item = Change<'a>>) {
+        let mut pending = self.lock();
+        for change in changes {
+            let key = change.key;
+            let invalidation = match (change.kind, self.granularity) {
+                (ChangeKind::Range(end), _) => Invalidation::Range {
+                    start: key.to_vec(),
+                    end: end.to_vec(),
+                },
+                (_, Granularity::Key) => Invalidation::Key(key.to_vec()),
+                (_, Granularity::Prefix(len)) => {
+                    Invalidation::Prefix(key[..len.min(key.len())].to_vec())
+                }
+            };
+            if pending.invalidations.last() != Some(&Invalidation::All) {
+                pending.invalidations.insert(invalidation);
+            }
+        }
+        if pending.invalidations.len() > MAX_PENDING_INVALIDATIONS {
+            pending.invalidations = BTreeSet::from([Invalidation::All]);
+        }
+        pending.sequence = sequence;
+        if pending.since.is_none() {
+            pending.since = Some(Instant::now());
+            self.changed.notify_all();
+        }
+    }
+}
+
+/// The feeds of a database, fed by every write.
+#[derive(Default)]
+pub(crate) struct Invalidations {
+    feeds: Mutex<Vec<Weak<FeedState>>>,
+}
+
+impl Invalidations {
+    pub(crate) fn subscribe(
+        &self,
+        granularity: Granularity,
+        window: Duration,
+    ) -> InvalidationFeed {
+        let state = Arc::new(FeedState {
+            granularity,
+            window,
+            pending: Mutex::new(Pending::default()),
+            changed: Condvar::new(),
+        });
+        self.feeds.lock().unwrap().push(Arc::downgrade(&state));
+        InvalidationFeed { state }
+    }
+
+    /// Reports the changes made by the write numbered `sequence`, or by a batch ending with
+    /// it, to every feed. Called in sequence order.
+    pub(crate) fn publish<'a, I>(&self, sequence: u64, changes: I)
+    where
+        I: Iterator<Item = Change<'a>> + Clone,
+    {
+        let mut feeds = self.feeds.lock().unwrap();
+        if feeds.is_empty() {
+            return;
+        }
+        feeds.retain(|feed| match feed.upgrade() {
+            Some(feed) => {
+                feed.add(sequence, changes.clone());
+                true
+            }
+            None => false,
+        });
+    }
+}
+
+impl Drop for Invalidations {
+    fn drop(&mut self) {
+        for feed in self.feeds.get_mut().unwrap().iter().filter_map(Weak::upgrade) {
+            feed.lock().closed = true;
+            feed.changed.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn key(key: &[u8]) -> Change<'_> {
+        Change {
+            key,
+            kind: ChangeKind::Delete,
+            timestamp: 0,
+            sequence: 0,
+        }
+    }
+
+    fn range<'a>(start: &'a [u8], end: &'a [u8]) -> Change<'a> {
+        Change {
+            kind: ChangeKind::Range(end),
+            ..key(start)
+        }
+    }
+
+    #[test]
+    fn test_coalesces_within_window() {
+        let invalidations = Invalidations::default();
+        let feed = invalidations.subscribe(Granularity::Key, Duration::from_millis(50));
+        let prefixes = invalidations.subscribe(Granularity::Prefix(3), Duration::ZERO);
+        assert_eq!(feed.try_recv(), None);
+
+        invalidations.publish(1, [key(b"key2")].into_iter());
+        invalidations.publish(3, [key(b"key1"), key(b"key2")].into_iter());
+        invalidations.publish(4, [range(b"a", b"c"), key(b"k")].into_iter());
+        assert_eq!(feed.recv_timeout(Duration::ZERO), None);
+
+        let batch = feed.recv().unwrap();
+        assert_eq!(batch.sequence, 4);
+        assert_eq!(
+            batch.invalidations,
+            vec![
+                Invalidation::Key(b"k".to_vec()),
+                Invalidation::Key(b"key1".to_vec()),
+                Invalidation::Key(b"key2".to_vec()),
+                Invalidation::Range {
+                    start: b"a".to_vec(),
+                    end: b"c".to_vec(),
+                },
+            ]
+        );
+        assert_eq!(
+            prefixes.recv().unwrap().invalidations,
+            vec![
+                Invalidation::Prefix(b"k".to_vec()),
+                Invalidation::Prefix(b"key".to_vec()),
+                Invalidation::Range {
+                    start: b"a".to_vec(),
+                    end: b"c".to_vec(),
+                },
+            ]
+        );
+
+        drop(prefixes);
+        invalidations.publish(5, [key(b"key3")].into_iter());
+        assert_eq!(invalidations.feeds.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_overflow_and_close() {
+        let invalidations = Invalidations::default();
+        let feed = invalidations.subscribe(Granularity::Key, Duration::from_secs(60));
+        for i in 0..=MAX_PENDING_INVALIDATIONS as u32 {
+            invalidations.publish(i as u64 + 1, [key(&i.to_be_bytes())].into_iter());
+        }
+        invalidations.publish(70000, [key(b"key1")].into_iter());
+
+        let receiver = thread::spawn(move || (feed.recv(), feed.recv()));
+        drop(invalidations);
+        let (batch, closed) = receiver.join().unwrap();
+        let batch = batch.unwrap();
+        assert_eq!(batch.sequence, 70000);
+        assert_eq!(batch.invalidations, vec![Invalidation::All]);
+        assert_eq!(closed, None);
+    }
+}
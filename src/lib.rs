@@ -1,5 +1,125 @@
+pub mod arena;
+#[cfg(feature = "async")]
+pub mod async_disk;
+pub mod backup;
+pub mod block_cache;
+pub mod bloom;
+pub mod budget;
+pub mod bytes;
+pub mod checksum;
+pub mod clock;
+pub mod comparator;
+pub mod compression;
+pub mod cursor;
 pub mod disk;
-mod mem_table;
-mod wal;
-mod wal_iterator;
-mod utils;
\ No newline at end of file
+pub mod dump;
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod follower;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod hash_index;
+pub mod health;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod invalidation;
+pub mod lock_metrics;
+pub mod logging;
+pub mod manifest;
+pub mod mem_table;
+pub mod merge;
+pub mod metrics;
+#[cfg(feature = "metrics-http")]
+pub mod metrics_http;
+pub mod options;
+pub mod prefix;
+pub mod rate_limiter;
+pub mod read_options;
+pub mod reflink;
+#[cfg(feature = "replication")]
+pub mod replication;
+pub mod scan_iterator;
+pub mod scan_options;
+pub mod schema;
+pub mod scrub;
+pub mod single_file;
+pub mod skip_list;
+pub mod snapshot;
+pub mod sst_writer;
+pub mod sstable;
+pub mod stats;
+pub mod storage;
+pub mod subscription;
+pub mod tombstone_retention;
+pub mod transaction;
+#[cfg(feature = "typed")]
+pub mod typed;
+pub mod validation;
+pub mod value_log;
+pub mod wal;
+pub mod wal_archive;
+pub mod wal_iterator;
+pub mod wal_mirror;
+pub mod wal_tail;
+pub mod write_batch;
+pub mod write_stall;
+mod platform;
+#[cfg(test)]
+mod utils;
+
+pub use arena::ArenaBytes;
+#[cfg(feature = "async")]
+pub use async_disk::AsyncDisk;
+pub use backup::BackupInfo;
+pub use block_cache::{BlockCache, BlockCacheStats};
+pub use bytes::Bytes;
+pub use clock::Clock;
+pub use comparator::{BytewiseComparator, Comparator, KeyOrder};
+pub use compression::Compression;
+pub use cursor::{Cursor, CursorTable};
+pub use disk::{Db, Disk, DiskEntry, PinnedValue, Version};
+pub use error::FluxError;
+pub use follower::Follower;
+#[cfg(feature = "grpc")]
+pub use grpc::GrpcService;
+pub use health::Health;
+pub use invalidation::{Granularity, Invalidation, InvalidationBatch, InvalidationFeed};
+pub use logging::{Level, LogSink, Logger, Subsystem};
+pub use manifest::{EditReason, ManifestEdit};
+pub use mem_table::{InMemoryTable, MemTable, MemTableKind};
+#[cfg(feature = "metrics-http")]
+pub use metrics_http::MetricsServer;
+pub use options::DiskOptions;
+pub use prefix::PrefixExtractor;
+pub use rate_limiter::{IoPriority, RateLimiter, RateLimiterStats};
+pub use read_options::ReadOptions;
+#[cfg(feature = "replication")]
+pub use replication::{Replica, ReplicationOptions, ReplicationServer};
+pub use scan_iterator::ScanIterator;
+pub use scan_options::{ScanFilter, ScanOptions};
+pub use schema::ValueSchema;
+pub use scrub::{CorruptBlock, ScrubOptions, ScrubReport};
+pub use single_file::SingleFileBackend;
+pub use snapshot::Snapshot;
+pub use sst_writer::SstWriter;
+pub use stats::{
+    DiskStats, HistogramSnapshot, KeyDistribution, LatencyHistograms, LevelStats, LiveFileInfo,
+    Operation, RecoveryStats, StatisticsSnapshot, TombstoneDensity, TreeStats,
+};
+pub use storage::{FsBackend, MemoryBackend, Storage, StorageBackend, StorageFile};
+pub use subscription::{ChangeEvent, ChangeOp};
+pub use tombstone_retention::TombstoneRetention;
+pub use transaction::Transaction;
+#[cfg(feature = "typed")]
+pub use typed::TypedDb;
+pub use validation::{ForbiddenPrefix, MaxValueSize, WriteValidator, WriteValidators};
+pub use value_log::{ValueLogOptions, ValueLogStats};
+pub use wal::WAL;
+pub use wal_archive::WalArchive;
+pub use wal_mirror::{MirrorAck, WalMirror};
+#[cfg(feature = "async")]
+pub use wal_tail::AsyncWalTail;
+pub use wal_tail::WalTail;
+pub use write_batch::{Op, TimestampRegression, WriteBatch, WriteOptions};
+pub use write_stall::{StallPolicy, WriteStall};
@@ -0,0 +1,205 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+
+/// Counters for the engine's internal locks, used to tell contention-bound slowdowns apart
+/// from I/O-bound ones.
+///
+/// Collection can be switched on and off at runtime; while it is off acquiring a lock costs
+/// a single relaxed atomic load on top of the lock itself.
+#[derive(Default)]
+pub struct LockMetrics {
+    enabled: AtomicBool,
+    write_log: LockStats,
+    mem_table_read: LockStats,
+    mem_table_write: LockStats,
+}
+
+/// Counters for a single lock.
+#[derive(Default)]
+struct LockStats {
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    wait_nanos: AtomicU64,
+    max_wait_nanos: AtomicU64,
+}
+
+/// Point-in-time copy of the counters of every instrumented lock.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LockMetricsSnapshot {
+    /// The write-path lock serializing WAL appends.
+    pub write_log: LockStatsSnapshot,
+    /// Shared acquisitions of the memtable lock by readers.
+    pub mem_table_read: LockStatsSnapshot,
+    /// Exclusive acquisitions of the memtable lock by writers.
+    pub mem_table_write: LockStatsSnapshot,
+}
+
+/// Point-in-time copy of the counters of one lock.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LockStatsSnapshot {
+    /// Number of times the lock was acquired while collection was enabled.
+    pub acquisitions: u64,
+    /// Number of those acquisitions that had to wait for another holder.
+    pub contended: u64,
+    /// Total time spent waiting for the lock.
+    pub total_wait: Duration,
+    /// Longest single wait for the lock.
+    pub max_wait: Duration,
+}
+
+impl LockMetrics {
+    /// Creates the metrics, collecting from the start if `enabled` is set.
+    pub fn new(enabled: bool) -> LockMetrics {
+        let metrics = LockMetrics::default();
+        metrics.set_enabled(enabled);
+        metrics
+    }
+
+    /// Turns collection on or off.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Returns whether collection is on.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Returns the current counters.
+    pub fn snapshot(&self) -> LockMetricsSnapshot {
+        LockMetricsSnapshot {
+            write_log: self.write_log.snapshot(),
+            mem_table_read: self.mem_table_read.snapshot(),
+            mem_table_write: self.mem_table_write.snapshot(),
+        }
+    }
+
+    /// Clears every counter.
+    pub fn reset(&self) {
+        for stats in [&self.write_log, &self.mem_table_read, &self.mem_table_write] {
+            stats.reset();
+        }
+    }
+
+    /// Acquires the write-path lock.
+    pub(crate) fn lock_write_log<'a, T>(&self, mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+        self.timed(
+            &self.write_log,
+            || mutex.try_lock().ok(),
+            || mutex.lock().unwrap(),
+        )
+    }
+
+    /// Acquires the memtable lock for reading.
+    pub(crate) fn read_mem_table<'a, T>(&self, lock: &'a RwLock<T>) -> RwLockReadGuard<'a, T> {
+        self.timed(
+            &self.mem_table_read,
+            || lock.try_read().ok(),
+            || lock.read().unwrap(),
+        )
+    }
+
+    /// Acquires the memtable lock for writing.
+    pub(crate) fn write_mem_table<'a, T>(&self, lock: &'a RwLock<T>) -> RwLockWriteGuard<'a, T> {
+        self.timed(
+            &self.mem_table_write,
+            || lock.try_write().ok(),
+            || lock.write().unwrap(),
+        )
+    }
+
+    /// Acquires a lock, first trying without blocking so uncontended acquisitions are not
+    /// timed, then blocking and recording how long the wait took.
+    fn timed<G>(
+        &self,
+        stats: &LockStats,
+        try_acquire: impl FnOnce() -> Option<G>,
+        acquire: impl FnOnce() -> G,
+    ) -> G {
+        if !self.is_enabled() {
+            return acquire();
+        }
+
+        stats.acquisitions.fetch_add(1, Ordering::Relaxed);
+        if let Some(guard) = try_acquire() {
+            return guard;
+        }
+
+        let start = Instant::now();
+        let guard = acquire();
+        let waited = start.elapsed().as_nanos() as u64;
+        stats.contended.fetch_add(1, Ordering::Relaxed);
+        stats.wait_nanos.fetch_add(waited, Ordering::Relaxed);
+        stats.max_wait_nanos.fetch_max(waited, Ordering::Relaxed);
+        guard
+    }
+}
+
+impl LockStats {
+    fn snapshot(&self) -> LockStatsSnapshot {
+        LockStatsSnapshot {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+            total_wait: Duration::from_nanos(self.wait_nanos.load(Ordering::Relaxed)),
+            max_wait: Duration::from_nanos(self.max_wait_nanos.load(Ordering::Relaxed)),
+        }
+    }
+
+    fn reset(&self) {
+        self.acquisitions.store(0, Ordering::Relaxed);
+        self.contended.store(0, Ordering::Relaxed);
+        self.wait_nanos.store(0, Ordering::Relaxed);
+        self.max_wait_nanos.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_counts_only_while_enabled() {
+        let metrics = LockMetrics::new(false);
+        let mutex = Mutex::new(0);
+        *metrics.lock_write_log(&mutex) += 1;
+        assert_eq!(metrics.snapshot(), LockMetricsSnapshot::default());
+
+        metrics.set_enabled(true);
+        *metrics.lock_write_log(&mutex) += 1;
+        let rwlock = RwLock::new(0);
+        let _first = metrics.read_mem_table(&rwlock);
+        let _second = metrics.read_mem_table(&rwlock);
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.write_log.acquisitions, 1);
+        assert_eq!(snapshot.write_log.contended, 0);
+        assert_eq!(snapshot.mem_table_read.acquisitions, 2);
+        assert_eq!(snapshot.mem_table_read.contended, 0);
+
+        metrics.reset();
+        assert_eq!(metrics.snapshot(), LockMetricsSnapshot::default());
+    }
+
+    #[test]
+    fn test_records_contended_wait() {
+        let metrics = Arc::new(LockMetrics::new(true));
+        let mutex = Arc::new(Mutex::new(0));
+
+        let guard = mutex.lock().unwrap();
+        let waiter = {
+            let (metrics, mutex) = (metrics.clone(), mutex.clone());
+            thread::spawn(move || *metrics.lock_write_log(&mutex) += 1)
+        };
+        thread::sleep(Duration::from_millis(50));
+        drop(guard);
+        waiter.join().unwrap();
+
+        let snapshot = metrics.snapshot().write_log;
+        assert_eq!(snapshot.contended, 1);
+        assert!(snapshot.max_wait >= Duration::from_millis(10));
+        assert_eq!(snapshot.total_wait, snapshot.max_wait);
+    }
+}
@@ -0,0 +1,194 @@
+use std::fmt;
+use std::sync::Arc;
+
+/// Severity of a log message, most severe first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+/// Part of the engine a message comes from. Each has its own level.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Subsystem {
+    /// Opening the database and replaying the WAL.
+    Recovery,
+    /// Appending to and rotating the WAL.
+    Wal,
+    /// Writing frozen memtables to segments.
+    Flush,
+    /// Merging segments.
+    Compaction,
+    /// Saving statistics.
+    Stats,
+    /// Shipping the WAL to replicas and applying it on them, and followers catching up.
+    Replication,
+    /// Watching the data directory for writes failing because it turned read-only.
+    Storage,
+    /// Gets and writes of callers slower than their threshold in `DiskOptions`.
+    Operations,
+    /// Verifying the segments block by block, see `DiskOptions::scrub`.
+    Scrub,
+}
+
+impl Subsystem {
+    pub const ALL: [Subsystem; 9] = [
+        Subsystem::Recovery,
+        Subsystem::Wal,
+        Subsystem::Flush,
+        Subsystem::Compaction,
+        Subsystem::Stats,
+        Subsystem::Replication,
+        Subsystem::Storage,
+        Subsystem::Operations,
+        Subsystem::Scrub,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Subsystem::Recovery => "recovery",
+            Subsystem::Wal => "wal",
+            Subsystem::Flush => "flush",
+            Subsystem::Compaction => "compaction",
+            Subsystem::Stats => "stats",
+            Subsystem::Replication => "replication",
+            Subsystem::Storage => "storage",
+            Subsystem::Operations => "operations",
+            Subsystem::Scrub => "scrub",
+        }
+    }
+}
+
+/// Destination of the messages the engine logs.
+pub trait LogSink: Send + Sync {
+    fn log(&self, level: Level, subsystem: Subsystem, message: &fmt::Arguments<'_>);
+}
+
+/// Sink used unless another is configured. With the `tracing` feature it emits `tracing`
+/// events with target `fluxdb` and a `subsystem` field; otherwise it writes to stderr.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultSink;
+
+impl LogSink for DefaultSink {
+    #[cfg(feature = "tracing")]
+    fn log(&self, level: Level, subsystem: Subsystem, message: &fmt::Arguments<'_>) {
+        let subsystem = subsystem.name();
+        match level {
+            Level::Error => tracing::error!(target: "fluxdb", subsystem, "{}", message),
+            Level::Warn => tracing::warn!(target: "fluxdb", subsystem, "{}", message),
+            Level::Info => tracing::info!(target: "fluxdb", subsystem, "{}", message),
+            Level::Debug => tracing::debug!(target: "fluxdb", subsystem, "{}", message),
+        }
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    fn log(&self, level: Level, subsystem: Subsystem, message: &fmt::Arguments<'_>) {
+        eprintln!("fluxdb {} [{}] {}", level.name(), subsystem.name(), message);
+    }
+}
+
+/// Routes the messages of each subsystem at or above its configured level to a sink.
+/// Every subsystem logs warnings and errors by default.
+#[derive(Clone)]
+pub struct Logger {
+    sink: Arc<dyn LogSink>,
+    levels: [Option<Level>; Subsystem::ALL.len()],
+}
+
+impl Logger {
+    /// Creates a logger writing to `sink`.
+    pub fn new<S: LogSink + 'static>(sink: S) -> Logger {
+        Logger {
+            sink: Arc::new(sink),
+            levels: [Some(Level::Warn); Subsystem::ALL.len()],
+        }
+    }
+
+    /// Sets the least severe level logged for a subsystem; `None` silences it.
+    pub fn with_level(mut self, subsystem: Subsystem, level: Option<Level>) -> Logger {
+        self.levels[subsystem as usize] = level;
+        self
+    }
+
+    /// Sets the level of every subsystem.
+    pub fn with_levels(mut self, level: Option<Level>) -> Logger {
+        self.levels = [level; Subsystem::ALL.len()];
+        self
+    }
+
+    /// Returns whether messages of the given level are logged for a subsystem.
+    pub fn enabled(&self, level: Level, subsystem: Subsystem) -> bool {
+        self.levels[subsystem as usize].is_some_and(|max| level <= max)
+    }
+
+    /// Passes a message to the sink if its level is enabled for the subsystem.
+    pub fn log(&self, level: Level, subsystem: Subsystem, message: fmt::Arguments<'_>) {
+        if self.enabled(level, subsystem) {
+            self.sink.log(level, subsystem, &message);
+        }
+    }
+}
+
+impl Default for Logger {
+    fn default() -> Logger {
+        Logger::new(DefaultSink)
+    }
+}
+
+impl fmt::Debug for Logger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let levels: Vec<(&str, Option<Level>)> = Subsystem::ALL
+            .iter()
+            .map(|&subsystem| (subsystem.name(), self.levels[subsystem as usize]))
+            .collect();
+        f.debug_struct("Logger")
+            .field("levels", &levels)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Collect(Mutex<Vec<String>>);
+
+    impl LogSink for Arc<Collect> {
+        fn log(&self, level: Level, subsystem: Subsystem, message: &fmt::Arguments<'_>) {
+            let line = format!("{} {} {}", level.name(), subsystem.name(), message);
+            self.0.lock().unwrap().push(line);
+        }
+    }
+
+    #[test]
+    fn test_levels_per_subsystem() {
+        let sink = Arc::new(Collect::default());
+        let logger = Logger::new(sink.clone())
+            .with_level(Subsystem::Compaction, Some(Level::Debug))
+            .with_level(Subsystem::Stats, None);
+
+        logger.log(Level::Debug, Subsystem::Compaction, format_args!("merged {}", 3));
+        logger.log(Level::Debug, Subsystem::Flush, format_args!("flushed"));
+        logger.log(Level::Warn, Subsystem::Flush, format_args!("slow sync"));
+        logger.log(Level::Error, Subsystem::Stats, format_args!("save failed"));
+
+        let lines = sink.0.lock().unwrap().clone();
+        assert_eq!(lines, vec!["debug compaction merged 3", "warn flush slow sync"]);
+        assert!(!logger.clone().with_levels(None).enabled(Level::Error, Subsystem::Wal));
+    }
+}
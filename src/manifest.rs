@@ -0,0 +1,755 @@
+use crate::comparator::{KeyOrder, BYTEWISE};
+use crate::storage::Storage;
+use std::fmt::Write;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Name of the file recording the live files of a database directory.
+pub const MANIFEST_FILE: &str = "MANIFEST";
+/// Name of the file every edit of the manifest is appended to, for audits.
+pub const HISTORY_FILE: &str = "MANIFEST-HISTORY";
+/// Name the history file is renamed to once it reaches `MAX_HISTORY_SIZE`, replacing the
+/// previous one, so the history of a database takes at most twice that.
+pub const ROTATED_HISTORY_FILE: &str = "MANIFEST-HISTORY.old";
+/// Size in bytes past which the history file is rotated.
+pub const MAX_HISTORY_SIZE: u64 = 1024 * 1024;
+/// First line of every manifest, identifying the format version.
+const MANIFEST_HEADER: &str = "FLUXDB-MANIFEST 1";
+
+/// Optional on-disk features this version of FluxDB knows about, with whether they were
+/// compiled into the binary.
+const KNOWN_FEATURES: [(&str, bool); 5] = [
+    ("backdated-versions", true),
+    ("lz4", cfg!(feature = "lz4")),
+    ("snappy", cfg!(feature = "snappy")),
+    ("value-log", true),
+    ("zstd", cfg!(feature = "zstd")),
+];
+
+/// Extension of WAL files.
+pub const WAL_EXTENSION: &str = "wal";
+/// Extension of segment (SSTable) files.
+pub const SEGMENT_EXTENSION: &str = "sst";
+/// Extension of value log files.
+pub const VALUE_LOG_EXTENSION: &str = "vlog";
+/// Extension appended to the files set aside on open because the engine never names a file
+/// that way.
+pub const QUARANTINE_EXTENSION: &str = "corrupt";
+
+/// Records which WAL, segment and value log files make up the current state of a database
+/// directory.
+///
+/// The manifest is rewritten atomically (write to a temporary file, then rename) whenever the
+/// set of live files changes, so recovery knows exactly which files to read. Files named as
+/// the engine names them that are not listed are leftovers of an interrupted operation.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Manifest {
+    /// Names of the live WAL files, oldest first. The last one is being appended to.
+    pub wal_files: Vec<String>,
+    /// Names of the live segment files, oldest first.
+    pub segment_files: Vec<String>,
+    /// Names of the live value log files, holding the large values segments point to.
+    pub value_log_files: Vec<String>,
+    /// Number used to name the next segment file.
+    pub next_file_number: u64,
+    /// Highest sequence number assigned when the manifest was stored. Numbering resumes
+    /// after it (or after the last replayed write, if higher) when the database is opened.
+    pub last_sequence: u64,
+    /// Resumable scans in progress, as (id, sequence) pairs. The versions visible at their
+    /// sequences are kept across restarts until the scans are done or released.
+    pub pinned_scans: Vec<(u64, u64)>,
+    /// Optional on-disk features the files of the database may use, such as compression
+    /// codecs, sorted. A feature is never removed, as older files may still use it.
+    pub features: Vec<String>,
+    /// Name of the comparator the keys are sorted with, or `None` for byte order.
+    pub comparator: Option<String>,
+    /// Segments added by `Disk::ingest_segments`, as (name, sequence) pairs: their entries,
+    /// stored at sequence 0, are read at the sequence they were ingested at.
+    pub ingested_segments: Vec<(String, u64)>,
+}
+
+impl Manifest {
+    /// Loads the manifest of a directory, or `None` if the directory has none yet.
+    pub fn load(dir: &Path) -> io::Result<Option<Manifest>> {
+        Manifest::load_with(&Storage::default(), dir)
+    }
+
+    /// Loads the manifest of a directory kept in `storage`, or `None` if it has none yet.
+    pub fn load_with(storage: &Storage, dir: &Path) -> io::Result<Option<Manifest>> {
+        let contents = match storage.read(&dir.join(MANIFEST_FILE)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let contents = String::from_utf8(contents)
+            .map_err(|_| invalid_manifest("manifest is not valid UTF-8"))?;
+
+        let mut lines = contents.lines();
+        match lines.next() {
+            Some(header) if header == MANIFEST_HEADER => {}
+            _ => return Err(invalid_manifest("missing manifest header")),
+        }
+
+        let mut manifest = Manifest::default();
+        for line in lines {
+            match line.split_once(' ') {
+                Some(("wal", name)) => manifest.wal_files.push(name.to_owned()),
+                Some(("segment", name)) => manifest.segment_files.push(name.to_owned()),
+                Some(("value-log", name)) => manifest.value_log_files.push(name.to_owned()),
+                Some(("next-file", number)) => {
+                    manifest.next_file_number = number
+                        .parse()
+                        .map_err(|_| invalid_manifest(&format!("bad file number {:?}", number)))?;
+                }
+                Some(("last-sequence", number)) => {
+                    manifest.last_sequence = number.parse().map_err(|_| {
+                        invalid_manifest(&format!("bad sequence number {:?}", number))
+                    })?;
+                }
+                Some(("pinned-scan", pin)) => {
+                    let parsed = pin.split_once(' ').and_then(|(id, sequence)| {
+                        Some((id.parse().ok()?, sequence.parse().ok()?))
+                    });
+                    let pin = parsed
+                        .ok_or_else(|| invalid_manifest(&format!("bad pinned scan {:?}", pin)))?;
+                    manifest.pinned_scans.push(pin);
+                }
+                Some(("feature", name)) => manifest.features.push(name.to_owned()),
+                Some(("comparator", name)) => manifest.comparator = Some(name.to_owned()),
+                Some(("ingested", segment)) => {
+                    let parsed = segment.split_once(' ').and_then(|(name, sequence)| {
+                        Some((name.to_owned(), sequence.parse().ok()?))
+                    });
+                    let segment = parsed.ok_or_else(|| {
+                        invalid_manifest(&format!("bad ingested segment {:?}", segment))
+                    })?;
+                    manifest.ingested_segments.push(segment);
+                }
+                _ => return Err(invalid_manifest(&format!("unexpected line {:?}", line))),
+            }
+        }
+
+        Ok(Some(manifest))
+    }
+
+    /// Atomically replaces the manifest of a directory with this one.
+    pub fn store(&self, dir: &Path) -> io::Result<()> {
+        self.store_with(&Storage::default(), dir)
+    }
+
+    /// Atomically replaces the manifest of a directory kept in `storage` with this one.
+    pub fn store_with(&self, storage: &Storage, dir: &Path) -> io::Result<()> {
+        let mut contents = String::new();
+        // Writing to a String can't fail.
+        let _ = writeln!(contents, "{}", MANIFEST_HEADER);
+        for name in self.wal_files.iter() {
+            let _ = writeln!(contents, "wal {}", name);
+        }
+        for name in self.segment_files.iter() {
+            let _ = writeln!(contents, "segment {}", name);
+        }
+        for name in self.value_log_files.iter() {
+            let _ = writeln!(contents, "value-log {}", name);
+        }
+        let _ = writeln!(contents, "next-file {}", self.next_file_number);
+        let _ = writeln!(contents, "last-sequence {}", self.last_sequence);
+        for (id, sequence) in self.pinned_scans.iter() {
+            let _ = writeln!(contents, "pinned-scan {} {}", id, sequence);
+        }
+        for name in self.features.iter() {
+            let _ = writeln!(contents, "feature {}", name);
+        }
+        if let Some(name) = &self.comparator {
+            let _ = writeln!(contents, "comparator {}", name);
+        }
+        // Segments compacted away since they were ingested are dropped.
+        for (name, sequence) in self.ingested_segments.iter() {
+            if self.segment_files.contains(name) {
+                let _ = writeln!(contents, "ingested {} {}", name, sequence);
+            }
+        }
+
+        storage.replace(dir, MANIFEST_FILE, contents.as_bytes())
+    }
+
+    /// Records that the files of the database may use an optional feature. Returns whether
+    /// it wasn't recorded yet.
+    pub fn add_feature(&mut self, name: &str) -> bool {
+        match self.features.binary_search_by(|feature| feature.as_str().cmp(name)) {
+            Ok(_) => false,
+            Err(i) => {
+                self.features.insert(i, name.to_owned());
+                true
+            }
+        }
+    }
+
+    /// Fails with `Unsupported` if the database uses a feature this binary can't read,
+    /// because it was built without it or predates it, before any file is misread.
+    pub fn check_features(&self) -> io::Result<()> {
+        for name in self.features.iter() {
+            let reason = match KNOWN_FEATURES.iter().find(|(known, _)| known == name) {
+                Some((_, true)) => continue,
+                Some(_) => format!("this build lacks the `{}` cargo feature", name),
+                None => "it is unknown to this version of FluxDB".to_owned(),
+            };
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("missing feature {}: the database uses it, but {}", name, reason),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Fails with `InvalidInput` if the keys of the database are sorted with another
+    /// comparator than `order`, as its files would be misread.
+    pub fn check_comparator(&self, order: &KeyOrder) -> io::Result<()> {
+        let recorded = self.comparator.as_deref().unwrap_or(BYTEWISE);
+        if recorded == order.name() {
+            return Ok(());
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "comparator mismatch: the database is sorted with {:?}, not {:?}",
+                recorded,
+                order.name()
+            ),
+        ))
+    }
+
+    /// Returns the sequences pinned by resumable scans, in increasing order.
+    pub fn pinned_sequences(&self) -> Vec<u64> {
+        let mut sequences: Vec<u64> =
+            self.pinned_scans.iter().map(|&(_, sequence)| sequence).collect();
+        sequences.sort_unstable();
+        sequences
+    }
+
+    /// Returns the paths of the live WAL files, oldest first.
+    pub fn wal_paths(&self, dir: &Path) -> Vec<PathBuf> {
+        self.wal_files.iter().map(|name| dir.join(name)).collect()
+    }
+
+    /// Reserves a name for a new segment file.
+    pub fn new_segment_name(&mut self) -> String {
+        self.next_file_number += 1;
+        format!("{:06}.{}", self.next_file_number, SEGMENT_EXTENSION)
+    }
+
+    /// Returns the sequence number the entries of a segment stored at sequence 0 are read at:
+    /// the one it was ingested at, or 0 for segments the database wrote.
+    pub fn ingested_sequence(&self, segment: &str) -> u64 {
+        self.ingested_segments
+            .iter()
+            .find(|(name, _)| name == segment)
+            .map_or(0, |&(_, sequence)| sequence)
+    }
+
+    /// Returns the paths of the live segment files, oldest first.
+    pub fn segment_paths(&self, dir: &Path) -> Vec<PathBuf> {
+        self.segment_files.iter().map(|name| dir.join(name)).collect()
+    }
+
+    /// Reserves a name for a new value log file.
+    pub fn new_value_log_name(&mut self) -> String {
+        self.next_file_number += 1;
+        format!("{:06}.{}", self.next_file_number, VALUE_LOG_EXTENSION)
+    }
+
+    /// Returns the paths of the live value log files.
+    pub fn value_log_paths(&self, dir: &Path) -> Vec<PathBuf> {
+        self.value_log_files.iter().map(|name| dir.join(name)).collect()
+    }
+
+    /// Fails if a file listed in the manifest is missing from the directory.
+    pub fn verify_files_exist(&self, dir: &Path) -> io::Result<()> {
+        self.verify_files_exist_with(&Storage::default(), dir)
+    }
+
+    /// Fails if a file listed in the manifest is missing from the directory in `storage`.
+    pub fn verify_files_exist_with(&self, storage: &Storage, dir: &Path) -> io::Result<()> {
+        let mut paths = self.wal_paths(dir);
+        paths.extend(self.segment_paths(dir));
+        paths.extend(self.value_log_paths(dir));
+        for path in paths.iter() {
+            if !storage.exists(path) {
+                return Err(invalid_manifest(&format!(
+                    "live file {} is missing",
+                    path.display()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes WAL, segment and value log files that the manifest doesn't list, along with
+    /// any leftover temporary manifest, and quarantines the files that only look like them,
+    /// as `clean_up_unlisted_files` does. Returns the deleted paths.
+    pub fn remove_unlisted_files(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.remove_unlisted_files_with(&Storage::default(), dir)
+    }
+
+    /// Cleans up the unlisted files of a directory kept in `storage`, as
+    /// `clean_up_unlisted_files_with` does. Returns the deleted paths.
+    pub fn remove_unlisted_files_with(
+        &self,
+        storage: &Storage,
+        dir: &Path,
+    ) -> io::Result<Vec<PathBuf>> {
+        Ok(self.clean_up_unlisted_files_with(storage, dir)?.removed)
+    }
+
+    /// Deletes WAL, segment and value log files that the manifest doesn't list, along with
+    /// any leftover temporary manifest, and quarantines the files that only look like them.
+    /// Returns what was done to each file.
+    pub fn clean_up_unlisted_files(&self, dir: &Path) -> io::Result<DirectoryCleanup> {
+        self.clean_up_unlisted_files_with(&Storage::default(), dir)
+    }
+
+    /// Cleans up the unlisted files of a directory kept in `storage`.
+    ///
+    /// Only files named as the engine names them, a number followed by the extension, are
+    /// deleted. Files with the extension of a database file but any other name, such as a
+    /// copy someone made by hand, are renamed to `<name>.corrupt` instead. Files with other
+    /// extensions are left alone.
+    pub fn clean_up_unlisted_files_with(
+        &self,
+        storage: &Storage,
+        dir: &Path,
+    ) -> io::Result<DirectoryCleanup> {
+        let mut cleanup = DirectoryCleanup::default();
+        for (extension, live) in [
+            (WAL_EXTENSION, &self.wal_files),
+            (SEGMENT_EXTENSION, &self.segment_files),
+            (VALUE_LOG_EXTENSION, &self.value_log_files),
+        ] {
+            for path in storage.list_with_extension(dir, extension)? {
+                let name = path.file_name().and_then(|name| name.to_str());
+                if name.is_some_and(|name| live.iter().any(|live| live == name)) {
+                    continue;
+                }
+                if name.is_some_and(|name| is_database_file_name(name, extension)) {
+                    storage.delete(&path)?;
+                    cleanup.removed.push(path);
+                } else {
+                    let quarantined = quarantine_with(storage, &path)?;
+                    cleanup.quarantined.push((path, quarantined));
+                }
+            }
+        }
+
+        let temp_path = dir.join(format!("{}.tmp", MANIFEST_FILE));
+        if storage.exists(&temp_path) {
+            storage.delete(&temp_path)?;
+            cleanup.removed.push(temp_path);
+        }
+
+        Ok(cleanup)
+    }
+}
+
+/// What cleaning up the files a manifest doesn't list did to them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DirectoryCleanup {
+    /// Files deleted as leftovers of an interrupted write.
+    pub removed: Vec<PathBuf>,
+    /// Files set aside as not written by the engine, with the paths they were renamed to.
+    pub quarantined: Vec<(PathBuf, PathBuf)>,
+}
+
+/// Returns whether `name` is one the engine gives files with `extension`: a number followed
+/// by the extension.
+pub fn is_database_file_name(name: &str, extension: &str) -> bool {
+    name.strip_suffix(extension)
+        .and_then(|stem| stem.strip_suffix('.'))
+        .is_some_and(|stem| !stem.is_empty() && stem.bytes().all(|byte| byte.is_ascii_digit()))
+}
+
+/// Renames a file of `storage` to `<name>.corrupt`, or `<name>.<n>.corrupt` if that is
+/// taken, so it is neither read nor deleted. Returns the new path.
+pub fn quarantine_with(storage: &Storage, path: &Path) -> io::Result<PathBuf> {
+    let name = path.file_name().unwrap_or(path.as_os_str());
+    let mut attempt = 0;
+    let target = loop {
+        let mut target = name.to_os_string();
+        if attempt > 0 {
+            target.push(format!(".{}", attempt));
+        }
+        target.push(format!(".{}", QUARANTINE_EXTENSION));
+        let target = path.with_file_name(target);
+        if !storage.exists(&target) {
+            break target;
+        }
+        attempt += 1;
+    };
+    storage.rename(path, &target)?;
+    Ok(target)
+}
+
+/// What led to an edit of the manifest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EditReason {
+    /// The database was opened: its WAL files were replayed into a fresh one.
+    Open,
+    /// The active WAL file reached `max_wal_file_size` and a new one was started.
+    WalRotation,
+    /// A memtable was written to a segment, retiring its WAL files.
+    Flush,
+    /// Segments were merged into one.
+    Compaction,
+    /// Segment files built outside the database were added to it.
+    Ingestion,
+}
+
+impl EditReason {
+    const ALL: [EditReason; 5] = [
+        EditReason::Open,
+        EditReason::WalRotation,
+        EditReason::Flush,
+        EditReason::Compaction,
+        EditReason::Ingestion,
+    ];
+
+    /// Returns the name of the reason, as written in the history file and printed by the
+    /// `history` command.
+    pub fn name(self) -> &'static str {
+        match self {
+            EditReason::Open => "open",
+            EditReason::WalRotation => "wal-rotation",
+            EditReason::Flush => "flush",
+            EditReason::Compaction => "compaction",
+            EditReason::Ingestion => "ingestion",
+        }
+    }
+}
+
+/// A change to the live files of a database, as recorded in its manifest history.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestEdit {
+    /// When the edit was stored, in microseconds since the Unix epoch.
+    pub timestamp: u128,
+    /// What led to the edit.
+    pub reason: EditReason,
+    /// Highest sequence number assigned when the edit was stored.
+    pub last_sequence: u64,
+    /// Files the edit made live, such as the segment written by a flush or a compaction.
+    pub added: Vec<String>,
+    /// Files the edit retired, such as the WAL files of a flush or the inputs of a
+    /// compaction.
+    pub removed: Vec<String>,
+}
+
+impl ManifestEdit {
+    /// Returns the edit turning `previous` into `current`.
+    pub fn between(
+        previous: &Manifest,
+        current: &Manifest,
+        reason: EditReason,
+        timestamp: u128,
+    ) -> ManifestEdit {
+        let files = |manifest: &Manifest| -> Vec<String> {
+            let files = manifest.wal_files.iter().chain(manifest.segment_files.iter());
+            files.chain(manifest.value_log_files.iter()).cloned().collect()
+        };
+        let (before, after) = (files(previous), files(current));
+        ManifestEdit {
+            timestamp,
+            reason,
+            last_sequence: current.last_sequence,
+            added: after.iter().filter(|name| !before.contains(name)).cloned().collect(),
+            removed: before.iter().filter(|name| !after.contains(name)).cloned().collect(),
+        }
+    }
+
+    /// Appends the edit to the history of a directory kept in `storage`, first rotating the
+    /// history file if the edit would take it past `MAX_HISTORY_SIZE`.
+    pub fn append_with(&self, storage: &Storage, dir: &Path) -> io::Result<()> {
+        self.append_capped(storage, dir, MAX_HISTORY_SIZE)
+    }
+
+    fn append_capped(&self, storage: &Storage, dir: &Path, max_size: u64) -> io::Result<()> {
+        let mut line = format!("{} {} {}", self.timestamp, self.reason.name(), self.last_sequence);
+        for name in self.added.iter() {
+            let _ = write!(line, " +{}", name);
+        }
+        for name in self.removed.iter() {
+            let _ = write!(line, " -{}", name);
+        }
+        line.push('\n');
+        let path = dir.join(HISTORY_FILE);
+        let mut file = storage.append(&path)?;
+        let size = file.size()?;
+        if size > 0 && size + line.len() as u64 > max_size {
+            drop(file);
+            storage.rename(&path, &dir.join(ROTATED_HISTORY_FILE))?;
+            file = storage.append(&path)?;
+        }
+        file.append(line.as_bytes())
+    }
+
+    fn parse(line: &str) -> Option<ManifestEdit> {
+        let mut fields = line.split(' ');
+        let timestamp = fields.next()?.parse().ok()?;
+        let reason = fields.next()?;
+        let reason = *EditReason::ALL.iter().find(|known| known.name() == reason)?;
+        let mut edit = ManifestEdit {
+            timestamp,
+            reason,
+            last_sequence: fields.next()?.parse().ok()?,
+            added: Vec::new(),
+            removed: Vec::new(),
+        };
+        for field in fields {
+            match field.split_at_checked(1)? {
+                ("+", name) => edit.added.push(name.to_owned()),
+                ("-", name) => edit.removed.push(name.to_owned()),
+                _ => return None,
+            }
+        }
+        Some(edit)
+    }
+}
+
+/// Loads the manifest history of a directory, oldest edit first.
+pub fn load_history(dir: &Path) -> io::Result<Vec<ManifestEdit>> {
+    load_history_with(&Storage::default(), dir)
+}
+
+/// Loads the manifest history of a directory kept in `storage`, oldest edit first, from the
+/// rotated history file and then the current one. A last line torn by a crash is skipped.
+pub fn load_history_with(storage: &Storage, dir: &Path) -> io::Result<Vec<ManifestEdit>> {
+    let mut edits = read_history(storage, &dir.join(ROTATED_HISTORY_FILE))?;
+    edits.extend(read_history(storage, &dir.join(HISTORY_FILE))?);
+    Ok(edits)
+}
+
+/// Reads the complete lines of a history file, or nothing if there is none.
+fn read_history(storage: &Storage, path: &Path) -> io::Result<Vec<ManifestEdit>> {
+    let contents = match storage.read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let contents = String::from_utf8_lossy(&contents);
+    let complete = match contents.rfind('\n') {
+        Some(end) => &contents[..end],
+        None => return Ok(Vec::new()),
+    };
+    complete
+        .lines()
+        .map(|line| {
+            ManifestEdit::parse(line)
+                .ok_or_else(|| invalid_manifest(&format!("bad history line {:?}", line)))
+        })
+        .collect()
+}
+
+/// Returns the name of a file as stored in the manifest.
+pub fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+fn invalid_manifest(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupt manifest: {}", reason),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryBackend;
+    use rand::Rng;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    #[test]
+    fn test_store_and_load() {
+        let mut rng = rand::thread_rng();
+        let test_dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir_all(&test_dir).unwrap();
+
+        assert_eq!(Manifest::load(&test_dir).unwrap(), None);
+
+        let manifest = Manifest {
+            wal_files: vec!["1.wal".to_owned(), "2.wal".to_owned()],
+            segment_files: vec!["000001.sst".to_owned()],
+            value_log_files: vec!["000002.vlog".to_owned()],
+            next_file_number: 2,
+            last_sequence: 42,
+            pinned_scans: vec![(2, 40), (1, 17)],
+            features: vec!["zstd".to_owned()],
+            comparator: Some("numeric".to_owned()),
+            ingested_segments: vec![("000001.sst".to_owned(), 30)],
+        };
+        manifest.store(&test_dir).unwrap();
+        assert_eq!(manifest.pinned_sequences(), vec![17, 40]);
+        assert_eq!(manifest.ingested_sequence("000001.sst"), 30);
+        assert_eq!(manifest.ingested_sequence("000003.sst"), 0);
+        assert_eq!(Manifest::load(&test_dir).unwrap(), Some(manifest));
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_remove_unlisted_files() {
+        let mut rng = rand::thread_rng();
+        let test_dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir_all(&test_dir).unwrap();
+
+        let names = [
+            "1.wal",
+            "2.wal",
+            "1.sst",
+            "3.vlog",
+            "notes.txt",
+            "MANIFEST.tmp",
+            "LOCK",
+            "README",
+            "copy of 1.sst",
+            ".wal",
+            "notes.wal",
+            "notes.wal.corrupt",
+        ];
+        for name in names {
+            write(test_dir.join(name), b"").unwrap();
+        }
+        let manifest = Manifest {
+            wal_files: vec!["2.wal".to_owned()],
+            ..Manifest::default()
+        };
+
+        let mut cleanup = manifest.clean_up_unlisted_files(&test_dir).unwrap();
+        cleanup.removed.sort();
+        cleanup.quarantined.sort();
+        assert_eq!(
+            cleanup.removed,
+            vec![
+                test_dir.join("1.sst"),
+                test_dir.join("1.wal"),
+                test_dir.join("3.vlog"),
+                test_dir.join("MANIFEST.tmp"),
+            ]
+        );
+        // Earlier quarantined files are kept; hidden files have no extension.
+        assert_eq!(
+            cleanup.quarantined,
+            vec![
+                (test_dir.join("copy of 1.sst"), test_dir.join("copy of 1.sst.corrupt")),
+                (test_dir.join("notes.wal"), test_dir.join("notes.wal.1.corrupt")),
+            ]
+        );
+        for name in ["2.wal", "notes.txt", "LOCK", "README", ".wal", "notes.wal.corrupt"] {
+            assert!(test_dir.join(name).exists());
+        }
+
+        // The older form only returns the deleted paths.
+        write(test_dir.join("4.sst"), b"").unwrap();
+        let removed = manifest.remove_unlisted_files(&test_dir).unwrap();
+        assert_eq!(removed, vec![test_dir.join("4.sst")]);
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_database_file_names() {
+        assert!(is_database_file_name("000001.sst", SEGMENT_EXTENSION));
+        assert!(is_database_file_name("1700000000000000.wal", WAL_EXTENSION));
+        assert!(!is_database_file_name("000001.sst", WAL_EXTENSION));
+        assert!(!is_database_file_name(".sst", SEGMENT_EXTENSION));
+        assert!(!is_database_file_name("backup.sst", SEGMENT_EXTENSION));
+        assert!(!is_database_file_name("1.old.sst", SEGMENT_EXTENSION));
+        assert!(!is_database_file_name("1sst", SEGMENT_EXTENSION));
+    }
+
+    #[test]
+    fn test_check_features() {
+        let mut manifest = Manifest::default();
+        manifest.check_features().unwrap();
+
+        assert!(manifest.add_feature("zstd"));
+        assert!(manifest.add_feature("lz4"));
+        assert!(!manifest.add_feature("zstd"));
+        assert_eq!(manifest.features, vec!["lz4".to_owned(), "zstd".to_owned()]);
+        let missing = manifest.check_features().map_err(|e| e.to_string());
+        match (cfg!(feature = "lz4"), cfg!(feature = "zstd")) {
+            (true, true) => missing.unwrap(),
+            (false, _) => assert!(missing.unwrap_err().starts_with("missing feature lz4")),
+            (true, false) => assert!(missing.unwrap_err().starts_with("missing feature zstd")),
+        }
+
+        manifest.features = vec!["encryption".to_owned()];
+        let err = manifest.check_features().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(err.to_string().contains("unknown to this version"));
+    }
+
+    #[test]
+    fn test_history() {
+        let storage = Storage::new(MemoryBackend::new());
+        let dir = Path::new("history");
+        assert_eq!(load_history_with(&storage, dir).unwrap(), Vec::new());
+
+        let previous = Manifest {
+            wal_files: vec!["1.wal".to_owned(), "2.wal".to_owned()],
+            segment_files: vec!["000001.sst".to_owned()],
+            ..Manifest::default()
+        };
+        let current = Manifest {
+            wal_files: vec!["2.wal".to_owned()],
+            segment_files: vec!["000001.sst".to_owned(), "000002.sst".to_owned()],
+            last_sequence: 42,
+            ..Manifest::default()
+        };
+        let edit = ManifestEdit::between(&previous, &current, EditReason::Flush, 1000);
+        assert_eq!(edit.added, vec!["000002.sst".to_owned()]);
+        assert_eq!(edit.removed, vec!["1.wal".to_owned()]);
+        edit.append_with(&storage, dir).unwrap();
+        let unchanged = ManifestEdit::between(&current, &current, EditReason::Open, 1001);
+        unchanged.append_with(&storage, dir).unwrap();
+
+        // A torn last line is skipped.
+        let path = dir.join(HISTORY_FILE);
+        storage.append(&path).unwrap().append(b"1002 compaction 4").unwrap();
+        assert_eq!(load_history_with(&storage, dir).unwrap(), vec![edit, unchanged]);
+        storage.append(&path).unwrap().append(b"\nnot an edit\n").unwrap();
+        assert!(load_history_with(&storage, dir).is_err());
+    }
+
+    #[test]
+    fn test_history_is_rotated() {
+        let storage = Storage::new(MemoryBackend::new());
+        let dir = Path::new("history");
+        let edits: Vec<ManifestEdit> = (0..5)
+            .map(|i| ManifestEdit {
+                timestamp: 1000 + i,
+                reason: EditReason::Flush,
+                last_sequence: i as u64,
+                added: vec![format!("{:06}.sst", i)],
+                removed: Vec::new(),
+            })
+            .collect();
+        // Each line takes 25 bytes, so two fit under the cap and the third rotates the file.
+        for edit in edits.iter() {
+            edit.append_capped(&storage, dir, 60).unwrap();
+        }
+        assert_eq!(storage.read(&dir.join(HISTORY_FILE)).unwrap().len(), 25);
+        assert_eq!(storage.read(&dir.join(ROTATED_HISTORY_FILE)).unwrap().len(), 50);
+        assert_eq!(load_history_with(&storage, dir).unwrap(), edits[2..]);
+    }
+
+    #[test]
+    fn test_load_rejects_garbage() {
+        let mut rng = rand::thread_rng();
+        let test_dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir_all(&test_dir).unwrap();
+
+        write(test_dir.join(MANIFEST_FILE), b"not a manifest\n").unwrap();
+        assert!(Manifest::load(&test_dir).is_err());
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+}
@@ -1,114 +1,520 @@
-/// Represents an entry in the InMemoryTable.
+use crate::arena::{Arena, ArenaBytes};
+use crate::comparator::KeyOrder;
+use crate::hash_index::HashIndex;
+use crate::skip_list::SkipList;
+use crate::snapshot::KeptVersions;
+use crate::sstable::RangeTombstone;
+use std::cmp::Ordering;
+use std::mem;
+use std::ops::{Bound, Range, RangeBounds};
+
+/// Represents an entry in the InMemoryTable. Its key and value are held in the table's
+/// arena, as `ArenaBytes` dereferencing to the bytes; `to_vec` copies them out.
 pub struct InMemoryRecord {
-    pub key: Vec<u8>,
-    pub value: Option<Vec<u8>>,
+    pub key: ArenaBytes,
+    pub value: Option<ArenaBytes>,
     pub timestamp: u128,
     pub is_deleted: bool,
+    /// Position of the write in the engine's commit order.
+    pub sequence: u64,
+    /// Schema version the value is encoded with, or 0 if untagged.
+    pub schema: u32,
+    /// Whether the version was written below a newer one of its key, so reads of the
+    /// latest version pass over it.
+    pub backdated: bool,
+}
+
+/// Structure holding the records of a memtable, indexed by key. The versions of a key are
+/// kept together, newest first.
+pub trait MemTable: Send + Sync {
+    /// Adds a version of its key, placed after the versions with higher sequence numbers.
+    /// Replaces the version of the same sequence number, if any, and returns it.
+    fn insert(&mut self, record: InMemoryRecord) -> Option<InMemoryRecord>;
+
+    /// Removes and returns the version of `key` at `index` among its versions, newest
+    /// first.
+    fn remove(&mut self, key: &[u8], index: usize) -> InMemoryRecord;
+
+    /// Returns the versions of `key`, newest first; empty if it has none.
+    fn fetch(&self, key: &[u8]) -> &[InMemoryRecord];
+
+    /// Iterates in key order over the keys within the bounds, yielding the versions of
+    /// each, newest first.
+    fn iter<'a>(
+        &'a self,
+        bounds: (Bound<&[u8]>, Bound<&[u8]>),
+    ) -> Box<dyn Iterator<Item = &'a [InMemoryRecord]> + 'a>;
+
+    /// Returns the number of records held, counting every version.
+    fn size(&self) -> usize;
+}
+
+/// Structure the memtables keep their records in. Set in `DiskOptions::memtable_kind`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MemTableKind {
+    /// One vector sorted by key. Scans read a slice of it, but a write moves the records
+    /// after its key, so writes slow down as the memtable grows.
+    #[default]
+    SortedVec,
+    /// A skip list: writes and lookups take logarithmic time, and scans walk the keys in
+    /// order, following a link per key.
+    SkipList,
+    /// A hash index over the keys, in a vector in write order. Writes and lookups take
+    /// constant time, but every scan, flush included, sorts the keys it reads, so it suits
+    /// write-heavy workloads that seldom scan.
+    HashIndex,
+}
+
+impl MemTableKind {
+    /// Creates an empty structure of this kind keeping its keys in `order`.
+    pub fn create(self, order: KeyOrder) -> Box<dyn MemTable> {
+        match self {
+            MemTableKind::SortedVec => Box::new(SortedVec::new(order)),
+            MemTableKind::SkipList => Box::new(SkipList::new(order)),
+            MemTableKind::HashIndex => Box::new(HashIndex::new(order)),
+        }
+    }
 }
 
 /* NOTE: A structure to hold the most recent written records, temporarily stored in memory.
    Entries in the InMemoryTable are kept in order to facilitate scans, and are
    moved to disk once the table reaches a predefined size limit.
+   A key may have several versions while snapshots need the older ones; versions of a key
+   are kept newest first. Ranges removed by `delete_range` are kept apart, as tombstones
+   over the records written before them.
 */
 
 pub struct InMemoryTable {
-    records: Vec<InMemoryRecord>,
-    total_size: usize,
+    records: Box<dyn MemTable>,
+    arena: Arena,
+    range_tombstones: Vec<RangeTombstone>,
+    /// Bytes of the versions holding a value.
+    live_bytes: usize,
+    /// Bytes of the tombstones, soft ones and range tombstones included.
+    tombstone_bytes: usize,
+    last_sequence: u64,
+    order: KeyOrder,
+    retained_versions: usize,
+}
+
+impl Default for InMemoryTable {
+    fn default() -> InMemoryTable {
+        InMemoryTable::new()
+    }
 }
 
 impl InMemoryTable {
     /// Initializes an empty InMemoryTable.
     pub fn new() -> InMemoryTable {
+        InMemoryTable::with_order(KeyOrder::default())
+    }
+
+    /// Initializes an empty InMemoryTable keeping its keys in `order`.
+    pub fn with_order(order: KeyOrder) -> InMemoryTable {
+        InMemoryTable::with_kind(order, MemTableKind::default())
+    }
+
+    /// Initializes an empty InMemoryTable keeping its keys in `order`, in a structure of the
+    /// given kind.
+    pub fn with_kind(order: KeyOrder, kind: MemTableKind) -> InMemoryTable {
         InMemoryTable {
-            records: Vec::new(),
-            total_size: 0,
+            records: kind.create(order.clone()),
+            arena: Arena::new(),
+            range_tombstones: Vec::new(),
+            live_bytes: 0,
+            tombstone_bytes: 0,
+            last_sequence: 0,
+            order,
+            retained_versions: 0,
         }
     }
 
+    /// Keeps up to `versions` versions of every key older than its latest one, on top of
+    /// the versions snapshots read.
+    pub fn with_retained_versions(mut self, versions: usize) -> InMemoryTable {
+        self.retained_versions = versions;
+        self
+    }
+
+    /// Returns the order the keys are kept in.
+    pub fn order(&self) -> &KeyOrder {
+        &self.order
+    }
+
     /// Inserts or updates a key-value pair in the table.
     pub fn insert(&mut self, key: &[u8], value: &[u8], timestamp: u128) {
-        let new_record = InMemoryRecord {
-            key: key.to_vec(),
-            value: Some(value.to_vec()),
-            timestamp,
-            is_deleted: false,
+        let sequence = self.last_sequence + 1;
+        self.apply(key, Some(value), timestamp, sequence, 0, &[]);
+    }
+
+    /// Marks a key as deleted in the InMemoryTable by using a tombstone.
+    pub fn remove(&mut self, key: &[u8], timestamp: u128) {
+        let sequence = self.last_sequence + 1;
+        self.apply(key, None, timestamp, sequence, 0, &[]);
+    }
+
+    /// Adds a version of a key written at `sequence`, a tombstone if `value` is `None`, with
+    /// the value encoded in the given `schema` version. Older versions of the key are dropped
+    /// unless one of the live `snapshots` (sorted sequences) still reads them, or they are
+    /// among the retained versions.
+    pub fn apply(
+        &mut self,
+        key: &[u8],
+        value: Option<&[u8]>,
+        timestamp: u128,
+        sequence: u64,
+        schema: u32,
+        snapshots: &[u64],
+    ) {
+        let record = self.new_record(key, value, timestamp, sequence, schema);
+        self.add_version(record, snapshots);
+    }
+
+    /// Adds a version of a key as `apply` does, but below its latest version: reads of the
+    /// latest version pass over it, and it is only kept among the retained versions.
+    pub fn apply_backdated(
+        &mut self,
+        key: &[u8],
+        value: Option<&[u8]>,
+        timestamp: u128,
+        sequence: u64,
+        schema: u32,
+        snapshots: &[u64],
+    ) {
+        let record = InMemoryRecord {
+            backdated: true,
+            ..self.new_record(key, value, timestamp, sequence, schema)
         };
+        self.add_version(record, snapshots);
+    }
 
-        match self.find_key_position(key) {
-            Ok(index) => {
-                if let Some(existing_value) = self.records[index].value.as_ref() {
-                    if value.len() < existing_value.len() {
-                        self.total_size -= existing_value.len() - value.len();
-                    } else {
-                        self.total_size += value.len() - existing_value.len();
-                    }
-                } else {
-                    self.total_size += value.len();
-                }
-                self.records[index] = new_record;
-            }
-            Err(index) => {
-                self.total_size += key.len() + value.len() + 17; // Key size + value size + 17 (timestamp + deletion flag)
-                self.records.insert(index, new_record);
-            }
+    /// Copies a version into the arena.
+    fn new_record(
+        &mut self,
+        key: &[u8],
+        value: Option<&[u8]>,
+        timestamp: u128,
+        sequence: u64,
+        schema: u32,
+    ) -> InMemoryRecord {
+        InMemoryRecord {
+            key: self.arena.alloc(key),
+            value: value.map(|value| self.arena.alloc(value)),
+            timestamp,
+            is_deleted: value.is_none(),
+            sequence,
+            schema,
+            backdated: false,
         }
     }
 
-    /// Marks a key as deleted in the InMemoryTable by using a tombstone.
-    pub fn remove(&mut self, key: &[u8], timestamp: u128) {
-        let tombstone_record = InMemoryRecord {
-            key: key.to_vec(),
-            value: None,
+    /// Adds a soft tombstone for a key: the key reads as deleted, but the tombstone keeps the
+    /// `retained` value so the key can be restored.
+    pub fn apply_soft_delete(
+        &mut self,
+        key: &[u8],
+        retained: &[u8],
+        timestamp: u128,
+        sequence: u64,
+        schema: u32,
+        snapshots: &[u64],
+    ) {
+        let record = InMemoryRecord {
+            key: self.arena.alloc(key),
+            value: Some(self.arena.alloc(retained)),
             timestamp,
             is_deleted: true,
+            sequence,
+            schema,
+            backdated: false,
         };
+        self.add_version(record, snapshots);
+    }
 
-        match self.find_key_position(key) {
-            Ok(index) => {
-                if let Some(existing_value) = self.records[index].value.as_ref() {
-                    self.total_size -= existing_value.len();
-                }
-                self.records[index] = tombstone_record;
-            }
-            Err(index) => {
-                self.total_size += key.len() + 17; // Key size + timestamp + tombstone flag
-                self.records.insert(index, tombstone_record);
+    /// Removes every key from `start` (inclusive) to `end` (exclusive) written before
+    /// `sequence`, in this table or any older data.
+    pub fn apply_range_delete(
+        &mut self,
+        start: &[u8],
+        end: &[u8],
+        timestamp: u128,
+        sequence: u64,
+    ) {
+        self.tombstone_bytes += start.len() + end.len() + RANGE_TOMBSTONE_OVERHEAD;
+        self.last_sequence = self.last_sequence.max(sequence);
+        self.range_tombstones.push(RangeTombstone {
+            start: start.to_vec(),
+            end: end.to_vec(),
+            timestamp,
+            sequence,
+        });
+    }
+
+    /// Returns the ranges deleted in the table, in the order they were applied.
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.range_tombstones
+    }
+
+    fn add_version(&mut self, record: InMemoryRecord, snapshots: &[u64]) {
+        let key = record.key.clone();
+        *self.size_counter(&record) += record_size(&record);
+        self.last_sequence = self.last_sequence.max(record.sequence);
+        if let Some(replaced) = self.records.insert(record) {
+            *self.size_counter(&replaced) -= record_size(&replaced);
+        }
+
+        // Keep only the newest version of the key visible to each group of snapshots.
+        let mut dropped = Vec::new();
+        let mut kept = KeptVersions::new(snapshots, self.retained_versions);
+        for (index, version) in self.records.fetch(&key).iter().enumerate() {
+            if !kept.keep(version.sequence, version.backdated) {
+                dropped.push(index);
             }
         }
+        for index in dropped.into_iter().rev() {
+            let removed = self.records.remove(&key, index);
+            *self.size_counter(&removed) -= record_size(&removed);
+        }
     }
 
-    /// Retrieves the value of a given key from the table.
+    /// Returns the counter the size of `record` is added to.
+    fn size_counter(&mut self, record: &InMemoryRecord) -> &mut usize {
+        match record.is_deleted {
+            true => &mut self.tombstone_bytes,
+            false => &mut self.live_bytes,
+        }
+    }
+
+    /// Retrieves the latest version of a given key from the table.
     pub fn fetch(&self, key: &[u8]) -> Option<&InMemoryRecord> {
-        self.find_key_position(key)
-            .ok()
-            .map(|idx| &self.records[idx])
+        self.fetch_at(key, u64::MAX)
+    }
+
+    /// Retrieves the latest version of a key written at or before `sequence`.
+    pub fn fetch_at(&self, key: &[u8], sequence: u64) -> Option<&InMemoryRecord> {
+        self.position_at(key, sequence).map(|index| self.record(key, index))
     }
 
-    /// Performs binary search to locate the index of the key or the insert position.
-    fn find_key_position(&self, key: &[u8]) -> Result<usize, usize> {
+    /// Returns the position among the versions of `key` of the record `fetch_at` would
+    /// return, valid until the next write, for `record`.
+    pub fn position_at(&self, key: &[u8], sequence: u64) -> Option<usize> {
         self.records
-            .binary_search_by_key(&key, |record| record.key.as_slice())
+            .fetch(key)
+            .iter()
+            .position(|record| record.sequence <= sequence && !record.backdated)
+    }
+
+    /// Returns the version of `key` at a position returned by `position_at`.
+    pub fn record(&self, key: &[u8], index: usize) -> &InMemoryRecord {
+        &self.records.fetch(key)[index]
+    }
+
+    /// Returns the records whose keys fall within the range, in key order with the versions
+    /// of each key newest first.
+    pub fn range<'a, R: RangeBounds<&'a [u8]>>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = &InMemoryRecord> {
+        self.range_by_key(range).flatten()
     }
 
-    /// Returns the number of records in the table.
+    /// Returns the keys within the range in key order, with the versions of each, newest
+    /// first.
+    pub fn range_by_key<'a, R: RangeBounds<&'a [u8]>>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = &[InMemoryRecord]> {
+        self.records.iter((range.start_bound().cloned(), range.end_bound().cloned()))
+    }
+
+    /// Iterates over the records in the order the writes were applied, so recent changes
+    /// can be replayed in commit order without reading the WAL.
+    pub fn iter_by_write_order(&self) -> impl Iterator<Item = &InMemoryRecord> {
+        let mut ordered: Vec<&InMemoryRecord> = self.all_records().collect();
+        ordered.sort_unstable_by_key(|record| record.sequence);
+        ordered.into_iter()
+    }
+
+    /// Returns the highest sequence number applied to the table.
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    /// Returns the number of records in the table, counting every retained version.
     pub fn record_count(&self) -> usize {
-        self.records.len()
+        self.records.size()
     }
 
-    /// Returns all records stored in the table.
-    pub fn all_records(&self) -> &[InMemoryRecord] {
-        &self.records
+    /// Returns all records stored in the table, in key order.
+    pub fn all_records(&self) -> impl Iterator<Item = &InMemoryRecord> {
+        self.range(..)
     }
 
     /// Returns the total size of the data in memory.
     pub fn current_size(&self) -> usize {
-        self.total_size
+        self.live_bytes + self.tombstone_bytes
+    }
+
+    /// Returns the size of the versions holding a value, part of `current_size`.
+    pub fn live_bytes(&self) -> usize {
+        self.live_bytes
+    }
+
+    /// Returns the size of the tombstones, soft ones and range tombstones included, the
+    /// rest of `current_size`.
+    pub fn tombstone_bytes(&self) -> usize {
+        self.tombstone_bytes
+    }
+
+    /// Recomputes the live and tombstone bytes from the records and range tombstones held,
+    /// and panics if they differ from the counters kept as writes are applied. Takes time
+    /// linear in the size of the table, and does nothing in release builds.
+    pub fn debug_assert_size_consistent(&self) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        let mut live = 0;
+        let mut tombstones = 0;
+        for record in self.all_records() {
+            match record.is_deleted {
+                true => tombstones += record_size(record),
+                false => live += record_size(record),
+            }
+        }
+        for tombstone in &self.range_tombstones {
+            tombstones += tombstone.start.len() + tombstone.end.len() + RANGE_TOMBSTONE_OVERHEAD;
+        }
+        assert_eq!(
+            (live, tombstones),
+            (self.live_bytes, self.tombstone_bytes),
+            "memtable size counters (live, tombstone) out of step with its records"
+        );
     }
+
+    /// Returns the memory allocated for keys and values, which only grows until the table
+    /// is dropped: it includes the versions since replaced, and the unused end of the chunk
+    /// being filled.
+    pub fn allocated_bytes(&self) -> usize {
+        self.arena.allocated()
+    }
+}
+
+/// Adds `record` to the versions of its key, newest first, replacing the version of the
+/// same sequence number, if any, which is returned.
+pub(crate) fn insert_version(
+    versions: &mut Vec<InMemoryRecord>,
+    record: InMemoryRecord,
+) -> Option<InMemoryRecord> {
+    let position = versions.partition_point(|version| version.sequence > record.sequence);
+    match versions.get_mut(position) {
+        Some(existing) if existing.sequence == record.sequence => {
+            Some(mem::replace(existing, record))
+        }
+        _ => {
+            versions.insert(position, record);
+            None
+        }
+    }
+}
+
+/// Records of a memtable in a single vector sorted by key, the versions of a key newest
+/// first.
+pub struct SortedVec {
+    records: Vec<InMemoryRecord>,
+    order: KeyOrder,
+}
+
+impl SortedVec {
+    pub fn new(order: KeyOrder) -> SortedVec {
+        SortedVec {
+            records: Vec::new(),
+            order,
+        }
+    }
+
+    /// Returns the positions of the versions of a key.
+    fn versions(&self, key: &[u8]) -> Range<usize> {
+        self.lower_bound(key)..self.upper_bound(key)
+    }
+
+    /// Returns the position of the first record whose key is not below `key`.
+    fn lower_bound(&self, key: &[u8]) -> usize {
+        self.records
+            .partition_point(|record| self.order.compare(&record.key, key) == Ordering::Less)
+    }
+
+    /// Returns the position of the first record whose key is above `key`.
+    fn upper_bound(&self, key: &[u8]) -> usize {
+        self.records
+            .partition_point(|record| self.order.compare(&record.key, key) != Ordering::Greater)
+    }
+}
+
+impl MemTable for SortedVec {
+    fn insert(&mut self, record: InMemoryRecord) -> Option<InMemoryRecord> {
+        let versions = self.versions(&record.key);
+        let position = versions.start
+            + self.records[versions.clone()]
+                .partition_point(|version| version.sequence > record.sequence);
+        match self.records.get_mut(position) {
+            Some(existing) if position < versions.end && existing.sequence == record.sequence => {
+                Some(mem::replace(existing, record))
+            }
+            _ => {
+                self.records.insert(position, record);
+                None
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &[u8], index: usize) -> InMemoryRecord {
+        let position = self.lower_bound(key) + index;
+        self.records.remove(position)
+    }
+
+    fn fetch(&self, key: &[u8]) -> &[InMemoryRecord] {
+        &self.records[self.versions(key)]
+    }
+
+    fn iter<'a>(
+        &'a self,
+        bounds: (Bound<&[u8]>, Bound<&[u8]>),
+    ) -> Box<dyn Iterator<Item = &'a [InMemoryRecord]> + 'a> {
+        let start = match bounds.0 {
+            Bound::Included(key) => self.lower_bound(key),
+            Bound::Excluded(key) => self.upper_bound(key),
+            Bound::Unbounded => 0,
+        };
+        let end = match bounds.1 {
+            Bound::Included(key) => self.upper_bound(key),
+            Bound::Excluded(key) => self.lower_bound(key),
+            Bound::Unbounded => self.records.len(),
+        };
+        let records = &self.records[start..end.max(start)];
+        Box::new(records.chunk_by(|a, b| a.key == b.key))
+    }
+
+    fn size(&self) -> usize {
+        self.records.len()
+    }
+}
+
+/// Memory a record takes besides its key and value bytes. Derived from the layout of
+/// `InMemoryRecord`, so the size accounting follows the record as fields are added to it.
+pub const RECORD_OVERHEAD: usize = mem::size_of::<InMemoryRecord>();
+
+/// Memory a range tombstone takes besides its bounds.
+const RANGE_TOMBSTONE_OVERHEAD: usize = mem::size_of::<RangeTombstone>();
+
+/// Key size + value size + the fixed size of a record.
+fn record_size(record: &InMemoryRecord) -> usize {
+    record.key.len() + record.value.as_ref().map_or(0, |value| value.len()) + RECORD_OVERHEAD
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::arena::ARENA_CHUNK_SIZE;
 
     #[test]
     fn test_insert_at_start() {
@@ -117,14 +523,15 @@ mod tests {
         table.insert(b"SDK", b"Software Development Kit Guide", 10);
         table.insert(b"CLI", b"Command Line Interface Manual", 15);
 
-        assert_eq!(table.records[0].key, b"API");
+        let records: Vec<&InMemoryRecord> = table.all_records().collect();
+        assert_eq!(records[0].key, b"API");
         assert_eq!(
-            table.records[0].value.as_ref().unwrap(),
+            records[0].value.as_ref().unwrap(),
             b"REST API Documentation"
         );
-        assert_eq!(table.records[0].timestamp, 5);
-        assert!(!table.records[0].is_deleted);
-        assert_eq!(table.current_size(), 141);
+        assert_eq!(records[0].timestamp, 5);
+        assert!(!records[0].is_deleted);
+        assert_eq!(table.current_size(), 90 + 3 * RECORD_OVERHEAD);
     }
 
     #[test]
@@ -135,11 +542,12 @@ mod tests {
         table.insert(b"SDK", b"Software Development Kit Guide", 10);
 
         // After inserting, the expected order is: API, CLI, SDK
-        assert_eq!(table.records[0].key, b"API");
-        assert_eq!(table.records[1].key, b"CLI");
-        assert_eq!(table.records[2].key, b"SDK");
+        let records: Vec<&InMemoryRecord> = table.all_records().collect();
+        assert_eq!(records[0].key, b"API");
+        assert_eq!(records[1].key, b"CLI");
+        assert_eq!(records[2].key, b"SDK");
 
-        assert_eq!(table.current_size(), 141);
+        assert_eq!(table.current_size(), 90 + 3 * RECORD_OVERHEAD);
     }
 
     #[test]
@@ -150,8 +558,8 @@ mod tests {
         table.insert(b"SDK", b"Software Development Kit Guide", 10);
 
         // Check that the last inserted key is at the end of the records
-        assert_eq!(table.records[2].key, b"SDK");
-        assert_eq!(table.current_size(), 141);
+        assert_eq!(table.all_records().nth(2).unwrap().key, b"SDK");
+        assert_eq!(table.current_size(), 90 + 3 * RECORD_OVERHEAD);
     }
 
     #[test]
@@ -168,7 +576,9 @@ mod tests {
         );
         assert_eq!(api_entry.timestamp, 10);
         assert!(!api_entry.is_deleted);
-        assert_eq!(table.current_size(), 50);
+        assert_eq!(table.current_size(), 33 + RECORD_OVERHEAD);
+        // The replaced version stays in the arena until the table is dropped.
+        assert_eq!(table.allocated_bytes(), ARENA_CHUNK_SIZE);
     }
 
     #[test]
@@ -203,7 +613,7 @@ mod tests {
         assert_eq!(entry.value, None); // Deleted, should be None
         assert_eq!(entry.timestamp, 10);
         assert!(entry.is_deleted);
-        assert_eq!(table.current_size(), 20);
+        assert_eq!(table.current_size(), 3 + RECORD_OVERHEAD);
     }
 
     #[test]
@@ -217,6 +627,117 @@ mod tests {
         assert_eq!(entry.value, None); // Should be tombstone
         assert_eq!(entry.timestamp, 10);
         assert!(entry.is_deleted);
-        assert_eq!(table.current_size(), 62);
+        assert_eq!(table.current_size(), 28 + 2 * RECORD_OVERHEAD);
+    }
+
+    #[test]
+    fn test_iter_by_write_order() {
+        let mut table = InMemoryTable::new();
+        table.insert(b"SDK", b"Software Development Kit Guide", 10);
+        table.insert(b"API", b"REST API Documentation", 5);
+        table.insert(b"CLI", b"Command Line Interface Manual", 15);
+        table.remove(b"SDK", 20);
+
+        let keys: Vec<&[u8]> = table
+            .iter_by_write_order()
+            .map(|record| &record.key[..])
+            .collect();
+        assert_eq!(keys, vec![&b"API"[..], b"CLI", b"SDK"]);
+        assert!(table.iter_by_write_order().last().unwrap().is_deleted);
+    }
+
+    #[test]
+    fn test_range() {
+        let mut table = InMemoryTable::new();
+        table.insert(b"API", b"REST API Documentation", 5);
+        table.insert(b"CLI", b"Command Line Interface Manual", 15);
+        table.insert(b"SDK", b"Software Development Kit Guide", 10);
+
+        fn keys<'a>(records: impl Iterator<Item = &'a InMemoryRecord>) -> Vec<Vec<u8>> {
+            records.map(|record| record.key.to_vec()).collect()
+        }
+        assert_eq!(keys(table.range(..)).len(), 3);
+        assert_eq!(keys(table.range(&b"B"[..]..&b"SDK"[..])), vec![b"CLI".to_vec()]);
+        assert_eq!(
+            keys(table.range(&b"CLI"[..]..=&b"SDK"[..])),
+            vec![b"CLI".to_vec(), b"SDK".to_vec()]
+        );
+        assert!(table.range(&b"T"[..]..&b"A"[..]).next().is_none());
+    }
+
+    #[test]
+    fn test_size_accounting() {
+        let mut table = InMemoryTable::new();
+        table.apply(b"API", None, 1, 1, 0, &[]);
+        assert_eq!(table.tombstone_bytes(), 3 + RECORD_OVERHEAD);
+        // A value over a tombstone, then a larger value, then a tombstone over a value.
+        table.apply(b"API", Some(b"v1"), 2, 2, 0, &[]);
+        assert_eq!((table.live_bytes(), table.tombstone_bytes()), (5 + RECORD_OVERHEAD, 0));
+        table.apply(b"API", Some(b"version 2"), 3, 3, 0, &[]);
+        assert_eq!(table.live_bytes(), 12 + RECORD_OVERHEAD);
+        table.apply(b"API", None, 4, 4, 0, &[3]);
+        assert_eq!(table.live_bytes(), 12 + RECORD_OVERHEAD);
+        assert_eq!(table.tombstone_bytes(), 3 + RECORD_OVERHEAD);
+        table.debug_assert_size_consistent();
+
+        // Rewriting a sequence number replaces its version, a soft tombstone keeps its value.
+        table.apply(b"API", Some(b"v4"), 4, 4, 0, &[3]);
+        table.apply_soft_delete(b"CLI", b"retained", 5, 5, 0, &[3]);
+        table.apply_range_delete(b"A", b"B", 6, 6);
+        assert_eq!(table.live_bytes(), 17 + 2 * RECORD_OVERHEAD);
+        assert_eq!(
+            table.tombstone_bytes(),
+            11 + RECORD_OVERHEAD + 2 + RANGE_TOMBSTONE_OVERHEAD
+        );
+        assert_eq!(
+            table.current_size(),
+            table.live_bytes() + table.tombstone_bytes()
+        );
+        table.debug_assert_size_consistent();
+    }
+
+    #[test]
+    fn test_versions_kept_for_snapshots() {
+        let mut table = InMemoryTable::new();
+        table.apply(b"API", Some(b"v1"), 5, 1, 0, &[]);
+        table.apply(b"API", Some(b"v2"), 6, 2, 0, &[1]);
+        table.apply(b"API", None, 7, 3, 0, &[1]);
+        table.apply(b"CLI", Some(b"v1"), 8, 4, 0, &[1]);
+
+        // The snapshot at 1 still reads v1; v2 is shadowed for every reader.
+        let sequences: Vec<u64> = table.range(..).map(|record| record.sequence).collect();
+        assert_eq!(sequences, vec![3, 1, 4]);
+        assert!(table.fetch(b"API").unwrap().is_deleted);
+        assert_eq!(table.fetch_at(b"API", 2).unwrap().value.as_deref(), Some(&b"v1"[..]));
+        assert!(table.fetch_at(b"CLI", 3).is_none());
+        assert_eq!(table.last_sequence(), 4);
+
+        // Once the snapshot is gone the next write drops the versions it kept.
+        table.apply(b"API", Some(b"v3"), 9, 5, 0, &[]);
+        assert_eq!(table.record_count(), 2);
+    }
+
+    #[test]
+    fn test_kinds_agree() {
+        let kinds = [MemTableKind::SortedVec, MemTableKind::SkipList, MemTableKind::HashIndex];
+        let contents: Vec<Vec<(Vec<u8>, u64)>> = kinds
+            .into_iter()
+            .map(|kind| {
+                let mut table = InMemoryTable::with_kind(KeyOrder::default(), kind);
+                for sequence in 1..=300u64 {
+                    let key = format!("key{:03}", (sequence * 37) % 100);
+                    match sequence % 5 {
+                        0 => table.apply(key.as_bytes(), None, 0, sequence, 0, &[100]),
+                        _ => table.apply(key.as_bytes(), Some(b"v"), 0, sequence, 0, &[100]),
+                    }
+                }
+                assert_eq!(table.fetch_at(b"key037", 100).unwrap().sequence, 1);
+                let range = table.range(&b"key010"[..]..&b"key020"[..]);
+                range.map(|record| (record.key.to_vec(), record.sequence)).collect()
+            })
+            .collect();
+        assert_eq!(contents[0].len(), 20);
+        assert_eq!(contents[0], contents[1]);
+        assert_eq!(contents[0], contents[2]);
     }
 }
@@ -0,0 +1,397 @@
+use crate::comparator::KeyOrder;
+use crate::snapshot::KeptVersions;
+use crate::sstable::Entry;
+use crate::tombstone_retention::TombstoneHorizon;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, VecDeque};
+use std::io;
+use std::iter::Peekable;
+
+/// A sorted stream of entries, as produced by a memtable or a segment: keys in increasing
+/// order, the versions of a key newest first.
+pub type EntrySource<'a> = Box<dyn Iterator<Item = io::Result<Entry>> + 'a>;
+
+/// Merges sorted sources into a single stream in the same order: byte order, or the order
+/// given to `with_key_order`.
+///
+/// Sources can also be merged in decreasing key order, with `new_descending`, provided they
+/// all yield keys in that order; the versions of a key still come newest first.
+///
+/// Every version of a key is yielded, newest first, so callers pick the ones they need: the
+/// latest visible to a read, or those still needed by snapshots during compaction. A version
+/// found in several sources (same key and sequence number) is only yielded once.
+pub struct MergeIterator<'a> {
+    sources: Vec<EntrySource<'a>>,
+    heads: Vec<Option<Entry>>,
+    heap: BinaryHeap<Reverse<HeapKey>>,
+    descending: bool,
+    order: KeyOrder,
+    error: Option<io::Error>,
+}
+
+/// Orders source heads by key, then newest version first, then by source position.
+struct HeapKey {
+    key: Vec<u8>,
+    sequence: u64,
+    source: usize,
+    descending: bool,
+    order: KeyOrder,
+}
+
+impl PartialEq for HeapKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapKey {}
+
+impl Ord for HeapKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let keys = self.order.compare(&self.key, &other.key);
+        let keys = if self.descending { keys.reverse() } else { keys };
+        keys.then(other.sequence.cmp(&self.sequence))
+            .then(self.source.cmp(&other.source))
+    }
+}
+
+impl PartialOrd for HeapKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'a> MergeIterator<'a> {
+    /// Creates the merge over the given sources.
+    pub fn new(sources: Vec<EntrySource<'a>>) -> MergeIterator<'a> {
+        MergeIterator::with_key_order(sources, &KeyOrder::default(), false)
+    }
+
+    /// Creates the merge over sources yielding keys in decreasing order.
+    pub fn new_descending(sources: Vec<EntrySource<'a>>) -> MergeIterator<'a> {
+        MergeIterator::with_key_order(sources, &KeyOrder::default(), true)
+    }
+
+    /// Creates the merge over sources sorted in `order`, or in the reverse of it if
+    /// `descending` is set.
+    pub fn with_key_order(
+        sources: Vec<EntrySource<'a>>,
+        order: &KeyOrder,
+        descending: bool,
+    ) -> MergeIterator<'a> {
+        let mut merge = MergeIterator {
+            heads: sources.iter().map(|_| None).collect(),
+            sources,
+            heap: BinaryHeap::new(),
+            descending,
+            order: order.clone(),
+            error: None,
+        };
+        for source in 0..merge.sources.len() {
+            merge.advance(source);
+        }
+        merge
+    }
+
+    /// Loads the next entry of a source into its head slot.
+    fn advance(&mut self, source: usize) {
+        match self.sources[source].next() {
+            Some(Ok(entry)) => {
+                self.heap.push(Reverse(HeapKey {
+                    key: entry.key.clone(),
+                    sequence: entry.sequence,
+                    source,
+                    descending: self.descending,
+                    order: self.order.clone(),
+                }));
+                self.heads[source] = Some(entry);
+            }
+            Some(Err(e)) => {
+                self.error.get_or_insert(e);
+            }
+            None => {}
+        }
+    }
+}
+
+impl Iterator for MergeIterator<'_> {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            self.heap.clear();
+            return Some(Err(e));
+        }
+
+        let Reverse(next) = self.heap.pop()?;
+        let entry = self.heads[next.source].take().unwrap();
+        self.advance(next.source);
+
+        while let Some(Reverse(duplicate)) = self.heap.peek() {
+            if duplicate.sequence != entry.sequence || duplicate.key != entry.key {
+                break;
+            }
+            let source = duplicate.source;
+            self.heap.pop();
+            self.heads[source] = None;
+            self.advance(source);
+        }
+
+        Some(Ok(entry))
+    }
+}
+
+/// Drops the versions no reader can see any more from a sorted stream: of the versions of a
+/// key visible to the same live snapshots, only the newest is kept, apart from the retained
+/// versions following the latest one. Backdated versions only stay as retained versions.
+///
+/// When the stream holds every version left in the database, tombstones at the bottom of a
+/// key's history shadow nothing and are dropped too, unless they keep a value for restoring
+/// or are newer than the tombstone horizon.
+pub struct RetainVersions<I: Iterator<Item = io::Result<Entry>>> {
+    entries: Peekable<I>,
+    snapshots: Vec<u64>,
+    drop_tombstones: bool,
+    horizon: TombstoneHorizon,
+    tombstones_dropped: u64,
+    retained_versions: usize,
+    pending: VecDeque<Entry>,
+}
+
+impl<I: Iterator<Item = io::Result<Entry>>> RetainVersions<I> {
+    /// Wraps a sorted stream, keeping the versions needed by the sorted `snapshots`.
+    pub fn new(entries: I, snapshots: Vec<u64>, drop_tombstones: bool) -> RetainVersions<I> {
+        RetainVersions {
+            entries: entries.peekable(),
+            snapshots,
+            drop_tombstones,
+            horizon: TombstoneHorizon::ALL,
+            tombstones_dropped: 0,
+            retained_versions: 0,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Also keeps up to `versions` versions of every key older than its latest one.
+    pub fn with_retained_versions(mut self, versions: usize) -> RetainVersions<I> {
+        self.retained_versions = versions;
+        self
+    }
+
+    /// Only drops the tombstones `horizon` allows, keeping newer ones however little they
+    /// hide.
+    pub(crate) fn with_tombstone_horizon(mut self, horizon: TombstoneHorizon) -> RetainVersions<I> {
+        self.horizon = horizon;
+        self
+    }
+
+    /// Returns the number of tombstones dropped so far.
+    pub fn tombstones_dropped(&self) -> u64 {
+        self.tombstones_dropped
+    }
+}
+
+impl<I: Iterator<Item = io::Result<Entry>>> Iterator for RetainVersions<I> {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() {
+            let first = match self.entries.next()? {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e)),
+            };
+
+            let mut kept = KeptVersions::new(&self.snapshots, self.retained_versions);
+            let key = first.key.clone();
+            let mut next = Some(first);
+            while let Some(version) = next {
+                if kept.keep(version.sequence, version.backdated) {
+                    self.pending.push_back(version);
+                }
+                let same_key = |older: &io::Result<Entry>| {
+                    older.as_ref().is_ok_and(|older| older.key == key)
+                };
+                next = self.entries.next_if(same_key).and_then(Result::ok);
+            }
+
+            // A backdated version sits above the tombstones older than it, yet they still hide
+            // it from reads by timestamp.
+            let backdated = self.pending.iter().any(|entry| entry.backdated);
+            if self.drop_tombstones && !backdated {
+                let horizon = self.horizon;
+                let droppable = |entry: &Entry| {
+                    entry.is_deleted()
+                        && !entry.is_soft_deleted()
+                        && horizon.allows(entry.sequence, entry.timestamp)
+                };
+                while self.pending.back().is_some_and(droppable) {
+                    self.pending.pop_back();
+                    self.tombstones_dropped += 1;
+                }
+            }
+        }
+        self.pending.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source<'a>(entries: &[(&str, Option<&str>, u64)]) -> EntrySource<'a> {
+        let entries: Vec<io::Result<Entry>> = entries
+            .iter()
+            .map(|(key, value, sequence)| {
+                Ok(Entry {
+                    key: key.as_bytes().to_vec(),
+                    value: value.map(|value| value.as_bytes().to_vec()),
+                    timestamp: 0,
+                    sequence: *sequence,
+                    schema: 0,
+                    retained: None,
+                    value_pointer: false,
+                    backdated: false,
+                })
+            })
+            .collect();
+        Box::new(entries.into_iter())
+    }
+
+    fn versions(entries: impl Iterator<Item = io::Result<Entry>>) -> Vec<(Vec<u8>, u64)> {
+        entries
+            .map(|entry| entry.unwrap())
+            .map(|entry| (entry.key, entry.sequence))
+            .collect()
+    }
+
+    #[test]
+    fn test_merges_versions_in_order() {
+        let merge = MergeIterator::new(vec![
+            source(&[("b", Some("new"), 9), ("d", None, 8)]),
+            source(&[("a", Some("old"), 5), ("b", Some("old"), 4), ("d", Some("old"), 6)]),
+            source(&[("c", Some("oldest"), 1), ("d", Some("old"), 6), ("d", Some("oldest"), 2)]),
+        ]);
+
+        assert_eq!(
+            versions(merge),
+            vec![
+                (b"a".to_vec(), 5),
+                (b"b".to_vec(), 9),
+                (b"b".to_vec(), 4),
+                (b"c".to_vec(), 1),
+                (b"d".to_vec(), 8),
+                (b"d".to_vec(), 6),
+                (b"d".to_vec(), 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merges_in_descending_order() {
+        let merge = MergeIterator::new_descending(vec![
+            source(&[("d", None, 8), ("b", Some("new"), 9)]),
+            source(&[("d", Some("old"), 6), ("d", Some("oldest"), 2), ("c", Some("old"), 1)]),
+            source(&[("d", Some("old"), 6), ("b", Some("old"), 4), ("a", Some("old"), 5)]),
+        ]);
+
+        assert_eq!(
+            versions(merge),
+            vec![
+                (b"d".to_vec(), 8),
+                (b"d".to_vec(), 6),
+                (b"d".to_vec(), 2),
+                (b"c".to_vec(), 1),
+                (b"b".to_vec(), 9),
+                (b"b".to_vec(), 4),
+                (b"a".to_vec(), 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_retains_versions_for_snapshots() {
+        let entries = || {
+            MergeIterator::new(vec![source(&[
+                ("a", Some("4"), 9),
+                ("a", Some("3"), 7),
+                ("a", None, 5),
+                ("a", Some("1"), 2),
+                ("b", None, 3),
+                ("c", Some("1"), 1),
+            ])])
+        };
+
+        let all = |snapshots: Vec<u64>, drop_tombstones| {
+            versions(RetainVersions::new(entries(), snapshots, drop_tombstones))
+        };
+        assert_eq!(
+            all(Vec::new(), false),
+            vec![(b"a".to_vec(), 9), (b"b".to_vec(), 3), (b"c".to_vec(), 1)]
+        );
+        assert_eq!(all(Vec::new(), true), vec![(b"a".to_vec(), 9), (b"c".to_vec(), 1)]);
+        // A tombstone is only dropped once nothing older is kept below it.
+        assert_eq!(all(vec![6], true), vec![(b"a".to_vec(), 9), (b"c".to_vec(), 1)]);
+        assert_eq!(
+            all(vec![2, 6], true),
+            vec![
+                (b"a".to_vec(), 9),
+                (b"a".to_vec(), 5),
+                (b"a".to_vec(), 2),
+                (b"c".to_vec(), 1),
+            ]
+        );
+        assert_eq!(
+            all(vec![3, 8], false),
+            vec![
+                (b"a".to_vec(), 9),
+                (b"a".to_vec(), 7),
+                (b"a".to_vec(), 2),
+                (b"b".to_vec(), 3),
+                (b"c".to_vec(), 1),
+            ]
+        );
+
+        // Retained versions come on top of the ones snapshots read.
+        let retained = |snapshots: Vec<u64>, versions: usize| {
+            let retain = RetainVersions::new(entries(), snapshots, true);
+            self::versions(retain.with_retained_versions(versions))
+        };
+        assert_eq!(
+            retained(Vec::new(), 1),
+            vec![(b"a".to_vec(), 9), (b"a".to_vec(), 7), (b"c".to_vec(), 1)]
+        );
+        assert_eq!(
+            retained(vec![3], 2),
+            vec![
+                (b"a".to_vec(), 9),
+                (b"a".to_vec(), 7),
+                (b"a".to_vec(), 5),
+                (b"a".to_vec(), 2),
+                (b"c".to_vec(), 1),
+            ]
+        );
+
+        // Tombstones newer than the horizon stay at the bottom of a key's history.
+        let horizon = TombstoneHorizon {
+            sequence: 4,
+            ..TombstoneHorizon::ALL
+        };
+        let mut retain =
+            RetainVersions::new(entries(), vec![6], true).with_tombstone_horizon(horizon);
+        assert_eq!(
+            versions(retain.by_ref()),
+            vec![(b"a".to_vec(), 9), (b"a".to_vec(), 5), (b"c".to_vec(), 1)]
+        );
+        assert_eq!(retain.tombstones_dropped(), 1);
+    }
+
+    #[test]
+    fn test_surfaces_source_errors() {
+        let error = io::Error::new(io::ErrorKind::InvalidData, "bad block");
+        let failing: EntrySource = Box::new(vec![Err(error)].into_iter());
+        let mut merge = MergeIterator::new(vec![source(&[("a", Some("1"), 1)]), failing]);
+
+        assert!(merge.next().unwrap().is_err());
+        assert!(merge.next().is_none());
+    }
+}
@@ -0,0 +1,244 @@
+use crate::disk::Disk;
+use crate::stats::{HistogramSnapshot, LevelStats};
+use std::fmt::Write;
+
+/// Prefix of every metric name.
+const PREFIX: &str = "fluxdb";
+
+/// Renders the statistics of a database in the Prometheus text exposition format (version
+/// 0.0.4). Counters carry over from earlier runs like `Disk::statistics`; histograms cover
+/// the current run.
+pub fn render(disk: &Disk) -> String {
+    let stats = disk.statistics();
+    let mut out = Exposition::default();
+
+    out.counter("bytes_written", "Key and value bytes written.", stats.bytes_written);
+    out.counter("puts", "Keys inserted or updated.", stats.puts);
+    out.counter("deletes", "Keys removed, soft and range deletions included.", stats.deletes);
+    out.counter("gets", "Keys looked up.", stats.gets);
+    out.counter("wal_bytes_written", "Bytes appended to the WAL.", stats.wal_bytes_written);
+    out.counter("wal_syncs", "WAL syncs made for writes under sync_writes.", stats.wal_syncs);
+    out.counter("flushes", "Memtables flushed to segments.", stats.flushes);
+    out.counter(
+        "tombstones_coalesced",
+        "Point tombstones flushes replaced with range tombstones.",
+        stats.tombstones_coalesced,
+    );
+    out.counter(
+        "tombstones_purged",
+        "Tombstones compactions dropped once nothing older was left to hide.",
+        stats.tombstones_purged,
+    );
+    out.counter("compactions", "Compactions run.", stats.compactions);
+    out.counter(
+        "compaction_bytes_read",
+        "Size of the segments read by compactions.",
+        stats.compaction_bytes_read,
+    );
+    out.counter(
+        "compaction_bytes_written",
+        "Size of the segments written by compactions.",
+        stats.compaction_bytes_written,
+    );
+    out.counter("bloom_checks", "Bloom filter checks made by lookups.", stats.bloom_checks);
+    out.counter(
+        "bloom_negatives",
+        "Bloom filter checks that ruled a segment out.",
+        stats.bloom_negatives,
+    );
+    out.counter(
+        "bloom_false_positives",
+        "Bloom filter checks that let a lookup read a segment without the key.",
+        stats.bloom_false_positives,
+    );
+    out.metric(
+        "stall_seconds_total",
+        "counter",
+        "Time writers spent waiting on engine maintenance.",
+        stats.stall_time.as_secs_f64(),
+    );
+
+    out.histogram(
+        "read_amplification",
+        "Segments a lookup read blocks from.",
+        &stats.read_amplification,
+        1.0,
+    );
+    out.histogram(
+        "get_latency_seconds",
+        "Time a get took.",
+        &stats.get_latency_micros,
+        1e-6,
+    );
+    let latencies = disk.latency_histograms();
+    out.histogram("set_latency_seconds", "Time a set took.", &latencies.set, 1e-6);
+    out.histogram("delete_latency_seconds", "Time a delete took.", &latencies.delete, 1e-6);
+    out.histogram("write_latency_seconds", "Time a batch write took.", &latencies.write, 1e-6);
+
+    out.gauge("last_sequence", "Sequence number of the last write.", disk.last_sequence() as f64);
+    out.gauge("segments", "Live segment files.", disk.segment_files().len() as f64);
+    let tree = disk.tree_stats();
+    let levels = |value: fn(&LevelStats) -> f64| {
+        let values = tree.levels.iter().map(|level| (level.level.to_string(), value(level)));
+        values.collect::<Vec<_>>()
+    };
+    out.gauges(
+        "level_files",
+        "Segment files of a level.",
+        "level",
+        &levels(|level| level.files as f64),
+    );
+    out.gauges(
+        "level_bytes",
+        "Size of the segment files of a level.",
+        "level",
+        &levels(|level| level.bytes as f64),
+    );
+    out.gauge(
+        "compaction_debt_bytes",
+        "Bytes a compaction would read to merge the segments into one.",
+        tree.compaction_debt as f64,
+    );
+    out.gauge(
+        "memtable_live_bytes",
+        "Bytes of the versions holding a value in the memtables.",
+        tree.memtable_live_bytes as f64,
+    );
+    out.gauge(
+        "memtable_tombstone_bytes",
+        "Bytes of the tombstones in the memtables.",
+        tree.memtable_tombstone_bytes as f64,
+    );
+    out.gauge(
+        "read_amplification_average",
+        "Rolling average of the segments read per lookup.",
+        disk.read_amplification(),
+    );
+    out.gauge(
+        "recovery_duration_seconds",
+        "Time the last open took to recover the database.",
+        stats.recovery.duration.as_secs_f64(),
+    );
+
+    if let Some(cache) = disk.block_cache() {
+        let cache = cache.stats();
+        let capacity = cache.capacity as f64;
+        out.gauge("block_cache_capacity_bytes", "Capacity of the block cache.", capacity);
+        out.gauge("block_cache_usage_bytes", "Bytes of blocks cached.", cache.usage as f64);
+        out.gauge("block_cache_blocks", "Blocks cached.", cache.blocks as f64);
+        out.counter("block_cache_hits", "Block reads served from the cache.", cache.hits);
+        out.counter("block_cache_misses", "Block reads that went to storage.", cache.misses);
+        out.counter("block_cache_evictions", "Blocks evicted from the cache.", cache.evictions);
+    }
+    out.text
+}
+
+/// Text of the exposition being rendered.
+#[derive(Default)]
+struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.metric(&format!("{}_total", name), "counter", help, value as f64);
+    }
+
+    fn gauge(&mut self, name: &str, help: &str, value: f64) {
+        self.metric(name, "gauge", help, value);
+    }
+
+    /// Writes a gauge with a sample for each value of `label`.
+    fn gauges(&mut self, name: &str, help: &str, label: &str, samples: &[(String, f64)]) {
+        self.header(name, "gauge", help);
+        for (label_value, value) in samples {
+            let labels = format!("{}=\"{}\"", label, label_value);
+            let _ = writeln!(self.text, "{}_{}{{{}}} {}", PREFIX, name, labels, value);
+        }
+    }
+
+    fn metric(&mut self, name: &str, kind: &str, help: &str, value: f64) {
+        self.header(name, kind, help);
+        // Writing to a String can't fail.
+        let _ = writeln!(self.text, "{}_{} {}", PREFIX, name, value);
+    }
+
+    /// Writes a histogram whose values are scaled by `scale` into the metric's unit. Buckets
+    /// past the largest recorded value are left out; `+Inf` always closes the list.
+    fn histogram(&mut self, name: &str, help: &str, histogram: &HistogramSnapshot, scale: f64) {
+        self.header(name, "histogram", help);
+        let buckets = &histogram.buckets;
+        let used = buckets.iter().rposition(|&count| count > 0).map_or(0, |last| last + 1);
+        let mut cumulative = 0;
+        // Bucket `i` holds values below 2^i, the last one every value from 2^63 up.
+        for (bucket, &count) in buckets[..used.min(64)].iter().enumerate() {
+            cumulative += count;
+            let bound = ((1u64 << bucket) - 1) as f64 * scale;
+            self.bucket(name, &bound.to_string(), cumulative);
+        }
+        self.bucket(name, "+Inf", histogram.count);
+        let _ = writeln!(self.text, "{}_{}_sum {}", PREFIX, name, histogram.sum as f64 * scale);
+        let _ = writeln!(self.text, "{}_{}_count {}", PREFIX, name, histogram.count);
+    }
+
+    fn bucket(&mut self, name: &str, bound: &str, count: u64) {
+        let _ = writeln!(self.text, "{}_{}_bucket{{le=\"{}\"}} {}", PREFIX, name, bound, count);
+    }
+
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.text, "# HELP {}_{} {}", PREFIX, name, help);
+        let _ = writeln!(self.text, "# TYPE {}_{} {}", PREFIX, name, kind);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let disk = Disk::open_in_memory().unwrap();
+        disk.set(b"key", b"nginx").unwrap();
+        disk.get(b"key").unwrap();
+        disk.get(b"missing").unwrap();
+
+        let text = render(&disk);
+        assert!(text.contains("# TYPE fluxdb_puts_total counter\nfluxdb_puts_total 1\n"));
+        assert!(text.contains("fluxdb_gets_total 2\n"));
+        assert!(text.contains("fluxdb_last_sequence 1\n"));
+        assert!(text.contains("fluxdb_level_files{level=\"0\"} 0\n"));
+        assert!(text.contains("fluxdb_compaction_debt_bytes 0\n"));
+        assert!(text.contains("fluxdb_memtable_tombstone_bytes 0\n"));
+        assert!(text.contains("# TYPE fluxdb_read_amplification histogram\n"));
+        assert!(text.contains("fluxdb_read_amplification_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("fluxdb_get_latency_seconds_count 2\n"));
+        assert!(text.contains("fluxdb_set_latency_seconds_count 1\n"));
+        assert!(!text.contains("block_cache"));
+        for line in text.lines().filter(|line| !line.starts_with('#')) {
+            let (_, value) = line.rsplit_once(' ').unwrap();
+            assert!(value == "+Inf" || value.parse::<f64>().is_ok(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let mut histogram = HistogramSnapshot::default();
+        histogram.buckets[0] = 1;
+        histogram.buckets[2] = 2;
+        histogram.count = 3;
+        histogram.sum = 6;
+        let mut out = Exposition::default();
+        out.histogram("sizes", "Sizes.", &histogram, 1.0);
+        let buckets: Vec<&str> =
+            out.text.lines().filter(|line| line.contains("_bucket")).collect();
+        assert_eq!(
+            buckets,
+            vec![
+                "fluxdb_sizes_bucket{le=\"0\"} 1",
+                "fluxdb_sizes_bucket{le=\"1\"} 1",
+                "fluxdb_sizes_bucket{le=\"3\"} 3",
+                "fluxdb_sizes_bucket{le=\"+Inf\"} 3",
+            ]
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use crate::disk::Disk;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Path the metrics are served at.
+const METRICS_PATH: &str = "/metrics";
+/// Largest request head read before the request is rejected.
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+/// How long a client may take to send its whole request, and to read the response.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Most connections served at once; the ones arriving past it are closed right away.
+const MAX_CONNECTIONS: usize = 16;
+
+/// A minimal HTTP listener serving the statistics of a database at `/metrics` for
+/// Prometheus to scrape, for embedded deployments that have no network API of their own.
+/// Requires the `metrics-http` feature.
+///
+/// The listener only answers `GET` and `HEAD` requests for `/metrics`, and never touches
+/// the data. Each connection is served on a thread of its own, up to 16 at once, and must
+/// send its request within 5 seconds, so a slow client can't hold up the scrapes. The
+/// listener holds a handle to the database, keeping it open until the server is dropped
+/// and the connections still being served are done.
+pub struct MetricsServer {
+    local_addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    /// Starts serving the metrics of `disk` on `addr`. Port 0 picks a free port, which
+    /// `local_addr` returns.
+    pub fn start<A: ToSocketAddrs>(disk: &Disk, addr: A) -> io::Result<MetricsServer> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let (disk, stop) = (disk.clone(), stop.clone());
+            thread::Builder::new()
+                .name("fluxdb-metrics".to_owned())
+                .spawn(move || {
+                    let active = Arc::new(AtomicUsize::new(0));
+                    for stream in listener.incoming() {
+                        if stop.load(Ordering::Acquire) {
+                            break;
+                        }
+                        let Ok(stream) = stream else {
+                            continue;
+                        };
+                        if active.fetch_add(1, Ordering::AcqRel) >= MAX_CONNECTIONS {
+                            active.fetch_sub(1, Ordering::AcqRel);
+                            continue;
+                        }
+                        let (disk, connections) = (disk.clone(), active.clone());
+                        let spawned = thread::Builder::new()
+                            .name("fluxdb-metrics-client".to_owned())
+                            .spawn(move || {
+                                // A client that fails or misbehaves only loses its own
+                                // response.
+                                let _ = serve(&disk, stream);
+                                connections.fetch_sub(1, Ordering::AcqRel);
+                            });
+                        if spawned.is_err() {
+                            active.fetch_sub(1, Ordering::AcqRel);
+                        }
+                    }
+                })?
+        };
+        Ok(MetricsServer {
+            local_addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the address the server listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        // Wakes the listener up so it sees the flag.
+        let mut wake = self.local_addr;
+        if wake.ip().is_unspecified() {
+            wake.set_ip(match wake.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        if TcpStream::connect_timeout(&wake, CLIENT_TIMEOUT).is_ok() {
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+/// Answers the request of one connection.
+fn serve(disk: &Disk, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let deadline = Instant::now() + CLIENT_TIMEOUT;
+    let mut head = Vec::new();
+    let mut buffer = [0; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_SIZE {
+            return respond(&mut stream, "431 Request Header Fields Too Large", "", false);
+        }
+        // The timeout covers the whole request, not each read, so trickling bytes in
+        // doesn't keep the connection open.
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return respond(&mut stream, "408 Request Timeout", "", false);
+        }
+        stream.set_read_timeout(Some(left))?;
+        match stream.read(&mut buffer)? {
+            0 => return Ok(()),
+            read => head.extend_from_slice(&buffer[..read]),
+        }
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+    let (method, target) = (request_line.next(), request_line.next().unwrap_or_default());
+    let path = target.split('?').next().unwrap_or_default();
+    match method {
+        _ if path != METRICS_PATH => respond(&mut stream, "404 Not Found", "", false),
+        Some("GET") => respond(&mut stream, "200 OK", &disk.prometheus_metrics(), false),
+        Some("HEAD") => respond(&mut stream, "200 OK", &disk.prometheus_metrics(), true),
+        _ => respond(&mut stream, "405 Method Not Allowed", "", false),
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str, head_only: bool) -> io::Result<()> {
+    let mut response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    if !head_only {
+        response.push_str(body);
+    }
+    stream.write_all(response.as_bytes())?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_serves_metrics() {
+        let disk = Disk::open_in_memory().unwrap();
+        disk.set(b"key", b"nginx").unwrap();
+        let server = MetricsServer::start(&disk, "127.0.0.1:0").unwrap();
+        let addr = server.local_addr();
+
+        let response = request(addr, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert!(body.contains("fluxdb_puts_total 1\n"));
+
+        let response = request(addr, "HEAD /metrics?format=text HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+        let response = request(addr, "GET / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = request(addr, "POST /metrics HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+
+        // A client that never finishes its request doesn't hold up the others.
+        let mut slow = TcpStream::connect(addr).unwrap();
+        slow.write_all(b"GET /metrics HTTP/1.1\r\n").unwrap();
+        let response = request(addr, "GET /metrics HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        drop(slow);
+
+        drop(server);
+        assert!(TcpStream::connect(addr).is_err());
+    }
+}
@@ -0,0 +1,328 @@
+use crate::block_cache::BlockCache;
+use crate::bloom::DEFAULT_BITS_PER_KEY;
+use crate::clock::Clock;
+use crate::comparator::KeyOrder;
+use crate::compression::Compression;
+use crate::error::FluxError;
+use crate::logging::{Level, Logger};
+use crate::mem_table::MemTableKind;
+use crate::prefix::PrefixExtractor;
+use crate::rate_limiter::RateLimiter;
+use crate::schema::ValueSchema;
+use crate::scrub::ScrubOptions;
+use crate::storage::Storage;
+use crate::tombstone_retention::TombstoneRetention;
+use crate::validation::WriteValidators;
+use crate::value_log::ValueLogOptions;
+use crate::wal_archive::WalArchive;
+use crate::wal_mirror::WalMirror;
+use crate::write_batch::{Op, TimestampRegression, WriteBatch};
+use crate::write_stall::WriteStall;
+use std::time::Duration;
+
+/// Settings applied when opening a `Disk`.
+#[derive(Clone, Debug)]
+pub struct DiskOptions {
+    /// Codec used for compressed payloads. The codec is recorded in each file header,
+    /// so files written with a different codec remain readable.
+    pub compression: Compression,
+    /// Whether values written to the WAL are compressed with `compression`.
+    pub compress_wal: bool,
+    /// Codec the WAL compresses whole blocks of records with, instead of their values one
+    /// by one, independently of `compression`. The records written between two flushes of
+    /// the log form a block, so a write batch compresses as one. `None` writes records as
+    /// they are, compressed by value if `compress_wal` is set.
+    pub wal_block_compression: Option<Compression>,
+    /// Whether WAL keys are delta-encoded against the previous record's key, which shrinks
+    /// the log for workloads writing runs of keys with a common prefix.
+    pub wal_prefix_keys: bool,
+    /// Size in bytes after which the active WAL file is closed and logging continues in a
+    /// new file. `None` only starts one when a memtable is frozen or the database is opened.
+    pub max_wal_file_size: Option<u64>,
+    /// Total size in bytes of the live WAL files past which the active memtable is flushed
+    /// early, so its WAL files can be retired. `None` lets the log grow until the memtable
+    /// fills up.
+    pub max_total_wal_bytes: Option<u64>,
+    /// Whether every write is synced to stable storage before it returns, rather than only
+    /// handed to the operating system. Concurrent writers share syncs: the WAL is synced
+    /// once for all the writes logged while the previous sync ran. Other readers see a
+    /// write as soon as it is logged, before it is synced. Once a sync fails, every durable
+    /// write fails until the database is reopened.
+    pub sync_writes: bool,
+    /// Second directory every WAL record is also written to, so losing the device of the
+    /// database doesn't lose the writes not yet flushed. `None` keeps a single copy.
+    pub wal_mirror: Option<WalMirror>,
+    /// Where WAL files the database no longer needs are moved instead of being deleted, so
+    /// the whole history of writes stays readable. `None` deletes them.
+    pub wal_archive: Option<WalArchive>,
+    /// Whether lock wait metrics are collected from the start. Collection can also be
+    /// toggled at runtime with `Disk::set_lock_metrics_enabled`.
+    pub lock_metrics: bool,
+    /// Size in bytes after which the active memtable is frozen and flushed to a segment in
+    /// the background while writes continue in a fresh memtable. Its arena may run a chunk
+    /// past it before the memtable is frozen as well.
+    pub memtable_size: usize,
+    /// Number of records, counting every retained version of a key, after which the active
+    /// memtable is frozen even if it is below `memtable_size`. Bounds the work of a flush
+    /// for many small records. `None` leaves only the size limit.
+    pub memtable_max_records: Option<usize>,
+    /// Bytes of tombstones, soft and range ones included, after which the active memtable
+    /// is frozen even if it is below `memtable_size`, so a burst of deletes reaches the
+    /// segments, where compactions can drop what it hides, without waiting for the memtable
+    /// to fill. `None` counts tombstones only towards `memtable_size`.
+    pub memtable_max_tombstone_bytes: Option<usize>,
+    /// Age of its first write after which the active memtable is frozen and flushed even if
+    /// it holds little, so the writes of a quiet database don't stay in the WAL and memory
+    /// for days. `None` leaves only the size and record limits.
+    pub memtable_max_age: Option<Duration>,
+    /// Structure the memtables keep their records in, trading scan speed for write speed.
+    /// See `MemTableKind`.
+    pub memtable_kind: MemTableKind,
+    /// How long a segment or WAL file retired by a flush or a compaction is kept before
+    /// being deleted, so followers in other processes can finish reading it. Files a scan
+    /// or iterator of this process still reads are kept until it is dropped either way.
+    /// Files still waiting when the database is closed are deleted when it is next opened.
+    pub obsolete_file_grace: Duration,
+    /// Length of the shortest run of consecutive deleted keys a flush replaces with a single
+    /// range tombstone, so a burst of deletes doesn't leave scans stepping over a tombstone
+    /// per key. A run is only replaced when no older key it spans is still live and no
+    /// snapshot separates its deletes. `None`, the default, keeps every point tombstone.
+    pub coalesce_tombstones: Option<usize>,
+    /// Number of versions of every key older than its latest one that memtables, flushes
+    /// and compactions keep, for `Disk::get_at` and `Disk::history` to read. Deleting a key
+    /// counts as a version; keys removed by `delete_range` keep no history. While it is
+    /// above 0, flushes don't coalesce tombstones, as that would drop the history of the
+    /// keys deleted. 0 keeps only the versions snapshots read.
+    pub retained_versions: usize,
+    /// Uncompressed size in bytes of the data blocks of segment files.
+    pub block_size: usize,
+    /// Size of the bloom filter written with every segment, in bits per key. About 10 bits
+    /// per key skips 99% of the segments that don't hold a looked-up key; 0 writes none.
+    pub bloom_bits_per_key: usize,
+    /// Also builds the bloom filter of every segment over the key prefixes it extracts, so
+    /// `Disk::scan_prefix` skips the segments holding no key with a prefix. `None` builds
+    /// key filters only.
+    pub prefix_extractor: Option<PrefixExtractor>,
+    /// Moves large values out of the segments into value log files, so compactions don't
+    /// copy them over and over. `None` keeps every value in the segments; values already
+    /// moved stay readable.
+    pub value_log: Option<ValueLogOptions>,
+    /// Cache of decompressed segment blocks. Clones of one `BlockCache` can be given to
+    /// several databases to share its capacity. `None` reads every block from storage.
+    pub block_cache: Option<BlockCache>,
+    /// Verifies every segment block in the background, slowly, to find damaged ones; see
+    /// `ScrubOptions`. `None` leaves blocks unchecked until they are read.
+    pub scrub: Option<ScrubOptions>,
+    /// Number of segments that triggers a background compaction merging them into one.
+    pub compaction_trigger: usize,
+    /// Rolling average of the number of segments a lookup reads blocks from that triggers a
+    /// compaction, even with fewer than `compaction_trigger` segments, so reads recover once
+    /// writes stop. The average covers roughly the last 64 lookups. `None` disables it;
+    /// opening fails with `InvalidInput` below 1, as a single segment never gets under it.
+    pub read_amplification_trigger: Option<f64>,
+    /// When writes are slowed down or stopped while flushes and compactions catch up.
+    pub write_stall: WriteStall,
+    /// Caps the bytes per second flushes and compactions write, flushes first. Clones of one
+    /// `RateLimiter` can be given to several databases to share its budget. `None` leaves
+    /// background work unthrottled.
+    pub rate_limiter: Option<RateLimiter>,
+    /// How often the cumulative statistics are saved to disk. They are also saved when the
+    /// database is closed.
+    pub stats_save_interval: Duration,
+    /// Current encoding of stored values. New values are tagged with its version and older
+    /// ones are upgraded on read and during compaction. `None` stores values untagged.
+    pub value_schema: Option<ValueSchema>,
+    /// Order keys are sorted in, byte order by default. It is recorded in the manifest when
+    /// the database is created, and opening it with a comparator of another name fails.
+    pub comparator: KeyOrder,
+    /// Checks every key put or deleted before the write commits, such as key formats, value
+    /// size limits or forbidden prefixes. A rejected key fails its whole batch.
+    pub validators: WriteValidators,
+    /// Longest key, in bytes, a write may put or delete. Empty keys are always rejected.
+    pub max_key_size: usize,
+    /// Largest value, in bytes, a write may put. Values this large are better kept in the
+    /// value log.
+    pub max_value_size: usize,
+    /// What happens to a write given a timestamp, with `WriteBatch::put_at` or
+    /// `set_commit_timestamp`, older than the latest version of its key. Rejected by
+    /// default. Writes stamped by `clock` aren't checked.
+    pub on_timestamp_regression: TimestampRegression,
+    /// How long a soft-deleted value stays restorable with `Disk::undelete`. Compactions
+    /// running after it has passed discard the value.
+    pub soft_delete_grace: Duration,
+    /// How long compactions keep a tombstone once nothing older is left for it to hide,
+    /// by age or by compactions run since. Kept tombstones take space until dropped, but
+    /// go on hiding the key from copies of the data taken before the delete.
+    pub tombstone_retention: TombstoneRetention,
+    /// Most memory, in bytes, a single scan or multi-get may materialize before failing
+    /// with `FluxError::MemoryLimit`. `None` leaves reads unbounded.
+    pub read_memory_limit: Option<usize>,
+    /// Receives warnings and errors the engine can't return to a caller, such as failed
+    /// background work or torn records skipped during recovery, with a level per subsystem.
+    pub logger: Logger,
+    /// Time past which a recovery, flush, compaction, WAL or segment sync, or a write held
+    /// back by `write_stall`, is logged as a warning rather than at debug level.
+    pub slow_operation_threshold: Duration,
+    /// Time past which a `get` is logged as a warning for `Subsystem::Operations`, with
+    /// its key. `None` never logs them; their latency is tracked either way.
+    pub slow_get_threshold: Option<Duration>,
+    /// Time past which a `set`, `delete` or `write` is logged as a warning for
+    /// `Subsystem::Operations`, stalls and WAL syncs included. `None` never logs them.
+    pub slow_write_threshold: Option<Duration>,
+    /// Clock the timestamps of writes and the names of WAL files are taken from. A
+    /// `Clock::logical` makes them reproducible in tests.
+    pub clock: Clock,
+    /// Backend the WAL files, segments, manifest and statistics are kept in. Defaults to
+    /// the local file system.
+    pub storage: Storage,
+    /// Keeps the whole database in one file, at the path it is opened at, instead of a
+    /// directory of files: see `SingleFileBackend`. Only needed when creating the database;
+    /// an existing single-file database is recognized when opened. Replaces `storage`, and
+    /// like other non-local backends rules out backups and checkpoints.
+    pub single_file: bool,
+}
+
+impl Default for DiskOptions {
+    fn default() -> DiskOptions {
+        DiskOptions {
+            compression: Compression::default(),
+            compress_wal: false,
+            wal_block_compression: None,
+            wal_prefix_keys: false,
+            max_wal_file_size: None,
+            max_total_wal_bytes: None,
+            sync_writes: false,
+            wal_mirror: None,
+            wal_archive: None,
+            lock_metrics: false,
+            memtable_size: 4 * 1024 * 1024,
+            memtable_max_records: None,
+            memtable_max_tombstone_bytes: None,
+            memtable_max_age: None,
+            memtable_kind: MemTableKind::default(),
+            obsolete_file_grace: Duration::ZERO,
+            coalesce_tombstones: None,
+            retained_versions: 0,
+            block_size: 4096,
+            bloom_bits_per_key: DEFAULT_BITS_PER_KEY,
+            prefix_extractor: None,
+            value_log: None,
+            block_cache: None,
+            scrub: None,
+            compaction_trigger: 4,
+            read_amplification_trigger: None,
+            write_stall: WriteStall::default(),
+            rate_limiter: None,
+            stats_save_interval: Duration::from_secs(60),
+            value_schema: None,
+            comparator: KeyOrder::default(),
+            validators: WriteValidators::default(),
+            max_key_size: 64 * 1024,
+            max_value_size: 64 * 1024 * 1024,
+            on_timestamp_regression: TimestampRegression::default(),
+            soft_delete_grace: Duration::from_secs(24 * 60 * 60),
+            tombstone_retention: TombstoneRetention::default(),
+            read_memory_limit: None,
+            logger: Logger::default(),
+            slow_operation_threshold: Duration::from_secs(1),
+            slow_get_threshold: None,
+            slow_write_threshold: None,
+            clock: Clock::default(),
+            storage: Storage::default(),
+            single_file: false,
+        }
+    }
+}
+
+impl DiskOptions {
+    /// Returns the schema version new values are tagged with, 0 meaning untagged.
+    pub fn schema_version(&self) -> u32 {
+        self.value_schema.as_ref().map_or(0, ValueSchema::version)
+    }
+
+    /// Returns the optional on-disk features that files written with these options use, to
+    /// be recorded in the manifest.
+    pub fn on_disk_features(&self) -> Vec<&'static str> {
+        let mut features = match self.compression {
+            Compression::None => Vec::new(),
+            codec => vec![codec.name()],
+        };
+        match self.wal_block_compression {
+            Some(codec) if codec != Compression::None && !features.contains(&codec.name()) => {
+                features.push(codec.name())
+            }
+            _ => {}
+        }
+        if self.value_log.is_some() {
+            features.push("value-log");
+        }
+        if self.on_timestamp_regression == TimestampRegression::KeepAsOlderVersion {
+            features.push("backdated-versions");
+        }
+        features
+    }
+
+    /// Checks a put of `value` under `key`, or a delete of `key` if `value` is `None`,
+    /// against the size limits, then runs the validators. Sizes out of bounds fail with
+    /// `FluxError::InvalidArgument`.
+    pub fn validate(&self, key: &[u8], value: Option<&[u8]>) -> Result<(), FluxError> {
+        if key.is_empty() {
+            return Err(FluxError::InvalidArgument("keys can't be empty".to_owned()));
+        }
+        if key.len() > self.max_key_size {
+            return Err(FluxError::InvalidArgument(format!(
+                "key of {} bytes exceeds the limit of {} bytes",
+                key.len(),
+                self.max_key_size
+            )));
+        }
+        if let Some(value) = value.filter(|value| value.len() > self.max_value_size) {
+            return Err(FluxError::InvalidArgument(format!(
+                "value of {} bytes for key {:?} exceeds the limit of {} bytes",
+                value.len(),
+                String::from_utf8_lossy(key),
+                self.max_value_size
+            )));
+        }
+        self.validators.validate(key, value)
+    }
+
+    /// Checks every operation of a batch as `validate` does, and that its commit timestamp
+    /// isn't in the future, returning the first failure.
+    pub fn validate_batch(&self, batch: &WriteBatch) -> Result<(), FluxError> {
+        if let Some(timestamp) = batch.commit_timestamp() {
+            if timestamp > self.clock.peek_micros() {
+                return Err(FluxError::InvalidArgument(format!(
+                    "commit timestamp {} is in the future",
+                    timestamp
+                )));
+            }
+        }
+        for (key, op) in batch.iter() {
+            let value = match op {
+                Op::Put(value) => Some(value.as_slice()),
+                Op::Delete => None,
+            };
+            self.validate(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the level an operation that took `elapsed` is logged at: a warning once it
+    /// passes `slow_operation_threshold`, debug otherwise.
+    pub(crate) fn operation_level(&self, elapsed: Duration) -> Level {
+        match elapsed >= self.slow_operation_threshold {
+            true => Level::Warn,
+            false => Level::Debug,
+        }
+    }
+
+    /// Returns the codec that new WAL files should use for their values.
+    pub fn wal_compression(&self) -> Compression {
+        if self.compress_wal {
+            self.compression
+        } else {
+            Compression::None
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! The few file system calls whose behaviour differs between Unix and Windows, so the rest
+//! of the engine can stay free of `cfg` attributes.
+
+use std::fs::{self, File, TryLockError};
+use std::io;
+use std::path::Path;
+
+/// Reads into `buf` from `offset` without moving the cursor of the file.
+#[cfg(unix)]
+pub(crate) fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+/// Reads into `buf` from `offset`. Windows moves the cursor of the file, which no reader
+/// relies on.
+#[cfg(windows)]
+pub(crate) fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+/// Persists the creations, renames and deletions of files in a directory, where the platform
+/// supports syncing directories. Windows can't open a directory as a file; renames there
+/// are made durable by the file system journal instead.
+pub(crate) fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// Atomically replaces `to`, if it exists, with `from`.
+///
+/// Windows refuses to replace a file another process, such as a virus scanner or an
+/// indexer, briefly holds open; the rename is tried again for a while before giving up.
+pub(crate) fn rename(from: &Path, to: &Path) -> io::Result<()> {
+    #[cfg(windows)]
+    for _ in 0..RENAME_RETRIES {
+        match fs::rename(from, to) {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                std::thread::sleep(RENAME_RETRY_DELAY);
+            }
+            result => return result,
+        }
+    }
+    fs::rename(from, to)
+}
+
+/// Attempts to rename over a file held open by another process before giving up.
+#[cfg(windows)]
+const RENAME_RETRIES: usize = 10;
+/// Pause between attempts at renaming over a file held open by another process.
+#[cfg(windows)]
+const RENAME_RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(20);
+
+/// Takes an exclusive lock on `file`, held until it is closed: an advisory `flock` on Unix,
+/// a mandatory `LockFileEx` on Windows. Fails with `WouldBlock` if another handle holds it,
+/// in this process or another. File systems without locks, such as some network mounts,
+/// leave the file unlocked.
+pub(crate) fn try_lock(file: &File, path: &Path) -> io::Result<()> {
+    match file.try_lock() {
+        Ok(()) => Ok(()),
+        Err(TryLockError::WouldBlock) => Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            format!("{} is locked: the database is already open", path.display()),
+        )),
+        Err(TryLockError::Error(e)) if e.kind() == io::ErrorKind::Unsupported => Ok(()),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
@@ -0,0 +1,110 @@
+use std::fmt;
+use std::sync::Arc;
+
+/// Returns the length of the prefix of a key, or `None` if it has none.
+type ExtractFn = dyn Fn(&[u8]) -> Option<usize> + Send + Sync;
+
+/// Maps keys to the prefix segments build a second bloom filter over, set through
+/// `DiskOptions::prefix_extractor`, so `Disk::scan_prefix` can skip the segments holding no
+/// key with a prefix without reading any of their blocks.
+///
+/// Keys the extractor returns `None` for are left out of the prefix filters. Every key
+/// starting with an extracted prefix must be extracted to that same prefix, as holds for the
+/// built-in extractors: scans for a prefix that isn't one read every segment. The `name` is
+/// recorded with each filter, so segments written under another extractor are read in full
+/// rather than ruled out.
+#[derive(Clone)]
+pub struct PrefixExtractor {
+    name: String,
+    extract: Arc<ExtractFn>,
+}
+
+impl PrefixExtractor {
+    /// Creates an extractor from a function returning the length of the prefix of a key, or
+    /// `None` if the key has none.
+    pub fn new<F>(name: &str, extract: F) -> PrefixExtractor
+    where
+        F: Fn(&[u8]) -> Option<usize> + Send + Sync + 'static,
+    {
+        PrefixExtractor {
+            name: name.to_owned(),
+            extract: Arc::new(extract),
+        }
+    }
+
+    /// Extracts the first `len` bytes of keys, leaving shorter keys out.
+    pub fn fixed(len: usize) -> PrefixExtractor {
+        PrefixExtractor::new(&format!("fixed:{}", len), move |key| {
+            (key.len() >= len).then_some(len)
+        })
+    }
+
+    /// Extracts keys up to and including the first `delimiter`, as `tenant/` from
+    /// `tenant/users/1`, leaving keys without one out.
+    pub fn delimited(delimiter: u8) -> PrefixExtractor {
+        PrefixExtractor::new(&format!("delimited:{}", delimiter), move |key| {
+            key.iter().position(|&byte| byte == delimiter).map(|at| at + 1)
+        })
+    }
+
+    /// Returns the name identifying the extractor.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the prefix of a key, or `None` if it has none.
+    pub fn prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        (self.extract)(key).map(|len| &key[..len.min(key.len())])
+    }
+
+    /// Returns whether `prefix` is its own prefix, so that prefix filters can rule out the
+    /// keys starting with it.
+    pub fn is_prefix(&self, prefix: &[u8]) -> bool {
+        self.prefix(prefix) == Some(prefix)
+    }
+}
+
+impl fmt::Debug for PrefixExtractor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("PrefixExtractor").field(&self.name).finish()
+    }
+}
+
+/// Returns the smallest key above every key starting with `prefix`, in byte order, `None` if
+/// there is none.
+pub(crate) fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extractors() {
+        let fixed = PrefixExtractor::fixed(4);
+        assert_eq!(fixed.prefix(b"user/1"), Some(&b"user"[..]));
+        assert_eq!(fixed.prefix(b"abc"), None);
+        assert!(fixed.is_prefix(b"user") && !fixed.is_prefix(b"use"));
+
+        let delimited = PrefixExtractor::delimited(b'/');
+        assert_eq!(delimited.prefix(b"acme/users/1"), Some(&b"acme/"[..]));
+        assert_eq!(delimited.prefix(b"acme"), None);
+        assert!(delimited.is_prefix(b"acme/") && !delimited.is_prefix(b"acme/users/"));
+        assert_eq!(delimited.name(), "delimited:47");
+    }
+
+    #[test]
+    fn test_prefix_end() {
+        assert_eq!(prefix_end(b"config/"), Some(b"config0".to_vec()));
+        assert_eq!(prefix_end(b"a\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_end(b"\xff"), None);
+    }
+}
@@ -0,0 +1,305 @@
+use crate::storage::{Storage, StorageBackend, StorageFile, StorageLock};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a low priority request waits before checking again whether the high priority
+/// ones ahead of it are done.
+const PRIORITY_RECHECK: Duration = Duration::from_millis(1);
+
+/// Caps the bytes per second flushes and compactions write, so background work doesn't
+/// starve foreground reads and WAL writes of disk bandwidth on slow disks.
+///
+/// The limiter is a token bucket: up to `burst` bytes can be written at once after a pause,
+/// after which writes are paced at `bytes_per_second`. A write larger than the bucket isn't
+/// split; it goes through and the writes after it wait until it is paid for. While a high
+/// priority write is waiting, low priority ones wait behind it.
+///
+/// The handle is cheap to clone; clones share the same budget, so handing one to several
+/// databases through `DiskOptions::rate_limiter` bounds their background I/O together.
+#[derive(Clone)]
+pub struct RateLimiter {
+    shared: Arc<Shared>,
+}
+
+/// Which background work a write is for, deciding who goes first when the budget is short.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoPriority {
+    /// Flushes: they free memory and WAL files, and stalled writes wait for them.
+    High,
+    /// Compactions, which only make reads cheaper.
+    Low,
+}
+
+struct Shared {
+    burst: u64,
+    bucket: Mutex<Bucket>,
+    changed: Condvar,
+}
+
+struct Bucket {
+    bytes_per_second: u64,
+    /// Bytes that can be written right away; negative while a large write is paid off.
+    available: f64,
+    refilled: Instant,
+    /// Number of high priority requests waiting.
+    waiting_high: usize,
+    high_priority_bytes: u64,
+    low_priority_bytes: u64,
+    wait_time: Duration,
+}
+
+/// Counters of a rate limiter, summed over every database sharing it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RateLimiterStats {
+    pub bytes_per_second: u64,
+    pub burst: u64,
+    /// Bytes written by flushes.
+    pub high_priority_bytes: u64,
+    /// Bytes written by compactions.
+    pub low_priority_bytes: u64,
+    /// Time writes spent waiting for the budget.
+    pub wait_time: Duration,
+}
+
+impl RateLimiter {
+    /// Creates a limiter allowing `bytes_per_second`, and up to `burst` bytes at once.
+    pub fn new(bytes_per_second: u64, burst: u64) -> RateLimiter {
+        let burst = burst.max(1);
+        RateLimiter {
+            shared: Arc::new(Shared {
+                burst,
+                bucket: Mutex::new(Bucket {
+                    bytes_per_second: bytes_per_second.max(1),
+                    available: burst as f64,
+                    refilled: Instant::now(),
+                    waiting_high: 0,
+                    high_priority_bytes: 0,
+                    low_priority_bytes: 0,
+                    wait_time: Duration::ZERO,
+                }),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Changes the rate, for example to let compactions catch up during quiet hours.
+    pub fn set_bytes_per_second(&self, bytes_per_second: u64) {
+        let mut bucket = self.shared.bucket.lock().unwrap();
+        bucket.refill(self.shared.burst);
+        bucket.bytes_per_second = bytes_per_second.max(1);
+        self.shared.changed.notify_all();
+    }
+
+    /// Blocks until `bytes` may be written at `priority`, and takes them from the budget.
+    pub fn request(&self, bytes: usize, priority: IoPriority) {
+        if bytes == 0 {
+            return;
+        }
+        let start = Instant::now();
+        let mut waited = false;
+        let mut bucket = self.shared.bucket.lock().unwrap();
+        if priority == IoPriority::High {
+            bucket.waiting_high += 1;
+        }
+        loop {
+            bucket.refill(self.shared.burst);
+            let first = priority == IoPriority::High || bucket.waiting_high == 0;
+            if first && bucket.available > 0.0 {
+                break;
+            }
+            let wait = match first {
+                true => Duration::from_secs_f64(
+                    (1.0 - bucket.available) / bucket.bytes_per_second as f64,
+                ),
+                false => PRIORITY_RECHECK,
+            };
+            bucket = self.shared.changed.wait_timeout(bucket, wait).unwrap().0;
+            waited = true;
+        }
+        bucket.available -= bytes as f64;
+        match priority {
+            IoPriority::High => {
+                bucket.waiting_high -= 1;
+                bucket.high_priority_bytes += bytes as u64;
+                self.shared.changed.notify_all();
+            }
+            IoPriority::Low => bucket.low_priority_bytes += bytes as u64,
+        }
+        if waited {
+            bucket.wait_time += start.elapsed();
+        }
+    }
+
+    /// Returns the counters of the limiter.
+    pub fn stats(&self) -> RateLimiterStats {
+        let bucket = self.shared.bucket.lock().unwrap();
+        RateLimiterStats {
+            bytes_per_second: bucket.bytes_per_second,
+            burst: self.shared.burst,
+            high_priority_bytes: bucket.high_priority_bytes,
+            low_priority_bytes: bucket.low_priority_bytes,
+            wait_time: bucket.wait_time,
+        }
+    }
+
+    /// Returns `storage` with the files it creates paying for their writes at `priority`.
+    pub(crate) fn limit(&self, storage: &Storage, priority: IoPriority) -> Storage {
+        Storage::new(LimitedBackend {
+            storage: storage.clone(),
+            limiter: self.clone(),
+            priority,
+        })
+    }
+}
+
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stats = self.stats();
+        f.debug_struct("RateLimiter")
+            .field("bytes_per_second", &stats.bytes_per_second)
+            .field("burst", &stats.burst)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Bucket {
+    /// Adds the bytes earned since the last refill, up to the burst.
+    fn refill(&mut self, burst: u64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        let earned = elapsed * self.bytes_per_second as f64;
+        self.available = (self.available + earned).min(burst as f64);
+        self.refilled = now;
+    }
+}
+
+/// Storage whose created files wait for the rate limiter before every append.
+struct LimitedBackend {
+    storage: Storage,
+    limiter: RateLimiter,
+    priority: IoPriority,
+}
+
+impl StorageBackend for LimitedBackend {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(LimitedFile {
+            file: self.storage.create(path)?,
+            limiter: self.limiter.clone(),
+            priority: self.priority,
+        }))
+    }
+
+    fn append(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        self.storage.append(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        self.storage.open(path)
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        self.storage.delete(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.storage.rename(from, to)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.storage.list(dir)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.storage.exists(path)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        self.storage.sync_dir(dir)
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<StorageLock> {
+        self.storage.lock(path)
+    }
+
+    fn is_local(&self) -> bool {
+        self.storage.is_local()
+    }
+}
+
+struct LimitedFile {
+    file: Box<dyn StorageFile>,
+    limiter: RateLimiter,
+    priority: IoPriority,
+}
+
+impl StorageFile for LimitedFile {
+    fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        self.limiter.request(data.len(), self.priority);
+        self.file.append(data)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.file.read_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.file.size()
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.file.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_paces_requests() {
+        let start = Instant::now();
+        let limiter = RateLimiter::new(1_000, 100);
+        // The burst goes through at once, then a request larger than the bucket.
+        limiter.request(100, IoPriority::Low);
+        limiter.request(1_000, IoPriority::High);
+        assert_eq!(limiter.stats().wait_time, Duration::ZERO);
+        // The next one waits until the large one is paid for, a second after the limiter
+        // was created however slowly the requests before it ran.
+        limiter.request(1, IoPriority::Low);
+        assert!(start.elapsed() >= Duration::from_secs(1));
+
+        let stats = limiter.stats();
+        assert_eq!(
+            (stats.high_priority_bytes, stats.low_priority_bytes),
+            (1_000, 101)
+        );
+        assert!(stats.wait_time > Duration::ZERO && stats.wait_time <= start.elapsed());
+    }
+
+    #[test]
+    fn test_high_priority_goes_first() {
+        let limiter = RateLimiter::new(1_000, 100);
+        limiter.request(300, IoPriority::Low);
+
+        // The flush is queued before the compaction, and is served as soon as the budget
+        // allows, while the compaction waits behind it.
+        let high = {
+            let limiter = limiter.clone();
+            thread::spawn(move || {
+                limiter.request(100, IoPriority::High);
+                Instant::now()
+            })
+        };
+        thread::sleep(Duration::from_millis(20));
+        limiter.request(100, IoPriority::Low);
+        let low = Instant::now();
+        assert!(high.join().unwrap() <= low);
+
+        limiter.set_bytes_per_second(1_000_000);
+        assert_eq!(limiter.stats().bytes_per_second, 1_000_000);
+    }
+}
@@ -0,0 +1,48 @@
+use crate::snapshot::Snapshot;
+use crate::sstable::BlockReads;
+use std::fmt;
+
+/// How `Disk::get_with` and `Disk::scan_read` read the database.
+pub struct ReadOptions {
+    /// Reads the segment blocks from their files even when the block cache holds them,
+    /// checking their checksums again. Blocks read from a file are always checked, so this
+    /// only matters for the cached ones: it makes a read tell whether the files themselves
+    /// are intact, as backup verification wants, at the cost of reading every block.
+    pub verify_checksums: bool,
+    /// Keeps the blocks read from the segment files in the block cache. Clearing it keeps a
+    /// large scan from evicting the blocks other reads use; it still reads the cached ones.
+    pub fill_cache: bool,
+    /// Reads the database as of this snapshot instead of the latest writes. It must have
+    /// been taken from the same database.
+    pub snapshot: Option<Snapshot>,
+}
+
+impl ReadOptions {
+    /// Returns how segment blocks are read.
+    pub(crate) fn block_reads(&self) -> BlockReads {
+        BlockReads {
+            verify: self.verify_checksums,
+            fill_cache: self.fill_cache,
+        }
+    }
+}
+
+impl Default for ReadOptions {
+    fn default() -> ReadOptions {
+        ReadOptions {
+            verify_checksums: false,
+            fill_cache: true,
+            snapshot: None,
+        }
+    }
+}
+
+impl fmt::Debug for ReadOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReadOptions")
+            .field("verify_checksums", &self.verify_checksums)
+            .field("fill_cache", &self.fill_cache)
+            .field("snapshot", &self.snapshot.as_ref().map(Snapshot::sequence))
+            .finish()
+    }
+}
@@ -0,0 +1,93 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Writes the first `len` bytes of `src` to a new file at `dest` and returns it, open for
+/// writing. Where the file system supports it (FICLONE on Linux, for btrfs, XFS and the
+/// like; clonefile on APFS) the new file is a copy-on-write clone sharing the blocks of
+/// `src`, which takes no time or space whatever its size. Otherwise the bytes are copied.
+pub(crate) fn clone_or_copy(src: &File, len: u64, dest: &Path) -> io::Result<File> {
+    let out = match clone(src, dest) {
+        Ok(out) => out,
+        Err(_) => {
+            let mut out = File::create(dest)?;
+            if io::copy(&mut src.take(len), &mut out)? != len {
+                return Err(shrank());
+            }
+            return Ok(out);
+        }
+    };
+    // The clone holds all of `src`, which may have grown past `len` since.
+    if out.metadata()?.len() < len {
+        return Err(shrank());
+    }
+    out.set_len(len)?;
+    Ok(out)
+}
+
+fn shrank() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while being copied")
+}
+
+#[cfg(target_os = "linux")]
+fn clone(src: &File, dest: &Path) -> io::Result<File> {
+    use std::os::unix::io::AsRawFd;
+
+    let out = File::create(dest)?;
+    // SAFETY: both descriptors stay open for the duration of the call.
+    match unsafe { libc::ioctl(out.as_raw_fd(), libc::FICLONE as _, src.as_raw_fd()) } {
+        0 => Ok(out),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn clone(src: &File, dest: &Path) -> io::Result<File> {
+    use std::ffi::CString;
+    use std::fs::{remove_file, OpenOptions};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::AsRawFd;
+
+    let dest_path = CString::new(dest.as_os_str().as_bytes())?;
+    // clonefile refuses to replace an existing file.
+    let _ = remove_file(dest);
+    // SAFETY: the descriptor stays open and the path is a valid C string for the call.
+    match unsafe { libc::fclonefileat(src.as_raw_fd(), libc::AT_FDCWD, dest_path.as_ptr(), 0) } {
+        0 => OpenOptions::new().write(true).open(dest),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn clone(_src: &File, _dest: &Path) -> io::Result<File> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "file cloning is not supported"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use std::fs::{self, create_dir_all, remove_dir_all};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_clone_or_copy_keeps_prefix() {
+        let mut rng = rand::thread_rng();
+        let test_dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir_all(&test_dir).unwrap();
+        let src_path = test_dir.join("source");
+        let data: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+        fs::write(&src_path, &data).unwrap();
+
+        let src = File::open(&src_path).unwrap();
+        let out = clone_or_copy(&src, 40_000, &test_dir.join("prefix")).unwrap();
+        out.sync_all().unwrap();
+        assert_eq!(fs::read(test_dir.join("prefix")).unwrap(), &data[..40_000]);
+
+        let src = File::open(&src_path).unwrap();
+        let err = clone_or_copy(&src, 100_000, &test_dir.join("longer")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+}
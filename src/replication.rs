@@ -0,0 +1,973 @@
+//! Primary/replica replication over TCP. Requires the `replication` feature.
+//!
+//! A `ReplicationServer` runs next to the primary database and streams its committed writes,
+//! as `Disk::tail` reads them, to every connected `Replica`. A replica applies them to a
+//! database of its own and only answers reads.
+//!
+//! A replica opens a connection with a handshake carrying the sequence number of the last
+//! primary write it applied. When the primary's WAL still holds every write after it, the
+//! primary streams them and carries on with new writes as they commit. Otherwise, for a new
+//! replica whose primary has already flushed writes, or one that was offline for too long,
+//! the primary takes a checkpoint and sends its files first; the replica replaces its
+//! database with them and catches up from the sequence number of the checkpoint. The
+//! position of a replica is saved in its directory after every batch of writes it applies,
+//! so it resumes where it stopped after a restart or a lost connection.
+//!
+//! The primary is known by a random id, kept in a `REPLICATION-ID` file of its directory. A
+//! replica saves the id along with its position and refuses to follow a primary with another
+//! one, such as a database recreated at the same address, whose sequence numbers mean nothing
+//! to it. When `ReplicationOptions::secret` is set, the primary only serves replicas
+//! presenting the same secret.
+//!
+//! Every message is a frame starting with a kind byte, numbers little-endian:
+//!
+//! ```text
+//! handshake: "FLXR" | version: u8 | position: u64 | id: u64
+//!                        (both ways; the primary sends position 0 and its id, a replica the
+//!                         id of the primary it follows, 0 until it first connects)
+//! secret:    len: u16 | secret                           (replica only, after its handshake)
+//! heartbeat: 0 | last sequence: u64
+//! write:     1 | sequence: u64 | timestamp: u128 | op: u8 | key len: u32 | key
+//!              | value len: u32 | value                  (op 0 put, 1 delete, 2 range delete
+//!                                                         up to the value)
+//! snapshot:  2 | sequence: u64 | file count: u32
+//!              | (name len: u16 | name | size: u64 | contents)*
+//! ```
+
+use crate::disk::{Disk, DiskEntry};
+use crate::error::FluxError;
+use crate::logging::{Level, Subsystem};
+use crate::manifest::{
+    is_database_file_name, HISTORY_FILE, MANIFEST_FILE, ROTATED_HISTORY_FILE, SEGMENT_EXTENSION,
+    VALUE_LOG_EXTENSION, WAL_EXTENSION,
+};
+use crate::options::DiskOptions;
+use crate::stats::STATS_FILE;
+use crate::subscription::{ChangeEvent, ChangeOp};
+use crate::wal_tail::WalTail;
+use crate::write_batch::{TimestampRegression, WriteBatch};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Bytes opening the handshake of both sides.
+const MAGIC: [u8; 4] = *b"FLXR";
+/// Version of the protocol. Both sides must speak the same one.
+const PROTOCOL_VERSION: u8 = 1;
+/// How often the primary sends a heartbeat to an idle replica.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a replica waits for a frame before giving up on the connection.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a replica waits before connecting again after losing the primary.
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+/// Most writes a replica applies in one batch.
+const MAX_APPLY_BATCH: usize = 1024;
+/// Name of the file a replica keeps its position in.
+const POSITION_FILE: &str = "REPLICA";
+/// Name of the file a primary keeps its id in.
+const ID_FILE: &str = "REPLICATION-ID";
+/// How many times the tail a checkpoint is sent with is taken again when a flush retires
+/// the writes it should start with, and how long is waited in between.
+const TAIL_ATTEMPTS: usize = 100;
+const TAIL_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+const HEARTBEAT_FRAME: u8 = 0;
+const WRITE_FRAME: u8 = 1;
+const SNAPSHOT_FRAME: u8 = 2;
+
+const PUT_OP: u8 = 0;
+const DELETE_OP: u8 = 1;
+const RANGE_DELETE_OP: u8 = 2;
+
+/// Settings shared by a `ReplicationServer` and the `Replica`s following it.
+#[derive(Clone, Default)]
+pub struct ReplicationOptions {
+    /// Secret a replica must present to be served, or `None` to serve any replica. It crosses
+    /// the network in the clear, so on networks others can listen to, the connections should
+    /// go through a tunnel such as TLS or a VPN.
+    pub secret: Option<Vec<u8>>,
+}
+
+impl ReplicationOptions {
+    /// Returns whether a replica presenting `secret` may be served, comparing in constant
+    /// time so the secret can't be guessed a byte at a time.
+    fn accepts(&self, secret: &[u8]) -> bool {
+        let expected = self.secret.as_deref().unwrap_or_default();
+        let diff = expected
+            .iter()
+            .zip(secret)
+            .fold(0, |diff, (a, b)| diff | (a ^ b));
+        expected.len() == secret.len() && diff == 0
+    }
+}
+
+/// Serves the writes of a primary database to replicas. Requires the `replication` feature.
+///
+/// Each replica is served on a thread of its own. Bootstrapping a replica needs
+/// `Disk::checkpoint`, so the primary must keep its files on the local file system. The
+/// server holds a handle to the database, keeping it open until the server is dropped.
+pub struct ReplicationServer {
+    local_addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ReplicationServer {
+    /// Starts serving the writes of `disk` on `addr`, to any replica. Port 0 picks a free
+    /// port, which `local_addr` returns.
+    pub fn start<A: ToSocketAddrs>(disk: &Disk, addr: A) -> io::Result<ReplicationServer> {
+        ReplicationServer::start_with(disk, addr, ReplicationOptions::default())
+    }
+
+    /// Starts serving the writes of `disk` on `addr`, to the replicas `replication` accepts.
+    pub fn start_with<A: ToSocketAddrs>(
+        disk: &Disk,
+        addr: A,
+        replication: ReplicationOptions,
+    ) -> io::Result<ReplicationServer> {
+        let id = database_id(disk)?;
+        let replication = Arc::new(replication);
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let (disk, stop) = (disk.clone(), stop.clone());
+            thread::Builder::new()
+                .name("fluxdb-replication".to_owned())
+                .spawn(move || {
+                    let mut replicas = Vec::new();
+                    for stream in listener.incoming() {
+                        if stop.load(Ordering::Acquire) {
+                            break;
+                        }
+                        let Ok(stream) = stream else { continue };
+                        let (disk, stop) = (disk.clone(), stop.clone());
+                        let replication = replication.clone();
+                        let replica = thread::Builder::new()
+                            .name("fluxdb-replica-feed".to_owned())
+                            .spawn(move || {
+                                let peer = stream.peer_addr();
+                                let served = serve(&disk, id, &replication, stream, &stop);
+                                if let Err(e) = served {
+                                    let logger = &disk.options().logger;
+                                    let message = match peer {
+                                        Ok(peer) => format!("replica {} dropped: {}", peer, e),
+                                        Err(_) => format!("replica dropped: {}", e),
+                                    };
+                                    logger.log(
+                                        Level::Info,
+                                        Subsystem::Replication,
+                                        format_args!("{}", message),
+                                    );
+                                }
+                            });
+                        replicas.extend(replica.ok());
+                        replicas.retain(|replica: &JoinHandle<()>| !replica.is_finished());
+                    }
+                    for replica in replicas {
+                        let _ = replica.join();
+                    }
+                })?
+        };
+        Ok(ReplicationServer {
+            local_addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the address the server listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for ReplicationServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        // Wakes the listener up so it sees the flag. Replica feeds see it within a heartbeat.
+        let mut wake = self.local_addr;
+        if wake.ip().is_unspecified() {
+            wake.set_ip(match wake.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        if TcpStream::connect_timeout(&wake, READ_TIMEOUT).is_ok() {
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+/// Returns the id of the database replicas follow, creating it on first use.
+fn database_id(disk: &Disk) -> io::Result<u64> {
+    let storage = &disk.options().storage;
+    match storage.read(&disk.dir().join(ID_FILE)) {
+        Ok(contents) => String::from_utf8_lossy(&contents)
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .filter(|&id| id != 0)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad replication id")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            // 0 stands for no id in the handshake.
+            let id = rand::random::<u64>().max(1);
+            storage.replace(disk.dir(), ID_FILE, id.to_string().as_bytes())?;
+            Ok(id)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Feeds one replica until it disconnects or the server stops.
+fn serve(
+    disk: &Disk,
+    id: u64,
+    replication: &ReplicationOptions,
+    stream: TcpStream,
+    stop: &AtomicBool,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_write_timeout(Some(READ_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    let (position, followed) = read_handshake(&mut &stream)?;
+    let len = read_u16(&mut &stream)? as usize;
+    // Nothing is sent to a replica that failed to authenticate, not even the id.
+    if !replication.accepts(&read_vec(&mut &stream, len)?) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "wrong replication secret",
+        ));
+    }
+    let mut out = BufWriter::new(stream);
+    write_handshake(&mut out, 0, id)?;
+    if followed != 0 && followed != id {
+        return Err(protocol_error(&format!(
+            "the replica follows database {}, not this one",
+            followed
+        )));
+    }
+
+    let tail = match position <= disk.last_sequence() {
+        true => disk.tail(position).ok(),
+        false => None,
+    };
+    let mut tail = match tail {
+        Some(tail) => tail,
+        None => bootstrap(disk, &mut out)?,
+    };
+    out.flush()?;
+
+    while !stop.load(Ordering::Acquire) {
+        match tail.recv_timeout(HEARTBEAT_INTERVAL) {
+            Some(event) => {
+                write_event(&mut out, &event)?;
+                while let Some(event) = tail.try_recv() {
+                    write_event(&mut out, &event)?;
+                }
+            }
+            // The replica reconnects and resumes from the last write it applied.
+            None if tail.is_ended() => {
+                return Err(io::Error::other("the replica fell too far behind"));
+            }
+            None => {
+                out.write_all(&[HEARTBEAT_FRAME])?;
+                out.write_all(&disk.last_sequence().to_le_bytes())?;
+            }
+        }
+        out.flush()?;
+    }
+    Ok(())
+}
+
+/// Sends a checkpoint of the database to a replica, and returns the tail of the writes
+/// committed since it was taken.
+fn bootstrap(disk: &Disk, out: &mut impl Write) -> io::Result<WalTail> {
+    static NEXT_CHECKPOINT: AtomicU64 = AtomicU64::new(0);
+
+    // Following the writes before the checkpoint is taken leaves no gap after it. A flush
+    // may retire the files of the few writes committed meanwhile, in which case it is tried
+    // again shortly.
+    let mut attempts = 1;
+    let tail = loop {
+        match disk.tail(disk.last_sequence()) {
+            Ok(tail) => break tail,
+            Err(FluxError::InvalidArgument(_)) if attempts < TAIL_ATTEMPTS => {
+                attempts += 1;
+                thread::sleep(TAIL_RETRY_DELAY);
+            }
+            Err(e) => return Err(into_io(e)),
+        }
+    };
+    let dir = std::env::temp_dir().join(format!(
+        "fluxdb-replication-{}-{}",
+        std::process::id(),
+        NEXT_CHECKPOINT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let result = disk
+        .checkpoint(&dir.to_string_lossy())
+        .and_then(|sequence| send_files(&dir, sequence, out));
+    let _ = fs::remove_dir_all(&dir);
+    result?;
+    Ok(tail)
+}
+
+fn send_files(dir: &Path, sequence: u64, out: &mut impl Write) -> io::Result<()> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let file = File::open(entry.path())?;
+        let size = file.metadata()?.len();
+        files.push((name, file, size));
+    }
+    out.write_all(&[SNAPSHOT_FRAME])?;
+    out.write_all(&sequence.to_le_bytes())?;
+    out.write_all(&(files.len() as u32).to_le_bytes())?;
+    for (name, file, size) in files {
+        out.write_all(&(name.len() as u16).to_le_bytes())?;
+        out.write_all(name.as_bytes())?;
+        out.write_all(&size.to_le_bytes())?;
+        let copied = io::copy(&mut file.take(size), out)?;
+        if copied != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "checkpoint file shrank",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// A read-only copy of a primary database, kept up to date over the network. Requires the
+/// `replication` feature.
+///
+/// The replica applies the writes of the primary, in commit order, to a database in its own
+/// directory, syncing each batch before saving its position. Reads see a consistent prefix
+/// of the primary's writes, up to `position`. When the connection is lost the replica keeps
+/// serving reads and connects again, resuming from its position.
+pub struct Replica {
+    shared: Arc<ReplicaShared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+struct ReplicaShared {
+    dir: PathBuf,
+    options: DiskOptions,
+    replication: ReplicationOptions,
+    /// `None` only while a checkpoint from the primary replaces the database.
+    disk: RwLock<Option<Disk>>,
+    position: Mutex<u64>,
+    /// Id of the primary followed, 0 until the replica first connects.
+    primary_id: AtomicU64,
+    applied: Condvar,
+    stop: AtomicBool,
+    /// Connection to the primary, shut down to stop the replica.
+    stream: Mutex<Option<TcpStream>>,
+}
+
+impl Replica {
+    /// Opens the replica database in `dir`, creating it if needed, and starts following the
+    /// primary served at `primary`. Writes are synced as they are applied, whatever
+    /// `sync_writes` says, and those older than the version a key holds are skipped. The
+    /// database must not be written to other than by the replica.
+    pub fn start<A: ToSocketAddrs>(
+        dir: &str,
+        options: DiskOptions,
+        primary: A,
+    ) -> io::Result<Replica> {
+        Replica::start_with(dir, options, primary, ReplicationOptions::default())
+    }
+
+    /// Starts a replica as `start` does, presenting the secret of `replication` to the
+    /// primary.
+    pub fn start_with<A: ToSocketAddrs>(
+        dir: &str,
+        options: DiskOptions,
+        primary: A,
+        replication: ReplicationOptions,
+    ) -> io::Result<Replica> {
+        let primary: Vec<SocketAddr> = primary.to_socket_addrs()?.collect();
+        // Writes the primary kept as older versions come with the latest version written
+        // again after them, so skipping them leaves the same data.
+        let options = DiskOptions {
+            sync_writes: true,
+            on_timestamp_regression: TimestampRegression::Ignore,
+            ..options
+        };
+        let dir = PathBuf::from(dir);
+        if options.storage.is_local() {
+            fs::create_dir_all(&dir)?;
+        }
+        // The position, followed by the id of the primary once known.
+        let (position, primary_id) = match options.storage.read(&dir.join(POSITION_FILE)) {
+            Ok(contents) => {
+                let contents = String::from_utf8_lossy(&contents);
+                let mut fields = contents.split_whitespace().map(str::parse::<u64>);
+                match (fields.next(), fields.next().unwrap_or(Ok(0)), fields.next()) {
+                    (Some(Ok(position)), Ok(id), None) => (position, id),
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "bad replica position",
+                        ))
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (0, 0),
+            Err(e) => return Err(e),
+        };
+        let disk = Disk::open(&dir.to_string_lossy(), options.clone())?;
+        let shared = Arc::new(ReplicaShared {
+            dir,
+            options,
+            replication,
+            disk: RwLock::new(Some(disk)),
+            position: Mutex::new(position),
+            primary_id: AtomicU64::new(primary_id),
+            applied: Condvar::new(),
+            stop: AtomicBool::new(false),
+            stream: Mutex::new(None),
+        });
+        let thread = {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("fluxdb-replica".to_owned())
+                .spawn(move || shared.follow(&primary))?
+        };
+        Ok(Replica {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the sequence number, on the primary, of the last write applied.
+    pub fn position(&self) -> u64 {
+        *self.shared.position.lock().unwrap()
+    }
+
+    /// Waits at most `timeout` for the write numbered `sequence` on the primary to be
+    /// applied, and returns whether it was.
+    pub fn wait_for(&self, sequence: u64, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut position = self.shared.position.lock().unwrap();
+        while *position < sequence {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            position = self
+                .shared
+                .applied
+                .wait_timeout(position, deadline - now)
+                .unwrap()
+                .0;
+        }
+        true
+    }
+
+    /// Looks up a key, as `Disk::get` does.
+    pub fn get(&self, key: &[u8]) -> io::Result<Option<DiskEntry>> {
+        self.shared.read(|disk| disk.get(key))
+    }
+
+    /// Returns the live entries within the range, as `Disk::scan` does.
+    pub fn scan<'a, R: RangeBounds<&'a [u8]>>(
+        &self,
+        range: R,
+    ) -> Result<Vec<DiskEntry>, FluxError> {
+        self.shared.read(|disk| disk.scan(range))
+    }
+}
+
+impl Drop for Replica {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Release);
+        if let Some(stream) = self.shared.stream.lock().unwrap().as_ref() {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        }
+        self.shared.applied.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl ReplicaShared {
+    /// Runs `f` on the database, failing if it couldn't be reopened after a bootstrap.
+    fn read<T, E: From<io::Error>>(&self, f: impl FnOnce(&Disk) -> Result<T, E>) -> Result<T, E> {
+        match self.disk.read().unwrap().as_ref() {
+            Some(disk) => f(disk),
+            None => Err(
+                io::Error::new(io::ErrorKind::NotFound, "the replica database is closed").into(),
+            ),
+        }
+    }
+
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Acquire)
+    }
+
+    /// Follows the primary until the replica is dropped, connecting again whenever the
+    /// connection is lost.
+    fn follow(&self, primary: &[SocketAddr]) {
+        while !self.stopped() {
+            if let Err(e) = self.stream_from(primary) {
+                if !self.stopped() {
+                    let message = format_args!("connection to the primary lost: {}", e);
+                    self.options
+                        .logger
+                        .log(Level::Warn, Subsystem::Replication, message);
+                }
+            }
+            let position = self.position.lock().unwrap();
+            if !self.stopped() {
+                let _ = self.applied.wait_timeout(position, RECONNECT_DELAY);
+            }
+        }
+    }
+
+    fn stream_from(&self, primary: &[SocketAddr]) -> io::Result<()> {
+        let stream = TcpStream::connect(primary)?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+        *self.stream.lock().unwrap() = Some(stream.try_clone()?);
+        // The replica may have been dropped while connecting.
+        if self.stopped() {
+            return Ok(());
+        }
+        let followed = self.primary_id.load(Ordering::Acquire);
+        let mut out = BufWriter::new(&stream);
+        let secret = self.replication.secret.as_deref().unwrap_or_default();
+        out.write_all(&handshake(*self.position.lock().unwrap(), followed))?;
+        out.write_all(&(secret.len() as u16).to_le_bytes())?;
+        out.write_all(secret)?;
+        out.flush()?;
+        drop(out);
+        let mut reader = BufReader::new(stream);
+        let (_, id) = read_handshake(&mut reader)?;
+        if followed != 0 && id != followed {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the primary is database {}, not {} the replica follows; delete the \
+                     replica's directory to follow it from scratch",
+                    id, followed
+                ),
+            ));
+        }
+        if followed == 0 {
+            self.primary_id.store(id, Ordering::Release);
+            let position = *self.position.lock().unwrap();
+            self.save_position(position)?;
+        }
+
+        loop {
+            match read_frame(&mut reader)? {
+                Frame::Heartbeat => {}
+                Frame::Snapshot { sequence, files } => {
+                    self.replace(&mut reader, sequence, files)?
+                }
+                Frame::Write(event) => {
+                    let mut events = vec![event];
+                    // Writes already received are applied together.
+                    while !reader.buffer().is_empty() && events.len() < MAX_APPLY_BATCH {
+                        match read_frame(&mut reader)? {
+                            Frame::Heartbeat => {}
+                            Frame::Write(event) => events.push(event),
+                            Frame::Snapshot { .. } => {
+                                return Err(protocol_error("unexpected snapshot"))
+                            }
+                        }
+                    }
+                    self.apply(events)?;
+                }
+            }
+        }
+    }
+
+    /// Applies writes received from the primary, skipping those already applied, then saves
+    /// the position.
+    fn apply(&self, events: Vec<ChangeEvent>) -> io::Result<()> {
+        let position = *self.position.lock().unwrap();
+        let Some(last) = events
+            .last()
+            .map(|event| event.sequence)
+            .filter(|&last| last > position)
+        else {
+            return Ok(());
+        };
+        self.read(|disk| {
+            let failed = || io::Error::other("applying replicated writes failed");
+            let mut batch = WriteBatch::new();
+            for event in events.into_iter().filter(|event| event.sequence > position) {
+                match event.op {
+                    ChangeOp::Put(value) => batch.put_at(&event.key, &value, event.timestamp),
+                    ChangeOp::Delete => batch.delete_at(&event.key, event.timestamp),
+                    ChangeOp::DeleteRange { end } => {
+                        if !batch.is_empty() {
+                            disk.write(std::mem::take(&mut batch))
+                                .map_err(|_| failed())?;
+                        }
+                        disk.delete_range_at(&event.key, &end, event.timestamp)
+                            .map_err(|_| failed())?;
+                    }
+                }
+            }
+            if !batch.is_empty() {
+                disk.write(batch).map_err(|_| failed())?;
+            }
+            Ok::<_, io::Error>(())
+        })?;
+        self.save_position(last)
+    }
+
+    /// Replaces the database with the files of a checkpoint of the primary holding the
+    /// writes up to `sequence`.
+    fn replace(&self, reader: &mut impl Read, sequence: u64, files: u32) -> io::Result<()> {
+        // Until the checkpoint is complete, the replica must bootstrap again after a restart.
+        self.save_position(0)?;
+        let mut disk = self.disk.write().unwrap();
+        // Closes the database, so its files can be replaced.
+        drop(disk.take());
+        let installed = self.install(reader, files);
+        *disk = Some(Disk::open(
+            &self.dir.to_string_lossy(),
+            self.options.clone(),
+        )?);
+        drop(disk);
+        installed?;
+        let message = format_args!(
+            "bootstrapped from a checkpoint of the primary at {}",
+            sequence
+        );
+        self.options
+            .logger
+            .log(Level::Info, Subsystem::Replication, message);
+        self.save_position(sequence)
+    }
+
+    /// Deletes the files of the database and writes those of a checkpoint in their place.
+    /// Other files of the directory are left alone.
+    fn install(&self, reader: &mut impl Read, files: u32) -> io::Result<()> {
+        let storage = &self.options.storage;
+        for path in storage.list(&self.dir)? {
+            let name = path.file_name().and_then(|name| name.to_str());
+            if name.is_some_and(is_database_file) {
+                storage.delete(&path)?;
+            }
+        }
+        let mut buffer = vec![0; 64 * 1024];
+        for _ in 0..files {
+            let len = read_u16(reader)? as usize;
+            let name = read_vec(reader, len)?;
+            let name = String::from_utf8(name).map_err(|_| protocol_error("bad file name"))?;
+            if !is_database_file(&name) {
+                return Err(protocol_error(&format!("unexpected file {:?}", name)));
+            }
+            let mut file = storage.create(&self.dir.join(&name))?;
+            let mut remaining = read_u64(reader)?;
+            while remaining > 0 {
+                let chunk = &mut buffer[..remaining.min(64 * 1024) as usize];
+                reader.read_exact(chunk)?;
+                file.append(chunk)?;
+                remaining -= chunk.len() as u64;
+            }
+            file.sync()?;
+        }
+        storage.sync_dir(&self.dir)
+    }
+
+    fn save_position(&self, sequence: u64) -> io::Result<()> {
+        let contents = match self.primary_id.load(Ordering::Acquire) {
+            0 => sequence.to_string(),
+            id => format!("{} {}", sequence, id),
+        };
+        self.options
+            .storage
+            .replace(&self.dir, POSITION_FILE, contents.as_bytes())?;
+        *self.position.lock().unwrap() = sequence;
+        self.applied.notify_all();
+        Ok(())
+    }
+}
+
+enum Frame {
+    Heartbeat,
+    Write(ChangeEvent),
+    Snapshot { sequence: u64, files: u32 },
+}
+
+/// Returns whether a file is one of a database, as written by a checkpoint or the database
+/// itself, rather than left in the replica's directory by its owner.
+fn is_database_file(name: &str) -> bool {
+    [
+        MANIFEST_FILE,
+        HISTORY_FILE,
+        ROTATED_HISTORY_FILE,
+        STATS_FILE,
+    ]
+    .contains(&name)
+        || [WAL_EXTENSION, SEGMENT_EXTENSION, VALUE_LOG_EXTENSION]
+            .iter()
+            .any(|extension| is_database_file_name(name, extension))
+}
+
+fn handshake(position: u64, id: u64) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(PROTOCOL_VERSION);
+    bytes.extend_from_slice(&position.to_le_bytes());
+    bytes.extend_from_slice(&id.to_le_bytes());
+    bytes
+}
+
+fn write_handshake(out: &mut impl Write, position: u64, id: u64) -> io::Result<()> {
+    out.write_all(&handshake(position, id))?;
+    out.flush()
+}
+
+/// Reads the handshake of the other side, returning the position and id it carries.
+fn read_handshake(reader: &mut impl Read) -> io::Result<(u64, u64)> {
+    let mut magic = [0; 5];
+    reader.read_exact(&mut magic)?;
+    if magic[..4] != MAGIC {
+        return Err(protocol_error("not a FluxDB replication peer"));
+    }
+    if magic[4] != PROTOCOL_VERSION {
+        return Err(protocol_error(&format!(
+            "unsupported protocol version {}",
+            magic[4]
+        )));
+    }
+    Ok((read_u64(reader)?, read_u64(reader)?))
+}
+
+fn write_event(out: &mut impl Write, event: &ChangeEvent) -> io::Result<()> {
+    let (op, value) = match &event.op {
+        ChangeOp::Put(value) => (PUT_OP, &value[..]),
+        ChangeOp::Delete => (DELETE_OP, &[][..]),
+        ChangeOp::DeleteRange { end } => (RANGE_DELETE_OP, &end[..]),
+    };
+    out.write_all(&[WRITE_FRAME])?;
+    out.write_all(&event.sequence.to_le_bytes())?;
+    out.write_all(&event.timestamp.to_le_bytes())?;
+    out.write_all(&[op])?;
+    out.write_all(&(event.key.len() as u32).to_le_bytes())?;
+    out.write_all(&event.key)?;
+    out.write_all(&(value.len() as u32).to_le_bytes())?;
+    out.write_all(value)
+}
+
+fn read_frame(reader: &mut impl Read) -> io::Result<Frame> {
+    match read_vec(reader, 1)?[0] {
+        HEARTBEAT_FRAME => {
+            read_u64(reader)?;
+            Ok(Frame::Heartbeat)
+        }
+        WRITE_FRAME => {
+            let sequence = read_u64(reader)?;
+            let mut timestamp = [0; 16];
+            reader.read_exact(&mut timestamp)?;
+            let op = read_vec(reader, 1)?[0];
+            let len = read_u32(reader)? as usize;
+            let key = read_vec(reader, len)?;
+            let len = read_u32(reader)? as usize;
+            let value = read_vec(reader, len)?;
+            let op = match op {
+                PUT_OP => ChangeOp::Put(value),
+                DELETE_OP => ChangeOp::Delete,
+                RANGE_DELETE_OP => ChangeOp::DeleteRange { end: value },
+                op => return Err(protocol_error(&format!("unknown write op {}", op))),
+            };
+            Ok(Frame::Write(ChangeEvent {
+                key,
+                op,
+                timestamp: u128::from_le_bytes(timestamp),
+                sequence,
+            }))
+        }
+        SNAPSHOT_FRAME => Ok(Frame::Snapshot {
+            sequence: read_u64(reader)?,
+            files: read_u32(reader)?,
+        }),
+        kind => Err(protocol_error(&format!("unknown frame kind {}", kind))),
+    }
+}
+
+fn read_vec(reader: &mut impl Read, len: usize) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    reader.take(len as u64).read_to_end(&mut buffer)?;
+    if buffer.len() < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(buffer)
+}
+
+fn read_u16(reader: &mut impl Read) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn protocol_error(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("replication protocol: {}", reason),
+    )
+}
+
+fn into_io(e: FluxError) -> io::Error {
+    match e {
+        FluxError::Io(e) => e,
+        e => io::Error::other(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryBackend, Storage};
+    use rand::Rng;
+    use std::fs::{create_dir_all, remove_dir_all};
+
+    const WAIT: Duration = Duration::from_secs(10);
+
+    fn value(replica: &Replica, key: &[u8]) -> Option<Vec<u8>> {
+        replica
+            .get(key)
+            .unwrap()
+            .map(|entry| entry.value().to_vec())
+    }
+
+    #[test]
+    fn test_replication() {
+        let mut rng = rand::thread_rng();
+        let test_dir = format!("./{}/", rng.gen::<u32>());
+        create_dir_all(&test_dir).unwrap();
+
+        let primary = Disk::open(&test_dir, DiskOptions::default()).unwrap();
+        for i in 0..50 {
+            primary
+                .set(format!("Server{:02}", i).as_bytes(), b"nginx")
+                .unwrap();
+        }
+        // Moves the first writes out of the WAL, so the replica has to bootstrap.
+        primary.compact().unwrap();
+        primary.set(b"Server50", b"apache").unwrap();
+        let server = ReplicationServer::start(&primary, "127.0.0.1:0").unwrap();
+
+        let options = DiskOptions {
+            storage: Storage::new(MemoryBackend::new()),
+            ..DiskOptions::default()
+        };
+        // Bootstrapping leaves the files of the directory other than the database's alone.
+        let notes = Path::new("replica/notes.txt");
+        options
+            .storage
+            .append(notes)
+            .unwrap()
+            .append(b"nginx")
+            .unwrap();
+        let replica = Replica::start("replica", options.clone(), server.local_addr()).unwrap();
+        assert!(replica.wait_for(primary.last_sequence(), WAIT));
+        assert_eq!(replica.scan(..).unwrap().len(), 51);
+        assert_eq!(options.storage.read(notes).unwrap(), b"nginx");
+        assert_eq!(value(&replica, b"Server50"), Some(b"apache".to_vec()));
+        let timestamp = |disk: &Disk| disk.get(b"Server07").unwrap().unwrap().timestamp();
+        let entry = replica.get(b"Server07").unwrap().unwrap();
+        assert_eq!(entry.timestamp(), timestamp(&primary));
+
+        // Live writes stream as they commit.
+        primary.set(b"Server03", b"caddy").unwrap();
+        primary.delete(b"Server04").unwrap();
+        primary.delete_range(b"Server10", b"Server20").unwrap();
+        assert!(replica.wait_for(primary.last_sequence(), WAIT));
+        assert_eq!(value(&replica, b"Server03"), Some(b"caddy".to_vec()));
+        assert_eq!(value(&replica, b"Server04"), None);
+        assert_eq!(replica.scan(..).unwrap().len(), 40);
+        let position = replica.position();
+        drop(replica);
+        let saved = options.storage.read(Path::new("replica/REPLICA")).unwrap();
+        let expected = format!("{} {}", position, database_id(&primary).unwrap());
+        assert_eq!(String::from_utf8(saved).unwrap(), expected);
+
+        // A restarted replica catches up from its position.
+        primary.set(b"Server60", b"envoy").unwrap();
+        let replica = Replica::start("replica", options, server.local_addr()).unwrap();
+        assert_eq!(replica.position(), position);
+        assert!(replica.wait_for(primary.last_sequence(), WAIT));
+        assert_eq!(value(&replica, b"Server60"), Some(b"envoy".to_vec()));
+        assert_eq!(replica.scan(..).unwrap().len(), 41);
+
+        drop(replica);
+        drop(server);
+        drop(primary);
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_other_peers() {
+        let primary = Disk::open_in_memory().unwrap();
+        let server = ReplicationServer::start(&primary, "127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        // The connection is closed, or reset over the unread request, without a reply.
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response);
+        assert!(response.is_empty());
+
+        let mut bytes = Vec::new();
+        write_handshake(&mut bytes, 7, 3).unwrap();
+        assert_eq!(read_handshake(&mut &bytes[..]).unwrap(), (7, 3));
+        bytes[4] = PROTOCOL_VERSION + 1;
+        assert!(read_handshake(&mut &bytes[..]).is_err());
+        drop(server);
+
+        // Replicas must present the secret, and follow no other primary.
+        let replication = ReplicationOptions {
+            secret: Some(b"hunter2".to_vec()),
+        };
+        let server = ReplicationServer::start_with(&primary, "127.0.0.1:0", replication).unwrap();
+        let id = database_id(&primary).unwrap();
+        let connect = |followed: u64, secret: &[u8]| {
+            let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+            let mut hello = handshake(0, followed);
+            hello.extend_from_slice(&(secret.len() as u16).to_le_bytes());
+            hello.extend_from_slice(secret);
+            stream.write_all(&hello).unwrap();
+            stream
+        };
+        let mut response = Vec::new();
+        let _ = connect(id, b"hunter3").read_to_end(&mut response);
+        assert!(response.is_empty());
+        assert_eq!(
+            read_handshake(&mut connect(0, b"hunter2")).unwrap(),
+            (0, id)
+        );
+        connect(id + 1, b"hunter2")
+            .read_to_end(&mut response)
+            .unwrap();
+        assert_eq!(response, handshake(0, id));
+        assert_eq!(database_id(&primary).unwrap(), id);
+    }
+}
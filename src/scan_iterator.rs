@@ -0,0 +1,234 @@
+use crate::disk::DiskEntry;
+use crate::error::FluxError;
+use crate::snapshot::Snapshot;
+use std::collections::VecDeque;
+use std::ops::Bound;
+
+/// Number of entries a resumable scan reads at a time.
+const PAGE_SIZE: usize = 1024;
+
+/// Version of the checkpoint encoding.
+const CHECKPOINT_VERSION: u8 = 1;
+
+/// Version of the encoding of the continuation tokens of `Disk::scan_page`.
+const CONTINUATION_VERSION: u8 = 1;
+
+/// Iterates over a range of keys as of one sequence number, and can be resumed from a
+/// checkpoint by a later process. Created by `Disk::scan_resumable` and `Disk::resume_scan`.
+///
+/// The sequence the scan reads at stays pinned in the manifest, so its versions survive
+/// flushes, compactions and restarts, until the scan reaches its end or its checkpoint is
+/// released with `Disk::release_scan_checkpoint`. Dropping the iterator keeps the pin.
+/// Pages are read from the files live at the time, so the iterator holds no file open
+/// between them.
+pub struct ScanIterator {
+    snapshot: Snapshot,
+    pin: u64,
+    /// Where the rest of the scan starts: after the last key returned.
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    page: VecDeque<DiskEntry>,
+    done: bool,
+}
+
+impl ScanIterator {
+    pub(crate) fn new(
+        snapshot: Snapshot,
+        pin: u64,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+    ) -> ScanIterator {
+        ScanIterator {
+            snapshot,
+            pin,
+            start,
+            end,
+            page: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Returns the sequence number the scan reads at.
+    pub fn sequence(&self) -> u64 {
+        self.snapshot.sequence()
+    }
+
+    /// Encodes the position of the scan, just after the last entry returned, for
+    /// `Disk::resume_scan` to continue from, in this process or a later one.
+    pub fn checkpoint(&self) -> Vec<u8> {
+        let mut checkpoint = vec![CHECKPOINT_VERSION];
+        checkpoint.extend_from_slice(&self.pin.to_le_bytes());
+        checkpoint.extend_from_slice(&self.sequence().to_le_bytes());
+        encode_bound(&mut checkpoint, &self.start);
+        encode_bound(&mut checkpoint, &self.end);
+        checkpoint
+    }
+
+    fn fill_page(&mut self) -> Result<(), FluxError> {
+        let range = (
+            self.start.as_ref().map(Vec::as_slice),
+            self.end.as_ref().map(Vec::as_slice),
+        );
+        self.page = self.snapshot.scan_page(range, PAGE_SIZE)?.into();
+        Ok(())
+    }
+
+    /// Releases the pin once the scan has returned every entry.
+    fn finish(&mut self) -> Result<(), FluxError> {
+        self.done = true;
+        self.snapshot.disk().unpin_scan(self.pin)?;
+        Ok(())
+    }
+}
+
+impl Iterator for ScanIterator {
+    type Item = Result<DiskEntry, FluxError>;
+
+    fn next(&mut self) -> Option<Result<DiskEntry, FluxError>> {
+        if self.done {
+            return None;
+        }
+        if self.page.is_empty() {
+            if let Err(e) = self.fill_page() {
+                return Some(Err(e));
+            }
+            if self.page.is_empty() {
+                return self.finish().err().map(Err);
+            }
+        }
+        let entry = self.page.pop_front()?;
+        self.start = Bound::Excluded(entry.key().to_vec());
+        Some(Ok(entry))
+    }
+}
+
+/// A checkpoint decoded by `Disk::resume_scan`.
+pub(crate) struct Checkpoint {
+    pub(crate) pin: u64,
+    pub(crate) sequence: u64,
+    pub(crate) start: Bound<Vec<u8>>,
+    pub(crate) end: Bound<Vec<u8>>,
+}
+
+impl Checkpoint {
+    pub(crate) fn decode(bytes: &[u8]) -> Result<Checkpoint, FluxError> {
+        let invalid = || FluxError::InvalidArgument("malformed scan checkpoint".to_owned());
+        let (&version, mut rest) = bytes.split_first().ok_or_else(invalid)?;
+        if version != CHECKPOINT_VERSION {
+            return Err(invalid());
+        }
+        let pin = take_u64(&mut rest).ok_or_else(invalid)?;
+        let sequence = take_u64(&mut rest).ok_or_else(invalid)?;
+        let start = decode_bound(&mut rest).ok_or_else(invalid)?;
+        let end = decode_bound(&mut rest).ok_or_else(invalid)?;
+        if !rest.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Checkpoint {
+            pin,
+            sequence,
+            start,
+            end,
+        })
+    }
+}
+
+/// Encodes the continuation token of a page ending at `last_key`, for the next page to start
+/// after it.
+pub(crate) fn encode_continuation(last_key: &[u8]) -> Vec<u8> {
+    let mut token = vec![CONTINUATION_VERSION];
+    encode_bound(&mut token, &Bound::Excluded(last_key.to_vec()));
+    token
+}
+
+/// Decodes a continuation token into the start bound of the next page.
+pub(crate) fn decode_continuation(token: &[u8]) -> Result<Bound<Vec<u8>>, FluxError> {
+    let invalid = || FluxError::InvalidArgument("malformed continuation token".to_owned());
+    let (&version, mut rest) = token.split_first().ok_or_else(invalid)?;
+    if version != CONTINUATION_VERSION {
+        return Err(invalid());
+    }
+    let start = decode_bound(&mut rest).ok_or_else(invalid)?;
+    if !rest.is_empty() || !matches!(start, Bound::Excluded(_)) {
+        return Err(invalid());
+    }
+    Ok(start)
+}
+
+fn encode_bound(out: &mut Vec<u8>, bound: &Bound<Vec<u8>>) {
+    let (tag, key) = match bound {
+        Bound::Unbounded => (0, None),
+        Bound::Included(key) => (1, Some(key)),
+        Bound::Excluded(key) => (2, Some(key)),
+    };
+    out.push(tag);
+    if let Some(key) = key {
+        out.extend_from_slice(&(key.len() as u64).to_le_bytes());
+        out.extend_from_slice(key);
+    }
+}
+
+fn decode_bound(bytes: &mut &[u8]) -> Option<Bound<Vec<u8>>> {
+    let (&tag, rest) = bytes.split_first()?;
+    *bytes = rest;
+    if tag == 0 {
+        return Some(Bound::Unbounded);
+    }
+    let len = usize::try_from(take_u64(bytes)?).ok()?;
+    if bytes.len() < len {
+        return None;
+    }
+    let (key, rest) = bytes.split_at(len);
+    *bytes = rest;
+    match tag {
+        1 => Some(Bound::Included(key.to_vec())),
+        2 => Some(Bound::Excluded(key.to_vec())),
+        _ => None,
+    }
+}
+
+fn take_u64(bytes: &mut &[u8]) -> Option<u64> {
+    let (number, rest) = bytes.split_first_chunk::<8>()?;
+    *bytes = rest;
+    Some(u64::from_le_bytes(*number))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checkpoint_decoding() {
+        let mut bytes = vec![CHECKPOINT_VERSION];
+        bytes.extend_from_slice(&3u64.to_le_bytes());
+        bytes.extend_from_slice(&42u64.to_le_bytes());
+        encode_bound(&mut bytes, &Bound::Excluded(b"Server".to_vec()));
+        encode_bound(&mut bytes, &Bound::Unbounded);
+
+        let checkpoint = Checkpoint::decode(&bytes).unwrap();
+        assert_eq!((checkpoint.pin, checkpoint.sequence), (3, 42));
+        assert_eq!(checkpoint.start, Bound::Excluded(b"Server".to_vec()));
+        assert_eq!(checkpoint.end, Bound::Unbounded);
+
+        for len in 0..bytes.len() {
+            assert!(Checkpoint::decode(&bytes[..len]).is_err());
+        }
+        bytes.push(0);
+        assert!(Checkpoint::decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_continuation_decoding() {
+        let token = encode_continuation(b"Server");
+        assert_eq!(
+            decode_continuation(&token).unwrap(),
+            Bound::Excluded(b"Server".to_vec())
+        );
+        for len in 0..token.len() {
+            assert!(decode_continuation(&token[..len]).is_err());
+        }
+        let mut included = vec![CONTINUATION_VERSION];
+        encode_bound(&mut included, &Bound::Included(b"Server".to_vec()));
+        assert!(decode_continuation(&included).is_err());
+    }
+}
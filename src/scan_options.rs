@@ -0,0 +1,56 @@
+use std::fmt;
+use std::sync::Arc;
+
+/// A predicate on a key or a value, deciding whether a scan returns the entry.
+pub type ScanFilter = Arc<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
+/// How `Disk::scan_with` and `Disk::scan_read` read a range.
+///
+/// The filters run inside the engine rather than on the returned entries: the key filter is
+/// checked on every record of the memtables and segments before the merge, so records it
+/// rejects are never cloned, and their values never read from the value log. The value
+/// filter runs once the newest version of a key is known, before the entry is materialized
+/// and counted against `DiskOptions::read_memory_limit`. `limit` counts the entries passing
+/// both.
+#[derive(Clone)]
+pub struct ScanOptions {
+    /// Keeps only the keys it returns true for.
+    pub key_filter: Option<ScanFilter>,
+    /// Keeps only the live values it returns true for.
+    pub value_filter: Option<ScanFilter>,
+    /// Most entries returned.
+    pub limit: usize,
+    /// Returns the entries in decreasing key order.
+    pub reverse: bool,
+}
+
+impl ScanOptions {
+    /// Returns whether the value filter, if any, keeps `value`.
+    pub(crate) fn keeps_value(&self, value: &[u8]) -> bool {
+        self.value_filter
+            .as_ref()
+            .is_none_or(|filter| filter(value))
+    }
+}
+
+impl Default for ScanOptions {
+    fn default() -> ScanOptions {
+        ScanOptions {
+            key_filter: None,
+            value_filter: None,
+            limit: usize::MAX,
+            reverse: false,
+        }
+    }
+}
+
+impl fmt::Debug for ScanOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScanOptions")
+            .field("key_filter", &self.key_filter.is_some())
+            .field("value_filter", &self.value_filter.is_some())
+            .field("limit", &self.limit)
+            .field("reverse", &self.reverse)
+            .finish()
+    }
+}
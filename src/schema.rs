@@ -0,0 +1,105 @@
+use crate::sstable::Entry;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+/// Callback upgrading a value stored with an older schema version, given as first argument,
+/// to the current encoding.
+pub type UpgradeFn = dyn Fn(u32, &[u8]) -> io::Result<Vec<u8>> + Send + Sync;
+
+/// The current encoding of the values stored in a database, and how to upgrade older ones.
+///
+/// Values written while a schema is configured are tagged with its version. Values with an
+/// older tag, or written before any schema was registered (version 0), are upgraded lazily
+/// when read, and permanently when compaction rewrites them, so changing the encoding needs
+/// no stop-the-world migration.
+#[derive(Clone)]
+pub struct ValueSchema {
+    version: u32,
+    upgrade: Arc<UpgradeFn>,
+}
+
+impl ValueSchema {
+    /// Registers schema `version`, which must be at least 1, with the callback upgrading
+    /// values stored under any older version.
+    pub fn new<F>(version: u32, upgrade: F) -> ValueSchema
+    where
+        F: Fn(u32, &[u8]) -> io::Result<Vec<u8>> + Send + Sync + 'static,
+    {
+        assert!(version > 0, "schema versions start at 1");
+        ValueSchema {
+            version,
+            upgrade: Arc::new(upgrade),
+        }
+    }
+
+    /// Returns the version new values are tagged with.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Brings the value of an entry to the current version. Values tagged with a newer
+    /// version than this build knows can't be read. Tombstones are returned unchanged, so
+    /// a value retained by a soft delete keeps the version it was written with.
+    pub(crate) fn upgrade(&self, mut entry: Entry) -> io::Result<Entry> {
+        let Some(value) = entry.value.as_mut() else {
+            return Ok(entry);
+        };
+        if entry.schema > self.version {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "value written with schema version {}, newer than {}",
+                    entry.schema, self.version
+                ),
+            ));
+        }
+        if entry.schema < self.version {
+            *value = (self.upgrade)(entry.schema, value)?;
+            entry.schema = self.version;
+        }
+        Ok(entry)
+    }
+}
+
+impl fmt::Debug for ValueSchema {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ValueSchema")
+            .field("version", &self.version)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(value: &[u8], schema: u32) -> Entry {
+        Entry {
+            key: b"Server".to_vec(),
+            value: Some(value.to_vec()),
+            timestamp: 0,
+            sequence: 1,
+            schema,
+            retained: None,
+            value_pointer: false,
+            backdated: false,
+        }
+    }
+
+    #[test]
+    fn test_upgrades_older_values() {
+        let schema = ValueSchema::new(2, |from, value| {
+            let mut upgraded = format!("v{}:", from).into_bytes();
+            upgraded.extend_from_slice(value);
+            Ok(upgraded)
+        });
+
+        let upgraded = schema.upgrade(entry(b"nginx", 0)).unwrap();
+        assert_eq!(upgraded.value.unwrap(), b"v0:nginx");
+        assert_eq!(upgraded.schema, 2);
+        assert_eq!(schema.upgrade(entry(b"nginx", 1)).unwrap().value.unwrap(), b"v1:nginx");
+        assert_eq!(schema.upgrade(entry(b"nginx", 2)).unwrap().value.unwrap(), b"nginx");
+        assert!(schema.upgrade(entry(b"nginx", 3)).is_err());
+    }
+}
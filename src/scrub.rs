@@ -0,0 +1,57 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Settings of the background scrubber, set in `DiskOptions::scrub`.
+///
+/// The scrubber reads every data block of every segment again, bypassing the block cache,
+/// and checks its checksum and that it decodes, so bit rot in data that is seldom read is
+/// found while a backup still holds a good copy, rather than when the data is next needed.
+/// It reads at a steady `bytes_per_second`, so it can run for days without taking disk
+/// bandwidth from the reads and writes of callers, then waits `interval` before the next
+/// pass.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScrubOptions {
+    /// Bytes of blocks read per second.
+    pub bytes_per_second: u64,
+    /// Time between the end of a pass and the start of the next.
+    pub interval: Duration,
+}
+
+impl Default for ScrubOptions {
+    fn default() -> ScrubOptions {
+        ScrubOptions {
+            bytes_per_second: 1024 * 1024,
+            interval: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// Outcome of a pass of the scrubber, as returned by `Disk::scrub` and `Disk::last_scrub`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScrubReport {
+    /// Number of segments read.
+    pub segments: usize,
+    /// Number of data blocks checked.
+    pub blocks: u64,
+    /// Bytes of the blocks checked, as stored.
+    pub bytes: u64,
+    /// Blocks found damaged, in the order they were read.
+    pub corrupt_blocks: Vec<CorruptBlock>,
+    /// How long the pass took.
+    pub elapsed: Duration,
+}
+
+/// A data block the scrubber found damaged. The segment keeps serving its other blocks;
+/// reads of this one fail with `InvalidData` until the segment is restored from a backup.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CorruptBlock {
+    /// Name of the segment file.
+    pub file: String,
+    /// Position of the block among the data blocks of the segment.
+    pub block: usize,
+    /// What the check found.
+    pub error: String,
+    /// Copy of the block as stored, checksum included, written next to the segment as
+    /// `<file>.<block>.corrupt` for inspection; `None` if it couldn't be written.
+    pub quarantined: Option<PathBuf>,
+}
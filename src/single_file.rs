@@ -0,0 +1,832 @@
+//! Keeps every file of a database in a single container file, for applications where
+//! shipping a directory of many files is awkward.
+//!
+//! The container is a log of records, each covering one file: its creation with a name, a
+//! chunk of data appended to it, a rename, or its deletion. Opening the container replays
+//! the records to rebuild the table of files, the position of each chunk of their data
+//! included; reads then go straight to the chunks. A torn record at the end, left by a
+//! crash in the middle of an append, is cut off, as the WAL does with its own; a damaged
+//! record followed by others fails the open instead, as cutting it off would lose them.
+//!
+//! Space of deleted files is only reclaimed when more than half of the container is dead:
+//! the live files are then copied into a fresh container, which replaces the old one
+//! atomically. The copy runs without holding up reads and writes; only what changed
+//! meanwhile is carried over while they wait. Files still open when they are deleted keep
+//! their data until their last handle is dropped, as on the local file system.
+
+use crate::checksum::{crc32c, crc32c_append};
+use crate::platform;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::storage::{StorageBackend, StorageFile, StorageLock};
+
+/// Identifies a container file.
+const MAGIC: &[u8; 8] = b"FLUXSF01";
+
+const CREATE: u8 = 1;
+const DATA: u8 = 2;
+const DELETE: u8 = 3;
+const RENAME: u8 = 4;
+
+/// Kind, body length and CRC32C of kind and body.
+const RECORD_HEADER_SIZE: u64 = 9;
+
+/// Largest chunk of data written in one record; bigger appends are split.
+const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Dead bytes the container must hold, as well as more dead than live bytes, before it is
+/// rewritten.
+const MIN_RECLAIMED_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Suffix of the fresh container written next to the old one while reclaiming space.
+const RECLAIM_SUFFIX: &str = ".reclaim";
+
+/// Keeps the files of a database in one container file. The paths handed to the backend
+/// must be directly under the path the container was opened at, which stands for the
+/// database directory.
+pub struct SingleFileBackend {
+    root: PathBuf,
+    container: Arc<RwLock<Container>>,
+}
+
+struct Container {
+    path: PathBuf,
+    file: File,
+    end: u64,
+    names: HashMap<PathBuf, u64>,
+    files: HashMap<u64, FileState>,
+    next_id: u64,
+    /// Whether a fresh container is being written to replace this one.
+    reclaiming: bool,
+}
+
+#[derive(Default)]
+struct FileState {
+    /// `None` once the file is deleted while handles to it are still open.
+    name: Option<PathBuf>,
+    chunks: Vec<Chunk>,
+    size: u64,
+    /// Bytes of the container taken by the records of the file.
+    used: u64,
+    handles: usize,
+}
+
+/// What `read_record` found.
+enum Record {
+    Valid(u8),
+    /// A record cut short by the end of the container.
+    Torn,
+    /// A whole record failing its checksum.
+    Corrupt,
+    End,
+}
+
+/// A run of file data stored contiguously in the container.
+#[derive(Clone, Copy)]
+struct Chunk {
+    /// Offset of the run within the file.
+    start: u64,
+    /// Offset of the run within the container.
+    offset: u64,
+    len: u64,
+}
+
+struct SingleFile {
+    container: Arc<RwLock<Container>>,
+    id: u64,
+}
+
+impl SingleFileBackend {
+    /// Opens the container at `path`, creating an empty one if there is none. The container
+    /// stays locked until the backend is dropped; fails with `WouldBlock` if another backend,
+    /// in this process or another, has it open.
+    pub fn open(path: &Path) -> io::Result<SingleFileBackend> {
+        let container = match OpenOptions::new().read(true).write(true).open(path) {
+            // Locked before loading, as loading cuts off a torn tail another process may be
+            // writing.
+            Ok(file) => {
+                platform::try_lock(&file, path)?;
+                Container::load(path, file)?
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Container::create(path)?,
+            Err(e) => return Err(e),
+        };
+        let container = Arc::new(RwLock::new(container));
+        reclaim_if_needed(&container)?;
+        Ok(SingleFileBackend {
+            root: path.to_owned(),
+            container,
+        })
+    }
+
+    /// Returns the name of a file within the container.
+    fn name(&self, path: &Path) -> io::Result<PathBuf> {
+        let name = path.strip_prefix(&self.root).map_err(|_| outside(path))?;
+        match name.components().collect::<Vec<_>>()[..] {
+            [Component::Normal(_)] => Ok(name.to_owned()),
+            _ => Err(outside(path)),
+        }
+    }
+
+    fn handle(&self, id: u64) -> Box<dyn StorageFile> {
+        Box::new(SingleFile {
+            container: self.container.clone(),
+            id,
+        })
+    }
+
+    fn open_file(&self, path: &Path, create: bool, truncate: bool) -> io::Result<u64> {
+        let name = self.name(path)?;
+        let mut container = self.container.write().unwrap();
+        let id = match container.names.get(&name) {
+            Some(_) if truncate => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} already exists", path.display()),
+                ));
+            }
+            Some(&id) => id,
+            None if create => container.create_file(name)?,
+            None => return Err(not_found(path)),
+        };
+        container.files.get_mut(&id).unwrap().handles += 1;
+        Ok(id)
+    }
+}
+
+impl StorageBackend for SingleFileBackend {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(self.handle(self.open_file(path, true, true)?))
+    }
+
+    fn append(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(self.handle(self.open_file(path, true, false)?))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(self.handle(self.open_file(path, false, false)?))
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        let name = self.name(path)?;
+        let mut container = self.container.write().unwrap();
+        let id = *container.names.get(&name).ok_or_else(|| not_found(path))?;
+        container.write_record(DELETE, &[&id.to_le_bytes()])?;
+        container.unlink(id);
+        drop(container);
+        reclaim_if_needed(&self.container)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from_name, to_name) = (self.name(from)?, self.name(to)?);
+        if from_name == to_name {
+            return Ok(());
+        }
+        let mut container = self.container.write().unwrap();
+        let id = *container.names.get(&from_name).ok_or_else(|| not_found(from))?;
+        let name = name_bytes(&to_name)?;
+        let used = container.write_record(RENAME, &[&id.to_le_bytes(), name])?;
+        if let Some(replaced) = container.names.remove(&to_name) {
+            container.unlink(replaced);
+        }
+        container.names.remove(&from_name);
+        container.names.insert(to_name.clone(), id);
+        let file = container.files.get_mut(&id).unwrap();
+        file.name = Some(to_name);
+        file.used += used;
+        drop(container);
+        reclaim_if_needed(&self.container)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        if dir.components().ne(self.root.components()) {
+            return Err(not_found(dir));
+        }
+        let container = self.container.read().unwrap();
+        Ok(container.names.keys().map(|name| self.root.join(name)).collect())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.name(path)
+            .is_ok_and(|name| self.container.read().unwrap().names.contains_key(&name))
+    }
+
+    fn sync_dir(&self, _dir: &Path) -> io::Result<()> {
+        self.container.read().unwrap().file.sync_data()
+    }
+
+    /// The container itself is locked from `open` on; the returned lock keeps it open.
+    fn lock(&self, path: &Path) -> io::Result<StorageLock> {
+        self.name(path)?;
+        Ok(StorageLock::new(self.container.clone()))
+    }
+}
+
+impl Container {
+    fn create(path: &Path) -> io::Result<Container> {
+        let mut file = OpenOptions::new().read(true).write(true).create_new(true).open(path)?;
+        platform::try_lock(&file, path)?;
+        file.write_all(MAGIC)?;
+        file.sync_all()?;
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            platform::sync_dir(parent)?;
+        }
+        Ok(Container::empty(path, file))
+    }
+
+    fn empty(path: &Path, file: File) -> Container {
+        Container {
+            path: path.to_owned(),
+            file,
+            end: MAGIC.len() as u64,
+            names: HashMap::new(),
+            files: HashMap::new(),
+            next_id: 1,
+            reclaiming: false,
+        }
+    }
+
+    /// Replays the records of an existing container.
+    fn load(path: &Path, mut file: File) -> io::Result<Container> {
+        let mut magic = [0; MAGIC.len()];
+        file.read_exact(&mut magic)
+            .ok()
+            .filter(|_| &magic == MAGIC)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is not a single-file database", path.display()),
+                )
+            })?;
+
+        let len = file.metadata()?.len();
+        let mut container = Container::empty(path, file.try_clone()?);
+        let mut reader = io::BufReader::new(file);
+        let mut body = Vec::new();
+        loop {
+            let record = read_record(&mut reader, &mut body)?;
+            let (offset, used) = (container.end, RECORD_HEADER_SIZE + body.len() as u64);
+            let kind = match record {
+                Record::Valid(kind) => kind,
+                Record::Torn | Record::End => break,
+                // Only the last record can have been torn by a crash.
+                Record::Corrupt if offset + used >= len => break,
+                Record::Corrupt => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("corrupt record at offset {} of {}", offset, path.display()),
+                    ))
+                }
+            };
+            container.apply(kind, &body, offset, used)?;
+            container.end += used;
+        }
+        // Cut off a torn record, so later appends aren't followed by its remains.
+        if len > container.end {
+            container.file.set_len(container.end)?;
+            container.file.sync_all()?;
+        }
+        Ok(container)
+    }
+
+    /// Applies a record found at `offset` while replaying. Files deleted or replaced while
+    /// handles to them are open keep their data, as they do live.
+    fn apply(&mut self, kind: u8, body: &[u8], offset: u64, used: u64) -> io::Result<()> {
+        let corrupt = || io::Error::new(io::ErrorKind::InvalidData, "corrupt container record");
+        let id = u64::from_le_bytes(body.get(..8).ok_or_else(corrupt)?.try_into().unwrap());
+        let rest = &body[8..];
+        self.next_id = self.next_id.max(id + 1);
+        match kind {
+            CREATE => {
+                let name = PathBuf::from(std::str::from_utf8(rest).map_err(|_| corrupt())?);
+                if let Some(&replaced) = self.names.get(&name) {
+                    self.unlink(replaced);
+                }
+                self.names.insert(name.clone(), id);
+                self.files.insert(
+                    id,
+                    FileState {
+                        name: Some(name),
+                        used,
+                        ..FileState::default()
+                    },
+                );
+            }
+            // Data of a file deleted before the crash is dead.
+            DATA => {
+                if let Some(file) = self.files.get_mut(&id) {
+                    file.add_chunk(offset + RECORD_HEADER_SIZE + 8, rest.len() as u64, used);
+                }
+            }
+            DELETE => {
+                if self.files.contains_key(&id) {
+                    self.unlink(id);
+                }
+            }
+            RENAME => {
+                let name = PathBuf::from(std::str::from_utf8(rest).map_err(|_| corrupt())?);
+                if let Some(&replaced) = self.names.get(&name) {
+                    self.unlink(replaced);
+                }
+                let file = self.files.get_mut(&id).ok_or_else(corrupt)?;
+                if let Some(old) = file.name.as_ref() {
+                    self.names.remove(old);
+                }
+                self.names.insert(name.clone(), id);
+                file.name = Some(name);
+                file.used += used;
+            }
+            _ => return Err(corrupt()),
+        }
+        Ok(())
+    }
+
+    /// Appends a record made of the concatenated `parts`, returning its size.
+    fn write_record(&mut self, kind: u8, parts: &[&[u8]]) -> io::Result<u64> {
+        let body_len: usize = parts.iter().map(|part| part.len()).sum();
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE as usize + body_len);
+        record.push(kind);
+        record.extend_from_slice(&(body_len as u32).to_le_bytes());
+        record.extend_from_slice(&[0; 4]);
+        for part in parts {
+            record.extend_from_slice(part);
+        }
+        let crc = crc32c_append(crc32c(&[kind]), &record[RECORD_HEADER_SIZE as usize..]);
+        record[5..9].copy_from_slice(&crc.to_le_bytes());
+
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(&record)?;
+        self.end += record.len() as u64;
+        Ok(record.len() as u64)
+    }
+
+    fn create_file(&mut self, name: PathBuf) -> io::Result<u64> {
+        let id = self.next_id;
+        let used = self.write_record(CREATE, &[&id.to_le_bytes(), name_bytes(&name)?])?;
+        self.next_id += 1;
+        self.names.insert(name.clone(), id);
+        self.files.insert(
+            id,
+            FileState {
+                name: Some(name),
+                used,
+                ..FileState::default()
+            },
+        );
+        Ok(id)
+    }
+
+    fn append(&mut self, id: u64, data: &[u8]) -> io::Result<()> {
+        for chunk in data.chunks(MAX_CHUNK_SIZE) {
+            let offset = self.end + RECORD_HEADER_SIZE + 8;
+            let used = self.write_record(DATA, &[&id.to_le_bytes(), chunk])?;
+            self.files.get_mut(&id).unwrap().add_chunk(offset, chunk.len() as u64, used);
+        }
+        Ok(())
+    }
+
+    /// Removes the name of a file, and its data too unless handles to it are open.
+    fn unlink(&mut self, id: u64) {
+        let file = self.files.get_mut(&id).unwrap();
+        if let Some(name) = file.name.take() {
+            self.names.remove(&name);
+        }
+        if file.handles == 0 {
+            self.files.remove(&id);
+        }
+    }
+
+    fn read_at(&self, id: u64, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        read_chunks(&self.file, &self.files[&id].chunks, buf, offset)
+    }
+
+    /// Starts an empty container to replace the one at `path`, under a name locked until it
+    /// does, so no other process writes to it meanwhile.
+    fn fresh(path: &Path) -> io::Result<Container> {
+        let temp_path = reclaim_path(path);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&temp_path)?;
+        platform::try_lock(&file, &temp_path)?;
+        // Left over by a reclaim that failed.
+        file.set_len(0)?;
+        let mut fresh = Container::empty(path, file);
+        fresh.file.write_all(MAGIC)?;
+        Ok(fresh)
+    }
+
+    /// Adds a file with no data, named unless it is only kept for its open handles: such
+    /// files get no record, so they are gone again if the container is reopened.
+    fn adopt(&mut self, id: u64, name: Option<PathBuf>) -> io::Result<()> {
+        let used = match &name {
+            Some(name) => self.write_record(CREATE, &[&id.to_le_bytes(), name_bytes(name)?])?,
+            None => 0,
+        };
+        if let Some(name) = &name {
+            self.names.insert(name.clone(), id);
+        }
+        let file = FileState {
+            name,
+            used,
+            ..FileState::default()
+        };
+        self.files.insert(id, file);
+        Ok(())
+    }
+
+    /// Appends the data of a file from its current size up to `size`, as `read` returns it.
+    fn copy(
+        &mut self,
+        id: u64,
+        size: u64,
+        read: impl Fn(&mut [u8], u64) -> io::Result<usize>,
+    ) -> io::Result<()> {
+        let mut copied = self.files[&id].size;
+        let mut buf = Vec::new();
+        while copied < size {
+            let len = (size - copied).min(MAX_CHUNK_SIZE as u64) as usize;
+            buf.resize(len, 0);
+            let mut filled = 0;
+            while filled < len {
+                let read = read(&mut buf[filled..], copied + filled as u64)?;
+                if read == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                filled += read;
+            }
+            self.append(id, &buf)?;
+            copied += len as u64;
+        }
+        Ok(())
+    }
+
+    /// Brings a fresh container, filled while `old` kept changing, up to date with it: the
+    /// records `old` got from `end` on are replayed into it, and files deleted meanwhile but
+    /// still open are copied whole.
+    fn catch_up(&mut self, old: &Container, end: u64) -> io::Result<()> {
+        // Open files survive the deletions replayed, as they do in `old`.
+        for (id, file) in self.files.iter_mut() {
+            file.handles = old.files.get(id).map_or(0, |file| file.handles);
+        }
+        let mut reader = io::BufReader::new(Span {
+            file: &old.file,
+            offset: end,
+            end: old.end,
+        });
+        let mut body = Vec::new();
+        loop {
+            let kind = match read_record(&mut reader, &mut body)? {
+                Record::Valid(kind) => kind,
+                Record::End => break,
+                Record::Torn | Record::Corrupt => {
+                    let message = "corrupt container record";
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+                }
+            };
+            let offset = self.end;
+            let used = self.write_record(kind, &[&body])?;
+            self.apply(kind, &body, offset, used)?;
+        }
+        self.next_id = self.next_id.max(old.next_id);
+
+        self.files.retain(|id, _| old.files.contains_key(id));
+        self.names.retain(|_, id| old.files.contains_key(id));
+        let mut ids: Vec<u64> = old.files.keys().copied().collect();
+        ids.sort_unstable();
+        for id in ids {
+            let file = &old.files[&id];
+            if !self.files.contains_key(&id) {
+                self.adopt(id, file.name.clone())?;
+            }
+            self.copy(id, file.size, |buf, offset| old.read_at(id, buf, offset))?;
+            self.files.get_mut(&id).unwrap().handles = file.handles;
+        }
+        Ok(())
+    }
+
+    /// Makes a fresh container durable and puts it in place of the old one.
+    fn install(&self) -> io::Result<()> {
+        self.file.sync_all()?;
+        platform::rename(&reclaim_path(&self.path), &self.path)?;
+        if let Some(parent) = self.path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            platform::sync_dir(parent)?;
+        }
+        Ok(())
+    }
+}
+
+impl FileState {
+    fn add_chunk(&mut self, offset: u64, len: u64, used: u64) {
+        match self.chunks.last_mut() {
+            Some(last) if last.offset + last.len == offset => last.len += len,
+            _ => self.chunks.push(Chunk {
+                start: self.size,
+                offset,
+                len,
+            }),
+        }
+        self.size += len;
+        self.used += used;
+    }
+}
+
+/// Reads a span of the container, without moving the cursor its appends rely on.
+struct Span<'a> {
+    file: &'a File,
+    offset: u64,
+    end: u64,
+}
+
+impl Read for Span<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min((self.end - self.offset) as usize);
+        let read = platform::read_at(self.file, &mut buf[..len], self.offset)?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+impl StorageFile for SingleFile {
+    fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        self.container.write().unwrap().append(self.id, data)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.container.read().unwrap().read_at(self.id, buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.container.read().unwrap().files[&self.id].size)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.container.read().unwrap().file.sync_data()
+    }
+}
+
+impl Drop for SingleFile {
+    fn drop(&mut self) {
+        let mut container = self.container.write().unwrap();
+        let file = container.files.get_mut(&self.id).unwrap();
+        file.handles -= 1;
+        if file.handles == 0 && file.name.is_none() {
+            container.files.remove(&self.id);
+            drop(container);
+            // Retried on the next deletion if it fails.
+            let _ = reclaim_if_needed(&self.container);
+        }
+    }
+}
+
+/// Reads the next record into `body`.
+fn read_record(reader: &mut impl Read, body: &mut Vec<u8>) -> io::Result<Record> {
+    let mut header = [0; RECORD_HEADER_SIZE as usize];
+    let mut read = 0;
+    while read < header.len() {
+        match reader.read(&mut header[read..]) {
+            Ok(0) if read == 0 => return Ok(Record::End),
+            Ok(0) => return Ok(Record::Torn),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let kind = header[0];
+    let len = u32::from_le_bytes(header[1..5].try_into().unwrap()) as u64;
+    let crc = u32::from_le_bytes(header[5..9].try_into().unwrap());
+    body.clear();
+    if reader.take(len).read_to_end(body)? as u64 != len {
+        return Ok(Record::Torn);
+    }
+    match crc32c_append(crc32c(&[kind]), body) == crc {
+        true => Ok(Record::Valid(kind)),
+        false => Ok(Record::Corrupt),
+    }
+}
+
+/// Rewrites the container with only the live files once most of it is dead. The live data
+/// is copied into a fresh container without holding the lock, so reads and writes go on
+/// meanwhile; what they changed is then carried over with it held, and the fresh container
+/// swapped in.
+fn reclaim_if_needed(container: &RwLock<Container>) -> io::Result<()> {
+    let (mut fresh, source, end, files) = {
+        let mut container = container.write().unwrap();
+        let live: u64 = container.files.values().map(|file| file.used).sum();
+        let dead = container.end - MAGIC.len() as u64 - live;
+        if container.reclaiming || dead < MIN_RECLAIMED_SIZE || dead <= live {
+            return Ok(());
+        }
+        let mut fresh = Container::fresh(&container.path)?;
+        let source = container.file.try_clone()?;
+        let mut files: Vec<_> = container
+            .files
+            .iter()
+            .map(|(&id, file)| (id, file.name.clone(), file.size, file.chunks.clone()))
+            .collect();
+        files.sort_unstable_by_key(|(id, ..)| *id);
+        fresh.next_id = container.next_id;
+        container.reclaiming = true;
+        (fresh, source, container.end, files)
+    };
+
+    let copied = files.into_iter().try_for_each(|(id, name, size, chunks)| {
+        fresh.adopt(id, name)?;
+        fresh.copy(id, size, |buf, offset| read_chunks(&source, &chunks, buf, offset))
+    });
+    // Synced before taking the lock, so only the records caught up are synced with it held.
+    let result = copied.and_then(|()| fresh.file.sync_data()).and_then(|()| {
+        let mut container = container.write().unwrap();
+        fresh.catch_up(&container, end)?;
+        fresh.install()?;
+        *container = fresh;
+        Ok(())
+    });
+    if result.is_err() {
+        container.write().unwrap().reclaiming = false;
+    }
+    result
+}
+
+/// Reads into `buf` the data at `offset` of a file stored in `chunks` of the container.
+fn read_chunks(file: &File, chunks: &[Chunk], buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let index = chunks.partition_point(|chunk| chunk.start + chunk.len <= offset);
+    let Some(chunk) = chunks.get(index) else {
+        return Ok(0);
+    };
+    let skipped = offset - chunk.start;
+    let len = buf.len().min((chunk.len - skipped) as usize);
+    platform::read_at(file, &mut buf[..len], chunk.offset + skipped)
+}
+
+/// Path of the fresh container written while reclaiming the space of the one at `path`.
+fn reclaim_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(RECLAIM_SUFFIX);
+    PathBuf::from(name)
+}
+
+fn name_bytes(name: &Path) -> io::Result<&[u8]> {
+    name.to_str().map(str::as_bytes).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not valid UTF-8", name.display()),
+        )
+    })
+}
+
+fn outside(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} is not a file of the single-file database", path.display()),
+    )
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{read_exact_at, Storage};
+    use rand::Rng;
+    use std::fs::{self, create_dir_all, remove_dir_all};
+
+    #[test]
+    fn test_container_survives_reopen_and_reclaims_space() {
+        let mut rng = rand::thread_rng();
+        let test_dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir_all(&test_dir).unwrap();
+        let root = test_dir.join("app.fluxdb");
+
+        let storage = Storage::new(SingleFileBackend::open(&root).unwrap());
+        let mut wal = storage.create(&root.join("000001.wal")).unwrap();
+        wal.append(b"Server ").unwrap();
+        let big = vec![7; MIN_RECLAIMED_SIZE as usize * 2];
+        let mut segment = storage.create(&root.join("000002.sst")).unwrap();
+        segment.append(&big).unwrap();
+        wal.append(b"nginx").unwrap();
+        wal.sync().unwrap();
+        storage.replace(&root, "MANIFEST", b"first").unwrap();
+        storage.replace(&root, "MANIFEST", b"second").unwrap();
+        assert!(storage.create(&root.join("nested/file")).is_err());
+        assert_eq!(storage.read(&root.join("000001.wal")).unwrap(), b"Server nginx");
+
+        // The container is locked while anything holds it open.
+        let error = SingleFileBackend::open(&root).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        drop(storage);
+        assert!(SingleFileBackend::open(&root).is_err());
+        drop((wal, segment));
+
+        // A torn record at the end is cut off.
+        let mut container = OpenOptions::new().append(true).open(&root).unwrap();
+        container.write_all(&[DATA, 200, 0, 0, 0, 1, 2]).unwrap();
+        drop(container);
+
+        let storage = Storage::new(SingleFileBackend::open(&root).unwrap());
+        let mut listed = storage.list(&root).unwrap();
+        listed.sort();
+        let names = ["000001.wal", "000002.sst", "MANIFEST"];
+        assert_eq!(listed, names.map(|name| root.join(name)));
+        assert_eq!(storage.read(&root.join("MANIFEST")).unwrap(), b"second");
+        assert_eq!(storage.read(&root.join("000002.sst")).unwrap(), big);
+        storage.append(&root.join("000001.wal")).unwrap().append(b"!").unwrap();
+
+        // Deleting the segment while it is open keeps it readable until it is dropped, which
+        // reclaims its space.
+        let reader = storage.open(&root.join("000002.sst")).unwrap();
+        storage.delete(&root.join("000002.sst")).unwrap();
+        assert!(!storage.exists(&root.join("000002.sst")));
+        let mut buf = [0; 3];
+        read_exact_at(reader.as_ref(), &mut buf, MIN_RECLAIMED_SIZE).unwrap();
+        assert_eq!(buf, [7; 3]);
+        assert!(fs::metadata(&root).unwrap().len() > MIN_RECLAIMED_SIZE * 2);
+        drop(reader);
+        assert!(fs::metadata(&root).unwrap().len() < 1024);
+        drop(storage);
+
+        let storage = Storage::new(SingleFileBackend::open(&root).unwrap());
+        assert_eq!(storage.list(&root).unwrap().len(), 2);
+        assert_eq!(storage.read(&root.join("000001.wal")).unwrap(), b"Server nginx!");
+
+        drop(storage);
+
+        // A damaged record followed by others isn't cut off.
+        let mut bytes = fs::read(&root).unwrap();
+        bytes[MAGIC.len() + RECORD_HEADER_SIZE as usize + 8] ^= 1;
+        fs::write(&root, bytes).unwrap();
+        let error = SingleFileBackend::open(&root).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        fs::write(test_dir.join("other"), b"not a database").unwrap();
+        let error = SingleFileBackend::open(&test_dir.join("other")).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_reclaim_catches_up_with_changes_made_during_the_copy() {
+        let mut rng = rand::thread_rng();
+        let test_dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir_all(&test_dir).unwrap();
+        let root = test_dir.join("app.fluxdb");
+        let backend = SingleFileBackend::open(&root).unwrap();
+        let container = backend.container.clone();
+        let storage = Storage::new(backend);
+        storage.create(&root.join("000001.wal")).unwrap().append(b"Server ").unwrap();
+        storage.create(&root.join("000002.sst")).unwrap().append(b"segment").unwrap();
+        storage.replace(&root, "MANIFEST", b"first").unwrap();
+
+        // The copy `reclaim_if_needed` makes without the lock.
+        let (mut fresh, end) = {
+            let old = container.read().unwrap();
+            let mut fresh = Container::fresh(&old.path).unwrap();
+            let mut ids: Vec<u64> = old.files.keys().copied().collect();
+            ids.sort_unstable();
+            for id in ids {
+                fresh.adopt(id, old.files[&id].name.clone()).unwrap();
+                let size = old.files[&id].size;
+                fresh.copy(id, size, |buf, offset| old.read_at(id, buf, offset)).unwrap();
+            }
+            (fresh, old.end)
+        };
+        // Locked until it replaces the container.
+        assert!(Container::fresh(&root).is_err());
+
+        storage.append(&root.join("000001.wal")).unwrap().append(b"nginx").unwrap();
+        storage.replace(&root, "MANIFEST", b"second").unwrap();
+        let reader = storage.open(&root.join("000002.sst")).unwrap();
+        storage.delete(&root.join("000002.sst")).unwrap();
+        storage.create(&root.join("000003.sst")).unwrap().append(b"new").unwrap();
+
+        {
+            let mut old = container.write().unwrap();
+            fresh.catch_up(&old, end).unwrap();
+            fresh.install().unwrap();
+            *old = fresh;
+        }
+        assert!(!reclaim_path(&root).exists());
+        let mut buf = [0; 7];
+        read_exact_at(reader.as_ref(), &mut buf, 0).unwrap();
+        assert_eq!(&buf, b"segment");
+        assert_eq!(storage.read(&root.join("000001.wal")).unwrap(), b"Server nginx");
+        drop((reader, storage, container));
+
+        let storage = Storage::new(SingleFileBackend::open(&root).unwrap());
+        let mut listed = storage.list(&root).unwrap();
+        listed.sort();
+        let names = ["000001.wal", "000003.sst", "MANIFEST"];
+        assert_eq!(listed, names.map(|name| root.join(name)));
+        assert_eq!(storage.read(&root.join("MANIFEST")).unwrap(), b"second");
+        assert_eq!(storage.read(&root.join("000003.sst")).unwrap(), b"new");
+        remove_dir_all(&test_dir).unwrap();
+    }
+}
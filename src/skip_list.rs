@@ -0,0 +1,215 @@
+use crate::arena::ArenaBytes;
+use crate::comparator::KeyOrder;
+use crate::mem_table::{insert_version, InMemoryRecord, MemTable};
+use rand::Rng;
+use std::ops::Bound;
+
+/// Most levels a key is linked in, enough for billions of keys.
+const MAX_HEIGHT: usize = 16;
+
+/// Position of the head node, which holds no key. As no link points back to it, it also
+/// stands for the end of a level.
+const HEAD: usize = 0;
+
+/// Records of a memtable in a skip list: keys linked in order on the bottom level, and on
+/// each level above, a quarter of the keys of the level below, so a search skips most of
+/// them. Nodes live in a vector and link to each other by position.
+pub struct SkipList {
+    nodes: Vec<Node>,
+    /// Levels in use, at least 1.
+    height: usize,
+    records: usize,
+    order: KeyOrder,
+}
+
+struct Node {
+    key: ArenaBytes,
+    /// Versions of the key, newest first.
+    versions: Vec<InMemoryRecord>,
+    /// Next node on each level the node is linked in, `HEAD` at the end of a level.
+    next: Vec<usize>,
+}
+
+impl SkipList {
+    pub fn new(order: KeyOrder) -> SkipList {
+        let head = Node {
+            key: ArenaBytes::from(&[][..]),
+            versions: Vec::new(),
+            next: vec![HEAD; MAX_HEIGHT],
+        };
+        SkipList {
+            nodes: vec![head],
+            height: 1,
+            records: 0,
+            order,
+        }
+    }
+
+    /// Returns the first node whose key is not below `key`, or `HEAD` if there is none,
+    /// recording in `previous` the last node before it on each level.
+    fn seek(&self, key: &[u8], previous: &mut [usize; MAX_HEIGHT]) -> usize {
+        let mut node = HEAD;
+        for level in (0..self.height).rev() {
+            loop {
+                let next = self.nodes[node].next[level];
+                if next == HEAD || self.order.compare(&self.nodes[next].key, key).is_ge() {
+                    break;
+                }
+                node = next;
+            }
+            previous[level] = node;
+        }
+        self.nodes[node].next[0]
+    }
+
+    /// Returns the node holding `key`, if any.
+    fn find(&self, key: &[u8]) -> Option<usize> {
+        let node = self.seek(key, &mut [HEAD; MAX_HEIGHT]);
+        (node != HEAD && self.nodes[node].key == key).then_some(node)
+    }
+
+    /// Draws the number of levels a new node is linked in.
+    fn random_height() -> usize {
+        let mut rng = rand::thread_rng();
+        let mut height = 1;
+        while height < MAX_HEIGHT && rng.gen_ratio(1, 4) {
+            height += 1;
+        }
+        height
+    }
+}
+
+impl MemTable for SkipList {
+    fn insert(&mut self, record: InMemoryRecord) -> Option<InMemoryRecord> {
+        let mut previous = [HEAD; MAX_HEIGHT];
+        let node = self.seek(&record.key, &mut previous);
+        if node != HEAD && self.nodes[node].key == record.key {
+            let replaced = insert_version(&mut self.nodes[node].versions, record);
+            if replaced.is_none() {
+                self.records += 1;
+            }
+            return replaced;
+        }
+
+        let height = SkipList::random_height();
+        // Levels not in use yet start at the head.
+        self.height = self.height.max(height);
+        let position = self.nodes.len();
+        let next = (0..height)
+            .map(|level| self.nodes[previous[level]].next[level])
+            .collect();
+        self.nodes.push(Node {
+            key: record.key.clone(),
+            versions: vec![record],
+            next,
+        });
+        for (level, &before) in previous.iter().enumerate().take(height) {
+            self.nodes[before].next[level] = position;
+        }
+        self.records += 1;
+        None
+    }
+
+    fn remove(&mut self, key: &[u8], index: usize) -> InMemoryRecord {
+        // A node left without versions stays linked; lookups and scans skip it.
+        let node = self.find(key).expect("removing a version of a missing key");
+        self.records -= 1;
+        self.nodes[node].versions.remove(index)
+    }
+
+    fn fetch(&self, key: &[u8]) -> &[InMemoryRecord] {
+        match self.find(key) {
+            Some(node) => &self.nodes[node].versions,
+            None => &[],
+        }
+    }
+
+    fn iter<'a>(
+        &'a self,
+        bounds: (Bound<&[u8]>, Bound<&[u8]>),
+    ) -> Box<dyn Iterator<Item = &'a [InMemoryRecord]> + 'a> {
+        let mut node = match bounds.0 {
+            Bound::Included(key) | Bound::Excluded(key) => self.seek(key, &mut [HEAD; MAX_HEIGHT]),
+            Bound::Unbounded => self.nodes[HEAD].next[0],
+        };
+        if let Bound::Excluded(key) = bounds.0 {
+            if node != HEAD && self.nodes[node].key == key {
+                node = self.nodes[node].next[0];
+            }
+        }
+        let end = bounds.1.map(<[u8]>::to_vec);
+        Box::new(std::iter::from_fn(move || loop {
+            if node == HEAD {
+                return None;
+            }
+            let current = &self.nodes[node];
+            let past_end = match &end {
+                Bound::Included(end) => self.order.compare(&current.key, end).is_gt(),
+                Bound::Excluded(end) => self.order.compare(&current.key, end).is_ge(),
+                Bound::Unbounded => false,
+            };
+            if past_end {
+                return None;
+            }
+            node = current.next[0];
+            if !current.versions.is_empty() {
+                return Some(current.versions.as_slice());
+            }
+        }))
+    }
+
+    fn size(&self) -> usize {
+        self.records
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(key: &[u8], sequence: u64) -> InMemoryRecord {
+        InMemoryRecord {
+            key: ArenaBytes::from(key),
+            value: Some(ArenaBytes::from(&sequence.to_le_bytes()[..])),
+            timestamp: sequence as u128,
+            is_deleted: false,
+            sequence,
+            schema: 0,
+            backdated: false,
+        }
+    }
+
+    #[test]
+    fn test_keys_in_order() {
+        let mut list = SkipList::new(KeyOrder::default());
+        // Keys in a scrambled order, each written twice.
+        for sequence in 0..2000u64 {
+            let key = format!("key{:04}", (sequence * 7919) % 1000);
+            list.insert(record(key.as_bytes(), sequence));
+        }
+        assert_eq!(list.size(), 2000);
+        let all = list.iter((Bound::Unbounded, Bound::Unbounded));
+        let keys: Vec<&[u8]> = all.map(|versions| &versions[0].key[..]).collect();
+        assert_eq!(keys.len(), 1000);
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        let versions = list.fetch(b"key0007");
+        assert_eq!(versions.len(), 2);
+        assert!(versions[0].sequence > versions[1].sequence);
+        let oldest = versions[1].sequence;
+
+        let bounds = (
+            Bound::Excluded(&b"key0010"[..]),
+            Bound::Included(&b"key0013"[..]),
+        );
+        let keys: Vec<&[u8]> = list
+            .iter(bounds)
+            .map(|versions| &versions[0].key[..])
+            .collect();
+        assert_eq!(keys, [&b"key0011"[..], b"key0012", b"key0013"]);
+
+        assert_eq!(list.remove(b"key0007", 1).sequence, oldest);
+        assert_eq!(list.fetch(b"key0007").len(), 1);
+        assert!(list.fetch(b"missing").is_empty());
+        assert_eq!(list.size(), 1999);
+    }
+}
@@ -0,0 +1,200 @@
+use crate::disk::{Disk, DiskEntry};
+use crate::error::FluxError;
+use crate::scan_options::ScanOptions;
+use std::collections::BTreeMap;
+use std::io;
+use std::ops::RangeBounds;
+use std::sync::Mutex;
+
+/// A consistent, read-only view of the database as of one sequence number.
+///
+/// Reads through a snapshot see every write committed before it was taken and none after.
+/// The versions a live snapshot can see are kept by flushes and compactions, so snapshots
+/// should be dropped once they are no longer needed.
+pub struct Snapshot {
+    disk: Disk,
+    sequence: u64,
+}
+
+impl Snapshot {
+    pub(crate) fn new(disk: Disk, sequence: u64) -> Snapshot {
+        Snapshot { disk, sequence }
+    }
+
+    /// Returns the database the snapshot reads from.
+    pub(crate) fn disk(&self) -> &Disk {
+        &self.disk
+    }
+
+    /// Returns the sequence number of the last write visible to the snapshot.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Looks up a key as it was when the snapshot was taken.
+    pub fn get(&self, key: &[u8]) -> io::Result<Option<DiskEntry>> {
+        self.disk.get_at_sequence(key, self.sequence)
+    }
+
+    /// Looks up several keys as they were when the snapshot was taken, as `Disk::multi_get`.
+    pub fn multi_get(&self, keys: &[&[u8]]) -> Result<Vec<Option<DiskEntry>>, FluxError> {
+        self.disk.multi_get_at(keys, self.sequence)
+    }
+
+    /// Returns the live entries within the range as they were when the snapshot was taken.
+    pub fn scan<'a, R: RangeBounds<&'a [u8]>>(
+        &self,
+        range: R,
+    ) -> Result<Vec<DiskEntry>, FluxError> {
+        self.disk.scan_at(range, self.sequence, usize::MAX)
+    }
+
+    /// Returns up to `limit` live entries within the range as they were when the snapshot
+    /// was taken, for reading a range a page at a time.
+    pub fn scan_page<'a, R: RangeBounds<&'a [u8]>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<DiskEntry>, FluxError> {
+        self.disk.scan_at(range, self.sequence, limit)
+    }
+
+    /// Returns the live entries within the range as they were when the snapshot was taken,
+    /// filtered, ordered and limited as `Disk::scan_with` does.
+    pub fn scan_with<'a, R: RangeBounds<&'a [u8]>>(
+        &self,
+        range: R,
+        options: &ScanOptions,
+    ) -> Result<Vec<DiskEntry>, FluxError> {
+        self.disk.scan_with_at(range, self.sequence, options)
+    }
+
+    /// Returns the keys of the live entries within the range as they were when the snapshot
+    /// was taken, without reading their values. See `Disk::scan_keys`.
+    pub fn scan_keys<'a, R: RangeBounds<&'a [u8]>>(
+        &self,
+        range: R,
+    ) -> Result<Vec<Vec<u8>>, FluxError> {
+        self.disk.scan_keys_at(range, self.sequence)
+    }
+
+    /// Returns the live entries whose keys start with `prefix` as they were when the snapshot
+    /// was taken. See `Disk::scan_prefix`.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<DiskEntry>, FluxError> {
+        self.disk.scan_prefix_at(prefix, self.sequence)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.disk.release_snapshot(self.sequence);
+    }
+}
+
+/// The sequence numbers of the live snapshots, with how many snapshots share each.
+#[derive(Default)]
+pub(crate) struct SnapshotList {
+    live: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl SnapshotList {
+    pub(crate) fn acquire(&self, sequence: u64) {
+        *self.live.lock().unwrap().entry(sequence).or_default() += 1;
+    }
+
+    pub(crate) fn release(&self, sequence: u64) {
+        let mut live = self.live.lock().unwrap();
+        if let Some(count) = live.get_mut(&sequence) {
+            *count -= 1;
+            if *count == 0 {
+                live.remove(&sequence);
+            }
+        }
+    }
+
+    /// Returns the live snapshot sequences in increasing order.
+    pub(crate) fn sequences(&self) -> Vec<u64> {
+        self.live.lock().unwrap().keys().copied().collect()
+    }
+}
+
+/// Returns which snapshots can see a version: the index of the oldest snapshot taken at or
+/// after it, or the number of snapshots if only current reads can. Of the versions of a key
+/// falling into the same stripe only the newest is ever read, so older ones can be dropped.
+pub(crate) fn stripe(sequence: u64, snapshots: &[u64]) -> usize {
+    snapshots.partition_point(|&snapshot| snapshot < sequence)
+}
+
+/// Picks the versions of a key worth keeping, shown newest first: the newest version of
+/// each stripe, and up to `retained` versions below the latest. Backdated versions are
+/// never the one a stripe reads, so they are only kept as retained versions.
+pub(crate) struct KeptVersions<'a> {
+    snapshots: &'a [u64],
+    retained: usize,
+    /// Stripe of the last version kept, once the latest has been seen.
+    last_stripe: Option<usize>,
+    /// Number of versions kept below the latest.
+    older: usize,
+}
+
+impl KeptVersions<'_> {
+    pub(crate) fn new(snapshots: &[u64], retained: usize) -> KeptVersions<'_> {
+        KeptVersions {
+            snapshots,
+            retained,
+            last_stripe: None,
+            older: 0,
+        }
+    }
+
+    /// Returns whether to keep the next version of the key, written at `sequence`.
+    pub(crate) fn keep(&mut self, sequence: u64, backdated: bool) -> bool {
+        let current = stripe(sequence, self.snapshots);
+        let retained = self.older < self.retained;
+        let kept = match self.last_stripe {
+            _ if backdated => retained,
+            None => true,
+            Some(last) => last != current || retained,
+        };
+        if kept {
+            if backdated || self.last_stripe.is_some() {
+                self.older += 1;
+            }
+            if !backdated {
+                self.last_stripe = Some(current);
+            }
+        }
+        kept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stripes() {
+        let snapshots = [5, 10];
+        assert_eq!(stripe(3, &snapshots), 0);
+        assert_eq!(stripe(5, &snapshots), 0);
+        assert_eq!(stripe(6, &snapshots), 1);
+        assert_eq!(stripe(11, &snapshots), 2);
+        assert_eq!(stripe(11, &[]), 0);
+
+        // The newest version of each stripe is kept, a backdated one only as retained.
+        let mut kept = KeptVersions::new(&snapshots, 1);
+        let versions = [(12, true), (11, false), (9, true), (8, false), (7, false), (4, false)];
+        let kept: Vec<bool> = versions.iter().map(|&(seq, back)| kept.keep(seq, back)).collect();
+        assert_eq!(kept, [true, true, false, true, false, true]);
+
+        let list = SnapshotList::default();
+        list.acquire(7);
+        list.acquire(3);
+        list.acquire(7);
+        list.release(7);
+        assert_eq!(list.sequences(), vec![3, 7]);
+        list.release(7);
+        list.release(3);
+        assert!(list.sequences().is_empty());
+    }
+}
@@ -0,0 +1,133 @@
+//! Segment files built outside a database, for bulk loads.
+//!
+//! An `SstWriter` writes sorted keys straight to a segment file, skipping the WAL and the
+//! memtable, and `Disk::ingest_segments` then adds the file to a database as it is. Loading
+//! millions of keys this way costs one sequential write of the data, rather than a WAL
+//! append, a memtable insert, a flush and compactions for each of them.
+//!
+//! Ingested entries are installed below every other segment, as though they had been
+//! written before anything else the database holds, and read at the sequence number the
+//! ingestion took. That is only correct for keys the database holds nothing about, so a
+//! file is refused if its key range overlaps data or range tombstones already there.
+
+use crate::options::DiskOptions;
+use crate::sstable::{Entry, SSTableWriter};
+use std::io;
+use std::path::Path;
+
+/// Writes a segment file from keys added in increasing order, for `Disk::ingest_segments`.
+///
+/// The file uses the codec, block size, filters and comparator of the options it is
+/// created with, which must be those of the database it is ingested into. Every value is
+/// stamped with the time the writer was created, and checked as `Disk::set` checks it.
+pub struct SstWriter {
+    writer: SSTableWriter,
+    options: DiskOptions,
+    timestamp: u128,
+    schema: u32,
+}
+
+impl SstWriter {
+    /// Creates the file at `path` in `options.storage`, failing if it already exists.
+    pub fn create(path: &Path, options: &DiskOptions) -> io::Result<SstWriter> {
+        let mut writer = SSTableWriter::create_with(
+            &options.storage,
+            path,
+            options.compression,
+            options.block_size,
+        )?;
+        writer.set_bloom_bits_per_key(options.bloom_bits_per_key);
+        writer.set_prefix_extractor(options.prefix_extractor.as_ref());
+        writer.set_key_order(&options.comparator);
+        Ok(SstWriter {
+            writer,
+            options: options.clone(),
+            timestamp: options.clock.now_micros(),
+            schema: options.schema_version(),
+        })
+    }
+
+    /// Appends a key and its value. Keys must be added in strictly increasing order. Fails
+    /// with `InvalidInput` if the key or value is refused, as `Disk::set` refuses it: empty
+    /// or too large, or failing one of the `validators`.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        if let Err(e) = self.options.validate(key, Some(value)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, e.to_string()));
+        }
+        self.writer.add(&Entry {
+            key: key.to_vec(),
+            value: Some(value.to_vec()),
+            timestamp: self.timestamp,
+            sequence: 0,
+            schema: self.schema,
+            retained: None,
+            value_pointer: false,
+            backdated: false,
+        })
+    }
+
+    /// Returns the number of keys added so far.
+    pub fn entry_count(&self) -> u64 {
+        self.writer.entry_count()
+    }
+
+    /// Writes the filters and index and syncs the file to disk. Returns the file size.
+    pub fn finish(self) -> io::Result<u64> {
+        self.writer.finish()
+    }
+
+    /// Returns the path of the file being written.
+    pub fn path(&self) -> &Path {
+        self.writer.path()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sstable::SSTable;
+    use crate::validation::{ForbiddenPrefix, WriteValidators};
+    use rand::Rng;
+    use std::fs::{create_dir_all, remove_dir_all};
+
+    #[test]
+    fn test_sst_writer() {
+        let mut rng = rand::thread_rng();
+        let test_dir = format!("./{}/", rng.gen::<u32>());
+        create_dir_all(&test_dir).unwrap();
+        let path = Path::new(&test_dir).join("load.sst");
+
+        let mut writer = SstWriter::create(&path, &DiskOptions::default()).unwrap();
+        writer.put(b"apple", b"red").unwrap();
+        writer.put(b"banana", b"yellow").unwrap();
+        assert!(writer.put(b"banana", b"green").is_err());
+        assert!(writer.put(b"apricot", b"orange").is_err());
+        assert!(writer.put(b"", b"none").is_err());
+        assert_eq!(writer.entry_count(), 2);
+        writer.finish().unwrap();
+
+        let segment = SSTable::open(&path).unwrap();
+        let entry = segment.get(b"banana").unwrap().unwrap();
+        assert_eq!(entry.value.as_deref(), Some(&b"yellow"[..]));
+        assert_eq!(entry.sequence, 0);
+        assert!(segment.get(b"apricot").unwrap().is_none());
+
+        // Keys and values are checked as `Disk::set` checks them.
+        let options = DiskOptions {
+            max_value_size: 8,
+            validators: WriteValidators::new().with(ForbiddenPrefix::new(b"internal/")),
+            ..DiskOptions::default()
+        };
+        let path = Path::new(&test_dir).join("checked.sst");
+        let mut writer = SstWriter::create(&path, &options).unwrap();
+        let e = writer.put(b"internal/key", b"red").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        let e = writer.put(b"key", b"a long value").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        writer.put(b"key", b"red").unwrap();
+        assert_eq!(writer.entry_count(), 1);
+        drop(writer);
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+}
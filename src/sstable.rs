@@ -0,0 +1,1418 @@
+use crate::block_cache::BlockCache;
+use crate::bloom::{hash_key, BloomFilter, DEFAULT_BITS_PER_KEY};
+use crate::checksum::crc32c;
+use crate::comparator::KeyOrder;
+use crate::bytes::Bytes;
+use crate::compression::Compression;
+use crate::prefix::PrefixExtractor;
+use crate::scan_options::ScanFilter;
+use crate::storage::{read_exact_at, FileWriter, Storage, StorageFile};
+use crate::value_log::ValueLog;
+use std::cmp::Ordering;
+use std::io::{self, BufWriter, Write};
+use std::ops::{Bound, Range};
+use std::path::{Path, PathBuf};
+
+/// Magic bytes at the start of every segment file.
+pub const SSTABLE_MAGIC: [u8; 4] = *b"FLXS";
+/// Current segment format version. Version 2 entries carry a sequence number; version 3
+/// entries may carry a schema version; version 4 files may have a bloom filter block;
+/// version 5 files may have a range tombstone block; version 6 files may have a prefix filter
+/// block.
+pub const SSTABLE_VERSION: u8 = 6;
+
+/// Magic, version and codec.
+const HEADER_SIZE: u64 = 4 + 1 + 1;
+/// Index offset, index size, entry count and magic.
+const FOOTER_SIZE: u64 = 8 + 8 + 8 + 4;
+/// Key size, value size, flags, timestamp and sequence number of an encoded entry.
+const ENTRY_OVERHEAD: usize = 4 + 4 + 1 + 16 + 8;
+const FLAG_TOMBSTONE: u8 = 1;
+/// Entry flag marking values tagged with a schema version, stored after the sequence number.
+const FLAG_SCHEMA: u8 = 2;
+/// Entry flag marking soft tombstones, whose value bytes hold the value kept for restoring
+/// the key. Older builds read them as plain tombstones.
+const FLAG_RETAINED: u8 = 4;
+/// Entry flag marking values kept in a value log, whose value bytes hold a pointer to them.
+/// Databases using it record the `value-log` feature, so older builds refuse to open them.
+const FLAG_VALUE_POINTER: u8 = 8;
+/// Entry flag marking backdated versions, which reads of the latest version pass over.
+/// Databases writing them record the `backdated-versions` feature.
+const FLAG_BACKDATED: u8 = 16;
+
+/// A version of a key: its value, or a tombstone when `value` is `None`. A soft tombstone
+/// also keeps the removed value in `retained`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub key: Vec<u8>,
+    pub value: Option<Vec<u8>>,
+    pub timestamp: u128,
+    pub sequence: u64,
+    /// Schema version the value (or retained value) is encoded with, or 0 if untagged.
+    pub schema: u32,
+    /// Value kept by a soft tombstone so the key can be restored.
+    pub retained: Option<Vec<u8>>,
+    /// Whether `value` is a pointer to the value in a value log file. Only entries read
+    /// unresolved from a segment are.
+    pub value_pointer: bool,
+    /// Whether the version was written below a newer one of its key, as
+    /// `TimestampRegression::KeepAsOlderVersion` keeps stale writes. Reads of the latest
+    /// version pass over it; reads by timestamp and histories see it.
+    pub backdated: bool,
+}
+
+impl Entry {
+    /// Returns whether the entry records a removal.
+    pub fn is_deleted(&self) -> bool {
+        self.value.is_none()
+    }
+
+    /// Returns whether the entry is a tombstone keeping the removed value.
+    pub fn is_soft_deleted(&self) -> bool {
+        self.value.is_none() && self.retained.is_some()
+    }
+}
+
+/// Removal of every key from `start` (inclusive) to `end` (exclusive) written before
+/// `sequence`. Keys written afterwards are not affected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeTombstone {
+    pub start: Vec<u8>,
+    pub end: Vec<u8>,
+    pub timestamp: u128,
+    pub sequence: u64,
+}
+
+impl RangeTombstone {
+    /// Returns whether the range holds the key, with keys sorted in `order`.
+    pub fn contains(&self, key: &[u8], order: &KeyOrder) -> bool {
+        order.compare(&self.start, key) != Ordering::Greater
+            && order.compare(key, &self.end) == Ordering::Less
+    }
+
+    /// Returns whether the tombstone hides a version of `key` written at `sequence`, with
+    /// keys sorted in `order`.
+    pub fn covers(&self, key: &[u8], sequence: u64, order: &KeyOrder) -> bool {
+        sequence < self.sequence && self.contains(key, order)
+    }
+}
+
+/// Location of a data block, found through the last key it holds.
+#[derive(Clone, Debug)]
+struct BlockHandle {
+    last_key: Vec<u8>,
+    offset: u64,
+    size: u64,
+}
+
+/* NOTE: Layout of a segment file, written once when a memtable is flushed or segments are
+   compacted and never modified afterwards:
+
+   header | data block* | filter block? | prefix filter block? | range tombstone block? |
+   index block | footer
+
+   Data blocks hold entries sorted by key, with the versions of a key newest first. They
+   are compressed with the codec named in the header and
+   are followed by the CRC32C of their stored bytes. The optional filter block holds a bloom
+   filter over the keys, followed by its CRC32C. The optional prefix filter block holds the
+   name of a prefix extractor and a bloom filter over the prefixes it extracted from the
+   keys, followed by its CRC32C. The optional range tombstone block lists the ranges deleted
+   by `delete_range`, followed by its CRC32C. The index block lists the last key, offset and
+   size of every data block, then the offset and size of the filter block, of the range
+   tombstone block and of the prefix filter block (zero if there is none), and is followed
+   by its own CRC32C.
+*/
+
+/// Writes a segment file from entries added in increasing key order, versions of the same
+/// key newest first.
+pub struct SSTableWriter {
+    path: PathBuf,
+    writer: BufWriter<FileWriter>,
+    compression: Compression,
+    block_size: usize,
+    block: Vec<u8>,
+    last_key: Vec<u8>,
+    last_sequence: u64,
+    offset: u64,
+    index: Vec<BlockHandle>,
+    entry_count: u64,
+    bits_per_key: usize,
+    key_hashes: Vec<u64>,
+    prefix_extractor: Option<PrefixExtractor>,
+    prefix_hashes: Vec<u64>,
+    last_prefix: Option<Vec<u8>>,
+    range_tombstones: Vec<RangeTombstone>,
+    order: KeyOrder,
+}
+
+impl SSTableWriter {
+    /// Creates the file at `path`, failing if it already exists.
+    pub fn create(
+        path: &Path,
+        compression: Compression,
+        block_size: usize,
+    ) -> io::Result<SSTableWriter> {
+        SSTableWriter::create_with(&Storage::default(), path, compression, block_size)
+    }
+
+    /// Creates the file at `path` in `storage`, failing if it already exists.
+    pub fn create_with(
+        storage: &Storage,
+        path: &Path,
+        compression: Compression,
+        block_size: usize,
+    ) -> io::Result<SSTableWriter> {
+        compression.ensure_available()?;
+        let mut writer = BufWriter::new(FileWriter::new(storage.create(path)?));
+        writer.write_all(&SSTABLE_MAGIC)?;
+        writer.write_all(&[SSTABLE_VERSION, compression.id()])?;
+
+        Ok(SSTableWriter {
+            path: path.to_owned(),
+            writer,
+            compression,
+            block_size: block_size.max(1),
+            block: Vec::new(),
+            last_key: Vec::new(),
+            last_sequence: 0,
+            offset: HEADER_SIZE,
+            index: Vec::new(),
+            entry_count: 0,
+            bits_per_key: DEFAULT_BITS_PER_KEY,
+            key_hashes: Vec::new(),
+            prefix_extractor: None,
+            prefix_hashes: Vec::new(),
+            last_prefix: None,
+            range_tombstones: Vec::new(),
+            order: KeyOrder::default(),
+        })
+    }
+
+    /// Sets the order entries must be added in, byte order by default.
+    pub fn set_key_order(&mut self, order: &KeyOrder) {
+        self.order = order.clone();
+    }
+
+    /// Sets the size of the bloom filter written for the keys of the segment, in bits per
+    /// key. Zero writes no filter.
+    pub fn set_bloom_bits_per_key(&mut self, bits_per_key: usize) {
+        self.bits_per_key = bits_per_key;
+    }
+
+    /// Also writes a bloom filter over the prefixes `extractor` takes from the keys, of the
+    /// same size per prefix as the key filter. `None`, the default, writes none.
+    pub fn set_prefix_extractor(&mut self, extractor: Option<&PrefixExtractor>) {
+        self.prefix_extractor = extractor.cloned();
+    }
+
+    /// Appends an entry. Keys must be added in increasing order, and the versions of a key
+    /// in decreasing sequence order.
+    pub fn add(&mut self, entry: &Entry) -> io::Result<()> {
+        let in_order = match self.order.compare(&entry.key, &self.last_key) {
+            Ordering::Greater => true,
+            Ordering::Equal => entry.sequence < self.last_sequence,
+            Ordering::Less => false,
+        };
+        if self.entry_count > 0 && !in_order {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "segment entries must be added in key and sequence order",
+            ));
+        }
+
+        if self.entry_count == 0 || entry.key != self.last_key {
+            self.key_hashes.push(hash_key(&entry.key));
+        }
+        let extractor = self.prefix_extractor.as_ref();
+        let prefix = extractor.and_then(|extractor| extractor.prefix(&entry.key));
+        if let Some(prefix) = prefix.filter(|&prefix| self.last_prefix.as_deref() != Some(prefix)) {
+            self.prefix_hashes.push(hash_key(prefix));
+            self.last_prefix = Some(prefix.to_vec());
+        }
+
+        let value = entry.value.as_deref().or(entry.retained.as_deref()).unwrap_or_default();
+        self.block.extend_from_slice(&(entry.key.len() as u32).to_le_bytes());
+        self.block.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        let mut flags = 0;
+        if entry.is_deleted() {
+            flags |= FLAG_TOMBSTONE;
+        }
+        if entry.is_soft_deleted() {
+            flags |= FLAG_RETAINED;
+        }
+        if entry.schema != 0 {
+            flags |= FLAG_SCHEMA;
+        }
+        if entry.value_pointer {
+            flags |= FLAG_VALUE_POINTER;
+        }
+        if entry.backdated {
+            flags |= FLAG_BACKDATED;
+        }
+        self.block.push(flags);
+        self.block.extend_from_slice(&entry.timestamp.to_le_bytes());
+        self.block.extend_from_slice(&entry.sequence.to_le_bytes());
+        if entry.schema != 0 {
+            self.block.extend_from_slice(&entry.schema.to_le_bytes());
+        }
+        self.block.extend_from_slice(&entry.key);
+        self.block.extend_from_slice(value);
+        self.last_key.clone_from(&entry.key);
+        self.last_sequence = entry.sequence;
+        self.entry_count += 1;
+
+        if self.block.len() >= self.block_size {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// Adds a range tombstone, stored apart from the entries so it may be added at any time.
+    pub fn add_range_tombstone(&mut self, tombstone: &RangeTombstone) {
+        self.range_tombstones.push(tombstone.clone());
+    }
+
+    /// Returns the number of entries added so far.
+    pub fn entry_count(&self) -> u64 {
+        self.entry_count
+    }
+
+    /// Writes the filter, range tombstones, index and footer and syncs the file to disk.
+    /// Returns the file size.
+    pub fn finish(mut self) -> io::Result<u64> {
+        self.finish_block()?;
+
+        let (mut filter_offset, mut filter_size) = (0, 0);
+        if self.bits_per_key > 0 && !self.key_hashes.is_empty() {
+            let filter = BloomFilter::build(&self.key_hashes, self.bits_per_key).encode();
+            self.writer.write_all(&filter)?;
+            self.writer.write_all(&crc32c(&filter).to_le_bytes())?;
+            filter_offset = self.offset;
+            filter_size = filter.len() as u64;
+            self.offset += filter_size + 4;
+        }
+
+        let (mut prefix_filter_offset, mut prefix_filter_size) = (0, 0);
+        if let Some(extractor) = self.prefix_extractor.as_ref() {
+            if self.bits_per_key > 0 && !self.prefix_hashes.is_empty() {
+                let filter = BloomFilter::build(&self.prefix_hashes, self.bits_per_key);
+                let mut block = (extractor.name().len() as u32).to_le_bytes().to_vec();
+                block.extend_from_slice(extractor.name().as_bytes());
+                block.extend_from_slice(&filter.encode());
+                self.writer.write_all(&block)?;
+                self.writer.write_all(&crc32c(&block).to_le_bytes())?;
+                prefix_filter_offset = self.offset;
+                prefix_filter_size = block.len() as u64;
+                self.offset += prefix_filter_size + 4;
+            }
+        }
+
+        let (mut tombstones_offset, mut tombstones_size) = (0, 0);
+        if !self.range_tombstones.is_empty() {
+            let tombstones = encode_range_tombstones(&self.range_tombstones);
+            self.writer.write_all(&tombstones)?;
+            self.writer.write_all(&crc32c(&tombstones).to_le_bytes())?;
+            tombstones_offset = self.offset;
+            tombstones_size = tombstones.len() as u64;
+            self.offset += tombstones_size + 4;
+        }
+
+        let mut index = Vec::new();
+        index.extend_from_slice(&(self.index.len() as u64).to_le_bytes());
+        for handle in self.index.iter() {
+            index.extend_from_slice(&(handle.last_key.len() as u32).to_le_bytes());
+            index.extend_from_slice(&handle.last_key);
+            index.extend_from_slice(&handle.offset.to_le_bytes());
+            index.extend_from_slice(&handle.size.to_le_bytes());
+        }
+        index.extend_from_slice(&filter_offset.to_le_bytes());
+        index.extend_from_slice(&filter_size.to_le_bytes());
+        index.extend_from_slice(&tombstones_offset.to_le_bytes());
+        index.extend_from_slice(&tombstones_size.to_le_bytes());
+        index.extend_from_slice(&prefix_filter_offset.to_le_bytes());
+        index.extend_from_slice(&prefix_filter_size.to_le_bytes());
+        let index_offset = self.offset;
+        self.writer.write_all(&index)?;
+        self.writer.write_all(&crc32c(&index).to_le_bytes())?;
+
+        self.writer.write_all(&index_offset.to_le_bytes())?;
+        self.writer.write_all(&(index.len() as u64).to_le_bytes())?;
+        self.writer.write_all(&self.entry_count.to_le_bytes())?;
+        self.writer.write_all(&SSTABLE_MAGIC)?;
+        self.writer.flush()?;
+        self.writer.get_ref().get_ref().sync()?;
+
+        Ok(index_offset + index.len() as u64 + 4 + FOOTER_SIZE)
+    }
+
+    /// Returns the path of the file being written.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn finish_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+
+        let stored = self.compression.compress(&self.block)?;
+        self.writer.write_all(&stored)?;
+        self.writer.write_all(&crc32c(&stored).to_le_bytes())?;
+        self.index.push(BlockHandle {
+            last_key: self.last_key.clone(),
+            offset: self.offset,
+            size: stored.len() as u64,
+        });
+        self.offset += stored.len() as u64 + 4;
+        self.block.clear();
+        Ok(())
+    }
+}
+
+/// How a read goes through the block cache of a segment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct BlockReads {
+    /// Reads blocks from the file even when they are cached, checking them again.
+    pub verify: bool,
+    /// Caches the blocks read from the file.
+    pub fill_cache: bool,
+}
+
+impl Default for BlockReads {
+    fn default() -> BlockReads {
+        BlockReads {
+            verify: false,
+            fill_cache: true,
+        }
+    }
+}
+
+/// A read-only segment file. Its index is kept in memory; data blocks are read on demand.
+pub struct SSTable {
+    path: PathBuf,
+    file: Box<dyn StorageFile>,
+    version: u8,
+    compression: Compression,
+    index: Vec<BlockHandle>,
+    filter: Option<BloomFilter>,
+    /// Bloom filter over the key prefixes, with the name of the extractor that took them.
+    prefix_filter: Option<(String, BloomFilter)>,
+    range_tombstones: Vec<RangeTombstone>,
+    entry_count: u64,
+    file_size: u64,
+    /// Cache of decompressed blocks, with the number identifying the file in it.
+    block_cache: Option<(BlockCache, u64)>,
+    /// Value log the entries flagged with `FLAG_VALUE_POINTER` point into.
+    value_log: Option<ValueLog>,
+    order: KeyOrder,
+    /// Sequence number the entries stored at sequence 0 are read at, or 0.
+    global_sequence: u64,
+}
+
+impl SSTable {
+    /// Opens a segment file, validating its header, footer and index.
+    pub fn open(path: &Path) -> io::Result<SSTable> {
+        SSTable::open_with(&Storage::default(), path)
+    }
+
+    /// Opens a segment file kept in `storage`, validating its header, footer and index.
+    pub fn open_with(storage: &Storage, path: &Path) -> io::Result<SSTable> {
+        let file = storage.open(path)?;
+        let file_size = file.size()?;
+        if file_size < HEADER_SIZE + FOOTER_SIZE + 4 {
+            return Err(corrupted(path, "file is too short"));
+        }
+
+        let mut header = [0u8; HEADER_SIZE as usize];
+        read_exact_at(file.as_ref(), &mut header, 0)?;
+        if header[..4] != SSTABLE_MAGIC {
+            return Err(corrupted(path, "bad magic"));
+        }
+        match header[4] {
+            0 => return Err(corrupted(path, "invalid format version 0")),
+            1..=SSTABLE_VERSION => {}
+            version => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!(
+                        "segment {} has format version {}, newer than version {}, the newest \
+                         this build reads",
+                        path.display(),
+                        version,
+                        SSTABLE_VERSION
+                    ),
+                ))
+            }
+        }
+        let compression = Compression::from_id(header[5])?;
+        compression.ensure_available()?;
+
+        let mut footer = [0u8; FOOTER_SIZE as usize];
+        read_exact_at(file.as_ref(), &mut footer, file_size - FOOTER_SIZE)?;
+        if footer[24..] != SSTABLE_MAGIC {
+            return Err(corrupted(path, "bad footer"));
+        }
+        let index_offset = u64::from_le_bytes(footer[..8].try_into().unwrap());
+        let index_size = u64::from_le_bytes(footer[8..16].try_into().unwrap());
+        let entry_count = u64::from_le_bytes(footer[16..24].try_into().unwrap());
+        if index_offset < HEADER_SIZE || index_offset + index_size + 4 + FOOTER_SIZE != file_size {
+            return Err(corrupted(path, "bad index location"));
+        }
+
+        let index = read_checked(file.as_ref(), index_offset, index_size)
+            .and_then(|bytes| decode_index(&bytes, header[4]))
+            .map_err(|_| corrupted(path, "bad index block"))?;
+        let filter = match index.filter {
+            Some((offset, size)) => Some(
+                read_checked(file.as_ref(), offset, size)
+                    .and_then(|bytes| BloomFilter::decode(&bytes))
+                    .map_err(|_| corrupted(path, "bad filter block"))?,
+            ),
+            None => None,
+        };
+        let prefix_filter = match index.prefix_filter {
+            Some((offset, size)) => Some(
+                read_checked(file.as_ref(), offset, size)
+                    .and_then(|bytes| decode_prefix_filter(&bytes))
+                    .map_err(|_| corrupted(path, "bad prefix filter block"))?,
+            ),
+            None => None,
+        };
+        let range_tombstones = match index.range_tombstones {
+            Some((offset, size)) => read_checked(file.as_ref(), offset, size)
+                .and_then(|bytes| decode_range_tombstones(&bytes))
+                .map_err(|_| corrupted(path, "bad range tombstone block"))?,
+            None => Vec::new(),
+        };
+
+        Ok(SSTable {
+            path: path.to_owned(),
+            file,
+            version: header[4],
+            compression,
+            index: index.blocks,
+            filter,
+            prefix_filter,
+            range_tombstones,
+            entry_count,
+            file_size,
+            block_cache: None,
+            value_log: None,
+            order: KeyOrder::default(),
+            global_sequence: 0,
+        })
+    }
+
+    /// Sets the order the keys of the segment were written in, byte order by default.
+    pub fn with_key_order(mut self, order: &KeyOrder) -> SSTable {
+        self.order = order.clone();
+        self
+    }
+
+    /// Keeps the decompressed blocks read from the segment in `cache`.
+    pub fn with_block_cache(mut self, cache: &BlockCache) -> SSTable {
+        self.block_cache = Some((cache.clone(), cache.file_id()));
+        self
+    }
+
+    /// Reads the values kept out of the segment from `log`.
+    pub(crate) fn with_value_log(mut self, log: &ValueLog) -> SSTable {
+        self.value_log = Some(log.clone());
+        self
+    }
+
+    /// Reads the entries stored at sequence 0, as those of a segment written by `SstWriter`,
+    /// at `sequence` instead: the one the segment was ingested at. 0 leaves them as stored.
+    pub(crate) fn with_global_sequence(mut self, sequence: u64) -> SSTable {
+        self.global_sequence = sequence;
+        self
+    }
+
+    /// Looks up the latest version of a key (possibly a tombstone) held by the segment.
+    pub fn get(&self, key: &[u8]) -> io::Result<Option<Entry>> {
+        self.get_at(key, u64::MAX)
+    }
+
+    /// Looks up the latest version of a key written at or before `sequence`.
+    pub fn get_at(&self, key: &[u8], sequence: u64) -> io::Result<Option<Entry>> {
+        self.get_at_with(key, sequence, BlockReads::default())
+    }
+
+    /// Like `get_at`, reading the blocks as `reads` says.
+    pub(crate) fn get_at_with(
+        &self,
+        key: &[u8],
+        sequence: u64,
+        reads: BlockReads,
+    ) -> io::Result<Option<Entry>> {
+        if !self.may_contain(key) {
+            return Ok(None);
+        }
+        for entry in self.iter_from(Bound::Included(key)).with_reads(reads) {
+            let entry = entry?;
+            if entry.key != key {
+                break;
+            }
+            if entry.sequence <= sequence && !entry.backdated {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Looks up several keys, given in increasing order, as `get_at` would. Keys ruled out by
+    /// the bloom filter are skipped, and keys falling into the same block share its read.
+    pub fn get_many_at(&self, keys: &[&[u8]], sequence: u64) -> io::Result<Vec<Option<Entry>>> {
+        let mut found = Vec::with_capacity(keys.len());
+        let mut cached: Option<(usize, Vec<Entry>)> = None;
+        for &key in keys {
+            let mut result = None;
+            let mut block = self.block_for(key);
+            'blocks: while self.may_contain(key) && block < self.index.len() {
+                let entries = match &cached {
+                    Some((cached_block, entries)) if *cached_block == block => entries,
+                    _ => &cached.insert((block, self.read_block(block)?)).1,
+                };
+                let start = entries
+                    .partition_point(|entry| self.order.compare(&entry.key, key).is_lt());
+                for entry in &entries[start..] {
+                    if entry.key != key {
+                        break 'blocks;
+                    }
+                    if entry.sequence <= sequence && !entry.backdated {
+                        result = Some(self.resolve(entry.clone())?);
+                        break 'blocks;
+                    }
+                }
+                // Older versions of the key continue in the next block.
+                block += 1;
+            }
+            found.push(result);
+        }
+        Ok(found)
+    }
+
+    /// Returns the ranges deleted in the segment. They apply to older versions of keys held
+    /// by any segment, not just this one.
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.range_tombstones
+    }
+
+    /// Returns whether the segment has a bloom filter; `may_contain` is always true otherwise.
+    pub fn has_bloom_filter(&self) -> bool {
+        self.filter.is_some()
+    }
+
+    /// Returns whether the segment may hold the key, according to its bloom filter.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.filter.as_ref().is_none_or(|filter| filter.may_contain(key))
+    }
+
+    /// Returns whether the segment may hold keys starting with `prefix`, according to its
+    /// prefix filter. Always true unless the filter was built by an extractor named as
+    /// `extractor` and `prefix` is one of its prefixes.
+    pub fn may_contain_prefix(&self, prefix: &[u8], extractor: &PrefixExtractor) -> bool {
+        match &self.prefix_filter {
+            Some((name, filter)) if name == extractor.name() && extractor.is_prefix(prefix) => {
+                filter.may_contain(prefix)
+            }
+            _ => true,
+        }
+    }
+
+    /// Iterates over every entry in key order.
+    pub fn iter(&self) -> SSTableIterator<'_> {
+        self.iter_from(Bound::Unbounded)
+    }
+
+    /// Iterates in key order over the entries at or after the start bound.
+    pub fn iter_from(&self, start: Bound<&[u8]>) -> SSTableIterator<'_> {
+        let block = match start {
+            Bound::Included(key) | Bound::Excluded(key) => self.block_for(key),
+            Bound::Unbounded => 0,
+        };
+        SSTableIterator {
+            table: self,
+            next_block: block,
+            resolve: true,
+            keys_only: false,
+            reads: BlockReads::default(),
+            key_filter: None,
+            entries: Vec::new().into_iter(),
+            start: match start {
+                Bound::Included(key) => Bound::Included(key.to_vec()),
+                Bound::Excluded(key) => Bound::Excluded(key.to_vec()),
+                Bound::Unbounded => Bound::Unbounded,
+            },
+        }
+    }
+
+    /// Iterates in decreasing key order over the entries at or before the end bound, the
+    /// versions of a key still newest first.
+    pub fn iter_rev_to(&self, end: Bound<&[u8]>) -> SSTableRevIterator<'_> {
+        // Blocks past the first one holding a key after the end bound can be skipped.
+        let blocks = match end {
+            Bound::Included(key) => {
+                self.index
+                    .partition_point(|handle| self.order.compare(&handle.last_key, key).is_le())
+                    + 1
+            }
+            Bound::Excluded(key) => self.block_for(key) + 1,
+            Bound::Unbounded => self.index.len(),
+        };
+        SSTableRevIterator {
+            table: self,
+            blocks: blocks.min(self.index.len()),
+            entries: Vec::new(),
+            key_filter: None,
+            reads: BlockReads::default(),
+            end: match end {
+                Bound::Included(key) => Bound::Included(key.to_vec()),
+                Bound::Excluded(key) => Bound::Excluded(key.to_vec()),
+                Bound::Unbounded => Bound::Unbounded,
+            },
+        }
+    }
+
+    /// Returns the blocks that may hold keys within the bounds, found from the index alone.
+    pub(crate) fn blocks_within(&self, bounds: (Bound<&[u8]>, Bound<&[u8]>)) -> Range<usize> {
+        let start = match bounds.0 {
+            Bound::Included(key) | Bound::Excluded(key) => self.block_for(key),
+            Bound::Unbounded => 0,
+        };
+        let end = match bounds.1 {
+            Bound::Included(key) | Bound::Excluded(key) => self.block_for(key) + 1,
+            Bound::Unbounded => self.index.len(),
+        };
+        start..end.min(self.index.len()).max(start)
+    }
+
+    /// Returns the entries of a data block, in key order, with the values kept in a value log
+    /// left as pointers.
+    pub(crate) fn block_entries(&self, block: usize) -> io::Result<Vec<Entry>> {
+        self.read_block(block)
+    }
+
+    /// Returns the number of data blocks.
+    pub fn block_count(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Checks a data block as stored in the file, even if it is cached: its checksum, and
+    /// that it decompresses and decodes. Returns its stored size, checksum included.
+    pub(crate) fn verify_block(&self, block: usize) -> io::Result<u64> {
+        let data = self.read_block_data(block)?;
+        decode_entries(&data, self.version, true)
+            .map_err(|_| corrupted(&self.path, "bad data block"))?;
+        Ok(self.index[block].size + 4)
+    }
+
+    /// Returns a data block as stored in the file, checksum included, intact or not.
+    pub(crate) fn raw_block(&self, block: usize) -> io::Result<Vec<u8>> {
+        let handle = &self.index[block];
+        let mut buf = vec![0u8; handle.size as usize + 4];
+        read_exact_at(self.file.as_ref(), &mut buf, handle.offset)?;
+        Ok(buf)
+    }
+
+    /// Returns the path of the segment file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the number of entries, tombstones included.
+    pub fn entry_count(&self) -> u64 {
+        self.entry_count
+    }
+
+    /// Returns the size of the file in bytes.
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    /// Returns the smallest key of the segment, reading its first block, or `None` if it
+    /// holds no entries.
+    pub fn smallest_key(&self) -> io::Result<Option<Vec<u8>>> {
+        if self.index.is_empty() {
+            return Ok(None);
+        }
+        Ok(self.read_block(0)?.into_iter().next().map(|entry| entry.key))
+    }
+
+    /// Returns the largest key of the segment, found from the index alone.
+    pub fn largest_key(&self) -> Option<&[u8]> {
+        self.index.last().map(|handle| handle.last_key.as_slice())
+    }
+
+    /// Returns the bytes of the data blocks that may hold keys within the bounds, as stored,
+    /// found from the index alone.
+    pub fn approximate_size(&self, bounds: (Bound<&[u8]>, Bound<&[u8]>)) -> u64 {
+        self.index[self.blocks_within(bounds)]
+            .iter()
+            .map(|handle| handle.size)
+            .sum()
+    }
+
+    /// Returns the index of the first block that may hold `key`.
+    fn block_for(&self, key: &[u8]) -> usize {
+        self.index
+            .partition_point(|handle| self.order.compare(&handle.last_key, key).is_lt())
+    }
+
+    fn read_block(&self, block: usize) -> io::Result<Vec<Entry>> {
+        self.read_block_entries(block, false, BlockReads::default())
+    }
+
+    /// Reads the entries of a data block through the block cache as `reads` says, leaving
+    /// every value empty when `keys_only` is set rather than copying it out of the block.
+    fn read_block_entries(
+        &self,
+        block: usize,
+        keys_only: bool,
+        reads: BlockReads,
+    ) -> io::Result<Vec<Entry>> {
+        let data = match &self.block_cache {
+            Some((cache, file)) if !reads.verify => match cache.get(*file, block) {
+                Some(data) => data,
+                None => {
+                    let data = Bytes::from(self.read_block_data(block)?);
+                    if reads.fill_cache {
+                        cache.insert(*file, block, data.clone());
+                    }
+                    data
+                }
+            },
+            _ => Bytes::from(self.read_block_data(block)?),
+        };
+        let mut entries = decode_entries(&data, self.version, keys_only)
+            .map_err(|_| corrupted(&self.path, "bad data block"))?;
+        if self.global_sequence != 0 {
+            for entry in entries.iter_mut().filter(|entry| entry.sequence == 0) {
+                entry.sequence = self.global_sequence;
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Reads the value an entry points to in the value log, if it does.
+    fn resolve(&self, mut entry: Entry) -> io::Result<Entry> {
+        if !entry.value_pointer {
+            return Ok(entry);
+        }
+        let (Some(log), Some(pointer)) = (&self.value_log, &entry.value) else {
+            let reason = "value kept in a value log, which only the database can read";
+            return Err(corrupted(&self.path, reason));
+        };
+        entry.value = Some(log.get(pointer)?);
+        entry.value_pointer = false;
+        Ok(entry)
+    }
+
+    /// Reads, checks and decompresses a data block.
+    fn read_block_data(&self, block: usize) -> io::Result<Vec<u8>> {
+        let handle = &self.index[block];
+        let stored = read_checked(self.file.as_ref(), handle.offset, handle.size)
+            .map_err(|_| corrupted(&self.path, "checksum mismatch in data block"))?;
+        self.compression.decompress(&stored)
+    }
+}
+
+impl Drop for SSTable {
+    fn drop(&mut self) {
+        if let Some((cache, file)) = &self.block_cache {
+            cache.remove_file(*file);
+        }
+    }
+}
+
+/// Iterator over the entries of a segment, reading one block at a time.
+pub struct SSTableIterator<'a> {
+    table: &'a SSTable,
+    next_block: usize,
+    /// Whether values kept in a value log are read, or left as pointers.
+    resolve: bool,
+    /// Whether values are left empty, live entries and tombstones still told apart.
+    keys_only: bool,
+    /// How blocks go through the block cache.
+    reads: BlockReads,
+    /// Skips the entries whose keys it rejects, before their values are read.
+    key_filter: Option<ScanFilter>,
+    entries: std::vec::IntoIter<Entry>,
+    start: Bound<Vec<u8>>,
+}
+
+impl SSTableIterator<'_> {
+    /// Leaves the values kept in a value log as pointers, for compactions to carry over.
+    pub(crate) fn unresolved(mut self) -> Self {
+        self.resolve = false;
+        self
+    }
+
+    /// Skips the entries whose keys `filter` rejects.
+    pub(crate) fn filter_keys(mut self, filter: Option<ScanFilter>) -> Self {
+        self.key_filter = filter;
+        self
+    }
+
+    /// Returns the entries with empty values, for reading keys alone: values are neither
+    /// copied out of their blocks nor read from a value log.
+    pub(crate) fn keys_only(mut self) -> Self {
+        self.keys_only = true;
+        self.resolve = false;
+        self
+    }
+
+    /// Reads the blocks as `reads` says.
+    pub(crate) fn with_reads(mut self, reads: BlockReads) -> Self {
+        self.reads = reads;
+        self
+    }
+}
+
+impl Iterator for SSTableIterator<'_> {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                let order = &self.table.order;
+                let before_start = match &self.start {
+                    Bound::Included(key) => order.compare(&entry.key, key) == Ordering::Less,
+                    Bound::Excluded(key) => order.compare(&entry.key, key) != Ordering::Greater,
+                    Bound::Unbounded => false,
+                };
+                let rejected = self.key_filter.as_ref().is_some_and(|f| !f(&entry.key));
+                if !before_start && !rejected {
+                    return Some(match self.resolve {
+                        true => self.table.resolve(entry),
+                        false => Ok(entry),
+                    });
+                }
+                continue;
+            }
+
+            if self.next_block >= self.table.index.len() {
+                return None;
+            }
+            match self.table.read_block_entries(self.next_block, self.keys_only, self.reads) {
+                Ok(entries) => {
+                    self.next_block += 1;
+                    self.entries = entries.into_iter();
+                }
+                Err(e) => {
+                    self.next_block = self.table.index.len();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Iterator over the entries of a segment in decreasing key order, reading one block at a
+/// time from the end.
+pub struct SSTableRevIterator<'a> {
+    table: &'a SSTable,
+    /// Number of blocks not read yet, from the start of the file.
+    blocks: usize,
+    /// Entries left to return, popped from the back: keys in increasing order, the versions
+    /// of a key oldest first.
+    entries: Vec<Entry>,
+    end: Bound<Vec<u8>>,
+    /// Skips the entries whose keys it rejects, before their values are read.
+    key_filter: Option<ScanFilter>,
+    /// How blocks go through the block cache.
+    reads: BlockReads,
+}
+
+impl SSTableRevIterator<'_> {
+    /// Skips the entries whose keys `filter` rejects.
+    pub(crate) fn filter_keys(mut self, filter: Option<ScanFilter>) -> Self {
+        self.key_filter = filter;
+        self
+    }
+
+    /// Reads the blocks as `reads` says.
+    pub(crate) fn with_reads(mut self, reads: BlockReads) -> Self {
+        self.reads = reads;
+        self
+    }
+
+    /// Reads the previous block. The versions of the first key of the entries held back may
+    /// continue at the end of that block, so those entries are only returned once it has
+    /// been read, keeping the versions of every key newest first.
+    fn read_previous_block(&mut self) -> io::Result<()> {
+        self.blocks -= 1;
+        let mut block = self.table.read_block_entries(self.blocks, false, self.reads)?;
+        let first_key = self.entries.first().map(|entry| entry.key.clone());
+        let split = match &first_key {
+            Some(key) => block.iter().rposition(|entry| entry.key != *key).map_or(0, |i| i + 1),
+            None => block.len(),
+        };
+        // The held back versions of the first key are oldest first; the ones in this block,
+        // newer, go after them.
+        let mut newer: Vec<Entry> = block.drain(split..).collect();
+        newer.reverse();
+        let held_back = std::mem::take(&mut self.entries);
+        let mut entries = reverse_versions(block);
+        let mut held_back = held_back.into_iter().peekable();
+        while let Some(entry) = held_back.next_if(|entry| Some(&entry.key) == first_key.as_ref()) {
+            entries.push(entry);
+        }
+        entries.extend(newer);
+        entries.extend(held_back);
+        self.entries = entries;
+        Ok(())
+    }
+}
+
+impl Iterator for SSTableRevIterator<'_> {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // Only the first key held may have newer versions in an earlier block.
+            let complete = match (self.entries.first(), self.entries.last()) {
+                (Some(first), Some(last)) => self.blocks == 0 || first.key != last.key,
+                _ => false,
+            };
+            if complete {
+                let entry = self.entries.pop()?;
+                let order = &self.table.order;
+                let after_end = match &self.end {
+                    Bound::Included(key) => order.compare(&entry.key, key) == Ordering::Greater,
+                    Bound::Excluded(key) => order.compare(&entry.key, key) != Ordering::Less,
+                    Bound::Unbounded => false,
+                };
+                let rejected = self.key_filter.as_ref().is_some_and(|f| !f(&entry.key));
+                if !after_end && !rejected {
+                    return Some(self.table.resolve(entry));
+                }
+                continue;
+            }
+
+            if self.blocks == 0 {
+                return None;
+            }
+            if let Err(e) = self.read_previous_block() {
+                self.blocks = 0;
+                self.entries.clear();
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+/// Reorders entries in key order, versions newest first, so that popping them from the back
+/// yields keys in decreasing order with the versions of each still newest first.
+fn reverse_versions(mut entries: Vec<Entry>) -> Vec<Entry> {
+    for versions in entries.chunk_by_mut(|a, b| a.key == b.key) {
+        versions.reverse();
+    }
+    entries
+}
+
+/// Reads `size` bytes at `offset` followed by their CRC32C, verifying the checksum.
+fn read_checked(file: &dyn StorageFile, offset: u64, size: u64) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; size as usize + 4];
+    read_exact_at(file, &mut buf, offset)?;
+    let crc = u32::from_le_bytes(buf[size as usize..].try_into().unwrap());
+    buf.truncate(size as usize);
+    if crc32c(&buf) != crc {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "checksum mismatch"));
+    }
+    Ok(buf)
+}
+
+/// Decoded index block: the data block handles and the offset and size of the optional
+/// blocks the file has.
+struct Index {
+    blocks: Vec<BlockHandle>,
+    filter: Option<(u64, u64)>,
+    range_tombstones: Option<(u64, u64)>,
+    prefix_filter: Option<(u64, u64)>,
+}
+
+/// Decodes the index block of a file written with the given format version.
+fn decode_index(bytes: &[u8], version: u8) -> io::Result<Index> {
+    let mut reader = ByteReader(bytes);
+    let count = reader.u64()?;
+    let mut blocks = Vec::new();
+    for _ in 0..count {
+        let key_len = reader.u32()? as usize;
+        blocks.push(BlockHandle {
+            last_key: reader.take(key_len)?.to_vec(),
+            offset: reader.u64()?,
+            size: reader.u64()?,
+        });
+    }
+    let mut location = |since: u8| -> io::Result<Option<(u64, u64)>> {
+        if version < since {
+            return Ok(None);
+        }
+        let (offset, size) = (reader.u64()?, reader.u64()?);
+        Ok((size > 0).then_some((offset, size)))
+    };
+    Ok(Index {
+        blocks,
+        filter: location(4)?,
+        range_tombstones: location(5)?,
+        prefix_filter: location(6)?,
+    })
+}
+
+/// Decodes a prefix filter block: the length and name of the extractor, then the filter.
+fn decode_prefix_filter(bytes: &[u8]) -> io::Result<(String, BloomFilter)> {
+    let mut reader = ByteReader(bytes);
+    let name_len = reader.u32()? as usize;
+    let name = String::from_utf8(reader.take(name_len)?.to_vec())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok((name, BloomFilter::decode(reader.0)?))
+}
+
+/// Encodes range tombstones as a count followed by each start key, end key, timestamp and
+/// sequence number.
+fn encode_range_tombstones(tombstones: &[RangeTombstone]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&(tombstones.len() as u64).to_le_bytes());
+    for tombstone in tombstones {
+        for key in [&tombstone.start, &tombstone.end] {
+            bytes.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(key);
+        }
+        bytes.extend_from_slice(&tombstone.timestamp.to_le_bytes());
+        bytes.extend_from_slice(&tombstone.sequence.to_le_bytes());
+    }
+    bytes
+}
+
+fn decode_range_tombstones(bytes: &[u8]) -> io::Result<Vec<RangeTombstone>> {
+    let mut reader = ByteReader(bytes);
+    let count = reader.u64()?;
+    let mut tombstones = Vec::new();
+    for _ in 0..count {
+        let start_len = reader.u32()? as usize;
+        let start = reader.take(start_len)?.to_vec();
+        let end_len = reader.u32()? as usize;
+        let end = reader.take(end_len)?.to_vec();
+        tombstones.push(RangeTombstone {
+            start,
+            end,
+            timestamp: u128::from_le_bytes(reader.take(16)?.try_into().unwrap()),
+            sequence: reader.u64()?,
+        });
+    }
+    Ok(tombstones)
+}
+
+/// Decodes the entries of a data block, with empty values if `keys_only` is set. Version 1
+/// entries have no sequence number and are read as sequence 0, older than anything written
+/// since.
+fn decode_entries(bytes: &[u8], version: u8, keys_only: bool) -> io::Result<Vec<Entry>> {
+    let overhead = if version >= 2 { ENTRY_OVERHEAD } else { ENTRY_OVERHEAD - 8 };
+    let mut reader = ByteReader(bytes);
+    let mut entries = Vec::new();
+    while !reader.0.is_empty() {
+        if reader.0.len() < overhead {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let key_len = reader.u32()? as usize;
+        let value_len = reader.u32()? as usize;
+        let flags = reader.take(1)?[0];
+        let timestamp = u128::from_le_bytes(reader.take(16)?.try_into().unwrap());
+        let sequence = if version >= 2 { reader.u64()? } else { 0 };
+        let schema = if flags & FLAG_SCHEMA != 0 { reader.u32()? } else { 0 };
+        let key = reader.take(key_len)?.to_vec();
+        let value = reader.take(value_len)?;
+        let value = if keys_only { Vec::new() } else { value.to_vec() };
+        let (value, retained) = match (flags & FLAG_TOMBSTONE, flags & FLAG_RETAINED) {
+            (0, _) => (Some(value), None),
+            (_, 0) => (None, None),
+            _ => (None, Some(value)),
+        };
+        entries.push(Entry {
+            value_pointer: flags & FLAG_VALUE_POINTER != 0 && value.is_some() && !keys_only,
+            backdated: flags & FLAG_BACKDATED != 0,
+            key,
+            value,
+            timestamp,
+            sequence,
+            schema,
+            retained,
+        });
+    }
+    Ok(entries)
+}
+
+/// Cursor over an in-memory buffer with bounds-checked reads.
+struct ByteReader<'a>(&'a [u8]);
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+fn corrupted(path: &Path, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupted segment {}: {}", path.display(), reason),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use std::fs::{create_dir_all, remove_dir_all};
+
+    fn entry(key: &str, value: Option<&str>, sequence: u64) -> Entry {
+        Entry {
+            key: key.as_bytes().to_vec(),
+            value: value.map(|value| value.as_bytes().to_vec()),
+            timestamp: sequence as u128,
+            sequence,
+            schema: 0,
+            retained: None,
+            value_pointer: false,
+            backdated: false,
+        }
+    }
+
+    fn write_table(path: &Path, entries: &[Entry], block_size: usize) {
+        let mut writer = SSTableWriter::create(path, Compression::None, block_size).unwrap();
+        for entry in entries {
+            writer.add(entry).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        let mut rng = rand::thread_rng();
+        let test_dir = format!("./{}/", rng.gen::<u32>());
+        create_dir_all(&test_dir).unwrap();
+        let path = Path::new(&test_dir).join("000001.sst");
+
+        let entries: Vec<Entry> = (0..500)
+            .map(|i| {
+                let value = format!("value{}", i);
+                entry(&format!("key{:04}", i), (i % 7 != 0).then_some(value.as_str()), i)
+            })
+            .collect();
+        write_table(&path, &entries, 256);
+
+        let table = SSTable::open(&path).unwrap();
+        assert_eq!(table.entry_count(), 500);
+        assert!(table.index.len() > 1);
+        assert_eq!(table.get(b"key0042").unwrap(), Some(entries[42].clone()));
+        assert!(table.get(b"key0049").unwrap().unwrap().is_deleted());
+        assert_eq!(table.get(b"key9999").unwrap(), None);
+        assert_eq!(table.get(b"key0042a").unwrap(), None);
+
+        let all: Vec<Entry> = table.iter().map(|entry| entry.unwrap()).collect();
+        assert_eq!(all, entries);
+        let tail: Vec<Entry> = table
+            .iter_from(Bound::Excluded(b"key0497"))
+            .map(|entry| entry.unwrap())
+            .collect();
+        assert_eq!(tail, entries[498..].to_vec());
+        let mut backwards: Vec<Entry> =
+            table.iter_rev_to(Bound::Unbounded).map(Result::unwrap).collect();
+        backwards.reverse();
+        assert_eq!(backwards, entries);
+        let head: Vec<Entry> = table
+            .iter_rev_to(Bound::Included(b"key0002"))
+            .map(|entry| entry.unwrap())
+            .collect();
+        assert_eq!(head, vec![entries[2].clone(), entries[1].clone(), entries[0].clone()]);
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_unordered_keys() {
+        let mut rng = rand::thread_rng();
+        let test_dir = format!("./{}/", rng.gen::<u32>());
+        create_dir_all(&test_dir).unwrap();
+
+        let path = Path::new(&test_dir).join("000001.sst");
+        let mut writer = SSTableWriter::create(&path, Compression::None, 4096).unwrap();
+        writer.add(&entry("b", Some("2"), 2)).unwrap();
+        assert!(writer.add(&entry("a", Some("3"), 3)).is_err());
+        assert!(writer.add(&entry("b", Some("3"), 3)).is_err());
+        writer.add(&entry("b", Some("1"), 1)).unwrap();
+        writer.add(&entry("c", Some("1"), 1)).unwrap();
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_detects_corrupted_block() {
+        let mut rng = rand::thread_rng();
+        let test_dir = format!("./{}/", rng.gen::<u32>());
+        create_dir_all(&test_dir).unwrap();
+        let path = Path::new(&test_dir).join("000001.sst");
+        write_table(&path, &[entry("Server", Some("nginx"), 1)], 4096);
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[HEADER_SIZE as usize + ENTRY_OVERHEAD] ^= 0xFF;
+        std::fs::write(&path, bytes).unwrap();
+
+        let table = SSTable::open(&path).unwrap();
+        let err = table.get(b"Server").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_unknown_versions() {
+        let mut rng = rand::thread_rng();
+        let test_dir = format!("./{}/", rng.gen::<u32>());
+        create_dir_all(&test_dir).unwrap();
+        let path = Path::new(&test_dir).join("000001.sst");
+        write_table(&path, &[entry("Server", Some("nginx"), 1)], 4096);
+        let bytes = std::fs::read(&path).unwrap();
+
+        let mut newer = bytes.clone();
+        newer[4] = SSTABLE_VERSION + 1;
+        std::fs::write(&path, newer).unwrap();
+        let err = SSTable::open(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        let message = format!(
+            "format version {}, newer than version {}",
+            SSTABLE_VERSION + 1,
+            SSTABLE_VERSION
+        );
+        assert!(err.to_string().contains(&message));
+
+        let mut zero = bytes;
+        zero[4] = 0;
+        std::fs::write(&path, zero).unwrap();
+        let err = SSTable::open(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_reads_versions_at_sequence() {
+        let mut rng = rand::thread_rng();
+        let test_dir = format!("./{}/", rng.gen::<u32>());
+        create_dir_all(&test_dir).unwrap();
+        let path = Path::new(&test_dir).join("000001.sst");
+
+        // Small blocks so the versions of "Server" span several of them.
+        let mut entries = vec![entry("API", Some("GraphQL"), 3)];
+        for sequence in (1..=20).rev() {
+            let value = if sequence == 10 { None } else { Some("nginx") };
+            entries.push(entry("Server", value, sequence * 10));
+        }
+        entries.push(entry("Storage", Some("S3"), 1));
+        write_table(&path, &entries, 64);
+
+        let table = SSTable::open(&path).unwrap();
+        assert_eq!(table.get(b"Server").unwrap().unwrap().sequence, 200);
+        assert_eq!(table.get_at(b"Server", 155).unwrap().unwrap().sequence, 150);
+        assert!(table.get_at(b"Server", 105).unwrap().unwrap().is_deleted());
+        assert_eq!(table.get_at(b"Server", 9).unwrap(), None);
+        assert_eq!(table.get_at(b"API", 2).unwrap(), None);
+
+        // Backwards, keys come in decreasing order with their versions still newest first.
+        let rev = |end| table.iter_rev_to(end).map(|entry| entry.unwrap()).collect::<Vec<_>>();
+        let mut expected = vec![entries[21].clone()];
+        expected.extend_from_slice(&entries[1..21]);
+        expected.push(entries[0].clone());
+        assert_eq!(rev(Bound::Unbounded), expected);
+        assert_eq!(rev(Bound::Included(b"Server")), expected[1..].to_vec());
+        assert_eq!(rev(Bound::Excluded(b"Server")), expected[21..].to_vec());
+        assert_eq!(rev(Bound::Excluded(b"API")), Vec::new());
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_get_many_and_bloom_filter() {
+        let mut rng = rand::thread_rng();
+        let test_dir = format!("./{}/", rng.gen::<u32>());
+        create_dir_all(&test_dir).unwrap();
+        let path = Path::new(&test_dir).join("000001.sst");
+
+        let entries: Vec<Entry> = (0..200)
+            .map(|i| entry(&format!("key{:04}", i * 2), Some("nginx"), i))
+            .collect();
+        write_table(&path, &entries, 256);
+
+        let table = SSTable::open(&path).unwrap();
+        assert!(entries.iter().all(|entry| table.may_contain(&entry.key)));
+        let absent = (0..200)
+            .filter(|i| table.may_contain(format!("key{:04}", i * 2 + 1).as_bytes()))
+            .count();
+        assert!(absent < 20, "{} absent keys pass the filter", absent);
+
+        let keys: Vec<String> = (0..400).map(|i| format!("key{:04}", i)).collect();
+        let keys: Vec<&[u8]> = keys.iter().map(|key| key.as_bytes()).collect();
+        let found = table.get_many_at(&keys, u64::MAX).unwrap();
+        for (key, entry) in keys.iter().zip(found) {
+            assert_eq!(entry, table.get(key).unwrap());
+        }
+        let found = table.get_many_at(&[b"key0010", b"key0010", b"key0300"], 4).unwrap();
+        assert_eq!(found, vec![None, None, None]);
+
+        // Without a filter every key has to be looked up.
+        let path = Path::new(&test_dir).join("000002.sst");
+        let mut writer = SSTableWriter::create(&path, Compression::None, 256).unwrap();
+        writer.set_bloom_bits_per_key(0);
+        writer.add(&entry("Server", Some("nginx"), 1)).unwrap();
+        writer.finish().unwrap();
+        let table = SSTable::open(&path).unwrap();
+        assert!(table.filter.is_none());
+        assert!(table.may_contain(b"Database"));
+        let found = table.get_many_at(&[b"Database", b"Server"], 1).unwrap();
+        assert_eq!(found, vec![None, table.get(b"Server").unwrap()]);
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_range_tombstones_round_trip() {
+        let mut rng = rand::thread_rng();
+        let test_dir = format!("./{}/", rng.gen::<u32>());
+        create_dir_all(&test_dir).unwrap();
+        let path = Path::new(&test_dir).join("000001.sst");
+
+        let tombstone = RangeTombstone {
+            start: b"B".to_vec(),
+            end: b"D".to_vec(),
+            timestamp: 7,
+            sequence: 5,
+        };
+        let mut writer = SSTableWriter::create(&path, Compression::None, 4096).unwrap();
+        writer.add_range_tombstone(&tombstone);
+        writer.add(&entry("C", Some("nginx"), 6)).unwrap();
+        writer.finish().unwrap();
+
+        let table = SSTable::open(&path).unwrap();
+        assert_eq!(table.range_tombstones(), std::slice::from_ref(&tombstone));
+        assert_eq!(table.get(b"C").unwrap().unwrap().sequence, 6);
+        let order = KeyOrder::default();
+        assert!(tombstone.covers(b"B", 4, &order) && tombstone.covers(b"Cache", 4, &order));
+        assert!(!tombstone.covers(b"C", 6, &order));
+        assert!(!tombstone.covers(b"A", 4, &order) && !tombstone.covers(b"D", 4, &order));
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+}
@@ -0,0 +1,637 @@
+use crate::storage::Storage;
+use std::fmt::Write;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Name of the file holding the cumulative statistics of a database directory.
+pub const STATS_FILE: &str = "STATS";
+/// First line of every statistics file, identifying the format version.
+const STATS_HEADER: &str = "FLUXDB-STATS 1";
+
+/// Number of buckets in a histogram: one for zero and one per power of two of a `u64`.
+const HISTOGRAM_BUCKETS: usize = 65;
+
+/// Cumulative counters kept across restarts, along with histograms of the current run.
+///
+/// The counters start from the values saved in the directory's statistics file and are saved
+/// back periodically by the background thread and when the database is closed, so a crash
+/// loses at most one save interval of counts. Histograms start empty on every open.
+#[derive(Default)]
+pub struct Statistics {
+    bytes_written: AtomicU64,
+    flushes: AtomicU64,
+    compactions: AtomicU64,
+    stall_nanos: AtomicU64,
+    puts: AtomicU64,
+    deletes: AtomicU64,
+    gets: AtomicU64,
+    wal_bytes_written: AtomicU64,
+    wal_syncs: AtomicU64,
+    tombstones_coalesced: AtomicU64,
+    tombstones_purged: AtomicU64,
+    compaction_bytes_read: AtomicU64,
+    compaction_bytes_written: AtomicU64,
+    bloom_checks: AtomicU64,
+    bloom_negatives: AtomicU64,
+    bloom_false_positives: AtomicU64,
+    read_amplification: Histogram,
+    get_latency: Histogram,
+    set_latency: Histogram,
+    delete_latency: Histogram,
+    write_latency: Histogram,
+    recovery: Mutex<RecoveryStats>,
+}
+
+/// Point-in-time copy of the cumulative counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StatisticsSnapshot {
+    /// Key and value bytes written by `set`, `delete` and batches.
+    pub bytes_written: u64,
+    /// Number of memtables flushed to segments.
+    pub flushes: u64,
+    /// Number of compactions run.
+    pub compactions: u64,
+    /// Time writers spent waiting on engine maintenance, such as switching to a new memtable.
+    pub stall_time: Duration,
+    /// Number of keys inserted or updated, batches included.
+    pub puts: u64,
+    /// Number of removals, soft and range deletions included.
+    pub deletes: u64,
+    /// Number of keys looked up by `get`, `get_ref` and `multi_get`, on snapshots as well.
+    pub gets: u64,
+    /// Bytes appended to the WAL by writes.
+    pub wal_bytes_written: u64,
+    /// Number of times the WAL was synced for writes under `sync_writes`, each covering
+    /// every writer waiting on it.
+    pub wal_syncs: u64,
+    /// Number of point tombstones flushes replaced with range tombstones, see
+    /// `DiskOptions::coalesce_tombstones`.
+    pub tombstones_coalesced: u64,
+    /// Number of point and range tombstones compactions dropped once nothing older was
+    /// left for them to hide, see `DiskOptions::tombstone_retention`.
+    pub tombstones_purged: u64,
+    /// Size of the segments read by compactions.
+    pub compaction_bytes_read: u64,
+    /// Size of the segments written by compactions.
+    pub compaction_bytes_written: u64,
+    /// Number of times a lookup consulted the bloom filter of a segment.
+    pub bloom_checks: u64,
+    /// Number of those checks that ruled the segment out, sparing a block read.
+    pub bloom_negatives: u64,
+    /// Number of those checks that let a lookup read a segment that didn't hold the key.
+    pub bloom_false_positives: u64,
+    /// Number of segments a lookup read blocks from, since the database was opened.
+    pub read_amplification: HistogramSnapshot,
+    /// Time `get` took, in microseconds, since the database was opened.
+    pub get_latency_micros: HistogramSnapshot,
+    /// What opening the database took to recover its state.
+    pub recovery: RecoveryStats,
+}
+
+/// Operation of a caller whose latency is tracked, see `Disk::latency_histograms`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Operation {
+    /// Looking up a key with `get` or `get_ref`, on snapshots as well.
+    Get,
+    /// Writing a key with `set`.
+    Set,
+    /// Removing a key with `delete`.
+    Delete,
+    /// Committing a batch with `write`.
+    Write,
+}
+
+impl Operation {
+    /// Returns the name of the method, as slow-operation warnings show it.
+    pub fn name(self) -> &'static str {
+        match self {
+            Operation::Get => "get",
+            Operation::Set => "set",
+            Operation::Delete => "delete",
+            Operation::Write => "write",
+        }
+    }
+}
+
+/// Time the operations of callers took, in microseconds, since the database was opened.
+/// Each histogram includes the time a write was held back by `DiskOptions::write_stall`
+/// and spent waiting for the WAL to be synced.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LatencyHistograms {
+    /// Time lookups took, see `Operation::Get`.
+    pub get: HistogramSnapshot,
+    /// Time `set` took.
+    pub set: HistogramSnapshot,
+    /// Time `delete` took.
+    pub delete: HistogramSnapshot,
+    /// Time committing a batch with `write` took.
+    pub write: HistogramSnapshot,
+}
+
+/// Work done by the most recent open of the database, which is mostly replaying the WAL.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RecoveryStats {
+    /// Time `Disk::open` took, from reading the manifest to starting the background thread.
+    pub duration: Duration,
+    /// Number of segments opened.
+    pub segments_opened: u64,
+    /// Total size of the segments opened, of which only the index, bloom filter and range
+    /// tombstones are read.
+    pub segment_bytes: u64,
+    /// Number of WAL files replayed.
+    pub wal_files_replayed: u64,
+    /// Number of records replayed from them, counting each record of a batch.
+    pub records_replayed: u64,
+    /// Bytes of intact records replayed from them.
+    pub wal_bytes_replayed: u64,
+    /// Number of WAL files whose end was ignored because it held a torn or corrupted record.
+    pub torn_tails: u64,
+    /// Number of records skipped because an earlier file already held a record with their
+    /// sequence number, as left by a recovery interrupted between copying records to a fresh
+    /// WAL file and removing the files it copied.
+    pub duplicate_records: u64,
+}
+
+/// What a scan of a key range reads through, as counted by `Disk::tombstone_density`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TombstoneDensity {
+    /// Versions of keys in the range held by the memtables and segments.
+    pub versions: u64,
+    /// Those versions that are point tombstones, soft ones included.
+    pub tombstones: u64,
+    /// Those versions that range tombstones hide.
+    pub covered: u64,
+    /// Keys a scan of the range returns.
+    pub live_keys: u64,
+}
+
+/// Sizes and prefixes of a sample of the live keys, as drawn by `Disk::key_distribution`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyDistribution {
+    /// Live keys sampled.
+    pub sampled_keys: u64,
+    /// Live keys the database holds, extrapolated from the sample.
+    pub estimated_keys: u64,
+    /// Sizes of the sampled keys, in bytes.
+    pub key_sizes: HistogramSnapshot,
+    /// Sizes of their values, in bytes, values kept in the value log included.
+    pub value_sizes: HistogramSnapshot,
+    /// Most common prefixes among the sampled keys, with the number of keys having each,
+    /// most common first.
+    pub top_prefixes: Vec<(Vec<u8>, u64)>,
+}
+
+/// Counters of the work done and shape of the segment tree, as returned by `Disk::stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DiskStats {
+    /// Counters and histograms, as `Disk::statistics` returns them.
+    pub counters: StatisticsSnapshot,
+    /// Levels of the tree and the work left to compact them, as `Disk::tree_stats` returns
+    /// them.
+    pub tree: TreeStats,
+}
+
+/// Shape of the segment tree as it stands, as returned by `Disk::tree_stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TreeStats {
+    /// Levels of the tree, from level 0. Segments all sit in level 0, which compactions
+    /// merge whole, so it is the only one.
+    pub levels: Vec<LevelStats>,
+    /// Bytes a compaction would have to read to merge the segments back into one: their
+    /// total size once there are `DiskOptions::compaction_trigger` of them, 0 below it. A
+    /// debt staying above 0 tells compactions are falling behind writes.
+    pub compaction_debt: u64,
+    /// Bytes of the versions holding a value in the memtables, active and frozen.
+    pub memtable_live_bytes: u64,
+    /// Bytes of the tombstones in the memtables, active and frozen, soft and range ones
+    /// included. See `DiskOptions::memtable_max_tombstone_bytes`.
+    pub memtable_tombstone_bytes: u64,
+}
+
+/// Files of one level of the segment tree, see `TreeStats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LevelStats {
+    /// Level the files sit in, 0 for the only one.
+    pub level: usize,
+    /// Segment files in the level. Lookups may read each of them, so read latency grows
+    /// with their number.
+    pub files: usize,
+    /// Size of those files in bytes.
+    pub bytes: u64,
+    /// Entries of those files, every version and tombstone included.
+    pub entries: u64,
+}
+
+/// Layout of a segment file, as listed by `Disk::live_files`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LiveFileInfo {
+    pub path: PathBuf,
+    /// Level the file sits in. Segments all sit in one level, which compactions merge whole,
+    /// so it is 0 for every file.
+    pub level: usize,
+    /// Smallest key the file holds, `None` if it only holds range tombstones.
+    pub smallest_key: Option<Vec<u8>>,
+    /// Largest key the file holds, `None` if it only holds range tombstones.
+    pub largest_key: Option<Vec<u8>>,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// Entries of the file, every version and tombstone included.
+    pub entry_count: u64,
+    /// When the file was written, from the file system. Segments are never modified once
+    /// written. `None` on storage backends not keeping files on the local file system.
+    pub created: Option<SystemTime>,
+}
+
+impl TombstoneDensity {
+    /// Returns the share of versions in the range that are deleted, or 0 for an empty range.
+    /// Scans of a range close to 1 step over many deletes for each key they return; a
+    /// compaction clears them.
+    pub fn density(&self) -> f64 {
+        ratio(self.tombstones + self.covered, self.versions)
+    }
+}
+
+impl StatisticsSnapshot {
+    /// Returns the share of bloom filter checks that spared a block read, or 0 before any
+    /// check.
+    pub fn bloom_hit_rate(&self) -> f64 {
+        ratio(self.bloom_negatives, self.bloom_checks)
+    }
+}
+
+/// Distribution of recorded values, in buckets bounded by powers of two.
+pub struct Histogram {
+    buckets: [AtomicU64; HISTOGRAM_BUCKETS],
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&self, value: u64) {
+        let bucket = (u64::BITS - value.leading_zeros()) as usize;
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        // Saturates rather than wrapping, so one huge value can't make the mean tiny.
+        let add = |sum: u64| Some(sum.saturating_add(value));
+        let _ = self.sum.fetch_update(Ordering::Relaxed, Ordering::Relaxed, add);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            buckets: std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed)),
+            count: self.count.load(Ordering::Relaxed),
+            sum: self.sum.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Point-in-time copy of a histogram. Bucket 0 counts zeros and bucket `i` the values from
+/// `2^(i-1)` to `2^i - 1`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    pub buckets: [u64; HISTOGRAM_BUCKETS],
+    pub count: u64,
+    pub sum: u64,
+}
+
+impl Default for HistogramSnapshot {
+    fn default() -> HistogramSnapshot {
+        HistogramSnapshot {
+            buckets: [0; HISTOGRAM_BUCKETS],
+            count: 0,
+            sum: 0,
+        }
+    }
+}
+
+impl HistogramSnapshot {
+    /// Returns the mean of the recorded values, or 0 if none were recorded.
+    pub fn mean(&self) -> f64 {
+        ratio(self.sum, self.count)
+    }
+
+    /// Returns an upper bound of the given percentile (0 to 100) of the recorded values: the
+    /// largest value of the bucket holding it. Returns 0 if no value was recorded.
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        let rank = (self.count as f64 * percentile / 100.0).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return match bucket {
+                    0 => 0,
+                    64 => u64::MAX,
+                    _ => (1 << bucket) - 1,
+                };
+            }
+        }
+        0
+    }
+}
+
+fn ratio(numerator: u64, denominator: u64) -> f64 {
+    match denominator {
+        0 => 0.0,
+        _ => numerator as f64 / denominator as f64,
+    }
+}
+
+impl Statistics {
+    /// Loads the counters saved in `dir`, starting from zero if none were saved yet.
+    pub fn load(dir: &Path) -> io::Result<Statistics> {
+        Statistics::load_with(&Storage::default(), dir)
+    }
+
+    /// Loads the counters saved in a directory kept in `storage`.
+    pub fn load_with(storage: &Storage, dir: &Path) -> io::Result<Statistics> {
+        let contents = match storage.read(&dir.join(STATS_FILE)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Statistics::default()),
+            Err(e) => return Err(e),
+        };
+        let contents = String::from_utf8(contents)
+            .map_err(|_| invalid_stats("statistics file is not valid UTF-8"))?;
+
+        let mut lines = contents.lines();
+        match lines.next() {
+            Some(header) if header == STATS_HEADER => {}
+            _ => return Err(invalid_stats("missing statistics header")),
+        }
+
+        let stats = Statistics::default();
+        for line in lines {
+            let (name, value) = line
+                .split_once(' ')
+                .ok_or_else(|| invalid_stats(&format!("unexpected line {:?}", line)))?;
+            let value: u64 = value
+                .parse()
+                .map_err(|_| invalid_stats(&format!("bad value for {}", name)))?;
+            // Counters this version doesn't know about are dropped rather than rejected.
+            if let Some(counter) = stats.counter(name) {
+                counter.store(value, Ordering::Relaxed);
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Atomically replaces the statistics file of `dir` with the current counters.
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        self.save_with(&Storage::default(), dir)
+    }
+
+    /// Atomically replaces the statistics file of a directory kept in `storage`.
+    pub fn save_with(&self, storage: &Storage, dir: &Path) -> io::Result<()> {
+        let mut contents = String::new();
+        // Writing to a String can't fail.
+        let _ = writeln!(contents, "{}", STATS_HEADER);
+        for name in COUNTERS {
+            let value = self.counter(name).unwrap().load(Ordering::Relaxed);
+            let _ = writeln!(contents, "{} {}", name, value);
+        }
+
+        storage.replace(dir, STATS_FILE, contents.as_bytes())
+    }
+
+    /// Returns the current counters.
+    pub fn snapshot(&self) -> StatisticsSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        StatisticsSnapshot {
+            bytes_written: load(&self.bytes_written),
+            flushes: load(&self.flushes),
+            compactions: load(&self.compactions),
+            stall_time: Duration::from_nanos(load(&self.stall_nanos)),
+            puts: load(&self.puts),
+            deletes: load(&self.deletes),
+            gets: load(&self.gets),
+            wal_bytes_written: load(&self.wal_bytes_written),
+            wal_syncs: load(&self.wal_syncs),
+            tombstones_coalesced: load(&self.tombstones_coalesced),
+            tombstones_purged: load(&self.tombstones_purged),
+            compaction_bytes_read: load(&self.compaction_bytes_read),
+            compaction_bytes_written: load(&self.compaction_bytes_written),
+            bloom_checks: load(&self.bloom_checks),
+            bloom_negatives: load(&self.bloom_negatives),
+            bloom_false_positives: load(&self.bloom_false_positives),
+            read_amplification: self.read_amplification.snapshot(),
+            get_latency_micros: self.get_latency.snapshot(),
+            recovery: *self.recovery.lock().unwrap(),
+        }
+    }
+
+    /// Returns the latency histograms of the operations of callers.
+    pub fn latency_histograms(&self) -> LatencyHistograms {
+        LatencyHistograms {
+            get: self.get_latency.snapshot(),
+            set: self.set_latency.snapshot(),
+            delete: self.delete_latency.snapshot(),
+            write: self.write_latency.snapshot(),
+        }
+    }
+
+    pub(crate) fn record_recovery(&self, recovery: RecoveryStats) {
+        *self.recovery.lock().unwrap() = recovery;
+    }
+
+    /// Counts a write of `bytes` key and value bytes made of `puts` insertions and `deletes`
+    /// removals.
+    pub(crate) fn record_write(&self, bytes: usize, puts: u64, deletes: u64) {
+        self.bytes_written.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.puts.fetch_add(puts, Ordering::Relaxed);
+        self.deletes.fetch_add(deletes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_wal_bytes(&self, bytes: u64) {
+        self.wal_bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_wal_sync(&self) {
+        self.wal_syncs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_tombstones_coalesced(&self, tombstones: u64) {
+        self.tombstones_coalesced.fetch_add(tombstones, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_tombstones_purged(&self, tombstones: u64) {
+        self.tombstones_purged.fetch_add(tombstones, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_gets(&self, keys: u64) {
+        self.gets.fetch_add(keys, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_latency(&self, operation: Operation, elapsed: Duration) {
+        let histogram = match operation {
+            Operation::Get => &self.get_latency,
+            Operation::Set => &self.set_latency,
+            Operation::Delete => &self.delete_latency,
+            Operation::Write => &self.write_latency,
+        };
+        histogram.record(u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX));
+    }
+
+    /// Counts the bloom filter checks of one lookup and the segments it read blocks from.
+    pub(crate) fn record_lookup(
+        &self,
+        checks: u64,
+        negatives: u64,
+        false_positives: u64,
+        reads: u64,
+    ) {
+        self.bloom_checks.fetch_add(checks, Ordering::Relaxed);
+        self.bloom_negatives.fetch_add(negatives, Ordering::Relaxed);
+        self.bloom_false_positives
+            .fetch_add(false_positives, Ordering::Relaxed);
+        self.read_amplification.record(reads);
+    }
+
+    pub(crate) fn record_flush(&self) {
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_compaction(&self, bytes_read: u64, bytes_written: u64) {
+        self.compactions.fetch_add(1, Ordering::Relaxed);
+        self.compaction_bytes_read
+            .fetch_add(bytes_read, Ordering::Relaxed);
+        self.compaction_bytes_written
+            .fetch_add(bytes_written, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_stall(&self, stalled: Duration) {
+        self.stall_nanos
+            .fetch_add(stalled.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn counter(&self, name: &str) -> Option<&AtomicU64> {
+        match name {
+            "bytes_written" => Some(&self.bytes_written),
+            "flushes" => Some(&self.flushes),
+            "compactions" => Some(&self.compactions),
+            "stall_nanos" => Some(&self.stall_nanos),
+            "puts" => Some(&self.puts),
+            "deletes" => Some(&self.deletes),
+            "gets" => Some(&self.gets),
+            "wal_bytes_written" => Some(&self.wal_bytes_written),
+            "wal_syncs" => Some(&self.wal_syncs),
+            "tombstones_coalesced" => Some(&self.tombstones_coalesced),
+            "tombstones_purged" => Some(&self.tombstones_purged),
+            "compaction_bytes_read" => Some(&self.compaction_bytes_read),
+            "compaction_bytes_written" => Some(&self.compaction_bytes_written),
+            "bloom_checks" => Some(&self.bloom_checks),
+            "bloom_negatives" => Some(&self.bloom_negatives),
+            "bloom_false_positives" => Some(&self.bloom_false_positives),
+            _ => None,
+        }
+    }
+}
+
+/// Names under which the counters are saved.
+const COUNTERS: [&str; 16] = [
+    "bytes_written",
+    "flushes",
+    "compactions",
+    "stall_nanos",
+    "puts",
+    "deletes",
+    "gets",
+    "wal_bytes_written",
+    "wal_syncs",
+    "tombstones_coalesced",
+    "tombstones_purged",
+    "compaction_bytes_read",
+    "compaction_bytes_written",
+    "bloom_checks",
+    "bloom_negatives",
+    "bloom_false_positives",
+];
+
+fn invalid_stats(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupt statistics file: {}", reason),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_save_and_load() {
+        let mut rng = rand::thread_rng();
+        let test_dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir_all(&test_dir).unwrap();
+
+        assert_eq!(Statistics::load(&test_dir).unwrap().snapshot(), StatisticsSnapshot::default());
+
+        let stats = Statistics::default();
+        stats.record_write(100, 2, 1);
+        stats.record_flush();
+        stats.record_compaction(400, 300);
+        stats.record_compaction(300, 200);
+        stats.record_lookup(3, 2, 1, 1);
+        stats.record_stall(Duration::from_millis(3));
+        stats.save(&test_dir).unwrap();
+        let loaded = Statistics::load(&test_dir).unwrap().snapshot();
+        // Histograms only cover the current run.
+        assert_eq!(loaded.read_amplification, HistogramSnapshot::default());
+        assert_eq!(
+            loaded,
+            StatisticsSnapshot {
+                read_amplification: HistogramSnapshot::default(),
+                ..stats.snapshot()
+            }
+        );
+        assert_eq!(loaded.compaction_bytes_read, 700);
+        assert!((loaded.bloom_hit_rate() - 2.0 / 3.0).abs() < 1e-9);
+
+        write(
+            test_dir.join(STATS_FILE),
+            format!("{}\nflushes 7\nfuture_counter 1\n", STATS_HEADER),
+        )
+        .unwrap();
+        assert_eq!(Statistics::load(&test_dir).unwrap().snapshot().flushes, 7);
+
+        write(test_dir.join(STATS_FILE), b"not statistics\n").unwrap();
+        assert!(Statistics::load(&test_dir).is_err());
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::default();
+        assert_eq!(histogram.snapshot().percentile(50.0), 0);
+        for value in [0, 1, 2, 3, 5, 100] {
+            histogram.record(value);
+        }
+        histogram.record(u64::MAX);
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 7);
+        assert_eq!(snapshot.buckets[..4], [1, 1, 2, 1]);
+        assert_eq!(snapshot.percentile(50.0), 3);
+        assert_eq!(snapshot.percentile(80.0), 127);
+        assert_eq!(snapshot.percentile(100.0), u64::MAX);
+        assert_eq!(snapshot.sum, u64::MAX);
+        histogram.record(1);
+        assert_eq!(histogram.snapshot().sum, u64::MAX);
+    }
+}
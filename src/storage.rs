@@ -0,0 +1,499 @@
+//! File access used by the engine, behind a trait so the files of a database can live
+//! somewhere other than the local file system.
+//!
+//! WAL files, segments, the manifest and the statistics file are all opened, written and
+//! removed through the `Storage` configured in `DiskOptions::storage`. `FsBackend`, the
+//! default, maps each call onto `std::fs`; `MemoryBackend` keeps every file in memory, for
+//! tests that shouldn't touch the disk. Backups and checkpoints copy files with hard links
+//! or copy-on-write clones, so they still read the database directory directly and are only
+//! available on backends that keep files there.
+//!
+//! An open database holds the lock of its directory, taken with `StorageBackend::lock` on
+//! its `LOCK` file, so a second process opening it fails instead of corrupting it.
+
+use crate::platform;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Name of the file an open database holds the lock of.
+pub const LOCK_FILE: &str = "LOCK";
+
+/// Where the files of a database are kept.
+///
+/// Files are only ever appended to, read at an offset, synced, renamed or deleted as a
+/// whole, so a backend doesn't need to support writes in place.
+pub trait StorageBackend: Send + Sync {
+    /// Creates an empty file for appending, failing with `AlreadyExists` if there is one.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>>;
+    /// Opens a file for appending, creating it if it doesn't exist.
+    fn append(&self, path: &Path) -> io::Result<Box<dyn StorageFile>>;
+    /// Opens an existing file for reading, failing with `NotFound` if there is none.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>>;
+    /// Removes a file, failing with `NotFound` if there is none.
+    fn delete(&self, path: &Path) -> io::Result<()>;
+    /// Atomically replaces `to`, if it exists, with `from`.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    /// Returns the paths of the files in a directory, in no particular order.
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+    /// Returns whether a file exists.
+    fn exists(&self, path: &Path) -> bool;
+    /// Makes the creations, renames and deletions of files in a directory durable.
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+    /// Takes an exclusive lock on the file at `path`, creating it if needed, held until the
+    /// returned guard is dropped. Fails with `WouldBlock` while another guard holds it. The
+    /// default takes no lock, leaving backends shared between processes unprotected.
+    fn lock(&self, _path: &Path) -> io::Result<StorageLock> {
+        Ok(StorageLock::unlocked())
+    }
+    /// Returns whether files are kept at their paths on the local file system, where
+    /// backups and checkpoints can hard-link or copy them.
+    fn is_local(&self) -> bool {
+        false
+    }
+}
+
+/// Lock taken with `StorageBackend::lock`, released when dropped.
+pub struct StorageLock(Option<Box<dyn Send + Sync>>);
+
+impl StorageLock {
+    /// Creates a lock held until `guard` is dropped.
+    pub fn new<G: Send + Sync + 'static>(guard: G) -> StorageLock {
+        StorageLock(Some(Box::new(guard)))
+    }
+
+    /// Creates a lock holding nothing, for backends without locks.
+    pub fn unlocked() -> StorageLock {
+        StorageLock(None)
+    }
+}
+
+impl fmt::Debug for StorageLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("StorageLock").field(&self.0.is_some()).finish()
+    }
+}
+
+/// A file opened through a `StorageBackend`.
+pub trait StorageFile: Send + Sync {
+    /// Writes `data` at the end of the file.
+    fn append(&mut self, data: &[u8]) -> io::Result<()>;
+    /// Reads into `buf` from `offset`, returning the number of bytes read, 0 at the end of
+    /// the file. Doesn't move any shared cursor, so concurrent readers need no lock.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+    /// Returns the current size of the file.
+    fn size(&self) -> io::Result<u64>;
+    /// Makes the data appended so far durable.
+    fn sync(&self) -> io::Result<()>;
+}
+
+/// Handle to the backend a database keeps its files in, cheap to clone. Defaults to the
+/// local file system.
+#[derive(Clone)]
+pub struct Storage {
+    backend: Arc<dyn StorageBackend>,
+}
+
+impl Storage {
+    /// Creates a handle to `backend`.
+    pub fn new<B: StorageBackend + 'static>(backend: B) -> Storage {
+        Storage {
+            backend: Arc::new(backend),
+        }
+    }
+
+    /// Reads a whole file.
+    pub fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        FileReader::new(self.open(path)?).read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    /// Returns the paths of the files in a directory with the given extension.
+    pub fn list_with_extension(&self, dir: &Path, extension: &str) -> io::Result<Vec<PathBuf>> {
+        let mut paths = self.list(dir)?;
+        paths.retain(|path| path.extension().is_some_and(|ext| ext == extension));
+        Ok(paths)
+    }
+
+    /// Atomically replaces the file `name` of `dir` with `contents`: they are written to a
+    /// temporary file, synced, then renamed over the old file.
+    pub fn replace(&self, dir: &Path, name: &str, contents: &[u8]) -> io::Result<()> {
+        let temp_path = dir.join(format!("{}.tmp", name));
+        if self.exists(&temp_path) {
+            self.delete(&temp_path)?;
+        }
+        let mut file = self.create(&temp_path)?;
+        file.append(contents)?;
+        file.sync()?;
+        self.rename(&temp_path, &dir.join(name))?;
+        self.sync_dir(dir)
+    }
+}
+
+impl Default for Storage {
+    fn default() -> Storage {
+        Storage::new(FsBackend)
+    }
+}
+
+impl Deref for Storage {
+    type Target = dyn StorageBackend;
+
+    fn deref(&self) -> &(dyn StorageBackend + 'static) {
+        self.backend.as_ref()
+    }
+}
+
+impl fmt::Debug for Storage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Storage").finish_non_exhaustive()
+    }
+}
+
+/// Adapts a `StorageFile` to `Write`, appending every write. Meant to be wrapped in a
+/// `BufWriter`.
+pub struct FileWriter {
+    file: Box<dyn StorageFile>,
+}
+
+impl FileWriter {
+    pub fn new(file: Box<dyn StorageFile>) -> FileWriter {
+        FileWriter { file }
+    }
+
+    pub fn get_ref(&self) -> &dyn StorageFile {
+        self.file.as_ref()
+    }
+}
+
+impl Write for FileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.append(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Adapts a `StorageFile` to `Read` and `Seek`, reading from its own position. Meant to be
+/// wrapped in a `BufReader`.
+pub struct FileReader {
+    file: Box<dyn StorageFile>,
+    position: u64,
+}
+
+impl FileReader {
+    pub fn new(file: Box<dyn StorageFile>) -> FileReader {
+        FileReader { file, position: 0 }
+    }
+
+    pub fn get_ref(&self) -> &dyn StorageFile {
+        self.file.as_ref()
+    }
+}
+
+impl Read for FileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read_at(buf, self.position)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for FileReader {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.file.size()?.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the file")
+        })?;
+        Ok(self.position)
+    }
+}
+
+/// Fills `buf` from `offset`, failing with `UnexpectedEof` if the file ends first.
+pub fn read_exact_at(
+    file: &dyn StorageFile,
+    mut buf: &mut [u8],
+    mut offset: u64,
+) -> io::Result<()> {
+    while !buf.is_empty() {
+        match file.read_at(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => {
+                buf = &mut buf[read..];
+                offset += read as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Keeps files on the local file system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FsBackend;
+
+struct FsFile(File);
+
+impl StorageBackend for FsBackend {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let file = OpenOptions::new().create_new(true).append(true).open(path)?;
+        Ok(Box::new(FsFile(file)))
+    }
+
+    fn append(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Box::new(FsFile(file)))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(FsFile(File::open(path)?)))
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        platform::rename(from, to)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(dir)?
+            .map(|entry| Ok(entry?.path()))
+            .collect()
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        platform::sync_dir(dir)
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<StorageLock> {
+        let file = OpenOptions::new().create(true).truncate(false).write(true).open(path)?;
+        platform::try_lock(&file, path)?;
+        // Closing the file releases the lock.
+        Ok(StorageLock::new(file))
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+}
+
+impl StorageFile for FsFile {
+    fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.write_all(data)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        platform::read_at(&self.0, buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.0.metadata()?.len())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.0.sync_all()
+    }
+}
+
+/// Keeps files in memory, for tests. Directories exist implicitly: a directory lists the
+/// files whose paths are directly under it. Nothing survives the backend being dropped,
+/// but clones of a `Storage` holding it share the same files, so a database can be closed
+/// and reopened on them.
+#[derive(Default)]
+pub struct MemoryBackend {
+    files: Mutex<HashMap<PathBuf, Arc<RwLock<Vec<u8>>>>>,
+    /// Paths locked with `lock`, shared with the guards that unlock them.
+    locks: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+/// Unlocks a path of a `MemoryBackend` when dropped.
+struct MemoryLock {
+    locks: Arc<Mutex<HashSet<PathBuf>>>,
+    path: PathBuf,
+}
+
+impl Drop for MemoryLock {
+    fn drop(&mut self) {
+        self.locks.lock().unwrap().remove(&self.path);
+    }
+}
+
+struct MemoryFile(Arc<RwLock<Vec<u8>>>);
+
+impl MemoryBackend {
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::default()
+    }
+
+    fn file(&self, path: &Path) -> io::Result<Arc<RwLock<Vec<u8>>>> {
+        let files = self.files.lock().unwrap();
+        files.get(path).cloned().ok_or_else(|| not_found(path))
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let mut files = self.files.lock().unwrap();
+        if files.contains_key(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", path.display()),
+            ));
+        }
+        let file = Arc::new(RwLock::new(Vec::new()));
+        files.insert(path.to_owned(), file.clone());
+        Ok(Box::new(MemoryFile(file)))
+    }
+
+    fn append(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let mut files = self.files.lock().unwrap();
+        let file = files.entry(path.to_owned()).or_default();
+        Ok(Box::new(MemoryFile(file.clone())))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(MemoryFile(self.file(path)?)))
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        files.remove(path).map(drop).ok_or_else(|| not_found(path))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let file = files.remove(from).ok_or_else(|| not_found(from))?;
+        files.insert(to.to_owned(), file);
+        Ok(())
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let files = self.files.lock().unwrap();
+        Ok(files
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .cloned()
+            .collect())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.files.lock().unwrap().contains_key(path)
+    }
+
+    fn sync_dir(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<StorageLock> {
+        self.append(path)?;
+        if !self.locks.lock().unwrap().insert(path.to_owned()) {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("{} is locked: the database is already open", path.display()),
+            ));
+        }
+        Ok(StorageLock::new(MemoryLock {
+            locks: self.locks.clone(),
+            path: path.to_owned(),
+        }))
+    }
+}
+
+impl StorageFile for MemoryFile {
+    fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.write().unwrap().extend_from_slice(data);
+        Ok(())
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let contents = self.0.read().unwrap();
+        let start = usize::try_from(offset).unwrap_or(usize::MAX).min(contents.len());
+        let read = buf.len().min(contents.len() - start);
+        buf[..read].copy_from_slice(&contents[start..start + read]);
+        Ok(read)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.0.read().unwrap().len() as u64)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use std::fs::{create_dir_all, remove_dir_all};
+
+    fn exercise(storage: &Storage, dir: &Path) {
+        let path = dir.join("000001.sst");
+        let mut file = storage.create(&path).unwrap();
+        assert_eq!(storage.create(&path).err().unwrap().kind(), io::ErrorKind::AlreadyExists);
+        file.append(b"Server nginx").unwrap();
+        file.sync().unwrap();
+
+        let reader = storage.open(&path).unwrap();
+        let mut buf = [0; 5];
+        read_exact_at(reader.as_ref(), &mut buf, 7).unwrap();
+        assert_eq!(&buf, b"nginx");
+        assert!(read_exact_at(reader.as_ref(), &mut buf, 8).is_err());
+        storage.append(&path).unwrap().append(b"!").unwrap();
+        assert_eq!(reader.size().unwrap(), 13);
+
+        storage.replace(dir, "MANIFEST", b"first").unwrap();
+        storage.replace(dir, "MANIFEST", b"second").unwrap();
+        assert_eq!(storage.read(&dir.join("MANIFEST")).unwrap(), b"second");
+        let mut listed = storage.list(dir).unwrap();
+        listed.sort();
+        assert_eq!(listed, vec![path.clone(), dir.join("MANIFEST")]);
+
+        storage.delete(&path).unwrap();
+        assert!(!storage.exists(&path));
+        assert_eq!(storage.open(&path).err().unwrap().kind(), io::ErrorKind::NotFound);
+
+        // A lock is exclusive until its guard is dropped.
+        let lock_path = dir.join(LOCK_FILE);
+        let lock = storage.lock(&lock_path).unwrap();
+        let err = storage.lock(&lock_path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        drop(lock);
+        drop(storage.lock(&lock_path).unwrap());
+    }
+
+    #[test]
+    fn test_backends() {
+        let mut rng = rand::thread_rng();
+        let test_dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir_all(&test_dir).unwrap();
+        exercise(&Storage::default(), &test_dir);
+        remove_dir_all(&test_dir).unwrap();
+
+        exercise(&Storage::new(MemoryBackend::new()), Path::new("./db/"));
+    }
+}
@@ -0,0 +1,180 @@
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::Mutex;
+
+/// A committed write, as received from `Disk::subscribe`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangeEvent {
+    /// Key written, or the start of the range removed by a range delete.
+    pub key: Vec<u8>,
+    pub op: ChangeOp,
+    /// Timestamp the write is stored with.
+    pub timestamp: u128,
+    /// Sequence number of the write. Events arrive in sequence order; the operations of a
+    /// batch are numbered consecutively.
+    pub sequence: u64,
+}
+
+/// What a committed write did to its key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChangeOp {
+    /// The key was set to this value.
+    Put(Vec<u8>),
+    /// The key was deleted, soft deletes included.
+    Delete,
+    /// Every key from the event's key up to, but not including, `end` was deleted.
+    DeleteRange { end: Vec<u8> },
+}
+
+/// A write as reported to the invalidation feeds and subscribers.
+#[derive(Clone, Copy)]
+pub(crate) struct Change<'a> {
+    pub(crate) key: &'a [u8],
+    pub(crate) kind: ChangeKind<'a>,
+    pub(crate) timestamp: u128,
+    pub(crate) sequence: u64,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum ChangeKind<'a> {
+    Put(&'a [u8]),
+    Delete,
+    /// Removal of every key from the change's key up to this end.
+    Range(&'a [u8]),
+}
+
+impl Change<'_> {
+    fn to_event(self) -> ChangeEvent {
+        ChangeEvent {
+            key: self.key.to_vec(),
+            op: match self.kind {
+                ChangeKind::Put(value) => ChangeOp::Put(value.to_vec()),
+                ChangeKind::Delete => ChangeOp::Delete,
+                ChangeKind::Range(end) => ChangeOp::DeleteRange { end: end.to_vec() },
+            },
+            timestamp: self.timestamp,
+            sequence: self.sequence,
+        }
+    }
+}
+
+/// Channel end an event is sent into.
+enum Subscriber {
+    Std(Sender<ChangeEvent>),
+    /// Dropped once its channel is full.
+    Bounded(SyncSender<ChangeEvent>),
+    #[cfg(feature = "async")]
+    Tokio(tokio::sync::mpsc::UnboundedSender<ChangeEvent>),
+    /// Dropped once its channel is full.
+    #[cfg(feature = "async")]
+    TokioBounded(tokio::sync::mpsc::Sender<ChangeEvent>),
+}
+
+impl Subscriber {
+    /// Sends an event, returning false once the receiver is gone.
+    fn send(&self, event: ChangeEvent) -> bool {
+        match self {
+            Subscriber::Std(sender) => sender.send(event).is_ok(),
+            Subscriber::Bounded(sender) => sender.try_send(event).is_ok(),
+            #[cfg(feature = "async")]
+            Subscriber::Tokio(sender) => sender.send(event).is_ok(),
+            #[cfg(feature = "async")]
+            Subscriber::TokioBounded(sender) => sender.try_send(event).is_ok(),
+        }
+    }
+}
+
+/// The subscribers of a database, sent every committed write.
+#[derive(Default)]
+pub(crate) struct Subscribers {
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+impl Subscribers {
+    pub(crate) fn subscribe(&self) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(Subscriber::Std(sender));
+        receiver
+    }
+
+    /// Subscribes with a channel holding at most `capacity` events. A subscriber that falls
+    /// that far behind is dropped, disconnecting its receiver, rather than holding up writes
+    /// or buffering without bound.
+    pub(crate) fn subscribe_bounded(&self, capacity: usize) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        self.subscribers.lock().unwrap().push(Subscriber::Bounded(sender));
+        receiver
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn subscribe_async(&self) -> tokio::sync::mpsc::UnboundedReceiver<ChangeEvent> {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        self.subscribers.lock().unwrap().push(Subscriber::Tokio(sender));
+        receiver
+    }
+
+    /// Like `subscribe_bounded`, with a tokio channel.
+    #[cfg(feature = "async")]
+    pub(crate) fn subscribe_async_bounded(
+        &self,
+        capacity: usize,
+    ) -> tokio::sync::mpsc::Receiver<ChangeEvent> {
+        let (sender, receiver) = tokio::sync::mpsc::channel(capacity);
+        self.subscribers.lock().unwrap().push(Subscriber::TokioBounded(sender));
+        receiver
+    }
+
+    /// Sends the changes of a write to every subscriber, dropping the subscribers whose
+    /// receiver is gone. Called in sequence order.
+    pub(crate) fn publish<'a>(&self, changes: impl Iterator<Item = Change<'a>> + Clone) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+        subscribers.retain(|subscriber| {
+            changes.clone().all(|change| subscriber.send(change.to_event()))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_publish() {
+        let subscribers = Subscribers::default();
+        let first = subscribers.subscribe();
+        let second = subscribers.subscribe();
+        // A bounded subscriber falling behind is dropped.
+        let bounded = subscribers.subscribe_bounded(1);
+        let change = |key, kind, sequence| Change {
+            key,
+            kind,
+            timestamp: 7,
+            sequence,
+        };
+        let changes = [
+            change(b"key1", ChangeKind::Put(b"nginx"), 1),
+            change(b"key2", ChangeKind::Delete, 2),
+        ];
+        subscribers.publish(changes.into_iter());
+        drop(second);
+        subscribers.publish([change(b"a", ChangeKind::Range(b"c"), 3)].into_iter());
+        assert_eq!(subscribers.subscribers.lock().unwrap().len(), 1);
+        assert_eq!(bounded.try_iter().count(), 1);
+        assert!(bounded.recv().is_err());
+
+        let events: Vec<ChangeEvent> = first.try_iter().collect();
+        let ops: Vec<(&[u8], &ChangeOp, u64)> =
+            events.iter().map(|event| (&event.key[..], &event.op, event.sequence)).collect();
+        assert_eq!(
+            ops,
+            vec![
+                (&b"key1"[..], &ChangeOp::Put(b"nginx".to_vec()), 1),
+                (&b"key2"[..], &ChangeOp::Delete, 2),
+                (&b"a"[..], &ChangeOp::DeleteRange { end: b"c".to_vec() }, 3),
+            ]
+        );
+        assert!(events.iter().all(|event| event.timestamp == 7));
+    }
+}
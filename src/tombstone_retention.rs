@@ -0,0 +1,109 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// How long compactions keep tombstones that no longer hide anything in the database. Set
+/// in `DiskOptions::tombstone_retention`.
+///
+/// A tombstone is only ever dropped by a compaction merging the oldest segment, once no
+/// older version of its key is left below it, so the key can't come back from the segments.
+/// Keeping it longer still leaves the delete visible to whatever copies the data elsewhere,
+/// such as backups restored next to newer writes or replicas catching up, at the cost of
+/// the space it takes until then.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TombstoneRetention {
+    /// Dropped by the first compaction that can.
+    #[default]
+    Bottommost,
+    /// Kept until its timestamp is at least this old.
+    Age(Duration),
+    /// Kept through this many compactions starting after it was written, then dropped by
+    /// the next one that can. Compactions are counted from when the database was opened,
+    /// so reopening it only keeps tombstones longer.
+    Compactions(usize),
+}
+
+/// Newest tombstones a compaction may drop: those written at or before `sequence`, with a
+/// timestamp before `timestamp`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct TombstoneHorizon {
+    pub sequence: u64,
+    pub timestamp: u128,
+}
+
+impl TombstoneHorizon {
+    /// Lets every tombstone be dropped.
+    pub const ALL: TombstoneHorizon = TombstoneHorizon {
+        sequence: u64::MAX,
+        timestamp: u128::MAX,
+    };
+
+    /// Returns whether a tombstone of `sequence` written at `timestamp` may be dropped.
+    pub fn allows(&self, sequence: u64, timestamp: u128) -> bool {
+        sequence <= self.sequence && timestamp < self.timestamp
+    }
+}
+
+impl TombstoneRetention {
+    /// Returns the horizon of a compaction starting at `now`, in microseconds since the
+    /// epoch, after the write of sequence number `last_sequence`. `rounds` holds the last
+    /// sequence numbers at the start of the compactions before it, oldest first, and gets
+    /// this one's.
+    pub(crate) fn horizon(
+        &self,
+        now: u128,
+        last_sequence: u64,
+        rounds: &mut VecDeque<u64>,
+    ) -> TombstoneHorizon {
+        match *self {
+            TombstoneRetention::Bottommost => TombstoneHorizon::ALL,
+            TombstoneRetention::Age(age) => TombstoneHorizon {
+                timestamp: now.saturating_sub(age.as_micros()),
+                ..TombstoneHorizon::ALL
+            },
+            TombstoneRetention::Compactions(count) => {
+                rounds.push_back(last_sequence);
+                while rounds.len() > count + 1 {
+                    rounds.pop_front();
+                }
+                // Tombstones written before the first of the last `count` compactions
+                // started have been kept through all of them.
+                let sequence = match rounds.len() > count {
+                    true => rounds[0],
+                    false => 0,
+                };
+                TombstoneHorizon {
+                    sequence,
+                    ..TombstoneHorizon::ALL
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_horizon() {
+        let mut rounds = VecDeque::new();
+        let bottommost = TombstoneRetention::Bottommost.horizon(1_000, 9, &mut rounds);
+        assert!(bottommost.allows(9, 999));
+
+        let age = TombstoneRetention::Age(Duration::from_micros(100));
+        let horizon = age.horizon(1_000, 9, &mut rounds);
+        assert!(horizon.allows(9, 899));
+        assert!(!horizon.allows(9, 900));
+        assert!(rounds.is_empty());
+
+        let compactions = TombstoneRetention::Compactions(2);
+        assert!(!compactions.horizon(0, 5, &mut rounds).allows(1, 0));
+        assert!(!compactions.horizon(0, 7, &mut rounds).allows(1, 0));
+        // The third compaction drops what was written before the first one.
+        let horizon = compactions.horizon(0, 9, &mut rounds);
+        assert!(horizon.allows(5, 0));
+        assert!(!horizon.allows(6, 0));
+        assert_eq!(compactions.horizon(0, 11, &mut rounds).sequence, 7);
+        assert_eq!(rounds, [7, 9, 11]);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::error::FluxError;
+use crate::snapshot::Snapshot;
+use crate::write_batch::{Op, WriteBatch};
+use std::collections::BTreeSet;
+
+/// An optimistic read-modify-write transaction.
+///
+/// Reads go through a snapshot taken when the transaction starts, and see the transaction's
+/// own writes. Writes are buffered and committed atomically by `commit`, which fails with
+/// `FluxError::Conflict` if any key the transaction read has been written since its snapshot.
+/// Dropping a transaction without committing discards its writes.
+pub struct Transaction {
+    snapshot: Snapshot,
+    writes: WriteBatch,
+    reads: BTreeSet<Vec<u8>>,
+}
+
+impl Transaction {
+    pub(crate) fn new(snapshot: Snapshot) -> Transaction {
+        Transaction {
+            snapshot,
+            writes: WriteBatch::new(),
+            reads: BTreeSet::new(),
+        }
+    }
+
+    /// Returns the sequence number of the snapshot the transaction reads from.
+    pub fn sequence(&self) -> u64 {
+        self.snapshot.sequence()
+    }
+
+    /// Returns the value of a key, as written by this transaction or else as of its snapshot.
+    /// Keys read from the snapshot are checked for conflicts on commit.
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, FluxError> {
+        let own_write = self.writes.iter().filter(|(written, _)| *written == key).last();
+        if let Some((_, op)) = own_write {
+            return Ok(match op {
+                Op::Put(value) => Some(value.clone()),
+                Op::Delete => None,
+            });
+        }
+
+        self.reads.insert(key.to_vec());
+        let entry = self.snapshot.get(key)?;
+        Ok(entry.map(|entry| entry.value().to_vec()))
+    }
+
+    /// Buffers an insertion or update of a key.
+    pub fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.writes.put(key, value);
+    }
+
+    /// Buffers a removal of a key.
+    pub fn delete(&mut self, key: &[u8]) {
+        self.writes.delete(key);
+    }
+
+    /// Commits the buffered writes atomically and returns their number.
+    pub fn commit(self) -> Result<usize, FluxError> {
+        let reads = self.reads.iter().map(|key| key.as_slice());
+        self.snapshot
+            .disk()
+            .commit(self.writes, reads, self.snapshot.sequence())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disk::Disk;
+    use crate::error::FluxError;
+    use rand::Rng;
+    use std::fs::{create_dir_all, remove_dir_all};
+
+    #[test]
+    fn test_counter_increments() {
+        let mut rng = rand::thread_rng();
+        let test_dir = format!("./{}/", rng.gen::<u32>());
+        create_dir_all(&test_dir).unwrap();
+
+        let disk = Disk::new(&test_dir);
+        let increment = |disk: &Disk| loop {
+            let mut transaction = disk.transaction();
+            let count = match transaction.get(b"counter").unwrap() {
+                Some(value) => u64::from_le_bytes(value.try_into().unwrap()),
+                None => 0,
+            };
+            transaction.set(b"counter", &(count + 1).to_le_bytes());
+            match transaction.commit() {
+                Ok(_) => return,
+                Err(FluxError::Conflict) => continue,
+                Err(e) => panic!("commit failed: {}", e),
+            }
+        };
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let disk = disk.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..25 {
+                        increment(&disk);
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        let count = disk.get(b"counter").unwrap().unwrap();
+        assert_eq!(count.value(), &100u64.to_le_bytes());
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_conflicting_commit_fails() {
+        let mut rng = rand::thread_rng();
+        let test_dir = format!("./{}/", rng.gen::<u32>());
+        create_dir_all(&test_dir).unwrap();
+
+        let disk = Disk::new(&test_dir);
+        disk.set(b"Server", b"nginx").unwrap();
+
+        let mut transaction = disk.transaction();
+        assert_eq!(transaction.get(b"Server").unwrap().unwrap(), b"nginx");
+        transaction.set(b"Server", b"apache");
+        assert_eq!(transaction.get(b"Server").unwrap().unwrap(), b"apache");
+        transaction.delete(b"Database");
+
+        disk.delete(b"Server").unwrap();
+        assert!(matches!(transaction.commit(), Err(FluxError::Conflict)));
+        assert!(disk.get(b"Server").unwrap().is_none());
+
+        // Writing keys the transaction never read doesn't conflict.
+        let mut transaction = disk.transaction();
+        assert!(transaction.get(b"Cache").unwrap().is_none());
+        transaction.set(b"Cache", b"redis");
+        disk.set(b"Server", b"caddy").unwrap();
+        assert_eq!(transaction.commit().unwrap(), 1);
+        assert_eq!(disk.get(b"Cache").unwrap().unwrap().value(), b"redis");
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+}
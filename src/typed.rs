@@ -0,0 +1,225 @@
+//! Typed front-end storing serde types as keys and values, encoded with bincode, so
+//! application code doesn't hand-roll a byte encoding for every struct it stores. Requires
+//! the `typed` feature.
+//!
+//! Keys are encoded big-endian with fixed-size integers, so unsigned integer keys, and tuples
+//! and structs starting with them, scan in numeric order. Other types scan in the order of
+//! their encoding: strings, for one, are prefixed with their length, so shorter ones come
+//! first.
+//!
+//! Encoding fails past `DiskOptions::max_key_size` or `max_value_size` bytes, before the
+//! write would be rejected, and decoding never reads past the stored bytes, so a corrupted
+//! length can't make it allocate more than they hold.
+
+use crate::disk::Disk;
+use crate::error::FluxError;
+use crate::options::DiskOptions;
+use crate::write_batch::WriteBatch;
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+/// A `Disk` holding values of type `V` under keys of type `K`.
+///
+/// Cheap to clone; clones share the database. `disk` returns the untyped handle, for
+/// backups, statistics and the rest of the API.
+pub struct TypedDb<K, V> {
+    disk: Disk,
+    types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> TypedDb<K, V>
+where
+    K: Serialize,
+    V: Serialize + DeserializeOwned,
+{
+    /// Opens the database in `dir`.
+    pub fn open(dir: &str, options: DiskOptions) -> io::Result<TypedDb<K, V>> {
+        Ok(TypedDb::from_disk(Disk::open(dir, options)?))
+    }
+
+    /// Wraps an already open database.
+    pub fn from_disk(disk: Disk) -> TypedDb<K, V> {
+        TypedDb {
+            disk,
+            types: PhantomData,
+        }
+    }
+
+    /// Returns the underlying untyped handle.
+    pub fn disk(&self) -> &Disk {
+        &self.disk
+    }
+
+    /// Returns the latest value of a key, or `None` if the key isn't live.
+    pub fn get(&self, key: &K) -> Result<Option<V>, FluxError> {
+        match self.disk.get(&self.encode_key(key)?)? {
+            Some(entry) => Ok(Some(decode(entry.value(), "value")?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets a key to a value.
+    pub fn set(&self, key: &K, value: &V) -> Result<(), FluxError> {
+        let mut batch = WriteBatch::new();
+        let max_value_size = self.disk.options().max_value_size;
+        batch.put(
+            &self.encode_key(key)?,
+            &encode(value, "value", max_value_size)?,
+        );
+        self.disk.commit(batch, [], 0).map(|_| ())
+    }
+
+    /// Deletes a key.
+    pub fn delete(&self, key: &K) -> Result<(), FluxError> {
+        let mut batch = WriteBatch::new();
+        batch.delete(&self.encode_key(key)?);
+        self.disk.commit(batch, [], 0).map(|_| ())
+    }
+
+    /// Returns the live keys within the range and their values, in the order of the encoded
+    /// keys.
+    pub fn scan<R: RangeBounds<K>>(&self, range: R) -> Result<Vec<(K, V)>, FluxError>
+    where
+        K: DeserializeOwned,
+    {
+        let max_key_size = self.disk.options().max_key_size;
+        let start = encode_bound(range.start_bound(), max_key_size)?;
+        let end = encode_bound(range.end_bound(), max_key_size)?;
+        let range = (
+            start.as_ref().map(Vec::as_slice),
+            end.as_ref().map(Vec::as_slice),
+        );
+        self.disk
+            .scan(range)?
+            .iter()
+            .map(|entry| Ok((decode(entry.key(), "key")?, decode(entry.value(), "value")?)))
+            .collect()
+    }
+
+    fn encode_key(&self, key: &K) -> Result<Vec<u8>, FluxError> {
+        encode(key, "key", self.disk.options().max_key_size)
+    }
+}
+
+impl<K, V> Clone for TypedDb<K, V> {
+    fn clone(&self) -> TypedDb<K, V> {
+        TypedDb {
+            disk: self.disk.clone(),
+            types: PhantomData,
+        }
+    }
+}
+
+impl<K, V> fmt::Debug for TypedDb<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TypedDb")
+            .field("key", &std::any::type_name::<K>())
+            .field("value", &std::any::type_name::<V>())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Encoding of keys and values: big-endian and fixed-size integers keep the order of
+/// unsigned keys. Fails past `limit` bytes.
+fn codec(limit: usize) -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_big_endian()
+        .with_fixint_encoding()
+        .with_limit(limit as u64)
+}
+
+fn encode<T: Serialize>(item: &T, what: &str, limit: usize) -> Result<Vec<u8>, FluxError> {
+    codec(limit)
+        .serialize(item)
+        .map_err(|e| FluxError::InvalidArgument(format!("can't encode {}: {}", what, e)))
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8], what: &str) -> Result<T, FluxError> {
+    codec(bytes.len()).deserialize(bytes).map_err(|e| {
+        let message = format!("can't decode stored {}: {}", what, e);
+        FluxError::Io(io::Error::new(io::ErrorKind::InvalidData, message))
+    })
+}
+
+fn encode_bound<K: Serialize>(bound: Bound<&K>, limit: usize) -> Result<Bound<Vec<u8>>, FluxError> {
+    Ok(match bound {
+        Bound::Included(key) => Bound::Included(encode(key, "key", limit)?),
+        Bound::Excluded(key) => Bound::Excluded(encode(key, "key", limit)?),
+        Bound::Unbounded => Bound::Unbounded,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryBackend, Storage};
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Session {
+        user: String,
+        expires: u64,
+        roles: Vec<String>,
+    }
+
+    fn session(user: &str, expires: u64) -> Session {
+        Session {
+            user: user.to_owned(),
+            expires,
+            roles: vec!["admin".to_owned()],
+        }
+    }
+
+    #[test]
+    fn test_typed_values() {
+        let options = || DiskOptions {
+            storage: Storage::new(MemoryBackend::new()),
+            ..DiskOptions::default()
+        };
+        let db: TypedDb<u64, Session> = TypedDb::open("memory", options()).unwrap();
+        for id in [300, 2, 10] {
+            db.set(&id, &session("paarth", id * 60)).unwrap();
+        }
+        assert_eq!(db.get(&10).unwrap(), Some(session("paarth", 600)));
+        assert_eq!(db.get(&11).unwrap(), None);
+
+        // Integer keys scan in numeric order.
+        db.delete(&2).unwrap();
+        let ids: Vec<u64> = db.scan(..).unwrap().into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, [10, 300]);
+        assert_eq!(db.scan(11..).unwrap(), [(300, session("paarth", 18000))]);
+
+        // Values of another type fail to decode instead of coming back garbled.
+        db.disk()
+            .set(&encode(&7u64, "key", 8).unwrap(), b"nginx")
+            .unwrap();
+        let err = db.get(&7).unwrap_err();
+        assert!(err.to_string().contains("can't decode stored value"));
+
+        // A length prefix claiming more than the stored bytes fails without allocating it.
+        let mut corrupt = encode(&session("paarth", 60), "value", 1024).unwrap();
+        corrupt[..8].copy_from_slice(&u64::MAX.to_be_bytes());
+        let key = encode(&8u64, "key", 8).unwrap();
+        db.disk().set(&key, &corrupt).unwrap();
+        let err = db.get(&8).unwrap_err();
+        assert!(err.to_string().contains("can't decode stored value"));
+
+        // Values past the size limit fail to encode, before the write is rejected.
+        let options = DiskOptions {
+            max_value_size: 64,
+            ..options()
+        };
+        let db: TypedDb<u64, Session> = TypedDb::open("memory", options).unwrap();
+        let large = Session {
+            user: "x".repeat(100),
+            ..session("paarth", 60)
+        };
+        let err = db.set(&9, &large).unwrap_err();
+        assert!(err.to_string().contains("can't encode value"));
+    }
+}
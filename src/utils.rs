@@ -6,7 +6,7 @@ pub fn find_files_with_extension(dir: &Path, ext: &str) -> Vec<PathBuf> {
   let mut files = Vec::new();
   for file in read_dir(dir).unwrap() {
     let path = file.unwrap().path();
-    if path.extension().unwrap() == ext {
+    if path.extension().is_some_and(|extension| extension == ext) {
       files.push(path);
     }
   }
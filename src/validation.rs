@@ -0,0 +1,173 @@
+use crate::error::FluxError;
+use crate::write_batch::{Op, WriteBatch};
+use std::fmt;
+use std::sync::Arc;
+
+/// A check run on every key a write puts or deletes, before the write commits. Returning an
+/// error rejects the whole batch the key belongs to, with the error as the reason.
+///
+/// Validators run while writes are serialized, so they should be quick and must not write to
+/// the database. Any `Fn(&[u8], Option<&[u8]>) -> Result<(), String>` closure is one, which
+/// is the simplest way to check a key format.
+pub trait WriteValidator: Send + Sync {
+    /// Checks a put of `value` under `key`, or a delete of `key` if `value` is `None`.
+    fn validate(&self, key: &[u8], value: Option<&[u8]>) -> Result<(), String>;
+}
+
+impl<F> WriteValidator for F
+where
+    F: Fn(&[u8], Option<&[u8]>) -> Result<(), String> + Send + Sync,
+{
+    fn validate(&self, key: &[u8], value: Option<&[u8]>) -> Result<(), String> {
+        self(key, value)
+    }
+}
+
+/// Rejects every write to a key starting with a prefix.
+pub struct ForbiddenPrefix {
+    prefix: Vec<u8>,
+}
+
+impl ForbiddenPrefix {
+    pub fn new(prefix: &[u8]) -> ForbiddenPrefix {
+        ForbiddenPrefix {
+            prefix: prefix.to_vec(),
+        }
+    }
+}
+
+impl WriteValidator for ForbiddenPrefix {
+    fn validate(&self, key: &[u8], _: Option<&[u8]>) -> Result<(), String> {
+        match key.starts_with(&self.prefix) {
+            true => Err(format!(
+                "key {:?} has the forbidden prefix {:?}",
+                String::from_utf8_lossy(key),
+                String::from_utf8_lossy(&self.prefix)
+            )),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Rejects values larger than a number of bytes under the keys starting with a prefix; an
+/// empty prefix applies the limit to every key.
+pub struct MaxValueSize {
+    prefix: Vec<u8>,
+    max: usize,
+}
+
+impl MaxValueSize {
+    pub fn new(prefix: &[u8], max: usize) -> MaxValueSize {
+        MaxValueSize {
+            prefix: prefix.to_vec(),
+            max,
+        }
+    }
+}
+
+impl WriteValidator for MaxValueSize {
+    fn validate(&self, key: &[u8], value: Option<&[u8]>) -> Result<(), String> {
+        match value {
+            Some(value) if value.len() > self.max && key.starts_with(&self.prefix) => Err(format!(
+                "value of {} bytes for key {:?} exceeds the limit of {} bytes",
+                value.len(),
+                String::from_utf8_lossy(key),
+                self.max
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The validators every write of a database goes through, set with
+/// `DiskOptions::validators`. Cheap to clone.
+#[derive(Clone, Default)]
+pub struct WriteValidators {
+    validators: Vec<Arc<dyn WriteValidator>>,
+}
+
+impl WriteValidators {
+    /// Creates an empty set, accepting every write.
+    pub fn new() -> WriteValidators {
+        WriteValidators::default()
+    }
+
+    /// Adds a validator, run after the ones added before it.
+    pub fn with<V: WriteValidator + 'static>(mut self, validator: V) -> WriteValidators {
+        self.validators.push(Arc::new(validator));
+        self
+    }
+
+    /// Returns whether no validator is set.
+    pub fn is_empty(&self) -> bool {
+        self.validators.is_empty()
+    }
+
+    /// Checks a put of `value` under `key`, or a delete of `key` if `value` is `None`,
+    /// returning the reason of the first validator rejecting it.
+    pub fn validate(&self, key: &[u8], value: Option<&[u8]>) -> Result<(), FluxError> {
+        for validator in self.validators.iter() {
+            validator.validate(key, value).map_err(FluxError::Rejected)?;
+        }
+        Ok(())
+    }
+
+    /// Checks every operation of a batch, returning the reason of the first rejection.
+    pub fn validate_batch(&self, batch: &WriteBatch) -> Result<(), FluxError> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        for (key, op) in batch.iter() {
+            let value = match op {
+                Op::Put(value) => Some(value.as_slice()),
+                Op::Delete => None,
+            };
+            self.validate(key, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for WriteValidators {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteValidators")
+            .field("len", &self.validators.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_rejection_wins() {
+        let validators = WriteValidators::new()
+            .with(ForbiddenPrefix::new(b"internal/"))
+            .with(MaxValueSize::new(b"user/", 4))
+            .with(|key: &[u8], _: Option<&[u8]>| match key.contains(&b' ') {
+                true => Err("keys can't hold spaces".to_owned()),
+                false => Ok(()),
+            });
+
+        validators.validate(b"user/1", Some(b"ngin")).unwrap();
+        validators.validate(b"user/1", None).unwrap();
+        validators.validate(b"other", Some(b"nginx")).unwrap();
+        let reason = |result: Result<(), FluxError>| match result {
+            Err(FluxError::Rejected(reason)) => reason,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(
+            reason(validators.validate(b"user/1", Some(b"nginx"))),
+            "value of 5 bytes for key \"user/1\" exceeds the limit of 4 bytes"
+        );
+        assert!(reason(validators.validate(b"internal/ a", None)).contains("forbidden prefix"));
+        assert_eq!(reason(validators.validate(b"a b", None)), "keys can't hold spaces");
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"user/2", b"ok");
+        batch.delete(b"internal/lock");
+        assert!(validators.validate_batch(&batch).is_err());
+        assert!(WriteValidators::new().validate_batch(&batch).is_ok());
+    }
+}
@@ -0,0 +1,534 @@
+use crate::checksum::crc32c;
+use crate::manifest::file_name;
+use crate::sstable::{Entry, SSTable};
+use crate::storage::{read_exact_at, FileWriter, Storage, StorageFile};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+/// Magic bytes at the start of every value log file.
+const VALUE_LOG_MAGIC: [u8; 4] = *b"FLXV";
+/// Current value log format version.
+const VALUE_LOG_VERSION: u8 = 1;
+/// Magic and version.
+const HEADER_SIZE: u64 = 4 + 1;
+/// CRC32C, key size and value size of a record.
+const RECORD_OVERHEAD: usize = 4 + 4 + 4;
+/// File number, offset and size of the record a pointer leads to.
+const POINTER_SIZE: usize = 8 + 8 + 4;
+
+/* NOTE: Layout of a value log file, written once by a flush or a compaction and never
+   modified afterwards:
+
+   header | record*
+
+   Each record holds the key and the value moved out of a segment, preceded by the CRC32C
+   of the rest of the record and the sizes of the key and the value. The segment entry keeps
+   a pointer in place of the value: the number of the file, and the offset and size of the
+   record. Keys are kept so a file can be inspected on its own.
+*/
+
+/// Settings of the value log, set in `DiskOptions::value_log`.
+///
+/// Values of at least `min_value_size` bytes are moved out of the segments when a memtable
+/// is flushed, into a value log file written next to the segment, which keeps a small
+/// pointer in their place. Compactions then only rewrite the pointers, instead of copying
+/// every large value again at each merge.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValueLogOptions {
+    /// Size in bytes from which a value is kept in the value log.
+    pub min_value_size: usize,
+    /// Share of the bytes of a value log file no segment points to any more, such as
+    /// overwritten or deleted values, past which the next compaction copies the values still
+    /// live into a new file, so the old one can be removed. Files nothing points to are
+    /// removed by any compaction.
+    pub gc_garbage_ratio: f64,
+}
+
+impl Default for ValueLogOptions {
+    fn default() -> ValueLogOptions {
+        ValueLogOptions {
+            min_value_size: 64 * 1024,
+            gc_garbage_ratio: 0.5,
+        }
+    }
+}
+
+/// Counters of the value log of a database.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ValueLogStats {
+    /// Number of live value log files.
+    pub files: usize,
+    /// Total size of the live value log files.
+    pub bytes: u64,
+    /// Bytes of records segments point to, as counted by the last compaction; records
+    /// written since are counted as live.
+    pub live_bytes: u64,
+    /// Bytes of live records copied to new files by compactions since the database was
+    /// opened.
+    pub relocated_bytes: u64,
+}
+
+/// The value log files of a database, shared by its segments to read the values they point
+/// to.
+#[derive(Clone)]
+pub(crate) struct ValueLog {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    storage: Storage,
+    dir: PathBuf,
+    files: Mutex<Files>,
+    relocated_bytes: AtomicU64,
+}
+
+#[derive(Default)]
+struct Files {
+    /// Open value log files, by number. Retired files stay open until no reader can need
+    /// them.
+    open: HashMap<u64, LogFile>,
+    /// Files no live segment points to, with the segments that pointed to them when they
+    /// were retired. They are removed once every one of those segments is dropped.
+    retired: Vec<(u64, Vec<Weak<SSTable>>)>,
+}
+
+struct LogFile {
+    name: String,
+    file: Arc<dyn StorageFile>,
+    size: u64,
+    live_bytes: u64,
+}
+
+/// Returns the size of the value `pointer` points to, written under a key of `key_len`
+/// bytes, without reading it.
+pub(crate) fn pointed_value_size(pointer: &[u8], key_len: usize) -> io::Result<u64> {
+    let pointer = ValuePointer::decode(pointer)?;
+    Ok((pointer.size as usize).saturating_sub(RECORD_OVERHEAD + key_len) as u64)
+}
+
+/// Location of a value in the value log, stored in a segment entry in place of the value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct ValuePointer {
+    file: u64,
+    offset: u64,
+    size: u32,
+}
+
+impl ValuePointer {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(POINTER_SIZE);
+        bytes.extend_from_slice(&self.file.to_le_bytes());
+        bytes.extend_from_slice(&self.offset.to_le_bytes());
+        bytes.extend_from_slice(&self.size.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> io::Result<ValuePointer> {
+        if bytes.len() != POINTER_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "corrupted value log pointer",
+            ));
+        }
+        Ok(ValuePointer {
+            file: u64::from_le_bytes(bytes[..8].try_into().unwrap()),
+            offset: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            size: u32::from_le_bytes(bytes[16..].try_into().unwrap()),
+        })
+    }
+}
+
+impl ValueLog {
+    pub(crate) fn new(storage: &Storage, dir: &Path) -> ValueLog {
+        ValueLog {
+            shared: Arc::new(Shared {
+                storage: storage.clone(),
+                dir: dir.to_owned(),
+                files: Mutex::new(Files::default()),
+                relocated_bytes: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Opens the live value log files listed in the manifest, counting them as fully live
+    /// until the next compaction.
+    pub(crate) fn load(&self, names: &[String]) -> io::Result<()> {
+        for name in names {
+            let path = self.shared.dir.join(name);
+            let file: Arc<dyn StorageFile> = Arc::from(self.shared.storage.open(&path)?);
+            let size = file.size()?;
+            let mut header = [0u8; HEADER_SIZE as usize];
+            read_exact_at(file.as_ref(), &mut header, 0)
+                .map_err(|_| corrupted(name, "file is too short"))?;
+            if header[..4] != VALUE_LOG_MAGIC {
+                return Err(corrupted(name, "bad magic"));
+            }
+            match header[4] {
+                0 => return Err(corrupted(name, "invalid format version 0")),
+                1..=VALUE_LOG_VERSION => {}
+                version => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!(
+                            "value log {} has format version {}, newer than version {}, the \
+                             newest this build reads",
+                            name, version, VALUE_LOG_VERSION
+                        ),
+                    ))
+                }
+            }
+            let log_file = LogFile {
+                name: name.clone(),
+                file,
+                size,
+                live_bytes: size - HEADER_SIZE,
+            };
+            let number = file_number(name)?;
+            self.shared
+                .files
+                .lock()
+                .unwrap()
+                .open
+                .insert(number, log_file);
+        }
+        Ok(())
+    }
+
+    /// Reads the value an entry points to.
+    pub(crate) fn get(&self, pointer: &[u8]) -> io::Result<Vec<u8>> {
+        let pointer = ValuePointer::decode(pointer)?;
+        let (name, file) = {
+            let files = self.shared.files.lock().unwrap();
+            let log_file = files.open.get(&pointer.file).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("value log file {:06} is missing", pointer.file),
+                )
+            })?;
+            (log_file.name.clone(), log_file.file.clone())
+        };
+        let mut record = vec![0u8; pointer.size as usize];
+        read_exact_at(file.as_ref(), &mut record, pointer.offset)
+            .map_err(|_| corrupted(&name, "record past the end of the file"))?;
+        if record.len() < RECORD_OVERHEAD
+            || u32::from_le_bytes(record[..4].try_into().unwrap()) != crc32c(&record[4..])
+        {
+            return Err(corrupted(&name, "checksum mismatch"));
+        }
+        let key_len = u32::from_le_bytes(record[4..8].try_into().unwrap()) as usize;
+        let value_len = u32::from_le_bytes(record[8..12].try_into().unwrap()) as usize;
+        if RECORD_OVERHEAD + key_len + value_len != record.len() {
+            return Err(corrupted(&name, "bad record size"));
+        }
+        record.drain(..RECORD_OVERHEAD + key_len);
+        Ok(record)
+    }
+
+    /// Starts a value log file named `name`, created in `storage` once a value is written.
+    /// Entries pointing to the files in `relocate` have their values copied to it.
+    pub(crate) fn writer(
+        &self,
+        storage: Storage,
+        name: String,
+        min_value_size: usize,
+        relocate: HashSet<u64>,
+    ) -> io::Result<ValueLogWriter> {
+        Ok(ValueLogWriter {
+            log: self.clone(),
+            storage,
+            path: self.shared.dir.join(&name),
+            number: file_number(&name)?,
+            min_value_size,
+            relocate,
+            writer: None,
+            reader: None,
+            offset: HEADER_SIZE,
+            referenced: HashMap::new(),
+            relocated_bytes: 0,
+        })
+    }
+
+    /// Returns the files whose share of bytes no segment points to has reached `ratio`.
+    pub(crate) fn garbage_files(&self, ratio: f64) -> HashSet<u64> {
+        let files = self.shared.files.lock().unwrap();
+        let retired: HashSet<u64> = files.retired.iter().map(|(number, _)| *number).collect();
+        files
+            .open
+            .iter()
+            .filter(|(number, _)| !retired.contains(number))
+            .filter(|(_, file)| {
+                let data = file.size - HEADER_SIZE;
+                data > 0 && (data - file.live_bytes.min(data)) as f64 >= ratio * data as f64
+            })
+            .map(|(number, _)| *number)
+            .collect()
+    }
+
+    /// Returns the names of the live files the entries written by a compaction don't point
+    /// to, which the compaction retires.
+    pub(crate) fn unreferenced(&self, writer: &ValueLogWriter) -> Vec<String> {
+        let files = self.shared.files.lock().unwrap();
+        let retired: HashSet<u64> = files.retired.iter().map(|(number, _)| *number).collect();
+        files
+            .open
+            .iter()
+            .filter(|(number, _)| {
+                !retired.contains(number) && !writer.referenced.contains_key(number)
+            })
+            .map(|(_, file)| file.name.clone())
+            .collect()
+    }
+
+    /// Adds the file written by `writer`, once the manifest lists it. After a compaction,
+    /// also records the bytes its output points to in every file, and retires the files
+    /// named in `retired`, which are removed once the `inputs` are all dropped.
+    pub(crate) fn commit(
+        &self,
+        writer: ValueLogWriter,
+        compaction: Option<(&[String], &[Arc<SSTable>])>,
+    ) {
+        let mut files = self.shared.files.lock().unwrap();
+        if let Some((retired, inputs)) = compaction {
+            for (number, file) in files.open.iter_mut() {
+                file.live_bytes = writer.referenced.get(number).copied().unwrap_or(0);
+            }
+            let readers: Vec<Weak<SSTable>> = inputs.iter().map(Arc::downgrade).collect();
+            let retired: Vec<u64> = files
+                .open
+                .iter()
+                .filter(|(_, file)| retired.contains(&file.name))
+                .map(|(number, _)| *number)
+                .collect();
+            for number in retired {
+                files.retired.push((number, readers.clone()));
+            }
+            let relocated = &self.shared.relocated_bytes;
+            relocated.fetch_add(writer.relocated_bytes, Ordering::Relaxed);
+        }
+        if let Some(file) = writer.reader {
+            let log_file = LogFile {
+                name: file_name(&writer.path),
+                file,
+                size: writer.offset,
+                live_bytes: writer.offset - HEADER_SIZE,
+            };
+            files.open.insert(writer.number, log_file);
+        }
+    }
+
+    /// Closes the retired files no segment can read any more, and returns their names for
+    /// the caller to remove.
+    pub(crate) fn purge(&self) -> Vec<String> {
+        let mut files = self.shared.files.lock().unwrap();
+        let (done, waiting) = std::mem::take(&mut files.retired)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(_, readers)| {
+                readers.iter().all(|reader| reader.strong_count() == 0)
+            });
+        files.retired = waiting;
+        done.into_iter()
+            .filter_map(|(number, _)| files.open.remove(&number))
+            .map(|file| file.name)
+            .collect()
+    }
+
+    pub(crate) fn stats(&self) -> ValueLogStats {
+        let files = self.shared.files.lock().unwrap();
+        let retired: HashSet<u64> = files.retired.iter().map(|(number, _)| *number).collect();
+        let live = files
+            .open
+            .iter()
+            .filter(|(number, _)| !retired.contains(number))
+            .map(|(_, file)| file);
+        let mut stats = ValueLogStats {
+            relocated_bytes: self.shared.relocated_bytes.load(Ordering::Relaxed),
+            ..ValueLogStats::default()
+        };
+        for file in live {
+            stats.files += 1;
+            stats.bytes += file.size;
+            stats.live_bytes += file.live_bytes;
+        }
+        stats
+    }
+}
+
+/// Moves the large values of the entries written to a segment into a new value log file.
+pub(crate) struct ValueLogWriter {
+    log: ValueLog,
+    storage: Storage,
+    path: PathBuf,
+    number: u64,
+    min_value_size: usize,
+    relocate: HashSet<u64>,
+    writer: Option<BufWriter<FileWriter>>,
+    /// Handle reading the file once it is complete.
+    reader: Option<Arc<dyn StorageFile>>,
+    offset: u64,
+    /// Bytes of the records in each file that the entries written point to.
+    referenced: HashMap<u64, u64>,
+    relocated_bytes: u64,
+}
+
+impl ValueLogWriter {
+    /// Returns the entry to write to the segment: with a pointer in place of a large value,
+    /// and with a pointer into a file being collected replaced by one into the new file.
+    pub(crate) fn separate(&mut self, mut entry: Entry) -> io::Result<Entry> {
+        let Some(value) = &entry.value else {
+            return Ok(entry);
+        };
+        if entry.value_pointer {
+            let pointer = ValuePointer::decode(value)?;
+            if !self.relocate.contains(&pointer.file) {
+                *self.referenced.entry(pointer.file).or_default() += pointer.size as u64;
+                return Ok(entry);
+            }
+            let value = self.log.get(value)?;
+            self.relocated_bytes += pointer.size as u64;
+            entry.value = Some(self.append(&entry.key, &value)?);
+        } else if value.len() >= self.min_value_size {
+            let pointer = self.append(&entry.key, value)?;
+            entry.value = Some(pointer);
+            entry.value_pointer = true;
+        }
+        Ok(entry)
+    }
+
+    /// Appends a record, creating the file on the first one, and returns its pointer.
+    fn append(&mut self, key: &[u8], value: &[u8]) -> io::Result<Vec<u8>> {
+        if self.writer.is_none() {
+            let mut writer = BufWriter::new(FileWriter::new(self.storage.create(&self.path)?));
+            writer.write_all(&VALUE_LOG_MAGIC)?;
+            writer.write_all(&[VALUE_LOG_VERSION])?;
+            self.writer = Some(writer);
+        }
+        let writer = self.writer.as_mut().unwrap();
+        let mut record = Vec::with_capacity(RECORD_OVERHEAD + key.len() + value.len());
+        record.extend_from_slice(&[0; 4]);
+        record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        record.extend_from_slice(key);
+        record.extend_from_slice(value);
+        let checksum = crc32c(&record[4..]);
+        record[..4].copy_from_slice(&checksum.to_le_bytes());
+        writer.write_all(&record)?;
+
+        let pointer = ValuePointer {
+            file: self.number,
+            offset: self.offset,
+            size: record.len() as u32,
+        };
+        self.offset += record.len() as u64;
+        *self.referenced.entry(self.number).or_default() += record.len() as u64;
+        Ok(pointer.encode())
+    }
+
+    /// Syncs the file, if any value was written, so it is durable before the manifest
+    /// lists it, and opens it for reading.
+    pub(crate) fn finish(&mut self) -> io::Result<()> {
+        if let Some(writer) = self.writer.as_mut() {
+            writer.flush()?;
+            writer.get_ref().get_ref().sync()?;
+            self.reader = Some(Arc::from(self.storage.open(&self.path)?));
+        }
+        Ok(())
+    }
+
+    /// Returns the name of the file written, if any value was written.
+    pub(crate) fn file_name(&self) -> Option<String> {
+        self.writer.as_ref().map(|_| file_name(&self.path))
+    }
+
+    /// Removes the file written, after the segment pointing to it failed.
+    pub(crate) fn abandon(&mut self) {
+        if self.writer.take().is_some() {
+            self.reader = None;
+            let _ = self.storage.delete(&self.path);
+        }
+    }
+}
+
+/// Returns the number a value log file is named after.
+fn file_number(name: &str) -> io::Result<u64> {
+    let number = name
+        .split('.')
+        .next()
+        .and_then(|number| number.parse().ok());
+    number.ok_or_else(|| corrupted(name, "bad file name"))
+}
+
+fn corrupted(name: &str, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupted value log {}: {}", name, reason),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryBackend;
+
+    fn entry(key: &[u8], value: &[u8]) -> Entry {
+        Entry {
+            key: key.to_vec(),
+            value: Some(value.to_vec()),
+            timestamp: 1,
+            sequence: 1,
+            schema: 0,
+            retained: None,
+            value_pointer: false,
+            backdated: false,
+        }
+    }
+
+    #[test]
+    fn test_separate_and_relocate() {
+        let storage = Storage::new(MemoryBackend::new());
+        let log = ValueLog::new(&storage, Path::new("db"));
+        let large = vec![7u8; 100];
+
+        let mut writer = log
+            .writer(
+                storage.clone(),
+                "000001.vlog".to_owned(),
+                64,
+                HashSet::new(),
+            )
+            .unwrap();
+        let small = writer.separate(entry(b"Server", b"nginx")).unwrap();
+        assert_eq!(small, entry(b"Server", b"nginx"));
+        let moved = writer.separate(entry(b"Body", &large)).unwrap();
+        assert!(moved.value_pointer);
+        assert_eq!(moved.value.as_ref().unwrap().len(), POINTER_SIZE);
+        writer.finish().unwrap();
+        assert_eq!(writer.file_name().as_deref(), Some("000001.vlog"));
+        log.commit(writer, None);
+        assert_eq!(log.get(moved.value.as_ref().unwrap()).unwrap(), large);
+        assert_eq!(log.stats().files, 1);
+
+        // Collecting the file copies the value into a new one.
+        assert!(log.garbage_files(0.5).is_empty());
+        let relocate = HashSet::from([1]);
+        let mut writer = log
+            .writer(storage.clone(), "000002.vlog".to_owned(), 64, relocate)
+            .unwrap();
+        let relocated = writer.separate(moved.clone()).unwrap();
+        assert_ne!(relocated.value, moved.value);
+        writer.finish().unwrap();
+        assert_eq!(log.unreferenced(&writer), vec!["000001.vlog".to_owned()]);
+        log.commit(writer, Some((&["000001.vlog".to_owned()], &[])));
+        assert_eq!(log.get(relocated.value.as_ref().unwrap()).unwrap(), large);
+        assert_eq!(log.purge(), vec!["000001.vlog".to_owned()]);
+        let stats = log.stats();
+        assert_eq!((stats.files, stats.relocated_bytes), (1, 116));
+
+        // A corrupted pointer is caught by the record checksum.
+        let mut pointer = relocated.value.unwrap();
+        pointer[8] += 1;
+        assert!(log.get(&pointer).is_err());
+    }
+}
@@ -1,113 +1,1086 @@
+use crate::checksum::crc32c_append;
+use crate::compression::Compression;
+use crate::logging::{Level, Logger, Subsystem};
+use crate::manifest::{is_database_file_name, WAL_EXTENSION};
 use crate::mem_table::InMemoryTable;
-use crate::utils::find_files_with_extension;
-use crate::wal_iterator::{LogFileIterator, LogRecord};
-use std::fs::{remove_file, File, OpenOptions};
-use std::io::{self, BufWriter, Write};
+use crate::options::DiskOptions;
+use crate::stats::RecoveryStats;
+use crate::storage::{FileWriter, Storage, StorageFile};
+use crate::wal_iterator::{CorruptionInfo, LogFileIterator, LogRecord};
+use crate::wal_mirror::MirrorAck;
+use crate::write_batch::{Op, WriteBatch};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
+
+/// Magic bytes at the start of every WAL file.
+pub const WAL_MAGIC: [u8; 4] = *b"FLXW";
+/// Version of the WAL file format written by this build.
+/// Version 1 headers hold the codec only; version 2 adds a flags byte; version 3 files may
+/// contain write batch frames; version 4 records carry a sequence number; version 5 files
+/// may contain schema-tagged insertions; version 6 files may contain soft deletions; version
+/// 7 files may contain range deletions; version 8 files may group records into compressed
+/// blocks; version 9 files may contain backdated versions.
+pub const WAL_VERSION: u8 = 9;
+/// Record kind marking the start of a write batch frame.
+pub const BATCH_RECORD: u8 = 2;
+/// Record kind of an insertion whose value is tagged with a schema version.
+pub const TAGGED_RECORD: u8 = 3;
+/// Record kind of a soft deletion, which keeps the removed value so it can be restored.
+pub const SOFT_DELETE_RECORD: u8 = 4;
+/// Record kind of a range deletion, whose key and value hold the start and end of the range.
+pub const RANGE_DELETE_RECORD: u8 = 5;
+/// Record kind of an insertion written below the latest version of its key.
+pub const BACKDATED_RECORD: u8 = 6;
+/// Record kind of a removal written below the latest version of its key, with an empty value.
+pub const BACKDATED_REMOVAL_RECORD: u8 = 7;
+/// Header flag marking files whose keys are delta-encoded against the previous record.
+const FLAG_PREFIX_KEYS: u8 = 1;
+/// Header flag marking files whose records end with a CRC32C of the record bytes.
+const FLAG_CHECKSUMS: u8 = 2;
+/// Header flag marking files whose records are grouped into blocks compressed as a whole.
+const FLAG_BLOCKS: u8 = 4;
+/// Uncompressed size past which the records buffered for a compressed block are written out
+/// without waiting for the next flush.
+const WAL_BLOCK_SIZE: usize = 64 << 10;
+/// Largest uncompressed size of a block, which holds records up to `WAL_BLOCK_SIZE` plus the
+/// last one. Readers refuse larger blocks rather than allocate what a corrupted size claims.
+pub(crate) const MAX_WAL_BLOCK_SIZE: usize = 1 << 30;
+
+/// Describes how the records following the header of a WAL file are encoded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WalHeader {
+    /// Codec the values in the file are compressed with, or its blocks if `blocks` is set.
+    pub compression: Compression,
+    /// Whether keys are stored as a shared-prefix length plus suffix relative to the
+    /// previous record's key.
+    pub prefix_keys: bool,
+    /// Whether every record ends with a checksum, so torn or corrupted records are detected.
+    pub checksums: bool,
+    /// Whether records store the sequence number assigned by the engine after the timestamp.
+    pub sequences: bool,
+    /// Whether records are grouped into blocks compressed as a whole, leaving their values
+    /// uncompressed inside the block.
+    pub blocks: bool,
+}
+
+impl WalHeader {
+    /// Builds the header new WAL files should carry for the given options.
+    pub fn from_options(options: &DiskOptions) -> WalHeader {
+        WalHeader {
+            compression: options.wal_block_compression.unwrap_or(options.wal_compression()),
+            prefix_keys: options.wal_prefix_keys,
+            checksums: true,
+            sequences: true,
+            blocks: options.wal_block_compression.is_some(),
+        }
+    }
+
+    /// Reads the header of a WAL file, leaving the reader positioned at the first record.
+    ///
+    /// Files written before headers were introduced start directly with a record; these are
+    /// rewound and treated as uncompressed with plain keys.
+    pub fn read_from<R: Read + Seek>(reader: &mut R) -> io::Result<WalHeader> {
+        let mut magic = [0; 4];
+        if reader.read_exact(&mut magic).is_err() || magic != WAL_MAGIC {
+            reader.seek(SeekFrom::Start(0))?;
+            return Ok(WalHeader::default());
+        }
+
+        let mut fields = [0; 2];
+        reader.read_exact(&mut fields)?;
+        let flags = match fields[0] {
+            0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid WAL format version 0",
+                ))
+            }
+            1 => 0,
+            2..=WAL_VERSION => {
+                let mut flags = [0; 1];
+                reader.read_exact(&mut flags)?;
+                flags[0]
+            }
+            // Guessing at the layout of a newer format would replay garbage.
+            version => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!(
+                        "WAL format version {} is newer than version {}, the newest this build \
+                         reads",
+                        version, WAL_VERSION
+                    ),
+                ))
+            }
+        };
+
+        Ok(WalHeader {
+            compression: Compression::from_id(fields[1])?,
+            prefix_keys: flags & FLAG_PREFIX_KEYS != 0,
+            checksums: flags & FLAG_CHECKSUMS != 0,
+            sequences: fields[0] >= 4,
+            blocks: flags & FLAG_BLOCKS != 0,
+        })
+    }
+
+    /// Writes the header: magic bytes, format version, value codec and flags.
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut flags = 0;
+        if self.prefix_keys {
+            flags |= FLAG_PREFIX_KEYS;
+        }
+        if self.checksums {
+            flags |= FLAG_CHECKSUMS;
+        }
+        if self.blocks {
+            flags |= FLAG_BLOCKS;
+        }
+        writer.write_all(&WAL_MAGIC)?;
+        writer.write_all(&[WAL_VERSION, self.compression.id(), flags])?;
+        writer.flush()
+    }
+}
 
 /// Write Ahead Log (WAL) - captures operations performed on an in-memory table (MemTable)
 /// for potential recovery in case of system failures.
+#[allow(clippy::upper_case_acronyms)]
 pub struct WAL {
     path: PathBuf,
-    writer: BufWriter<File>,
+    /// The file being appended to, followed by its copy in the mirror directory, if any.
+    copies: Vec<WalCopy>,
+    ack: MirrorAck,
+    logger: Logger,
+    storage: Storage,
+    header: WalHeader,
+    last_key: Vec<u8>,
+    size: u64,
+    record_crc: u32,
+    /// Records written since the last flush, for files compressed by block.
+    block: Vec<u8>,
+}
+
+/// One copy of the WAL file being appended to.
+struct WalCopy {
+    path: PathBuf,
+    storage: Storage,
+    writer: BufWriter<FileWriter>,
+    sync_file: Option<Arc<dyn StorageFile>>,
+    /// Set once a write to the copy failed. A copy missing records is never appended to
+    /// again.
+    failed: bool,
+}
+
+impl WalCopy {
+    fn new(path: PathBuf, storage: &Storage, file: Box<dyn StorageFile>) -> WalCopy {
+        WalCopy {
+            path,
+            storage: storage.clone(),
+            writer: BufWriter::new(FileWriter::new(file)),
+            sync_file: None,
+            failed: false,
+        }
+    }
+}
+
+/// Handle syncing every copy of a WAL file, from `WAL::sync_handle`.
+pub struct WalSyncHandle {
+    files: Vec<Arc<dyn StorageFile>>,
+    ack: MirrorAck,
+}
+
+impl WalSyncHandle {
+    /// Makes the records flushed so far durable, in every copy or, if writes are
+    /// acknowledged by either copy, in at least one.
+    pub fn sync(&self) -> io::Result<()> {
+        let mut error = None;
+        for file in self.files.iter() {
+            match file.sync() {
+                Ok(()) if self.ack == MirrorAck::Either => return Ok(()),
+                Ok(()) => {}
+                Err(e) if self.ack == MirrorAck::Both => return Err(e),
+                Err(e) => error = Some(e),
+            }
+        }
+        error.map_or(Ok(()), Err)
+    }
 }
 
 impl WAL {
     /// Initializes a new WAL file in the specified directory.
     pub fn create_new(dir: &Path) -> io::Result<WAL> {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_micros();
+        WAL::create_with_options(dir, &DiskOptions::default())
+    }
 
-        let path = dir.join(format!("{}.wal", timestamp));
-        let file = OpenOptions::new().create(true).append(true).open(&path)?;
-        let writer = BufWriter::new(file);
+    /// Initializes a new WAL file encoded according to the given options.
+    pub fn create_with_options(dir: &Path, options: &DiskOptions) -> io::Result<WAL> {
+        WAL::create_with_header(options, dir, WalHeader::from_options(options))
+    }
+
+    fn create_with_header(options: &DiskOptions, dir: &Path, header: WalHeader) -> io::Result<WAL> {
+        header.compression.ensure_available()?;
+        let storage = &options.storage;
+
+        let mut timestamp = options.clock.now_micros();
+
+        // Files created within the same microsecond (e.g. on rotation) take the next free name.
+        let (path, file) = loop {
+            let path = dir.join(format!("{}.wal", timestamp));
+            match storage.create(&path) {
+                Ok(file) => break (path, file),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => timestamp += 1,
+                Err(e) => return Err(e),
+            }
+        };
+        let mut copies = vec![WalCopy::new(path.clone(), storage, file)];
+        let mut ack = MirrorAck::Both;
+        if let Some(mirror) = &options.wal_mirror {
+            ack = mirror.ack;
+            let name = path.file_name().unwrap().to_str().unwrap_or_default();
+            let copy_path = mirror.copy_path(name);
+            // A copy by that name can only be left over from a file that was retired.
+            if mirror.storage.exists(&copy_path) {
+                let _ = mirror.storage.delete(&copy_path);
+            }
+            match mirror.storage.create(&copy_path) {
+                Ok(file) => copies.push(WalCopy::new(copy_path, &mirror.storage, file)),
+                Err(e) if ack == MirrorAck::Either => {
+                    let message = format_args!("not mirroring {}: {}", copy_path.display(), e);
+                    options.logger.log(Level::Warn, Subsystem::Wal, message);
+                }
+                Err(e) => {
+                    let _ = storage.delete(&path);
+                    return Err(e);
+                }
+            }
+        }
 
-        Ok(WAL { path, writer })
+        let mut wal = WAL {
+            path,
+            copies,
+            ack,
+            logger: options.logger.clone(),
+            storage: storage.clone(),
+            header,
+            last_key: Vec::new(),
+            size: 0,
+            record_crc: 0,
+            block: Vec::new(),
+        };
+        wal.each_copy(|writer| header.write_to(writer))?;
+        wal.size = wal.copies[0].writer.get_ref().get_ref().size()?;
+        Ok(wal)
     }
 
     /// Opens an existing WAL file for appending new operations.
     pub fn open_existing(path: &Path) -> io::Result<WAL> {
-        let file = OpenOptions::new().create(true).append(true).open(path)?;
-        let writer = BufWriter::new(file);
+        WAL::open_existing_with_options(path, &DiskOptions::default())
+    }
+
+    /// Opens an existing WAL file kept in `options.storage` for appending new operations.
+    /// An empty file is given a header encoding records as the options say; otherwise the
+    /// file's own header is kept.
+    pub fn open_existing_with_options(path: &Path, options: &DiskOptions) -> io::Result<WAL> {
+        let storage = options.storage.clone();
+        let mut writer = BufWriter::new(FileWriter::new(storage.append(path)?));
+        let mut header = WalHeader::from_options(options);
+        let mut last_key = Vec::new();
+
+        if writer.get_ref().get_ref().size()? == 0 {
+            header.write_to(&mut writer)?;
+        } else {
+            let records = LogFileIterator::open_with(&storage, path)?;
+            header = records.header();
+            if header.prefix_keys {
+                // Appended keys are encoded against the last key already in the file.
+                if let Some(log) = records.last() {
+                    last_key = log.identifier;
+                }
+            }
+        }
+        let size = writer.get_ref().get_ref().size()?;
 
         Ok(WAL {
             path: path.to_owned(),
-            writer,
+            copies: vec![WalCopy {
+                path: path.to_owned(),
+                storage: storage.clone(),
+                writer,
+                sync_file: None,
+                failed: false,
+            }],
+            ack: MirrorAck::Both,
+            logger: options.logger.clone(),
+            storage,
+            header,
+            last_key,
+            size,
+            record_crc: 0,
+            block: Vec::new(),
         })
     }
 
     /// Loads existing WAL files in the given directory, recovering the in-memory state and returning
     /// a fresh WAL instance.
     pub fn recover_from_directory(dir: &Path) -> io::Result<(WAL, InMemoryTable)> {
-        let mut wal_files = find_files_with_extension(dir, "wal");
-        wal_files.sort();
-
-        let mut mem_table = InMemoryTable::new();
-        let mut active_wal = WAL::create_new(dir)?;
-
-        for wal_path in wal_files.iter() {
-            if let Ok(wal) = WAL::open_existing(wal_path) {
-                for log in wal.into_iter() {
-                    if log.is_removed {
-                        mem_table.remove(&log.identifier, log.event_time);
-                        active_wal.record_removal(&log.identifier, log.event_time)?;
-                    } else {
-                        mem_table.insert(
-                            &log.identifier,
-                            log.data.as_ref().unwrap(),
-                            log.event_time,
-                        );
-                        active_wal.record_insertion(
-                            &log.identifier,
-                            &log.data.unwrap(),
-                            log.event_time,
-                        )?;
-                    }
-                }
-            }
-        }
+        WAL::recover_with_options(dir, &DiskOptions::default())
+    }
+
+    /// Recovers the in-memory state like `recover_from_directory`, encoding the fresh WAL
+    /// according to the given options. Each existing file is decoded using its own header.
+    pub fn recover_with_options(
+        dir: &Path,
+        options: &DiskOptions,
+    ) -> io::Result<(WAL, InMemoryTable)> {
+        let wal_files = find_wal_files_with(&options.storage, dir)?;
+        let (mut active_wal, mem_table) = WAL::replay_files(dir, &wal_files, options, 0, &[])?;
+        // The copies must be durable before the originals go. Should removing them be cut
+        // short, the next recovery skips the records copied twice.
+        active_wal.sync()?;
 
-        active_wal.flush()?; // Ensure all writes are saved
         for wal_path in wal_files {
-            remove_file(wal_path)?; // Clean up WAL files
+            options.storage.delete(&wal_path)?; // Clean up WAL files
         }
 
         Ok((active_wal, mem_table))
     }
 
-    /// Adds a new key-value pair operation to the WAL.
+    /// Replays the given WAL files, oldest first, into a fresh WAL and memtable. The replayed
+    /// files are left in place for the caller to retire once the fresh WAL is recorded.
+    ///
+    /// Records from files written before sequence numbers existed are numbered in log order
+    /// after `last_sequence`; the memtable's `last_sequence` is the highest number replayed.
+    /// The memtable keeps the versions visible to `snapshots` (sorted sequences).
+    pub fn replay_files(
+        dir: &Path,
+        wal_files: &[PathBuf],
+        options: &DiskOptions,
+        last_sequence: u64,
+        snapshots: &[u64],
+    ) -> io::Result<(WAL, InMemoryTable)> {
+        let (active_wal, mem_table, _) =
+            WAL::replay_files_with_stats(dir, wal_files, options, last_sequence, snapshots)?;
+        Ok((active_wal, mem_table))
+    }
+
+    /// Replays the given WAL files as `replay_files` does, also returning what was replayed
+    /// in a `RecoveryStats`, apart from the time and segments.
+    pub fn replay_files_with_stats(
+        dir: &Path,
+        wal_files: &[PathBuf],
+        options: &DiskOptions,
+        last_sequence: u64,
+        snapshots: &[u64],
+    ) -> io::Result<(WAL, InMemoryTable, RecoveryStats)> {
+        let mut active_wal = WAL::create_with_options(dir, options)?;
+        let target = Replay::Copy(&mut active_wal);
+        let (mem_table, recovery, _) =
+            replay(wal_files, options, last_sequence, snapshots, target)?;
+        active_wal.flush()?; // Ensure all writes are saved
+        Ok((active_wal, mem_table, recovery))
+    }
+
+    /// Replays the given WAL files, oldest first, into a memtable as `replay_files` does,
+    /// without copying their records: the files stay live ahead of the fresh WAL returned
+    /// until the memtable is flushed, so recovery reads the log once and writes none of it.
+    /// Also returns the files to keep, oldest first; the others hold no record and can be
+    /// retired once the fresh WAL is recorded.
+    ///
+    /// Files written before sequence numbers existed are copied into the fresh WAL, as
+    /// `replay_files` does, along with every other file: their records would otherwise be
+    /// numbered anew on each recovery.
+    pub fn recover_files(
+        dir: &Path,
+        wal_files: &[PathBuf],
+        options: &DiskOptions,
+        last_sequence: u64,
+        snapshots: &[u64],
+    ) -> io::Result<(WAL, InMemoryTable, RecoveryStats, Vec<PathBuf>)> {
+        for path in wal_files {
+            if !LogFileIterator::open_with(&options.storage, path)?.header().sequences {
+                let (active_wal, mem_table, recovery) = WAL::replay_files_with_stats(
+                    dir,
+                    wal_files,
+                    options,
+                    last_sequence,
+                    snapshots,
+                )?;
+                return Ok((active_wal, mem_table, recovery, Vec::new()));
+            }
+        }
+        let (mem_table, recovery, kept) =
+            replay(wal_files, options, last_sequence, snapshots, Replay::Keep)?;
+        let active_wal = WAL::create_with_options(dir, options)?;
+        Ok((active_wal, mem_table, recovery, kept))
+    }
+
+    /// Reads the given WAL files, oldest first, into a memtable as `replay_files` does,
+    /// without writing anything. The files may still be appended to: a record cut short at
+    /// the end of one is left out rather than reported.
+    pub fn read_files(
+        wal_files: &[PathBuf],
+        options: &DiskOptions,
+        last_sequence: u64,
+        snapshots: &[u64],
+    ) -> io::Result<(InMemoryTable, RecoveryStats)> {
+        let (_, mem_table, recovery) =
+            FollowedWal::open(wal_files, options, last_sequence, snapshots)?;
+        Ok((mem_table, recovery))
+    }
+
+    /// Adds a new key-value pair operation, written at `sequence`, to the WAL.
     pub fn record_insertion(
         &mut self,
         key: &[u8],
         value: &[u8],
         timestamp: u128,
+        sequence: u64,
+    ) -> io::Result<()> {
+        self.record_insertion_with_schema(key, value, timestamp, sequence, 0)
+    }
+
+    /// Adds a key-value pair operation whose value is encoded with the given schema version,
+    /// 0 meaning untagged.
+    pub fn record_insertion_with_schema(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        timestamp: u128,
+        sequence: u64,
+        schema: u32,
     ) -> io::Result<()> {
+        let kind = if schema == 0 { 0 } else { TAGGED_RECORD };
+        self.write_value_record(kind, key, value, timestamp, sequence, schema)
+    }
+
+    /// Records a soft deletion of a key, keeping its `retained` value, encoded with the given
+    /// schema version, so the key can be restored.
+    pub fn record_soft_removal(
+        &mut self,
+        key: &[u8],
+        retained: &[u8],
+        timestamp: u128,
+        sequence: u64,
+        schema: u32,
+    ) -> io::Result<()> {
+        self.write_value_record(SOFT_DELETE_RECORD, key, retained, timestamp, sequence, schema)
+    }
+
+    /// Records the removal of every key from `start` (inclusive) to `end` (exclusive).
+    pub fn record_range_removal(
+        &mut self,
+        start: &[u8],
+        end: &[u8],
+        timestamp: u128,
+        sequence: u64,
+    ) -> io::Result<()> {
+        self.write_value_record(RANGE_DELETE_RECORD, start, end, timestamp, sequence, 0)
+    }
+
+    /// Records a version of a key written below its latest one, as
+    /// `TimestampRegression::KeepAsOlderVersion` keeps stale writes: an insertion whose value
+    /// is encoded with the given schema version, or a removal if `value` is `None`.
+    pub fn record_backdated(
+        &mut self,
+        key: &[u8],
+        value: Option<&[u8]>,
+        timestamp: u128,
+        sequence: u64,
+        schema: u32,
+    ) -> io::Result<()> {
+        match value {
+            Some(value) => {
+                self.write_value_record(BACKDATED_RECORD, key, value, timestamp, sequence, schema)
+            }
+            None => {
+                self.write_value_record(BACKDATED_REMOVAL_RECORD, key, &[], timestamp, sequence, 0)
+            }
+        }
+    }
+
+    /// Writes a record carrying a value: a plain insertion (kind 0), or a record of the given
+    /// kind followed by the schema version of the value.
+    fn write_value_record(
+        &mut self,
+        kind: u8,
+        key: &[u8],
+        value: &[u8],
+        timestamp: u128,
+        sequence: u64,
+        schema: u32,
+    ) -> io::Result<()> {
+        let compressed;
+        let value = if self.header.compression == Compression::None || self.header.blocks {
+            value
+        } else {
+            compressed = self.header.compression.compress(value)?;
+            compressed.as_slice()
+        };
+
         // Ensure the correct order and data types for writes
-        self.writer.write_all(&(key.len() as u64).to_le_bytes())?; // Key size
-        self.writer.write_all(&(false as u8).to_le_bytes())?; // Deletion flag (false)
-        self.writer.write_all(&(value.len() as u64).to_le_bytes())?; // Value size
-        self.writer.write_all(key)?; // Key
-        self.writer.write_all(value)?; // Value
-        self.writer.write_all(&timestamp.to_le_bytes())?; // Timestamp
+        let shared = self.write_key_size(key)?; // Key size
+        if kind == 0 {
+            self.write(&(false as u8).to_le_bytes())?; // Deletion flag (false)
+        } else {
+            self.write(&[kind])?; // Record kind
+            self.write(&schema.to_le_bytes())?; // Schema version
+        }
+        self.write(&(value.len() as u64).to_le_bytes())?; // Value size
+        self.write(&key[shared..])?; // Key
+        self.write(value)?; // Value
+        self.write(&timestamp.to_le_bytes())?; // Timestamp
+        self.write_sequence(sequence)?; // Sequence number
+        self.finish_record()
+    }
+
+    /// Records a removal operation, written at `sequence`, in the WAL.
+    pub fn record_removal(&mut self, key: &[u8], timestamp: u128, sequence: u64) -> io::Result<()> {
+        let shared = self.write_key_size(key)?; // Key size
+        self.write(&(true as u8).to_le_bytes())?; // Deletion flag (true)
+        self.write(&key[shared..])?; // Key
+        self.write(&timestamp.to_le_bytes())?; // Timestamp
+        self.write_sequence(sequence)?; // Sequence number
+        self.finish_record()
+    }
+
+    /// Records a write batch as one frame: a header record holding the number of operations,
+    /// followed by the operations. Recovery only applies a frame whose records are all intact.
+    /// The operations are numbered consecutively from `first_sequence`, stamped with
+    /// `timestamp` unless the batch gives their own, and inserted values are tagged with the
+    /// `schema` version.
+    pub fn record_batch(
+        &mut self,
+        batch: &WriteBatch,
+        timestamp: u128,
+        first_sequence: u64,
+        schema: u32,
+    ) -> io::Result<()> {
+        self.write(&(batch.len() as u64).to_le_bytes())?; // Operation count
+        self.write(&[BATCH_RECORD])?; // Record kind
+        self.finish_record()?;
+        let ops = batch.iter_committed(timestamp);
+        for ((key, op, timestamp, backdated), sequence) in ops.zip(first_sequence..) {
+            match (op, backdated) {
+                (Op::Put(value), false) => {
+                    self.record_insertion_with_schema(key, value, timestamp, sequence, schema)?
+                }
+                (Op::Delete, false) => self.record_removal(key, timestamp, sequence)?,
+                (Op::Put(value), true) => {
+                    self.record_backdated(key, Some(value), timestamp, sequence, schema)?
+                }
+                (Op::Delete, true) => self.record_backdated(key, None, timestamp, sequence, 0)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the sequence number of a record, for files whose records carry one.
+    fn write_sequence(&mut self, sequence: u64) -> io::Result<()> {
+        if self.header.sequences {
+            self.write(&sequence.to_le_bytes())?;
+        }
         Ok(())
     }
 
-    /// Records a removal operation in the WAL.
-    pub fn record_removal(&mut self, key: &[u8], timestamp: u128) -> io::Result<()> {
-        self.writer.write_all(&(key.len() as u64).to_le_bytes())?; // Key size
-        self.writer.write_all(&(true as u8).to_le_bytes())?; // Deletion flag (true)
-        self.writer.write_all(key)?; // Key
-        self.writer.write_all(&timestamp.to_le_bytes())?; // Timestamp
+    /// Writes the 8-byte key size field and returns how many leading key bytes are shared
+    /// with the previous record. With prefix keys the field holds the shared length and the
+    /// suffix length as two u32s, so only the suffix has to be written after it.
+    fn write_key_size(&mut self, key: &[u8]) -> io::Result<usize> {
+        if !self.header.prefix_keys {
+            self.write(&(key.len() as u64).to_le_bytes())?;
+            return Ok(0);
+        }
+
+        if key.len() > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "key too large for prefix encoding",
+            ));
+        }
+        let shared = self
+            .last_key
+            .iter()
+            .zip(key)
+            .take_while(|(a, b)| a == b)
+            .count();
+        self.write(&(shared as u32).to_le_bytes())?;
+        self.write(&((key.len() - shared) as u32).to_le_bytes())?;
+
+        self.last_key.truncate(shared);
+        self.last_key.extend_from_slice(&key[shared..]);
+        Ok(shared)
+    }
+
+    /// Appends raw record bytes, keeping track of the record checksum.
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.write_raw(bytes)?;
+        self.record_crc = crc32c_append(self.record_crc, bytes);
+        Ok(())
+    }
+
+    /// Appends bytes to the file, keeping track of its size, or to the pending block if the
+    /// file is compressed by block.
+    fn write_raw(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.header.blocks {
+            self.block.extend_from_slice(bytes);
+            return Ok(());
+        }
+        self.each_copy(|writer| writer.write_all(bytes))?;
+        self.size += bytes.len() as u64;
         Ok(())
     }
 
-    /// Ensures that all buffered writes are saved to disk.
+    /// Ends the current record, appending its checksum if the file carries them. Records
+    /// never straddle blocks, so a full block is written out only once a record ends.
+    fn finish_record(&mut self) -> io::Result<()> {
+        let crc = std::mem::take(&mut self.record_crc);
+        if self.header.checksums {
+            self.write_raw(&crc.to_le_bytes())?;
+        }
+        if self.block.len() >= WAL_BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    /// Compresses the pending block and appends it to the file as one frame: its
+    /// uncompressed and stored sizes as u32s, the stored bytes and a CRC32C of them.
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let block = std::mem::take(&mut self.block);
+        let stored = self.header.compression.compress(&block)?;
+        if block.len() > MAX_WAL_BLOCK_SIZE || stored.len() > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "record too large for a WAL block",
+            ));
+        }
+        let mut frame = Vec::with_capacity(stored.len() + 12);
+        frame.extend_from_slice(&(block.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&stored);
+        frame.extend_from_slice(&crc32c_append(0, &stored).to_le_bytes());
+        self.each_copy(|writer| writer.write_all(&frame))?;
+        self.size += frame.len() as u64;
+        Ok(())
+    }
+
+    /// Ensures that all buffered writes are saved to disk. In files compressed by block, the
+    /// records written since the last flush become one block.
     pub fn flush(&mut self) -> io::Result<()> {
-        self.writer.flush()
+        self.write_block()?;
+        self.each_copy(|writer| writer.flush())
+    }
+
+    /// Flushes the buffered records and makes everything written so far durable.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.write_block()?;
+        self.each_copy(|writer| {
+            writer.flush()?;
+            writer.get_ref().get_ref().sync()
+        })
+    }
+
+    /// Returns a handle to the file and its mirrored copy, for syncing the records flushed
+    /// so far without holding on to the WAL meanwhile. The files are opened on first use and
+    /// shared afterwards.
+    pub fn sync_handle(&mut self) -> io::Result<WalSyncHandle> {
+        let mut files = Vec::new();
+        for copy in self.copies.iter_mut().filter(|copy| !copy.failed) {
+            if copy.sync_file.is_none() {
+                copy.sync_file = Some(Arc::from(copy.storage.append(&copy.path)?));
+            }
+            files.push(copy.sync_file.clone().unwrap());
+        }
+        Ok(WalSyncHandle {
+            files,
+            ack: self.ack,
+        })
+    }
+
+    /// Runs a write on every copy still in use. A failed copy is dropped with a warning.
+    /// Unless writes are acknowledged by either copy, the first failure is returned, and
+    /// every later write to the file fails too, as the copies no longer match; otherwise
+    /// the write only fails if no copy is left.
+    fn each_copy<F>(&mut self, mut write: F) -> io::Result<()>
+    where
+        F: FnMut(&mut BufWriter<FileWriter>) -> io::Result<()>,
+    {
+        if self.ack == MirrorAck::Both {
+            if let Some(copy) = self.copies.iter().find(|copy| copy.failed) {
+                let message = format!("WAL copy {} failed earlier", copy.path.display());
+                return Err(io::Error::other(message));
+            }
+        }
+        let mut written = false;
+        let mut error = None;
+        let mirrored = self.copies.len() > 1;
+        for copy in self.copies.iter_mut().filter(|copy| !copy.failed) {
+            match write(&mut copy.writer) {
+                Ok(()) => written = true,
+                // Without a mirror, there is no other copy for it to stop matching.
+                Err(e) if !mirrored => return Err(e),
+                Err(e) => {
+                    copy.failed = true;
+                    let message = format_args!("dropped WAL copy {}: {}", copy.path.display(), e);
+                    self.logger.log(Level::Warn, Subsystem::Wal, message);
+                    if self.ack == MirrorAck::Both {
+                        return Err(e);
+                    }
+                    error = Some(e);
+                }
+            }
+        }
+        match (written, error) {
+            (true, _) => Ok(()),
+            (false, Some(e)) => Err(e),
+            (false, None) => Err(io::Error::other("every copy of the WAL failed")),
+        }
+    }
+
+    /// Closes the file without writing out the records still buffered, once a write to it
+    /// has failed: they belong to writes that were never acknowledged.
+    pub fn discard_unflushed(mut self) {
+        self.block.clear();
+        for copy in std::mem::take(&mut self.copies) {
+            let _ = copy.writer.into_parts();
+        }
+    }
+
+    /// Returns the path of the file currently being appended to.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the size of the WAL file in bytes, including writes not yet flushed. Records
+    /// waiting for their block to be compressed count at their uncompressed size.
+    pub fn size(&self) -> u64 {
+        self.size + self.block.len() as u64
+    }
+}
+
+impl Drop for WAL {
+    /// Writes out the pending block, as the file buffers flush the bytes they hold.
+    fn drop(&mut self) {
+        let _ = self.write_block();
+    }
+}
+
+/// What `replay` does with the records it reads besides applying them to the memtable.
+enum Replay<'a> {
+    /// Copies them into a fresh WAL, so the files read can be retired.
+    Copy(&'a mut WAL),
+    /// Leaves them in the files read, which stay live.
+    Keep,
+    /// Leaves them in files another process may still be appending to, whose torn tails
+    /// aren't worth reporting. The files are kept open in the `FollowedWal`.
+    Follow(&'a mut FollowedWal),
+}
+
+/// Numbers the records read from the WAL, oldest first.
+#[derive(Clone, Copy)]
+struct Sequencer {
+    /// Highest sequence number given out, from which records written before sequence numbers
+    /// existed are numbered.
+    last_sequence: u64,
+    /// Highest sequence number of a record read.
+    highest_replayed: u64,
+}
+
+impl Sequencer {
+    /// Returns the sequence number to apply a record written at `sequence` at, or `None` for
+    /// a copy of a record already applied.
+    fn next(&mut self, sequence: u64) -> Option<u64> {
+        // Sequence numbers only grow along the log, so a record numbered at or below the
+        // highest one replayed so far is a copy of a record already applied.
+        if sequence != 0 && sequence <= self.highest_replayed {
+            return None;
+        }
+        let sequence = match sequence {
+            0 => self.last_sequence + 1,
+            sequence => sequence,
+        };
+        self.last_sequence = self.last_sequence.max(sequence);
+        self.highest_replayed = sequence;
+        Some(sequence)
+    }
+}
+
+/// Replays WAL files into a fresh memtable. Also returns the files that held any record.
+fn replay(
+    wal_files: &[PathBuf],
+    options: &DiskOptions,
+    last_sequence: u64,
+    snapshots: &[u64],
+    mut target: Replay<'_>,
+) -> io::Result<(InMemoryTable, RecoveryStats, Vec<PathBuf>)> {
+    let order = options.comparator.clone();
+    let mut mem_table = InMemoryTable::with_kind(order, options.memtable_kind)
+        .with_retained_versions(options.retained_versions);
+    let mut sequencer = Sequencer {
+        last_sequence,
+        highest_replayed: 0,
+    };
+    let mut recovery = RecoveryStats::default();
+    let mut holding_records = Vec::new();
+    let report_torn_tails = !matches!(target, Replay::Follow(_));
+
+    for wal_path in wal_files.iter() {
+        let mut records = LogFileIterator::open_with(&options.storage, wal_path)?;
+        let replayed_before = recovery.records_replayed;
+        let duplicates_before = recovery.duplicate_records;
+        for mut log in records.by_ref() {
+            let Some(sequence) = sequencer.next(log.sequence) else {
+                recovery.duplicate_records += 1;
+                continue;
+            };
+            recovery.records_replayed += 1;
+            log.sequence = sequence;
+            let active_wal = match &mut target {
+                Replay::Copy(wal) => Some(&mut **wal),
+                Replay::Keep | Replay::Follow(_) => None,
+            };
+            apply_record(&mut mem_table, log, snapshots, active_wal)?;
+        }
+        recovery.wal_files_replayed += 1;
+        recovery.wal_bytes_replayed += records.file_size() - records.trailing_bytes();
+        if recovery.records_replayed > replayed_before {
+            holding_records.push(wal_path.clone());
+        }
+        if recovery.duplicate_records > duplicates_before {
+            let message = format_args!(
+                "skipped {} records of {} already replayed from an earlier file",
+                recovery.duplicate_records - duplicates_before,
+                wal_path.display()
+            );
+            options.logger.log(Level::Warn, Subsystem::Recovery, message);
+        }
+        if let Some(corruption) = records.corruption().filter(|_| report_torn_tails) {
+            recovery.torn_tails += 1;
+            options.logger.log(
+                Level::Warn,
+                Subsystem::Recovery,
+                format_args!("ignored the end of {}: {}", wal_path.display(), corruption),
+            );
+        }
+        if let Replay::Follow(followed) = &mut target {
+            followed.files.push((wal_path.clone(), records));
+        }
+    }
+    if let Replay::Follow(followed) = &mut target {
+        followed.sequencer = sequencer;
+    }
+
+    Ok((mem_table, recovery, holding_records))
+}
+
+/// Applies a record read from the WAL, at its sequence number, to a memtable, and copies it
+/// to `wal` if given.
+fn apply_record(
+    mem_table: &mut InMemoryTable,
+    log: LogRecord,
+    snapshots: &[u64],
+    wal: Option<&mut WAL>,
+) -> io::Result<()> {
+    let sequence = log.sequence;
+    if log.is_range_removal {
+        let end = log.data.unwrap_or_default();
+        mem_table.apply_range_delete(&log.identifier, &end, log.event_time, sequence);
+        if let Some(wal) = wal {
+            wal.record_range_removal(&log.identifier, &end, log.event_time, sequence)?;
+        }
+        return Ok(());
+    }
+    if log.is_backdated {
+        let value = log.data.as_deref();
+        mem_table.apply_backdated(
+            &log.identifier,
+            value,
+            log.event_time,
+            sequence,
+            log.schema,
+            snapshots,
+        );
+        if let Some(wal) = wal {
+            wal.record_backdated(&log.identifier, value, log.event_time, sequence, log.schema)?;
+        }
+        return Ok(());
     }
+    match (log.is_removed, log.data) {
+        (true, Some(retained)) => {
+            mem_table.apply_soft_delete(
+                &log.identifier,
+                &retained,
+                log.event_time,
+                sequence,
+                log.schema,
+                snapshots,
+            );
+            if let Some(wal) = wal {
+                wal.record_soft_removal(
+                    &log.identifier,
+                    &retained,
+                    log.event_time,
+                    sequence,
+                    log.schema,
+                )?;
+            }
+        }
+        (_, None) => {
+            mem_table.apply(&log.identifier, None, log.event_time, sequence, 0, snapshots);
+            if let Some(wal) = wal {
+                wal.record_removal(&log.identifier, log.event_time, sequence)?;
+            }
+        }
+        (false, Some(value)) => {
+            mem_table.apply(
+                &log.identifier,
+                Some(&value),
+                log.event_time,
+                sequence,
+                log.schema,
+                snapshots,
+            );
+            if let Some(wal) = wal {
+                wal.record_insertion_with_schema(
+                    &log.identifier,
+                    &value,
+                    log.event_time,
+                    sequence,
+                    log.schema,
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The WAL files of a database another process writes, as a follower reads them. The files
+/// stay open between reads, so catching up only reads the records appended since.
+pub(crate) struct FollowedWal {
+    /// Files read so far, oldest first, each with the iterator that read it.
+    files: Vec<(PathBuf, LogFileIterator)>,
+    sequencer: Sequencer,
+}
+
+impl FollowedWal {
+    /// Reads the given WAL files, oldest first, into a memtable as `WAL::read_files` does,
+    /// keeping them open for `read_appended`.
+    pub fn open(
+        wal_files: &[PathBuf],
+        options: &DiskOptions,
+        last_sequence: u64,
+        snapshots: &[u64],
+    ) -> io::Result<(FollowedWal, InMemoryTable, RecoveryStats)> {
+        let mut followed = FollowedWal {
+            files: Vec::new(),
+            sequencer: Sequencer {
+                last_sequence,
+                highest_replayed: 0,
+            },
+        };
+        let target = Replay::Follow(&mut followed);
+        let (mem_table, recovery, _) =
+            replay(wal_files, options, last_sequence, snapshots, target)?;
+        Ok((followed, mem_table, recovery))
+    }
+
+    /// Returns the highest sequence number of the records read.
+    pub fn last_sequence(&self) -> u64 {
+        self.sequencer.last_sequence
+    }
+
+    /// Reads the records appended since the last read, to the files already read and to the
+    /// ones listed after them in `wal_files`, numbered as they are applied. Returns `None`,
+    /// having read nothing, if `wal_files` no longer starts with the files already read: the
+    /// records of the ones retired are in segments now, and the files must be read anew.
+    pub fn read_appended(
+        &mut self,
+        wal_files: &[PathBuf],
+        options: &DiskOptions,
+    ) -> io::Result<Option<Vec<LogRecord>>> {
+        let read = self.files.iter().map(|(path, _)| path);
+        if wal_files.len() < self.files.len() || !read.eq(&wal_files[..self.files.len()]) {
+            return Ok(None);
+        }
+        for path in &wal_files[self.files.len()..] {
+            let records = LogFileIterator::open_with(&options.storage, path)?;
+            self.files.push((path.clone(), records));
+        }
+        let mut appended = Vec::new();
+        for (_, records) in &mut self.files {
+            records.resume()?;
+            for mut log in records.by_ref() {
+                if let Some(sequence) = self.sequencer.next(log.sequence) {
+                    log.sequence = sequence;
+                    appended.push(log);
+                }
+            }
+        }
+        Ok(Some(appended))
+    }
+
+    /// Applies records returned by `read_appended` to a memtable.
+    pub fn apply(
+        mem_table: &mut InMemoryTable,
+        records: Vec<LogRecord>,
+        snapshots: &[u64],
+    ) -> io::Result<()> {
+        for log in records {
+            apply_record(mem_table, log, snapshots, None)?;
+        }
+        Ok(())
+    }
+}
+
+/// Gets the WAL files in a directory, oldest first.
+pub fn find_wal_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    find_wal_files_with(&Storage::default(), dir)
+}
+
+/// Gets the WAL files of a directory kept in `storage`, oldest first. Files with the WAL
+/// extension but a name the engine never gives one, such as `notes.wal`, are ignored.
+pub fn find_wal_files_with(storage: &Storage, dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut wal_files = storage.list_with_extension(dir, WAL_EXTENSION)?;
+    wal_files.retain(|path| {
+        let name = path.file_name().and_then(|name| name.to_str());
+        name.is_some_and(|name| is_database_file_name(name, WAL_EXTENSION))
+    });
+    wal_files.sort_by_key(|path| wal_file_number(path));
+    Ok(wal_files)
+}
+
+/// Reads every record of a WAL file, with its offset, for inspection. Where the file stops
+/// being readable, the last item tells the offset and the reason instead of the iteration
+/// silently ending as it does during recovery.
+pub fn inspect(path: &Path) -> io::Result<impl Iterator<Item = Result<LogRecord, CorruptionInfo>>> {
+    let mut records = LogFileIterator::from_path(path.to_owned())?;
+    let mut done = false;
+    Ok(std::iter::from_fn(move || {
+        if done {
+            return None;
+        }
+        match records.next() {
+            Some(record) => Some(Ok(record)),
+            None => {
+                done = true;
+                records.corruption().cloned().map(Err)
+            }
+        }
+    }))
+}
+
+/// Orders WAL files by the creation timestamp in their name, falling back to the name itself
+/// for timestamps too large to parse.
+fn wal_file_number(path: &Path) -> (u128, PathBuf) {
+    let number = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.parse().ok())
+        .unwrap_or(u128::MAX);
+    (number, path.to_owned())
 }
 
 impl IntoIterator for WAL {
@@ -116,19 +1089,34 @@ impl IntoIterator for WAL {
 
     /// Converts a WAL instance into an iterator over the log entries.
     fn into_iter(self) -> LogFileIterator {
-        LogFileIterator::from_path(self.path).expect("Failed to create log iterator")
+        LogFileIterator::open_with(&self.storage, &self.path)
+            .expect("Failed to create log iterator")
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::wal::WAL;
+    use crate::compression::Compression;
+    use crate::options::DiskOptions;
+    use crate::storage::{MemoryBackend, Storage};
+    use crate::wal::{find_wal_files, inspect, WAL, WAL_MAGIC, WAL_VERSION};
+    use crate::wal_iterator::{CorruptionKind, LogFileIterator};
+    use crate::write_batch::WriteBatch;
     use rand::Rng;
-    use std::fs::{create_dir_all, remove_dir_all, File};
-    use std::io::{BufReader, Read};
+    use std::fs::{create_dir_all, remove_dir_all, File, OpenOptions};
+    use std::io::{self, BufReader, Read, Write};
     use std::path::PathBuf;
     use std::time::{SystemTime, UNIX_EPOCH};
 
+    fn validate_header(reader: &mut BufReader<File>, expected_compression: Compression) {
+        let mut header = [0; 7];
+        reader.read_exact(&mut header).unwrap();
+        assert_eq!(header[..4], WAL_MAGIC, "Magic mismatch");
+        assert_eq!(header[4], WAL_VERSION, "Version mismatch");
+        assert_eq!(header[5], expected_compression.id(), "Codec mismatch");
+        assert_eq!(header[6], 2, "Flags mismatch");
+    }
+
     fn validate_log_entry(
         reader: &mut BufReader<File>,
         expected_key: &[u8],
@@ -155,6 +1143,9 @@ mod tests {
             reader.read_exact(&mut timestamp_buffer).unwrap();
             let timestamp = u128::from_le_bytes(timestamp_buffer);
             assert_eq!(timestamp, expected_timestamp, "Timestamp mismatch");
+
+            reader.read_exact(&mut buffer).unwrap();
+            assert_ne!(u64::from_le_bytes(buffer), 0, "Sequence missing");
         } else {
             reader.read_exact(&mut buffer).unwrap();
             let value_size = usize::from_le_bytes(buffer);
@@ -180,7 +1171,13 @@ mod tests {
             reader.read_exact(&mut timestamp_buffer).unwrap();
             let timestamp = u128::from_le_bytes(timestamp_buffer);
             assert_eq!(timestamp, expected_timestamp, "Timestamp mismatch");
+
+            reader.read_exact(&mut buffer).unwrap();
+            assert_ne!(u64::from_le_bytes(buffer), 0, "Sequence missing");
         }
+
+        let mut checksum = [0; 4];
+        reader.read_exact(&mut checksum).unwrap();
     }
 
     #[test]
@@ -195,12 +1192,13 @@ mod tests {
             .as_micros();
 
         let mut wal = WAL::create_new(&test_dir).unwrap();
-        wal.record_insertion(b"Server", b"nginx", current_time)
+        wal.record_insertion(b"Server", b"nginx", current_time, 1)
             .unwrap();
         wal.flush().unwrap();
 
         let file = File::open(&wal.path).unwrap();
         let mut reader = BufReader::new(file);
+        validate_header(&mut reader, Compression::None);
 
         validate_log_entry(&mut reader, b"Server", Some(b"nginx"), current_time, false);
 
@@ -226,14 +1224,15 @@ mod tests {
 
         let mut wal = WAL::create_new(&test_dir).unwrap();
 
-        for (key, value) in entries.iter() {
-            wal.record_insertion(key, value.unwrap(), current_time)
+        for (sequence, (key, value)) in (1..).zip(entries.iter()) {
+            wal.record_insertion(key, value.unwrap(), current_time, sequence)
                 .unwrap();
         }
         wal.flush().unwrap();
 
         let file = File::open(&wal.path).unwrap();
         let mut reader = BufReader::new(file);
+        validate_header(&mut reader, Compression::None);
 
         for (key, value) in entries.iter() {
             validate_log_entry(&mut reader, key, Some(value.unwrap()), current_time, false);
@@ -254,11 +1253,12 @@ mod tests {
             .as_micros();
 
         let mut wal = WAL::create_new(&test_dir).unwrap();
-        wal.record_removal(b"Server", current_time).unwrap();
+        wal.record_removal(b"Server", current_time, 1).unwrap();
         wal.flush().unwrap();
 
         let file = File::open(&wal.path).unwrap();
         let mut reader = BufReader::new(file);
+        validate_header(&mut reader, Compression::None);
 
         validate_log_entry(&mut reader, b"Server", None, current_time, true);
 
@@ -293,7 +1293,7 @@ mod tests {
             .as_micros();
 
         let mut wal = WAL::create_new(&test_dir).unwrap();
-        wal.record_insertion(b"Server", b"nginx", current_time)
+        wal.record_insertion(b"Server", b"nginx", current_time, 1)
             .unwrap();
         wal.flush().unwrap();
 
@@ -301,14 +1301,421 @@ mod tests {
 
         let file = File::open(&new_wal.path).unwrap();
         let mut reader = BufReader::new(file);
+        validate_header(&mut reader, Compression::None);
 
         validate_log_entry(&mut reader, b"Server", Some(b"nginx"), current_time, false);
 
         let mem_entry = new_mem_table.fetch(b"Server").unwrap();
         assert_eq!(mem_entry.key, b"Server");
-        assert_eq!(mem_entry.value.as_ref().unwrap().as_slice(), b"nginx");
+        assert_eq!(mem_entry.value.as_deref().unwrap(), b"nginx");
         assert_eq!(mem_entry.timestamp, current_time);
 
         remove_dir_all(&test_dir).unwrap();
     }
+
+    #[test]
+    fn test_recover_schema_tagged_values() {
+        let mut rng = rand::thread_rng();
+        let test_dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir_all(&test_dir).unwrap();
+
+        let mut wal = WAL::create_new(&test_dir).unwrap();
+        wal.record_insertion_with_schema(b"Server", b"nginx", 1, 1, 3)
+            .unwrap();
+        wal.record_insertion(b"Database", b"PostgreSQL", 2, 2).unwrap();
+        wal.flush().unwrap();
+
+        let (_new_wal, new_mem_table) = WAL::recover_from_directory(&test_dir).unwrap();
+        let server = new_mem_table.fetch(b"Server").unwrap();
+        assert_eq!(server.value.as_deref(), Some(&b"nginx"[..]));
+        assert_eq!(server.schema, 3);
+        assert_eq!(new_mem_table.fetch(b"Database").unwrap().schema, 0);
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_recover_headerless_file() {
+        let mut rng = rand::thread_rng();
+        let test_dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir_all(&test_dir).unwrap();
+
+        // Files written before headers were introduced start directly with a record.
+        let mut legacy = File::create(test_dir.join("1.wal")).unwrap();
+        legacy.write_all(&6u64.to_le_bytes()).unwrap();
+        legacy.write_all(&[0]).unwrap();
+        legacy.write_all(&5u64.to_le_bytes()).unwrap();
+        legacy.write_all(b"Server").unwrap();
+        legacy.write_all(b"nginx").unwrap();
+        legacy.write_all(&7u128.to_le_bytes()).unwrap();
+        drop(legacy);
+
+        let (_new_wal, new_mem_table) = WAL::recover_from_directory(&test_dir).unwrap();
+        let mem_entry = new_mem_table.fetch(b"Server").unwrap();
+        assert_eq!(mem_entry.value.as_deref().unwrap(), b"nginx");
+        assert_eq!(mem_entry.timestamp, 7);
+        // Records predating sequence numbers are numbered in log order on replay.
+        assert_eq!(mem_entry.sequence, 1);
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_recover_files_keeps_logs() {
+        let mut rng = rand::thread_rng();
+        let test_dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir_all(&test_dir).unwrap();
+        let options = DiskOptions::default();
+
+        let mut wal = WAL::create_new(&test_dir).unwrap();
+        wal.record_insertion(b"Server", b"nginx", 7, 1).unwrap();
+        wal.record_removal(b"Database", 8, 2).unwrap();
+        wal.flush().unwrap();
+        let empty = WAL::create_new(&test_dir).unwrap();
+        let wal_files = vec![wal.path().to_owned(), empty.path().to_owned()];
+        let size = wal.size();
+        drop((wal, empty));
+
+        // Only the file holding records is kept, and nothing is copied out of it.
+        let (fresh, mem_table, recovery, kept) =
+            WAL::recover_files(&test_dir, &wal_files, &options, 0, &[]).unwrap();
+        assert_eq!(kept, wal_files[..1]);
+        assert_eq!(std::fs::metadata(&wal_files[0]).unwrap().len(), size);
+        assert_eq!(recovery.records_replayed, 2);
+        assert_eq!(mem_table.last_sequence(), 2);
+        assert_eq!(mem_table.fetch(b"Server").unwrap().value.as_deref(), Some(&b"nginx"[..]));
+        assert!(!fresh.into_iter().any(|_| true));
+
+        // Records without sequence numbers are copied, numbered, into the fresh WAL.
+        let legacy_path = test_dir.join("1.wal");
+        let mut legacy = File::create(&legacy_path).unwrap();
+        legacy.write_all(&6u64.to_le_bytes()).unwrap();
+        legacy.write_all(&[0]).unwrap();
+        legacy.write_all(&5u64.to_le_bytes()).unwrap();
+        legacy.write_all(b"Server").unwrap();
+        legacy.write_all(b"apache").unwrap();
+        legacy.write_all(&9u128.to_le_bytes()).unwrap();
+        drop(legacy);
+        let wal_files = vec![wal_files[0].clone(), legacy_path];
+        let (fresh, mem_table, _, kept) =
+            WAL::recover_files(&test_dir, &wal_files, &options, 0, &[]).unwrap();
+        assert!(kept.is_empty());
+        assert_eq!(mem_table.fetch(b"Server").unwrap().sequence, 3);
+        assert_eq!(fresh.into_iter().count(), 3);
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_duplicate_records_are_skipped() {
+        let mut rng = rand::thread_rng();
+        let test_dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir_all(&test_dir).unwrap();
+        let options = DiskOptions::default();
+
+        // A recovery cut short after copying the records of the first file to the second.
+        let mut original = WAL::create_new(&test_dir).unwrap();
+        original.record_insertion(b"Server", b"nginx", 7, 1).unwrap();
+        original.record_removal(b"Database", 8, 2).unwrap();
+        original.flush().unwrap();
+        let mut copy = WAL::create_new(&test_dir).unwrap();
+        copy.record_insertion(b"Server", b"nginx", 7, 1).unwrap();
+        copy.record_removal(b"Database", 8, 2).unwrap();
+        copy.record_insertion(b"Server", b"apache", 9, 3).unwrap();
+        copy.flush().unwrap();
+        let wal_files = vec![original.path().to_owned(), copy.path().to_owned()];
+        drop((original, copy));
+
+        let (_, mem_table, recovery, kept) =
+            WAL::recover_files(&test_dir, &wal_files, &options, 0, &[]).unwrap();
+        assert_eq!(kept, wal_files);
+        assert_eq!(recovery.records_replayed, 3);
+        assert_eq!(recovery.duplicate_records, 2);
+        let entry = mem_table.fetch(b"Server").unwrap();
+        assert_eq!((entry.value.as_deref(), entry.sequence), (Some(&b"apache"[..]), 3));
+
+        // Recovering again, whatever was left of an earlier attempt, ends in the same state.
+        let (fresh, mem_table) = WAL::recover_with_options(&test_dir, &options).unwrap();
+        assert_eq!(mem_table.fetch(b"Server").unwrap().sequence, 3);
+        assert_eq!(fresh.into_iter().count(), 3);
+        assert_eq!(find_wal_files(&test_dir).unwrap().len(), 1);
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_recover_unknown_version() {
+        let mut rng = rand::thread_rng();
+        let test_dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir_all(&test_dir).unwrap();
+
+        let mut future = File::create(test_dir.join("1.wal")).unwrap();
+        future.write_all(&WAL_MAGIC).unwrap();
+        future.write_all(&[WAL_VERSION + 1, 0, 0]).unwrap();
+        drop(future);
+
+        let err = WAL::recover_from_directory(&test_dir).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(err.to_string().contains("1.wal: WAL format version 10 is newer than version 9"));
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_recover_mixed_codecs() {
+        let mut rng = rand::thread_rng();
+        let test_dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir_all(&test_dir).unwrap();
+
+        let mut plain = WAL::create_new(&test_dir).unwrap();
+        plain.record_insertion(b"Server", b"nginx", 1, 1).unwrap();
+        plain.flush().unwrap();
+
+        let options = DiskOptions {
+            compression: Compression::Lz4,
+            compress_wal: true,
+            ..DiskOptions::default()
+        };
+        let mut compressed = WAL::create_with_options(&test_dir, &options).unwrap();
+        compressed
+            .record_insertion(b"Database", &b"PostgreSQL".repeat(16), 2, 2)
+            .unwrap();
+        compressed.flush().unwrap();
+
+        let (new_wal, new_mem_table) = WAL::recover_with_options(&test_dir, &options).unwrap();
+        assert_eq!(
+            new_mem_table.fetch(b"Server").unwrap().value.as_ref().unwrap(),
+            b"nginx"
+        );
+        assert_eq!(
+            new_mem_table.fetch(b"Database").unwrap().value.as_ref().unwrap(),
+            &b"PostgreSQL".repeat(16)
+        );
+
+        let file = File::open(&new_wal.path).unwrap();
+        let mut reader = BufReader::new(file);
+        validate_header(&mut reader, Compression::Lz4);
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_prefix_keys_roundtrip() {
+        let mut rng = rand::thread_rng();
+        let test_dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir_all(&test_dir).unwrap();
+
+        let options = DiskOptions {
+            wal_prefix_keys: true,
+            ..DiskOptions::default()
+        };
+        let mut wal = WAL::create_with_options(&test_dir, &options).unwrap();
+        wal.record_insertion(b"tenant/1/user/1", b"alice", 1, 1).unwrap();
+        wal.record_insertion(b"tenant/1/user/2", b"bob", 2, 2).unwrap();
+        wal.record_removal(b"tenant/1/user/1", 3, 3).unwrap();
+        wal.record_insertion(b"other", b"carol", 4, 4).unwrap();
+        wal.flush().unwrap();
+
+        // The second key only stores its one-byte suffix after the shared prefix.
+        let file = File::open(&wal.path).unwrap();
+        let mut reader = BufReader::new(file);
+        let mut header = [0; 7];
+        reader.read_exact(&mut header).unwrap();
+        assert_eq!(header[6] & 1, 1, "Prefix flag missing");
+        let mut first = vec![0; 8 + 1 + 8 + 15 + 5 + 16 + 8 + 4];
+        reader.read_exact(&mut first).unwrap();
+        let mut sizes = [0; 8];
+        reader.read_exact(&mut sizes).unwrap();
+        assert_eq!(u32::from_le_bytes(sizes[..4].try_into().unwrap()), 14);
+        assert_eq!(u32::from_le_bytes(sizes[4..].try_into().unwrap()), 1);
+
+        // Appending to the file keeps encoding against its last key.
+        let wal_path = wal.path.clone();
+        drop(wal);
+        let mut reopened = WAL::open_existing(&wal_path).unwrap();
+        assert!(reopened.header.prefix_keys);
+        reopened.record_insertion(b"otherwise", b"dave", 5, 5).unwrap();
+        reopened.flush().unwrap();
+
+        let (_new_wal, new_mem_table) = WAL::recover_with_options(&test_dir, &options).unwrap();
+        assert!(new_mem_table.fetch(b"tenant/1/user/1").unwrap().is_deleted);
+        assert_eq!(
+            new_mem_table.fetch(b"tenant/1/user/2").unwrap().value.as_ref().unwrap(),
+            b"bob"
+        );
+        assert_eq!(
+            new_mem_table.fetch(b"other").unwrap().value.as_ref().unwrap(),
+            b"carol"
+        );
+        assert_eq!(
+            new_mem_table.fetch(b"otherwise").unwrap().value.as_ref().unwrap(),
+            b"dave"
+        );
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_reopen_in_configured_storage() {
+        let options = DiskOptions {
+            storage: Storage::new(MemoryBackend::new()),
+            ..DiskOptions::default()
+        };
+        let dir = PathBuf::from("memory");
+        let mut wal = WAL::create_with_options(&dir, &options).unwrap();
+        wal.record_insertion(b"Server", b"nginx", 1, 1).unwrap();
+        wal.flush().unwrap();
+        let path = wal.path.clone();
+        drop(wal);
+
+        // The file only exists in the options' storage, not on the file system.
+        assert!(WAL::open_existing(&path).is_err());
+        let mut reopened = WAL::open_existing_with_options(&path, &options).unwrap();
+        reopened.record_insertion(b"Database", b"PostgreSQL", 2, 2).unwrap();
+        reopened.flush().unwrap();
+        drop(reopened);
+
+        let (mem_table, _) = WAL::read_files(&[path], &options, 0, &[]).unwrap();
+        assert_eq!(mem_table.record_count(), 2);
+    }
+
+    #[test]
+    fn test_corrupted_record_ends_log() {
+        let mut rng = rand::thread_rng();
+        let test_dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir_all(&test_dir).unwrap();
+
+        let mut wal = WAL::create_new(&test_dir).unwrap();
+        let header_end = wal.size();
+        wal.record_insertion(b"Server", b"nginx", 1, 1).unwrap();
+        let first_record_end = wal.size();
+        wal.record_insertion(b"Database", b"PostgreSQL", 2, 2).unwrap();
+        wal.flush().unwrap();
+
+        // Flip a byte inside the second record's value.
+        let mut bytes = std::fs::read(&wal.path).unwrap();
+        bytes[first_record_end as usize + 8 + 1 + 8 + 8] ^= 0xFF;
+        let mut file = OpenOptions::new().write(true).open(&wal.path).unwrap();
+        file.write_all(&bytes).unwrap();
+
+        let mut iterator = LogFileIterator::from_path(wal.path.clone()).unwrap();
+        let records: Vec<_> = iterator.by_ref().collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].identifier, b"Server");
+        assert_eq!(iterator.trailing_bytes(), wal.size() - first_record_end);
+
+        let items: Vec<_> = inspect(&wal.path).unwrap().collect();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].as_ref().unwrap().offset, header_end);
+        let corruption = items[1].as_ref().unwrap_err();
+        assert_eq!(corruption.offset, first_record_end);
+        assert_eq!(corruption.kind, CorruptionKind::ChecksumMismatch);
+        assert_eq!(corruption.trailing_bytes, wal.size() - first_record_end);
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_torn_batch_is_discarded() {
+        let mut rng = rand::thread_rng();
+        let test_dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir_all(&test_dir).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"Database", b"PostgreSQL");
+        batch.delete(b"Server");
+
+        let mut wal = WAL::create_new(&test_dir).unwrap();
+        wal.record_insertion(b"Server", b"nginx", 1, 1).unwrap();
+        let first_record_end = wal.size();
+        wal.record_batch(&batch, 2, 2, 0).unwrap();
+        wal.flush().unwrap();
+
+        let mut iterator = LogFileIterator::from_path(wal.path.clone()).unwrap();
+        let records: Vec<_> = iterator.by_ref().collect();
+        assert_eq!(records.len(), 3);
+        assert!(records[2].is_removed);
+        assert_eq!(iterator.trailing_bytes(), 0);
+
+        // Lose the tail of the frame, as if the process crashed while writing it.
+        let file = OpenOptions::new().write(true).open(&wal.path).unwrap();
+        file.set_len(wal.size() - 10).unwrap();
+
+        let mut iterator = LogFileIterator::from_path(wal.path.clone()).unwrap();
+        let records: Vec<_> = iterator.by_ref().collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].identifier, b"Server");
+        assert_eq!(iterator.trailing_bytes(), wal.size() - 10 - first_record_end);
+        let corruption = iterator.corruption().unwrap();
+        assert_eq!(corruption.offset, first_record_end);
+        assert_eq!(corruption.kind, CorruptionKind::Truncated);
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_block_compression() {
+        let mut rng = rand::thread_rng();
+        let test_dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir_all(&test_dir).unwrap();
+
+        let options = DiskOptions {
+            wal_block_compression: Some(Compression::default()),
+            ..DiskOptions::default()
+        };
+        let mut batch = WriteBatch::new();
+        batch.put(b"Database", &b"PostgreSQL".repeat(16));
+        batch.delete(b"Server");
+
+        let mut wal = WAL::create_with_options(&test_dir, &options).unwrap();
+        let header_end = wal.size() as usize;
+        wal.record_insertion(b"Server", b"nginx", 1, 1).unwrap();
+        wal.flush().unwrap();
+        let first_block_end = wal.size();
+        wal.record_batch(&batch, 2, 2, 0).unwrap();
+        wal.record_insertion(b"Cache", b"redis", 4, 4).unwrap();
+        wal.flush().unwrap();
+        assert_eq!(wal.size(), std::fs::metadata(&wal.path).unwrap().len());
+
+        let mut iterator = LogFileIterator::from_path(wal.path.clone()).unwrap();
+        assert!(iterator.header().blocks);
+        let records: Vec<_> = iterator.by_ref().collect();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[1].data.as_deref(), Some(&b"PostgreSQL".repeat(16)[..]));
+        assert!(records[2].is_removed);
+        assert_eq!(records[3].identifier, b"Cache");
+        assert_eq!(records[3].offset, first_block_end);
+        assert_eq!(iterator.trailing_bytes(), 0);
+
+        // A torn block loses every record in it, and only those.
+        let file = OpenOptions::new().write(true).open(&wal.path).unwrap();
+        file.set_len(wal.size() - 2).unwrap();
+        let mut iterator = LogFileIterator::from_path(wal.path.clone()).unwrap();
+        let records: Vec<_> = iterator.by_ref().collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].identifier, b"Server");
+        let corruption = iterator.corruption().unwrap();
+        assert_eq!(corruption.offset, first_block_end);
+        assert_eq!(corruption.kind, CorruptionKind::Truncated);
+        let wal_path = wal.path.clone();
+        drop(wal);
+
+        // A block claiming a huge uncompressed size is reported without being decompressed.
+        let torn = std::fs::read(&wal_path).unwrap();
+        let mut bytes = torn.clone();
+        bytes[header_end..header_end + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&wal_path, &bytes).unwrap();
+        let mut iterator = LogFileIterator::from_path(wal_path.clone()).unwrap();
+        assert_eq!(iterator.by_ref().count(), 0);
+        let corruption = iterator.corruption().unwrap();
+        assert_eq!(corruption.kind, CorruptionKind::LengthOutOfBounds);
+        std::fs::write(&wal_path, &torn).unwrap();
+
+        let (_, mem_table) = WAL::recover_with_options(&test_dir, &options).unwrap();
+        assert_eq!(mem_table.fetch(b"Server").unwrap().value.as_deref(), Some(&b"nginx"[..]));
+        assert!(mem_table.fetch(b"Cache").is_none());
+
+        remove_dir_all(&test_dir).unwrap();
+    }
 }
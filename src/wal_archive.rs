@@ -0,0 +1,115 @@
+use crate::storage::Storage;
+use crate::wal::find_wal_files_with;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// A directory the WAL files a database no longer needs are moved to instead of being
+/// deleted, so point-in-time recovery and audit pipelines can read every write it logged.
+/// Set with `DiskOptions::wal_archive`.
+///
+/// Archived files keep their names, which sort in the order they were written, and are
+/// read with `LogFileIterator` like live ones. The oldest are deleted once they are older
+/// than `max_age`, or for as long as the archive holds more than `max_bytes`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WalArchive {
+    /// Directory the files are moved to, relative to the database directory unless it is
+    /// absolute. Created when the database is opened.
+    pub dir: PathBuf,
+    /// Age, counted from when a file was created, past which an archived file is deleted.
+    /// `None` keeps files however old they are.
+    pub max_age: Option<Duration>,
+    /// Total size in bytes past which the oldest archived files are deleted. `None` lets the
+    /// archive grow without bound.
+    pub max_bytes: Option<u64>,
+}
+
+impl Default for WalArchive {
+    /// Archives to `archive/` in the database directory and keeps every file.
+    fn default() -> WalArchive {
+        WalArchive {
+            dir: PathBuf::from("archive"),
+            max_age: None,
+            max_bytes: None,
+        }
+    }
+}
+
+impl WalArchive {
+    /// Returns the archive directory of the database in `dir`.
+    pub fn path(&self, dir: &Path) -> PathBuf {
+        dir.join(&self.dir)
+    }
+
+    /// Deletes the files of the archive directory `dir` kept in `storage` that are older
+    /// than `max_age` at `now`, in microseconds since the epoch as WAL file names hold, then
+    /// the oldest ones until the rest fit in `max_bytes`. Returns the deleted files.
+    pub(crate) fn prune(
+        &self,
+        storage: &Storage,
+        dir: &Path,
+        now: u128,
+    ) -> io::Result<Vec<PathBuf>> {
+        let files = find_wal_files_with(storage, dir)?;
+        let sizes = files
+            .iter()
+            .map(|path| storage.open(path)?.size())
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut total: u64 = sizes.iter().sum();
+        let max_age = self.max_age.map(|age| age.as_micros());
+
+        let mut removed = Vec::new();
+        for (path, size) in files.into_iter().zip(sizes) {
+            let created = path
+                .file_stem()
+                .and_then(|stem| stem.to_str()?.parse::<u128>().ok());
+            let expired = max_age
+                .zip(created)
+                .is_some_and(|(age, created)| now.saturating_sub(created) > age);
+            let oversized = self.max_bytes.is_some_and(|max| total > max);
+            // Files are listed oldest first, so the ones to delete come first.
+            if !expired && !oversized {
+                break;
+            }
+            storage.delete(&path)?;
+            total -= size;
+            removed.push(path);
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryBackend;
+
+    #[test]
+    fn test_prune() {
+        let storage = Storage::new(MemoryBackend::new());
+        let dir = Path::new("db/archive");
+        for (name, size) in [("100.wal", 10), ("200.wal", 20), ("300.wal", 30)] {
+            storage
+                .create(&dir.join(name))
+                .unwrap()
+                .append(&vec![0; size])
+                .unwrap();
+        }
+
+        let mut archive = WalArchive {
+            max_age: Some(Duration::from_micros(150)),
+            ..WalArchive::default()
+        };
+        assert_eq!(archive.path(Path::new("db")), dir);
+        let removed = archive.prune(&storage, dir, 300).unwrap();
+        assert_eq!(removed, vec![dir.join("100.wal")]);
+
+        archive.max_bytes = Some(40);
+        let removed = archive.prune(&storage, dir, 300).unwrap();
+        assert_eq!(removed, vec![dir.join("200.wal")]);
+        assert_eq!(
+            find_wal_files_with(&storage, dir).unwrap(),
+            vec![dir.join("300.wal")]
+        );
+    }
+}
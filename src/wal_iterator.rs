@@ -1,87 +1,464 @@
-use std::fs::{File, OpenOptions};
-use std::io::{self, BufReader, Read};
-use std::path::PathBuf;
+use crate::checksum::crc32c_append;
+use crate::compression::Compression;
+use crate::wal::{
+    WalHeader, BACKDATED_RECORD, BACKDATED_REMOVAL_RECORD, BATCH_RECORD, MAX_WAL_BLOCK_SIZE,
+    RANGE_DELETE_RECORD, SOFT_DELETE_RECORD, TAGGED_RECORD,
+};
+use std::collections::VecDeque;
+use crate::storage::{FileReader, Storage};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 
 /// Represents an individual record in the Write-Ahead Log.
+#[derive(Debug)]
 pub struct LogRecord {
     pub identifier: Vec<u8>,            // Key for identifying the record
-    pub data: Option<Vec<u8>>,          // Value for the record (if not deleted)
+    pub data: Option<Vec<u8>>,          // Value for the record (if not deleted), or the value kept by a soft deletion
     pub event_time: u128,               // Timestamp for tracking when the record was created or updated
     pub is_removed: bool,               // Flag indicating if the record has been deleted
+    pub is_range_removal: bool,         // Flag indicating the removal of the keys from the identifier up to the data
+    pub is_backdated: bool,             // Flag indicating a version written below the latest one of its key
+    pub sequence: u64,                  // Position in the commit order, or 0 for files written before sequence numbers
+    pub schema: u32,                    // Schema version the value is encoded with, or 0 if untagged
+    pub offset: u64,                    // Offset of the record, or of the block holding it, in the WAL file
+}
+
+/// Why the records of a WAL file stop being readable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CorruptionKind {
+    /// The file ends in the middle of a record or batch frame, as after a crash during a write.
+    Truncated,
+    /// The checksum stored after a record doesn't match its bytes.
+    ChecksumMismatch,
+    /// The record kind byte holds no known kind.
+    UnknownKind(u8),
+    /// A prefix-encoded key shares more bytes than the previous key holds.
+    InvalidKeyPrefix,
+    /// A key or value length runs past the end of the file.
+    LengthOutOfBounds,
+    /// A value doesn't decompress with the codec of the file.
+    Decompression,
+    /// A batch frame starts inside another batch frame.
+    NestedBatch,
+}
+
+impl std::fmt::Display for CorruptionKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CorruptionKind::Truncated => write!(f, "truncated record"),
+            CorruptionKind::ChecksumMismatch => write!(f, "checksum mismatch"),
+            CorruptionKind::UnknownKind(kind) => write!(f, "unknown record kind {}", kind),
+            CorruptionKind::InvalidKeyPrefix => write!(f, "invalid key prefix"),
+            CorruptionKind::LengthOutOfBounds => write!(f, "length past the end of the file"),
+            CorruptionKind::Decompression => write!(f, "value fails to decompress"),
+            CorruptionKind::NestedBatch => write!(f, "batch frame inside a batch frame"),
+        }
+    }
+}
+
+/// Where and why the readable part of a WAL file ends.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CorruptionInfo {
+    /// Offset of the first record or batch frame that could not be read, or of the block
+    /// holding it in files compressed by block.
+    pub offset: u64,
+    pub kind: CorruptionKind,
+    /// Number of bytes from `offset` to the end of the file, all ignored by recovery.
+    pub trailing_bytes: u64,
+}
+
+impl std::fmt::Display for CorruptionInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} at offset {} ({} bytes ignored)",
+            self.kind, self.offset, self.trailing_bytes
+        )
+    }
 }
 
 /// Struct responsible for iterating through entries in a WAL (Write-Ahead Log) file.
 pub struct LogFileIterator {
-    file_reader: BufReader<File>,       // Buffer for reading from the WAL file
+    file_reader: BufReader<FileReader>, // Buffer for reading from the WAL file
+    header: WalHeader,                  // Encoding of the records in this file
+    last_key: Vec<u8>,                  // Key of the previous record, for prefix-encoded keys
+    intact_last_key: Vec<u8>,           // Key of the last intact record, for prefix-encoded keys
+    record_crc: u32,                    // Checksum of the bytes read so far for the current record
+    pending: VecDeque<LogRecord>,       // Records of a batch frame that have not been returned yet
+    position: u64,                      // Offset of the next byte to read
+    intact: u64,                        // Offset just past the last intact record or frame
+    file_size: u64,                     // Size of the WAL file when it was opened or resumed
+    corruption: Option<CorruptionInfo>, // Why iteration stopped before the end of the file
+    block: Vec<u8>,                     // Decompressed records of the current block, for files compressed by block
+    block_position: usize,              // Offset of the next byte to read in the current block
+    block_offset: u64,                  // Offset of the current block in the file
+}
+
+/// An entry decoded from the WAL: a single operation or the start of a batch frame.
+enum LogEntry {
+    Record(LogRecord),
+    Batch(u64),
 }
 
 impl LogFileIterator {
     /// Constructs a new iterator for traversing the WAL file, given a path to the file.
     pub fn from_path(filepath: PathBuf) -> io::Result<LogFileIterator> {
-        let wal_file = OpenOptions::new().read(true).open(filepath)?;
+        LogFileIterator::open_with(&Storage::default(), &filepath)
+    }
+
+    /// Constructs an iterator over a WAL file kept in `storage`.
+    pub fn open_with(storage: &Storage, path: &Path) -> io::Result<LogFileIterator> {
+        let mut wal_file = FileReader::new(storage.open(path)?);
+        let header = WalHeader::read_from(&mut wal_file)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        header.compression.ensure_available()?;
+        let position = wal_file.stream_position()?;
+        let file_size = wal_file.get_ref().size()?;
         let buffered_reader = BufReader::new(wal_file);
-        Ok(LogFileIterator { file_reader: buffered_reader })
+        Ok(LogFileIterator {
+            file_reader: buffered_reader,
+            header,
+            last_key: Vec::new(),
+            intact_last_key: Vec::new(),
+            record_crc: 0,
+            pending: VecDeque::new(),
+            position,
+            intact: position,
+            file_size,
+            corruption: None,
+            block: Vec::new(),
+            block_position: 0,
+            block_offset: position,
+        })
+    }
+
+    /// Returns the header of the file, describing how its records are encoded.
+    pub fn header(&self) -> WalHeader {
+        self.header
+    }
+
+    /// Returns the size of the file when it was opened, or last resumed.
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    /// Returns the number of bytes after the last intact record, which were ignored because
+    /// they hold a torn or corrupted record. Only meaningful once the iterator is exhausted.
+    pub fn trailing_bytes(&self) -> u64 {
+        self.file_size.saturating_sub(self.intact)
+    }
+
+    /// Returns where and why iteration stopped before the end of the file, or `None` if every
+    /// record was read. Only meaningful once the iterator is exhausted.
+    pub fn corruption(&self) -> Option<&CorruptionInfo> {
+        self.corruption.as_ref()
+    }
+
+    /// Picks up the records appended to the file since the iterator was exhausted, for a file
+    /// another process is still writing: reading starts again after the last intact record,
+    /// so one that was cut short at the end of the file is read whole this time. The records
+    /// of a block read only in part are returned again.
+    pub(crate) fn resume(&mut self) -> io::Result<()> {
+        debug_assert!(self.pending.is_empty());
+        self.file_size = self.file_reader.get_ref().get_ref().size()?;
+        self.file_reader.seek(SeekFrom::Start(self.intact))?;
+        self.position = self.intact;
+        self.last_key.clone_from(&self.intact_last_key);
+        self.record_crc = 0;
+        self.corruption = None;
+        self.block.clear();
+        self.block_position = 0;
+        self.block_offset = self.intact;
+        Ok(())
+    }
+
+    /// Fills the buffer with record bytes, folding them into the record checksum.
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), CorruptionKind> {
+        self.read_raw(buffer)?;
+        self.record_crc = crc32c_append(self.record_crc, buffer);
+        Ok(())
+    }
+
+    /// Fills the buffer from the current block in files compressed by block, or else from
+    /// the file. Records never straddle blocks.
+    fn read_raw(&mut self, buffer: &mut [u8]) -> Result<(), CorruptionKind> {
+        if !self.header.blocks {
+            return self.read_file(buffer);
+        }
+        let end = self.block_position + buffer.len();
+        let bytes = self
+            .block
+            .get(self.block_position..end)
+            .ok_or(CorruptionKind::Truncated)?;
+        buffer.copy_from_slice(bytes);
+        self.block_position = end;
+        Ok(())
+    }
+
+    /// Fills the buffer from the file.
+    fn read_file(&mut self, buffer: &mut [u8]) -> Result<(), CorruptionKind> {
+        self.file_reader
+            .read_exact(buffer)
+            .map_err(|_| CorruptionKind::Truncated)?;
+        self.position += buffer.len() as u64;
+        Ok(())
+    }
+
+    /// Reads and decompresses the next block of the file, checking it against its checksum.
+    /// Returns `Ok(false)` at the end of the file.
+    fn read_block(&mut self) -> Result<bool, CorruptionKind> {
+        if self.position >= self.file_size {
+            return Ok(false);
+        }
+        self.block_offset = self.position;
+        let mut sizes = [0; 8];
+        self.read_file(&mut sizes)?;
+        let length = u32::from_le_bytes(sizes[..4].try_into().unwrap()) as usize;
+        let stored_length = u32::from_le_bytes(sizes[4..].try_into().unwrap()) as u64;
+        if length > MAX_WAL_BLOCK_SIZE
+            || stored_length > self.file_size.saturating_sub(self.position)
+        {
+            return Err(CorruptionKind::LengthOutOfBounds);
+        }
+        let mut stored = vec![0; stored_length as usize];
+        self.read_file(&mut stored)?;
+        let mut crc = [0; 4];
+        self.read_file(&mut crc)?;
+        if u32::from_le_bytes(crc) != crc32c_append(0, &stored) {
+            return Err(CorruptionKind::ChecksumMismatch);
+        }
+        let block = self
+            .header
+            .compression
+            .decompress_within(&stored, length)
+            .map_err(|_| CorruptionKind::Decompression)?;
+        if block.len() != length {
+            return Err(CorruptionKind::Decompression);
+        }
+        self.block = block;
+        self.block_position = 0;
+        Ok(true)
+    }
+
+    /// Returns the offset of the next entry, reading the next block once the current one is
+    /// used up, or `None` at the end of the log.
+    fn next_offset(&mut self) -> Result<Option<u64>, CorruptionKind> {
+        if !self.header.blocks {
+            return Ok((self.position < self.file_size).then_some(self.position));
+        }
+        if self.block_position == self.block.len() && !self.read_block()? {
+            return Ok(None);
+        }
+        Ok(Some(self.block_offset))
+    }
+
+    /// Reads the checksum trailing a record, if the file carries them, and compares it
+    /// against the bytes read for the record.
+    fn verify_record(&mut self) -> Result<(), CorruptionKind> {
+        let crc = std::mem::take(&mut self.record_crc);
+        if !self.header.checksums {
+            return Ok(());
+        }
+        let mut stored = [0; 4];
+        self.read_raw(&mut stored)?;
+        if u32::from_le_bytes(stored) != crc {
+            return Err(CorruptionKind::ChecksumMismatch);
+        }
+        Ok(())
+    }
+
+    /// Checks that a length read from the file fits in what is left of it, or of its current
+    /// block, so a corrupted length is reported instead of allocated.
+    fn check_length(&self, length: usize) -> Result<(), CorruptionKind> {
+        let left = match self.header.blocks {
+            true => (self.block.len() - self.block_position) as u64,
+            false => self.file_size.saturating_sub(self.position),
+        };
+        if length as u64 > left {
+            return Err(CorruptionKind::LengthOutOfBounds);
+        }
+        Ok(())
+    }
+
+    /// Reads the key of a record once its size field is known.
+    fn read_key(&mut self, key_size: [u8; 8]) -> Result<Vec<u8>, CorruptionKind> {
+        let (shared, suffix_length) = if self.header.prefix_keys {
+            let shared = u32::from_le_bytes(key_size[..4].try_into().unwrap()) as usize;
+            let suffix = u32::from_le_bytes(key_size[4..].try_into().unwrap()) as usize;
+            (shared, suffix)
+        } else {
+            (0, usize::from_le_bytes(key_size))
+        };
+        if shared > self.last_key.len() {
+            return Err(CorruptionKind::InvalidKeyPrefix);
+        }
+        self.check_length(suffix_length)?;
+
+        let mut identifier = self.last_key[..shared].to_vec();
+        identifier.resize(shared + suffix_length, 0);
+        self.read(&mut identifier[shared..])?;
+        if self.header.prefix_keys {
+            self.last_key.clone_from(&identifier);
+        }
+        Ok(identifier)
     }
 }
 
 /*
     ---------- USAGE ----------
-    * Reads the key length (first 8 bytes).
+    * Reads the key length (first 8 bytes), or the shared prefix and suffix lengths for
+      files with prefix-encoded keys.
     * Reads the deletion flag (1 byte), indicating whether the record is marked as deleted.
+      A value of 2 marks the start of a batch frame instead, whose first 8 bytes hold the
+      number of records in the frame, and a value of 3 an insertion followed by the 4-byte
+      schema version of its value. A value of 4 marks a soft deletion, laid out like a
+      tagged insertion whose value is the one kept for restoring the key. A value of 5 marks
+      a range deletion, laid out the same way, whose key and value are the start and end of
+      the removed range.
     * Reads the key (identifier).
     * Reads the value if the record is not deleted, or skips it if it is.
     * Reads the timestamp (16 bytes).
+    * Reads the sequence number (8 bytes) for files written with sequence numbers.
+    * Verifies the record checksum (4 bytes) for files written with checksums.
     * Returns the LogRecord that contains all this data.
+    In files compressed by block, records are read from blocks laid out as the uncompressed
+    size (4 bytes), the stored size (4 bytes), the stored bytes and their CRC32C (4 bytes).
 */
 impl Iterator for LogFileIterator {
     type Item = LogRecord;
 
     /// Advances the iterator, retrieving the next record in the WAL file if available.
+    /// The records of a batch frame are only returned once the whole frame has been read.
+    /// A torn or corrupted record ends the log; `corruption` then tells where and why.
     fn next(&mut self) -> Option<LogRecord> {
-        let mut key_length_buffer = [0; 8];
-        if self.file_reader.read_exact(&mut key_length_buffer).is_err() {
+        if let Some(record) = self.pending.pop_front() {
+            return Some(record);
+        }
+        if self.corruption.is_some() {
             return None;
         }
-        let key_length = usize::from_le_bytes(key_length_buffer);
+
+        match self.read_frame() {
+            Ok(record) => {
+                // A block is only intact once every record in it has been read.
+                if !self.header.blocks || self.block_position == self.block.len() {
+                    self.intact = self.position;
+                    self.intact_last_key.clone_from(&self.last_key);
+                }
+                record
+            }
+            Err(kind) => {
+                self.corruption = Some(CorruptionInfo {
+                    offset: self.intact,
+                    kind,
+                    trailing_bytes: self.trailing_bytes(),
+                });
+                None
+            }
+        }
+    }
+}
+
+impl LogFileIterator {
+    /// Reads a record, or a whole batch frame whose records after the first are queued in
+    /// `pending`. Returns `Ok(None)` at the end of the file.
+    fn read_frame(&mut self) -> Result<Option<LogRecord>, CorruptionKind> {
+        let count = match self.read_entry()? {
+            None => return Ok(None),
+            Some(LogEntry::Record(record)) => return Ok(Some(record)),
+            Some(LogEntry::Batch(count)) => count,
+        };
+        let mut records = VecDeque::new();
+        for _ in 0..count {
+            match self.read_entry()? {
+                Some(LogEntry::Record(record)) => records.push_back(record),
+                Some(LogEntry::Batch(_)) => return Err(CorruptionKind::NestedBatch),
+                None => return Err(CorruptionKind::Truncated),
+            }
+        }
+        self.pending = records;
+        Ok(self.pending.pop_front())
+    }
+
+    /// Decodes the next entry of the file, or `None` at the end of the log.
+    fn read_entry(&mut self) -> Result<Option<LogEntry>, CorruptionKind> {
+        let Some(offset) = self.next_offset()? else {
+            return Ok(None);
+        };
+        let mut key_length_buffer = [0; 8];
+        self.read(&mut key_length_buffer)?;
 
         let mut deletion_flag_buffer = [0; 1];
-        if self.file_reader.read_exact(&mut deletion_flag_buffer).is_err() {
-            return None;
+        self.read(&mut deletion_flag_buffer)?;
+        if deletion_flag_buffer[0] == BATCH_RECORD {
+            self.verify_record()?;
+            return Ok(Some(LogEntry::Batch(u64::from_le_bytes(key_length_buffer))));
         }
-        let is_deleted = deletion_flag_buffer[0] != 0;
+        let mut schema = 0;
+        let kind = deletion_flag_buffer[0];
+        if kind > BACKDATED_REMOVAL_RECORD {
+            return Err(CorruptionKind::UnknownKind(kind));
+        }
+        if kind >= TAGGED_RECORD {
+            let mut schema_buf = [0; 4];
+            self.read(&mut schema_buf)?;
+            schema = u32::from_le_bytes(schema_buf);
+        }
+        let is_deleted = matches!(
+            kind,
+            1 | SOFT_DELETE_RECORD | RANGE_DELETE_RECORD | BACKDATED_REMOVAL_RECORD
+        );
 
-        let mut identifier = vec![0; key_length];
+        let identifier;
         let mut data = None;
-        if is_deleted {
-            if self.file_reader.read_exact(&mut identifier).is_err() {
-                return None;
-            }
+        if kind == 1 {
+            identifier = self.read_key(key_length_buffer)?;
         } else {
-            if self.file_reader.read_exact(&mut key_length_buffer).is_err() {
-                return None;
-            }
-            let value_length = usize::from_le_bytes(key_length_buffer);
-            if self.file_reader.read_exact(&mut identifier).is_err() {
-                return None;
-            }
+            let mut value_length_buffer = [0; 8];
+            self.read(&mut value_length_buffer)?;
+            let value_length = usize::from_le_bytes(value_length_buffer);
+            self.check_length(value_length)?;
+            identifier = self.read_key(key_length_buffer)?;
             let mut value_buffer = vec![0; value_length];
-            if self.file_reader.read_exact(&mut value_buffer).is_err() {
-                return None;
-            }
+            self.read(&mut value_buffer)?;
             data = Some(value_buffer);
         }
 
         let mut timestamp_buf = [0; 16];
-        if self.file_reader.read_exact(&mut timestamp_buf).is_err() {
-            return None;
-        }
+        self.read(&mut timestamp_buf)?;
         let event_time = u128::from_le_bytes(timestamp_buf);
 
-        Some(LogRecord {
+        let mut sequence_buf = [0; 8];
+        if self.header.sequences {
+            self.read(&mut sequence_buf)?;
+        }
+        let sequence = u64::from_le_bytes(sequence_buf);
+
+        self.verify_record()?;
+        // The empty value of a backdated removal only keeps the record layout.
+        if kind == BACKDATED_REMOVAL_RECORD {
+            data = None;
+        }
+        if let Some(value) = data.as_mut() {
+            if self.header.compression != Compression::None && !self.header.blocks {
+                *value = self
+                    .header
+                    .compression
+                    .decompress(value)
+                    .map_err(|_| CorruptionKind::Decompression)?;
+            }
+        }
+
+        Ok(Some(LogEntry::Record(LogRecord {
             identifier,
             data,
             event_time,
             is_removed: is_deleted,
-        })
+            is_range_removal: kind == RANGE_DELETE_RECORD,
+            is_backdated: kind == BACKDATED_RECORD || kind == BACKDATED_REMOVAL_RECORD,
+            sequence,
+            schema,
+            offset,
+        })))
     }
 }
@@ -0,0 +1,286 @@
+use crate::manifest::WAL_EXTENSION;
+use crate::storage::Storage;
+use crate::wal_iterator::LogFileIterator;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// When a write logged to a mirrored WAL is acknowledged.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MirrorAck {
+    /// Once both copies hold it: a failure of either copy fails the write.
+    #[default]
+    Both,
+    /// Once either copy holds it. A copy that fails is dropped, with a warning, and logging
+    /// continues in the other one alone until the next WAL file.
+    Either,
+}
+
+/// A second directory, ideally on another device, every WAL record is also written to, so a
+/// device failure doesn't lose the writes not yet flushed to a segment. Set with
+/// `DiskOptions::wal_mirror`.
+///
+/// The copies carry the same file names as the WAL files of the database. When the database
+/// is opened, a live WAL file that is missing, or whose copy in the mirror holds more intact
+/// records, is restored from the mirror before being replayed.
+#[derive(Clone, Debug)]
+pub struct WalMirror {
+    /// Directory the copies are kept in. It must exist.
+    pub dir: PathBuf,
+    /// When writes are acknowledged.
+    pub ack: MirrorAck,
+    /// Backend the copies are kept in, the local file system by default.
+    pub storage: Storage,
+}
+
+impl WalMirror {
+    /// Mirrors the WAL to `dir` on the local file system, acknowledging writes once both
+    /// copies hold them.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> WalMirror {
+        WalMirror {
+            dir: dir.into(),
+            ack: MirrorAck::default(),
+            storage: Storage::default(),
+        }
+    }
+
+    /// Returns the path of the copy of the WAL file `name`.
+    pub fn copy_path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    /// Restores the WAL files `names` of `dir` in `storage` whose copy in the mirror holds
+    /// more intact bytes, or which are missing. Returns the names of the restored files.
+    /// Copies that can't be read are passed over, as the mirror may be the failed device.
+    pub fn restore(
+        &self,
+        storage: &Storage,
+        dir: &Path,
+        names: &[String],
+    ) -> io::Result<Vec<String>> {
+        let mut restored = Vec::new();
+        for name in names {
+            let copy = self.copy_path(name);
+            let Ok(mirrored) = intact_bytes(&self.storage, &copy) else {
+                continue;
+            };
+            let path = dir.join(name);
+            let local = match storage.exists(&path) {
+                true => intact_bytes(storage, &path).ok(),
+                false => None,
+            };
+            if local.is_some_and(|local| local >= mirrored) {
+                continue;
+            }
+            storage.replace(dir, name, &self.storage.read(&copy)?)?;
+            restored.push(name.clone());
+        }
+        Ok(restored)
+    }
+
+    /// Removes the copy of the WAL file `name`. Failures are ignored: the copy is removed
+    /// again when the database is next opened.
+    pub fn remove(&self, name: &str) {
+        let _ = self.storage.delete(&self.copy_path(name));
+    }
+
+    /// Removes the copies of WAL files other than `live`, ignoring failures.
+    pub fn remove_unlisted(&self, live: &[String]) {
+        let Ok(paths) = self.storage.list_with_extension(&self.dir, WAL_EXTENSION) else {
+            return;
+        };
+        for path in paths {
+            let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+            if !live.iter().any(|live| live == name) {
+                let _ = self.storage.delete(&path);
+            }
+        }
+    }
+}
+
+/// Returns the size of the readable records of a WAL file, its header included.
+fn intact_bytes(storage: &Storage, path: &Path) -> io::Result<u64> {
+    let mut records = LogFileIterator::open_with(storage, path)?;
+    records.by_ref().for_each(drop);
+    Ok(records.file_size() - records.trailing_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::DiskOptions;
+    use crate::storage::{MemoryBackend, StorageBackend, StorageFile};
+    use crate::wal::WAL;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    /// Keeps files in memory, failing every append and sync once broken.
+    struct FailingBackend {
+        files: MemoryBackend,
+        broken: Arc<AtomicBool>,
+    }
+
+    struct FailingFile {
+        file: Box<dyn StorageFile>,
+        broken: Arc<AtomicBool>,
+    }
+
+    impl FailingFile {
+        fn check(&self) -> io::Result<()> {
+            match self.broken.load(Ordering::Relaxed) {
+                true => Err(io::Error::other("device failed")),
+                false => Ok(()),
+            }
+        }
+    }
+
+    impl StorageFile for FailingFile {
+        fn append(&mut self, data: &[u8]) -> io::Result<()> {
+            self.check()?;
+            self.file.append(data)
+        }
+
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            self.file.read_at(buf, offset)
+        }
+
+        fn size(&self) -> io::Result<u64> {
+            self.file.size()
+        }
+
+        fn sync(&self) -> io::Result<()> {
+            self.check()
+        }
+    }
+
+    impl FailingBackend {
+        fn wrap(&self, file: Box<dyn StorageFile>) -> Box<dyn StorageFile> {
+            let broken = self.broken.clone();
+            Box::new(FailingFile { file, broken })
+        }
+    }
+
+    impl StorageBackend for FailingBackend {
+        fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+            Ok(self.wrap(self.files.create(path)?))
+        }
+
+        fn append(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+            Ok(self.wrap(self.files.append(path)?))
+        }
+
+        fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+            self.files.open(path)
+        }
+
+        fn delete(&self, path: &Path) -> io::Result<()> {
+            self.files.delete(path)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            self.files.rename(from, to)
+        }
+
+        fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+            self.files.list(dir)
+        }
+
+        fn exists(&self, path: &Path) -> bool {
+            self.files.exists(path)
+        }
+
+        fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+            self.files.sync_dir(dir)
+        }
+    }
+
+    fn mirrored_options(ack: MirrorAck) -> (DiskOptions, Arc<AtomicBool>) {
+        let broken = Arc::new(AtomicBool::new(false));
+        let backend = FailingBackend {
+            files: MemoryBackend::new(),
+            broken: broken.clone(),
+        };
+        let options = DiskOptions {
+            storage: Storage::new(MemoryBackend::new()),
+            wal_mirror: Some(WalMirror {
+                dir: PathBuf::from("mirror"),
+                ack,
+                storage: Storage::new(backend),
+            }),
+            ..DiskOptions::default()
+        };
+        (options, broken)
+    }
+
+    fn keys(storage: &Storage, path: &Path) -> Vec<Vec<u8>> {
+        let records = LogFileIterator::open_with(storage, path).unwrap();
+        records.map(|record| record.identifier).collect()
+    }
+
+    #[test]
+    fn test_failed_copy() {
+        let (options, broken) = mirrored_options(MirrorAck::Either);
+        let mirror = options.wal_mirror.clone().unwrap();
+        let mut wal = WAL::create_with_options(Path::new("db"), &options).unwrap();
+        let name = wal.path().file_name().unwrap().to_str().unwrap().to_owned();
+        wal.record_insertion(b"a", b"1", 1, 1).unwrap();
+        wal.sync().unwrap();
+        assert_eq!(keys(&mirror.storage, &mirror.copy_path(&name)), vec![b"a".to_vec()]);
+
+        // The mirror's device fails: writes go on in the other copy.
+        broken.store(true, Ordering::Relaxed);
+        wal.record_insertion(b"b", b"2", 2, 2).unwrap();
+        wal.sync().unwrap();
+        wal.sync_handle().unwrap().sync().unwrap();
+        broken.store(false, Ordering::Relaxed);
+        wal.record_insertion(b"c", b"3", 3, 3).unwrap();
+        wal.flush().unwrap();
+        assert_eq!(keys(&options.storage, wal.path()).len(), 3);
+        assert_eq!(keys(&mirror.storage, &mirror.copy_path(&name)), vec![b"a".to_vec()]);
+
+        let (options, broken) = mirrored_options(MirrorAck::Both);
+        let mirror = options.wal_mirror.clone().unwrap();
+        let mut wal = WAL::create_with_options(Path::new("db"), &options).unwrap();
+        let name = wal.path().file_name().unwrap().to_str().unwrap().to_owned();
+        wal.record_insertion(b"a", b"1", 1, 1).unwrap();
+        wal.sync().unwrap();
+        broken.store(true, Ordering::Relaxed);
+        assert!(wal.sync_handle().unwrap().sync().is_err());
+        wal.record_insertion(b"b", b"2", 2, 2).unwrap();
+        assert!(wal.flush().is_err());
+        // The copies no longer match, so the file takes no more writes, even once the
+        // mirror recovers.
+        broken.store(false, Ordering::Relaxed);
+        assert!(wal.record_insertion(b"c", b"3", 3, 3).and_then(|_| wal.flush()).is_err());
+        assert_eq!(keys(&mirror.storage, &mirror.copy_path(&name)), vec![b"a".to_vec()]);
+    }
+
+    #[test]
+    fn test_restore() {
+        let (options, _) = mirrored_options(MirrorAck::Both);
+        let mirror = options.wal_mirror.clone().unwrap();
+        let dir = Path::new("db");
+        let mut wal = WAL::create_with_options(dir, &options).unwrap();
+        let name = wal.path().file_name().unwrap().to_str().unwrap().to_owned();
+        wal.record_insertion(b"a", b"1", 1, 1).unwrap();
+        wal.record_insertion(b"b", b"2", 2, 2).unwrap();
+        wal.flush().unwrap();
+        let names = vec![name.clone()];
+        let storage = &options.storage;
+        assert!(mirror.restore(storage, dir, &names).unwrap().is_empty());
+
+        // A torn copy is replaced with the mirrored one, and so is a lost one.
+        let bytes = storage.read(wal.path()).unwrap();
+        storage.replace(dir, &name, &bytes[..bytes.len() - 10]).unwrap();
+        assert_eq!(keys(storage, wal.path()).len(), 1);
+        assert_eq!(mirror.restore(storage, dir, &names).unwrap(), names);
+        assert_eq!(keys(storage, wal.path()).len(), 2);
+        storage.delete(wal.path()).unwrap();
+        assert_eq!(mirror.restore(storage, dir, &names).unwrap(), names);
+        assert_eq!(storage.read(wal.path()).unwrap(), bytes);
+
+        mirror.remove_unlisted(&names);
+        assert!(mirror.storage.exists(&mirror.copy_path(&name)));
+        mirror.remove_unlisted(&[]);
+        assert!(!mirror.storage.exists(&mirror.copy_path(&name)));
+    }
+}
@@ -0,0 +1,183 @@
+//! Tailing the WAL, for replicating a database to another system.
+//!
+//! `Disk::tail(from_sequence)` first reads back the writes after `from_sequence` still held
+//! by the live WAL files, then hands out every write as it commits. Both come with the
+//! sequence numbers of their WAL records, which never change, so a replica that stores the
+//! last one it applied can resume from it after a restart of either side, as long as the
+//! writes it missed haven't been flushed out of the WAL meanwhile.
+//!
+//! The writes committed while a tail isn't read wait in a channel of `TAIL_CAPACITY` events.
+//! A tail falling further behind ends, as if the database were closed; a new one resumes
+//! from its `position`.
+
+use crate::subscription::{ChangeEvent, ChangeOp};
+use crate::wal_iterator::LogRecord;
+use std::collections::VecDeque;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// Most committed writes a tail holds before it is read, past which it ends.
+pub const TAIL_CAPACITY: usize = 64 * 1024;
+
+/// The writes committed after a sequence number, returned by `Disk::tail`. Iterating blocks
+/// until the next write commits, and ends once the database is closed or the tail falls
+/// `TAIL_CAPACITY` writes behind.
+pub struct WalTail {
+    backlog: VecDeque<ChangeEvent>,
+    live: Receiver<ChangeEvent>,
+    position: u64,
+    ended: bool,
+}
+
+impl WalTail {
+    pub(crate) fn new(
+        backlog: VecDeque<ChangeEvent>,
+        live: Receiver<ChangeEvent>,
+        position: u64,
+    ) -> WalTail {
+        WalTail {
+            backlog,
+            live,
+            position,
+            ended: false,
+        }
+    }
+
+    /// Returns the sequence number of the last write returned, or the one the tail started
+    /// from.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Returns whether the tail has ended: the database was closed, or the tail fell too far
+    /// behind.
+    pub fn is_ended(&self) -> bool {
+        self.ended
+    }
+
+    /// Waits for the next write. Returns `None` once the tail has ended.
+    pub fn recv(&mut self) -> Option<ChangeEvent> {
+        loop {
+            let event = match self.backlog.pop_front() {
+                Some(event) => event,
+                None => match self.live.recv() {
+                    Ok(event) => event,
+                    Err(_) => {
+                        self.ended = true;
+                        return None;
+                    }
+                },
+            };
+            if let Some(event) = self.advance(event) {
+                return Some(event);
+            }
+        }
+    }
+
+    /// Waits at most `timeout` for the next write.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Option<ChangeEvent> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let event = match self.backlog.pop_front() {
+                Some(event) => event,
+                None => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    match self.live.recv_timeout(timeout) {
+                        Ok(event) => event,
+                        Err(RecvTimeoutError::Timeout) => return None,
+                        Err(RecvTimeoutError::Disconnected) => {
+                            self.ended = true;
+                            return None;
+                        }
+                    }
+                }
+            };
+            if let Some(event) = self.advance(event) {
+                return Some(event);
+            }
+        }
+    }
+
+    /// Returns the next write if it has already committed.
+    pub fn try_recv(&mut self) -> Option<ChangeEvent> {
+        self.recv_timeout(Duration::ZERO)
+    }
+
+    /// Moves past an event, dropping it if it is at or before the position.
+    fn advance(&mut self, event: ChangeEvent) -> Option<ChangeEvent> {
+        if event.sequence <= self.position {
+            return None;
+        }
+        self.position = event.sequence;
+        Some(event)
+    }
+}
+
+impl Iterator for WalTail {
+    type Item = ChangeEvent;
+
+    fn next(&mut self) -> Option<ChangeEvent> {
+        self.recv()
+    }
+}
+
+/// The writes committed after a sequence number, returned by `AsyncDisk::tail`.
+#[cfg(feature = "async")]
+pub struct AsyncWalTail {
+    backlog: VecDeque<ChangeEvent>,
+    live: tokio::sync::mpsc::Receiver<ChangeEvent>,
+    position: u64,
+}
+
+#[cfg(feature = "async")]
+impl AsyncWalTail {
+    pub(crate) fn new(
+        backlog: VecDeque<ChangeEvent>,
+        live: tokio::sync::mpsc::Receiver<ChangeEvent>,
+        position: u64,
+    ) -> AsyncWalTail {
+        AsyncWalTail {
+            backlog,
+            live,
+            position,
+        }
+    }
+
+    /// Returns the sequence number of the last write returned, or the one the tail started
+    /// from.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Waits for the next write. Returns `None` once the database is closed or the tail fell
+    /// `TAIL_CAPACITY` writes behind.
+    pub async fn recv(&mut self) -> Option<ChangeEvent> {
+        loop {
+            let event = match self.backlog.pop_front() {
+                Some(event) => event,
+                None => self.live.recv().await?,
+            };
+            if event.sequence > self.position {
+                self.position = event.sequence;
+                return Some(event);
+            }
+        }
+    }
+}
+
+/// Converts a WAL record to the event it was published as when it committed.
+pub(crate) fn record_event(record: LogRecord) -> ChangeEvent {
+    let op = match (record.is_range_removal, record.is_removed, record.data) {
+        (true, _, end) => ChangeOp::DeleteRange {
+            end: end.unwrap_or_default(),
+        },
+        (false, true, _) => ChangeOp::Delete,
+        (false, false, value) => ChangeOp::Put(value.unwrap_or_default()),
+    };
+    ChangeEvent {
+        key: record.identifier,
+        op,
+        timestamp: record.event_time,
+        sequence: record.sequence,
+    }
+}
@@ -0,0 +1,275 @@
+use crate::error::FluxError;
+
+/// Largest encoded size of a batch committed as a single WAL frame.
+pub const MAX_BATCH_BYTES: usize = 4 * 1024 * 1024;
+
+/// Fixed per-operation overhead in a WAL frame: key size, kind, value size, timestamp, checksum.
+const OP_OVERHEAD: usize = 8 + 1 + 8 + 16 + 4;
+
+/// Operation applied to a key by a `WriteBatch`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    Put(Vec<u8>),
+    Delete,
+}
+
+/// What a commit does with an operation stamped with a timestamp older than the latest
+/// version of its key, as happens with skewed clocks or replayed messages. Set by
+/// `DiskOptions::on_timestamp_regression`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimestampRegression {
+    /// Fails the commit, writing nothing of its batch.
+    #[default]
+    Reject,
+    /// Writes the operation as a version below the latest one, which reads keep returning.
+    /// `Disk::get_at` and `Disk::history` see the operation for as long as
+    /// `DiskOptions::retained_versions` keeps it, and change feeds report it with its own
+    /// timestamp. Databases using it record the `backdated-versions` feature, so older
+    /// builds, which would read such versions as the latest, refuse to open them.
+    KeepAsOlderVersion,
+    /// Drops the operation, writing the rest of the batch.
+    Ignore,
+}
+
+/// Options of a single write, overriding how `DiskOptions` logs it. Taken by `Disk::set_with`,
+/// `Disk::delete_with` and `Disk::write_with`; the default follows `DiskOptions`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WriteOptions {
+    /// Skips the WAL: the write only goes to the memtable, so it is lost if the database
+    /// closes or crashes before the memtable is flushed, and never shows up to what reads
+    /// the WAL, like `Disk::subscribe_from` or followers. Meant for bulk loads that can be
+    /// replayed from their source, calling `Disk::flush` once done.
+    pub disable_wal: bool,
+    /// Waits for the write to be synced to disk before returning, even without
+    /// `DiskOptions::sync_writes`. Can't be combined with `disable_wal`.
+    pub sync: bool,
+}
+
+impl WriteOptions {
+    /// Fails with `FluxError::InvalidArgument` if the options contradict each other.
+    pub(crate) fn check(&self) -> Result<(), FluxError> {
+        if self.disable_wal && self.sync {
+            return Err(FluxError::InvalidArgument(
+                "a write skipping the WAL can't be synced".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A group of writes committed atomically: after a crash either every operation of the batch
+/// is recovered or none is.
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    /// Operations with their own timestamp, if any, and whether they go below the latest
+    /// version of their key.
+    ops: Vec<(Vec<u8>, Op, Option<u128>, bool)>,
+    size: usize,
+    commit_timestamp: Option<u128>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Queues an insertion or update of a key.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.push(key.to_vec(), Op::Put(value.to_vec()), None, false);
+    }
+
+    /// Queues a removal of a key.
+    pub fn delete(&mut self, key: &[u8]) {
+        self.push(key.to_vec(), Op::Delete, None, false);
+    }
+
+    /// Queues an insertion or update of a key stamped with `timestamp`, in microseconds since
+    /// the Unix epoch, instead of the commit time. If the key already holds a newer version,
+    /// the commit follows `DiskOptions::on_timestamp_regression`.
+    pub fn put_at(&mut self, key: &[u8], value: &[u8], timestamp: u128) {
+        self.push(key.to_vec(), Op::Put(value.to_vec()), Some(timestamp), false);
+    }
+
+    /// Queues a removal of a key stamped with `timestamp`, as `put_at` does.
+    pub fn delete_at(&mut self, key: &[u8], timestamp: u128) {
+        self.push(key.to_vec(), Op::Delete, Some(timestamp), false);
+    }
+
+    /// Queues every operation yielded by the iterator, in order.
+    pub fn extend_from_iter<K, I>(&mut self, ops: I)
+    where
+        K: AsRef<[u8]>,
+        I: IntoIterator<Item = (K, Op)>,
+    {
+        for (key, op) in ops {
+            self.push(key.as_ref().to_vec(), op, None, false);
+        }
+    }
+
+    /// Stamps the operations of the batch queued without a timestamp of their own with
+    /// `timestamp`, in microseconds since the Unix epoch, instead of the time of the commit.
+    /// Meant for backfills that preserve original event times: keys of the batch already
+    /// holding a newer version are dealt with as `DiskOptions::on_timestamp_regression` says.
+    /// A timestamp later than the database's clock fails the batch with
+    /// `FluxError::InvalidArgument`, as its writes would shadow the ones made until then.
+    pub fn set_commit_timestamp(&mut self, timestamp: u128) {
+        self.commit_timestamp = Some(timestamp);
+    }
+
+    /// Returns the timestamp set by `set_commit_timestamp`, if any.
+    pub fn commit_timestamp(&self) -> Option<u128> {
+        self.commit_timestamp
+    }
+
+    /// Returns the number of queued operations.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns whether the batch holds no operations.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Returns the approximate size of the batch once encoded in the WAL.
+    pub fn approximate_size(&self) -> usize {
+        self.size
+    }
+
+    /// Iterates over the queued operations in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &Op)> + Clone {
+        self.ops.iter().map(|(key, op, ..)| (key.as_slice(), op))
+    }
+
+    /// Returns whether some operation carries a timestamp given by the caller.
+    pub(crate) fn has_explicit_timestamps(&self) -> bool {
+        self.commit_timestamp.is_some()
+            || self.ops.iter().any(|(_, _, timestamp, _)| timestamp.is_some())
+    }
+
+    /// Iterates over the queued operations with the timestamp each is committed with:
+    /// its own, else the batch's commit timestamp, else `now`.
+    pub(crate) fn iter_timestamped(
+        &self,
+        now: u128,
+    ) -> impl Iterator<Item = (&[u8], &Op, u128)> + Clone {
+        self.iter_committed(now).map(|(key, op, timestamp, _)| (key, op, timestamp))
+    }
+
+    /// Iterates over the queued operations as `iter_timestamped` does, along with whether
+    /// each was queued by `push_backdated`.
+    pub(crate) fn iter_committed(
+        &self,
+        now: u128,
+    ) -> impl Iterator<Item = (&[u8], &Op, u128, bool)> + Clone {
+        let default = self.commit_timestamp.unwrap_or(now);
+        self.ops.iter().map(move |(key, op, timestamp, backdated)| {
+            (key.as_slice(), op, timestamp.unwrap_or(default), *backdated)
+        })
+    }
+
+    /// Splits the batch into consecutive batches whose encoded size stays within `max_bytes`.
+    /// An operation larger than `max_bytes` on its own gets a batch of its own.
+    pub fn split(self, max_bytes: usize) -> Vec<WriteBatch> {
+        let mut batches = Vec::new();
+        let empty = WriteBatch {
+            commit_timestamp: self.commit_timestamp,
+            ..WriteBatch::default()
+        };
+        let mut current = empty.clone();
+        for (key, op, timestamp, backdated) in self.ops {
+            if !current.is_empty() && current.size + op_size(&key, &op) > max_bytes {
+                batches.push(std::mem::replace(&mut current, empty.clone()));
+            }
+            current.push(key, op, timestamp, backdated);
+        }
+        if !current.is_empty() {
+            batches.push(current);
+        }
+        batches
+    }
+
+    /// Queues an operation stamped with `timestamp`.
+    pub(crate) fn push_at(&mut self, key: &[u8], op: Op, timestamp: u128) {
+        self.push(key.to_vec(), op, Some(timestamp), false);
+    }
+
+    /// Queues an operation stamped with `timestamp`, to be written below the latest version
+    /// of its key rather than over it.
+    pub(crate) fn push_backdated(&mut self, key: &[u8], op: Op, timestamp: u128) {
+        self.push(key.to_vec(), op, Some(timestamp), true);
+    }
+
+    fn push(&mut self, key: Vec<u8>, op: Op, timestamp: Option<u128>, backdated: bool) {
+        self.size += op_size(&key, &op);
+        self.ops.push((key, op, timestamp, backdated));
+    }
+}
+
+fn op_size(key: &[u8], op: &Op) -> usize {
+    let value_size = match op {
+        Op::Put(value) => value.len(),
+        Op::Delete => 0,
+    };
+    OP_OVERHEAD + key.len() + value_size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extend_from_iter() {
+        let mut batch = WriteBatch::new();
+        batch.put(b"Server", b"nginx");
+        batch.extend_from_iter(vec![
+            ("Database", Op::Put(b"PostgreSQL".to_vec())),
+            ("Server", Op::Delete),
+        ]);
+
+        let ops: Vec<_> = batch.iter().collect();
+        assert_eq!(ops.len(), 3);
+        assert_eq!(ops[1], (&b"Database"[..], &Op::Put(b"PostgreSQL".to_vec())));
+        assert_eq!(ops[2], (&b"Server"[..], &Op::Delete));
+        assert_eq!(batch.approximate_size(), 3 * OP_OVERHEAD + 6 + 5 + 8 + 10 + 6);
+    }
+
+    #[test]
+    fn test_split_respects_limit() {
+        let mut batch = WriteBatch::new();
+        batch.extend_from_iter((0..100).map(|i| (format!("key{:03}", i), Op::Put(vec![0; 50]))));
+        let total = batch.approximate_size();
+
+        let parts = batch.split(1000);
+        assert!(parts.len() > 1);
+        assert!(parts.iter().all(|part| part.approximate_size() <= 1000));
+        assert_eq!(parts.iter().map(|part| part.len()).sum::<usize>(), 100);
+        assert_eq!(parts.iter().map(|part| part.approximate_size()).sum::<usize>(), total);
+        assert_eq!(parts[1].iter().next().unwrap().0, format!("key{:03}", parts[0].len()).as_bytes());
+
+        let mut oversized = WriteBatch::new();
+        oversized.put(b"big", &[0; 2000]);
+        oversized.put(b"small", b"1");
+        oversized.set_commit_timestamp(7);
+        let parts = oversized.split(1000);
+        assert_eq!(parts.len(), 2);
+        assert!(parts.iter().all(|part| part.commit_timestamp() == Some(7)));
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let mut batch = WriteBatch::new();
+        batch.put(b"Server", b"nginx");
+        batch.put_at(b"Database", b"PostgreSQL", 3);
+        assert!(batch.has_explicit_timestamps());
+        let stamped: Vec<u128> = batch.iter_timestamped(10).map(|(_, _, ts)| ts).collect();
+        assert_eq!(stamped, vec![10, 3]);
+
+        batch.set_commit_timestamp(5);
+        batch.delete_at(b"Server", 8);
+        let stamped: Vec<u128> = batch.iter_timestamped(10).map(|(_, _, ts)| ts).collect();
+        assert_eq!(stamped, vec![5, 3, 8]);
+        assert!(!WriteBatch::new().has_explicit_timestamps());
+    }
+}
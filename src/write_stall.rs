@@ -0,0 +1,168 @@
+use std::time::Duration;
+
+/// Thresholds at which writes are slowed down, then stopped, while flushes and compactions
+/// fall behind, so a burst of writes can't queue frozen memtables until memory runs out or
+/// pile up segments until reads slow to a crawl. Set in `DiskOptions::write_stall`.
+///
+/// Pending bytes are the size of the frozen memtables waiting to be flushed. Past a
+/// slowdown threshold every write first sleeps for `slowdown_delay`, giving the background
+/// thread room to catch up; past a stop threshold writes wait until it has, or fail with
+/// `FluxError::Busy`, as `on_stop` says. `None` disables a threshold, and every threshold
+/// is disabled by default; `WriteStall::recommended()` returns thresholds suiting the
+/// default options.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WriteStall {
+    /// Bytes of frozen memtables at which writes slow down.
+    pub slowdown_pending_bytes: Option<usize>,
+    /// Bytes of frozen memtables at which writes stop. With `StallPolicy::Wait`, opening
+    /// fails with `InvalidInput` if it is 0, as nothing is ever pending below it.
+    pub stop_pending_bytes: Option<usize>,
+    /// Segment count at which writes slow down.
+    pub slowdown_segments: Option<usize>,
+    /// Segment count at which writes stop. Reaching it also triggers a compaction, even
+    /// below `DiskOptions::compaction_trigger`. With `StallPolicy::Wait`, opening fails with
+    /// `InvalidInput` below 2, as a single segment is never compacted away.
+    pub stop_segments: Option<usize>,
+    /// How long each write sleeps past a slowdown threshold.
+    pub slowdown_delay: Duration,
+    /// Whether writes past a stop threshold wait or fail.
+    pub on_stop: StallPolicy,
+}
+
+/// What a write does once a stop threshold of `WriteStall` is reached.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StallPolicy {
+    /// Wait until flushes and compactions bring the database back under the thresholds.
+    #[default]
+    Wait,
+    /// Fail right away with `FluxError::Busy`, for callers that would rather shed load or
+    /// retry later. `Disk::set` and the other writes returning a count fail with 0.
+    Reject,
+}
+
+/// How far behind the background work is, as far as writes are concerned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Stall {
+    None,
+    SlowDown,
+    Stop,
+}
+
+impl Default for WriteStall {
+    fn default() -> WriteStall {
+        WriteStall {
+            slowdown_pending_bytes: None,
+            stop_pending_bytes: None,
+            slowdown_segments: None,
+            stop_segments: None,
+            slowdown_delay: Duration::from_millis(1),
+            on_stop: StallPolicy::default(),
+        }
+    }
+}
+
+impl WriteStall {
+    /// Never slows down nor stops writes, as by default.
+    pub fn disabled() -> WriteStall {
+        WriteStall::default()
+    }
+
+    /// Slows writes down past 64 MiB of frozen memtables or 20 segments, by 1 ms each, and
+    /// stops them past 128 MiB or 36 segments until the background work catches up.
+    pub fn recommended() -> WriteStall {
+        WriteStall {
+            slowdown_pending_bytes: Some(64 * 1024 * 1024),
+            stop_pending_bytes: Some(128 * 1024 * 1024),
+            slowdown_segments: Some(20),
+            stop_segments: Some(36),
+            ..WriteStall::default()
+        }
+    }
+
+    /// Returns why writes stopped by these thresholds would never go on again, if they
+    /// wouldn't.
+    pub(crate) fn unreachable_stop(&self) -> Option<String> {
+        if self.on_stop != StallPolicy::Wait {
+            return None;
+        }
+        if let Some(stop) = self.stop_segments.filter(|&stop| stop < 2) {
+            return Some(format!("write_stall.stop_segments of {} is below 2", stop));
+        }
+        (self.stop_pending_bytes == Some(0))
+            .then(|| "write_stall.stop_pending_bytes is 0".to_owned())
+    }
+
+    pub(crate) fn is_disabled(&self) -> bool {
+        self.slowdown_pending_bytes.is_none()
+            && self.stop_pending_bytes.is_none()
+            && self.slowdown_segments.is_none()
+            && self.stop_segments.is_none()
+    }
+
+    /// Returns what writes should do with `pending_bytes` of frozen memtables waiting and
+    /// `segments` live segments.
+    pub(crate) fn check(&self, pending_bytes: usize, segments: usize) -> Stall {
+        let reached = |threshold: Option<usize>, value| threshold.is_some_and(|t| value >= t);
+        if reached(self.stop_pending_bytes, pending_bytes) || self.stops_at(segments) {
+            Stall::Stop
+        } else if reached(self.slowdown_pending_bytes, pending_bytes)
+            || reached(self.slowdown_segments, segments)
+        {
+            Stall::SlowDown
+        } else {
+            Stall::None
+        }
+    }
+
+    /// Whether `segments` live segments stop writes.
+    pub(crate) fn stops_at(&self, segments: usize) -> bool {
+        self.stop_segments.is_some_and(|stop| segments >= stop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let stall = WriteStall {
+            slowdown_pending_bytes: Some(100),
+            stop_pending_bytes: Some(200),
+            slowdown_segments: Some(4),
+            stop_segments: Some(8),
+            ..WriteStall::default()
+        };
+        assert_eq!(stall.check(99, 3), Stall::None);
+        assert_eq!(stall.check(100, 0), Stall::SlowDown);
+        assert_eq!(stall.check(0, 4), Stall::SlowDown);
+        assert_eq!(stall.check(200, 0), Stall::Stop);
+        assert_eq!(stall.check(150, 8), Stall::Stop);
+        assert_eq!(
+            WriteStall::disabled().check(usize::MAX, usize::MAX),
+            Stall::None
+        );
+        assert!(WriteStall::disabled().is_disabled() && !stall.is_disabled());
+        assert!(WriteStall::recommended().unreachable_stop().is_none());
+
+        // A single segment is never compacted away, and nothing is pending once flushed.
+        let stall = WriteStall {
+            stop_segments: Some(1),
+            ..WriteStall::default()
+        };
+        assert!(stall
+            .unreachable_stop()
+            .unwrap()
+            .contains("stop_segments of 1"));
+        let stall = WriteStall {
+            on_stop: StallPolicy::Reject,
+            ..stall
+        };
+        assert!(stall.unreachable_stop().is_none());
+        let stall = WriteStall {
+            stop_pending_bytes: Some(0),
+            ..WriteStall::default()
+        };
+        assert!(stall.unreachable_stop().is_some());
+    }
+}